@@ -0,0 +1,72 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::time::Duration;
+
+use glowrs::{DType, SentenceTransformer};
+use glowrs_bench::{create_model_repo, devices, sentences, Architecture};
+
+const BATCH_SIZES: [usize; 3] = [1, 8, 32];
+const SEQUENCE_LENGTHS: [usize; 2] = [16, 128];
+const DTYPES: [DType; 2] = [DType::F32, DType::F16];
+
+fn bench_encode(c: &mut Criterion) {
+    for architecture in Architecture::ALL {
+        let repo = create_model_repo(architecture).expect("Failed to create model repository");
+
+        for (device_name, device) in devices() {
+            for dtype in DTYPES {
+                let encoder = SentenceTransformer::builder()
+                    .with_model_folder(repo.path())
+                    .with_device(device.clone())
+                    .with_dtype(dtype)
+                    .build()
+                    .expect("Failed to load model");
+
+                // Not every architecture supports every data type
+                if let Err(err) = encoder.encode_batch(sentences(1, 1), false) {
+                    eprintln!(
+                        "Skipping {} on {} with {:?}: {}",
+                        architecture.name(),
+                        device_name,
+                        dtype,
+                        err
+                    );
+                    continue;
+                }
+
+                let mut group = c.benchmark_group(format!(
+                    "encode/{}/{}/{}",
+                    architecture.name(),
+                    device_name,
+                    dtype.as_str()
+                ));
+                group
+                    .sample_size(10)
+                    .measurement_time(Duration::from_secs(5));
+
+                for batch_size in BATCH_SIZES {
+                    for n_words in SEQUENCE_LENGTHS {
+                        let batch = sentences(batch_size, n_words);
+
+                        group.throughput(Throughput::Elements(batch_size as u64));
+                        group.bench_with_input(
+                            BenchmarkId::new(format!("seq{n_words}"), batch_size),
+                            &batch,
+                            |b, batch| {
+                                b.iter(|| {
+                                    encoder
+                                        .encode_batch(batch.clone(), false)
+                                        .expect("Failed to encode batch")
+                                })
+                            },
+                        );
+                    }
+                }
+
+                group.finish();
+            }
+        }
+    }
+}
+
+criterion_group!(benches, bench_encode);
+criterion_main!(benches);
@@ -0,0 +1,161 @@
+//! Benchmark fixtures for `glowrs`.
+//!
+//! Creates model repositories with randomly initialized weights for each supported architecture,
+//! so encode throughput can be measured without downloading models from the Hugging Face Hub.
+
+use anyhow::Result;
+use candle_core::{DType, Device};
+use candle_nn::{VarBuilder, VarMap};
+use candle_transformers::models::{bert, distilbert, jina_bert};
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// Model architectures supported by `glowrs`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Architecture {
+    Bert,
+    JinaBert,
+    DistilBert,
+}
+
+impl Architecture {
+    pub const ALL: [Architecture; 3] = [
+        Architecture::Bert,
+        Architecture::JinaBert,
+        Architecture::DistilBert,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Architecture::Bert => "bert",
+            Architecture::JinaBert => "jina-bert",
+            Architecture::DistilBert => "distilbert",
+        }
+    }
+
+    /// Test fixture whose configuration and tokenizer are used for this architecture.
+    fn fixture(&self) -> PathBuf {
+        let fixture = match self {
+            Architecture::Bert => "all-MiniLM-L6-v2",
+            Architecture::JinaBert => "jina-embeddings-v2-base-en",
+            Architecture::DistilBert => "multi-qa-distilbert-dot-v1",
+        };
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../glowrs/tests/fixtures")
+            .join(fixture)
+    }
+
+    /// Model dimensions of a commonly used model of this architecture.
+    fn dimensions(&self) -> serde_json::Value {
+        match self {
+            // sentence-transformers/all-MiniLM-L6-v2
+            Architecture::Bert => json!({
+                "hidden_size": 384,
+                "intermediate_size": 1536,
+                "num_attention_heads": 12,
+                "num_hidden_layers": 6,
+            }),
+            // jinaai/jina-embeddings-v2-small-en
+            Architecture::JinaBert => json!({
+                "hidden_size": 512,
+                "intermediate_size": 2048,
+                "num_attention_heads": 8,
+                "num_hidden_layers": 4,
+            }),
+            // sentence-transformers/multi-qa-distilbert-cos-v1
+            Architecture::DistilBert => json!({
+                "dim": 768,
+                "hidden_dim": 3072,
+                "n_heads": 12,
+                "n_layers": 6,
+            }),
+        }
+    }
+}
+
+/// Create a model repository with random weights for the given architecture.
+///
+/// The repository is removed when the returned [`TempDir`] is dropped.
+pub fn create_model_repo(architecture: Architecture) -> Result<TempDir> {
+    let dir = tempfile::tempdir()?;
+    let fixture = architecture.fixture();
+
+    let mut config: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(fixture.join("config.json"))?)?;
+    if let (Some(config), Some(dimensions)) = (
+        config.as_object_mut(),
+        architecture.dimensions().as_object(),
+    ) {
+        config.extend(dimensions.clone());
+    }
+    fs::write(dir.path().join("config.json"), config.to_string())?;
+
+    fs::copy(
+        fixture.join("tokenizer.json"),
+        dir.path().join("tokenizer.json"),
+    )?;
+    fs::create_dir_all(dir.path().join("1_Pooling"))?;
+    fs::write(
+        dir.path().join("1_Pooling/config.json"),
+        json!({
+            "pooling_mode_cls_token": false,
+            "pooling_mode_mean_tokens": true,
+            "pooling_mode_max_tokens": false,
+            "pooling_mode_mean_sqrt_len_tokens": false,
+        })
+        .to_string(),
+    )?;
+
+    let var_map = VarMap::new();
+    let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+    match architecture {
+        Architecture::Bert => {
+            bert::BertModel::load(vb, &serde_json::from_value(config)?)?;
+        }
+        Architecture::JinaBert => {
+            jina_bert::BertModel::new(vb, &serde_json::from_value(config)?)?;
+        }
+        Architecture::DistilBert => {
+            distilbert::DistilBertModel::load(vb, &serde_json::from_value(config)?)?;
+        }
+    }
+    var_map.save(dir.path().join("model.safetensors"))?;
+
+    Ok(dir)
+}
+
+/// Create `batch_size` sentences of `n_words` words each, which tokenize to roughly
+/// `n_words + 2` tokens.
+pub fn sentences(batch_size: usize, n_words: usize) -> Vec<String> {
+    const WORDS: [&str; 8] = [
+        "the", "cat", "sits", "outside", "while", "a", "man", "plays",
+    ];
+
+    (0..batch_size)
+        .map(|i| {
+            (0..n_words)
+                .map(|j| WORDS[(i + j) % WORDS.len()])
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect()
+}
+
+/// Devices to benchmark on: the CPU, plus the accelerator the crate was compiled for.
+pub fn devices() -> Vec<(&'static str, Device)> {
+    #[allow(unused_mut)]
+    let mut devices = vec![("cpu", Device::Cpu)];
+
+    #[cfg(feature = "cuda")]
+    devices.push(("cuda", Device::new_cuda(0).expect("No CUDA device found.")));
+
+    #[cfg(feature = "metal")]
+    devices.push((
+        "metal",
+        Device::new_metal(0).expect("No Metal device found."),
+    ));
+
+    devices
+}
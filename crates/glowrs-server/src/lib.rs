@@ -0,0 +1,8 @@
+//! The glowrs embedding server as a library, to serve it with custom [`RouteHook`]s without
+//! changing the binary: build the router with [`init_router`] from [`RouterArgs`], which are
+//! parsed from the command line like those of `glowrs-server`.
+
+pub mod server;
+
+pub use server::hooks::{RequestContext, RouteHook};
+pub use server::{init_router, RouterArgs};
@@ -1,15 +1,21 @@
 use anyhow::Result;
+use axum_server::tls_rustls::RustlsConfig;
 use clap::Parser;
-use std::net::IpAddr;
+use std::future::IntoFuture;
+use std::net::{IpAddr, SocketAddr};
 use std::process::ExitCode;
+use std::sync::Arc;
 use tokio::net::TcpListener;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use glowrs::core::device::print_device_info;
 
-mod server;
+use glowrs_server::server;
+use server::tls;
 use server::utils;
 use server::utils::port_in_range;
+use server::worker;
 use server::{init_router, RouterArgs};
 
 #[derive(Debug, Parser)]
@@ -29,6 +35,13 @@ pub struct App {
 async fn main() -> Result<ExitCode> {
     let args = App::parse();
 
+    // Workers talk to the router over stdout, so they log to stderr
+    let worker_model = args.router_args.worker_args.worker_model.clone();
+    let fmt_layer = tracing_subscriber::fmt::layer().with_writer(match worker_model {
+        Some(_) => BoxMakeWriter::new(std::io::stderr),
+        None => BoxMakeWriter::new(std::io::stdout),
+    });
+
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
@@ -38,19 +51,54 @@ async fn main() -> Result<ExitCode> {
                 "glowrs=trace,server=debug,tower_http=debug,axum::rejection=trace".into()
             }),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(fmt_layer)
         .init();
 
+    if let Some(model_repo) = worker_model {
+        worker::serve(&args.router_args, &model_repo).await?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
     // TODO: Configuration passing
     print_device_info();
 
-    let router = init_router(&args.router_args)?;
+    // Register custom `RouteHook`s here to run them on the embeddings routes
+    let hooks = Vec::new();
+    let (router, shutdown) = init_router(&args.router_args, hooks)?;
+
+    let Some(tls_config) = tls::server_config(&args.router_args.tls_args)? else {
+        let listener = TcpListener::bind(format!("{}:{}", args.host, args.port)).await?;
+        tracing::info!("listening on {}", listener.local_addr()?);
+        let server = axum::serve(listener, router)
+            .with_graceful_shutdown(utils::shutdown_signal(None))
+            .into_future();
+        shutdown.serve(server).await?;
+
+        return Ok(ExitCode::SUCCESS);
+    };
+
+    let handle = axum_server::Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            utils::shutdown_signal(None).await;
+            handle.graceful_shutdown(None);
+        }
+    });
 
-    let listener = TcpListener::bind(format!("{}:{}", args.host, args.port)).await?;
-    tracing::info!("listening on {}", listener.local_addr()?);
-    axum::serve(listener, router)
-        .with_graceful_shutdown(utils::shutdown_signal(None))
-        .await?;
+    let address = SocketAddr::new(args.host, args.port);
+    tracing::info!(
+        "listening on {} (TLS{})",
+        address,
+        match args.router_args.tls_args.tls_client_ca {
+            Some(_) => ", client certificates required",
+            None => "",
+        }
+    );
+    let server = axum_server::bind_rustls(address, RustlsConfig::from_config(Arc::new(tls_config)))
+        .handle(handle)
+        .serve(router.into_make_service());
+    shutdown.serve(server).await?;
 
     Ok(ExitCode::SUCCESS)
 }
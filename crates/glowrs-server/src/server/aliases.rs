@@ -0,0 +1,173 @@
+//! Model aliases and the default model
+//!
+//! Clients written for other APIs send the names of their models, e.g. `text-embedding-3-small`.
+//! Aliases map such names to served models, and requests for a name that is neither a served
+//! model nor an alias are served by the default model, if one is configured. Aliases are given
+//! with `--model-alias` or in a JSON file:
+//!
+//! ```json
+//! {
+//!     "aliases": {
+//!         "text-embedding-3-small": "BAAI/bge-small-en-v1.5",
+//!         "text-embedding-3-large": "BAAI/bge-large-en-v1.5"
+//!     },
+//!     "default_model": "BAAI/bge-small-en-v1.5"
+//! }
+//! ```
+
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Debug, Args)]
+pub struct AliasArgs {
+    /// Alias of a served model, as `<alias>=<model>`, e.g.
+    /// `text-embedding-3-small=BAAI/bge-small-en-v1.5`
+    #[clap(long)]
+    pub model_alias: Vec<String>,
+
+    /// JSON file with model aliases and the default model. `--model-alias` and `--default-model`
+    /// take precedence over the file
+    #[clap(long)]
+    pub alias_config: Option<PathBuf>,
+
+    /// Model to serve requests for unknown models with, and requests that don't name a model.
+    /// Requests for unknown models are rejected with `404 Not Found` if not given
+    #[clap(long)]
+    pub default_model: Option<String>,
+}
+
+/// Model aliases and the default model, as given in the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AliasConfig {
+    #[serde(default)]
+    aliases: HashMap<String, String>,
+    default_model: Option<String>,
+}
+
+/// Served models by alias, and the model to serve unknown models with.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Aliases {
+    aliases: HashMap<String, String>,
+    default_model: Option<String>,
+}
+
+impl Aliases {
+    pub fn from_args(args: &AliasArgs) -> Result<Self> {
+        let config = match &args.alias_config {
+            Some(path) => {
+                let content = fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                serde_json::from_str(&content)
+                    .with_context(|| format!("Invalid alias configuration {}", path.display()))?
+            }
+            None => AliasConfig::default(),
+        };
+
+        let mut aliases = config.aliases;
+        for alias in &args.model_alias {
+            let (alias, model) = alias.split_once('=').with_context(|| {
+                format!("Invalid model alias `{alias}`, expected `<alias>=<model>`")
+            })?;
+            aliases.insert(alias.to_string(), model.to_string());
+        }
+
+        Ok(Self {
+            aliases,
+            default_model: args.default_model.clone().or(config.default_model),
+        })
+    }
+
+    /// Check that the aliases and the default model refer to the served models `names`, and that
+    /// no alias shadows a served model.
+    pub fn validate(&self, names: &[String]) -> Result<()> {
+        for (alias, model) in &self.aliases {
+            if names.contains(alias) {
+                anyhow::bail!("Alias `{alias}` is the name of a served model");
+            }
+            if !names.contains(model) {
+                anyhow::bail!("Model `{model}` of alias `{alias}` is not served");
+            }
+        }
+        match &self.default_model {
+            Some(model) if !names.contains(model) => {
+                anyhow::bail!("Default model `{model}` is not served")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The model to serve unknown models with, if configured.
+    pub fn default_model(&self) -> Option<&str> {
+        self.default_model.as_deref()
+    }
+
+    /// The served model an alias refers to.
+    pub fn get(&self, alias: &str) -> Option<&str> {
+        self.aliases.get(alias).map(String::as_str)
+    }
+
+    /// The aliases, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.aliases.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// The model to serve a request for `model` with: the model itself if `served`, the model
+    /// it is an alias of, or else the default model. Unknown models remain as they are without a
+    /// default model.
+    pub fn resolve<'a>(&'a self, model: &'a str, served: bool) -> &'a str {
+        if served {
+            return model;
+        }
+        self.get(model).or(self.default_model()).unwrap_or(model)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_aliases() -> Result<()> {
+        let args = AliasArgs {
+            model_alias: vec!["text-embedding-3-small=org/small".to_string()],
+            alias_config: None,
+            default_model: Some("org/large".to_string()),
+        };
+        let aliases = Aliases::from_args(&args)?;
+        let names = vec!["org/small".to_string(), "org/large".to_string()];
+        aliases.validate(&names)?;
+
+        assert_eq!(aliases.resolve("org/small", true), "org/small");
+        assert_eq!(
+            aliases.resolve("text-embedding-3-small", false),
+            "org/small"
+        );
+        assert_eq!(aliases.resolve("gpt-4", false), "org/large");
+        assert_eq!(Aliases::default().resolve("gpt-4", false), "gpt-4");
+
+        // Aliases must refer to served models, and may not shadow them
+        assert!(aliases.validate(&names[..1]).is_err());
+        let shadowing = Aliases::from_args(&AliasArgs {
+            model_alias: vec!["org/large=org/small".to_string()],
+            alias_config: None,
+            default_model: None,
+        })?;
+        assert!(shadowing.validate(&names).is_err());
+
+        let invalid = AliasArgs {
+            model_alias: vec!["no-model".to_string()],
+            alias_config: None,
+            default_model: None,
+        };
+        assert!(Aliases::from_args(&invalid).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+//! Arrow IPC responses
+//!
+//! With `Accept: application/vnd.apache.arrow.stream`, `/v1/embeddings` returns an Arrow IPC
+//! stream with one record batch, which polars, pandas (with pyarrow) and duckdb load without
+//! parsing. Each row is an input, with the columns
+//!
+//! * `index` (`UInt32`) - Index of the input.
+//! * `embedding` (`FixedSizeList<Float32>`) - Embedding of the input. Embeddings requested with
+//!   `dtype: "f16"` are `FixedSizeList<Float16>`, and with `dtype: "bf16"` they are
+//!   `FixedSizeList<UInt16>` of the bits of the values, as Arrow has no `bfloat16` type.
+//! * `tokens` (`UInt32`) and `truncated` (`Boolean`) - Number of tokens of the input and whether it
+//!   was truncated, with `api_version=v2`.
+//!
+//! The model, the token usage and the `dtype` of the embeddings are in the metadata of the
+//! schema.
+
+use arrow_array::builder::{
+    ArrayBuilder, FixedSizeListBuilder, Float16Builder, Float32Builder, UInt16Builder,
+};
+use arrow_array::{Array, ArrayRef, BooleanArray, FixedSizeListArray, RecordBatch, UInt32Array};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{DataType, Field, Schema};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use half::{bf16, f16};
+
+use crate::server::data_models::{EmbeddingsDType, EmbeddingsResponse};
+
+pub const ARROW_STREAM_CONTENT_TYPE: &str = "application/vnd.apache.arrow.stream";
+
+/// Serialize a response as an Arrow IPC stream, see the [module](self) documentation.
+pub fn to_ipc_stream(response: &EmbeddingsResponse) -> anyhow::Result<Vec<u8>> {
+    let embeddings: Vec<Vec<f32>> = response
+        .data
+        .iter()
+        .map(|inner| inner.embedding.to_vec())
+        .collect();
+    let dimensions = embeddings.first().map_or(0, Vec::len);
+    if embeddings.iter().any(|values| values.len() != dimensions) {
+        anyhow::bail!("Embeddings have different dimensions");
+    }
+
+    let embedding = match response.dtype {
+        EmbeddingsDType::F32 => list_array(
+            Float32Builder::new(),
+            DataType::Float32,
+            &embeddings,
+            |builder, values| builder.append_slice(values),
+        ),
+        EmbeddingsDType::F16 => list_array(
+            Float16Builder::new(),
+            DataType::Float16,
+            &embeddings,
+            |builder, values| {
+                values
+                    .iter()
+                    .for_each(|&value| builder.append_value(f16::from_f32(value)))
+            },
+        ),
+        EmbeddingsDType::Bf16 => list_array(
+            UInt16Builder::new(),
+            DataType::UInt16,
+            &embeddings,
+            |builder, values| {
+                values
+                    .iter()
+                    .for_each(|&value| builder.append_value(bf16::from_f32(value).to_bits()))
+            },
+        ),
+    };
+
+    let index: UInt32Array = response.data.iter().map(|inner| inner.index).collect();
+    let mut fields = vec![
+        Field::new("index", DataType::UInt32, false),
+        Field::new("embedding", embedding.data_type().clone(), false),
+    ];
+    let mut columns: Vec<ArrayRef> = vec![Arc::new(index), Arc::new(embedding)];
+
+    // The v2 fields are only set with `api_version=v2`
+    if response.data.iter().any(|inner| inner.tokens.is_some()) {
+        let tokens: UInt32Array = response.data.iter().map(|inner| inner.tokens).collect();
+        let truncated: BooleanArray = response.data.iter().map(|inner| inner.truncated).collect();
+        fields.push(Field::new("tokens", DataType::UInt32, true));
+        fields.push(Field::new("truncated", DataType::Boolean, true));
+        columns.push(Arc::new(tokens));
+        columns.push(Arc::new(truncated));
+    }
+
+    let mut metadata = HashMap::from([
+        ("model".to_string(), response.model.clone()),
+        ("dtype".to_string(), response.dtype.as_str().to_string()),
+        (
+            "prompt_tokens".to_string(),
+            response.usage.prompt_tokens.to_string(),
+        ),
+        (
+            "total_tokens".to_string(),
+            response.usage.total_tokens.to_string(),
+        ),
+    ]);
+    if let Some(revision) = &response.revision {
+        metadata.insert("revision".to_string(), revision.clone());
+    }
+    let schema = Arc::new(Schema::new_with_metadata(fields, metadata));
+    let batch = RecordBatch::try_new(schema.clone(), columns)?;
+
+    let mut writer = StreamWriter::try_new(Vec::new(), &schema)?;
+    writer.write(&batch)?;
+    Ok(writer.into_inner()?)
+}
+
+/// A list array of the embeddings, with the values of each appended to `values` by `append`.
+fn list_array<B: ArrayBuilder>(
+    values: B,
+    item: DataType,
+    embeddings: &[Vec<f32>],
+    append: impl Fn(&mut B, &[f32]),
+) -> FixedSizeListArray {
+    let dimensions = embeddings.first().map_or(0, Vec::len);
+    let mut builder =
+        FixedSizeListBuilder::with_capacity(values, dimensions as i32, embeddings.len())
+            .with_field(Field::new("item", item, false));
+    for embedding in embeddings {
+        append(builder.values(), embedding);
+        builder.append(true);
+    }
+    builder.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::data_models::ApiVersion;
+    use crate::server::data_models::EmbeddingsDType;
+    use arrow_array::{Float16Array, Float32Array, UInt16Array};
+    use arrow_ipc::reader::StreamReader;
+    use glowrs::{InputUsage, Usage};
+
+    #[test]
+    fn test_to_ipc_stream() -> anyhow::Result<()> {
+        let embeddings = vec![vec![0.5, -1.25, 3.0], vec![1.0, 0.0, -2.0]];
+        let response = EmbeddingsResponse::from_vectors(
+            embeddings.clone(),
+            Usage {
+                prompt_tokens: 7,
+                total_tokens: 7,
+            },
+            vec![InputUsage::default(); 2],
+            "model".to_string(),
+        );
+
+        let bytes = to_ipc_stream(&response.into_version(ApiVersion::V1))?;
+        let mut reader = StreamReader::try_new(bytes.as_slice(), None)?;
+        let schema = reader.schema();
+        assert_eq!(schema.metadata()["model"], "model");
+        assert_eq!(schema.metadata()["total_tokens"], "7");
+        assert!(schema.field_with_name("tokens").is_err());
+
+        let batch = reader.next().unwrap()?;
+        assert_eq!(batch.num_rows(), 2);
+        let column = batch.column_by_name("embedding").unwrap();
+        let list = column
+            .as_any()
+            .downcast_ref::<FixedSizeListArray>()
+            .unwrap();
+        assert_eq!(list.value_length(), 3);
+        for (row, expected) in embeddings.iter().enumerate() {
+            let value = list.value(row);
+            let values = value.as_any().downcast_ref::<Float32Array>().unwrap();
+            assert_eq!(values.values().to_vec(), *expected);
+        }
+        assert!(reader.next().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_half_precision() -> anyhow::Result<()> {
+        let values = vec![0.1234568f32, -0.9876543];
+        let read = |dtype: EmbeddingsDType| -> anyhow::Result<_> {
+            let mut response = EmbeddingsResponse::from_vectors(
+                vec![values.clone()],
+                Usage::default(),
+                vec![InputUsage::default()],
+                "model".to_string(),
+            );
+            response.round_to(dtype);
+            let bytes = to_ipc_stream(&response)?;
+            let mut reader = StreamReader::try_new(bytes.as_slice(), None)?;
+            assert_eq!(reader.schema().metadata()["dtype"], dtype.as_str());
+            let batch = reader.next().unwrap()?;
+            let list = batch
+                .column_by_name("embedding")
+                .unwrap()
+                .as_any()
+                .downcast_ref::<FixedSizeListArray>()
+                .unwrap()
+                .value(0);
+            Ok(list)
+        };
+
+        let f16_values = read(EmbeddingsDType::F16)?;
+        let f16_values = f16_values.as_any().downcast_ref::<Float16Array>().unwrap();
+        let expected: Vec<f16> = values.iter().map(|&v| f16::from_f32(v)).collect();
+        assert_eq!(f16_values.values().to_vec(), expected);
+
+        let bf16_values = read(EmbeddingsDType::Bf16)?;
+        let bf16_values = bf16_values.as_any().downcast_ref::<UInt16Array>().unwrap();
+        let expected: Vec<u16> = values
+            .iter()
+            .map(|&v| bf16::from_f32(v).to_bits())
+            .collect();
+        assert_eq!(bf16_values.values().to_vec(), expected);
+
+        Ok(())
+    }
+}
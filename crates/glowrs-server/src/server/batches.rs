@@ -0,0 +1,562 @@
+//! OpenAI-compatible batch API
+//!
+//! Batch input files of the OpenAI batch API, with a `/v1/embeddings` request on every line, are
+//! uploaded to `/v1/files` and run with `/v1/batches`. A batch is a batch job (see
+//! [`crate::server::jobs`]) that embeds the inputs of all its requests; the embeddings are
+//! grouped per request again in the output file, which has a line per request like the output
+//! files of OpenAI.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::time::{SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::server::jobs::{CreateJobRequest, Job, JobResult, JobStatus, Jobs};
+use crate::server::ServerError;
+
+const BATCH_FILE: &str = "batch.json";
+/// The only endpoint batches can run
+const ENDPOINT: &str = "/v1/embeddings";
+/// Purpose of batch input files
+const PURPOSE: &str = "batch";
+
+/// An uploaded batch input file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct FileObject {
+    pub id: String,
+    pub object: String,
+    /// Size of the file in bytes
+    pub bytes: u64,
+    /// Time the file was uploaded, in seconds since the Unix epoch
+    pub created_at: u64,
+    pub filename: String,
+    pub purpose: String,
+}
+
+/// Request to run the requests of an uploaded batch input file.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateBatchRequest {
+    /// ID of the uploaded input file, with a `/v1/embeddings` request on every line
+    pub input_file_id: String,
+    /// Endpoint of the requests, only `/v1/embeddings` is supported
+    pub endpoint: String,
+    /// Only `24h` is supported. Batches aren't expired.
+    pub completion_window: String,
+    #[serde(default)]
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStatus {
+    InProgress,
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct RequestCounts {
+    pub total: usize,
+    pub completed: usize,
+    pub failed: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct BatchError {
+    pub code: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct BatchErrors {
+    pub object: String,
+    pub data: Vec<BatchError>,
+}
+
+/// A batch, as returned by the `/v1/batches` routes.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct Batch {
+    pub id: String,
+    pub object: String,
+    pub endpoint: String,
+    /// Why the batch failed
+    pub errors: Option<BatchErrors>,
+    pub input_file_id: String,
+    pub completion_window: String,
+    pub status: BatchStatus,
+    /// ID of the file with the responses, once the batch has completed
+    pub output_file_id: Option<String>,
+    /// Always `null`: requests don't fail individually, the whole batch fails instead
+    pub error_file_id: Option<String>,
+    /// Time the batch was created, in seconds since the Unix epoch
+    pub created_at: u64,
+    pub request_counts: RequestCounts,
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+/// A request of a batch input file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InputLine {
+    custom_id: String,
+    method: String,
+    url: String,
+    body: InputBody,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InputBody {
+    model: String,
+    input: Input,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Input {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+/// A batch, as persisted next to its job.
+#[derive(Debug, Serialize, Deserialize)]
+struct BatchInfo {
+    input_file_id: String,
+    completion_window: String,
+    metadata: Option<HashMap<String, String>>,
+    requests: Vec<BatchRequest>,
+}
+
+/// A request of a batch, of which the inputs follow those of the previous request in the job.
+#[derive(Debug, Serialize, Deserialize)]
+struct BatchRequest {
+    custom_id: String,
+    inputs: usize,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Store an uploaded batch input file.
+pub fn upload_file(
+    jobs: &Jobs,
+    filename: String,
+    purpose: &str,
+    contents: &[u8],
+) -> Result<FileObject, ServerError> {
+    if purpose != PURPOSE {
+        return Err(ServerError::InvalidRequest(format!(
+            "Only files with purpose `{PURPOSE}` can be uploaded"
+        )));
+    }
+
+    let file = FileObject {
+        id: format!("file-{}", Uuid::new_v4().simple()),
+        object: "file".to_string(),
+        bytes: contents.len() as u64,
+        created_at: now(),
+        filename,
+        purpose: purpose.to_string(),
+    };
+    let dir = jobs.files_dir();
+    fs::create_dir_all(&dir).map_err(anyhow::Error::from)?;
+    fs::write(dir.join(format!("{}.jsonl", file.id)), contents).map_err(anyhow::Error::from)?;
+    // The metadata is written last, so the file only exists once it is complete
+    fs::write(
+        dir.join(format!("{}.json", file.id)),
+        serde_json::to_vec(&file).map_err(anyhow::Error::from)?,
+    )
+    .map_err(anyhow::Error::from)?;
+
+    Ok(file)
+}
+
+/// Read an uploaded file if it exists.
+fn read_file(jobs: &Jobs, id: &str) -> Result<Option<Vec<u8>>> {
+    // IDs are generated, so anything else can't be a file
+    let Some(uuid) = id.strip_prefix("file-") else {
+        return Ok(None);
+    };
+    if !uuid.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(None);
+    }
+    let dir = jobs.files_dir();
+    if !dir.join(format!("{id}.json")).is_file() {
+        return Ok(None);
+    }
+
+    Ok(Some(fs::read(dir.join(format!("{id}.jsonl")))?))
+}
+
+/// Parse the requests of a batch input file into the inputs of a job and the requests they
+/// belong to.
+fn parse_input_file(contents: &[u8]) -> Result<(CreateJobRequest, Vec<BatchRequest>), ServerError> {
+    let invalid = |line: usize, message: String| {
+        ServerError::InvalidRequest(format!("Line {line} of the input file: {message}"))
+    };
+
+    let mut model: Option<String> = None;
+    let mut input = Vec::new();
+    let mut requests = Vec::new();
+    let mut custom_ids = HashSet::new();
+    for (i, line) in BufReader::new(contents).lines().enumerate() {
+        let line = line.map_err(|err| invalid(i + 1, err.to_string()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request: InputLine =
+            serde_json::from_str(&line).map_err(|err| invalid(i + 1, err.to_string()))?;
+
+        if request.method != "POST" || request.url != ENDPOINT {
+            return Err(invalid(
+                i + 1,
+                format!("Only `POST {ENDPOINT}` requests are supported"),
+            ));
+        }
+        if !custom_ids.insert(request.custom_id.clone()) {
+            return Err(invalid(
+                i + 1,
+                format!("Duplicate custom_id `{}`", request.custom_id),
+            ));
+        }
+        // The inputs are embedded as one job, by one model
+        match &model {
+            Some(model) if *model != request.body.model => {
+                return Err(invalid(
+                    i + 1,
+                    "All requests of a batch should use the same model".to_string(),
+                ))
+            }
+            Some(_) => {}
+            None => model = Some(request.body.model),
+        }
+
+        let inputs = match request.body.input {
+            Input::Single(text) => vec![text],
+            Input::Multiple(texts) if !texts.is_empty() => texts,
+            Input::Multiple(_) => {
+                return Err(invalid(i + 1, "At least one input is required".to_string()))
+            }
+        };
+        requests.push(BatchRequest {
+            custom_id: request.custom_id,
+            inputs: inputs.len(),
+        });
+        input.extend(inputs);
+    }
+
+    let model = model.ok_or(ServerError::InvalidRequest(
+        "The input file has no requests".to_string(),
+    ))?;
+    Ok((CreateJobRequest { model, input }, requests))
+}
+
+/// A batch whose input file was read, to be checked like a job before it is queued.
+pub struct PendingBatch {
+    pub job: CreateJobRequest,
+    info: BatchInfo,
+}
+
+/// Read and validate the requests of an uploaded input file for a batch job.
+pub fn prepare_batch(
+    jobs: &Jobs,
+    request: CreateBatchRequest,
+) -> Result<PendingBatch, ServerError> {
+    if request.endpoint != ENDPOINT {
+        return Err(ServerError::InvalidRequest(format!(
+            "Only the `{ENDPOINT}` endpoint is supported"
+        )));
+    }
+    if request.completion_window != "24h" {
+        return Err(ServerError::InvalidRequest(
+            "Only a completion window of `24h` is supported".to_string(),
+        ));
+    }
+    let contents = read_file(jobs, &request.input_file_id)?.ok_or(ServerError::FileNotFound)?;
+    let (job, requests) = parse_input_file(&contents)?;
+
+    Ok(PendingBatch {
+        job,
+        info: BatchInfo {
+            input_file_id: request.input_file_id,
+            completion_window: request.completion_window,
+            metadata: request.metadata,
+            requests,
+        },
+    })
+}
+
+/// Queue a prepared batch as a batch job.
+pub fn submit_batch(jobs: &Jobs, batch: PendingBatch) -> Result<Batch, ServerError> {
+    let PendingBatch { job, info } = batch;
+    let job = jobs.submit_with(job, |job_dir| {
+        fs::write(job_dir.join(BATCH_FILE), serde_json::to_vec(&info)?)?;
+        Ok(())
+    })?;
+
+    Ok(to_batch(&job, &info))
+}
+
+/// Read the batch of a job, if the job is a batch.
+fn read_batch(jobs: &Jobs, id: &str) -> Result<Option<(Job, BatchInfo)>> {
+    let Some(job) = jobs.get(id) else {
+        return Ok(None);
+    };
+    let path = jobs.job_dir(&job.id).join(BATCH_FILE);
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    Ok(Some((job, serde_json::from_slice(&fs::read(path)?)?)))
+}
+
+pub fn get_batch(jobs: &Jobs, id: &str) -> Result<Option<Batch>> {
+    Ok(read_batch(jobs, id)?.map(|(job, info)| to_batch(&job, &info)))
+}
+
+/// ID of the output file of a batch.
+fn output_file_id(batch_id: &str) -> String {
+    format!("file-{batch_id}")
+}
+
+fn to_batch(job: &Job, info: &BatchInfo) -> Batch {
+    // Requests are completed once all of their inputs are
+    let mut embedded = 0;
+    let completed = info
+        .requests
+        .iter()
+        .take_while(|request| {
+            embedded += request.inputs;
+            embedded <= job.completed
+        })
+        .count();
+    let (status, failed) = match job.status {
+        JobStatus::Queued | JobStatus::Running => (BatchStatus::InProgress, 0),
+        JobStatus::Completed => (BatchStatus::Completed, 0),
+        JobStatus::Failed => (BatchStatus::Failed, info.requests.len() - completed),
+    };
+
+    Batch {
+        id: job.id.clone(),
+        object: "batch".to_string(),
+        endpoint: ENDPOINT.to_string(),
+        errors: job.error.as_ref().map(|message| BatchErrors {
+            object: "list".to_string(),
+            data: vec![BatchError {
+                code: "batch_failed".to_string(),
+                message: message.clone(),
+            }],
+        }),
+        input_file_id: info.input_file_id.clone(),
+        completion_window: info.completion_window.clone(),
+        status,
+        output_file_id: (status == BatchStatus::Completed).then(|| output_file_id(&job.id)),
+        error_file_id: None,
+        created_at: job.created_at,
+        request_counts: RequestCounts {
+            total: info.requests.len(),
+            completed,
+            failed,
+        },
+        metadata: info.metadata.clone(),
+    }
+}
+
+/// Content of a file: an uploaded input file, or the output file of a completed batch with a
+/// line per request, in the order of the input file.
+pub fn file_content(jobs: &Jobs, id: &str) -> Result<Vec<u8>, ServerError> {
+    if let Some(contents) = read_file(jobs, id)? {
+        return Ok(contents);
+    }
+    let Some((job, info)) = id
+        .strip_prefix("file-")
+        .map(|batch_id| read_batch(jobs, batch_id))
+        .transpose()?
+        .flatten()
+    else {
+        return Err(ServerError::FileNotFound);
+    };
+    if job.status != JobStatus::Completed {
+        return Err(ServerError::JobNotCompleted);
+    }
+
+    let results =
+        BufReader::new(fs::File::open(jobs.results_path(&job.id)).map_err(anyhow::Error::from)?);
+    let mut results = results
+        .lines()
+        .map(|line| Ok(serde_json::from_str::<JobResult>(&line?)?));
+
+    let mut output = Vec::new();
+    for (i, request) in info.requests.iter().enumerate() {
+        let data = (0..request.inputs)
+            .map(|index| {
+                let result = results
+                    .next()
+                    .unwrap_or_else(|| Err(anyhow::anyhow!("Batch results are incomplete")))?;
+                Ok(serde_json::json!({
+                    "object": "embedding",
+                    "index": index,
+                    "embedding": result.embedding,
+                }))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let line = serde_json::json!({
+            "id": format!("{}-{i}", job.id),
+            "custom_id": request.custom_id,
+            "response": {
+                "status_code": 200,
+                "body": {
+                    "object": "list",
+                    "data": data,
+                    "model": job.model,
+                },
+            },
+            "error": null,
+        });
+        serde_json::to_writer(&mut output, &line).map_err(anyhow::Error::from)?;
+        output.push(b'\n');
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    const INPUT_FILE: &str = r#"{"custom_id": "a", "method": "POST", "url": "/v1/embeddings", "body": {"model": "model", "input": "The cat"}}
+{"custom_id": "b", "method": "POST", "url": "/v1/embeddings", "body": {"model": "model", "input": ["A dog", "Birds"]}}
+"#;
+
+    fn create(jobs: &Jobs, contents: &str) -> Result<Batch, ServerError> {
+        let file = upload_file(
+            jobs,
+            "input.jsonl".to_string(),
+            "batch",
+            contents.as_bytes(),
+        )?;
+        let batch = prepare_batch(
+            jobs,
+            CreateBatchRequest {
+                input_file_id: file.id,
+                endpoint: ENDPOINT.to_string(),
+                completion_window: "24h".to_string(),
+                metadata: None,
+            },
+        )?;
+        if batch.job.model != "model" {
+            return Err(ServerError::ModelNotFound);
+        }
+        submit_batch(jobs, batch)
+    }
+
+    #[test]
+    fn test_batch() -> Result<()> {
+        let dir = tempdir()?;
+        let jobs = Jobs::open(dir.path(), 2)?;
+
+        let batch = create(&jobs, INPUT_FILE)?;
+        assert_eq!(batch.status, BatchStatus::InProgress);
+        assert_eq!(batch.output_file_id, None);
+        assert_eq!(batch.request_counts.total, 2);
+        let mut job = jobs.get(&batch.id).unwrap();
+        assert_eq!(job.total, 3);
+        assert!(matches!(
+            file_content(&jobs, &output_file_id(&batch.id)),
+            Err(ServerError::JobNotCompleted)
+        ));
+
+        // The first request is completed with its only input
+        job.status = JobStatus::Running;
+        job.completed = 2;
+        jobs.update(&job)?;
+        let batch = get_batch(&jobs, &batch.id)?.unwrap();
+        assert_eq!(batch.request_counts.completed, 1);
+
+        let mut results = fs::File::create(jobs.results_path(&job.id))?;
+        for (index, value) in [1.0, 2.0, 3.0].into_iter().enumerate() {
+            let result = JobResult {
+                index,
+                embedding: vec![value],
+            };
+            writeln!(results, "{}", serde_json::to_string(&result)?)?;
+        }
+        job.status = JobStatus::Completed;
+        job.completed = 3;
+        jobs.update(&job)?;
+
+        let batch = get_batch(&jobs, &batch.id)?.unwrap();
+        assert_eq!(batch.status, BatchStatus::Completed);
+        assert_eq!(batch.request_counts.completed, 2);
+        let output = file_content(&jobs, batch.output_file_id.as_deref().unwrap())?;
+        let lines: Vec<serde_json::Value> = output
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["custom_id"], "a");
+        assert_eq!(lines[1]["custom_id"], "b");
+        let body = &lines[1]["response"]["body"];
+        assert_eq!(body["data"][0]["embedding"], serde_json::json!([2.0]));
+        assert_eq!(body["data"][1]["index"], 1);
+        assert_eq!(body["data"][1]["embedding"], serde_json::json!([3.0]));
+
+        // Jobs submitted to `/v1/jobs` aren't batches
+        let job = jobs.submit(CreateJobRequest {
+            model: "model".to_string(),
+            input: vec!["a".to_string()],
+        })?;
+        assert_eq!(get_batch(&jobs, &job.id)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_batch() -> Result<()> {
+        let dir = tempdir()?;
+        let jobs = Jobs::open(dir.path(), 2)?;
+
+        let line = |custom_id: &str, url: &str, model: &str| {
+            format!(
+                r#"{{"custom_id": "{custom_id}", "method": "POST", "url": "{url}", "body": {{"model": "{model}", "input": "a"}}}}"#
+            )
+        };
+        for contents in [
+            String::new(),
+            line("a", "/v1/chat/completions", "model"),
+            [line("a", ENDPOINT, "model"), line("a", ENDPOINT, "model")].join("\n"),
+            [line("a", ENDPOINT, "model"), line("b", ENDPOINT, "other")].join("\n"),
+            INPUT_FILE.replace("\"input\"", "\"dimensions\": 4, \"input\""),
+        ] {
+            assert!(matches!(
+                create(&jobs, &contents),
+                Err(ServerError::InvalidRequest(_))
+            ));
+        }
+        assert!(matches!(
+            create(&jobs, &line("a", ENDPOINT, "other")),
+            Err(ServerError::ModelNotFound)
+        ));
+        assert!(matches!(
+            upload_file(&jobs, "input.jsonl".to_string(), "fine-tune", b""),
+            Err(ServerError::InvalidRequest(_))
+        ));
+        assert!(matches!(
+            file_content(&jobs, "file-../job"),
+            Err(ServerError::FileNotFound)
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+//! Canary routing
+//!
+//! Loads a canary revision of one of the served models alongside the stable revision, and
+//! routes a percentage of the requests for that model to it. Responses for the model include the
+//! revision that produced them, so a new revision can be rolled out gradually.
+
+use anyhow::Result;
+use clap::Args;
+use glowrs::core::utils::parse_repo_string;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::server::device::DeviceConfig;
+use crate::server::image::ImageConfig;
+use crate::server::infer::embed::{EmbeddingsClient, EmbeddingsHandler};
+use crate::server::infer::limits::QueueConfig;
+use crate::server::infer::DedicatedExecutor;
+use crate::server::preprocess::PreprocessConfig;
+use crate::server::utils::sampled;
+
+#[derive(Debug, Args)]
+pub struct CanaryArgs {
+    /// Canary revision of a served model, as `<repo>:<revision>`. A percentage of the requests
+    /// for the model is routed to it
+    #[clap(long)]
+    pub canary: Option<String>,
+
+    /// Percentage of the requests to route to the canary revision
+    #[clap(long, default_value = "10")]
+    pub canary_percent: f64,
+}
+
+/// Revision of a model that serves a request.
+pub enum Route<'a> {
+    Stable(&'a str),
+    Canary(&'a EmbeddingsClient, &'a str),
+}
+
+pub struct Canary {
+    name: String,
+    stable_revision: String,
+    revision: String,
+    client: EmbeddingsClient,
+    executors: Vec<DedicatedExecutor<EmbeddingsHandler>>,
+    rate: f64,
+    seen: AtomicU64,
+}
+
+impl Canary {
+    /// Load the canary revision, if one is configured.
+    pub fn from_args(
+        args: &CanaryArgs,
+        model_repos: &[String],
+        device: &DeviceConfig,
+        preprocess_config: &PreprocessConfig,
+        queue_config: &QueueConfig,
+        images: ImageConfig,
+    ) -> Result<Option<Self>> {
+        let Some(canary_repo) = &args.canary else {
+            return Ok(None);
+        };
+
+        if !(0.0..=100.0).contains(&args.canary_percent) {
+            anyhow::bail!("Canary percentage should be between 0 and 100");
+        }
+
+        let (name, revision) = parse_repo_string(canary_repo)?;
+        let stable_revisions: Vec<&str> = model_repos
+            .iter()
+            .filter_map(|repo| parse_repo_string(repo).ok())
+            .filter_map(|(stable_name, stable_revision)| {
+                (stable_name == name).then_some(stable_revision)
+            })
+            .collect();
+        let stable_revision = match stable_revisions.as_slice() {
+            [] => anyhow::bail!("Canary {canary_repo} is not a revision of a served model"),
+            [stable_revision] => *stable_revision,
+            _ => anyhow::bail!(
+                "Canary {canary_repo} is a revision of a model served in several revisions"
+            ),
+        };
+
+        let handler = EmbeddingsHandler::from_repo_string(canary_repo, device)?
+            .with_preprocessor(preprocess_config.preprocessor(name)?)
+            .with_images(images);
+        let (client, executors) = EmbeddingsClient::spawn(handler, queue_config.limits(name))?;
+
+        tracing::info!(
+            "Routing {}% of the requests for {name} to revision {revision}",
+            args.canary_percent
+        );
+
+        Ok(Some(Self {
+            name: name.to_string(),
+            stable_revision: stable_revision.to_string(),
+            revision: revision.to_string(),
+            client,
+            executors,
+            rate: args.canary_percent / 100.,
+            seen: AtomicU64::new(0),
+        }))
+    }
+
+    /// Executors of the canary revision.
+    pub(crate) fn executors(&self) -> &[DedicatedExecutor<EmbeddingsHandler>] {
+        &self.executors
+    }
+
+    /// Choose the revision to serve a request for `model` with, if the model has a canary.
+    pub fn route(&self, model: &str) -> Option<Route<'_>> {
+        if model != self.name {
+            return None;
+        }
+
+        if sampled(self.seen.fetch_add(1, Ordering::Relaxed), self.rate) {
+            Some(Route::Canary(&self.client, &self.revision))
+        } else {
+            Some(Route::Stable(&self.stable_revision))
+        }
+    }
+}
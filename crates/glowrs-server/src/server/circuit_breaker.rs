@@ -0,0 +1,381 @@
+//! Overload circuit breaker
+//!
+//! Tracks the latency and outcome of recent inference requests. When the mean latency or the
+//! error rate exceeds its threshold, the breaker opens and new requests are rejected with
+//! `503 Service Unavailable` and a `Retry-After` header, giving the queues time to drain. After
+//! the cooldown a single probe request is let through; the breaker closes again if it succeeds.
+//!
+//! Failures are responses with a server error (`5xx`) status, such as queue timeouts and crashed
+//! workers. Responses that say nothing about the load of the models
+//! are [`Unrecorded`]: `503` for models that are still loading, and `429 Too Many Requests` for
+//! full queues, which already shed load themselves.
+
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use clap::Args;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::server::ServerError;
+
+/// Response extension marking responses the circuit breaker doesn't record.
+#[derive(Debug, Clone, Copy)]
+pub struct Unrecorded;
+
+#[derive(Debug, Args)]
+pub struct CircuitBreakerArgs {
+    /// Open the circuit breaker when the mean latency of recent requests exceeds this many
+    /// milliseconds
+    #[clap(long)]
+    pub breaker_max_latency_ms: Option<u64>,
+
+    /// Open the circuit breaker when the fraction of recent requests that failed with a server
+    /// error exceeds this rate (between 0 and 1)
+    #[clap(long)]
+    pub breaker_max_error_rate: Option<f64>,
+
+    /// Number of recent requests the thresholds are evaluated over
+    #[clap(long, default_value = "50")]
+    pub breaker_window: usize,
+
+    /// Seconds to reject requests after the circuit breaker opens
+    #[clap(long, default_value = "10")]
+    pub breaker_cooldown: u64,
+}
+
+/// Thresholds of a [`CircuitBreaker`].
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    pub max_latency: Option<Duration>,
+    pub max_error_rate: Option<f64>,
+    pub window: usize,
+    pub cooldown: Duration,
+}
+
+impl CircuitBreakerConfig {
+    /// Get the configuration from the command line arguments, if any threshold is set.
+    pub fn from_args(args: &CircuitBreakerArgs) -> anyhow::Result<Option<Self>> {
+        if args.breaker_max_latency_ms.is_none() && args.breaker_max_error_rate.is_none() {
+            return Ok(None);
+        }
+        if args.breaker_window == 0 {
+            anyhow::bail!("Circuit breaker window should be at least 1");
+        }
+        if let Some(rate) = args.breaker_max_error_rate {
+            if !(0.0..=1.0).contains(&rate) {
+                anyhow::bail!("Circuit breaker error rate should be between 0 and 1");
+            }
+        }
+
+        Ok(Some(Self {
+            max_latency: args.breaker_max_latency_ms.map(Duration::from_millis),
+            max_error_rate: args.breaker_max_error_rate,
+            window: args.breaker_window,
+            cooldown: Duration::from_secs(args.breaker_cooldown),
+        }))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BreakerState {
+    Closed,
+    Open {
+        until: Instant,
+    },
+    /// The cooldown has passed and a single probe request is in flight
+    HalfOpen,
+}
+
+struct Inner {
+    state: BreakerState,
+    /// Latency and success of the most recent requests
+    outcomes: VecDeque<(Duration, bool)>,
+}
+
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        let outcomes = VecDeque::with_capacity(config.window);
+        Self {
+            config,
+            inner: Arc::new(Mutex::new(Inner {
+                state: BreakerState::Closed,
+                outcomes,
+            })),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Check whether a request may pass. Returns the time to wait before retrying if not.
+    fn try_acquire(&self, now: Instant) -> Result<(), Duration> {
+        let mut inner = self.lock();
+        match inner.state {
+            BreakerState::Closed => Ok(()),
+            BreakerState::Open { until } if now >= until => {
+                tracing::info!("Circuit breaker half-open, letting a probe request through");
+                inner.state = BreakerState::HalfOpen;
+                Ok(())
+            }
+            BreakerState::Open { until } => Err(until - now),
+            // Other requests wait for the outcome of the probe
+            BreakerState::HalfOpen => Err(Duration::from_secs(1)),
+        }
+    }
+
+    /// Record the outcome of a request that was let through.
+    fn record(&self, now: Instant, latency: Duration, success: bool) {
+        let mut inner = self.lock();
+
+        if inner.state == BreakerState::HalfOpen {
+            if success && self.config.max_latency.is_none_or(|max| latency <= max) {
+                tracing::info!("Circuit breaker closed");
+                inner.state = BreakerState::Closed;
+                inner.outcomes.clear();
+            } else {
+                self.open(&mut inner, now);
+            }
+            return;
+        }
+
+        if inner.outcomes.len() == self.config.window {
+            inner.outcomes.pop_front();
+        }
+        inner.outcomes.push_back((latency, success));
+
+        if inner.state == BreakerState::Closed && self.exceeds_thresholds(&inner.outcomes) {
+            self.open(&mut inner, now);
+        }
+    }
+
+    /// Release a request that was let through without recording its outcome. If it was the
+    /// probe, the next request is let through as the probe instead.
+    fn release(&self, now: Instant) {
+        let mut inner = self.lock();
+        if inner.state == BreakerState::HalfOpen {
+            inner.state = BreakerState::Open { until: now };
+        }
+    }
+
+    fn exceeds_thresholds(&self, outcomes: &VecDeque<(Duration, bool)>) -> bool {
+        // Only evaluate full windows, so a few slow requests after startup don't trip it
+        if outcomes.len() < self.config.window {
+            return false;
+        }
+
+        let n = outcomes.len() as u32;
+        let mean_latency = outcomes
+            .iter()
+            .map(|(latency, _)| *latency)
+            .sum::<Duration>()
+            / n;
+        let error_rate = outcomes.iter().filter(|(_, success)| !success).count() as f64 / n as f64;
+
+        self.config
+            .max_latency
+            .is_some_and(|max| mean_latency > max)
+            || self
+                .config
+                .max_error_rate
+                .is_some_and(|max| error_rate > max)
+    }
+
+    fn open(&self, inner: &mut Inner, now: Instant) {
+        tracing::warn!(
+            "Circuit breaker open, rejecting requests for {} s",
+            self.config.cooldown.as_secs()
+        );
+        inner.state = BreakerState::Open {
+            until: now + self.config.cooldown,
+        };
+        inner.outcomes.clear();
+    }
+}
+
+/// Records a request as failed if it is dropped before completing, e.g. on timeouts.
+struct Outcome<'a> {
+    breaker: &'a CircuitBreaker,
+    start: Instant,
+    recorded: bool,
+}
+
+impl Outcome<'_> {
+    fn record(mut self, success: bool) {
+        self.recorded = true;
+        let now = Instant::now();
+        self.breaker.record(now, now - self.start, success);
+    }
+
+    fn release(mut self) {
+        self.recorded = true;
+        self.breaker.release(Instant::now());
+    }
+}
+
+/// Whether a response counts as a success, or `None` if it isn't recorded. Client errors are
+/// not a sign of overload.
+fn success(response: &Response) -> Option<bool> {
+    if response.extensions().get::<Unrecorded>().is_some() {
+        return None;
+    }
+    Some(!response.status().is_server_error())
+}
+
+impl Drop for Outcome<'_> {
+    fn drop(&mut self) {
+        if !self.recorded {
+            let now = Instant::now();
+            self.breaker.record(now, now - self.start, false);
+        }
+    }
+}
+
+/// Middleware rejecting requests while the circuit breaker is open.
+pub async fn circuit_breaker(
+    State(breaker): State<CircuitBreaker>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    if let Err(retry_after) = breaker.try_acquire(start) {
+        return ServerError::ServiceUnavailable { retry_after }.into_response();
+    }
+
+    let outcome = Outcome {
+        breaker: &breaker,
+        start,
+        recorded: false,
+    };
+    let response = next.run(request).await;
+
+    match success(&response) {
+        Some(success) => outcome.record(success),
+        None => outcome.release(),
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::http::StatusCode;
+
+    fn breaker(max_latency_ms: Option<u64>, max_error_rate: Option<f64>) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig {
+            max_latency: max_latency_ms.map(Duration::from_millis),
+            max_error_rate,
+            window: 4,
+            cooldown: Duration::from_secs(10),
+        })
+    }
+
+    #[test]
+    fn test_trips_on_latency() {
+        let breaker = breaker(Some(100), None);
+        let now = Instant::now();
+
+        for latency in [50, 150, 150] {
+            breaker.record(now, Duration::from_millis(latency), true);
+            assert!(breaker.try_acquire(now).is_ok());
+        }
+
+        breaker.record(now, Duration::from_millis(150), true);
+        assert_eq!(breaker.try_acquire(now), Err(Duration::from_secs(10)));
+        assert_eq!(
+            breaker.try_acquire(now + Duration::from_secs(4)),
+            Err(Duration::from_secs(6))
+        );
+    }
+
+    #[test]
+    fn test_trips_on_error_rate() {
+        let breaker = breaker(None, Some(0.5));
+        let now = Instant::now();
+
+        for success in [true, false, true, false] {
+            breaker.record(now, Duration::ZERO, success);
+        }
+        assert!(breaker.try_acquire(now).is_ok());
+
+        breaker.record(now, Duration::ZERO, false);
+        assert!(breaker.try_acquire(now).is_err());
+    }
+
+    #[test]
+    fn test_half_open_probe() {
+        let breaker = breaker(None, Some(0.));
+        let now = Instant::now();
+
+        for _ in 0..4 {
+            breaker.record(now, Duration::ZERO, false);
+        }
+        assert!(breaker.try_acquire(now).is_err());
+
+        // A failed probe opens the breaker again
+        let later = now + Duration::from_secs(10);
+        assert!(breaker.try_acquire(later).is_ok());
+        assert!(breaker.try_acquire(later).is_err());
+        breaker.record(later, Duration::ZERO, false);
+        assert_eq!(breaker.try_acquire(later), Err(Duration::from_secs(10)));
+
+        // A successful probe closes it
+        let later = later + Duration::from_secs(10);
+        assert!(breaker.try_acquire(later).is_ok());
+        breaker.record(later, Duration::ZERO, true);
+        assert!(breaker.try_acquire(later).is_ok());
+        assert!(breaker.try_acquire(later).is_ok());
+    }
+
+    #[test]
+    fn test_unrecorded_probe() {
+        let breaker = breaker(None, Some(0.));
+        let now = Instant::now();
+
+        for _ in 0..4 {
+            breaker.record(now, Duration::ZERO, false);
+        }
+
+        // The next request is the probe if the probe isn't recorded
+        let later = now + Duration::from_secs(10);
+        assert!(breaker.try_acquire(later).is_ok());
+        breaker.release(later);
+        assert!(breaker.try_acquire(later).is_ok());
+        assert!(breaker.try_acquire(later).is_err());
+    }
+
+    #[test]
+    fn test_success() {
+        let outcome = |error: ServerError| success(&error.into_response());
+
+        assert_eq!(
+            outcome(ServerError::InternalError(anyhow::anyhow!("error"))),
+            Some(false)
+        );
+        assert_eq!(
+            outcome(ServerError::ServiceUnavailable {
+                retry_after: Duration::from_secs(1)
+            }),
+            Some(false)
+        );
+        assert_eq!(
+            outcome(ServerError::InvalidRequest("invalid".to_string())),
+            Some(true)
+        );
+        assert_eq!(
+            success(&StatusCode::GATEWAY_TIMEOUT.into_response()),
+            Some(false)
+        );
+        assert_eq!(outcome(ServerError::ModelLoading("m".to_string())), None);
+        assert_eq!(outcome(ServerError::TooManyRequestsError), None);
+    }
+}
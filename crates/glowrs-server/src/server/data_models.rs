@@ -1,43 +1,414 @@
+use axum::http::{HeaderMap, HeaderValue};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use candle_core::Tensor;
-use glowrs::Usage;
+use glowrs::{ChunkConfig, InputUsage, PoolingStrategy, Usage};
+use half::{bf16, f16};
 use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use utoipa::ToSchema;
 
-#[derive(Debug, Deserialize, Clone)]
+use crate::server::timing::Timings;
+use crate::server::ServerError;
+
+/// Version of the response schema, negotiated with the `api_version` query parameter.
+///
+/// * `v1` - The OpenAI compatible schema (default).
+/// * `v2` - Extends each embedding with the number of tokens of its input, and whether the input
+///   was truncated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ApiVersion {
+    #[default]
+    V1,
+    V2,
+}
+
+impl FromStr for ApiVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" | "1" => Ok(Self::V1),
+            "v2" | "2" => Ok(Self::V2),
+            _ => Err(format!(
+                "Unsupported API version `{s}`, expected `v1` or `v2`"
+            )),
+        }
+    }
+}
+
+/// Format the embeddings are returned in: arrays of floats, or the base64 encoding of the
+/// little-endian bytes of each embedding in its [`EmbeddingsDType`], which is smaller to send.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, ToSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum EncodingFormat {
     Float,
     Base64,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// An embedding, in an [`EncodingFormat`].
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, ToSchema)]
+#[serde(untagged)]
+pub enum Embedding {
+    Float(Vec<f32>),
+    /// Base64-encoded little-endian values
+    #[schema(value_type = String)]
+    Base64(#[serde(with = "base64_values")] EncodedValues),
+    /// Little-endian values, for binary response formats
+    #[schema(value_type = Vec<u8>)]
+    Binary(#[serde(with = "binary_values")] EncodedValues),
+}
+
+impl Embedding {
+    /// The values of the embedding, decoded if they are encoded.
+    pub fn to_vec(&self) -> Vec<f32> {
+        match self {
+            Self::Float(values) => values.clone(),
+            Self::Base64(encoded) | Self::Binary(encoded) => encoded.decode(),
+        }
+    }
+
+    /// The values of the embedding, decoding them first if they are encoded.
+    pub fn values_mut(&mut self) -> &mut Vec<f32> {
+        if !matches!(self, Self::Float(_)) {
+            *self = Self::Float(self.to_vec());
+        }
+        match self {
+            Self::Float(values) => values,
+            Self::Base64(_) | Self::Binary(_) => unreachable!("Embedding was decoded"),
+        }
+    }
+
+    /// Encode the embedding in a format, with values of a data type.
+    pub fn encode(&mut self, format: &EncodingFormat, dtype: EmbeddingsDType) {
+        let values = self.values_mut();
+        *self = match format {
+            EncodingFormat::Float => return,
+            EncodingFormat::Base64 => Self::Base64(EncodedValues::encode(values, dtype)),
+        };
+    }
+
+    /// Send the values of the embedding as bytes of a data type, unless they are
+    /// base64-encoded.
+    pub fn encode_binary(&mut self, dtype: EmbeddingsDType) {
+        if let Self::Float(values) = self {
+            *self = Self::Binary(EncodedValues::encode(values, dtype));
+        }
+    }
+}
+
+/// Little-endian bytes of the values of an encoded embedding, 4 per value, or 2 for half
+/// precision.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodedValues {
+    pub bytes: Vec<u8>,
+    /// Data type of the values. It isn't sent, as clients know it from their request, so values
+    /// are deserialized as `f32`
+    pub dtype: EmbeddingsDType,
+}
+
+impl EncodedValues {
+    fn encode(values: &[f32], dtype: EmbeddingsDType) -> Self {
+        let bytes = match dtype {
+            EmbeddingsDType::F32 => values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            EmbeddingsDType::F16 => values
+                .iter()
+                .flat_map(|&v| f16::from_f32(v).to_le_bytes())
+                .collect(),
+            EmbeddingsDType::Bf16 => values
+                .iter()
+                .flat_map(|&v| bf16::from_f32(v).to_le_bytes())
+                .collect(),
+        };
+        Self { bytes, dtype }
+    }
+
+    fn decode(&self) -> Vec<f32> {
+        match self.dtype {
+            EmbeddingsDType::F32 => self
+                .bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            EmbeddingsDType::F16 => self
+                .bytes
+                .chunks_exact(2)
+                .map(|b| f16::from_le_bytes([b[0], b[1]]).to_f32())
+                .collect(),
+            EmbeddingsDType::Bf16 => self
+                .bytes
+                .chunks_exact(2)
+                .map(|b| bf16::from_le_bytes([b[0], b[1]]).to_f32())
+                .collect(),
+        }
+    }
+
+    /// Values deserialized from bytes, which are taken to be `f32`.
+    fn from_f32_bytes(bytes: Vec<u8>) -> Self {
+        Self {
+            bytes,
+            dtype: EmbeddingsDType::F32,
+        }
+    }
+}
+
+/// (De)serialize [`EncodedValues`] as a base64 string.
+mod base64_values {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        values: &EncodedValues,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(&values.bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<EncodedValues, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let bytes = STANDARD.decode(encoded).map_err(serde::de::Error::custom)?;
+        Ok(EncodedValues::from_f32_bytes(bytes))
+    }
+}
+
+/// (De)serialize [`EncodedValues`] as a byte string.
+mod binary_values {
+    use super::*;
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        values: &EncodedValues,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serde_bytes::serialize(&values.bytes, serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<EncodedValues, D::Error> {
+        serde_bytes::deserialize(deserializer).map(EncodedValues::from_f32_bytes)
+    }
+}
+
+impl From<Vec<f32>> for Embedding {
+    fn from(values: Vec<f32>) -> Self {
+        Self::Float(values)
+    }
+}
+
+/// Type of the inputs of a request, for retrieval models that expect queries and passages with
+/// different prefixes.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum InputType {
+    Query,
+    Passage,
+}
+
+/// Data type the embeddings are returned in. Half precision embeddings are rounded, and sent as
+/// 2 bytes per value in base64 and the binary response formats, which halves their size.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbeddingsDType {
+    #[default]
+    F32,
+    F16,
+    Bf16,
+}
+
+impl EmbeddingsDType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::F32 => "f32",
+            Self::F16 => "f16",
+            Self::Bf16 => "bf16",
+        }
+    }
+
+    /// Round a value to the data type.
+    fn round(&self, value: f32) -> f32 {
+        match self {
+            Self::F32 => value,
+            Self::F16 => f16::from_f32(value).to_f32(),
+            Self::Bf16 => bf16::from_f32(value).to_f32(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 #[allow(dead_code)]
 pub struct EmbeddingsRequest {
-    pub input: Sentences,
+    pub input: EmbeddingsInput,
     pub model: String,
     pub encoding_format: Option<EncodingFormat>,
+    /// Number of dimensions to truncate the embeddings to, for models trained with Matryoshka
+    /// representation learning. The truncated embeddings are renormalized if `normalize` is set.
+    pub dimensions: Option<usize>,
+    pub user: Option<String>,
+    /// Pooling strategy to use instead of the model default (`cls`, `pooler`, `mean`,
+    /// `last-token` or `splade`)
+    pub pooling: Option<PoolingStrategy>,
+    /// Normalize the embeddings to unit length (default `false`)
+    pub normalize: Option<bool>,
+    /// Whether the inputs are queries or passages, to put the prefix configured for the model in
+    /// front of them. Rejected if the model has no prefix for the input type
+    pub input_type: Option<InputType>,
+    /// Task to embed for, for models with task adapters such as `jinaai/jina-embeddings-v3`
+    /// (e.g. `retrieval.query` or `retrieval.passage`)
+    pub task: Option<String>,
+    /// Data type to return the embeddings in (`f32`, `f16` or `bf16`)
+    pub dtype: Option<EmbeddingsDType>,
+    /// Split inputs longer than the context of the model into overlapping chunks, and combine
+    /// the embeddings of the chunks, instead of truncating them
+    pub chunking: Option<ChunkConfig>,
+}
+
+impl EmbeddingsRequest {
+    pub fn new(input: Sentences, model: String) -> Self {
+        Self {
+            input: input.into(),
+            model,
+            encoding_format: None,
+            dimensions: None,
+            user: None,
+            pooling: None,
+            normalize: None,
+            input_type: None,
+            task: None,
+            dtype: None,
+            chunking: None,
+        }
+    }
+}
+
+/// Request to embed the same inputs with several models.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct MultiEmbeddingsRequest {
+    pub input: EmbeddingsInput,
+    pub models: Vec<String>,
+    pub encoding_format: Option<EncodingFormat>,
     pub dimensions: Option<usize>,
     pub user: Option<String>,
+    pub pooling: Option<PoolingStrategy>,
+    pub normalize: Option<bool>,
+    pub input_type: Option<InputType>,
+    pub task: Option<String>,
+    pub dtype: Option<EmbeddingsDType>,
+    pub chunking: Option<ChunkConfig>,
 }
 
-#[derive(Debug, Serialize)]
+impl MultiEmbeddingsRequest {
+    /// Split into one [`EmbeddingsRequest`] per model.
+    pub fn into_requests(self) -> Vec<EmbeddingsRequest> {
+        self.models
+            .into_iter()
+            .map(|model| EmbeddingsRequest {
+                input: self.input.clone(),
+                model,
+                encoding_format: self.encoding_format.clone(),
+                dimensions: self.dimensions,
+                user: self.user.clone(),
+                pooling: self.pooling,
+                normalize: self.normalize,
+                input_type: self.input_type,
+                task: self.task.clone(),
+                dtype: self.dtype,
+                chunking: self.chunking,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct EmbeddingsResponse {
     pub object: String,
     pub data: Vec<InnerEmbeddingsResponse>,
     pub model: String,
+    /// Revision of the model that produced the embeddings, for models with a canary revision
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
     pub usage: Usage,
+    /// How the embeddings were produced
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<EmbeddingsMetadata>,
+    /// Durations of the stages of the request, returned in the `Server-Timing` header
+    #[serde(skip)]
+    pub timings: Timings,
+    /// Data type the embeddings were rounded to, which they are encoded in
+    #[serde(skip)]
+    pub dtype: EmbeddingsDType,
+}
+
+/// How embeddings were produced, so stored vectors can be traced back to the exact model and
+/// settings.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, ToSchema)]
+pub struct EmbeddingsMetadata {
+    /// Revision of the model repository
+    pub revision: String,
+    /// Commit hash of the revision, if the model was loaded from the Hugging Face cache
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// Data type of the model weights
+    pub dtype: String,
+    /// Pooling strategy, for text embedding models
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pooling: Option<PoolingStrategy>,
+    pub glowrs_version: String,
+}
+
+impl EmbeddingsMetadata {
+    /// The metadata as `x-glowrs-*` response headers.
+    pub fn headers(&self) -> HeaderMap {
+        let pooling = self
+            .pooling
+            .and_then(|pooling| serde_json::to_value(pooling).ok())
+            .and_then(|pooling| pooling.as_str().map(str::to_string));
+        let values = [
+            ("x-glowrs-revision", Some(self.revision.clone())),
+            ("x-glowrs-commit", self.commit.clone()),
+            ("x-glowrs-dtype", Some(self.dtype.clone())),
+            ("x-glowrs-pooling", pooling),
+            ("x-glowrs-version", Some(self.glowrs_version.clone())),
+        ];
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            if let Some(value) = value.and_then(|value| HeaderValue::from_str(&value).ok()) {
+                headers.insert(name, value);
+            }
+        }
+        headers
+    }
 }
 
 impl EmbeddingsResponse {
-    pub fn from_embeddings(embeddings: Tensor, usage: Usage, model: String) -> Self {
+    pub fn from_embeddings(
+        embeddings: Tensor,
+        usage: Usage,
+        inputs: Vec<InputUsage>,
+        model: String,
+    ) -> Self {
+        Self::from_vectors(embeddings.to_vec2().unwrap(), usage, inputs, model)
+    }
+
+    pub fn from_vectors(
+        embeddings: Vec<Vec<f32>>,
+        usage: Usage,
+        inputs: Vec<InputUsage>,
+        model: String,
+    ) -> Self {
         let inner_responses: Vec<InnerEmbeddingsResponse> = embeddings
-            .to_vec2()
-            .unwrap()
             .into_iter()
+            .zip(inputs)
             .enumerate()
-            .map(|(index, embedding)| InnerEmbeddingsResponse {
+            .map(|(index, (embedding, input))| InnerEmbeddingsResponse {
                 object: "core".to_string(),
-                embedding,
+                embedding: embedding.into(),
                 index: index as u32,
+                tokens: Some(input.tokens),
+                truncated: Some(input.truncated),
             })
             .collect();
 
@@ -45,19 +416,164 @@ impl EmbeddingsResponse {
             object: "list".to_string(),
             data: inner_responses,
             model,
+            revision: None,
             usage,
+            metadata: None,
+            timings: Timings::default(),
+            dtype: EmbeddingsDType::F32,
+        }
+    }
+
+    /// Number of tokens of all inputs, before the response is restricted to an API version.
+    pub fn total_tokens(&self) -> u64 {
+        self.data
+            .iter()
+            .filter_map(|inner| inner.tokens)
+            .map(u64::from)
+            .sum()
+    }
+
+    /// Truncate the embeddings to their first `dimensions` values, and renormalize them if
+    /// `normalize` is set.
+    pub fn truncate(&mut self, dimensions: usize, normalize: bool) -> Result<(), ServerError> {
+        for inner in self.data.iter_mut() {
+            let embedding = inner.embedding.values_mut();
+            if dimensions == 0 || dimensions > embedding.len() {
+                return Err(ServerError::InvalidRequest(format!(
+                    "`dimensions` must be between 1 and {}",
+                    embedding.len()
+                )));
+            }
+            embedding.truncate(dimensions);
+            if normalize {
+                self::normalize(embedding);
+            }
         }
+        Ok(())
     }
+
+    /// Round the embeddings to a data type, which they are then encoded in.
+    pub fn round_to(&mut self, dtype: EmbeddingsDType) {
+        for inner in self.data.iter_mut() {
+            inner
+                .embedding
+                .values_mut()
+                .iter_mut()
+                .for_each(|value| *value = dtype.round(*value));
+        }
+        self.dtype = dtype;
+    }
+
+    /// Encode the embeddings in a format.
+    pub fn encode(&mut self, format: &EncodingFormat) {
+        for inner in self.data.iter_mut() {
+            inner.embedding.encode(format, self.dtype);
+        }
+    }
+
+    /// Restrict the response to the schema of an API version.
+    pub fn into_version(mut self, version: ApiVersion) -> Self {
+        if version == ApiVersion::V1 {
+            for inner in self.data.iter_mut() {
+                inner.tokens = None;
+                inner.truncated = None;
+            }
+        }
+        self
+    }
+}
+
+/// Embeddings of the same inputs by several models, in the order the models were requested.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MultiEmbeddingsResponse {
+    pub object: String,
+    pub data: Vec<EmbeddingsResponse>,
 }
 
-#[derive(Debug, Serialize)]
+impl From<Vec<EmbeddingsResponse>> for MultiEmbeddingsResponse {
+    fn from(data: Vec<EmbeddingsResponse>) -> Self {
+        Self {
+            object: "list".to_string(),
+            data,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct InnerEmbeddingsResponse {
     pub object: String,
-    pub embedding: Vec<f32>,
+    pub embedding: Embedding,
     pub index: u32,
+    /// Number of tokens of the input (v2)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<u32>,
+    /// Whether the input was truncated to the maximum sequence length of the model (v2)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncated: Option<bool>,
+}
+
+/// Inputs of an embeddings request: texts, token ids, or a list of texts and images.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(untagged)]
+pub enum EmbeddingsInput {
+    Text(Sentences),
+    /// Inputs that are already tokenized, e.g. by `/v1/tokenize`. The ids are embedded as they
+    /// are, so they should include the special tokens of the model
+    TokenIds(TokenIds),
+    /// Texts and images, for multimodal models. Text-only models accept texts in this form too
+    Multimodal(Vec<MultimodalInput>),
+}
+
+impl EmbeddingsInput {
+    /// Take the texts to embed, failing if there are image inputs.
+    pub fn into_texts(self) -> Result<Vec<String>, ServerError> {
+        match self {
+            EmbeddingsInput::Text(sentences) => Ok(sentences.into()),
+            EmbeddingsInput::TokenIds(_) => Err(ServerError::InvalidRequest(
+                "Token id inputs aren't supported for this request".to_string(),
+            )),
+            EmbeddingsInput::Multimodal(inputs) => inputs
+                .into_iter()
+                .map(|input| match input {
+                    MultimodalInput::Text { text } => Ok(text),
+                    MultimodalInput::Image { .. } => Err(ServerError::InvalidRequest(
+                        "Image inputs are only supported by multimodal models".to_string(),
+                    )),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn has_images(&self) -> bool {
+        match self {
+            EmbeddingsInput::Text(_) | EmbeddingsInput::TokenIds(_) => false,
+            EmbeddingsInput::Multimodal(inputs) => inputs
+                .iter()
+                .any(|input| matches!(input, MultimodalInput::Image { .. })),
+        }
+    }
+}
+
+impl From<Sentences> for EmbeddingsInput {
+    fn from(sentences: Sentences) -> Self {
+        Self::Text(sentences)
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(untagged)]
+pub enum MultimodalInput {
+    Text {
+        text: String,
+    },
+    /// A `data:` URL or the base64-encoded bytes of a PNG, JPEG or PPM image, or an `http(s)` URL
+    /// with `--allow-image-urls`
+    Image {
+        image: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 #[serde(untagged)]
 pub enum Sentences {
     Single(String),
@@ -90,3 +606,452 @@ impl From<Sentences> for Vec<String> {
         }
     }
 }
+
+fn default_true() -> bool {
+    true
+}
+
+/// Request to tokenize inputs with the tokenizer of a model, without embedding them.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct TokenizeRequest {
+    pub input: Sentences,
+    pub model: String,
+    /// Whether to add the special tokens of the model, such as `[CLS]` and `[SEP]`
+    #[serde(default = "default_true")]
+    pub add_special_tokens: bool,
+}
+
+/// A token of a tokenized input.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, ToSchema)]
+pub struct Token {
+    pub id: u32,
+    /// The token in the vocabulary of the tokenizer, e.g. `##ing`
+    pub text: String,
+    /// Whether the token is a special token, such as `[CLS]`
+    pub special: bool,
+    /// Byte offset of the start of the token in the input, except for special tokens
+    pub start: Option<usize>,
+    /// Byte offset of the end of the token in the input, except for special tokens
+    pub stop: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, ToSchema)]
+pub struct TokenizedInput {
+    pub index: u32,
+    pub tokens: Vec<Token>,
+    /// Whether the input was truncated to the maximum sequence length of the model
+    pub truncated: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TokenizeResponse {
+    pub object: String,
+    pub data: Vec<TokenizedInput>,
+    pub model: String,
+}
+
+/// Token ids of one or more inputs.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(untagged)]
+pub enum TokenIds {
+    Single(Vec<u32>),
+    Multiple(Vec<Vec<u32>>),
+}
+
+impl From<TokenIds> for Vec<Vec<u32>> {
+    fn from(ids: TokenIds) -> Self {
+        match ids {
+            TokenIds::Single(ids) => vec![ids],
+            TokenIds::Multiple(ids) => ids,
+        }
+    }
+}
+
+/// Request to decode token ids into text with the tokenizer of a model.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct DecodeRequest {
+    pub ids: TokenIds,
+    pub model: String,
+    /// Whether to leave the special tokens of the model out of the text
+    #[serde(default = "default_true")]
+    pub skip_special_tokens: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct DecodeResponse {
+    pub object: String,
+    /// The decoded text of each list of token ids
+    pub data: Vec<String>,
+    pub model: String,
+}
+
+/// Request of the text-embeddings-inference (TEI) compatible `/embed` and `/embed_sparse`
+/// routes.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct TeiEmbedRequest {
+    pub inputs: Sentences,
+    /// Model to embed with, defaults to the first served model. Not part of the TEI API, which
+    /// serves a single model.
+    pub model: Option<String>,
+    /// Normalize the embeddings to unit length (`/embed` only)
+    #[serde(default = "default_true")]
+    pub normalize: bool,
+    /// Truncate inputs longer than the maximum sequence length of the model, instead of
+    /// rejecting the request
+    #[serde(default = "default_true")]
+    pub truncate: bool,
+    /// Number of dimensions to truncate the embeddings to (`/embed` only)
+    pub dimensions: Option<usize>,
+}
+
+impl TeiEmbedRequest {
+    /// The equivalent embeddings request for `model`.
+    pub fn to_request(&self, model: String) -> EmbeddingsRequest {
+        EmbeddingsRequest {
+            dimensions: self.dimensions,
+            ..EmbeddingsRequest::new(self.inputs.clone(), model)
+        }
+    }
+}
+
+/// Message of the WebSocket route `/v1/embeddings/ws`, with inputs to embed.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct StreamEmbeddingsRequest {
+    pub input: Sentences,
+    /// Model to embed with, defaults to the model of the connection
+    pub model: Option<String>,
+    /// Number of dimensions to truncate the embeddings to
+    pub dimensions: Option<usize>,
+}
+
+impl StreamEmbeddingsRequest {
+    /// The equivalent embeddings request for `model`.
+    pub fn to_request(&self, model: String) -> EmbeddingsRequest {
+        EmbeddingsRequest {
+            dimensions: self.dimensions,
+            ..EmbeddingsRequest::new(self.input.clone(), model)
+        }
+    }
+}
+
+/// Non-zero value of a sparse embedding.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, ToSchema)]
+pub struct SparseValue {
+    /// Index in the vocabulary of the model
+    pub index: u32,
+    pub value: f32,
+}
+
+/// The non-zero values of an embedding.
+pub fn sparse_values(embedding: &[f32]) -> Vec<SparseValue> {
+    embedding
+        .iter()
+        .enumerate()
+        .filter(|(_, &value)| value != 0.)
+        .map(|(index, &value)| SparseValue {
+            index: index as u32,
+            value,
+        })
+        .collect()
+}
+
+/// Normalize an embedding to unit length.
+pub fn normalize(embedding: &mut [f32]) {
+    let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0. {
+        embedding.iter_mut().for_each(|v| *v /= norm);
+    }
+}
+
+/// Request to classify inputs with a sequence classification model.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct ClassifyRequest {
+    pub input: Sentences,
+    pub model: String,
+}
+
+/// Score of a label of a classification model.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, ToSchema)]
+pub struct LabelScore {
+    /// Name of the label, from the `id2label` of the model configuration
+    pub label: String,
+    pub score: f32,
+}
+
+impl From<glowrs::core::classifier::LabelScore> for LabelScore {
+    fn from(label_score: glowrs::core::classifier::LabelScore) -> Self {
+        Self {
+            label: label_score.label,
+            score: label_score.score,
+        }
+    }
+}
+
+/// The labels of an input, from the highest score to the lowest.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Classification {
+    pub object: String,
+    pub index: u32,
+    pub labels: Vec<LabelScore>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ClassifyResponse {
+    pub object: String,
+    pub data: Vec<Classification>,
+    pub model: String,
+}
+
+impl ClassifyResponse {
+    pub fn from_predictions(
+        predictions: Vec<Vec<glowrs::core::classifier::LabelScore>>,
+        model: String,
+    ) -> Self {
+        let data = predictions
+            .into_iter()
+            .enumerate()
+            .map(|(index, labels)| Classification {
+                object: "classification".to_string(),
+                index: index as u32,
+                labels: labels.into_iter().map(LabelScore::from).collect(),
+            })
+            .collect();
+
+        Self {
+            object: "list".to_string(),
+            data,
+            model,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_api_version() {
+        assert_eq!("v1".parse(), Ok(ApiVersion::V1));
+        assert_eq!("2".parse(), Ok(ApiVersion::V2));
+        assert!("v3".parse::<ApiVersion>().is_err());
+    }
+
+    #[test]
+    fn test_truncate() {
+        let response = || {
+            EmbeddingsResponse::from_vectors(
+                vec![vec![3., 4., 12.]],
+                Usage::default(),
+                vec![InputUsage::default()],
+                "m".to_string(),
+            )
+        };
+        assert!(response().truncate(0, true).is_err());
+        assert!(response().truncate(4, true).is_err());
+
+        let mut normalized = response();
+        normalized.truncate(2, true).unwrap();
+        assert_eq!(normalized.data[0].embedding.to_vec(), vec![0.6, 0.8]);
+
+        let mut unnormalized = response();
+        unnormalized.truncate(2, false).unwrap();
+        assert_eq!(unnormalized.data[0].embedding.to_vec(), vec![3., 4.]);
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut response = EmbeddingsResponse::from_vectors(
+            vec![vec![1., -2.]],
+            Usage::default(),
+            vec![InputUsage::default()],
+            "m".to_string(),
+        );
+        response.encode(&EncodingFormat::Base64);
+        assert!(matches!(response.data[0].embedding, Embedding::Base64(_)));
+        assert_eq!(response.data[0].embedding.to_vec(), vec![1., -2.]);
+        assert_eq!(
+            serde_json::to_string(&response.data[0].embedding).unwrap(),
+            r#""AACAPwAAAMA=""#
+        );
+
+        // Base64-encoded embeddings are decoded to be truncated
+        response.truncate(1, false).unwrap();
+        assert_eq!(response.data[0].embedding, Embedding::Float(vec![1.]));
+    }
+
+    #[test]
+    fn test_tei_embed_request() {
+        let request: TeiEmbedRequest =
+            serde_json::from_value(serde_json::json!({"inputs": "Hello"})).unwrap();
+        assert!(request.normalize && request.truncate);
+        assert_eq!(request.model, None);
+
+        let request = request.to_request("m".to_string());
+        assert_eq!(request.model, "m");
+        assert_eq!(request.input.into_texts().unwrap(), vec!["Hello"]);
+
+        assert_eq!(
+            sparse_values(&[0., 1.5, 0., 0.25]),
+            vec![
+                SparseValue {
+                    index: 1,
+                    value: 1.5
+                },
+                SparseValue {
+                    index: 3,
+                    value: 0.25
+                }
+            ]
+        );
+
+        let mut embedding = vec![3., 4.];
+        normalize(&mut embedding);
+        assert_eq!(embedding, vec![0.6, 0.8]);
+    }
+
+    #[test]
+    fn test_classify_response() {
+        let label = |label: &str, score| glowrs::core::classifier::LabelScore {
+            label: label.to_string(),
+            score,
+        };
+        let response = ClassifyResponse::from_predictions(
+            vec![
+                vec![label("positive", 0.9), label("negative", 0.1)],
+                vec![label("negative", 0.7), label("positive", 0.3)],
+            ],
+            "m".to_string(),
+        );
+
+        assert_eq!(response.data.len(), 2);
+        assert_eq!(response.data[1].index, 1);
+        assert_eq!(
+            response.data[1].labels[0],
+            LabelScore {
+                label: "negative".to_string(),
+                score: 0.7
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(&response.data[0]).unwrap()["labels"][0],
+            serde_json::json!({"label": "positive", "score": 0.9f32})
+        );
+    }
+
+    #[test]
+    fn test_round_to() {
+        let values = vec![0.1234568, -0.9876543, 1e-3];
+        let response = || {
+            EmbeddingsResponse::from_vectors(
+                vec![values.clone()],
+                Usage::default(),
+                vec![InputUsage::default()],
+                "m".to_string(),
+            )
+        };
+
+        let mut rounded = response();
+        rounded.round_to(EmbeddingsDType::F16);
+        let expected: Vec<f32> = values.iter().map(|&v| f16::from_f32(v).to_f32()).collect();
+        assert_eq!(rounded.data[0].embedding.to_vec(), expected);
+
+        // Encoded half precision values take 2 bytes each
+        rounded.encode(&EncodingFormat::Base64);
+        let Embedding::Base64(encoded) = &rounded.data[0].embedding else {
+            panic!("Embedding should be base64-encoded");
+        };
+        assert_eq!(encoded.bytes.len(), 2 * values.len());
+        let expected_bytes: Vec<u8> = values
+            .iter()
+            .flat_map(|&v| f16::from_f32(v).to_le_bytes())
+            .collect();
+        assert_eq!(
+            serde_json::to_string(&rounded.data[0].embedding).unwrap(),
+            format!("\"{}\"", STANDARD.encode(expected_bytes))
+        );
+        assert_eq!(rounded.data[0].embedding.to_vec(), expected);
+
+        let mut rounded = response();
+        rounded.round_to(EmbeddingsDType::Bf16);
+        rounded.data[0].embedding.encode_binary(rounded.dtype);
+        let expected: Vec<f32> = values.iter().map(|&v| bf16::from_f32(v).to_f32()).collect();
+        assert_eq!(rounded.data[0].embedding.to_vec(), expected);
+    }
+
+    #[test]
+    fn test_embeddings_input() {
+        let request: EmbeddingsRequest =
+            serde_json::from_str(r#"{"input": ["a", "b"], "model": "m"}"#).unwrap();
+        assert!(matches!(request.input, EmbeddingsInput::Text(_)));
+        assert_eq!(request.input.into_texts().unwrap(), vec!["a", "b"]);
+
+        let request: EmbeddingsRequest = serde_json::from_str(
+            r#"{"input": [{"text": "a"}, {"image": "https://example.com/cat.png"}], "model": "m"}"#,
+        )
+        .unwrap();
+        assert!(request.input.has_images());
+        assert!(request.input.into_texts().is_err());
+
+        let request: EmbeddingsRequest =
+            serde_json::from_str(r#"{"input": [{"text": "a"}], "model": "m"}"#).unwrap();
+        assert!(!request.input.has_images());
+        assert_eq!(request.input.into_texts().unwrap(), vec!["a"]);
+
+        let request: EmbeddingsRequest =
+            serde_json::from_str(r#"{"input": [[101, 2023, 102], [101, 102]], "model": "m"}"#)
+                .unwrap();
+        assert!(matches!(
+            request.input,
+            EmbeddingsInput::TokenIds(TokenIds::Multiple(_))
+        ));
+        assert!(request.input.into_texts().is_err());
+
+        let request: EmbeddingsRequest =
+            serde_json::from_str(r#"{"input": [101, 2023, 102], "model": "m"}"#).unwrap();
+        let EmbeddingsInput::TokenIds(ids) = request.input else {
+            panic!("Expected token ids");
+        };
+        assert_eq!(Vec::<Vec<u32>>::from(ids), vec![vec![101, 2023, 102]]);
+    }
+
+    #[test]
+    fn test_response_versions() {
+        let response = || {
+            EmbeddingsResponse::from_vectors(
+                vec![vec![0.5, 0.5]],
+                Usage::default(),
+                vec![InputUsage {
+                    tokens: 7,
+                    truncated: true,
+                }],
+                "model".to_string(),
+            )
+        };
+
+        let v1 = serde_json::to_value(response().into_version(ApiVersion::V1)).unwrap();
+        assert!(v1["data"][0].get("tokens").is_none());
+        assert!(v1["data"][0].get("truncated").is_none());
+
+        let v2 = serde_json::to_value(response().into_version(ApiVersion::V2)).unwrap();
+        assert_eq!(v2["data"][0]["tokens"], 7);
+        assert_eq!(v2["data"][0]["truncated"], true);
+    }
+
+    #[test]
+    fn test_metadata_headers() {
+        let metadata = EmbeddingsMetadata {
+            revision: "main".to_string(),
+            commit: None,
+            dtype: "f16".to_string(),
+            pooling: Some(PoolingStrategy::Mean),
+            glowrs_version: glowrs::VERSION.to_string(),
+        };
+
+        let headers = metadata.headers();
+        assert_eq!(headers["x-glowrs-revision"], "main");
+        assert!(headers.get("x-glowrs-commit").is_none());
+        assert_eq!(headers["x-glowrs-dtype"], "f16");
+        assert_eq!(headers["x-glowrs-pooling"], "mean");
+        assert_eq!(headers["x-glowrs-version"], glowrs::VERSION);
+    }
+}
@@ -0,0 +1,57 @@
+//! Device selection
+//!
+//! Models run on the accelerator the server is compiled for (CUDA or Metal), or on the device
+//! given with `--device`, e.g. `cuda:1` on hosts with several GPUs. If it fails to initialize, or
+//! a model fails to load on it with a device error such as running out of memory, the server
+//! falls back to the CPU with a warning instead of aborting, unless that is disabled.
+
+use anyhow::Result;
+use candle_core::{Device, DeviceLocation};
+use clap::Args;
+use glowrs::core::device::{load_with_fallback, select_device_spec, DeviceSpec};
+
+#[derive(Debug, Args)]
+pub struct DeviceArgs {
+    /// Device to run the models on: `cpu`, `cuda[:<index>]` or `metal[:<index>]`. Defaults to
+    /// the first device of the accelerator the server is compiled for
+    #[clap(long)]
+    pub device: Option<DeviceSpec>,
+
+    /// Fail instead of falling back to the CPU when the accelerator fails to initialize or a
+    /// model fails to load on it
+    #[clap(long)]
+    pub no_device_fallback: bool,
+}
+
+/// Device to load the models on.
+#[derive(Debug, Clone)]
+pub struct DeviceConfig {
+    pub device: Device,
+    /// Whether to fall back to the CPU if a model fails to load on the device
+    pub fallback: bool,
+}
+
+impl DeviceConfig {
+    pub fn from_args(args: &DeviceArgs) -> Result<Self> {
+        let fallback = !args.no_device_fallback;
+
+        Ok(Self {
+            device: select_device_spec(args.device.unwrap_or_else(DeviceSpec::compiled), fallback)?,
+            fallback,
+        })
+    }
+
+    /// Load a model on the device with `load`, falling back to the CPU if enabled.
+    pub fn load<T>(&self, load: impl Fn(&Device) -> Result<T>) -> Result<T> {
+        load_with_fallback(&self.device, self.fallback, load)
+    }
+}
+
+/// Name of a device, such as `cpu` or `cuda:0`.
+pub fn device_name(device: &Device) -> String {
+    match device.location() {
+        DeviceLocation::Cpu => "cpu".to_string(),
+        DeviceLocation::Cuda { gpu_id } => format!("cuda:{gpu_id}"),
+        DeviceLocation::Metal { gpu_id } => format!("metal:{gpu_id}"),
+    }
+}
@@ -0,0 +1,200 @@
+//! Binary response formats
+//!
+//! Embedding responses are JSON unless the request asks for MessagePack with
+//! `Accept: application/msgpack` or CBOR with `Accept: application/cbor`. These formats send
+//! each embedding as a byte string of little-endian values rather than as an array of floats,
+//! which is much smaller and faster to parse. The values are `f32`, or 2-byte `f16` or `bf16`
+//! with the `dtype` of the request. Embeddings requested with
+//! `encoding_format: "base64"` are sent as base64 strings in any format. `/v1/embeddings` also
+//! returns Arrow IPC streams, see [`arrow`](crate::server::arrow).
+
+use axum::body::Body;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use bytes::Bytes;
+use serde::Serialize;
+
+use crate::server::arrow::{self, ARROW_STREAM_CONTENT_TYPE};
+use crate::server::data_models::EmbeddingsResponse;
+use crate::server::ServerError;
+
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+pub const CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+/// Format a response is serialized in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResponseFormat {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+    /// Only for single embeddings responses
+    Arrow,
+}
+
+impl ResponseFormat {
+    /// The format of the first supported media type in the `Accept` header. Responses are JSON
+    /// if the header names none, so quality values aren't taken into account.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .find_map(|media_type| {
+                let media_type = media_type.split(';').next().unwrap_or_default().trim();
+                match media_type.to_ascii_lowercase().as_str() {
+                    "application/json" => Some(Self::Json),
+                    MSGPACK_CONTENT_TYPE | "application/x-msgpack" | "application/vnd.msgpack" => {
+                        Some(Self::MessagePack)
+                    }
+                    CBOR_CONTENT_TYPE => Some(Self::Cbor),
+                    ARROW_STREAM_CONTENT_TYPE => Some(Self::Arrow),
+                    _ => None,
+                }
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::MessagePack => MSGPACK_CONTENT_TYPE,
+            Self::Cbor => CBOR_CONTENT_TYPE,
+            Self::Arrow => ARROW_STREAM_CONTENT_TYPE,
+        }
+    }
+
+    /// Prepare the embeddings of a response for the format, sending them as bytes in MessagePack
+    /// and CBOR.
+    pub fn prepare(&self, response: &mut EmbeddingsResponse) {
+        if matches!(self, Self::MessagePack | Self::Cbor) {
+            for inner in response.data.iter_mut() {
+                inner.embedding.encode_binary(response.dtype);
+            }
+        }
+    }
+
+    /// Serialize an embeddings response, which can also be an Arrow IPC stream.
+    pub fn serialize_embeddings(
+        &self,
+        response: &EmbeddingsResponse,
+    ) -> Result<Bytes, ServerError> {
+        match self {
+            Self::Arrow => arrow::to_ipc_stream(response)
+                .map(Bytes::from)
+                .map_err(ServerError::InternalError),
+            _ => self.serialize(response),
+        }
+    }
+
+    /// Serialize a response. Arrow IPC streams are only supported for embeddings responses, see
+    /// [`Self::serialize_embeddings`].
+    pub fn serialize<T: Serialize>(&self, value: &T) -> Result<Bytes, ServerError> {
+        let bytes = match self {
+            Self::Json => serde_json::to_vec(value).map_err(anyhow::Error::from),
+            // Structs are serialized as maps, so the fields are named like in JSON
+            Self::MessagePack => rmp_serde::to_vec_named(value).map_err(anyhow::Error::from),
+            Self::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes)
+                    .map(|_| bytes)
+                    .map_err(anyhow::Error::from)
+            }
+            Self::Arrow => Err(anyhow::anyhow!("Response can't be serialized as Arrow")),
+        };
+        bytes.map(Bytes::from).map_err(ServerError::InternalError)
+    }
+
+    /// A `200 OK` response with a body serialized in the format.
+    pub fn response(&self, body: Bytes) -> Response {
+        let mut response = (StatusCode::OK, Body::from(body)).into_response();
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(self.content_type()),
+        );
+        response
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::data_models::{Embedding, EmbeddingsDType};
+    use glowrs::{InputUsage, Usage};
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_from_headers() {
+        assert_eq!(
+            ResponseFormat::from_headers(&HeaderMap::new()),
+            ResponseFormat::Json
+        );
+        assert_eq!(
+            ResponseFormat::from_headers(&accept("application/msgpack")),
+            ResponseFormat::MessagePack
+        );
+        assert_eq!(
+            ResponseFormat::from_headers(&accept("text/html, application/CBOR;q=0.9, */*")),
+            ResponseFormat::Cbor
+        );
+        assert_eq!(
+            ResponseFormat::from_headers(&accept("application/json, application/msgpack")),
+            ResponseFormat::Json
+        );
+        assert_eq!(
+            ResponseFormat::from_headers(&accept("*/*")),
+            ResponseFormat::Json
+        );
+        assert_eq!(
+            ResponseFormat::from_headers(&accept("application/vnd.apache.arrow.stream")),
+            ResponseFormat::Arrow
+        );
+    }
+
+    #[test]
+    fn test_serialize() -> anyhow::Result<()> {
+        let values = vec![0.5f32, -1.25, 3.0];
+        let response = || {
+            EmbeddingsResponse::from_vectors(
+                vec![values.clone()],
+                Usage::default(),
+                vec![InputUsage::default()],
+                "model".to_string(),
+            )
+        };
+
+        let json = ResponseFormat::Json.serialize(&response())?;
+        for format in [ResponseFormat::MessagePack, ResponseFormat::Cbor] {
+            let mut binary = response();
+            format.prepare(&mut binary);
+            let bytes = format.serialize(&binary)?;
+            assert!(bytes.len() < json.len());
+
+            let decoded: EmbeddingsResponse = match format {
+                ResponseFormat::MessagePack => rmp_serde::from_slice(&bytes)?,
+                _ => ciborium::from_reader(bytes.as_ref())?,
+            };
+            assert!(matches!(decoded.data[0].embedding, Embedding::Binary(_)));
+            assert_eq!(decoded.data[0].embedding.to_vec(), values);
+            assert_eq!(decoded.model, "model");
+
+            // Half precision values are sent as 2 bytes each
+            let mut half = response();
+            half.round_to(EmbeddingsDType::F16);
+            format.prepare(&mut half);
+            let Embedding::Binary(encoded) = &half.data[0].embedding else {
+                panic!("Embedding should be sent as bytes");
+            };
+            assert_eq!(encoded.bytes.len(), 2 * values.len());
+            assert_eq!(half.data[0].embedding.to_vec(), values);
+        }
+
+        Ok(())
+    }
+}
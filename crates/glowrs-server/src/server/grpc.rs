@@ -0,0 +1,270 @@
+//! gRPC interface
+//!
+//! With `--grpc-address`, the `glowrs.v1.Embeddings` service of `proto/glowrs.proto` is served
+//! alongside the HTTP API, for service-to-service calls. It shares the state of the HTTP API, so
+//! requests go through the same models, queues, hooks and token quotas.
+
+use anyhow::{Context, Result};
+use clap::Args;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+use crate::server::data_models::{normalize, EmbeddingsRequest, Sentences};
+use crate::server::routes::tokenize::tokenize_inputs;
+use crate::server::state::ServerState;
+use crate::server::tls::{self, TlsArgs};
+use crate::server::utils;
+use crate::server::ServerError;
+
+pub mod proto {
+    tonic::include_proto!("glowrs.v1");
+}
+
+use proto::embeddings_server::{Embeddings, EmbeddingsServer};
+
+#[derive(Debug, Args)]
+pub struct GrpcArgs {
+    /// Address to serve the gRPC interface on, e.g. `127.0.0.1:50051`. Disabled if not set
+    #[clap(long)]
+    pub grpc_address: Option<SocketAddr>,
+}
+
+impl From<ServerError> for Status {
+    fn from(err: ServerError) -> Self {
+        let message = err.to_string();
+        match err {
+            ServerError::InvalidRequest(_) | ServerError::LimitExceeded(_) => {
+                Status::invalid_argument(message)
+            }
+            ServerError::ModelNotFound => Status::not_found(message),
+            ServerError::TooManyRequestsError | ServerError::QuotaExceeded(_) => {
+                Status::resource_exhausted(message)
+            }
+            ServerError::ServiceUnavailable { .. } | ServerError::ModelLoading(_) => {
+                Status::unavailable(message)
+            }
+            _ => Status::internal(message),
+        }
+    }
+}
+
+/// The texts ranked by the cosine similarity of their embeddings to that of the query, from the
+/// most similar to the least.
+fn rank(query: &[f32], texts: Vec<Vec<f32>>) -> Vec<proto::Rank> {
+    let mut query = query.to_vec();
+    normalize(&mut query);
+
+    let mut ranks: Vec<proto::Rank> = texts
+        .into_iter()
+        .enumerate()
+        .map(|(index, mut text)| {
+            normalize(&mut text);
+            proto::Rank {
+                index: index as u32,
+                score: query.iter().zip(&text).map(|(q, t)| q * t).sum(),
+            }
+        })
+        .collect();
+    ranks.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    ranks
+}
+
+/// Serves the `Embeddings` service with the state of the server.
+pub struct EmbeddingsService {
+    state: Arc<ServerState>,
+}
+
+impl EmbeddingsService {
+    /// The model of a request, where an empty model is the default model.
+    fn model(&self, model: String) -> Result<String, ServerError> {
+        self.state
+            .model_or_default(Some(model).filter(|model| !model.is_empty()))
+    }
+}
+
+#[tonic::async_trait]
+impl Embeddings for EmbeddingsService {
+    async fn embed(
+        &self,
+        request: Request<proto::EmbedRequest>,
+    ) -> Result<Response<proto::EmbedResponse>, Status> {
+        let headers = request.metadata().clone().into_headers();
+        let request = request.into_inner();
+        let model = self.model(request.model)?;
+
+        let embeddings_request = EmbeddingsRequest {
+            dimensions: request.dimensions.map(|dimensions| dimensions as usize),
+            ..EmbeddingsRequest::new(Sentences::Multiple(request.inputs), model.clone())
+        };
+        let response = self
+            .state
+            .embed("/glowrs.v1.Embeddings/Embed", &headers, embeddings_request)
+            .await?;
+
+        let total_tokens = response.total_tokens();
+        let embeddings = response
+            .data
+            .into_iter()
+            .map(|inner| {
+                let mut values = inner.embedding.to_vec();
+                if request.normalize {
+                    normalize(&mut values);
+                }
+                proto::Embedding {
+                    values,
+                    tokens: inner.tokens.unwrap_or_default(),
+                    truncated: inner.truncated.unwrap_or_default(),
+                }
+            })
+            .collect();
+
+        Ok(Response::new(proto::EmbedResponse {
+            model,
+            embeddings,
+            total_tokens,
+        }))
+    }
+
+    async fn rerank(
+        &self,
+        request: Request<proto::RerankRequest>,
+    ) -> Result<Response<proto::RerankResponse>, Status> {
+        let headers = request.metadata().clone().into_headers();
+        let request = request.into_inner();
+        let model = self.model(request.model)?;
+
+        // Embed the query along with the texts, in one request
+        let mut inputs = vec![request.query];
+        inputs.extend(request.texts);
+        let response = self
+            .state
+            .embed(
+                "/glowrs.v1.Embeddings/Rerank",
+                &headers,
+                EmbeddingsRequest::new(Sentences::Multiple(inputs), model.clone()),
+            )
+            .await?;
+
+        let total_tokens = response.total_tokens();
+        let mut embeddings = response.data.iter().map(|inner| inner.embedding.to_vec());
+        let query = embeddings.next().unwrap_or_default();
+        let mut ranks = rank(&query, embeddings.collect());
+        if let Some(top_n) = request.top_n {
+            ranks.truncate(top_n as usize);
+        }
+
+        Ok(Response::new(proto::RerankResponse {
+            model,
+            ranks,
+            total_tokens,
+        }))
+    }
+
+    async fn tokenize(
+        &self,
+        request: Request<proto::TokenizeRequest>,
+    ) -> Result<Response<proto::TokenizeResponse>, Status> {
+        let request = request.into_inner();
+        let model = self.model(request.model)?;
+        let (client, _) = self.state.client(&model).await?;
+        let tokenizer = client.tokenizer().clone();
+
+        let add_special_tokens = request.add_special_tokens;
+        let inputs = tokio::task::spawn_blocking(move || {
+            tokenize_inputs(&tokenizer, request.inputs, add_special_tokens)
+        })
+        .await
+        .map_err(|err| Status::internal(err.to_string()))?
+        .map_err(|err| Status::internal(err.to_string()))?;
+
+        let inputs = inputs
+            .into_iter()
+            .map(|input| proto::TokenizedInput {
+                tokens: input
+                    .tokens
+                    .into_iter()
+                    .map(|token| proto::Token {
+                        id: token.id,
+                        text: token.text,
+                        special: token.special,
+                        start: token.start.map(|start| start as u32),
+                        stop: token.stop.map(|stop| stop as u32),
+                    })
+                    .collect(),
+                truncated: input.truncated,
+            })
+            .collect();
+
+        Ok(Response::new(proto::TokenizeResponse { model, inputs }))
+    }
+}
+
+/// Serve the gRPC interface in the background, if an address is configured, until the server
+/// shuts down. It is served with TLS like the HTTP API if a certificate is configured, and the
+/// address is bound up front, so the server doesn't start if it is taken.
+pub fn spawn_grpc(args: &GrpcArgs, tls_args: &TlsArgs, state: Arc<ServerState>) -> Result<()> {
+    let Some(address) = args.grpc_address else {
+        return Ok(());
+    };
+
+    let mut server = Server::builder();
+    let tls_config = tls::grpc_config(tls_args)?;
+    let tls = tls_config.is_some();
+    if let Some(tls_config) = tls_config {
+        server = server
+            .tls_config(tls_config)
+            .context("Invalid TLS configuration for gRPC")?;
+    }
+    let incoming = TcpIncoming::new(address, true, None)
+        .map_err(|err| anyhow::anyhow!(err))
+        .with_context(|| format!("Failed to bind the gRPC address {address}"))?;
+
+    tracing::info!(
+        "gRPC listening on {address}{}",
+        if tls { " (TLS)" } else { "" }
+    );
+    tokio::spawn(async move {
+        let result = server
+            .add_service(EmbeddingsServer::new(EmbeddingsService { state }))
+            .serve_with_incoming_shutdown(incoming, utils::shutdown_signal(None))
+            .await;
+        if let Err(err) = result {
+            tracing::error!("gRPC server failed: {err}");
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rank() {
+        let ranks = rank(&[1., 0.], vec![vec![0., 2.], vec![3., 0.], vec![1., 1.]]);
+
+        let indices: Vec<u32> = ranks.iter().map(|rank| rank.index).collect();
+        assert_eq!(indices, vec![1, 2, 0]);
+        assert!((ranks[0].score - 1.).abs() < 1e-6);
+        assert!((ranks[1].score - 0.5f32.sqrt()).abs() < 1e-6);
+        assert_eq!(ranks[2].score, 0.);
+    }
+
+    #[test]
+    fn test_status() {
+        let status = Status::from(ServerError::InvalidRequest("bad".to_string()));
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(
+            Status::from(ServerError::TooManyRequestsError).code(),
+            tonic::Code::ResourceExhausted
+        );
+        assert_eq!(
+            Status::from(ServerError::ModelNotFound).code(),
+            tonic::Code::NotFound
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! Route hooks
+//!
+//! Hooks run custom code on the embeddings routes without changing them: before a request is
+//! tokenized, for validation or enrichment, and after the embeddings are computed, for example
+//! to redact fields. They are registered with [`crate::init_router`] and run in order of
+//! registration. A hook rejects a request by returning an error.
+
+use axum::http::HeaderMap;
+use std::sync::Arc;
+
+use crate::server::data_models::{EmbeddingsRequest, EmbeddingsResponse};
+use crate::server::ServerError;
+
+/// Context of the request a hook runs for.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestContext<'a> {
+    /// Route the request was made to, e.g. `/v1/embeddings`
+    pub route: &'static str,
+    pub headers: &'a HeaderMap,
+    /// Name of the model that serves the request
+    pub model: &'a str,
+    /// Revision of the model that serves the request
+    pub revision: &'a str,
+}
+
+/// Hook into the requests to and responses of the embeddings routes.
+///
+/// Requests to `/v1/embeddings/multi` are split into one request per model, and the hooks run
+/// for each of them.
+pub trait RouteHook: Send + Sync {
+    /// Inspect or change a request before its inputs are tokenized. The model that serves the
+    /// request is chosen before the hooks run, so they can't change it: requests whose model a
+    /// hook changes are rejected.
+    fn pre_tokenize(
+        &self,
+        _context: &RequestContext,
+        _request: &mut EmbeddingsRequest,
+    ) -> Result<(), ServerError> {
+        Ok(())
+    }
+
+    /// Inspect or change the response to a request once the embeddings are computed.
+    fn post_embedding(
+        &self,
+        _context: &RequestContext,
+        _response: &mut EmbeddingsResponse,
+    ) -> Result<(), ServerError> {
+        Ok(())
+    }
+}
+
+/// The registered hooks, in order.
+#[derive(Clone, Default)]
+pub struct Hooks(Vec<Arc<dyn RouteHook>>);
+
+impl Hooks {
+    pub fn new(hooks: Vec<Arc<dyn RouteHook>>) -> Self {
+        Self(hooks)
+    }
+
+    pub fn pre_tokenize(
+        &self,
+        context: &RequestContext,
+        request: &mut EmbeddingsRequest,
+    ) -> Result<(), ServerError> {
+        let model = request.model.clone();
+        self.0
+            .iter()
+            .try_for_each(|hook| hook.pre_tokenize(context, request))?;
+        if request.model != model {
+            return Err(ServerError::InternalError(anyhow::anyhow!(
+                "Route hooks can't change the model of a request"
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn post_embedding(
+        &self,
+        context: &RequestContext,
+        response: &mut EmbeddingsResponse,
+    ) -> Result<(), ServerError> {
+        self.0
+            .iter()
+            .try_for_each(|hook| hook.post_embedding(context, response))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::data_models::Sentences;
+    use glowrs::Usage;
+
+    /// Prefixes the inputs, and rejects requests without a tenant header.
+    struct TenantHook;
+
+    impl RouteHook for TenantHook {
+        fn pre_tokenize(
+            &self,
+            context: &RequestContext,
+            request: &mut EmbeddingsRequest,
+        ) -> Result<(), ServerError> {
+            let tenant = context
+                .headers
+                .get("x-tenant")
+                .and_then(|tenant| tenant.to_str().ok())
+                .ok_or_else(|| ServerError::InvalidRequest("Missing tenant".to_string()))?;
+
+            let texts = request.input.clone().into_texts()?;
+            request.input = Sentences::from(
+                texts
+                    .iter()
+                    .map(|text| format!("{tenant}: {text}"))
+                    .collect::<Vec<_>>(),
+            )
+            .into();
+            Ok(())
+        }
+    }
+
+    /// Redacts the model name.
+    struct RedactHook;
+
+    impl RouteHook for RedactHook {
+        fn post_embedding(
+            &self,
+            _context: &RequestContext,
+            response: &mut EmbeddingsResponse,
+        ) -> Result<(), ServerError> {
+            response.model = "redacted".to_string();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_hooks() -> Result<(), ServerError> {
+        let hooks = Hooks::new(vec![Arc::new(TenantHook), Arc::new(RedactHook)]);
+        let mut headers = HeaderMap::new();
+        fn context(headers: &HeaderMap) -> RequestContext<'_> {
+            RequestContext {
+                route: "/v1/embeddings",
+                headers,
+                model: "model",
+                revision: "main",
+            }
+        }
+
+        let mut request = EmbeddingsRequest::new(Sentences::from(vec!["a"]), "model".to_string());
+        assert!(hooks
+            .pre_tokenize(&context(&headers), &mut request)
+            .is_err());
+
+        headers.insert("x-tenant", "acme".parse().unwrap());
+        hooks.pre_tokenize(&context(&headers), &mut request)?;
+        assert_eq!(request.input.into_texts()?, vec!["acme: a"]);
+
+        let mut response = EmbeddingsResponse::from_vectors(
+            vec![vec![1.]],
+            Usage::default(),
+            vec![Default::default()],
+            "model".to_string(),
+        );
+        hooks.post_embedding(&context(&headers), &mut response)?;
+        assert_eq!(response.model, "redacted");
+
+        // The model is chosen before the hooks run
+        struct ModelHook;
+        impl RouteHook for ModelHook {
+            fn pre_tokenize(
+                &self,
+                _context: &RequestContext,
+                request: &mut EmbeddingsRequest,
+            ) -> Result<(), ServerError> {
+                request.model = "other".to_string();
+                Ok(())
+            }
+        }
+        let mut request = EmbeddingsRequest::new(Sentences::from(vec!["a"]), "model".to_string());
+        assert!(Hooks::new(vec![Arc::new(ModelHook)])
+            .pre_tokenize(&context(&headers), &mut request)
+            .is_err());
+
+        Ok(())
+    }
+}
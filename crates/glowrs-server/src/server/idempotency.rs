@@ -0,0 +1,357 @@
+//! Idempotency keys for embedding requests
+//!
+//! Requests with an `Idempotency-Key` header are executed once; the response is kept for a
+//! limited time and returned as is for retries with the same key, marked with an
+//! `Idempotent-Replayed: true` header. Keys are scoped per route, and reusing a key with a
+//! different request body is rejected. At most `capacity` responses are kept; beyond that, the
+//! least recently used ones are evicted before they expire.
+
+use axum::http::{HeaderMap, HeaderValue};
+use axum::response::Response;
+use bytes::Bytes;
+use lru::LruCache;
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::server::format::ResponseFormat;
+use crate::server::ServerError;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+const MAX_KEY_LENGTH: usize = 255;
+
+enum Entry {
+    /// The first request with the key is still being processed
+    InFlight { fingerprint: u64 },
+    Completed {
+        fingerprint: u64,
+        expires_at: Instant,
+        body: Bytes,
+    },
+}
+
+impl Entry {
+    fn fingerprint(&self) -> u64 {
+        match self {
+            Entry::InFlight { fingerprint } | Entry::Completed { fingerprint, .. } => *fingerprint,
+        }
+    }
+}
+
+/// Short-lived store of responses by idempotency key.
+#[derive(Clone)]
+pub struct IdempotencyStore {
+    ttl: Duration,
+    capacity: NonZeroUsize,
+    /// Keys in flight aren't counted against the capacity, as the queues already bound them
+    entries: Arc<Mutex<LruCache<String, Entry>>>,
+}
+
+/// Marks a key as in flight, and releases it if the request doesn't complete (e.g. on errors
+/// or timeouts), so the request can be retried.
+struct InFlightGuard<'a> {
+    store: &'a IdempotencyStore,
+    key: Option<String>,
+}
+
+impl InFlightGuard<'_> {
+    fn complete(mut self, fingerprint: u64, body: Bytes) {
+        if let Some(key) = self.key.take() {
+            let entry = Entry::Completed {
+                fingerprint,
+                expires_at: Instant::now() + self.store.ttl,
+                body,
+            };
+            let mut entries = self.store.lock();
+            entries.put(key, entry);
+            evict(&mut entries, self.store.capacity.get());
+        }
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.store.lock().pop(&key);
+        }
+    }
+}
+
+impl IdempotencyStore {
+    pub fn new(ttl: Duration, capacity: NonZeroUsize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            entries: Arc::new(Mutex::new(LruCache::unbounded())),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LruCache<String, Entry>> {
+        // The map is always left in a consistent state, so a poisoned lock can be recovered
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Run `f` for the request, unless a request with the same key was completed before, in
+    /// which case its response is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `scope` - Scope of the key, e.g. the route.
+    /// * `headers` - Request headers, which may contain an `Idempotency-Key`.
+    /// * `request` - The request, to detect reuse of a key for a different request.
+    /// * `format` - Format of the response. Responses in other formats are kept separately.
+    /// * `f` - Produces the response body, serialized in `format`.
+    pub async fn run<R, F>(
+        &self,
+        scope: &str,
+        headers: &HeaderMap,
+        request: &R,
+        format: ResponseFormat,
+        f: F,
+    ) -> Result<Response, ServerError>
+    where
+        R: Serialize,
+        F: Future<Output = Result<Bytes, ServerError>>,
+    {
+        let Some(key) = idempotency_key(headers)? else {
+            return Ok(format.response(f.await?));
+        };
+        let key = format!("{scope}:{}:{key}", format.content_type());
+        let fingerprint = fingerprint(request)?;
+
+        let guard = {
+            let mut entries = self.lock();
+            let now = Instant::now();
+            let expired: Vec<String> = entries
+                .iter()
+                .filter(|(_, entry)| {
+                    matches!(entry, Entry::Completed { expires_at, .. } if *expires_at <= now)
+                })
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in expired {
+                entries.pop(&key);
+            }
+
+            match entries.get(&key) {
+                Some(entry) if entry.fingerprint() != fingerprint => {
+                    return Err(ServerError::IdempotencyKeyMismatch);
+                }
+                Some(Entry::InFlight { .. }) => return Err(ServerError::IdempotencyKeyInUse),
+                Some(Entry::Completed { body, .. }) => {
+                    tracing::trace!("Replaying response for idempotency key {key}");
+                    return Ok(replayed(format.response(body.clone())));
+                }
+                None => {
+                    entries.put(key.clone(), Entry::InFlight { fingerprint });
+                }
+            }
+
+            InFlightGuard {
+                store: self,
+                key: Some(key),
+            }
+        };
+
+        let body = f.await?;
+        guard.complete(fingerprint, body.clone());
+
+        Ok(format.response(body))
+    }
+}
+
+/// Evict the least recently used responses until at most `capacity` are kept.
+fn evict(entries: &mut LruCache<String, Entry>, capacity: usize) {
+    let completed = |entry: &Entry| matches!(entry, Entry::Completed { .. });
+    let mut n_completed = entries.iter().filter(|(_, entry)| completed(entry)).count();
+
+    while n_completed > capacity {
+        // Iterate from the least recently used entry
+        let Some(key) = entries
+            .iter()
+            .rev()
+            .find(|(_, entry)| completed(entry))
+            .map(|(key, _)| key.clone())
+        else {
+            break;
+        };
+        entries.pop(&key);
+        n_completed -= 1;
+    }
+}
+
+fn idempotency_key(headers: &HeaderMap) -> Result<Option<&str>, ServerError> {
+    let Some(value) = headers.get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(None);
+    };
+
+    match value.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => Ok(Some(key)),
+        _ => Err(ServerError::InvalidRequest(format!(
+            "Idempotency key should be 1 to {MAX_KEY_LENGTH} visible ASCII characters"
+        ))),
+    }
+}
+
+fn fingerprint<R: Serialize>(request: &R) -> Result<u64, ServerError> {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_vec(request)
+        .map_err(|e| ServerError::InternalError(e.into()))?
+        .hash(&mut hasher);
+    Ok(hasher.finish())
+}
+
+fn replayed(mut response: Response) -> Response {
+    response
+        .headers_mut()
+        .insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn headers(key: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(IDEMPOTENCY_KEY_HEADER, key.parse().unwrap());
+        headers
+    }
+
+    async fn body(response: Response) -> Bytes {
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_replay() -> anyhow::Result<()> {
+        let store = IdempotencyStore::new(Duration::from_secs(60), NonZeroUsize::new(16).unwrap());
+        let calls = AtomicUsize::new(0);
+        let run = |key: &str, request: &'static str| {
+            let headers = headers(key);
+            let calls = &calls;
+            let store = &store;
+            async move {
+                store
+                    .run(
+                        "embeddings",
+                        &headers,
+                        &request,
+                        ResponseFormat::Json,
+                        async {
+                            let calls = calls.fetch_add(1, Ordering::SeqCst);
+                            Ok(Bytes::from(calls.to_string()))
+                        },
+                    )
+                    .await
+            }
+        };
+
+        let first = run("key", "request").await?;
+        assert!(first.headers().get(IDEMPOTENT_REPLAYED_HEADER).is_none());
+
+        let retry = run("key", "request").await?;
+        assert_eq!(retry.headers()[IDEMPOTENT_REPLAYED_HEADER], "true");
+        assert_eq!(body(retry).await, body(first).await);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        assert!(matches!(
+            run("key", "other request").await,
+            Err(ServerError::IdempotencyKeyMismatch)
+        ));
+
+        run("other key", "request").await?;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_request_is_not_stored() -> anyhow::Result<()> {
+        let store = IdempotencyStore::new(Duration::from_secs(60), NonZeroUsize::new(16).unwrap());
+        let headers = headers("key");
+
+        let failed = store
+            .run(
+                "embeddings",
+                &headers,
+                &"request",
+                ResponseFormat::Json,
+                async { Err(ServerError::ModelNotFound) },
+            )
+            .await;
+        assert!(failed.is_err());
+
+        let retry = store
+            .run(
+                "embeddings",
+                &headers,
+                &"request",
+                ResponseFormat::Json,
+                async { Ok(Bytes::from("1")) },
+            )
+            .await?;
+        assert!(retry.headers().get(IDEMPOTENT_REPLAYED_HEADER).is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expiry() -> anyhow::Result<()> {
+        let store = IdempotencyStore::new(Duration::ZERO, NonZeroUsize::MIN);
+        let headers = headers("key");
+
+        for _ in 0..2 {
+            let response = store
+                .run(
+                    "embeddings",
+                    &headers,
+                    &"request",
+                    ResponseFormat::Json,
+                    async { Ok(Bytes::from("1")) },
+                )
+                .await?;
+            assert!(response.headers().get(IDEMPOTENT_REPLAYED_HEADER).is_none());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_eviction() -> anyhow::Result<()> {
+        let store = IdempotencyStore::new(Duration::from_secs(60), NonZeroUsize::MIN);
+        let replayed = |key: &str| {
+            let headers = headers(key);
+            let store = &store;
+            async move {
+                let response = store
+                    .run(
+                        "embeddings",
+                        &headers,
+                        &"request",
+                        ResponseFormat::Json,
+                        async { Ok(Bytes::from("1")) },
+                    )
+                    .await?;
+                anyhow::Ok(response.headers().get(IDEMPOTENT_REPLAYED_HEADER).is_some())
+            }
+        };
+
+        assert!(!replayed("a").await?);
+        assert!(replayed("a").await?);
+
+        // The response for `a` is evicted to make room for `b`
+        assert!(!replayed("b").await?);
+        assert!(!replayed("a").await?);
+
+        Ok(())
+    }
+}
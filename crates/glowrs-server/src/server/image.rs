@@ -0,0 +1,333 @@
+//! Image inputs
+//!
+//! Images in embeddings requests are given as `data:` URLs or base64-encoded bytes, or as
+//! `http(s)` URLs with `--allow-image-urls`. They are fetched before a request is queued, so slow
+//! downloads don't hold up the model, and decoded by the executor of the model.
+//!
+//! Since the server fetches URLs on behalf of its clients, URLs are only fetched if they resolve
+//! to public addresses, so clients can't reach services on the private network of the server,
+//! such as cloud metadata endpoints. The checked addresses are the ones connected to, and
+//! redirects are not followed, since they could point anywhere.
+
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use clap::Args;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use glowrs::vision::MAX_IMAGE_PIXELS;
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use url::Host;
+
+use crate::server::ServerError;
+
+/// Maximum size of an image in bytes
+const MAX_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximum number of images of a request fetched at once
+const MAX_CONCURRENT_FETCHES: usize = 4;
+
+#[derive(Debug, Args)]
+pub struct ImageArgs {
+    /// Fetch image inputs given as `http(s)` URLs. URLs that resolve to loopback, private,
+    /// link-local or other non-public addresses are rejected, and redirects are not followed
+    #[clap(long)]
+    pub allow_image_urls: bool,
+
+    /// Maximum number of pixels of image inputs. Larger images are rejected before they are
+    /// decoded
+    #[clap(long, default_value_t = MAX_IMAGE_PIXELS)]
+    pub max_image_pixels: usize,
+}
+
+/// How image inputs are fetched and decoded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageConfig {
+    pub allow_urls: bool,
+    pub max_pixels: usize,
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        Self {
+            allow_urls: false,
+            max_pixels: MAX_IMAGE_PIXELS,
+        }
+    }
+}
+
+impl ImageConfig {
+    pub fn from_args(args: &ImageArgs) -> Self {
+        Self {
+            allow_urls: args.allow_image_urls,
+            max_pixels: args.max_image_pixels,
+        }
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> anyhow::Error {
+    ServerError::InvalidRequest(msg.into()).into()
+}
+
+/// Get the bytes of an image input.
+pub async fn fetch_image(source: &str, config: ImageConfig) -> Result<Bytes> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        if !config.allow_urls {
+            return Err(invalid(
+                "Image URLs are not allowed, send the image as a data URL or base64-encoded bytes",
+            ));
+        }
+        return download(source).await;
+    }
+
+    // `data:[<media type>][;base64],<data>`
+    let encoded = match source.strip_prefix("data:") {
+        Some(data_url) => {
+            let (media_type, data) = data_url
+                .split_once(',')
+                .ok_or_else(|| invalid("Invalid data URL"))?;
+            if !media_type.ends_with(";base64") {
+                return Err(invalid("Only base64-encoded data URLs are supported"));
+            }
+            data
+        }
+        None => source,
+    };
+
+    if encoded.len() / 4 * 3 > MAX_IMAGE_BYTES {
+        return Err(invalid(format!(
+            "Images should be at most {MAX_IMAGE_BYTES} bytes"
+        )));
+    }
+
+    STANDARD
+        .decode(encoded.trim())
+        .map(Bytes::from)
+        .map_err(|_| invalid("Image should be a URL or base64-encoded"))
+}
+
+/// Get the bytes of the image inputs of a request, in order, fetching at most
+/// `MAX_CONCURRENT_FETCHES` at once.
+pub async fn fetch_images<'a>(
+    sources: impl IntoIterator<Item = &'a str>,
+    config: ImageConfig,
+) -> Result<Vec<Bytes>> {
+    // The futures are created up front, as futures created by a closure of the stream aren't
+    // `Send` for every lifetime of the sources
+    let fetches: Vec<_> = sources
+        .into_iter()
+        .map(|source| fetch_image(source, config))
+        .collect();
+    stream::iter(fetches)
+        .buffered(MAX_CONCURRENT_FETCHES)
+        .try_collect()
+        .await
+}
+
+async fn download(url: &str) -> Result<Bytes> {
+    let too_large = || {
+        invalid(format!(
+            "Image at {url} is larger than {MAX_IMAGE_BYTES} bytes"
+        ))
+    };
+
+    let parsed = Url::parse(url).map_err(|_| invalid(format!("Invalid image URL {url}")))?;
+    let port = parsed
+        .port_or_known_default()
+        .ok_or_else(|| invalid(format!("Invalid image URL {url}")))?;
+    let mut client = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .redirect(Policy::none())
+        // A proxy would resolve the host itself
+        .no_proxy();
+    match parsed.host() {
+        Some(Host::Domain(domain)) => {
+            let addrs = resolve_public(domain, port).await?;
+            // Connect to the checked addresses, rather than resolving the host again
+            client = client.resolve_to_addrs(domain, &addrs);
+        }
+        Some(Host::Ipv4(ip)) => check_public(&ip.to_string(), IpAddr::V4(ip))?,
+        Some(Host::Ipv6(ip)) => check_public(&ip.to_string(), IpAddr::V6(ip))?,
+        None => return Err(invalid(format!("Invalid image URL {url}"))),
+    }
+
+    let mut response = client
+        .build()?
+        .get(parsed)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| invalid(format!("Failed to fetch image at {url}: {err}")))?;
+    if response.status().is_redirection() {
+        return Err(invalid(format!(
+            "Image at {url} redirects elsewhere, which is not followed"
+        )));
+    }
+
+    if response
+        .content_length()
+        .is_some_and(|length| length as usize > MAX_IMAGE_BYTES)
+    {
+        return Err(too_large());
+    }
+
+    // The content length can be missing, so enforce the limit while reading
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|err| invalid(format!("Failed to fetch image at {url}: {err}")))?
+    {
+        if bytes.len() + chunk.len() > MAX_IMAGE_BYTES {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(bytes.into())
+}
+
+/// Resolve a host to its addresses, failing if any of them is not public.
+async fn resolve_public(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|err| invalid(format!("Failed to resolve {host}: {err}")))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(invalid(format!("Failed to resolve {host}")));
+    }
+    for addr in &addrs {
+        check_public(host, addr.ip())?;
+    }
+
+    Ok(addrs)
+}
+
+fn check_public(host: &str, ip: IpAddr) -> Result<()> {
+    match is_public(ip) {
+        true => Ok(()),
+        false => Err(invalid(format!(
+            "Images can't be fetched from {host}, which is not a public address"
+        ))),
+    }
+}
+
+/// Whether an address is reachable on the public internet, so not a loopback, private,
+/// link-local (including cloud metadata endpoints such as `169.254.169.254`), shared, reserved or
+/// multicast address.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // 0.0.0.0/8, shared address space 100.64.0.0/10, 198.18.0.0/15 and 240.0.0.0/4
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local fc00::/7, which includes the AWS metadata endpoint fd00:ec2::254
+        || (first & 0xfe00) == 0xfc00
+        // Link-local fe80::/10
+        || (first & 0xffc0) == 0xfe80
+        // Documentation 2001:db8::/32
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fetch_encoded_image() -> Result<()> {
+        let config = ImageConfig::default();
+        let bytes = fetch_image("data:image/png;base64,iVBORw0KGgo=", config).await?;
+        assert_eq!(&bytes[..], b"\x89PNG\r\n\x1a\n");
+
+        let bytes = fetch_image("UDYKMSAxCjI1NQoAAAA=", config).await?;
+        assert_eq!(&bytes[..], b"P6\n1 1\n255\n\x00\x00\x00");
+
+        assert!(fetch_image("data:image/png,raw", config).await.is_err());
+        assert!(fetch_image("not base64!", config).await.is_err());
+
+        let images = fetch_images(["UDYKMSAxCjI1NQoAAAA=", "iVBORw0KGgo="], config).await?;
+        assert_eq!(&images[0][..2], b"P6");
+        assert_eq!(&images[1][..4], b"\x89PNG");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_image_url() {
+        // URLs are only fetched if allowed
+        let url = "https://example.com/cat.png";
+        let err = fetch_image(url, ImageConfig::default()).await.unwrap_err();
+        assert!(err.to_string().contains("not allowed"));
+
+        // Non-public addresses are rejected before connecting
+        let config = ImageConfig {
+            allow_urls: true,
+            ..ImageConfig::default()
+        };
+        for url in [
+            "http://127.0.0.1/image.png",
+            "http://localhost:8080/image.png",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://10.0.0.1/image.png",
+            "http://[::1]/image.png",
+            "http://[::ffff:192.168.0.1]/image.png",
+        ] {
+            let err = fetch_image(url, config).await.unwrap_err();
+            assert!(err.to_string().contains("not a public address"), "{url}");
+        }
+    }
+
+    #[test]
+    fn test_is_public() {
+        for ip in ["8.8.8.8", "1.1.1.1", "2606:4700:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "0.0.0.0",
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.100.100.200",
+            "255.255.255.255",
+            "224.0.0.1",
+            "::",
+            "::1",
+            "fd00:ec2::254",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+}
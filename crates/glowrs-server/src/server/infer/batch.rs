@@ -1,3 +1,4 @@
+use anyhow::Result;
 use tokio::sync::oneshot;
 use tokio::time::Instant;
 use uuid::Uuid;
@@ -18,7 +19,7 @@ where
     pub request: THandler::Input,
 
     /// Response sender
-    pub response_tx: oneshot::Sender<THandler::Output>,
+    pub response_tx: oneshot::Sender<Result<THandler::Output>>,
 
     /// Instant when this entry was queued
     pub queue_time: Instant,
@@ -28,7 +29,10 @@ impl<THandler> QueueEntry<THandler>
 where
     THandler: RequestHandler,
 {
-    pub fn new(request: THandler::Input, response_tx: oneshot::Sender<THandler::Output>) -> Self {
+    pub fn new(
+        request: THandler::Input,
+        response_tx: oneshot::Sender<Result<THandler::Output>>,
+    ) -> Self {
         Self {
             id: Uuid::new_v4(),
             request,
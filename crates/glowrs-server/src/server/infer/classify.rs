@@ -0,0 +1,63 @@
+use crate::server::data_models::{ClassifyRequest, ClassifyResponse};
+use crate::server::infer::client::Client;
+use crate::server::infer::handler::RequestHandler;
+use crate::server::infer::limits::Limiter;
+use crate::server::infer::DedicatedExecutor;
+use glowrs::core::classifier::TextClassifier;
+use std::sync::Arc;
+
+/// Runs the sequence classification head of a model on the inputs of requests.
+pub struct ClassifyHandler {
+    classifier: TextClassifier,
+}
+
+impl ClassifyHandler {
+    pub fn new(classifier: TextClassifier) -> Self {
+        Self { classifier }
+    }
+}
+
+impl RequestHandler for ClassifyHandler {
+    type Input = ClassifyRequest;
+    type Output = ClassifyResponse;
+
+    fn handle(&mut self, request: ClassifyRequest) -> anyhow::Result<ClassifyResponse> {
+        let texts: Vec<String> = request.input.into();
+        let predictions = self.classifier.predict(texts)?;
+
+        Ok(ClassifyResponse::from_predictions(
+            predictions,
+            request.model,
+        ))
+    }
+}
+
+/// Classification client of a model, which shares the queue limits of its embeddings client.
+#[derive(Clone)]
+pub struct ClassifyClient {
+    client: Client<ClassifyHandler>,
+    limiter: Arc<Limiter>,
+}
+
+impl ClassifyClient {
+    /// Start an executor for the classifier. The executor runs as long as the client (or one of
+    /// its clones) is alive.
+    pub(crate) fn spawn(classifier: TextClassifier, limiter: Arc<Limiter>) -> anyhow::Result<Self> {
+        let executor = DedicatedExecutor::new(ClassifyHandler::new(classifier))?;
+
+        Ok(Self {
+            client: Client::new(&executor),
+            limiter,
+        })
+    }
+
+    pub async fn classify(&self, request: ClassifyRequest) -> anyhow::Result<ClassifyResponse> {
+        self.limiter
+            .run(async {
+                let rx = self.client.send(request).await?;
+                rx.await
+                    .map_err(|_| anyhow::anyhow!("Failed to receive response from executor"))?
+            })
+            .await
+    }
+}
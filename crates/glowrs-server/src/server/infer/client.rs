@@ -27,7 +27,7 @@ where
     pub(crate) async fn send(
         &self,
         value: THandler::Input,
-    ) -> Result<oneshot::Receiver<THandler::Output>> {
+    ) -> Result<oneshot::Receiver<Result<THandler::Output>>> {
         // Create channel
         let (tx, rx) = oneshot::channel();
 
@@ -1,52 +1,631 @@
-use crate::server::data_models::{EmbeddingsRequest, EmbeddingsResponse};
+use crate::server::data_models::{
+    EmbeddingsInput, EmbeddingsMetadata, EmbeddingsRequest, EmbeddingsResponse, InputType,
+    MultimodalInput, Sentences,
+};
+use crate::server::device::DeviceConfig;
+use crate::server::image::{fetch_images, ImageConfig};
+use crate::server::infer::classify::ClassifyClient;
 use crate::server::infer::client::Client;
-use crate::server::infer::handler::RequestHandler;
+use crate::server::infer::handler::{Preparer, RequestHandler};
+use crate::server::infer::limits::{Limiter, QueueLimits};
 use crate::server::infer::DedicatedExecutor;
-use glowrs::core::embedder::EmbedOutput;
-use glowrs::{Device, SentenceTransformer};
+use crate::server::preprocess::Preprocessor;
+use crate::server::timing::{timed, Timings};
+use crate::server::worker::WorkerProcess;
+use crate::server::ServerError;
+use bytes::Bytes;
+use candle_core::DType;
+use glowrs::core::cache::{CachedEmbedOutput, EmbeddingCache};
+use glowrs::core::classifier::TextClassifier;
+use glowrs::core::embedder::{EmbedOutput, TokenizedBatch};
+use glowrs::core::utils::parse_repo_string;
+use glowrs::vision::{is_clip_model_repo, RgbImage};
+use glowrs::{Device, ImageEncoder, InputUsage, PoolingStrategy, SentenceTransformer};
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokenizers::Tokenizer;
 
+/// Revision of models that are loaded without one
+const DEFAULT_REVISION: &str = "main";
+
+/// Input of the dummy batch models are warmed up with
+const WARMUP_INPUT: &str = "The quick brown fox jumps over the lazy dog.";
+/// Number of inputs in the dummy batch models are warmed up with
+const WARMUP_BATCH_SIZE: usize = 8;
+
+/// An embeddings request, with its inputs tokenized once it has been prepared.
+pub struct EmbeddingsTask {
+    request: EmbeddingsRequest,
+    batch: Option<TokenizedBatch>,
+    /// Fetched image inputs, in the order of the inputs
+    images: Vec<Bytes>,
+    /// When the request started waiting for the model
+    queued_at: Instant,
+    timings: Timings,
+}
+
+impl From<EmbeddingsRequest> for EmbeddingsTask {
+    fn from(request: EmbeddingsRequest) -> Self {
+        Self {
+            request,
+            batch: None,
+            images: Vec::new(),
+            queued_at: Instant::now(),
+            timings: Timings::default(),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum EmbeddingModel {
+    Text(Arc<SentenceTransformer>),
+    /// Embeds both texts and images
+    Multimodal(Arc<ImageEncoder>),
+}
+
+/// Clones share the model weights and tokenizer, so they can serve as replicas.
+#[derive(Clone)]
 pub struct EmbeddingsHandler {
-    sentence_transformer: SentenceTransformer,
+    model: EmbeddingModel,
+    metadata: EmbeddingsMetadata,
+    cache: Option<Arc<EmbeddingCache>>,
+    preprocessor: Option<Arc<Preprocessor>>,
+    images: ImageConfig,
 }
 
 impl EmbeddingsHandler {
     pub fn new(sentence_transformer: SentenceTransformer) -> Self {
+        let metadata = EmbeddingsMetadata {
+            revision: DEFAULT_REVISION.to_string(),
+            commit: sentence_transformer.commit_hash().map(str::to_string),
+            dtype: sentence_transformer.dtype().as_str().to_string(),
+            pooling: sentence_transformer.pooling_strategy(),
+            glowrs_version: glowrs::VERSION.to_string(),
+        };
+
+        Self {
+            model: EmbeddingModel::Text(Arc::new(sentence_transformer)),
+            metadata,
+            cache: None,
+            preprocessor: None,
+            images: ImageConfig::default(),
+        }
+    }
+
+    /// Serve a multimodal model, which embeds both texts and images.
+    pub fn new_multimodal(image_encoder: ImageEncoder) -> Self {
+        let metadata = EmbeddingsMetadata {
+            revision: DEFAULT_REVISION.to_string(),
+            commit: image_encoder.commit_hash().map(str::to_string),
+            dtype: DType::F32.as_str().to_string(),
+            pooling: None,
+            glowrs_version: glowrs::VERSION.to_string(),
+        };
+
+        Self {
+            model: EmbeddingModel::Multimodal(Arc::new(image_encoder)),
+            metadata,
+            cache: None,
+            preprocessor: None,
+            images: ImageConfig::default(),
+        }
+    }
+
+    pub fn is_multimodal(&self) -> bool {
+        matches!(self.model, EmbeddingModel::Multimodal(_))
+    }
+
+    /// Device the model runs on.
+    pub fn device(&self) -> &Device {
+        match &self.model {
+            EmbeddingModel::Text(sentence_transformer) => sentence_transformer.device(),
+            EmbeddingModel::Multimodal(image_encoder) => image_encoder.device(),
+        }
+    }
+
+    /// The tokenizer the model encodes its text inputs with.
+    pub fn tokenizer(&self) -> &Tokenizer {
+        match &self.model {
+            EmbeddingModel::Text(sentence_transformer) => sentence_transformer.get_tokenizer(),
+            EmbeddingModel::Multimodal(image_encoder) => image_encoder.get_tokenizer(),
+        }
+    }
+
+    /// The sequence classifier of the model, if it is a text classification model. It shares the
+    /// weights of the model.
+    pub fn classifier(&self) -> Option<TextClassifier> {
+        let EmbeddingModel::Text(sentence_transformer) = &self.model else {
+            return None;
+        };
+        if !sentence_transformer.is_text_classifier() {
+            return None;
+        }
+
+        TextClassifier::from_model(SentenceTransformer::clone(sentence_transformer))
+            .inspect_err(|e| tracing::warn!("Failed to load the classification head: {e}"))
+            .ok()
+    }
+
+    /// Embed a dummy batch, so that the first requests don't pay for compiling kernels and paging
+    /// in the weights. Returns how long it took, which is logged under the name of the model.
+    /// The embeddings of the batch aren't cached.
+    pub fn warm_up(&self, model: &str) -> anyhow::Result<Duration> {
+        let handler = Self {
+            cache: None,
+            ..self.clone()
+        };
+        let request = EmbeddingsRequest::new(
+            Sentences::from(vec![WARMUP_INPUT.to_string(); WARMUP_BATCH_SIZE]),
+            "warm-up".to_string(),
+        );
+
+        let start = Instant::now();
+        handler.embed(request, None, Vec::new(), &mut Timings::default())?;
+        let elapsed = start.elapsed();
+        tracing::info!(
+            "Warmed up model {model} in {:.1} ms",
+            elapsed.as_secs_f64() * 1000.
+        );
+
+        Ok(elapsed)
+    }
+
+    /// Set the revision of the model repository the model was loaded from.
+    pub fn with_revision(mut self, revision: impl Into<String>) -> Self {
+        self.metadata.revision = revision.into();
+        self
+    }
+
+    /// Look up embeddings in (and add them to) the given cache before running the model.
+    pub fn with_cache(self, cache: Option<Arc<EmbeddingCache>>) -> Self {
+        Self { cache, ..self }
+    }
+
+    /// Preprocess all inputs with the given pipeline before tokenization.
+    pub fn with_preprocessor(self, preprocessor: Option<Preprocessor>) -> Self {
         Self {
-            sentence_transformer,
+            preprocessor: preprocessor.map(Arc::new),
+            ..self
         }
     }
-    pub fn from_repo_string(model_repo: &str, device: &Device) -> anyhow::Result<Self> {
+
+    /// Fetch and decode image inputs with the given settings.
+    pub fn with_images(self, images: ImageConfig) -> Self {
+        Self { images, ..self }
+    }
+
+    pub fn from_repo_string(model_repo: &str, device: &DeviceConfig) -> anyhow::Result<Self> {
         tracing::info!("Loading core: {}. Wait for core load.", model_repo);
+        let (_, revision) = parse_repo_string(model_repo)?;
+
+        if is_clip_model_repo(model_repo)? {
+            let image_encoder =
+                device.load(|device| Ok(ImageEncoder::from_model_repo(model_repo, device)?))?;
+            tracing::info!("Multimodal model loaded");
+
+            return Ok(Self::new_multimodal(image_encoder).with_revision(revision));
+        }
 
         let sentence_transformer = SentenceTransformer::builder()
             .with_model_repo(model_repo)?
-            .with_device(device.clone())
+            .with_device(device.device.clone())
+            .with_device_fallback(device.fallback)
             .build()?;
 
         tracing::info!("Model loaded");
 
-        Ok(Self {
-            sentence_transformer,
-        })
+        Ok(Self::new(sentence_transformer).with_revision(revision))
+    }
+}
+
+/// Check that the input type of a request, if any, can be applied: token ids can't be prefixed,
+/// and the model needs a prefix for the input type.
+fn check_input_type(
+    preprocessor: Option<&Preprocessor>,
+    request: &EmbeddingsRequest,
+) -> Result<(), ServerError> {
+    let Some(input_type) = request.input_type else {
+        return Ok(());
+    };
+    if matches!(request.input, EmbeddingsInput::TokenIds(_)) {
+        return Err(ServerError::InvalidRequest(
+            "`input_type` can't be set for token id inputs".to_string(),
+        ));
+    }
+    if preprocessor
+        .and_then(|preprocessor| preprocessor.prefix(input_type))
+        .is_none()
+    {
+        let name = match input_type {
+            InputType::Query => "query",
+            InputType::Passage => "passage",
+        };
+        return Err(ServerError::InvalidRequest(format!(
+            "Model `{}` has no {name} prefix configured",
+            request.model
+        )));
+    }
+    Ok(())
+}
+
+/// Preprocess the sentences, and put the prefix of their input type in front of them.
+fn preprocess(
+    preprocessor: Option<&Preprocessor>,
+    input_type: Option<InputType>,
+    sentences: Vec<String>,
+) -> Vec<String> {
+    match preprocessor {
+        Some(preprocessor) => {
+            let prefix = input_type
+                .and_then(|input_type| preprocessor.prefix(input_type))
+                .unwrap_or_default();
+            sentences
+                .iter()
+                .map(|s| format!("{prefix}{}", preprocessor.apply(s)))
+                .collect()
+        }
+        None => sentences,
+    }
+}
+
+/// The replica of the model with the adapter of a task, see [`SentenceTransformer::with_task`].
+fn task_model<'a>(
+    sentence_transformer: &'a SentenceTransformer,
+    task: Option<&str>,
+) -> anyhow::Result<Cow<'a, SentenceTransformer>> {
+    let Some(task) = task else {
+        return Ok(Cow::Borrowed(sentence_transformer));
+    };
+
+    match sentence_transformer.with_task(task) {
+        Ok(replica) => Ok(Cow::Owned(replica)),
+        Err(_) => Err(ServerError::InvalidRequest(format!(
+            "Unsupported task `{task}`, the model supports: {:?}",
+            sentence_transformer.tasks()
+        ))
+        .into()),
+    }
+}
+
+/// Put the instruction of a task in front of each sentence.
+fn with_instruction(
+    sentence_transformer: &SentenceTransformer,
+    task: Option<&str>,
+    sentences: Vec<String>,
+) -> Vec<String> {
+    match task.and_then(|task| sentence_transformer.task_instruction(task)) {
+        Some(instruction) => sentences
+            .into_iter()
+            .map(|sentence| format!("{instruction}{sentence}"))
+            .collect(),
+        None => sentences,
+    }
+}
+
+/// Embed the texts and images of a request with a multimodal model, in the order of the inputs.
+/// Images with more than `max_pixels` pixels are rejected.
+fn encode_multimodal(
+    image_encoder: &ImageEncoder,
+    preprocessor: Option<&Preprocessor>,
+    request: EmbeddingsRequest,
+    images: Vec<Bytes>,
+    max_pixels: usize,
+) -> anyhow::Result<EmbeddingsResponse> {
+    if request.pooling.is_some() {
+        return Err(ServerError::InvalidRequest(
+            "Pooling can't be set for multimodal models".to_string(),
+        )
+        .into());
+    }
+    if request.task.is_some() {
+        return Err(
+            ServerError::InvalidRequest("Multimodal models have no tasks".to_string()).into(),
+        );
     }
+    if request.chunking.is_some() {
+        return Err(ServerError::InvalidRequest(
+            "Multimodal models don't support chunking".to_string(),
+        )
+        .into());
+    }
+
+    let inputs = match request.input {
+        EmbeddingsInput::Text(sentences) => Vec::<String>::from(sentences)
+            .into_iter()
+            .map(|text| MultimodalInput::Text { text })
+            .collect(),
+        EmbeddingsInput::TokenIds(_) => {
+            return Err(ServerError::InvalidRequest(
+                "Multimodal models don't support token id inputs".to_string(),
+            )
+            .into())
+        }
+        EmbeddingsInput::Multimodal(inputs) => inputs,
+    };
+    let is_image: Vec<bool> = inputs
+        .iter()
+        .map(|input| matches!(input, MultimodalInput::Image { .. }))
+        .collect();
+    let texts: Vec<String> = inputs
+        .into_iter()
+        .filter_map(|input| match input {
+            MultimodalInput::Text { text } => Some(text),
+            MultimodalInput::Image { .. } => None,
+        })
+        .collect();
+
+    let normalize = request.normalize.unwrap_or_default();
+
+    let (mut text_embeddings, usage, mut text_inputs) = if texts.is_empty() {
+        (Vec::new(), Default::default(), Vec::new())
+    } else {
+        let EmbedOutput {
+            embeddings,
+            usage,
+            inputs,
+        } = image_encoder.encode_text_with_usage(
+            preprocess(preprocessor, request.input_type, texts),
+            normalize,
+        )?;
+        (embeddings.to_vec2::<f32>()?, usage, inputs)
+    };
+
+    let mut image_embeddings = if images.is_empty() {
+        Vec::new()
+    } else {
+        let images = images
+            .iter()
+            .map(|bytes| RgbImage::decode_with_limit(bytes, max_pixels))
+            .collect::<glowrs::Result<Vec<_>>>()?;
+        image_encoder
+            .encode_images(&images, normalize)?
+            .to_vec2::<f32>()?
+    };
+
+    // Merge the embeddings back into the order of the inputs
+    text_embeddings.reverse();
+    text_inputs.reverse();
+    image_embeddings.reverse();
+    let (embeddings, inputs) = is_image
+        .into_iter()
+        .map(|is_image| match is_image {
+            true => (image_embeddings.pop(), Some(InputUsage::default())),
+            false => (text_embeddings.pop(), text_inputs.pop()),
+        })
+        .map(|(embedding, input)| embedding.zip(input))
+        .collect::<Option<(Vec<_>, Vec<_>)>>()
+        .ok_or_else(|| anyhow::anyhow!("Number of embeddings doesn't match the inputs"))?;
+
+    Ok(EmbeddingsResponse::from_vectors(
+        embeddings,
+        usage,
+        inputs,
+        request.model,
+    ))
 }
 
 impl RequestHandler for EmbeddingsHandler {
-    type Input = EmbeddingsRequest;
+    type Input = EmbeddingsTask;
     type Output = EmbeddingsResponse;
 
-    fn handle(&mut self, request: EmbeddingsRequest) -> anyhow::Result<EmbeddingsResponse> {
-        let sentences = request.input;
+    fn handle(&mut self, task: EmbeddingsTask) -> anyhow::Result<EmbeddingsResponse> {
+        let EmbeddingsTask {
+            request,
+            batch,
+            images,
+            queued_at,
+            mut timings,
+        } = task;
+
+        // Time spent tokenizing ahead doesn't count as waiting
+        timings.queue = Some(
+            queued_at
+                .elapsed()
+                .saturating_sub(timings.tokenize.unwrap_or_default()),
+        );
+
+        let mut response = self.embed(request, batch, images, &mut timings)?;
+        response.timings = timings;
+
+        Ok(response)
+    }
+
+    /// Preprocess and tokenize the inputs ahead of inference. Cached models look up the
+    /// preprocessed inputs before tokenizing, so they are handled as a whole.
+    fn preparer(&self) -> Option<Preparer<EmbeddingsTask>> {
+        let EmbeddingModel::Text(sentence_transformer) = &self.model else {
+            return None;
+        };
+        if self.cache.is_some() {
+            return None;
+        }
+
+        let sentence_transformer = sentence_transformer.clone();
+        let preprocessor = self.preprocessor.clone();
+
+        Some(Box::new(move |task: EmbeddingsTask| {
+            check_input_type(preprocessor.as_deref(), &task.request)?;
+            // Chunked inputs are tokenized when they are embedded
+            if task.request.chunking.is_some() {
+                return Ok(task);
+            }
+            let EmbeddingsTask {
+                mut request,
+                queued_at,
+                mut timings,
+                ..
+            } = task;
 
-        // TODO: Is this even necessary?
-        const NORMALIZE: bool = false;
+            // The inputs aren't needed anymore once tokenized
+            let input =
+                std::mem::replace(&mut request.input, Sentences::Multiple(Vec::new()).into());
+            let batch = timed("tokenize", &mut timings.tokenize, || match input {
+                EmbeddingsInput::TokenIds(ids) => {
+                    anyhow::Ok(sentence_transformer.batch_from_ids(ids.into())?)
+                }
+                input => {
+                    let sentences = with_instruction(
+                        &sentence_transformer,
+                        request.task.as_deref(),
+                        preprocess(
+                            preprocessor.as_deref(),
+                            request.input_type,
+                            input.into_texts()?,
+                        ),
+                    );
+                    anyhow::Ok(sentence_transformer.tokenize_batch(sentences)?)
+                }
+            })?;
+
+            Ok(EmbeddingsTask {
+                request,
+                batch: Some(batch),
+                images: Vec::new(),
+                queued_at,
+                timings,
+            })
+        }))
+    }
+}
+
+impl EmbeddingsHandler {
+    /// Embed the inputs of a request, timing the stages in `timings`.
+    fn embed(
+        &self,
+        request: EmbeddingsRequest,
+        batch: Option<TokenizedBatch>,
+        images: Vec<Bytes>,
+        timings: &mut Timings,
+    ) -> anyhow::Result<EmbeddingsResponse> {
+        // Tokenized requests were checked when they were prepared, without their inputs
+        if batch.is_none() {
+            check_input_type(self.preprocessor.as_deref(), &request)?;
+        }
+
+        let sentence_transformer = match &self.model {
+            EmbeddingModel::Text(sentence_transformer) => sentence_transformer,
+            EmbeddingModel::Multimodal(image_encoder) => {
+                return timed("forward", &mut timings.forward, || {
+                    encode_multimodal(
+                        image_encoder,
+                        self.preprocessor.as_deref(),
+                        request,
+                        images,
+                        self.images.max_pixels,
+                    )
+                });
+            }
+        };
+
+        let normalize = request.normalize.unwrap_or_default();
+
+        let task = request.task.as_deref();
+        let sentence_transformer = task_model(sentence_transformer, task)?;
+
+        // Long inputs are split into chunks, bypassing the cache
+        if let Some(chunking) = &request.chunking {
+            if request.pooling.is_some() {
+                return Err(ServerError::InvalidRequest(
+                    "Pooling can't be set for chunked inputs".to_string(),
+                )
+                .into());
+            }
+            let sentences = with_instruction(
+                &sentence_transformer,
+                task,
+                preprocess(
+                    self.preprocessor.as_deref(),
+                    request.input_type,
+                    request.input.into_texts()?,
+                ),
+            );
+            let EmbedOutput {
+                embeddings,
+                usage,
+                inputs,
+            } = timed("forward", &mut timings.forward, || {
+                sentence_transformer.encode_long(&sentences, normalize, chunking)
+            })?;
+
+            return Ok(EmbeddingsResponse::from_embeddings(
+                embeddings,
+                usage,
+                inputs,
+                request.model,
+            ));
+        }
+
+        let batch = match (batch, request.input) {
+            // Tokenized ahead by the preparer
+            (Some(batch), _) => batch,
+            // Token ids are embedded as they are, bypassing the preprocessor and the cache
+            (None, EmbeddingsInput::TokenIds(ids)) => {
+                timed("tokenize", &mut timings.tokenize, || {
+                    sentence_transformer.batch_from_ids(ids.into())
+                })?
+            }
+            (None, input) => {
+                let sentences = with_instruction(
+                    &sentence_transformer,
+                    task,
+                    preprocess(
+                        self.preprocessor.as_deref(),
+                        request.input_type,
+                        input.into_texts()?,
+                    ),
+                );
+
+                // The cache looks up embeddings by input, and tokenizes what it doesn't have
+                if let Some(cache) = &self.cache {
+                    let CachedEmbedOutput {
+                        embeddings,
+                        usage,
+                        inputs,
+                    } = timed("forward", &mut timings.forward, || {
+                        // Tasks embed the same inputs differently
+                        let model_id = match task {
+                            Some(task) => format!("{}:{task}", request.model),
+                            None => request.model.clone(),
+                        };
+                        cache.encode_batch_with_usage(
+                            &sentence_transformer,
+                            &model_id,
+                            &sentences,
+                            normalize,
+                            request.pooling,
+                        )
+                    })?;
+
+                    return Ok(EmbeddingsResponse::from_vectors(
+                        embeddings,
+                        usage,
+                        inputs,
+                        request.model,
+                    ));
+                }
+
+                timed("tokenize", &mut timings.tokenize, || {
+                    sentence_transformer.tokenize_batch(sentences)
+                })?
+            }
+        };
 
         // Infer embeddings
-        let EmbedOutput { embeddings, usage } = self
-            .sentence_transformer
-            .encode_batch_with_usage(sentences.into(), NORMALIZE)?;
+        let EmbedOutput {
+            embeddings,
+            usage,
+            inputs,
+        } = timed("forward", &mut timings.forward, || match request.pooling {
+            Some(pooling) => {
+                sentence_transformer.encode_tokenized_with_pooling(&batch, normalize, pooling)
+            }
+            None => sentence_transformer.encode_tokenized(&batch, normalize),
+        })?;
 
-        let response = EmbeddingsResponse::from_embeddings(embeddings, usage, request.model);
+        let response =
+            EmbeddingsResponse::from_embeddings(embeddings, usage, inputs, request.model);
 
         Ok(response)
     }
@@ -58,21 +637,357 @@ impl From<SentenceTransformer> for EmbeddingsHandler {
     }
 }
 
+impl From<ImageEncoder> for EmbeddingsHandler {
+    fn from(image_encoder: ImageEncoder) -> Self {
+        Self::new_multimodal(image_encoder)
+    }
+}
+
+/// Where the requests for a model are processed.
+#[derive(Clone)]
+enum Backend {
+    /// Executors in this process, one per replica
+    Executors {
+        clients: Arc<Vec<Client<EmbeddingsHandler>>>,
+        next: Arc<AtomicUsize>,
+    },
+    /// A worker process, which runs the replicas itself
+    Worker {
+        worker: Arc<WorkerProcess>,
+        replicas: usize,
+    },
+}
+
 /// Embeddings inference struct
 #[derive(Clone)]
-pub struct EmbeddingsClient(Client<EmbeddingsHandler>);
+pub struct EmbeddingsClient {
+    backend: Backend,
+    limiter: Arc<Limiter>,
+    /// Whether the model accepts image inputs
+    multimodal: bool,
+    /// How image inputs are fetched
+    images: ImageConfig,
+    /// How the embeddings of the model are produced
+    metadata: EmbeddingsMetadata,
+    /// Device the model runs on
+    device: Device,
+    /// Tokenizer of the model, to tokenize without going through the model
+    tokenizer: Arc<Tokenizer>,
+    /// Tokenizer of the model without truncation, to count the tokens of inputs. Created when
+    /// it is first needed
+    token_counter: Arc<OnceLock<Tokenizer>>,
+    /// Classifier of text classification models
+    classifier: Option<ClassifyClient>,
+    /// How long the model took to warm up, if it was warmed up
+    warmup: Option<Duration>,
+}
 
 impl EmbeddingsClient {
-    pub(crate) fn new(executor: &DedicatedExecutor<EmbeddingsHandler>) -> Self {
-        Self(Client::new(executor))
+    /// Start `limits.replicas` executors for the handler, which share its model, and a client
+    /// that distributes the requests over them.
+    pub(crate) fn spawn(
+        handler: EmbeddingsHandler,
+        limits: QueueLimits,
+    ) -> anyhow::Result<(Self, Vec<DedicatedExecutor<EmbeddingsHandler>>)> {
+        let executors = (0..limits.replicas)
+            .map(|_| DedicatedExecutor::new(handler.clone()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let client = Self {
+            backend: Backend::Executors {
+                clients: Arc::new(executors.iter().map(Client::new).collect()),
+                next: Arc::new(AtomicUsize::new(0)),
+            },
+            limiter: Arc::new(Limiter::new(limits)),
+            multimodal: handler.is_multimodal(),
+            images: handler.images,
+            metadata: handler.metadata.clone(),
+            device: handler.device().clone(),
+            tokenizer: Arc::new(handler.tokenizer().clone()),
+            token_counter: Arc::new(OnceLock::new()),
+            classifier: None,
+            warmup: None,
+        };
+
+        Ok((client, executors))
+    }
+
+    /// A client that sends the requests to a worker process, which runs the model with the
+    /// given metadata.
+    pub(crate) fn from_worker(
+        worker: Arc<WorkerProcess>,
+        metadata: EmbeddingsMetadata,
+        multimodal: bool,
+        device: Device,
+        tokenizer: Tokenizer,
+        limits: QueueLimits,
+    ) -> Self {
+        Self {
+            backend: Backend::Worker {
+                worker,
+                replicas: limits.replicas,
+            },
+            limiter: Arc::new(Limiter::new(limits)),
+            multimodal,
+            // The worker fetches the images itself
+            images: ImageConfig::default(),
+            metadata,
+            device,
+            tokenizer: Arc::new(tokenizer),
+            token_counter: Arc::new(OnceLock::new()),
+            classifier: None,
+            warmup: None,
+        }
+    }
+
+    /// Record how long the model took to warm up.
+    pub(crate) fn with_warmup(self, warmup: Option<Duration>) -> Self {
+        Self { warmup, ..self }
+    }
+
+    /// How long the model took to warm up, if it was warmed up.
+    pub fn warmup(&self) -> Option<Duration> {
+        self.warmup
+    }
+
+    /// Serve classification requests with the given classifier, within the queue limits of the
+    /// model.
+    pub(crate) fn with_classifier(
+        self,
+        classifier: Option<TextClassifier>,
+    ) -> anyhow::Result<Self> {
+        let classifier = classifier
+            .map(|classifier| ClassifyClient::spawn(classifier, self.limiter.clone()))
+            .transpose()?;
+
+        Ok(Self { classifier, ..self })
+    }
+
+    /// Classification client, if the model is a text classification model.
+    pub fn classifier(&self) -> Option<&ClassifyClient> {
+        self.classifier.as_ref()
+    }
+
+    /// Revision of the model repository the model was loaded from.
+    pub fn revision(&self) -> &str {
+        &self.metadata.revision
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// The tokenizer of the model.
+    pub fn tokenizer(&self) -> &Arc<Tokenizer> {
+        &self.tokenizer
+    }
+
+    /// Count the tokens of each text, including special tokens, without truncating them.
+    pub async fn count_tokens(&self, texts: Vec<String>) -> anyhow::Result<Vec<usize>> {
+        let tokenizer = self.tokenizer.clone();
+        let token_counter = self.token_counter.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let counter = token_counter.get_or_init(|| {
+                let mut counter = Tokenizer::clone(&tokenizer);
+                counter.with_padding(None);
+                // Removing the truncation can't fail
+                let _ = counter.with_truncation(None);
+                counter
+            });
+            let encodings = counter
+                .encode_batch(texts, true)
+                .map_err(anyhow::Error::msg)?;
+
+            Ok(encodings.iter().map(|encoding| encoding.len()).collect())
+        })
+        .await?
+    }
+
+    /// Number of executors the requests are distributed over.
+    pub fn replicas(&self) -> usize {
+        match &self.backend {
+            Backend::Executors { clients, .. } => clients.len(),
+            Backend::Worker { replicas, .. } => *replicas,
+        }
+    }
+
+    /// Number of requests waiting for a free slot.
+    pub fn queued(&self) -> usize {
+        self.limiter.queued()
+    }
+
+    /// Number of requests queued in the executors or being processed.
+    pub fn in_flight(&self) -> usize {
+        self.limiter.in_flight()
+    }
+
+    /// How the embeddings for a request are produced, given the pooling strategy it asks for.
+    pub fn metadata(&self, pooling: Option<PoolingStrategy>) -> EmbeddingsMetadata {
+        EmbeddingsMetadata {
+            pooling: pooling.or(self.metadata.pooling),
+            ..self.metadata.clone()
+        }
     }
 
     pub async fn generate_embedding(
         &self,
         request: EmbeddingsRequest,
     ) -> anyhow::Result<EmbeddingsResponse> {
-        let rx = self.0.send(request).await?;
-        rx.await
-            .map_err(|_| anyhow::anyhow!("Failed to receive response from executor"))
+        if request.input.has_images() && !self.multimodal {
+            return Err(ServerError::InvalidRequest(format!(
+                "Model `{}` doesn't support image inputs",
+                request.model
+            ))
+            .into());
+        }
+
+        let metadata = self.metadata(request.pooling);
+        let (dimensions, dtype) = (request.dimensions, request.dtype);
+        let normalize = request.normalize.unwrap_or_default();
+        let encoding_format = request.encoding_format.clone();
+        let mut response = match &self.backend {
+            Backend::Executors { clients, next } => {
+                // Fetch the images before queueing, so downloads don't take up a place in the
+                // queue
+                let images = match &request.input {
+                    EmbeddingsInput::Multimodal(inputs) if request.input.has_images() => {
+                        let images = inputs.iter().filter_map(|input| match input {
+                            MultimodalInput::Image { image } => Some(image.as_str()),
+                            MultimodalInput::Text { .. } => None,
+                        });
+                        fetch_images(images, self.images).await?
+                    }
+                    _ => Vec::new(),
+                };
+
+                let queued_at = Instant::now();
+                self.limiter
+                    .run(async {
+                        let task = EmbeddingsTask {
+                            request,
+                            batch: None,
+                            images,
+                            queued_at,
+                            timings: Timings::default(),
+                        };
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let rx = clients[i % clients.len()].send(task).await?;
+                        rx.await.map_err(|_| {
+                            anyhow::anyhow!("Failed to receive response from executor")
+                        })?
+                    })
+                    .await?
+            }
+            // The worker fetches the images itself
+            Backend::Worker { worker, .. } => self.limiter.run(worker.embed(request)).await?,
+        };
+        if let Some(dimensions) = dimensions {
+            response.truncate(dimensions, normalize)?;
+        }
+        if let Some(dtype) = dtype {
+            response.round_to(dtype);
+        }
+        if let Some(encoding_format) = &encoding_format {
+            response.encode(encoding_format);
+        }
+        response.metadata = Some(metadata);
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::data_models::TokenIds;
+    use crate::server::preprocess::{PreprocessArgs, PreprocessConfig};
+    use crate::server::test_utils::tiny_embeddings_client;
+    use tempfile::tempdir;
+
+    fn norm(values: &[f32]) -> f32 {
+        values.iter().map(|v| v * v).sum::<f32>().sqrt()
+    }
+
+    #[tokio::test]
+    async fn test_normalize_with_dimensions() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let (client, _executors) = tiny_embeddings_client(dir.path(), None)?;
+        let embed = |normalize, dimensions| {
+            let mut request =
+                EmbeddingsRequest::new("Hello, world!".to_string().into(), "m".to_string());
+            request.normalize = normalize;
+            request.dimensions = dimensions;
+            client.generate_embedding(request)
+        };
+
+        let full = embed(None, None).await?.data[0].embedding.to_vec();
+        for normalize in [Some(true), Some(false), None] {
+            let embedding = embed(normalize, None).await?.data[0].embedding.to_vec();
+            let truncated = embed(normalize, Some(4)).await?.data[0].embedding.to_vec();
+            assert_eq!(truncated.len(), 4);
+
+            if normalize == Some(true) {
+                assert!((norm(&embedding) - 1.).abs() < 1e-5);
+                assert!((norm(&truncated) - 1.).abs() < 1e-5);
+            } else {
+                // Without normalization, the embeddings are truncated as they are
+                assert_eq!(embedding, full);
+                assert_eq!(truncated, full[..4]);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_input_type() -> anyhow::Result<()> {
+        let config = PreprocessConfig::from_args(&PreprocessArgs {
+            preprocess_config: None,
+            query_prefix: vec!["m=query: ".to_string()],
+            passage_prefix: Vec::new(),
+        })?;
+        let dir = tempdir()?;
+        let (client, _executors) = tiny_embeddings_client(dir.path(), config.preprocessor("m")?)?;
+        let embed = |input: EmbeddingsInput, input_type| {
+            let mut request = EmbeddingsRequest::new(Vec::<String>::new().into(), "m".to_string());
+            request.input = input;
+            request.input_type = Some(input_type);
+            client.generate_embedding(request)
+        };
+        let text = || EmbeddingsInput::Text("Hello, world!".to_string().into());
+        let is_invalid = |result: anyhow::Result<EmbeddingsResponse>| {
+            matches!(
+                result.map_err(ServerError::from),
+                Err(ServerError::InvalidRequest(_))
+            )
+        };
+
+        // The query prefix is put in front of queries
+        let query = embed(text(), InputType::Query).await?;
+        let prefixed =
+            EmbeddingsRequest::new("query: Hello, world!".to_string().into(), "m".to_string());
+        let prefixed = client.generate_embedding(prefixed).await?;
+        assert_eq!(
+            query.data[0].embedding.to_vec(),
+            prefixed.data[0].embedding.to_vec()
+        );
+
+        // The model has no passage prefix
+        assert!(is_invalid(embed(text(), InputType::Passage).await));
+
+        // Token ids can't be prefixed
+        let ids = EmbeddingsInput::TokenIds(TokenIds::Single(vec![101, 7592, 102]));
+        assert!(is_invalid(embed(ids, InputType::Query).await));
+
+        // Models without a preprocessor have no prefixes
+        let dir = tempdir()?;
+        let (client, _executors) = tiny_embeddings_client(dir.path(), None)?;
+        let mut request = EmbeddingsRequest::new(Vec::<String>::new().into(), "m".to_string());
+        request.input = text();
+        request.input_type = Some(InputType::Query);
+        assert!(is_invalid(client.generate_embedding(request).await));
+
+        Ok(())
     }
 }
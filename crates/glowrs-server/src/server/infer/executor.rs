@@ -1,12 +1,13 @@
 use anyhow::Result;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+use tokio::sync::mpsc::{channel, unbounded_channel, Receiver, UnboundedReceiver, UnboundedSender};
 use uuid::Uuid;
 
 use crate::server::infer::batch::QueueEntry;
-use crate::server::infer::handler::RequestHandler;
+use crate::server::infer::handler::{Preparer, RequestHandler};
 
 /// Queue command
-#[allow(dead_code)]
 pub(crate) enum Command<THandler>
 where
     THandler: RequestHandler,
@@ -22,6 +23,8 @@ where
     THandler: RequestHandler,
 {
     pub(crate) tx: UnboundedSender<Command<THandler>>,
+    /// Thread of the executor, until it is joined
+    thread: Arc<Mutex<Option<JoinHandle<Result<()>>>>>,
 }
 
 impl<THandler> DedicatedExecutor<THandler>
@@ -32,7 +35,7 @@ where
         // Create channel
         let (tx, rx) = unbounded_channel();
 
-        let _join_handle = std::thread::spawn(move || {
+        let thread = std::thread::spawn(move || {
             // Create a new Runtime to run tasks
             let runtime = tokio::runtime::Builder::new_multi_thread()
                 .enable_all()
@@ -43,31 +46,146 @@ where
             runtime.block_on(queue_task(rx, processor))
         });
 
-        Ok(Self { tx })
+        Ok(Self {
+            tx,
+            thread: Arc::new(Mutex::new(Some(thread))),
+        })
     }
+
+    /// Stop the executor once it has processed the tasks queued before, and wait for it to
+    /// finish. Tasks whose clients stopped waiting are skipped.
+    pub(crate) fn stop(&self) {
+        // The executor has already stopped if the channel is closed
+        let _ = self.tx.send(Command::Stop);
+
+        let thread = self
+            .thread
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(thread) = thread {
+            match thread.join() {
+                Ok(Err(err)) => tracing::error!("Executor failed: {err}"),
+                Err(_) => tracing::error!("Executor panicked"),
+                Ok(Ok(())) => {}
+            }
+        }
+    }
+}
+
+/// Commands for the processor, either straight from the clients or through the preparer.
+enum Commands<THandler>
+where
+    THandler: RequestHandler,
+{
+    Direct(UnboundedReceiver<Command<THandler>>),
+    Prepared(Receiver<Command<THandler>>),
+}
+
+impl<THandler> Commands<THandler>
+where
+    THandler: RequestHandler,
+{
+    async fn recv(&mut self) -> Option<Command<THandler>> {
+        match self {
+            Commands::Direct(receiver) => receiver.recv().await,
+            Commands::Prepared(receiver) => receiver.recv().await,
+        }
+    }
+}
+
+/// Prepare the requests on a separate thread. Only one prepared request is buffered, so the
+/// preparer stays a single request ahead of the processor.
+fn prepare_ahead<THandler>(
+    mut receiver: UnboundedReceiver<Command<THandler>>,
+    mut preparer: Preparer<THandler::Input>,
+) -> Receiver<Command<THandler>>
+where
+    THandler: RequestHandler,
+{
+    let (tx, rx) = channel(1);
+
+    std::thread::spawn(move || {
+        while let Some(cmd) = receiver.blocking_recv() {
+            let entry = match cmd {
+                Command::Append(entry) => entry,
+                Command::Stop => {
+                    let _ = tx.blocking_send(Command::Stop);
+                    break;
+                }
+            };
+
+            if entry.response_tx.is_closed() {
+                tracing::debug!("Skipping task {}, the client stopped waiting", entry.id);
+                continue;
+            }
+
+            let QueueEntry {
+                id,
+                request,
+                response_tx,
+                queue_time,
+            } = entry;
+
+            match preparer(request) {
+                Ok(request) => {
+                    let entry = QueueEntry {
+                        id,
+                        request,
+                        response_tx,
+                        queue_time,
+                    };
+                    if tx.blocking_send(Command::Append(entry)).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    tracing::debug!("Preparing task {} failed: {}", id, err);
+                    let _ = response_tx.send(Err(err));
+                }
+            }
+        }
+    });
+
+    rx
 }
 
 // Generic background task executor with stateful processor
 async fn queue_task<THandler>(
-    mut receiver: UnboundedReceiver<Command<THandler>>,
+    receiver: UnboundedReceiver<Command<THandler>>,
     mut processor: THandler,
 ) -> Result<()>
 where
     THandler: RequestHandler,
 {
+    let mut receiver = match processor.preparer() {
+        Some(preparer) => Commands::Prepared(prepare_ahead(receiver, preparer)),
+        None => Commands::Direct(receiver),
+    };
+
     'main: while let Some(cmd) = receiver.recv().await {
         use Command::*;
 
         match cmd {
             Append(entry) => {
+                // The client stopped waiting, e.g. after a timeout
+                if entry.response_tx.is_closed() {
+                    tracing::debug!("Skipping task {}, the client stopped waiting", entry.id);
+                    continue;
+                }
+
                 tracing::trace!(
                     "Processing task {}, added {}ms ago",
                     entry.id,
                     entry.queue_time.elapsed().as_millis()
                 );
 
-                // Process the task
-                let response = processor.handle(entry.request)?;
+                // Process the task. Errors are returned to the client, so a single bad
+                // request doesn't take down the worker.
+                let response = processor.handle(entry.request);
+                if let Err(err) = &response {
+                    tracing::debug!("Task {} failed: {}", entry.id, err);
+                }
 
                 if entry.response_tx.send(response).is_ok() {
                     tracing::trace!("Successfully sent response for task {}", entry.id)
@@ -113,6 +231,9 @@ mod tests {
         type Output = Task;
 
         fn handle(&mut self, request: Task) -> Result<Task> {
+            if request.name.is_empty() {
+                anyhow::bail!("Task has no name");
+            }
             let new_name = format!("{}-processed", request.name);
             Ok(Task::new(new_name))
         }
@@ -140,10 +261,109 @@ mod tests {
             .unwrap();
 
         // Wait for the response
-        let response = task_rx.await.unwrap();
+        let response = task_rx.await.unwrap().unwrap();
         assert_eq!(
             response,
             Task::new(format!("{}-processed", name).to_string())
         );
     }
+
+    #[tokio::test]
+    async fn test_queue_continues_after_error() {
+        let executor = DedicatedExecutor::new(TaskProcessor::new().unwrap()).unwrap();
+
+        for (name, ok) in [("", false), ("test", true)] {
+            let (task_tx, task_rx) = oneshot::channel();
+            executor
+                .tx
+                .send(Command::Append(QueueEntry::new(
+                    Task::new(name.to_string()),
+                    task_tx,
+                )))
+                .unwrap();
+
+            assert_eq!(task_rx.await.unwrap().is_ok(), ok);
+        }
+    }
+
+    /// Prepares tasks by tagging them with the preparing thread.
+    struct PreparedTaskProcessor;
+
+    impl RequestHandler for PreparedTaskProcessor {
+        type Input = Task;
+        type Output = Task;
+
+        fn handle(&mut self, request: Task) -> Result<Task> {
+            let (name, thread) = request.name.rsplit_once('@').unwrap();
+            assert_ne!(thread, format!("{:?}", std::thread::current().id()));
+            Ok(Task::new(format!("{name}-processed")))
+        }
+
+        fn preparer(&self) -> Option<Preparer<Task>> {
+            Some(Box::new(|request: Task| {
+                if request.name.is_empty() {
+                    anyhow::bail!("Task has no name");
+                }
+                let thread = std::thread::current().id();
+                Ok(Task::new(format!("{}-prepared@{thread:?}", request.name)))
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_with_preparer() {
+        let executor = DedicatedExecutor::new(PreparedTaskProcessor).unwrap();
+
+        let mut receivers = Vec::new();
+        for name in ["a", "", "b"] {
+            let (task_tx, task_rx) = oneshot::channel();
+            executor
+                .tx
+                .send(Command::Append(QueueEntry::new(
+                    Task::new(name.to_string()),
+                    task_tx,
+                )))
+                .unwrap();
+            receivers.push(task_rx);
+        }
+
+        let responses: Vec<_> = futures_util::future::join_all(receivers).await;
+        assert_eq!(
+            responses[0].as_ref().unwrap().as_ref().unwrap(),
+            &Task::new("a-prepared-processed".to_string())
+        );
+        assert!(responses[1].as_ref().unwrap().is_err());
+        assert_eq!(
+            responses[2].as_ref().unwrap().as_ref().unwrap(),
+            &Task::new("b-prepared-processed".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stop_after_queued_tasks() {
+        let executor = DedicatedExecutor::new(TaskProcessor::new().unwrap()).unwrap();
+
+        let (task_tx, task_rx) = oneshot::channel();
+        executor
+            .tx
+            .send(Command::Append(QueueEntry::new(
+                Task::new("test".to_string()),
+                task_tx,
+            )))
+            .unwrap();
+        let tx = executor.tx.clone();
+        tokio::task::spawn_blocking(move || executor.stop())
+            .await
+            .unwrap();
+
+        // The task queued before stopping is processed, and the executor takes no more tasks
+        assert!(task_rx.await.unwrap().is_ok());
+        let (task_tx, _task_rx) = oneshot::channel();
+        assert!(tx
+            .send(Command::Append(QueueEntry::new(
+                Task::new("test".to_string()),
+                task_tx,
+            )))
+            .is_err());
+    }
 }
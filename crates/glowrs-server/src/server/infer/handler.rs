@@ -1,5 +1,8 @@
 use std::marker::PhantomData;
 
+/// Prepares requests before they are handled. See [`RequestHandler::preparer`].
+pub type Preparer<Input> = Box<dyn FnMut(Input) -> anyhow::Result<Input> + Send>;
+
 /// Trait representing a (stateful) task processor that should run inside its
 /// own thread.
 pub trait RequestHandler
@@ -10,6 +13,13 @@ where
     type Output: Send + Sync + 'static;
 
     fn handle(&mut self, request: Self::Input) -> anyhow::Result<Self::Output>;
+
+    /// Work to do on a request before it is handled, e.g. tokenization. The executor runs it on
+    /// a separate thread, so the next request is prepared while the current one is handled.
+    /// Errors are returned to the client without handling the request.
+    fn preparer(&self) -> Option<Preparer<Self::Input>> {
+        None
+    }
 }
 
 pub struct CustomFnRequestHandler<F, Input, Output>
@@ -90,7 +100,7 @@ mod test {
         let client = Client::new(&executor);
         let rx = client.send(task).await.unwrap();
 
-        let response = rx.await.unwrap();
+        let response = rx.await.unwrap().unwrap();
 
         assert_eq!("task-processed", response);
     }
@@ -114,7 +124,7 @@ mod test {
         let task = Tensor::randn::<_, f32>(0., 2., (TENSOR_DIM, 1), &DEVICE).unwrap();
         let rx = client.send(task).await.unwrap();
 
-        let response = rx.await.unwrap();
+        let response = rx.await.unwrap().unwrap();
 
         assert_eq!(response.dims()[0], 1);
         assert_eq!(response.dims()[1], 1);
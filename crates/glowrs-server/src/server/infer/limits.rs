@@ -0,0 +1,318 @@
+//! Per-model request limits
+//!
+//! Every model has its own limiter, so a flood of requests for one model can't exhaust memory or
+//! starve the other models. A limited number of requests per model is in flight (queued in the
+//! executor or being processed) at once; further requests wait for a free slot in a bounded
+//! waiting queue, and are rejected when it is full. Requests that take longer than the timeout,
+//! including the time spent waiting, fail. The limits apply to a model as a whole, however many
+//! replicas it runs on.
+
+use anyhow::{Context, Result};
+use clap::Args;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::Semaphore;
+
+#[derive(Debug, Args)]
+pub struct QueueArgs {
+    /// Maximum number of requests per model that are queued in the executor or processed at once
+    #[clap(long, default_value = "16")]
+    pub max_concurrent_requests: usize,
+
+    /// Maximum number of requests per model waiting for one of the concurrent slots. Further
+    /// requests are rejected with `429 Too Many Requests`
+    #[clap(long, default_value = "128")]
+    pub max_queue_size: usize,
+
+    /// Milliseconds a request for a model may take, including waiting, before it fails with
+    /// `503 Service Unavailable`. No timeout if not given
+    #[clap(long)]
+    pub request_timeout_ms: Option<u64>,
+
+    /// Number of executors per model. Replicas of a model share its weights, and requests are
+    /// distributed over them round-robin
+    #[clap(long, default_value = "1")]
+    pub replicas: usize,
+
+    /// Limits of a single model, as `<model>=<key>:<value>,...` with the keys `concurrency`,
+    /// `queue`, `timeout-ms` and `replicas`
+    #[clap(long)]
+    pub model_limits: Vec<String>,
+}
+
+/// Limits of the requests for a model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueueLimits {
+    pub max_concurrent: usize,
+    pub max_queued: usize,
+    pub timeout: Option<Duration>,
+    pub replicas: usize,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 16,
+            max_queued: 128,
+            timeout: None,
+            replicas: 1,
+        }
+    }
+}
+
+impl QueueLimits {
+    /// Override the limits with `<key>:<value>` settings separated by commas.
+    fn with_settings(mut self, settings: &str) -> Result<Self> {
+        for setting in settings.split(',').filter(|s| !s.is_empty()) {
+            let (key, value) = setting
+                .split_once(':')
+                .with_context(|| format!("Invalid model limit `{setting}`"))?;
+            let value: u64 = value
+                .parse()
+                .with_context(|| format!("Invalid value of model limit `{key}`"))?;
+
+            match key {
+                "concurrency" => self.max_concurrent = value as usize,
+                "queue" => self.max_queued = value as usize,
+                "timeout-ms" => self.timeout = Some(Duration::from_millis(value)),
+                "replicas" => self.replicas = value as usize,
+                _ => anyhow::bail!("Unknown model limit `{key}`"),
+            }
+        }
+
+        self.validate()
+    }
+
+    fn validate(self) -> Result<Self> {
+        if self.max_concurrent == 0 {
+            anyhow::bail!("Maximum number of concurrent requests should be at least 1");
+        }
+        if self.replicas == 0 {
+            anyhow::bail!("Number of replicas should be at least 1");
+        }
+        Ok(self)
+    }
+}
+
+/// Request limits by model name.
+#[derive(Debug, Clone, Default)]
+pub struct QueueConfig {
+    default: QueueLimits,
+    models: HashMap<String, QueueLimits>,
+}
+
+impl QueueConfig {
+    pub fn from_args(args: &QueueArgs) -> Result<Self> {
+        let default = QueueLimits {
+            max_concurrent: args.max_concurrent_requests,
+            max_queued: args.max_queue_size,
+            timeout: args.request_timeout_ms.map(Duration::from_millis),
+            replicas: args.replicas,
+        }
+        .validate()?;
+
+        let models = args
+            .model_limits
+            .iter()
+            .map(|limits| {
+                let (model, settings) = limits
+                    .split_once('=')
+                    .with_context(|| format!("Invalid model limits `{limits}`"))?;
+                Ok((model.to_string(), default.with_settings(settings)?))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { default, models })
+    }
+
+    /// Get the limits of a model.
+    pub fn limits(&self, model: &str) -> QueueLimits {
+        self.models.get(model).copied().unwrap_or(self.default)
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum QueueError {
+    #[error("Too many requests queued for the model")]
+    Full,
+
+    #[error("Request for the model timed out")]
+    Timeout,
+}
+
+/// Enforces the [`QueueLimits`] of a model.
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    limits: QueueLimits,
+    slots: Semaphore,
+    waiting: AtomicUsize,
+}
+
+/// Counts a request as waiting until it is dropped.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Limiter {
+    pub(crate) fn new(limits: QueueLimits) -> Self {
+        Self {
+            limits,
+            slots: Semaphore::new(limits.max_concurrent),
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of requests waiting for a slot.
+    pub(crate) fn queued(&self) -> usize {
+        self.waiting.load(Ordering::SeqCst)
+    }
+
+    /// Number of requests holding a slot, queued in the executor or being processed.
+    pub(crate) fn in_flight(&self) -> usize {
+        self.limits.max_concurrent - self.slots.available_permits()
+    }
+
+    /// Run `f` once one of the concurrent slots is free.
+    pub(crate) async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let limited = async {
+            let _slot = match self.slots.try_acquire() {
+                Ok(slot) => slot,
+                Err(_) => {
+                    if self.waiting.fetch_add(1, Ordering::SeqCst) >= self.limits.max_queued {
+                        self.waiting.fetch_sub(1, Ordering::SeqCst);
+                        return Err(QueueError::Full.into());
+                    }
+                    let _waiting = Waiting(&self.waiting);
+                    self.slots.acquire().await?
+                }
+            };
+
+            f.await
+        };
+
+        match self.limits.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, limited)
+                .await
+                .map_err(|_| QueueError::Timeout)?,
+            None => limited.await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::ServerError;
+    use axum::http::{header, StatusCode};
+    use axum::response::IntoResponse;
+    use std::sync::Arc;
+    use tokio::sync::Notify;
+
+    fn limits(max_concurrent: usize, max_queued: usize, timeout_ms: Option<u64>) -> QueueLimits {
+        QueueLimits {
+            max_concurrent,
+            max_queued,
+            timeout: timeout_ms.map(Duration::from_millis),
+            replicas: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_full() {
+        let limiter = Arc::new(Limiter::new(limits(1, 1, None)));
+        let release = Arc::new(Notify::new());
+
+        // Occupy the slot, and the waiting queue
+        let mut tasks = Vec::new();
+        for _ in 0..2 {
+            let (limiter, release) = (limiter.clone(), release.clone());
+            tasks.push(tokio::spawn(async move {
+                limiter
+                    .run(async {
+                        release.notified().await;
+                        Ok(())
+                    })
+                    .await
+            }));
+            tokio::task::yield_now().await;
+        }
+
+        assert_eq!((limiter.in_flight(), limiter.queued()), (1, 1));
+        let rejected = limiter.run(async { Ok(()) }).await.unwrap_err();
+        assert_eq!(rejected.downcast_ref(), Some(&QueueError::Full));
+        let response = ServerError::from(rejected).into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+
+        release.notify_one();
+        tokio::task::yield_now().await;
+        release.notify_one();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        limiter.run(async { Ok(()) }).await.unwrap();
+        assert_eq!((limiter.in_flight(), limiter.queued()), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let limiter = Limiter::new(limits(1, 1, Some(10)));
+
+        let timed_out = limiter
+            .run(async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                Ok(())
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(timed_out.downcast_ref(), Some(&QueueError::Timeout));
+
+        // The slot is released after the timeout
+        limiter.run(async { Ok(()) }).await.unwrap();
+    }
+
+    #[test]
+    fn test_model_limits() -> Result<()> {
+        let config = QueueConfig::from_args(&QueueArgs {
+            max_concurrent_requests: 4,
+            max_queue_size: 8,
+            request_timeout_ms: None,
+            replicas: 1,
+            model_limits: vec![
+                "org/model=concurrency:2,timeout-ms:500".to_string(),
+                "org/replicated=replicas:3".to_string(),
+            ],
+        })?;
+
+        assert_eq!(config.limits("other"), limits(4, 8, None));
+        assert_eq!(config.limits("org/model"), limits(2, 8, Some(500)));
+        assert_eq!(config.limits("org/replicated").replicas, 3);
+
+        let invalid = |model_limits: &str| {
+            QueueConfig::from_args(&QueueArgs {
+                max_concurrent_requests: 4,
+                max_queue_size: 8,
+                request_timeout_ms: None,
+                replicas: 1,
+                model_limits: vec![model_limits.to_string()],
+            })
+            .is_err()
+        };
+        assert!(invalid("org/model"));
+        assert!(invalid("org/model=speed:2"));
+        assert!(invalid("org/model=concurrency:0"));
+        assert!(invalid("org/model=replicas:0"));
+
+        Ok(())
+    }
+}
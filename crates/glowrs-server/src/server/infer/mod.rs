@@ -1,8 +1,10 @@
 pub mod batch;
+pub mod classify;
 mod client;
 pub mod embed;
 pub mod executor;
 mod handler;
+pub mod limits;
 
 pub use executor::DedicatedExecutor;
 use uuid::Uuid;
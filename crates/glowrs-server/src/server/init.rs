@@ -1,8 +1,10 @@
+use std::num::NonZeroUsize;
 use std::sync::Arc;
 use std::time::Duration;
 
-use axum::extract::MatchedPath;
+use axum::extract::{DefaultBodyLimit, MatchedPath};
 use axum::http::Request;
+use axum::middleware;
 use axum::routing::{get, post};
 use axum::Router;
 
@@ -10,28 +12,238 @@ use tower_http::timeout::TimeoutLayer;
 use tower_http::trace::TraceLayer;
 
 use clap::Args;
-use glowrs::core::device::DEVICE;
+use glowrs::core::cache::EmbeddingCache;
 use thiserror::__private::AsDisplay;
 use tracing::{info_span, Span};
 
+use crate::server::aliases::{AliasArgs, Aliases};
+use crate::server::canary::{Canary, CanaryArgs};
+use crate::server::circuit_breaker::{
+    circuit_breaker, CircuitBreaker, CircuitBreakerArgs, CircuitBreakerConfig,
+};
+use crate::server::device::{DeviceArgs, DeviceConfig};
+use crate::server::grpc::{spawn_grpc, GrpcArgs};
+use crate::server::hooks::{Hooks, RouteHook};
+use crate::server::idempotency::IdempotencyStore;
+use crate::server::image::{ImageArgs, ImageConfig};
+use crate::server::infer::limits::{QueueArgs, QueueConfig};
+use crate::server::jobs::{spawn_worker, JobArgs, Jobs};
+use crate::server::openapi;
+use crate::server::preprocess::{PreprocessArgs, PreprocessConfig};
+use crate::server::quota::{QuotaArgs, Quotas};
+use crate::server::record::{RecordArgs, Recorder};
+use crate::server::request_limits::{limit_body, RequestLimitArgs, RequestLimits};
 use crate::server::routes::models::get_model;
-use crate::server::routes::{default, embeddings, models::list_models};
-use crate::server::state::ServerState;
+use crate::server::routes::{
+    batches, classify, default, embeddings, jobs,
+    models::{self, list_models},
+    shadow, stream, tei, tokenize,
+};
+use crate::server::shadow::{Shadow, ShadowArgs};
+use crate::server::shutdown::{Shutdown, ShutdownArgs};
+use crate::server::state::{ModelLoader, ServerState};
+use crate::server::tls::TlsArgs;
+use crate::server::watch::{spawn_watcher, WatchArgs};
+use crate::server::worker::{WorkerArgs, Workers};
 
 #[derive(Debug, Args)]
 pub struct RouterArgs {
     #[clap(short, long, num_args(1..), required = true)]
     pub model_repo: Vec<String>,
+
+    /// Number of embeddings to keep in an in-memory LRU cache shared by all models. Disabled if 0
+    #[clap(long, default_value = "0")]
+    pub embedding_cache_size: usize,
+
+    /// Seconds to keep responses to requests with an `Idempotency-Key` header, to replay them
+    /// for retries. Disabled if 0
+    #[clap(long, default_value = "600")]
+    pub idempotency_ttl: u64,
+
+    /// Maximum number of responses to keep for idempotency keys. The least recently used ones
+    /// are evicted first
+    #[clap(long, default_value = "10000")]
+    pub idempotency_capacity: NonZeroUsize,
+
+    /// Maximum number of models to keep loaded. The least recently used models are unloaded to
+    /// make room for others, and loaded again on their next request. Unlimited if not given
+    #[clap(long)]
+    pub max_loaded_models: Option<NonZeroUsize>,
+
+    /// Load models on their first request instead of at startup
+    #[clap(long)]
+    pub lazy_load: bool,
+
+    /// Embed a dummy batch with each model when it is loaded, so the first requests don't pay
+    /// for compiling kernels and paging in the weights
+    #[clap(long)]
+    pub warmup: bool,
+
+    #[clap(flatten)]
+    pub device_args: DeviceArgs,
+
+    #[clap(flatten)]
+    pub queue_args: QueueArgs,
+
+    #[clap(flatten)]
+    pub request_limit_args: RequestLimitArgs,
+
+    #[clap(flatten)]
+    pub alias_args: AliasArgs,
+
+    #[clap(flatten)]
+    pub watch_args: WatchArgs,
+
+    #[clap(flatten)]
+    pub preprocess_args: PreprocessArgs,
+
+    #[clap(flatten)]
+    pub image_args: ImageArgs,
+
+    #[clap(flatten)]
+    pub circuit_breaker_args: CircuitBreakerArgs,
+
+    #[clap(flatten)]
+    pub shadow_args: ShadowArgs,
+
+    #[clap(flatten)]
+    pub canary_args: CanaryArgs,
+
+    #[clap(flatten)]
+    pub record_args: RecordArgs,
+
+    #[clap(flatten)]
+    pub quota_args: QuotaArgs,
+
+    #[clap(flatten)]
+    pub job_args: JobArgs,
+
+    #[clap(flatten)]
+    pub worker_args: WorkerArgs,
+
+    #[clap(flatten)]
+    pub grpc_args: GrpcArgs,
+
+    #[clap(flatten)]
+    pub tls_args: TlsArgs,
+
+    #[clap(flatten)]
+    pub shutdown_args: ShutdownArgs,
 }
 
-pub fn init_router(args: &RouterArgs) -> anyhow::Result<Router> {
-    let state = Arc::new(ServerState::new(args.model_repo.clone(), &DEVICE)?);
+/// Build the router, running `hooks` on the embeddings routes, see [`RouteHook`]. Serve it
+/// through the returned [`Shutdown`] to drain requests and stop the models on shutdown.
+pub fn init_router(
+    args: &RouterArgs,
+    hooks: Vec<Arc<dyn RouteHook>>,
+) -> anyhow::Result<(Router, Shutdown)> {
+    let cache = NonZeroUsize::new(args.embedding_cache_size)
+        .map(|capacity| Arc::new(EmbeddingCache::new(capacity)));
+
+    let device = DeviceConfig::from_args(&args.device_args)?;
+    let preprocess_config = PreprocessConfig::from_args(&args.preprocess_args)?;
+    let images = ImageConfig::from_args(&args.image_args);
+    let breaker_config = CircuitBreakerConfig::from_args(&args.circuit_breaker_args)?;
+    let queue_config = QueueConfig::from_args(&args.queue_args)?;
+
+    let idempotency = Some(Duration::from_secs(args.idempotency_ttl))
+        .filter(|ttl| !ttl.is_zero())
+        .map(|ttl| IdempotencyStore::new(ttl, args.idempotency_capacity));
 
-    let router = Router::new()
+    let shadow = Shadow::from_args(
+        &args.shadow_args,
+        &args.model_repo,
+        &device,
+        &preprocess_config,
+        &queue_config,
+        images,
+    )?;
+
+    let canary = Canary::from_args(
+        &args.canary_args,
+        &args.model_repo,
+        &device,
+        &preprocess_config,
+        &queue_config,
+        images,
+    )?;
+
+    let recorder = Recorder::from_args(&args.record_args)?;
+    let quotas = Quotas::from_args(&args.quota_args)?;
+    let jobs = Jobs::from_args(&args.job_args)?;
+    let workers = Workers::from_args(args)?;
+    let aliases = Aliases::from_args(&args.alias_args)?;
+
+    let state = Arc::new(
+        ServerState::new(
+            args.model_repo.clone(),
+            ModelLoader {
+                device,
+                cache,
+                preprocess_config,
+                queue_config,
+                images,
+                workers,
+                warmup: args.warmup,
+            },
+            args.max_loaded_models,
+            args.lazy_load,
+        )?
+        .with_aliases(aliases)?
+        .with_idempotency(idempotency)
+        .with_shadow(shadow)
+        .with_canary(canary)
+        .with_recorder(recorder)
+        .with_quotas(quotas)
+        .with_jobs(jobs)
+        .with_request_limits(RequestLimits::from_args(&args.request_limit_args))
+        .with_hooks(Hooks::new(hooks)),
+    );
+
+    spawn_watcher(&args.watch_args, &args.model_repo, state.clone())?;
+    spawn_worker(state.clone());
+    spawn_grpc(&args.grpc_args, &args.tls_args, state.clone())?;
+    let shutdown = Shutdown::new(&args.shutdown_args, state.clone());
+
+    let mut router = Router::new()
         .route("/v1/embeddings", post(embeddings::infer_text_embeddings))
+        .route(
+            "/v1/embeddings/multi",
+            post(embeddings::infer_multi_model_embeddings),
+        )
+        .route("/v1/classify", post(classify::classify))
+        .route("/predict", post(classify::predict))
+        .route("/embed", post(tei::embed))
+        .route("/embed_sparse", post(tei::embed_sparse))
+        .route("/v1/embeddings/ws", get(stream::stream_embeddings));
+
+    // Only inference routes are guarded, so health checks and model listing keep working
+    if let Some(config) = breaker_config {
+        router = router.route_layer(middleware::from_fn_with_state(
+            CircuitBreaker::new(config),
+            circuit_breaker,
+        ));
+    }
+
+    let max_body_bytes = args.request_limit_args.max_body_bytes;
+    let router = router
         .route("/v1/models", get(list_models))
         .route("/v1/models/:model_id", get(get_model))
+        .route("/v1/models/load", post(models::load_model))
+        .route("/v1/models/unload", post(models::unload_model))
+        .route("/v1/tokenize", post(tokenize::tokenize))
+        .route("/v1/decode", post(tokenize::decode))
+        .route("/v1/jobs", post(jobs::create_job))
+        .route("/v1/jobs/:job_id", get(jobs::get_job))
+        .route("/v1/jobs/:job_id/results", get(jobs::get_job_results))
+        .route("/v1/files", post(batches::upload_file))
+        .route("/v1/files/:file_id/content", get(batches::get_file_content))
+        .route("/v1/batches", post(batches::create_batch))
+        .route("/v1/batches/:batch_id", get(batches::get_batch))
+        .route("/v1/shadow", get(shadow::shadow_stats))
         .route("/health", get(default::health_check))
+        .route("/status", get(default::status))
+        .merge(openapi::docs())
         .with_state(state)
         .layer((
             TraceLayer::new_for_http()
@@ -56,6 +268,9 @@ pub fn init_router(args: &RouterArgs) -> anyhow::Result<Router> {
                     // created above.
                 }),
             TimeoutLayer::new(Duration::from_secs(15)),
+            // The body is read up front, so the extractors get the same limit
+            DefaultBodyLimit::max(max_body_bytes),
+            middleware::from_fn_with_state(max_body_bytes, limit_body),
         ));
-    Ok(router)
+    Ok((router, shutdown))
 }
@@ -0,0 +1,385 @@
+//! Durable batch jobs
+//!
+//! Bulk embedding jobs are submitted to `/v1/jobs` and processed in the background, in chunks
+//! that go through the same request queue as the embeddings routes. Every job is persisted in a
+//! directory of its own: its inputs, its state and the embeddings of the inputs processed so
+//! far, one JSON line per input. When the server restarts, unfinished jobs resume after the last
+//! embedding that was written, instead of being lost.
+//!
+//! The OpenAI-compatible `/v1/batches` routes run batches as jobs too, see
+//! [`crate::server::batches`].
+
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::server::data_models::{EmbeddingsRequest, Sentences};
+use crate::server::infer::limits::QueueError;
+use crate::server::state::ServerState;
+
+const JOB_FILE: &str = "job.json";
+const INPUT_FILE: &str = "input.json";
+const RESULTS_FILE: &str = "results.jsonl";
+/// Directory of the uploaded batch input files, see [`crate::server::batches`]
+const FILES_DIR: &str = "files";
+/// Time to wait before retrying a chunk that was rejected because the model was busy
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Args)]
+pub struct JobArgs {
+    /// Directory to persist batch jobs in. Enables the `/v1/jobs` routes; unfinished jobs in the
+    /// directory resume on startup
+    #[clap(long)]
+    pub jobs_dir: Option<PathBuf>,
+
+    /// Number of inputs of a batch job that are embedded per request to the model
+    #[clap(long, default_value = "64")]
+    pub job_chunk_size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+}
+
+impl JobStatus {
+    fn is_finished(self) -> bool {
+        matches!(self, Self::Completed | Self::Failed)
+    }
+}
+
+/// A batch job, as persisted and returned by the `/v1/jobs` routes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct Job {
+    pub id: String,
+    pub object: String,
+    pub model: String,
+    pub status: JobStatus,
+    /// Time the job was submitted, in seconds since the Unix epoch
+    pub created_at: u64,
+    /// Number of inputs
+    pub total: usize,
+    /// Number of inputs embedded so far
+    pub completed: usize,
+    /// Why the job failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Request to embed a batch of texts in the background.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateJobRequest {
+    pub model: String,
+    pub input: Vec<String>,
+}
+
+/// Embedding of an input of a batch job, one JSON line per input in the results.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct JobResult {
+    pub(crate) index: usize,
+    pub(crate) embedding: Vec<f32>,
+}
+
+/// Store of the batch jobs, backed by a directory.
+pub struct Jobs {
+    dir: PathBuf,
+    chunk_size: usize,
+    jobs: Mutex<HashMap<String, Job>>,
+    /// Wakes up the worker when a job is submitted
+    submitted: Notify,
+}
+
+impl Jobs {
+    pub fn from_args(args: &JobArgs) -> Result<Option<Self>> {
+        let Some(dir) = &args.jobs_dir else {
+            return Ok(None);
+        };
+        if args.job_chunk_size == 0 {
+            anyhow::bail!("Job chunk size should be at least 1");
+        }
+
+        Self::open(dir, args.job_chunk_size).map(Some)
+    }
+
+    /// Load the jobs in `dir`, queueing unfinished jobs to resume where they left off.
+    pub(crate) fn open(dir: &Path, chunk_size: usize) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create jobs directory {}", dir.display()))?;
+
+        let mut jobs = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let job_dir = entry?.path();
+            if !job_dir.join(JOB_FILE).is_file() {
+                continue;
+            }
+            let mut job: Job = serde_json::from_slice(&fs::read(job_dir.join(JOB_FILE))?)
+                .with_context(|| format!("Invalid job in {}", job_dir.display()))?;
+
+            if !job.status.is_finished() {
+                job.completed = recover_results(&job_dir.join(RESULTS_FILE))?;
+                job.status = JobStatus::Queued;
+                tracing::info!(
+                    "Resuming job {} at {}/{} inputs",
+                    job.id,
+                    job.completed,
+                    job.total
+                );
+                write_job(&job_dir, &job)?;
+            }
+            jobs.insert(job.id.clone(), job);
+        }
+
+        let jobs = Self {
+            dir: dir.to_path_buf(),
+            chunk_size,
+            jobs: Mutex::new(jobs),
+            submitted: Notify::new(),
+        };
+        jobs.submitted.notify_one();
+        Ok(jobs)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Job>> {
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn job_dir(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    pub(crate) fn files_dir(&self) -> PathBuf {
+        self.dir.join(FILES_DIR)
+    }
+
+    /// Persist a new job and queue it.
+    pub fn submit(&self, request: CreateJobRequest) -> Result<Job> {
+        self.submit_with(request, |_| Ok(()))
+    }
+
+    /// Same as [`Self::submit`], with `write` writing extra files to the directory of the job
+    /// before it is queued.
+    pub(crate) fn submit_with(
+        &self,
+        request: CreateJobRequest,
+        write: impl FnOnce(&Path) -> Result<()>,
+    ) -> Result<Job> {
+        let job = Job {
+            id: format!("job-{}", Uuid::new_v4().simple()),
+            object: "job".to_string(),
+            model: request.model,
+            status: JobStatus::Queued,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            total: request.input.len(),
+            completed: 0,
+            error: None,
+        };
+
+        let job_dir = self.job_dir(&job.id);
+        fs::create_dir_all(&job_dir)?;
+        fs::write(
+            job_dir.join(INPUT_FILE),
+            serde_json::to_vec(&request.input)?,
+        )?;
+        File::create(job_dir.join(RESULTS_FILE))?;
+        write(&job_dir)?;
+        write_job(&job_dir, &job)?;
+
+        self.lock().insert(job.id.clone(), job.clone());
+        self.submitted.notify_one();
+        Ok(job)
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.lock().get(id).cloned()
+    }
+
+    /// Path of the results of a job, as JSON lines of the index and embedding of each input.
+    pub fn results_path(&self, id: &str) -> PathBuf {
+        self.job_dir(id).join(RESULTS_FILE)
+    }
+
+    /// The oldest unfinished job.
+    fn next(&self) -> Option<Job> {
+        self.lock()
+            .values()
+            .filter(|job| !job.status.is_finished())
+            .min_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)))
+            .cloned()
+    }
+
+    /// Persist the state of a job.
+    pub(crate) fn update(&self, job: &Job) -> Result<()> {
+        write_job(&self.job_dir(&job.id), job)?;
+        self.lock().insert(job.id.clone(), job.clone());
+        Ok(())
+    }
+}
+
+/// Write the job to a temporary file first, so it is never left partially written.
+fn write_job(job_dir: &Path, job: &Job) -> Result<()> {
+    let tmp_path = job_dir.join(format!("{JOB_FILE}.tmp"));
+    fs::write(&tmp_path, serde_json::to_vec_pretty(job)?)?;
+    fs::rename(tmp_path, job_dir.join(JOB_FILE))?;
+    Ok(())
+}
+
+/// Count the results that were completely written, dropping a partially written last line.
+fn recover_results(path: &Path) -> Result<usize> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let complete = contents
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path)?
+        .set_len(complete as u64)?;
+
+    Ok(contents[..complete].iter().filter(|&&b| b == b'\n').count())
+}
+
+/// Process the queued jobs one at a time, in the background.
+pub fn spawn_worker(state: Arc<ServerState>) {
+    let Some(jobs) = state.jobs.clone() else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        loop {
+            while let Some(mut job) = jobs.next() {
+                if let Err(err) = run(&state, &jobs, &mut job).await {
+                    tracing::error!("Job {} failed: {err}", job.id);
+                    job.status = JobStatus::Failed;
+                    job.error = Some(err.to_string());
+                    if let Err(err) = jobs.update(&job) {
+                        // Don't retry the job forever if its state can't be written
+                        tracing::error!("Failed to persist job {}: {err}", job.id);
+                        jobs.lock().insert(job.id.clone(), job);
+                    }
+                }
+            }
+            jobs.submitted.notified().await;
+        }
+    });
+}
+
+/// Embed the remaining inputs of a job, persisting the results after every chunk.
+async fn run(state: &ServerState, jobs: &Jobs, job: &mut Job) -> Result<()> {
+    job.status = JobStatus::Running;
+    jobs.update(job)?;
+
+    let job_dir = jobs.job_dir(&job.id);
+    let inputs: Vec<String> = serde_json::from_slice(&fs::read(job_dir.join(INPUT_FILE))?)?;
+    let mut results = BufWriter::new(
+        OpenOptions::new()
+            .append(true)
+            .open(job_dir.join(RESULTS_FILE))?,
+    );
+
+    while job.completed < job.total {
+        let chunk = &inputs[job.completed..(job.completed + jobs.chunk_size).min(job.total)];
+        let (client, _) = state.client(&job.model).await?;
+        let request = EmbeddingsRequest::new(Sentences::from(chunk.to_vec()), job.model.clone());
+        let response = match client.generate_embedding(request).await {
+            Ok(response) => response,
+            // The model is busy with other requests, try again later
+            Err(err) if err.downcast_ref::<QueueError>().is_some() => {
+                tokio::time::sleep(RETRY_INTERVAL).await;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+
+        for (i, inner) in response.data.into_iter().enumerate() {
+            let result = JobResult {
+                index: job.completed + i,
+                embedding: inner.embedding.to_vec(),
+            };
+            serde_json::to_writer(&mut results, &result)?;
+            results.write_all(b"\n")?;
+        }
+        results.flush()?;
+
+        job.completed += chunk.len();
+        jobs.update(job)?;
+    }
+
+    job.status = JobStatus::Completed;
+    jobs.update(job)?;
+    tracing::info!("Job {} completed", job.id);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_resume_jobs() -> Result<()> {
+        let dir = tempdir()?;
+
+        let jobs = Jobs::open(dir.path(), 2)?;
+        let mut job = jobs.submit(CreateJobRequest {
+            model: "model".to_string(),
+            input: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+        })?;
+        let finished = jobs.submit(CreateJobRequest {
+            model: "model".to_string(),
+            input: vec![],
+        })?;
+        assert_eq!(jobs.get(&job.id), Some(job.clone()));
+
+        // Interrupted while writing the second result of a running job
+        job.status = JobStatus::Running;
+        job.completed = 1;
+        jobs.update(&job)?;
+        let mut finished = finished;
+        finished.status = JobStatus::Completed;
+        jobs.update(&finished)?;
+        fs::write(
+            jobs.results_path(&job.id),
+            "{\"index\":0,\"embedding\":[1.0]}\n{\"index\":1,\"embe",
+        )?;
+        drop(jobs);
+
+        let jobs = Jobs::open(dir.path(), 2)?;
+        let resumed = jobs.get(&job.id).unwrap();
+        assert_eq!(resumed.status, JobStatus::Queued);
+        assert_eq!(resumed.completed, 1);
+        assert_eq!(
+            fs::read_to_string(jobs.results_path(&job.id))?,
+            "{\"index\":0,\"embedding\":[1.0]}\n"
+        );
+
+        // Finished jobs are kept, but not run again
+        assert_eq!(jobs.get(&finished.id).unwrap().status, JobStatus::Completed);
+        assert_eq!(jobs.next().map(|job| job.id), Some(job.id));
+
+        Ok(())
+    }
+}
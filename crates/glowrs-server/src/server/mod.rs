@@ -1,30 +1,142 @@
+pub mod aliases;
+pub mod arrow;
+pub mod batches;
+pub mod canary;
+pub mod circuit_breaker;
 pub mod data_models;
+pub mod device;
+pub mod format;
+pub mod grpc;
+pub mod hooks;
+pub mod idempotency;
+pub mod image;
 pub mod infer;
 mod init;
+pub mod jobs;
+pub mod openapi;
+pub mod preprocess;
+pub mod quota;
+pub mod record;
+pub mod request_limits;
 pub mod routes;
+pub mod shadow;
+pub mod shutdown;
 mod state;
+#[cfg(test)]
+mod test_utils;
+pub mod timing;
+pub mod tls;
 pub mod utils;
+pub mod watch;
+pub mod worker;
 
 pub use init::{init_router, RouterArgs};
 
-use axum::http::StatusCode;
+use crate::server::circuit_breaker::Unrecorded;
+use crate::server::infer::limits::QueueError;
+use crate::server::quota::QuotaStatus;
+use crate::server::worker::WorkerError;
+use axum::http::{header, StatusCode};
 use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 #[allow(dead_code)]
 pub enum ServerError {
     #[error("Internal server error: `{0}`")]
-    InternalError(#[from] anyhow::Error),
+    InternalError(anyhow::Error),
+
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+
+    #[error("Limit exceeded: {0}")]
+    LimitExceeded(String),
+
+    #[error("Not acceptable: {0}")]
+    NotAcceptable(String),
 
     #[error("Model not found")]
     ModelNotFound,
 
+    #[error("Model `{0}` is already loaded")]
+    ModelConflict(String),
+
+    #[error("Model `{0}` is loading, retry after 1 s")]
+    ModelLoading(String),
+
+    #[error("Job not found")]
+    JobNotFound,
+
+    #[error("Job has not completed")]
+    JobNotCompleted,
+
+    #[error("Batch not found")]
+    BatchNotFound,
+
+    #[error("File not found")]
+    FileNotFound,
+
+    #[error("A request with this idempotency key is still being processed")]
+    IdempotencyKeyInUse,
+
+    #[error("Idempotency key was already used for a different request")]
+    IdempotencyKeyMismatch,
+
     #[error("Too many requests.")]
     TooManyRequestsError,
 
+    #[error("Token quota exceeded, retry after {} s", .0.retry_after())]
+    QuotaExceeded(QuotaStatus),
+
     #[error("Inference error")]
     InferenceError,
+
+    #[error("Service overloaded, retry after {} s", retry_after.as_secs_f32().ceil())]
+    ServiceUnavailable { retry_after: Duration },
+}
+
+impl From<anyhow::Error> for ServerError {
+    fn from(err: anyhow::Error) -> Self {
+        // Invalid arguments to the model are caused by the request, not by the server
+        if let Some(glowrs::Error::InvalidArgument(msg)) = err.downcast_ref::<glowrs::Error>() {
+            return Self::InvalidRequest(msg.to_string());
+        }
+        if let Some(ServerError::InvalidRequest(msg)) = err.downcast_ref::<ServerError>() {
+            return Self::InvalidRequest(msg.clone());
+        }
+
+        if let Some(WorkerError::Exited) = err.downcast_ref::<WorkerError>() {
+            return Self::ServiceUnavailable {
+                retry_after: Duration::from_secs(1),
+            };
+        }
+
+        match err.downcast_ref::<QueueError>() {
+            Some(QueueError::Full) => Self::TooManyRequestsError,
+            Some(QueueError::Timeout) => Self::ServiceUnavailable {
+                retry_after: Duration::from_secs(1),
+            },
+            None => Self::InternalError(err),
+        }
+    }
+}
+
+/// An error response with an OpenAI-style error body.
+fn error_json(status: StatusCode, message: &str, code: &str) -> Response {
+    let body = serde_json::json!({
+        "error": {
+            "message": message,
+            "type": "invalid_request_error",
+            "param": null,
+            "code": code,
+        }
+    });
+    (status, axum::Json(body)).into_response()
 }
 
 impl IntoResponse for ServerError {
@@ -33,9 +145,85 @@ impl IntoResponse for ServerError {
             ServerError::InternalError(err) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
             }
-            ServerError::TooManyRequestsError => StatusCode::TOO_MANY_REQUESTS.into_response(),
+            ServerError::InvalidRequest(msg) => (StatusCode::BAD_REQUEST, msg).into_response(),
+            ServerError::PayloadTooLarge(ref msg) => {
+                error_json(StatusCode::PAYLOAD_TOO_LARGE, msg, "payload_too_large")
+            }
+            ServerError::LimitExceeded(ref msg) => {
+                error_json(StatusCode::UNPROCESSABLE_ENTITY, msg, "limit_exceeded")
+            }
+            ServerError::NotAcceptable(ref msg) => {
+                error_json(StatusCode::NOT_ACCEPTABLE, msg, "not_acceptable")
+            }
+            ServerError::IdempotencyKeyInUse => {
+                (StatusCode::CONFLICT, self.to_string()).into_response()
+            }
+            ServerError::IdempotencyKeyMismatch => {
+                (StatusCode::UNPROCESSABLE_ENTITY, self.to_string()).into_response()
+            }
+            // The queue drains as requests complete, so clients can retry soon
+            ServerError::TooManyRequestsError => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, "1")],
+                Extension(Unrecorded),
+                self.to_string(),
+            )
+                .into_response(),
+            ServerError::QuotaExceeded(ref status) => {
+                let body = serde_json::json!({
+                    "error": {
+                        "message": self.to_string(),
+                        "type": "quota_exceeded",
+                        "quota": status,
+                    }
+                });
+                let mut response = (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(header::RETRY_AFTER, status.retry_after().to_string())],
+                    axum::Json(body),
+                )
+                    .into_response();
+                response.headers_mut().extend(status.headers());
+                response
+            }
             ServerError::InferenceError => StatusCode::BAD_REQUEST.into_response(),
             ServerError::ModelNotFound => StatusCode::NOT_FOUND.into_response(),
+            ServerError::ModelConflict(_) => {
+                (StatusCode::CONFLICT, self.to_string()).into_response()
+            }
+            ServerError::ModelLoading(ref model) => {
+                let body = serde_json::json!({
+                    "error": {
+                        "message": self.to_string(),
+                        "type": "model_loading",
+                        "model": model,
+                        "state": "loading",
+                    }
+                });
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    [(header::RETRY_AFTER, "1")],
+                    Extension(Unrecorded),
+                    axum::Json(body),
+                )
+                    .into_response()
+            }
+            ServerError::JobNotFound | ServerError::BatchNotFound | ServerError::FileNotFound => {
+                (StatusCode::NOT_FOUND, self.to_string()).into_response()
+            }
+            ServerError::JobNotCompleted => {
+                (StatusCode::CONFLICT, self.to_string()).into_response()
+            }
+            ServerError::ServiceUnavailable { retry_after } => {
+                // `Retry-After` is in whole seconds, so round up
+                let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    [(header::RETRY_AFTER, seconds.to_string())],
+                    self.to_string(),
+                )
+                    .into_response()
+            }
         }
     }
 }
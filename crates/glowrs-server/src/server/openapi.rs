@@ -0,0 +1,96 @@
+//! OpenAPI specification
+//!
+//! The specification is generated from the request and response types and the route handlers,
+//! and served at `/openapi.json`, with a Swagger UI at `/docs`.
+
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::server::routes::{
+    batches, classify, default, embeddings, jobs, models, shadow, stream, tei, tokenize,
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "glowrs-server",
+        description = "OpenAI compatible embeddings server"
+    ),
+    paths(
+        embeddings::infer_text_embeddings,
+        embeddings::infer_multi_model_embeddings,
+        stream::stream_embeddings,
+        tei::embed,
+        tei::embed_sparse,
+        classify::classify,
+        classify::predict,
+        jobs::create_job,
+        jobs::get_job,
+        jobs::get_job_results,
+        batches::upload_file,
+        batches::get_file_content,
+        batches::create_batch,
+        batches::get_batch,
+        models::list_models,
+        models::get_model,
+        models::load_model,
+        models::unload_model,
+        tokenize::tokenize,
+        tokenize::decode,
+        shadow::shadow_stats,
+        default::health_check,
+        default::status,
+    ),
+    tags(
+        (name = "embeddings", description = "Embed texts and images"),
+        (name = "tei", description = "Routes compatible with text-embeddings-inference"),
+        (name = "classification", description = "Classify texts with classification models"),
+        (name = "jobs", description = "Batch jobs processed in the background"),
+        (name = "batches", description = "OpenAI-compatible batches, run as batch jobs"),
+        (name = "models", description = "Served models"),
+        (name = "tokenizer", description = "Tokenize and decode with the tokenizer of a model"),
+        (name = "shadow", description = "Shadow traffic"),
+        (name = "status", description = "Health and status"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Routes that serve the specification and the Swagger UI.
+pub fn docs() -> SwaggerUi {
+    SwaggerUi::new("/docs").url("/openapi.json", ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_openapi() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+
+        let paths = spec["paths"].as_object().unwrap();
+        for path in [
+            "/v1/embeddings",
+            "/v1/embeddings/multi",
+            "/v1/embeddings/ws",
+            "/v1/models/{model_id}",
+            "/v1/tokenize",
+            "/v1/decode",
+            "/v1/classify",
+            "/predict",
+            "/v1/models/load",
+            "/embed",
+            "/embed_sparse",
+            "/v1/batches/{batch_id}",
+            "/v1/files/{file_id}/content",
+        ] {
+            assert!(paths.contains_key(path), "Missing path {path}");
+        }
+
+        let schemas = &spec["components"]["schemas"];
+        let request = &schemas["EmbeddingsRequest"];
+        assert_eq!(request["required"], serde_json::json!(["input", "model"]));
+        assert!(schemas["PoolingStrategy"].is_object());
+        assert!(schemas["EmbeddingsInput"]["oneOf"].is_array());
+    }
+}
@@ -0,0 +1,309 @@
+//! Text preprocessing applied before tokenization
+//!
+//! Preprocessing pipelines are configured per model in a JSON file, mapping model names to a list
+//! of steps. The `*` entry applies to every model without an entry of its own:
+//!
+//! ```json
+//! {
+//!     "sentence-transformers/all-MiniLM-L6-v2": [
+//!         "strip_html",
+//!         "collapse_whitespace",
+//!         { "regex_replace": { "pattern": "https?://\\S+", "replacement": "<url>" } }
+//!     ],
+//!     "*": ["collapse_whitespace"]
+//! }
+//! ```
+//!
+//! Retrieval models such as e5 expect queries and passages with different prefixes, e.g.
+//! `query: ` and `passage: `. These are configured per model with `--query-prefix` and
+//! `--passage-prefix`, and put in front of the preprocessed inputs of requests with an
+//! `input_type`.
+
+use anyhow::{Context, Result};
+use clap::Args;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::server::data_models::InputType;
+
+const DEFAULT_PIPELINE_KEY: &str = "*";
+
+#[derive(Debug, Args)]
+pub struct PreprocessArgs {
+    /// JSON file with text preprocessing pipelines per model, applied before tokenization
+    #[clap(long)]
+    pub preprocess_config: Option<PathBuf>,
+
+    /// Prefix of inputs with `"input_type": "query"` for a model, as `<model>=<prefix>`, e.g.
+    /// `intfloat/e5-small-v2=query: `
+    #[clap(long)]
+    pub query_prefix: Vec<String>,
+
+    /// Prefix of inputs with `"input_type": "passage"` for a model, as `<model>=<prefix>`, e.g.
+    /// `intfloat/e5-small-v2=passage: `
+    #[clap(long)]
+    pub passage_prefix: Vec<String>,
+}
+
+/// Prefixes of the input types of a model.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InputPrefixes {
+    query: Option<String>,
+    passage: Option<String>,
+}
+
+/// A single preprocessing step, as given in the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PreprocessStep {
+    /// Remove HTML tags (and the contents of `script` and `style` elements), and decode common
+    /// HTML entities
+    StripHtml,
+    /// Replace runs of whitespace with a single space, and trim leading and trailing whitespace
+    CollapseWhitespace,
+    Lowercase,
+    /// Replace all matches of a regular expression. The replacement can refer to capture groups
+    /// with `$1` or `$name`.
+    RegexReplace {
+        pattern: String,
+        #[serde(default)]
+        replacement: String,
+    },
+}
+
+enum CompiledStep {
+    StripHtml,
+    CollapseWhitespace,
+    Lowercase,
+    RegexReplace { regex: Regex, replacement: String },
+}
+
+/// A compiled preprocessing pipeline for a single model, and the prefixes of its input types.
+pub struct Preprocessor {
+    steps: Vec<CompiledStep>,
+    prefixes: InputPrefixes,
+}
+
+static HTML_ELEMENTS: once_cell::sync::Lazy<(Regex, Regex)> = once_cell::sync::Lazy::new(|| {
+    (
+        Regex::new(r"(?is)<script\b.*?</script>|<style\b.*?</style>").expect("Invalid regex"),
+        Regex::new(r"(?s)<!--.*?-->|<[^>]*>").expect("Invalid regex"),
+    )
+});
+
+const HTML_ENTITIES: [(&str, &str); 6] = [
+    ("&nbsp;", " "),
+    ("&lt;", "<"),
+    ("&gt;", ">"),
+    ("&quot;", "\""),
+    ("&#39;", "'"),
+    // Decoded last, so that e.g. `&amp;lt;` becomes `&lt;` rather than `<`
+    ("&amp;", "&"),
+];
+
+impl Preprocessor {
+    pub fn new(steps: &[PreprocessStep]) -> Result<Self> {
+        let steps = steps
+            .iter()
+            .map(|step| {
+                Ok(match step {
+                    PreprocessStep::StripHtml => CompiledStep::StripHtml,
+                    PreprocessStep::CollapseWhitespace => CompiledStep::CollapseWhitespace,
+                    PreprocessStep::Lowercase => CompiledStep::Lowercase,
+                    PreprocessStep::RegexReplace {
+                        pattern,
+                        replacement,
+                    } => CompiledStep::RegexReplace {
+                        regex: Regex::new(pattern)
+                            .with_context(|| format!("Invalid preprocessing regex `{pattern}`"))?,
+                        replacement: replacement.clone(),
+                    },
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            steps,
+            prefixes: InputPrefixes::default(),
+        })
+    }
+
+    pub fn with_prefixes(self, prefixes: InputPrefixes) -> Self {
+        Self { prefixes, ..self }
+    }
+
+    /// The prefix of inputs of a type, if one is configured.
+    pub fn prefix(&self, input_type: InputType) -> Option<&str> {
+        match input_type {
+            InputType::Query => self.prefixes.query.as_deref(),
+            InputType::Passage => self.prefixes.passage.as_deref(),
+        }
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        self.steps
+            .iter()
+            .fold(text.to_string(), |text, step| match step {
+                CompiledStep::StripHtml => strip_html(&text),
+                CompiledStep::CollapseWhitespace => {
+                    text.split_whitespace().collect::<Vec<_>>().join(" ")
+                }
+                CompiledStep::Lowercase => text.to_lowercase(),
+                CompiledStep::RegexReplace { regex, replacement } => {
+                    regex.replace_all(&text, replacement.as_str()).into_owned()
+                }
+            })
+    }
+}
+
+fn strip_html(text: &str) -> String {
+    let (elements, tags) = &*HTML_ELEMENTS;
+
+    let text = elements.replace_all(text, " ");
+    let text = tags.replace_all(&text, " ");
+
+    HTML_ENTITIES
+        .iter()
+        .fold(text.into_owned(), |text, (entity, decoded)| {
+            text.replace(entity, decoded)
+        })
+}
+
+/// Preprocessing pipelines and input type prefixes by model name.
+#[derive(Clone, Default)]
+pub struct PreprocessConfig {
+    pipelines: HashMap<String, Vec<PreprocessStep>>,
+    prefixes: HashMap<String, InputPrefixes>,
+}
+
+impl PreprocessConfig {
+    pub fn from_args(args: &PreprocessArgs) -> Result<Self> {
+        let pipelines = match &args.preprocess_config {
+            Some(path) => {
+                let content = fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                serde_json::from_str(&content).with_context(|| {
+                    format!("Invalid preprocessing configuration {}", path.display())
+                })?
+            }
+            None => HashMap::new(),
+        };
+
+        let mut prefixes: HashMap<String, InputPrefixes> = HashMap::new();
+        for (input_type, args) in [
+            (InputType::Query, &args.query_prefix),
+            (InputType::Passage, &args.passage_prefix),
+        ] {
+            for arg in args {
+                let (model, prefix) = arg.split_once('=').with_context(|| {
+                    format!("Invalid prefix `{arg}`, expected `<model>=<prefix>`")
+                })?;
+                let prefixes = prefixes.entry(model.to_string()).or_default();
+                let prefix = Some(prefix.to_string());
+                match input_type {
+                    InputType::Query => prefixes.query = prefix,
+                    InputType::Passage => prefixes.passage = prefix,
+                }
+            }
+        }
+
+        Ok(Self {
+            pipelines,
+            prefixes,
+        })
+    }
+
+    /// Compile the pipeline for a model, if one or input type prefixes are configured.
+    pub fn preprocessor(&self, model: &str) -> Result<Option<Preprocessor>> {
+        let steps = self
+            .pipelines
+            .get(model)
+            .or_else(|| self.pipelines.get(DEFAULT_PIPELINE_KEY))
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let prefixes = self.prefixes.get(model).cloned().unwrap_or_default();
+        if steps.is_empty() && prefixes == InputPrefixes::default() {
+            return Ok(None);
+        }
+
+        Ok(Some(Preprocessor::new(steps)?.with_prefixes(prefixes)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(json: &str) -> PreprocessConfig {
+        PreprocessConfig {
+            pipelines: serde_json::from_str(json).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_pipeline() -> Result<()> {
+        let config = config(
+            r##"{
+                "model": [
+                    "strip_html",
+                    "collapse_whitespace",
+                    "lowercase",
+                    { "regex_replace": { "pattern": "\\d+", "replacement": "#" } }
+                ]
+            }"##,
+        );
+        let preprocessor = config.preprocessor("model")?.unwrap();
+
+        let html =
+            "<html><style>p { color: red; }</style><p>Hello\n  <b>World</b> &amp; 42 friends</p>\
+            <script>alert('hi')</script></html>";
+        assert_eq!(preprocessor.apply(html), "hello world & # friends");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_pipeline() -> Result<()> {
+        let config = config(r#"{ "model": [], "*": ["lowercase"] }"#);
+
+        assert!(config.preprocessor("model")?.is_none());
+        assert_eq!(config.preprocessor("other")?.unwrap().apply("ABC"), "abc");
+        assert!(PreprocessConfig::default().preprocessor("other")?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefixes() -> Result<()> {
+        let args = PreprocessArgs {
+            preprocess_config: None,
+            query_prefix: vec!["intfloat/e5-small-v2=query: ".to_string()],
+            passage_prefix: vec!["intfloat/e5-small-v2=passage: ".to_string()],
+        };
+        let config = PreprocessConfig::from_args(&args)?;
+
+        let preprocessor = config.preprocessor("intfloat/e5-small-v2")?.unwrap();
+        assert_eq!(preprocessor.prefix(InputType::Query), Some("query: "));
+        assert_eq!(preprocessor.prefix(InputType::Passage), Some("passage: "));
+        assert_eq!(preprocessor.apply("Hello"), "Hello");
+        assert!(config.preprocessor("other")?.is_none());
+
+        let invalid = PreprocessArgs {
+            query_prefix: vec!["query: ".to_string()],
+            ..args
+        };
+        assert!(PreprocessConfig::from_args(&invalid).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_regex() {
+        let config = config(r#"{ "model": [{ "regex_replace": { "pattern": "(" } }] }"#);
+        assert!(config.preprocessor("model").is_err());
+    }
+}
@@ -0,0 +1,342 @@
+//! Token quotas per API key
+//!
+//! Request limits bound how many requests are processed at once, but a single request with long
+//! inputs can cost as much compute as hundreds of short ones. Quotas cap the number of tokens an
+//! API key (sent as `Authorization: Bearer <key>`) embeds per minute and per day. A request is
+//! rejected with `429 Too Many Requests` once a quota is used up, with the remaining quota and
+//! the time until it resets in the body. Requests are charged for their tokens after they are
+//! processed, so the last request within a window may exceed the quota. Batch jobs and batches
+//! run after their request, so they are charged for all their tokens when they are submitted.
+
+use anyhow::{Context, Result};
+use axum::http::{header, HeaderMap, HeaderValue};
+use clap::Args;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const MINUTE: Duration = Duration::from_secs(60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Args)]
+pub struct QuotaArgs {
+    /// Token quota of an API key, as `<api key>=<key>:<value>,...` with the keys `minute` and
+    /// `day`, the maximum number of tokens per minute and per day
+    #[clap(long)]
+    pub api_key_quota: Vec<String>,
+
+    /// Token quota shared by all requests without one of the API keys of `--api-key-quota`, as
+    /// `<key>:<value>,...` with the keys `minute` and `day`. Unlimited if not given
+    #[clap(long)]
+    pub default_token_quota: Option<String>,
+}
+
+/// Maximum number of tokens per minute and per day.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TokenQuota {
+    pub per_minute: Option<u64>,
+    pub per_day: Option<u64>,
+}
+
+impl TokenQuota {
+    /// Parse `<key>:<value>` settings separated by commas.
+    fn parse(settings: &str) -> Result<Self> {
+        let mut quota = Self::default();
+        for setting in settings.split(',').filter(|s| !s.is_empty()) {
+            let (key, value) = setting
+                .split_once(':')
+                .with_context(|| format!("Invalid token quota `{setting}`"))?;
+            let value: u64 = value
+                .parse()
+                .with_context(|| format!("Invalid value of token quota `{key}`"))?;
+
+            match key {
+                "minute" => quota.per_minute = Some(value),
+                "day" => quota.per_day = Some(value),
+                _ => anyhow::bail!("Unknown token quota `{key}`"),
+            }
+        }
+
+        if quota.per_minute.is_none() && quota.per_day.is_none() {
+            anyhow::bail!("Token quota `{settings}` sets no limit");
+        }
+        Ok(quota)
+    }
+}
+
+/// Usage of a quota within one window.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct WindowStatus {
+    /// `minute` or `day`
+    pub window: &'static str,
+    /// Maximum number of tokens in the window
+    pub limit: u64,
+    /// Tokens left in the current window
+    pub remaining: u64,
+    /// Seconds until the window resets, rounded up
+    pub reset_seconds: u64,
+}
+
+/// Usage of a quota, by window.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QuotaStatus(pub Vec<WindowStatus>);
+
+impl QuotaStatus {
+    fn is_exhausted(&self) -> bool {
+        self.0.iter().any(|window| window.remaining == 0)
+    }
+
+    /// Seconds until all exhausted windows have reset.
+    pub fn retry_after(&self) -> u64 {
+        self.0
+            .iter()
+            .filter(|window| window.remaining == 0)
+            .map(|window| window.reset_seconds)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The window with the fewest remaining tokens as `x-ratelimit-*-tokens` headers.
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let Some(window) = self.0.iter().min_by_key(|window| window.remaining) else {
+            return headers;
+        };
+
+        for (name, value) in [
+            ("x-ratelimit-limit-tokens", window.limit),
+            ("x-ratelimit-remaining-tokens", window.remaining),
+            ("x-ratelimit-reset-tokens", window.reset_seconds),
+        ] {
+            headers.insert(name, HeaderValue::from(value));
+        }
+        headers
+    }
+}
+
+#[derive(Debug)]
+struct Window {
+    name: &'static str,
+    length: Duration,
+    limit: u64,
+    start: Instant,
+    used: u64,
+}
+
+impl Window {
+    fn new(name: &'static str, length: Duration, limit: u64, now: Instant) -> Self {
+        Self {
+            name,
+            length,
+            limit,
+            start: now,
+            used: 0,
+        }
+    }
+
+    /// Start a new window if the current one has passed.
+    fn advance(&mut self, now: Instant) {
+        if now.duration_since(self.start) >= self.length {
+            self.start = now;
+            self.used = 0;
+        }
+    }
+
+    fn status(&self, now: Instant) -> WindowStatus {
+        let reset = self.length.saturating_sub(now.duration_since(self.start));
+        WindowStatus {
+            window: self.name,
+            limit: self.limit,
+            remaining: self.limit.saturating_sub(self.used),
+            reset_seconds: reset.as_secs() + u64::from(reset.subsec_nanos() > 0),
+        }
+    }
+}
+
+/// Token usage of an API key, or of all requests without one.
+#[derive(Debug)]
+struct Bucket(Mutex<Vec<Window>>);
+
+impl Bucket {
+    fn new(quota: TokenQuota, now: Instant) -> Self {
+        let windows = [
+            ("minute", MINUTE, quota.per_minute),
+            ("day", DAY, quota.per_day),
+        ]
+        .into_iter()
+        .filter_map(|(name, length, limit)| Some(Window::new(name, length, limit?, now)))
+        .collect();
+        Self(Mutex::new(windows))
+    }
+
+    fn update(&self, now: Instant, tokens: u64) -> QuotaStatus {
+        let mut windows = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        QuotaStatus(
+            windows
+                .iter_mut()
+                .map(|window| {
+                    window.advance(now);
+                    window.used = window.used.saturating_add(tokens);
+                    window.status(now)
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Token quotas by API key.
+#[derive(Debug)]
+pub struct Quotas {
+    keys: HashMap<String, Arc<Bucket>>,
+    default: Option<Arc<Bucket>>,
+}
+
+impl Quotas {
+    /// Get the quotas from the command line arguments, if any quota is set.
+    pub fn from_args(args: &QuotaArgs) -> Result<Option<Self>> {
+        if args.api_key_quota.is_empty() && args.default_token_quota.is_none() {
+            return Ok(None);
+        }
+
+        let now = Instant::now();
+        let keys = args
+            .api_key_quota
+            .iter()
+            .map(|quota| {
+                let (key, settings) = quota
+                    .split_once('=')
+                    .context("Invalid API key quota, expected `<api key>=<key>:<value>,...`")?;
+                let bucket = Bucket::new(TokenQuota::parse(settings)?, now);
+                Ok((key.to_string(), Arc::new(bucket)))
+            })
+            .collect::<Result<_>>()?;
+        let default = args
+            .default_token_quota
+            .as_deref()
+            .map(|settings| {
+                Ok::<_, anyhow::Error>(Arc::new(Bucket::new(TokenQuota::parse(settings)?, now)))
+            })
+            .transpose()?;
+
+        Ok(Some(Self { keys, default }))
+    }
+
+    /// Check the quota of the API key of a request. Returns the quota to charge the request's
+    /// tokens to, if it has one, or the status of the quota if it is used up.
+    pub fn acquire(&self, headers: &HeaderMap) -> Result<Option<Charge>, QuotaStatus> {
+        self.acquire_at(headers, Instant::now())
+    }
+
+    fn acquire_at(&self, headers: &HeaderMap, now: Instant) -> Result<Option<Charge>, QuotaStatus> {
+        let bucket = api_key(headers)
+            .and_then(|key| self.keys.get(key))
+            .or(self.default.as_ref());
+        let Some(bucket) = bucket else {
+            return Ok(None);
+        };
+
+        let status = bucket.update(now, 0);
+        if status.is_exhausted() {
+            return Err(status);
+        }
+        Ok(Some(Charge(bucket.clone())))
+    }
+}
+
+/// The quota a request is charged to.
+#[derive(Debug, Clone)]
+pub struct Charge(Arc<Bucket>);
+
+impl Charge {
+    /// Charge `tokens` to the quota, returning what is left of it.
+    pub fn charge(&self, tokens: u64) -> QuotaStatus {
+        self.0.update(Instant::now(), tokens)
+    }
+}
+
+/// The API key of a request, sent as `Authorization: Bearer <key>`.
+fn api_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers(api_key: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(key) = api_key {
+            let value = HeaderValue::from_str(&format!("Bearer {key}")).unwrap();
+            headers.insert(header::AUTHORIZATION, value);
+        }
+        headers
+    }
+
+    #[test]
+    fn test_parse_quota() -> Result<()> {
+        assert_eq!(
+            TokenQuota::parse("minute:100,day:1000")?,
+            TokenQuota {
+                per_minute: Some(100),
+                per_day: Some(1000),
+            }
+        );
+        assert!(TokenQuota::parse("hour:100").is_err());
+        assert!(TokenQuota::parse("minute:many").is_err());
+        assert!(TokenQuota::parse("").is_err());
+
+        assert!(Quotas::from_args(&QuotaArgs {
+            api_key_quota: vec![],
+            default_token_quota: None,
+        })?
+        .is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quota() -> Result<()> {
+        let quotas = Quotas::from_args(&QuotaArgs {
+            api_key_quota: vec!["secret=minute:100,day:150".to_string()],
+            default_token_quota: Some("minute:10".to_string()),
+        })?
+        .unwrap();
+        let now = Instant::now();
+
+        // The last request within the window may exceed the quota
+        let charge = quotas.acquire_at(&headers(Some("secret")), now).unwrap();
+        let status = charge.unwrap().0.update(now, 120);
+        assert_eq!(status.0[0].remaining, 0);
+        assert_eq!(status.0[1].remaining, 30);
+
+        let status = quotas
+            .acquire_at(&headers(Some("secret")), now + Duration::from_secs(20))
+            .unwrap_err();
+        assert_eq!(status.retry_after(), 40);
+        assert_eq!(status.headers()["x-ratelimit-remaining-tokens"], "0");
+
+        // The minute window resets, but the daily quota is nearly used up
+        let charge = quotas
+            .acquire_at(&headers(Some("secret")), now + MINUTE)
+            .unwrap()
+            .unwrap();
+        let status = charge.0.update(now + MINUTE, 30);
+        assert_eq!(status.0[0].remaining, 70);
+        assert!(quotas
+            .acquire_at(&headers(Some("secret")), now + 2 * MINUTE)
+            .is_err());
+
+        // Other requests share the default quota
+        let charge = quotas.acquire_at(&headers(None), now).unwrap().unwrap();
+        charge.0.update(now, 10);
+        assert!(quotas.acquire_at(&headers(Some("unknown")), now).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,318 @@
+//! Request recording
+//!
+//! Writes a sample of the embedding requests to a JSON lines file, along with the endpoint and
+//! the latency of each request, so production traffic can be replayed with `glowrs replay`.
+//!
+//! Inputs are anonymized by default: every word is replaced by a pseudo-word of the same length,
+//! consistently within a recording, and the `user` field is dropped. This keeps the shape of the
+//! inputs (number and length of words, repetitions) while hiding their content. Image inputs
+//! are replaced by a blank image.
+//!
+//! Records are written to the file by a dedicated thread, so requests don't wait on disk I/O. If
+//! the thread falls behind by more than `WRITE_QUEUE_SIZE` records, new records are dropped.
+
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::hash_map::RandomState;
+use std::fs::{File, OpenOptions};
+use std::hash::BuildHasher;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::server::utils::sampled;
+
+/// A base64-encoded, black 1x1 PPM image that replaces image inputs when anonymizing
+const BLANK_IMAGE: &str = "UDYKMSAxCjI1NQoAAAA=";
+/// Number of records waiting to be written before new records are dropped
+const WRITE_QUEUE_SIZE: usize = 1024;
+
+#[derive(Debug, Args)]
+pub struct RecordArgs {
+    /// JSON lines file to append a sample of the embedding requests to, for `glowrs replay`
+    #[clap(long)]
+    pub record_file: Option<PathBuf>,
+
+    /// Fraction of the requests to record (between 0 and 1)
+    #[clap(long, default_value = "1.0")]
+    pub record_sample_rate: f64,
+
+    /// Record the inputs as is, instead of anonymizing them
+    #[clap(long)]
+    pub record_raw: bool,
+}
+
+/// A recorded request, as written to the record file.
+#[derive(Debug, Serialize)]
+struct RecordedRequest<'a> {
+    /// Time the request was received, in milliseconds since the Unix epoch
+    timestamp_ms: u128,
+    /// Endpoint path relative to `/v1/`
+    endpoint: &'a str,
+    latency_ms: f64,
+    request: Value,
+}
+
+pub struct Recorder {
+    /// Sends serialized records to the writer thread. Taken on drop to stop the thread
+    sender: Option<SyncSender<String>>,
+    writer: Option<JoinHandle<()>>,
+    sample_rate: f64,
+    raw: bool,
+    /// Random key of the pseudo-words, so they can't be reversed with a dictionary
+    hasher: RandomState,
+    seen: AtomicU64,
+}
+
+impl Recorder {
+    pub fn from_args(args: &RecordArgs) -> Result<Option<Self>> {
+        let Some(path) = &args.record_file else {
+            return Ok(None);
+        };
+
+        if !(0.0..=1.0).contains(&args.record_sample_rate) {
+            anyhow::bail!("Record sample rate should be between 0 and 1");
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open record file {}", path.display()))?;
+
+        let (sender, receiver) = sync_channel(WRITE_QUEUE_SIZE);
+        let writer = std::thread::Builder::new()
+            .name("glowrs-recorder".to_string())
+            .spawn(move || write_records(receiver, BufWriter::new(file)))?;
+
+        tracing::info!("Recording requests to {}", path.display());
+
+        Ok(Some(Self {
+            sender: Some(sender),
+            writer: Some(writer),
+            sample_rate: args.record_sample_rate,
+            raw: args.record_raw,
+            hasher: RandomState::new(),
+            seen: AtomicU64::new(0),
+        }))
+    }
+
+    /// Record a request, if it is sampled.
+    pub fn record<R: Serialize>(&self, endpoint: &str, request: &R, latency: Duration) {
+        if !sampled(self.seen.fetch_add(1, Ordering::Relaxed), self.sample_rate) {
+            return;
+        }
+
+        if let Err(e) = self.write(endpoint, request, latency) {
+            tracing::warn!("Failed to record request: {e}");
+        }
+    }
+
+    fn write<R: Serialize>(&self, endpoint: &str, request: &R, latency: Duration) -> Result<()> {
+        let mut request = serde_json::to_value(request)?;
+        if !self.raw {
+            self.anonymize_request(&mut request);
+        }
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)? - latency;
+        let record = RecordedRequest {
+            timestamp_ms: timestamp.as_millis(),
+            endpoint,
+            latency_ms: latency.as_secs_f64() * 1000.,
+            request,
+        };
+
+        let line = serde_json::to_string(&record)?;
+        match self.sender.as_ref().map(|sender| sender.try_send(line)) {
+            Some(Ok(())) => Ok(()),
+            Some(Err(TrySendError::Full(_))) => {
+                anyhow::bail!("Record writer is falling behind, dropping the record")
+            }
+            Some(Err(TrySendError::Disconnected(_))) | None => {
+                anyhow::bail!("Record writer stopped")
+            }
+        }
+    }
+
+    fn anonymize_request(&self, request: &mut Value) {
+        let Some(fields) = request.as_object_mut() else {
+            return;
+        };
+
+        fields.remove("user");
+        match fields.get_mut("input") {
+            Some(Value::String(text)) => *text = self.anonymize(text),
+            Some(Value::Array(inputs)) => {
+                for input in inputs.iter_mut() {
+                    match input {
+                        Value::String(text) => *text = self.anonymize(text),
+                        // Multimodal inputs
+                        Value::Object(input) => {
+                            if let Some(Value::String(text)) = input.get_mut("text") {
+                                *text = self.anonymize(text);
+                            }
+                            if let Some(image) = input.get_mut("image") {
+                                *image = Value::from(BLANK_IMAGE);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Replace every word by a pseudo-word of the same length. Letters map to lowercase
+    /// letters and digits to digits; everything else is kept.
+    fn anonymize(&self, text: &str) -> String {
+        let mut anonymized = String::with_capacity(text.len());
+        let mut word = String::new();
+
+        for c in text.chars().chain(std::iter::once(' ')) {
+            if c.is_alphanumeric() {
+                word.push(c);
+                continue;
+            }
+            if !word.is_empty() {
+                anonymized.push_str(&self.pseudo_word(&word));
+                word.clear();
+            }
+            anonymized.push(c);
+        }
+        // Remove the sentinel
+        anonymized.pop();
+
+        anonymized
+    }
+
+    fn pseudo_word(&self, word: &str) -> String {
+        let mut state = self.hasher.hash_one(word.to_lowercase());
+
+        word.chars()
+            .map(|c| {
+                // Xorshift, seeded with the hash of the word
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                if c.is_numeric() {
+                    (b'0' + (state % 10) as u8) as char
+                } else {
+                    (b'a' + (state % 26) as u8) as char
+                }
+            })
+            .collect()
+    }
+}
+
+impl Drop for Recorder {
+    /// Write the remaining records before closing the file.
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Write records as lines until the recorder is dropped, flushing whenever no more are waiting.
+fn write_records(receiver: Receiver<String>, mut writer: BufWriter<File>) {
+    let mut write = |line: String, flush: bool| -> std::io::Result<()> {
+        writeln!(writer, "{line}")?;
+        if flush {
+            writer.flush()?;
+        }
+        Ok(())
+    };
+
+    while let Ok(line) = receiver.recv() {
+        let mut pending = Some(line);
+        while let Some(line) = pending {
+            pending = receiver.try_recv().ok();
+            if let Err(e) = write(line, pending.is_none()) {
+                tracing::warn!("Failed to record request: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_anonymize() -> Result<()> {
+        let dir = tempdir()?;
+        let recorder = Recorder::from_args(&RecordArgs {
+            record_file: Some(dir.path().join("requests.jsonl")),
+            record_sample_rate: 1.0,
+            record_raw: false,
+        })?
+        .unwrap();
+
+        let text = "The cat, the dog and 42 cats.";
+        let anonymized = recorder.anonymize(text);
+
+        assert_eq!(anonymized.len(), text.len());
+        assert_ne!(anonymized, text);
+        let words: Vec<&str> = anonymized.split([' ', ',', '.']).collect();
+        let original: Vec<&str> = text.split([' ', ',', '.']).collect();
+        for (word, original) in words.iter().zip(&original) {
+            assert_eq!(word.len(), original.len());
+        }
+        // Repeated words map to the same pseudo-word
+        assert_eq!(words[0], words[3]);
+        assert!(words[6].chars().all(|c| c.is_ascii_digit()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_record() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("requests.jsonl");
+        let recorder = Recorder::from_args(&RecordArgs {
+            record_file: Some(path.clone()),
+            record_sample_rate: 0.5,
+            record_raw: false,
+        })?
+        .unwrap();
+
+        let request = serde_json::json!({"input": ["hello world"], "model": "m", "user": "me"});
+        for _ in 0..4 {
+            recorder.record("embeddings", &request, Duration::from_millis(5));
+        }
+        let mut anonymized = serde_json::json!({
+            "input": [{"text": "hello world"}, {"image": "https://example.com/cat.png"}],
+            "model": "m"
+        });
+        recorder.anonymize_request(&mut anonymized);
+        // Wait for the records to be written
+        drop(recorder);
+
+        let content = std::fs::read_to_string(path)?;
+        let records: Vec<Value> = content
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(records.len(), 2);
+
+        let recorded = &records[0];
+        assert_eq!(recorded["endpoint"], "embeddings");
+        assert_eq!(recorded["latency_ms"], 5.0);
+        assert_eq!(recorded["request"]["model"], "m");
+        assert!(recorded["request"].get("user").is_none());
+        assert_ne!(recorded["request"]["input"][0], "hello world");
+
+        assert_ne!(anonymized["input"][0]["text"], "hello world");
+        assert_eq!(anonymized["input"][1]["image"], BLANK_IMAGE);
+
+        Ok(())
+    }
+}
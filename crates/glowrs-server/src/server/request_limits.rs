@@ -0,0 +1,165 @@
+//! Limits on the size of requests
+//!
+//! Queue limits bound how many requests are processed at once, but not how large each request
+//! is. These limits reject requests with bodies larger than `--max-body-bytes` with
+//! `413 Payload Too Large`, and requests with more than `--max-inputs` inputs or inputs of more
+//! than `--max-input-tokens` tokens with `422 Unprocessable Entity`, before they are queued.
+//! Inputs that are too long are otherwise truncated to the maximum sequence length of the model.
+//! NDJSON request bodies of `/v1/embeddings` are streamed, so their size isn't limited, but each
+//! of their batches is limited like a request.
+//! The inputs of batch jobs and batches are checked against `--max-input-tokens` when they are
+//! submitted, but not against `--max-inputs`, as they are embedded in chunks.
+
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use clap::Args;
+
+use crate::server::data_models::{EmbeddingsInput, MultimodalInput};
+use crate::server::infer::embed::EmbeddingsClient;
+use crate::server::routes::ndjson::is_streamed;
+use crate::server::ServerError;
+
+#[derive(Debug, Args)]
+pub struct RequestLimitArgs {
+    /// Maximum size of request bodies in bytes. Larger requests are rejected with
+    /// `413 Payload Too Large`
+    #[clap(long, default_value = "2097152")]
+    pub max_body_bytes: usize,
+
+    /// Maximum number of inputs per request. Requests with more are rejected with
+    /// `422 Unprocessable Entity`. Unlimited if not given
+    #[clap(long)]
+    pub max_inputs: Option<usize>,
+
+    /// Maximum number of tokens per input, including special tokens. Requests with longer inputs
+    /// are rejected with `422 Unprocessable Entity` instead of truncating them. Unlimited if not
+    /// given
+    #[clap(long)]
+    pub max_input_tokens: Option<usize>,
+}
+
+/// Limits on the inputs of a request.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RequestLimits {
+    pub max_inputs: Option<usize>,
+    pub max_input_tokens: Option<usize>,
+}
+
+impl RequestLimits {
+    pub fn from_args(args: &RequestLimitArgs) -> Self {
+        Self {
+            max_inputs: args.max_inputs,
+            max_input_tokens: args.max_input_tokens,
+        }
+    }
+
+    /// Check the number of inputs of a request.
+    fn check_inputs(&self, inputs: usize) -> Result<(), ServerError> {
+        match self.max_inputs {
+            Some(max_inputs) if inputs > max_inputs => Err(ServerError::LimitExceeded(format!(
+                "Request has {inputs} inputs, the maximum is {max_inputs}"
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the number of tokens of each input.
+    pub(crate) fn check_tokens(&self, tokens: &[usize]) -> Result<(), ServerError> {
+        let Some(max_tokens) = self.max_input_tokens else {
+            return Ok(());
+        };
+
+        match tokens.iter().position(|&tokens| tokens > max_tokens) {
+            Some(index) => Err(ServerError::LimitExceeded(format!(
+                "Input {index} has {} tokens, the maximum is {max_tokens}",
+                tokens[index]
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Check the inputs of a request for the model of `client`, which counts the tokens of text
+    /// inputs.
+    pub async fn check(
+        &self,
+        client: &EmbeddingsClient,
+        input: &EmbeddingsInput,
+    ) -> Result<(), ServerError> {
+        let texts: Vec<String> = match input {
+            EmbeddingsInput::Text(sentences) => Vec::from(sentences.clone()),
+            EmbeddingsInput::TokenIds(ids) => {
+                let ids = Vec::<Vec<u32>>::from(ids.clone());
+                self.check_inputs(ids.len())?;
+                return self.check_tokens(&ids.iter().map(Vec::len).collect::<Vec<_>>());
+            }
+            EmbeddingsInput::Multimodal(inputs) => {
+                self.check_inputs(inputs.len())?;
+                inputs
+                    .iter()
+                    .filter_map(|input| match input {
+                        MultimodalInput::Text { text } => Some(text.clone()),
+                        MultimodalInput::Image { .. } => None,
+                    })
+                    .collect()
+            }
+        };
+        self.check_inputs(texts.len())?;
+
+        if self.max_input_tokens.is_some() {
+            self.check_tokens(&client.count_tokens(texts).await?)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Middleware rejecting request bodies larger than `max_body_bytes`.
+pub async fn limit_body(
+    State(max_body_bytes): State<usize>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // NDJSON bodies of the streaming routes are read a batch at a time, see `routes::ndjson`
+    if is_streamed(request.uri().path(), request.headers()) {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, max_body_bytes).await else {
+        return ServerError::PayloadTooLarge(format!(
+            "Request body is larger than {max_body_bytes} bytes"
+        ))
+        .into_response();
+    };
+
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::http::StatusCode;
+
+    #[test]
+    fn test_request_limits() {
+        let limits = RequestLimits {
+            max_inputs: Some(2),
+            max_input_tokens: Some(8),
+        };
+        assert!(limits.check_inputs(2).is_ok());
+        assert!(limits.check_tokens(&[8, 3]).is_ok());
+
+        let err = limits.check_tokens(&[3, 9]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Limit exceeded: Input 1 has 9 tokens, the maximum is 8"
+        );
+        let response = limits.check_inputs(3).unwrap_err().into_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        assert!(RequestLimits::default().check_inputs(usize::MAX).is_ok());
+    }
+}
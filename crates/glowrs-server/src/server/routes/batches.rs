@@ -0,0 +1,138 @@
+use axum::extract::{Multipart, Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use std::sync::Arc;
+
+use crate::server::batches::{self, Batch, CreateBatchRequest, FileObject};
+use crate::server::jobs::Jobs;
+use crate::server::state::ServerState;
+use crate::server::ServerError;
+
+fn jobs(server_state: &ServerState) -> Result<&Jobs, ServerError> {
+    server_state
+        .jobs
+        .as_deref()
+        .ok_or(ServerError::FileNotFound)
+}
+
+/// Upload a batch input file, as a multipart form with the `file` and its `purpose`, which
+/// should be `batch`.
+#[utoipa::path(
+    post,
+    path = "/v1/files",
+    tag = "batches",
+    request_body(content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "The uploaded file", body = FileObject),
+        (status = 400, description = "Invalid request"),
+        (status = 404, description = "Batch jobs are not enabled"),
+    )
+)]
+pub async fn upload_file(
+    State(server_state): State<Arc<ServerState>>,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<FileObject>), ServerError> {
+    let jobs = jobs(&server_state)?;
+
+    let invalid = |err: axum::extract::multipart::MultipartError| {
+        ServerError::InvalidRequest(err.body_text())
+    };
+    let (mut purpose, mut file) = (None, None);
+    while let Some(field) = multipart.next_field().await.map_err(invalid)? {
+        match field.name() {
+            Some("purpose") => purpose = Some(field.text().await.map_err(invalid)?),
+            Some("file") => {
+                let filename = field.file_name().unwrap_or("input.jsonl").to_string();
+                file = Some((filename, field.bytes().await.map_err(invalid)?));
+            }
+            _ => {}
+        }
+    }
+    let (Some(purpose), Some((filename, contents))) = (purpose, file) else {
+        return Err(ServerError::InvalidRequest(
+            "The `file` and `purpose` fields are required".to_string(),
+        ));
+    };
+
+    let file = batches::upload_file(jobs, filename, &purpose, &contents)?;
+    Ok((StatusCode::OK, Json(file)))
+}
+
+/// Download the content of a file: an uploaded input file, or the output file of a completed
+/// batch with a line per request.
+#[utoipa::path(
+    get,
+    path = "/v1/files/{file_id}/content",
+    tag = "batches",
+    params(("file_id" = String, Path, description = "ID of the file")),
+    responses(
+        (status = 200, description = "Content of the file", content_type = "application/jsonl"),
+        (status = 404, description = "The file doesn't exist"),
+        (status = 409, description = "The batch of the output file hasn't completed"),
+    )
+)]
+pub async fn get_file_content(
+    State(server_state): State<Arc<ServerState>>,
+    Path(file_id): Path<String>,
+) -> Result<Response, ServerError> {
+    let contents = batches::file_content(jobs(&server_state)?, &file_id)?;
+
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/jsonl")],
+        contents,
+    )
+        .into_response())
+}
+
+/// Run the `/v1/embeddings` requests of an uploaded input file in the background.
+#[utoipa::path(
+    post,
+    path = "/v1/batches",
+    tag = "batches",
+    request_body = CreateBatchRequest,
+    responses(
+        (status = 200, description = "The batch was queued", body = Batch),
+        (status = 400, description = "Invalid request or input file"),
+        (status = 404, description = "The input file or model doesn't exist, or batch jobs are not enabled"),
+        (status = 422, description = "An input has more tokens than allowed"),
+        (status = 429, description = "The token quota of the API key is used up"),
+    )
+)]
+pub async fn create_batch(
+    State(server_state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    Json(request): Json<CreateBatchRequest>,
+) -> Result<(StatusCode, Json<Batch>), ServerError> {
+    let jobs = jobs(&server_state)?;
+    let pending = batches::prepare_batch(jobs, request)?;
+    server_state.admit_job(&headers, &pending.job).await?;
+    let batch = batches::submit_batch(jobs, pending)?;
+
+    Ok((StatusCode::OK, Json(batch)))
+}
+
+/// Get the status and progress of a batch.
+#[utoipa::path(
+    get,
+    path = "/v1/batches/{batch_id}",
+    tag = "batches",
+    params(("batch_id" = String, Path, description = "ID of the batch")),
+    responses(
+        (status = 200, description = "The batch", body = Batch),
+        (status = 404, description = "The batch doesn't exist"),
+    )
+)]
+pub async fn get_batch(
+    State(server_state): State<Arc<ServerState>>,
+    Path(batch_id): Path<String>,
+) -> Result<(StatusCode, Json<Batch>), ServerError> {
+    let jobs = server_state
+        .jobs
+        .as_deref()
+        .ok_or(ServerError::BatchNotFound)?;
+    let batch = batches::get_batch(jobs, &batch_id)?.ok_or(ServerError::BatchNotFound)?;
+
+    Ok((StatusCode::OK, Json(batch)))
+}
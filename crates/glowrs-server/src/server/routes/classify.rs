@@ -0,0 +1,64 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use std::sync::Arc;
+
+use crate::server::data_models::{ClassifyRequest, ClassifyResponse};
+use crate::server::state::ServerState;
+use crate::server::ServerError;
+
+/// Classify the inputs with a sequence classification model, returning the score of each of its
+/// labels.
+#[utoipa::path(
+    post,
+    path = "/v1/classify",
+    tag = "classification",
+    request_body = ClassifyRequest,
+    responses(
+        (status = 200, description = "Labels of the inputs", body = ClassifyResponse),
+        (status = 400, description = "The model isn't a classification model"),
+        (status = 404, description = "The model isn't served"),
+        (status = 429, description = "The queue of the model is full"),
+    )
+)]
+pub async fn classify(
+    State(server_state): State<Arc<ServerState>>,
+    Json(request): Json<ClassifyRequest>,
+) -> Result<(StatusCode, Json<ClassifyResponse>), ServerError> {
+    let (client, _) = server_state.client(&request.model).await?;
+    let Some(classifier) = client.classifier() else {
+        return Err(ServerError::InvalidRequest(format!(
+            "Model `{}` isn't a classification model",
+            request.model
+        )));
+    };
+
+    server_state
+        .request_limits
+        .check(&client, &request.input.clone().into())
+        .await?;
+
+    let response = classifier.classify(request).await?;
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+/// Alias of `/v1/classify`.
+#[utoipa::path(
+    post,
+    path = "/predict",
+    tag = "classification",
+    request_body = ClassifyRequest,
+    responses(
+        (status = 200, description = "Labels of the inputs", body = ClassifyResponse),
+        (status = 400, description = "The model isn't a classification model"),
+        (status = 404, description = "The model isn't served"),
+        (status = 429, description = "The queue of the model is full"),
+    )
+)]
+pub async fn predict(
+    state: State<Arc<ServerState>>,
+    request: Json<ClassifyRequest>,
+) -> Result<(StatusCode, Json<ClassifyResponse>), ServerError> {
+    classify(state, request).await
+}
@@ -1,5 +1,158 @@
-use axum::{http, response::IntoResponse};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use glowrs::core::utils::parse_repo_string;
+use serde::Serialize;
+use std::sync::Arc;
+use utoipa::ToSchema;
 
-pub async fn health_check() -> impl IntoResponse {
-    (http::StatusCode::OK, "Everything is ok!".to_string())
+use crate::server::device::device_name;
+use crate::server::state::ServerState;
+use crate::server::utils::resident_memory_bytes;
+
+/// Healthy when all models are ready to serve requests.
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "status",
+    responses(
+        (status = 200, description = "All models are ready", body = String),
+        (status = 503, description = "Some models failed to load", body = String),
+    )
+)]
+pub async fn health_check(State(server_state): State<Arc<ServerState>>) -> impl IntoResponse {
+    let failed = server_state.failed();
+    if failed.is_empty() {
+        (StatusCode::OK, "Everything is ok!".to_string())
+    } else {
+        let mut failed: Vec<&str> = failed.keys().map(String::as_str).collect();
+        failed.sort();
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("Models failed to load: {}", failed.join(", ")),
+        )
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelState {
+    Ready,
+    /// Not loaded yet, or unloaded to make room for other models. Loaded on its next request
+    Unloaded,
+    Loading,
+    Failed,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ModelStatus {
+    name: String,
+    state: ModelState,
+    /// Error the model failed to load with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revision: Option<String>,
+    /// Device the model runs on, which is the CPU if it fell back to it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replicas: Option<usize>,
+    /// Requests waiting for a free slot
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queued: Option<usize>,
+    /// Requests queued in the executors or being processed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    in_flight: Option<usize>,
+    /// How long the model took to warm up when it was loaded, with `--warmup`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warmup_ms: Option<f64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ServerStatus {
+    /// `ok` if all models are ready, `degraded` otherwise
+    status: String,
+    device: String,
+    /// Resident memory of the server process, where the platform reports it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memory_bytes: Option<u64>,
+    glowrs_version: String,
+    models: Vec<ModelStatus>,
+}
+
+/// Runtime status of the server and of each model.
+#[utoipa::path(
+    get,
+    path = "/status",
+    tag = "status",
+    responses((status = 200, description = "Server and model status", body = ServerStatus))
+)]
+pub async fn status(State(server_state): State<Arc<ServerState>>) -> Json<ServerStatus> {
+    let failed = server_state.failed();
+    let ready = server_state
+        .model_map
+        .clients()
+        .into_iter()
+        .map(|(name, client)| ModelStatus {
+            name,
+            state: ModelState::Ready,
+            error: None,
+            revision: Some(client.revision().to_string()),
+            device: Some(device_name(client.device())),
+            replicas: Some(client.replicas()),
+            queued: Some(client.queued()),
+            in_flight: Some(client.in_flight()),
+            warmup_ms: client.warmup().map(|warmup| warmup.as_secs_f64() * 1000.),
+        });
+    let unloaded_models = server_state.model_map.unloaded_models();
+    let unloaded = unloaded_models
+        .into_iter()
+        .map(|(name, model_repo, loading)| {
+            let revision = parse_repo_string(&model_repo)
+                .ok()
+                .map(|(_, r)| r.to_string());
+            ModelStatus {
+                name,
+                state: match loading {
+                    true => ModelState::Loading,
+                    false => ModelState::Unloaded,
+                },
+                error: None,
+                revision,
+                device: None,
+                replicas: None,
+                queued: None,
+                in_flight: None,
+                warmup_ms: None,
+            }
+        });
+    let failed_models = failed.iter().map(|(name, error)| ModelStatus {
+        name: name.clone(),
+        state: ModelState::Failed,
+        error: Some(error.clone()),
+        revision: None,
+        device: None,
+        replicas: None,
+        queued: None,
+        in_flight: None,
+        warmup_ms: None,
+    });
+
+    let mut models: Vec<ModelStatus> = ready.chain(unloaded).chain(failed_models).collect();
+    models.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let status = match failed.is_empty() {
+        true => "ok",
+        false => "degraded",
+    };
+
+    Json(ServerStatus {
+        status: status.to_string(),
+        device: device_name(&server_state.device),
+        memory_bytes: resident_memory_bytes(),
+        glowrs_version: glowrs::VERSION.to_string(),
+        models,
+    })
 }
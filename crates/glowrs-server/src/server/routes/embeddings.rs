@@ -1,39 +1,317 @@
 use anyhow::Result;
-use axum::extract::{Query, State};
-use axum::http::StatusCode;
+use axum::extract::{FromRequest, Query, Request, State};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
 use axum::Json;
+use futures_util::future::try_join_all;
 use serde::Deserialize;
 use std::sync::Arc;
 use tokio::time::Instant;
+use utoipa::IntoParams;
 
-use crate::server::data_models::{EmbeddingsRequest, EmbeddingsResponse};
+use crate::server::data_models::{
+    ApiVersion, EmbeddingsRequest, EmbeddingsResponse, MultiEmbeddingsRequest,
+    MultiEmbeddingsResponse,
+};
+use crate::server::format::ResponseFormat;
+use crate::server::hooks::RequestContext;
+use crate::server::quota::QuotaStatus;
+use crate::server::routes::ndjson;
 use crate::server::state::ServerState;
+use crate::server::timing::{self, Timings, SERVER_TIMING_HEADER};
 use crate::server::ServerError;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams)]
 pub struct QueryData {
+    /// Version of the response schema: `v1` (OpenAI compatible, default) or `v2`, which adds
+    /// the number of tokens and truncation of each input
     api_version: Option<String>,
+    /// Whether to wait for the model to load if it isn't loaded yet (default). Otherwise the
+    /// model starts loading and `503 Service Unavailable` is returned until it is loaded
+    wait: Option<bool>,
+    /// Model to embed NDJSON requests with, which have no `model` field. Defaults to the default
+    /// model
+    model: Option<String>,
 }
 
+impl QueryData {
+    fn api_version(&self) -> Result<ApiVersion, ServerError> {
+        self.api_version
+            .as_deref()
+            .map_or(Ok(ApiVersion::default()), str::parse)
+            .map_err(ServerError::InvalidRequest)
+    }
+
+    fn wait(&self) -> bool {
+        self.wait.unwrap_or(true)
+    }
+}
+
+/// Embed the inputs with a model. Requests with `Content-Type: application/x-ndjson` have one
+/// input per line, and are answered with one embedding per line as the inputs are embedded.
+/// Responses are MessagePack, CBOR or Arrow IPC streams if the `Accept` header asks for them.
+#[utoipa::path(
+    post,
+    path = "/v1/embeddings",
+    tag = "embeddings",
+    params(QueryData, ("idempotency-key" = Option<String>, Header, description = "Execute the request only once for this key")),
+    request_body = EmbeddingsRequest,
+    responses(
+        (status = 200, description = "Embeddings of the inputs, in MessagePack, CBOR or an Arrow IPC stream with `Accept: application/msgpack`, `Accept: application/cbor` or `Accept: application/vnd.apache.arrow.stream`", body = EmbeddingsResponse),
+        (status = 400, description = "Invalid request"),
+        (status = 404, description = "The model isn't served"),
+        (status = 409, description = "A request with the idempotency key is still being processed"),
+        (status = 413, description = "The request body is too large"),
+        (status = 422, description = "The idempotency key was used for a different request, or the request has too many inputs or tokens"),
+        (status = 429, description = "Too many requests queued for the model, or the token quota of the API key is used up"),
+        (status = 503, description = "The request timed out, the circuit breaker is open, or the model is loading with `wait=false`"),
+    )
+)]
 pub async fn infer_text_embeddings(
     State(server_state): State<Arc<ServerState>>,
     Query(query): Query<QueryData>,
-    Json(embeddings_request): Json<EmbeddingsRequest>,
-) -> Result<(StatusCode, Json<EmbeddingsResponse>), ServerError> {
-    tracing::trace!("Requested API version: {:?}", query.api_version);
+    headers: HeaderMap,
+    request: Request,
+) -> Result<Response, ServerError> {
+    let api_version = query.api_version()?;
+    let format = ResponseFormat::from_headers(&headers);
+
+    if ndjson::is_ndjson(&headers) {
+        let model = server_state.model_or_default(query.model)?;
+        let body = request.into_body();
+        return Ok(ndjson::stream_embeddings(
+            server_state,
+            headers,
+            model,
+            api_version,
+            body,
+        ));
+    }
+    let embeddings_request = match Json::<EmbeddingsRequest>::from_request(request, &()).await {
+        Ok(Json(embeddings_request)) => embeddings_request,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
 
     let start = Instant::now();
-    let (client, _) = server_state
-        .model_map
-        .get(&embeddings_request.model)
-        .ok_or(ServerError::ModelNotFound)?;
+    let (client, revision) = server_state
+        .client_or_load(&embeddings_request.model, query.wait())
+        .await?;
+    let quota = server_state.quota(&headers)?;
+    server_state
+        .request_limits
+        .check(&client, &embeddings_request.input)
+        .await?;
+
+    // Not set for responses replayed for an idempotency key
+    let mut timings: Option<Timings> = None;
+    let mut quota_status: Option<QuotaStatus> = None;
+    let infer = async {
+        let context = RequestContext {
+            route: "/v1/embeddings",
+            headers: &headers,
+            model: &embeddings_request.model,
+            revision: revision.unwrap_or(client.revision()),
+        };
+        let mut request = embeddings_request.clone();
+        server_state.hooks.pre_tokenize(&context, &mut request)?;
+
+        let mut response = client.generate_embedding(request.clone()).await?;
+        response.revision = revision.map(str::to_string);
+        server_state.hooks.post_embedding(&context, &mut response)?;
+
+        let duration = Instant::now() - start;
+        tracing::trace!("Inference took {} ms", duration.as_millis());
+
+        if let Some(quota) = &quota {
+            quota_status = Some(quota.charge(response.total_tokens()));
+        }
 
-    let response = client.generate_embedding(embeddings_request).await?;
+        if let Some(recorder) = &server_state.recorder {
+            recorder.record("embeddings", &request, duration);
+        }
+        if let Some(shadow) = &server_state.shadow {
+            if shadow.shadows(&request.model) {
+                shadow.mirror(&request, &response, duration);
+            }
+        }
+
+        let mut response_timings = response.timings;
+        let mut response = response.into_version(api_version);
+        format.prepare(&mut response);
+        let body = timing::timed("serialize", &mut response_timings.serialize, || {
+            format.serialize_embeddings(&response)
+        })?;
+        timings = Some(response_timings);
+
+        Ok(body)
+    };
+
+    let metadata_headers = client.metadata(embeddings_request.pooling).headers();
+    let mut response = match &server_state.idempotency {
+        Some(store) => {
+            let scope = format!("embeddings/{api_version:?}");
+            store
+                .run(&scope, &headers, &embeddings_request, format, infer)
+                .await?
+        }
+        None => format.response(infer.await?),
+    };
+    response.headers_mut().extend(metadata_headers);
+    insert_timings(&mut response, timings);
+    insert_quota(&mut response, quota_status);
+
+    Ok(response)
+}
+
+/// Embed the same inputs with several models, running the models concurrently.
+#[utoipa::path(
+    post,
+    path = "/v1/embeddings/multi",
+    tag = "embeddings",
+    params(QueryData, ("idempotency-key" = Option<String>, Header, description = "Execute the request only once for this key")),
+    request_body = MultiEmbeddingsRequest,
+    responses(
+        (status = 200, description = "Embeddings of the inputs by each model, in MessagePack or CBOR with `Accept: application/msgpack` or `Accept: application/cbor`", body = MultiEmbeddingsResponse),
+        (status = 400, description = "Invalid request"),
+        (status = 404, description = "One of the models isn't served"),
+        (status = 406, description = "Arrow IPC streams were requested"),
+        (status = 409, description = "A request with the idempotency key is still being processed"),
+        (status = 413, description = "The request body is too large"),
+        (status = 422, description = "The idempotency key was used for a different request, or the request has too many inputs or tokens"),
+        (status = 429, description = "Too many requests queued for one of the models, or the token quota of the API key is used up"),
+        (status = 503, description = "The request timed out, the circuit breaker is open, or the model is loading with `wait=false`"),
+    )
+)]
+pub async fn infer_multi_model_embeddings(
+    State(server_state): State<Arc<ServerState>>,
+    Query(query): Query<QueryData>,
+    headers: HeaderMap,
+    Json(multi_request): Json<MultiEmbeddingsRequest>,
+) -> Result<Response, ServerError> {
+    let api_version = query.api_version()?;
+    let format = ResponseFormat::from_headers(&headers);
 
-    let duration = Instant::now() - start;
-    tracing::trace!("Inference took {} ms", duration.as_millis());
+    if format == ResponseFormat::Arrow {
+        return Err(ServerError::NotAcceptable(
+            "Arrow responses are only supported by `/v1/embeddings`".to_string(),
+        ));
+    }
+    if multi_request.models.is_empty() {
+        return Err(ServerError::InvalidRequest(
+            "At least one model is required".to_string(),
+        ));
+    }
+
+    let start = Instant::now();
+    let quota = server_state.quota(&headers)?;
+    let mut timings: Option<Timings> = None;
+    let mut quota_status: Option<QuotaStatus> = None;
+
+    // Resolve all models before queueing any work
+    let mut requests = Vec::new();
+    for request in multi_request.clone().into_requests() {
+        let (client, revision) = server_state
+            .client_or_load(&request.model, query.wait())
+            .await?;
+        requests.push((client, revision, request));
+    }
+    // Each model counts the tokens of the inputs with its own tokenizer
+    for (client, _, request) in &requests {
+        server_state
+            .request_limits
+            .check(client, &request.input)
+            .await?;
+    }
+
+    let infer = async {
+        let responses =
+            try_join_all(requests.into_iter().map(|(client, revision, mut request)| {
+                let headers = &headers;
+                let hooks = &server_state.hooks;
+                async move {
+                    let model = request.model.clone();
+                    let context = RequestContext {
+                        route: "/v1/embeddings/multi",
+                        headers,
+                        model: &model,
+                        revision: revision.unwrap_or(client.revision()),
+                    };
+                    hooks.pre_tokenize(&context, &mut request)?;
+
+                    let mut response = client.generate_embedding(request).await?;
+                    response.revision = revision.map(str::to_string);
+                    hooks.post_embedding(&context, &mut response)?;
+                    Ok::<_, ServerError>(response)
+                }
+            }))
+            .await?;
+
+        let duration = Instant::now() - start;
+        tracing::trace!(
+            "Inference with {} models took {} ms",
+            responses.len(),
+            duration.as_millis()
+        );
+
+        if let Some(recorder) = &server_state.recorder {
+            recorder.record("embeddings/multi", &multi_request, duration);
+        }
+        // Every model embeds the inputs, so the request is charged for the tokens of each
+        if let Some(quota) = &quota {
+            let tokens = responses.iter().map(EmbeddingsResponse::total_tokens).sum();
+            quota_status = Some(quota.charge(tokens));
+        }
+
+        // The models run concurrently, so the slowest one determines each stage
+        let mut multi_timings = responses
+            .iter()
+            .fold(Timings::default(), |timings, response| {
+                timings.max(response.timings)
+            });
+        let responses: Vec<EmbeddingsResponse> = responses
+            .into_iter()
+            .map(|response| {
+                let mut response = response.into_version(api_version);
+                format.prepare(&mut response);
+                response
+            })
+            .collect();
+
+        let body = timing::serialize(
+            &MultiEmbeddingsResponse::from(responses),
+            format,
+            &mut multi_timings,
+        )?;
+        timings = Some(multi_timings);
+
+        Ok(body)
+    };
+
+    let mut response = match &server_state.idempotency {
+        Some(store) => {
+            let scope = format!("embeddings/multi/{api_version:?}");
+            store
+                .run(&scope, &headers, &multi_request, format, infer)
+                .await?
+        }
+        None => format.response(infer.await?),
+    };
+    insert_timings(&mut response, timings);
+    insert_quota(&mut response, quota_status);
+
+    Ok(response)
+}
+
+fn insert_timings(response: &mut Response, timings: Option<Timings>) {
+    if let Some(value) = timings.and_then(|timings| timings.header_value()) {
+        response.headers_mut().insert(SERVER_TIMING_HEADER, value);
+    }
+}
 
-    Ok((StatusCode::OK, Json(response)))
+fn insert_quota(response: &mut Response, status: Option<QuotaStatus>) {
+    if let Some(status) = status {
+        response.headers_mut().extend(status.headers());
+    }
 }
 
 // #[cfg(test)]
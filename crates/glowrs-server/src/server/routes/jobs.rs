@@ -0,0 +1,101 @@
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use std::sync::Arc;
+
+use crate::server::jobs::{CreateJobRequest, Job, JobStatus, Jobs};
+use crate::server::state::ServerState;
+use crate::server::ServerError;
+
+fn jobs(server_state: &ServerState) -> Result<&Jobs, ServerError> {
+    server_state.jobs.as_deref().ok_or(ServerError::JobNotFound)
+}
+
+/// Submit a batch of texts to embed in the background.
+#[utoipa::path(
+    post,
+    path = "/v1/jobs",
+    tag = "jobs",
+    request_body = CreateJobRequest,
+    responses(
+        (status = 202, description = "The job was queued", body = Job),
+        (status = 400, description = "Invalid request"),
+        (status = 404, description = "The model isn't served, or batch jobs are not enabled"),
+        (status = 422, description = "An input has more tokens than allowed"),
+        (status = 429, description = "The token quota of the API key is used up"),
+    )
+)]
+pub async fn create_job(
+    State(server_state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    Json(request): Json<CreateJobRequest>,
+) -> Result<(StatusCode, Json<Job>), ServerError> {
+    let jobs = jobs(&server_state)?;
+    if request.input.is_empty() {
+        return Err(ServerError::InvalidRequest(
+            "At least one input is required".to_string(),
+        ));
+    }
+    server_state.admit_job(&headers, &request).await?;
+
+    let job = jobs.submit(request)?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+/// Get the status and progress of a batch job.
+#[utoipa::path(
+    get,
+    path = "/v1/jobs/{job_id}",
+    tag = "jobs",
+    params(("job_id" = String, Path, description = "ID of the job")),
+    responses(
+        (status = 200, description = "The job", body = Job),
+        (status = 404, description = "The job doesn't exist"),
+    )
+)]
+pub async fn get_job(
+    State(server_state): State<Arc<ServerState>>,
+    Path(job_id): Path<String>,
+) -> Result<(StatusCode, Json<Job>), ServerError> {
+    let job = jobs(&server_state)?
+        .get(&job_id)
+        .ok_or(ServerError::JobNotFound)?;
+
+    Ok((StatusCode::OK, Json(job)))
+}
+
+/// Download the embeddings of a completed batch job, as JSON lines with the `index` and
+/// `embedding` of each input.
+#[utoipa::path(
+    get,
+    path = "/v1/jobs/{job_id}/results",
+    tag = "jobs",
+    params(("job_id" = String, Path, description = "ID of the job")),
+    responses(
+        (status = 200, description = "Embeddings of the inputs", content_type = "application/x-ndjson"),
+        (status = 404, description = "The job doesn't exist"),
+        (status = 409, description = "The job hasn't completed"),
+    )
+)]
+pub async fn get_job_results(
+    State(server_state): State<Arc<ServerState>>,
+    Path(job_id): Path<String>,
+) -> Result<Response, ServerError> {
+    let jobs = jobs(&server_state)?;
+    let job = jobs.get(&job_id).ok_or(ServerError::JobNotFound)?;
+    if job.status != JobStatus::Completed {
+        return Err(ServerError::JobNotCompleted);
+    }
+
+    let results = tokio::fs::read(jobs.results_path(&job.id))
+        .await
+        .map_err(anyhow::Error::from)?;
+
+    Ok((
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        results,
+    )
+        .into_response())
+}
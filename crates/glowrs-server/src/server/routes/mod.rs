@@ -1,3 +1,11 @@
+pub mod batches;
+pub mod classify;
 pub mod default;
 pub mod embeddings;
+pub mod jobs;
 pub mod models;
+pub mod ndjson;
+pub mod shadow;
+pub mod stream;
+pub mod tei;
+pub mod tokenize;
@@ -1,67 +1,97 @@
-use axum::extract::State;
+use axum::extract::{Path, State};
 use axum::http::StatusCode;
 use axum::Json;
-use serde::Serialize;
+use glowrs::core::utils::parse_repo_string;
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
 
 use crate::server::state::ServerState;
 use crate::server::ServerError;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct ModelCard {
     id: String,
     object: String,
     created: usize,
     owned_by: String,
+    /// Revision of the model repository
+    revision: String,
+    /// Served model the model is an alias of
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alias_of: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct ModelCardList {
     object: String,
     data: Vec<ModelCard>,
+    /// Model for requests that don't name a model, or name an unknown model if configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default_model: Option<String>,
 }
 
+/// List the served models and their aliases.
+#[utoipa::path(
+    get,
+    path = "/v1/models",
+    tag = "models",
+    responses((status = 200, description = "Served models", body = ModelCardList))
+)]
 pub async fn list_models(
     State(server_state): State<Arc<ServerState>>,
 ) -> anyhow::Result<(StatusCode, Json<ModelCardList>), ServerError> {
-    let model_map = &server_state.model_map;
-
-    let model_cards = model_map
-        .keys()
-        .map(|model_name| {
-            ModelCard {
-                id: model_name.clone(),
-                object: "core".to_string(),
-                // This is a placeholder for the actual creation time
-                created: SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs() as usize,
-                owned_by: "hf_hub".to_string(),
-            }
-        })
+    // Includes the models that are unloaded until their next request
+    let model_cards = server_state
+        .model_map
+        .names()
+        .into_iter()
+        .chain(server_state.aliases.names())
+        .filter_map(|model_name| model_card(&server_state, model_name).ok())
         .collect();
 
     let model_card_list = ModelCardList {
         object: "list".to_string(),
         data: model_cards,
+        default_model: server_state.default_model.clone(),
     };
 
     Ok((StatusCode::OK, Json(model_card_list)))
 }
 
+/// Get a served model by name or alias. Slashes in the name are URL-encoded as `%2F`.
+#[utoipa::path(
+    get,
+    path = "/v1/models/{model_id}",
+    tag = "models",
+    params(("model_id" = String, Path, description = "Name of the model")),
+    responses(
+        (status = 200, description = "The model", body = ModelCard),
+        (status = 404, description = "The model isn't served"),
+    )
+)]
 pub async fn get_model(
     State(server_state): State<Arc<ServerState>>,
-    model_id: String,
+    Path(model_id): Path<String>,
 ) -> anyhow::Result<(StatusCode, Json<ModelCard>), ServerError> {
-    let _ = server_state
+    let model_card = model_card(&server_state, model_id)?;
+
+    Ok((StatusCode::OK, Json(model_card)))
+}
+
+fn model_card(server_state: &ServerState, model_id: String) -> Result<ModelCard, ServerError> {
+    let alias_of = match server_state.model_map.contains(&model_id) {
+        true => None,
+        false => server_state.aliases.get(&model_id).map(str::to_string),
+    };
+    let revision = server_state
         .model_map
-        .get(&model_id)
+        .revision(alias_of.as_deref().unwrap_or(&model_id))
         .ok_or(ServerError::ModelNotFound)?;
 
-    let model_card = ModelCard {
-        id: model_id.clone(),
+    Ok(ModelCard {
+        id: model_id,
         object: "core".to_string(),
         // This is a placeholder for the actual creation time
         created: SystemTime::now()
@@ -69,9 +99,89 @@ pub async fn get_model(
             .unwrap()
             .as_secs() as usize,
         owned_by: "hf_hub".to_string(),
+        revision,
+        alias_of,
+    })
+}
+
+/// Request to load a model into the running server.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct LoadModelRequest {
+    /// Model repository, as `<repo>` or `<repo>:<revision>`
+    model_repo: String,
+    /// Name to serve the model under. Defaults to the repository
+    name: Option<String>,
+}
+
+/// Request to stop serving a model.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct UnloadModelRequest {
+    /// Name of the model
+    model: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UnloadedModel {
+    id: String,
+    object: String,
+    deleted: bool,
+}
+
+/// Load a model repository into the running server, and serve it along with the other models.
+#[utoipa::path(
+    post,
+    path = "/v1/models/load",
+    tag = "models",
+    request_body = LoadModelRequest,
+    responses(
+        (status = 201, description = "The loaded model", body = ModelCard),
+        (status = 409, description = "A model with the same name is already served"),
+        (status = 500, description = "The model failed to load"),
+    )
+)]
+pub async fn load_model(
+    State(server_state): State<Arc<ServerState>>,
+    Json(request): Json<LoadModelRequest>,
+) -> anyhow::Result<(StatusCode, Json<ModelCard>), ServerError> {
+    let name = match request.name {
+        Some(name) => name,
+        None => parse_repo_string(&request.model_repo)
+            .map_err(anyhow::Error::from)?
+            .0
+            .to_string(),
     };
+    server_state
+        .load_model(request.model_repo, name.clone())
+        .await?;
 
-    Ok((StatusCode::OK, Json(model_card)))
+    Ok((StatusCode::CREATED, Json(model_card(&server_state, name)?)))
+}
+
+/// Stop serving a model. Requests for the model that are in progress are completed first.
+#[utoipa::path(
+    post,
+    path = "/v1/models/unload",
+    tag = "models",
+    request_body = UnloadModelRequest,
+    responses(
+        (status = 200, description = "The model was unloaded", body = UnloadedModel),
+        (status = 404, description = "The model isn't served"),
+    )
+)]
+pub async fn unload_model(
+    State(server_state): State<Arc<ServerState>>,
+    Json(request): Json<UnloadModelRequest>,
+) -> anyhow::Result<(StatusCode, Json<UnloadedModel>), ServerError> {
+    server_state.unload_model(&request.model)?;
+
+    Ok((
+        StatusCode::OK,
+        Json(UnloadedModel {
+            id: request.model,
+            object: "model".to_string(),
+            deleted: true,
+        }),
+    ))
 }
 
 // #[cfg(test)]
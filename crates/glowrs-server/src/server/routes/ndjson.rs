@@ -0,0 +1,220 @@
+//! Newline-delimited JSON (NDJSON) mode of `/v1/embeddings`
+//!
+//! Requests with `Content-Type: application/x-ndjson` have one input per line, as a JSON string,
+//! and are answered with one embedding per line as each batch of inputs completes. The request
+//! body is read a batch at a time, so memory use stays flat however many inputs are sent.
+
+use axum::body::Body;
+use axum::http::{header, HeaderMap, HeaderValue};
+use axum::response::{IntoResponse, Response};
+use futures_util::StreamExt;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use crate::server::data_models::{ApiVersion, EmbeddingsRequest, Sentences};
+use crate::server::routes::stream::error_message;
+use crate::server::state::ServerState;
+use crate::server::ServerError;
+
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+/// Routes that stream NDJSON request bodies
+const STREAMING_ROUTES: [&str; 1] = ["/v1/embeddings"];
+/// Number of inputs embedded at once, unless fewer inputs are allowed per request
+const BATCH_SIZE: usize = 64;
+
+/// Whether a request has an NDJSON body.
+pub fn is_ndjson(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(NDJSON_CONTENT_TYPE))
+}
+
+/// Whether a request is streamed in NDJSON mode: an NDJSON body sent to a route that supports it.
+/// Other routes read NDJSON bodies up front like any other body.
+pub fn is_streamed(path: &str, headers: &HeaderMap) -> bool {
+    STREAMING_ROUTES.contains(&path) && is_ndjson(headers)
+}
+
+/// Splits a stream of bytes into lines.
+#[derive(Default)]
+struct Lines {
+    buffer: Vec<u8>,
+}
+
+impl Lines {
+    /// Add bytes, returning the lines they complete.
+    fn push(&mut self, bytes: &[u8]) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(bytes);
+        let Some(end) = self.buffer.iter().rposition(|&b| b == b'\n') else {
+            return Vec::new();
+        };
+
+        let rest = self.buffer.split_off(end + 1);
+        let complete = std::mem::replace(&mut self.buffer, rest);
+        complete
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.trim_ascii().is_empty())
+            .map(<[u8]>::to_vec)
+            .collect()
+    }
+
+    /// The last line, if the stream didn't end with a newline.
+    fn finish(self) -> Option<Vec<u8>> {
+        Some(self.buffer).filter(|line| !line.trim_ascii().is_empty())
+    }
+}
+
+/// Parse an input line, which is a JSON string.
+fn parse_input(line: &[u8]) -> Result<String, ServerError> {
+    serde_json::from_slice(line)
+        .map_err(|err| ServerError::InvalidRequest(format!("Invalid input line: {err}")))
+}
+
+/// Embed a batch of inputs, numbered from `offset`, returning a line per embedding.
+async fn embed_batch(
+    server_state: &ServerState,
+    headers: &HeaderMap,
+    model: &str,
+    api_version: ApiVersion,
+    inputs: Vec<String>,
+    offset: usize,
+) -> Result<String, ServerError> {
+    let request = EmbeddingsRequest::new(Sentences::from(inputs), model.to_string());
+    let response = server_state
+        .embed("/v1/embeddings", headers, request)
+        .await?
+        .into_version(api_version);
+
+    let mut lines = String::new();
+    for mut inner in response.data {
+        inner.index += offset as u32;
+        lines.push_str(&serde_json::to_string(&inner).map_err(anyhow::Error::from)?);
+        lines.push('\n');
+    }
+    Ok(lines)
+}
+
+/// Read the inputs of `body` a batch at a time, and send the lines of their embeddings to `tx`.
+/// Stops at the first error, which is sent as the last line.
+async fn embed_lines(
+    server_state: Arc<ServerState>,
+    headers: HeaderMap,
+    model: String,
+    api_version: ApiVersion,
+    body: Body,
+    tx: mpsc::Sender<String>,
+) -> Result<(), ServerError> {
+    let batch_size = server_state
+        .request_limits
+        .max_inputs
+        .map_or(BATCH_SIZE, |max_inputs| max_inputs.clamp(1, BATCH_SIZE));
+    let mut stream = body.into_data_stream();
+    let mut lines = Lines::default();
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut offset = 0;
+
+    loop {
+        let (complete, done) = match stream.next().await {
+            Some(chunk) => {
+                let chunk = chunk.map_err(|err| ServerError::InvalidRequest(err.to_string()))?;
+                (lines.push(&chunk), false)
+            }
+            None => (
+                std::mem::take(&mut lines).finish().into_iter().collect(),
+                true,
+            ),
+        };
+        for line in complete {
+            batch.push(parse_input(&line)?);
+            if batch.len() == batch_size {
+                let inputs = std::mem::replace(&mut batch, Vec::with_capacity(batch_size));
+                let embeddings =
+                    embed_batch(&server_state, &headers, &model, api_version, inputs, offset)
+                        .await?;
+                offset += batch_size;
+                // The client disconnected
+                if tx.send(embeddings).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+
+        if done {
+            break;
+        }
+    }
+
+    if !batch.is_empty() {
+        let embeddings =
+            embed_batch(&server_state, &headers, &model, api_version, batch, offset).await?;
+        let _ = tx.send(embeddings).await;
+    }
+    Ok(())
+}
+
+/// Stream the embeddings of the inputs of an NDJSON request body, see the [module](self)
+/// documentation.
+pub fn stream_embeddings(
+    server_state: Arc<ServerState>,
+    headers: HeaderMap,
+    model: String,
+    api_version: ApiVersion,
+    body: Body,
+) -> Response {
+    // Only one batch is buffered, so inputs are read as fast as the client reads the embeddings
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let errors = tx.clone();
+        let result = embed_lines(server_state, headers, model, api_version, body, tx).await;
+        if let Err(err) = result {
+            let _ = errors.send(error_message(err) + "\n").await;
+        }
+    });
+
+    let lines = futures_util::stream::unfold(rx, |mut rx| async move {
+        let line = rx.recv().await?;
+        Some((Ok::<_, Infallible>(line), rx))
+    });
+    (
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(NDJSON_CONTENT_TYPE),
+        )],
+        Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lines() {
+        let mut lines = Lines::default();
+        assert!(lines.push(b"\"a").is_empty());
+        assert_eq!(
+            lines.push(b"\"\n\n\"b\"\n\"c"),
+            vec![b"\"a\"".to_vec(), b"\"b\"".to_vec()]
+        );
+        assert_eq!(lines.finish(), Some(b"\"c".to_vec()));
+
+        assert_eq!(parse_input(b"\"a\"").unwrap(), "a");
+        assert!(parse_input(b"a").is_err());
+    }
+
+    #[test]
+    fn test_is_streamed() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_streamed("/v1/embeddings", &headers));
+
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(NDJSON_CONTENT_TYPE),
+        );
+        assert!(is_streamed("/v1/embeddings", &headers));
+        assert!(!is_streamed("/v1/files", &headers));
+    }
+}
@@ -0,0 +1,29 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use std::sync::Arc;
+
+use crate::server::shadow::ShadowStats;
+use crate::server::state::ServerState;
+use crate::server::ServerError;
+
+/// Statistics of the requests mirrored to the shadow model.
+#[utoipa::path(
+    get,
+    path = "/v1/shadow",
+    tag = "shadow",
+    responses(
+        (status = 200, description = "Shadow traffic statistics", body = ShadowStats),
+        (status = 404, description = "No shadow model is configured"),
+    )
+)]
+pub async fn shadow_stats(
+    State(server_state): State<Arc<ServerState>>,
+) -> Result<(StatusCode, Json<ShadowStats>), ServerError> {
+    let shadow = server_state
+        .shadow
+        .as_ref()
+        .ok_or(ServerError::ModelNotFound)?;
+
+    Ok((StatusCode::OK, Json(shadow.stats())))
+}
@@ -0,0 +1,126 @@
+//! WebSocket route for streaming inputs
+//!
+//! Clients send messages with inputs to embed over a single connection, and receive the
+//! embeddings of each message as soon as they are done, so large ingestion jobs don't need to
+//! be sent in one huge request body. Messages are processed one at a time, in the order they
+//! are sent.
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use std::sync::Arc;
+use utoipa::IntoParams;
+
+use crate::server::data_models::{EmbeddingsResponse, StreamEmbeddingsRequest};
+use crate::server::state::ServerState;
+use crate::server::ServerError;
+
+const ROUTE: &str = "/v1/embeddings/ws";
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct StreamQuery {
+    /// Model to embed with, unless a message names one. Defaults to the default model
+    model: Option<String>,
+}
+
+/// Embed the inputs of one message.
+async fn embed_message(
+    server_state: &ServerState,
+    headers: &HeaderMap,
+    model: &str,
+    text: &str,
+) -> Result<EmbeddingsResponse, ServerError> {
+    let request: StreamEmbeddingsRequest = serde_json::from_str(text)
+        .map_err(|err| ServerError::InvalidRequest(format!("Invalid message: {err}")))?;
+    let model = request.model.clone().unwrap_or_else(|| model.to_string());
+
+    server_state
+        .embed(ROUTE, headers, request.to_request(model))
+        .await
+}
+
+/// The message of an error, with the status code the HTTP routes respond to it with.
+pub(crate) fn error_message(err: ServerError) -> String {
+    let message = err.to_string();
+    let status = err.into_response().status();
+    serde_json::json!({
+        "error": {
+            "message": message,
+            "code": status.as_u16(),
+        }
+    })
+    .to_string()
+}
+
+/// Stream inputs over a WebSocket. Each text message is a JSON object with the `input` to embed,
+/// and is answered with a message with its embeddings, like the response of `/v1/embeddings`,
+/// or with an `error`. Errors don't close the connection.
+#[utoipa::path(
+    get,
+    path = "/v1/embeddings/ws",
+    tag = "embeddings",
+    params(StreamQuery),
+    responses(
+        (status = 101, description = "Switched to the WebSocket protocol"),
+        (status = 404, description = "The model isn't served"),
+    )
+)]
+pub async fn stream_embeddings(
+    State(server_state): State<Arc<ServerState>>,
+    Query(query): Query<StreamQuery>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Result<Response, ServerError> {
+    let model = server_state.model_or_default(query.model)?;
+    if !server_state
+        .model_map
+        .contains(server_state.resolve(&model))
+    {
+        return Err(ServerError::ModelNotFound);
+    }
+
+    Ok(ws.on_upgrade(move |socket| stream(socket, server_state, headers, model)))
+}
+
+async fn stream(
+    mut socket: WebSocket,
+    server_state: Arc<ServerState>,
+    headers: HeaderMap,
+    model: String,
+) {
+    while let Some(Ok(message)) = socket.recv().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            // Pings are answered by axum
+            _ => continue,
+        };
+
+        let reply = match embed_message(&server_state, &headers, &model, &text).await {
+            Ok(response) => serde_json::to_string(&response)
+                .unwrap_or_else(|err| error_message(ServerError::InternalError(err.into()))),
+            Err(err) => error_message(err),
+        };
+        if socket.send(Message::Text(reply)).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_error_message() {
+        let message = error_message(ServerError::InvalidRequest("Invalid message".to_string()));
+        let message: serde_json::Value = serde_json::from_str(&message).unwrap();
+        assert_eq!(message["error"]["code"], 400);
+        assert_eq!(
+            message["error"]["message"],
+            "Invalid request: Invalid message"
+        );
+    }
+}
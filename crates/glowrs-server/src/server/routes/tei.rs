@@ -0,0 +1,112 @@
+//! Routes compatible with Hugging Face text-embeddings-inference (TEI), so that TEI clients can
+//! use the server without changes.
+
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use glowrs::PoolingStrategy;
+use std::sync::Arc;
+
+use crate::server::data_models::{
+    normalize, sparse_values, EmbeddingsResponse, SparseValue, TeiEmbedRequest,
+};
+use crate::server::state::ServerState;
+use crate::server::ServerError;
+
+/// Embed a TEI request with its model.
+async fn embed_request(
+    server_state: &ServerState,
+    route: &'static str,
+    headers: &HeaderMap,
+    request: &TeiEmbedRequest,
+    sparse: bool,
+) -> Result<EmbeddingsResponse, ServerError> {
+    let model = server_state.model_or_default(request.model.clone())?;
+    let (client, _) = server_state.client(&model).await?;
+    if sparse && client.metadata(None).pooling != Some(PoolingStrategy::Splade) {
+        return Err(ServerError::InvalidRequest(format!(
+            "Model `{model}` isn't a sparse (SPLADE) model"
+        )));
+    }
+
+    let response = server_state
+        .embed(route, headers, request.to_request(model))
+        .await?;
+    if !request.truncate
+        && response
+            .data
+            .iter()
+            .any(|inner| inner.truncated == Some(true))
+    {
+        return Err(ServerError::InvalidRequest(
+            "Inputs are longer than the maximum sequence length of the model".to_string(),
+        ));
+    }
+
+    Ok(response)
+}
+
+/// Embed the inputs, like the `/embed` route of text-embeddings-inference.
+#[utoipa::path(
+    post,
+    path = "/embed",
+    tag = "tei",
+    request_body = TeiEmbedRequest,
+    responses(
+        (status = 200, description = "Embeddings of the inputs", body = Vec<Vec<f32>>),
+        (status = 400, description = "Invalid request, or inputs too long without `truncate`"),
+        (status = 404, description = "The model isn't served"),
+        (status = 429, description = "Too many requests queued for the model, or the token quota of the API key is used up"),
+    )
+)]
+pub async fn embed(
+    State(server_state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    Json(request): Json<TeiEmbedRequest>,
+) -> Result<(StatusCode, Json<Vec<Vec<f32>>>), ServerError> {
+    let response = embed_request(&server_state, "/embed", &headers, &request, false).await?;
+
+    let embeddings = response
+        .data
+        .iter()
+        .map(|inner| {
+            let mut embedding = inner.embedding.to_vec();
+            if request.normalize {
+                normalize(&mut embedding);
+            }
+            embedding
+        })
+        .collect();
+
+    Ok((StatusCode::OK, Json(embeddings)))
+}
+
+/// Embed the inputs with a SPLADE model, returning the non-zero values of each embedding, like
+/// the `/embed_sparse` route of text-embeddings-inference.
+#[utoipa::path(
+    post,
+    path = "/embed_sparse",
+    tag = "tei",
+    request_body = TeiEmbedRequest,
+    responses(
+        (status = 200, description = "Sparse embeddings of the inputs", body = Vec<Vec<SparseValue>>),
+        (status = 400, description = "Invalid request, the model isn't a SPLADE model, or inputs too long without `truncate`"),
+        (status = 404, description = "The model isn't served"),
+        (status = 429, description = "Too many requests queued for the model, or the token quota of the API key is used up"),
+    )
+)]
+pub async fn embed_sparse(
+    State(server_state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    Json(request): Json<TeiEmbedRequest>,
+) -> Result<(StatusCode, Json<Vec<Vec<SparseValue>>>), ServerError> {
+    let response = embed_request(&server_state, "/embed_sparse", &headers, &request, true).await?;
+
+    let embeddings = response
+        .data
+        .iter()
+        .map(|inner| sparse_values(&inner.embedding.to_vec()))
+        .collect();
+
+    Ok((StatusCode::OK, Json(embeddings)))
+}
@@ -0,0 +1,192 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use std::sync::Arc;
+use tokenizers::Tokenizer;
+
+use crate::server::data_models::{
+    DecodeRequest, DecodeResponse, Token, TokenizeRequest, TokenizeResponse, TokenizedInput,
+};
+use crate::server::state::ServerState;
+use crate::server::ServerError;
+
+/// Tokenize each input on its own, so that the tokens aren't padded.
+pub(crate) fn tokenize_inputs(
+    tokenizer: &Tokenizer,
+    inputs: Vec<String>,
+    add_special_tokens: bool,
+) -> anyhow::Result<Vec<TokenizedInput>> {
+    inputs
+        .into_iter()
+        .enumerate()
+        .map(|(index, input)| {
+            let encoding = tokenizer
+                .encode(input, add_special_tokens)
+                .map_err(anyhow::Error::msg)?;
+            let tokens = encoding
+                .get_ids()
+                .iter()
+                .zip(encoding.get_tokens())
+                .zip(encoding.get_offsets())
+                .zip(encoding.get_special_tokens_mask())
+                .map(|(((&id, text), &(start, stop)), &special)| {
+                    let special = special == 1;
+                    Token {
+                        id,
+                        text: text.clone(),
+                        special,
+                        start: (!special).then_some(start),
+                        stop: (!special).then_some(stop),
+                    }
+                })
+                .collect();
+
+            Ok(TokenizedInput {
+                index: index as u32,
+                tokens,
+                truncated: !encoding.get_overflowing().is_empty(),
+            })
+        })
+        .collect()
+}
+
+/// Tokenize the inputs with the tokenizer of a model, without embedding them, e.g. to count
+/// their tokens or check whether they are truncated.
+#[utoipa::path(
+    post,
+    path = "/v1/tokenize",
+    tag = "tokenizer",
+    request_body = TokenizeRequest,
+    responses(
+        (status = 200, description = "Tokens of the inputs", body = TokenizeResponse),
+        (status = 404, description = "The model isn't served"),
+    )
+)]
+pub async fn tokenize(
+    State(server_state): State<Arc<ServerState>>,
+    Json(request): Json<TokenizeRequest>,
+) -> Result<(StatusCode, Json<TokenizeResponse>), ServerError> {
+    let (client, _) = server_state.client(&request.model).await?;
+    let tokenizer = client.tokenizer().clone();
+
+    let inputs = request.input.into();
+    let data = tokio::task::spawn_blocking(move || {
+        tokenize_inputs(&tokenizer, inputs, request.add_special_tokens)
+    })
+    .await
+    .map_err(anyhow::Error::from)??;
+
+    Ok((
+        StatusCode::OK,
+        Json(TokenizeResponse {
+            object: "list".to_string(),
+            data,
+            model: request.model,
+        }),
+    ))
+}
+
+/// Decode token ids into text with the tokenizer of a model.
+#[utoipa::path(
+    post,
+    path = "/v1/decode",
+    tag = "tokenizer",
+    request_body = DecodeRequest,
+    responses(
+        (status = 200, description = "Text of each list of token ids", body = DecodeResponse),
+        (status = 404, description = "The model isn't served"),
+    )
+)]
+pub async fn decode(
+    State(server_state): State<Arc<ServerState>>,
+    Json(request): Json<DecodeRequest>,
+) -> Result<(StatusCode, Json<DecodeResponse>), ServerError> {
+    let (client, _) = server_state.client(&request.model).await?;
+    let tokenizer = client.tokenizer().clone();
+
+    let ids: Vec<Vec<u32>> = request.ids.into();
+    let skip_special_tokens = request.skip_special_tokens;
+    let data = tokio::task::spawn_blocking(move || {
+        let ids: Vec<&[u32]> = ids.iter().map(Vec::as_slice).collect();
+        tokenizer
+            .decode_batch(&ids, skip_special_tokens)
+            .map_err(anyhow::Error::msg)
+    })
+    .await
+    .map_err(anyhow::Error::from)??;
+
+    Ok((
+        StatusCode::OK,
+        Json(DecodeResponse {
+            object: "list".to_string(),
+            data,
+            model: request.model,
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+
+    const TOKENIZER: &str = r#"{
+        "version": "1.0",
+        "truncation": {"direction": "Right", "max_length": 4, "strategy": "LongestFirst", "stride": 0},
+        "padding": null,
+        "added_tokens": [
+            {"id": 0, "content": "[CLS]", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true},
+            {"id": 1, "content": "[SEP]", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true}
+        ],
+        "normalizer": null,
+        "pre_tokenizer": {"type": "Whitespace"},
+        "post_processor": {
+            "type": "TemplateProcessing",
+            "single": [{"SpecialToken": {"id": "[CLS]", "type_id": 0}}, {"Sequence": {"id": "A", "type_id": 0}}, {"SpecialToken": {"id": "[SEP]", "type_id": 0}}],
+            "pair": [{"Sequence": {"id": "A", "type_id": 0}}, {"Sequence": {"id": "B", "type_id": 1}}],
+            "special_tokens": {
+                "[CLS]": {"id": "[CLS]", "ids": [0], "tokens": ["[CLS]"]},
+                "[SEP]": {"id": "[SEP]", "ids": [1], "tokens": ["[SEP]"]}
+            }
+        },
+        "decoder": null,
+        "model": {"type": "WordLevel", "vocab": {"[CLS]": 0, "[SEP]": 1, "[UNK]": 2, "the": 3, "cat": 4, "sits": 5}, "unk_token": "[UNK]"}
+    }"#;
+
+    #[test]
+    fn test_tokenize_inputs() -> anyhow::Result<()> {
+        let tokenizer = Tokenizer::from_str(TOKENIZER).map_err(anyhow::Error::msg)?;
+
+        let inputs = vec!["the cat".to_string(), "the cat sits".to_string()];
+        let tokenized = tokenize_inputs(&tokenizer, inputs.clone(), true)?;
+        let ids: Vec<Vec<u32>> = tokenized
+            .iter()
+            .map(|input| input.tokens.iter().map(|token| token.id).collect())
+            .collect();
+        // The second input doesn't fit in 4 tokens
+        assert_eq!(ids, vec![vec![0, 3, 4, 1], vec![0, 3, 4, 1]]);
+        assert_eq!(
+            tokenized
+                .iter()
+                .map(|input| input.truncated)
+                .collect::<Vec<_>>(),
+            vec![false, true]
+        );
+        assert_eq!(
+            tokenized[0].tokens[2],
+            Token {
+                id: 4,
+                text: "cat".to_string(),
+                special: false,
+                start: Some(4),
+                stop: Some(7),
+            }
+        );
+        assert_eq!(tokenized[0].tokens[0].start, None);
+
+        let tokenized = tokenize_inputs(&tokenizer, inputs, false)?;
+        assert_eq!(tokenized[1].tokens.len(), 3);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,277 @@
+//! Shadow traffic
+//!
+//! Mirrors a sample of the embedding requests for one of the served (primary) models to a shadow
+//! model that is loaded alongside it, but not served. The shadow responses are discarded; only
+//! their latency, errors and, optionally, the cosine similarity to the primary embeddings are
+//! recorded, to validate a model upgrade on production traffic. At most `--shadow-max-in-flight`
+//! mirrored requests are processed at once; requests mirrored beyond that are dropped, so a slow
+//! shadow model doesn't pile up work.
+
+use anyhow::{Context, Result};
+use clap::Args;
+use glowrs::core::utils::parse_repo_string;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use utoipa::ToSchema;
+
+use crate::server::data_models::{EmbeddingsRequest, EmbeddingsResponse};
+use crate::server::device::DeviceConfig;
+use crate::server::image::ImageConfig;
+use crate::server::infer::embed::{EmbeddingsClient, EmbeddingsHandler};
+use crate::server::infer::limits::QueueConfig;
+use crate::server::infer::DedicatedExecutor;
+use crate::server::preprocess::PreprocessConfig;
+use crate::server::state::model_names;
+use crate::server::utils::sampled;
+
+#[derive(Debug, Args)]
+pub struct ShadowArgs {
+    /// Model to mirror requests to. Its responses are discarded, only their latency, errors and
+    /// similarity to the primary model are recorded
+    #[clap(long)]
+    pub shadow_model: Option<String>,
+
+    /// Served model whose requests are mirrored. Defaults to the first given model
+    #[clap(long)]
+    pub shadow_primary: Option<String>,
+
+    /// Fraction of the primary requests to mirror (between 0 and 1)
+    #[clap(long, default_value = "1.0")]
+    pub shadow_sample_rate: f64,
+
+    /// Maximum number of mirrored requests processed at once. Requests mirrored beyond that are
+    /// dropped
+    #[clap(long, default_value = "16")]
+    pub shadow_max_in_flight: usize,
+
+    /// Record the cosine similarity between primary and shadow embeddings
+    #[clap(long)]
+    pub shadow_compare: bool,
+}
+
+/// Statistics of the mirrored requests.
+#[derive(Debug, Clone, Default, Serialize, PartialEq, ToSchema)]
+pub struct ShadowStats {
+    pub primary_model: String,
+    pub shadow_model: String,
+    pub requests: u64,
+    pub errors: u64,
+    /// Sampled requests that weren't mirrored, as too many mirrored requests were in flight
+    pub dropped: u64,
+    /// Mean latency of the primary model on the mirrored requests, in milliseconds
+    pub primary_latency_ms: f64,
+    /// Mean latency of the shadow model on the mirrored requests, in milliseconds
+    pub shadow_latency_ms: f64,
+    /// Mean cosine similarity between the primary and shadow embedding of each input
+    pub mean_similarity: Option<f64>,
+    pub min_similarity: Option<f64>,
+}
+
+#[derive(Default)]
+struct Totals {
+    requests: u64,
+    errors: u64,
+    dropped: u64,
+    primary_latency: Duration,
+    shadow_latency: Duration,
+    similarity_sum: f64,
+    similarity_count: u64,
+    min_similarity: Option<f64>,
+}
+
+pub struct Shadow {
+    primary: String,
+    name: String,
+    client: EmbeddingsClient,
+    _executors: Vec<DedicatedExecutor<EmbeddingsHandler>>,
+    sample_rate: f64,
+    compare: bool,
+    seen: AtomicU64,
+    in_flight: Arc<Semaphore>,
+    totals: Mutex<Totals>,
+}
+
+impl Shadow {
+    /// Load the shadow model, if one is configured.
+    pub fn from_args(
+        args: &ShadowArgs,
+        model_repos: &[String],
+        device: &DeviceConfig,
+        preprocess_config: &PreprocessConfig,
+        queue_config: &QueueConfig,
+        images: ImageConfig,
+    ) -> Result<Option<Self>> {
+        let Some(shadow_repo) = &args.shadow_model else {
+            return Ok(None);
+        };
+
+        if !(0.0..=1.0).contains(&args.shadow_sample_rate) {
+            anyhow::bail!("Shadow sample rate should be between 0 and 1");
+        }
+        if args.shadow_max_in_flight == 0 {
+            anyhow::bail!("Shadow max in flight should be at least 1");
+        }
+
+        let primary = match &args.shadow_primary {
+            Some(primary) => primary.clone(),
+            None => model_names(model_repos)?
+                .into_iter()
+                .next()
+                .context("No models provided")?,
+        };
+        let (name, _) = parse_repo_string(shadow_repo)?;
+
+        // Preprocess the shadow inputs like the primary ones, so only the models are compared
+        let handler = EmbeddingsHandler::from_repo_string(shadow_repo, device)?
+            .with_preprocessor(preprocess_config.preprocessor(&primary)?)
+            .with_images(images);
+        let (client, executors) = EmbeddingsClient::spawn(handler, queue_config.limits(name))?;
+
+        tracing::info!(
+            "Mirroring {:.0}% of the requests for {primary} to shadow model {name}",
+            args.shadow_sample_rate * 100.
+        );
+
+        Ok(Some(Self {
+            primary,
+            name: name.to_string(),
+            client,
+            _executors: executors,
+            sample_rate: args.shadow_sample_rate,
+            compare: args.shadow_compare,
+            seen: AtomicU64::new(0),
+            in_flight: Arc::new(Semaphore::new(args.shadow_max_in_flight)),
+            totals: Mutex::new(Totals::default()),
+        }))
+    }
+
+    /// Whether requests for `model` are mirrored.
+    pub fn shadows(&self, model: &str) -> bool {
+        self.primary == model
+    }
+
+    fn sample(&self) -> bool {
+        sampled(self.seen.fetch_add(1, Ordering::Relaxed), self.sample_rate)
+    }
+
+    fn totals(&self) -> std::sync::MutexGuard<'_, Totals> {
+        self.totals.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Mirror a primary request in the background, if it is sampled and there is room for it.
+    pub fn mirror(
+        self: &Arc<Self>,
+        request: &EmbeddingsRequest,
+        primary_response: &EmbeddingsResponse,
+        primary_latency: Duration,
+    ) {
+        if !self.sample() {
+            return;
+        }
+        let Ok(permit) = self.in_flight.clone().try_acquire_owned() else {
+            self.totals().dropped += 1;
+            return;
+        };
+
+        let request = EmbeddingsRequest {
+            model: self.name.clone(),
+            ..request.clone()
+        };
+        let primary_embeddings: Option<Vec<Vec<f32>>> = self.compare.then(|| {
+            primary_response
+                .data
+                .iter()
+                .map(|inner| inner.embedding.to_vec())
+                .collect()
+        });
+
+        let shadow = self.clone();
+        tokio::spawn(async move {
+            let start = Instant::now();
+            let result = shadow.client.generate_embedding(request).await;
+            let shadow_latency = start.elapsed();
+            drop(permit);
+
+            let mut totals = shadow.totals();
+            totals.requests += 1;
+            totals.primary_latency += primary_latency;
+            totals.shadow_latency += shadow_latency;
+
+            let response = match result {
+                Ok(response) => response,
+                Err(e) => {
+                    tracing::warn!("Shadow model {} failed: {e}", shadow.name);
+                    totals.errors += 1;
+                    return;
+                }
+            };
+
+            if let Some(primary_embeddings) = primary_embeddings {
+                for (a, b) in primary_embeddings.iter().zip(&response.data) {
+                    // Embeddings of models with different dimensions can't be compared
+                    if let Some(similarity) = cosine_similarity(a, &b.embedding.to_vec()) {
+                        totals.similarity_sum += similarity;
+                        totals.similarity_count += 1;
+                        totals.min_similarity = Some(
+                            totals
+                                .min_similarity
+                                .map_or(similarity, |min| min.min(similarity)),
+                        );
+                    }
+                }
+            }
+        });
+    }
+
+    pub fn stats(&self) -> ShadowStats {
+        let totals = self.totals();
+        let mean_ms = |total: Duration| {
+            if totals.requests == 0 {
+                0.
+            } else {
+                total.as_secs_f64() * 1000. / totals.requests as f64
+            }
+        };
+
+        ShadowStats {
+            primary_model: self.primary.clone(),
+            shadow_model: self.name.clone(),
+            requests: totals.requests,
+            errors: totals.errors,
+            dropped: totals.dropped,
+            primary_latency_ms: mean_ms(totals.primary_latency),
+            shadow_latency_ms: mean_ms(totals.shadow_latency),
+            mean_similarity: (totals.similarity_count > 0)
+                .then(|| totals.similarity_sum / totals.similarity_count as f64),
+            min_similarity: totals.min_similarity,
+        }
+    }
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> Option<f64> {
+    if a.len() != b.len() {
+        return None;
+    }
+
+    let dot: f64 = a.iter().zip(b).map(|(x, y)| (x * y) as f64).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| (x * x) as f64).sum::<f64>().sqrt();
+    let norms = norm(a) * norm(b);
+
+    (norms > 0.).then(|| dot / norms)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert_eq!(cosine_similarity(&[1., 0.], &[2., 0.]), Some(1.));
+        assert_eq!(cosine_similarity(&[1., 0.], &[0., 1.]), Some(0.));
+        assert!(cosine_similarity(&[1., 0.], &[1., 0., 0.]).is_none());
+        assert!(cosine_similarity(&[0., 0.], &[1., 0.]).is_none());
+    }
+}
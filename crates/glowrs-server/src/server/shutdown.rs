@@ -0,0 +1,82 @@
+//! Graceful shutdown
+//!
+//! On `SIGTERM` or Ctrl+C, the server stops accepting connections and gives the requests in
+//! progress up to `--drain-timeout` seconds to finish. Requests that are still in progress after
+//! that are dropped. Then the executors of the models are stopped, each once it has finished the
+//! inference it is running, rather than being killed mid-inference when the process exits.
+
+use clap::Args;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::server::state::ServerState;
+use crate::server::utils;
+
+#[derive(Debug, Args)]
+pub struct ShutdownArgs {
+    /// Seconds to let requests in progress finish after a shutdown signal, before they are
+    /// dropped and the models are stopped
+    #[clap(long, default_value = "30")]
+    pub drain_timeout: u64,
+}
+
+/// Drains the requests and stops the models of a server when it shuts down.
+pub struct Shutdown {
+    state: Arc<ServerState>,
+    drain_timeout: Duration,
+}
+
+impl Shutdown {
+    pub fn new(args: &ShutdownArgs, state: Arc<ServerState>) -> Self {
+        Self {
+            state,
+            drain_timeout: Duration::from_secs(args.drain_timeout),
+        }
+    }
+
+    /// Run `server`, which shuts down gracefully on a shutdown signal, until it has finished or
+    /// the drain timeout has passed since the signal. Then stop the models.
+    pub async fn serve<F>(self, server: F) -> std::io::Result<()>
+    where
+        F: Future<Output = std::io::Result<()>>,
+    {
+        let drained = async {
+            utils::shutdown_signal(None).await;
+            tracing::info!(
+                "Shutting down, draining requests for up to {} s",
+                self.drain_timeout.as_secs()
+            );
+            tokio::time::sleep(self.drain_timeout).await;
+        };
+
+        let result = tokio::select! {
+            result = server => result,
+            _ = drained => {
+                tracing::warn!("Requests didn't finish within the drain timeout, dropping them");
+                Ok(())
+            }
+        };
+        self.stop().await;
+
+        result
+    }
+
+    /// Stop the executors of the models, once they have finished the inference they are running.
+    async fn stop(&self) {
+        let mut executors = self.state.model_map.executors();
+        if let Some(canary) = &self.state.canary {
+            executors.extend(canary.executors().iter().cloned());
+        }
+
+        tracing::info!("Stopping {} executors", executors.len());
+        let stopped = tokio::task::spawn_blocking(move || {
+            for executor in executors {
+                executor.stop();
+            }
+        });
+        if let Err(err) = stopped.await {
+            tracing::error!("Failed to stop the executors: {err}");
+        }
+    }
+}
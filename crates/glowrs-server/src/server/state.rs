@@ -1,42 +1,731 @@
 use anyhow::Result;
+use axum::http::HeaderMap;
 use candle_core::Device;
+use glowrs::core::cache::EmbeddingCache;
 use glowrs::core::utils::parse_repo_string;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
 
+use crate::server::aliases::Aliases;
+use crate::server::canary::{Canary, Route};
+use crate::server::data_models::{EmbeddingsRequest, EmbeddingsResponse};
+use crate::server::device::DeviceConfig;
+use crate::server::hooks::{Hooks, RequestContext};
+use crate::server::idempotency::IdempotencyStore;
+use crate::server::image::ImageConfig;
 use crate::server::infer::embed::EmbeddingsClient;
 use crate::server::infer::embed::EmbeddingsHandler;
+use crate::server::infer::limits::QueueConfig;
 use crate::server::infer::DedicatedExecutor;
+use crate::server::jobs::{CreateJobRequest, Jobs};
+use crate::server::preprocess::PreprocessConfig;
+use crate::server::quota::{Charge, Quotas};
+use crate::server::record::Recorder;
+use crate::server::request_limits::RequestLimits;
+use crate::server::shadow::Shadow;
+use crate::server::worker::Workers;
+use crate::server::ServerError;
 
-// TODO: Create a struct to hold the core map
+/// A loaded model: its client, and the executors in this process that serve it.
+type LoadedModel = (
+    EmbeddingsClient,
+    Arc<Vec<DedicatedExecutor<EmbeddingsHandler>>>,
+);
+
+/// A served model, which may not be loaded.
+struct ModelEntry {
+    /// Repository the model is loaded from
+    model_repo: String,
+    /// The model, unless it isn't loaded yet or was unloaded to make room for other models
+    loaded: Option<LoadedModel>,
+    /// Tick of the clock of the map when the model was last used
+    last_used: AtomicU64,
+    /// Held while the model is loaded again, so concurrent requests load it only once
+    loading: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl ModelEntry {
+    fn new(model_repo: String, loaded: Option<LoadedModel>) -> Self {
+        Self {
+            model_repo,
+            loaded,
+            last_used: AtomicU64::new(0),
+            loading: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+}
+
+/// The served models by name. Models can be loaded and unloaded while the server runs, so the
+/// map is shared behind a lock, which is only held to look up or change entries.
+///
+/// With a capacity, at most that many models are loaded at once: the least recently used models
+/// are unloaded to make room, and loaded again on their next request.
 // TODO: Needs to support externally provided models (e.g. other gRPC services)
-type EmbeddingModelMap =
-    HashMap<String, (EmbeddingsClient, Arc<DedicatedExecutor<EmbeddingsHandler>>)>;
+#[derive(Clone, Default)]
+pub struct ModelMap {
+    models: Arc<RwLock<HashMap<String, ModelEntry>>>,
+    /// Maximum number of loaded models
+    capacity: Option<NonZeroUsize>,
+    /// Logical clock, ticking on every use of a model
+    clock: Arc<AtomicU64>,
+}
+
+impl ModelMap {
+    pub fn new(capacity: Option<NonZeroUsize>) -> Self {
+        Self {
+            capacity,
+            ..Self::default()
+        }
+    }
+
+    /// Whether another model can be loaded without unloading one.
+    fn has_room(&self) -> bool {
+        let models = self.models.read().unwrap_or_else(PoisonError::into_inner);
+        let loaded = models
+            .values()
+            .filter(|entry| entry.loaded.is_some())
+            .count();
+        self.capacity.is_none_or(|capacity| loaded < capacity.get())
+    }
+
+    /// The client of a loaded model, marking the model as used.
+    pub fn get(&self, name: &str) -> Option<EmbeddingsClient> {
+        let models = self.models.read().unwrap_or_else(PoisonError::into_inner);
+        let entry = models.get(name)?;
+        let (client, _) = entry.loaded.as_ref()?;
+        let tick = self.clock.fetch_add(1, Ordering::Relaxed);
+        entry.last_used.store(tick, Ordering::Relaxed);
+
+        Some(client.clone())
+    }
+
+    /// The names of the served models, loaded or not.
+    pub fn names(&self) -> Vec<String> {
+        let models = self.models.read().unwrap_or_else(PoisonError::into_inner);
+        let mut names: Vec<String> = models.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// The revision of a served model, without marking it as used.
+    pub fn revision(&self, name: &str) -> Option<String> {
+        let models = self.models.read().unwrap_or_else(PoisonError::into_inner);
+        let entry = models.get(name)?;
+        match &entry.loaded {
+            Some((client, _)) => Some(client.revision().to_string()),
+            None => parse_repo_string(&entry.model_repo)
+                .ok()
+                .map(|(_, revision)| revision.to_string()),
+        }
+    }
+
+    /// Whether a model is served, loaded or not.
+    pub fn contains(&self, name: &str) -> bool {
+        let models = self.models.read().unwrap_or_else(PoisonError::into_inner);
+        models.contains_key(name)
+    }
+
+    /// Add a model, unless a model with the same name is already served. Models that aren't
+    /// loaded yet are loaded on their first request. Returns whether the model was added.
+    pub fn insert(&self, name: String, model_repo: String, model: Option<LoadedModel>) -> bool {
+        let mut models = self.models.write().unwrap_or_else(PoisonError::into_inner);
+        match models.entry(name) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                let entry = entry.insert(ModelEntry::new(model_repo, model));
+                let tick = self.clock.fetch_add(1, Ordering::Relaxed);
+                entry.last_used.store(tick, Ordering::Relaxed);
+                true
+            }
+        }
+    }
+
+    /// Remove a model. Its executors stop once the requests in progress are done. Returns
+    /// whether the model was served.
+    pub fn remove(&self, name: &str) -> bool {
+        let mut models = self.models.write().unwrap_or_else(PoisonError::into_inner);
+        models.remove(name).is_some()
+    }
+
+    /// Whether a served model is being loaded.
+    pub fn is_loading(&self, name: &str) -> bool {
+        let models = self.models.read().unwrap_or_else(PoisonError::into_inner);
+        models
+            .get(name)
+            .is_some_and(|entry| entry.loading.try_lock().is_err())
+    }
+
+    /// The repository of a model that isn't loaded, and the lock to hold while loading it.
+    fn unloaded(&self, name: &str) -> Option<(String, Arc<tokio::sync::Mutex<()>>)> {
+        let models = self.models.read().unwrap_or_else(PoisonError::into_inner);
+        models
+            .get(name)
+            .filter(|entry| entry.loaded.is_none())
+            .map(|entry| (entry.model_repo.clone(), entry.loading.clone()))
+    }
+
+    /// Set the loaded model of a served model. Returns whether the model is still served.
+    fn set_loaded(&self, name: &str, model: LoadedModel) -> bool {
+        let mut models = self.models.write().unwrap_or_else(PoisonError::into_inner);
+        let Some(entry) = models.get_mut(name) else {
+            return false;
+        };
+        entry.loaded = Some(model);
+        let tick = self.clock.fetch_add(1, Ordering::Relaxed);
+        entry.last_used.store(tick, Ordering::Relaxed);
+        true
+    }
+
+    /// Unload the least recently used models while more models are loaded than the capacity.
+    /// Their executors stop once the requests in progress are done. Returns the unloaded models.
+    pub fn evict(&self) -> Vec<String> {
+        let Some(capacity) = self.capacity else {
+            return Vec::new();
+        };
+        let mut models = self.models.write().unwrap_or_else(PoisonError::into_inner);
+        let loaded: Vec<(&str, u64)> = models
+            .iter()
+            .filter(|(_, entry)| entry.loaded.is_some())
+            .map(|(name, entry)| (name.as_str(), entry.last_used.load(Ordering::Relaxed)))
+            .collect();
+        let evicted: Vec<String> = least_recently_used(loaded, capacity.get())
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        for name in &evicted {
+            if let Some(entry) = models.get_mut(name) {
+                entry.loaded = None;
+            }
+        }
+
+        evicted
+    }
+
+    /// The clients of the loaded models, by name.
+    pub fn clients(&self) -> Vec<(String, EmbeddingsClient)> {
+        let models = self.models.read().unwrap_or_else(PoisonError::into_inner);
+        let mut clients: Vec<(String, EmbeddingsClient)> = models
+            .iter()
+            .filter_map(|(name, entry)| {
+                let (client, _) = entry.loaded.as_ref()?;
+                Some((name.clone(), client.clone()))
+            })
+            .collect();
+        clients.sort_by(|(a, _), (b, _)| a.cmp(b));
+        clients
+    }
+
+    /// The executors of the loaded models.
+    pub fn executors(&self) -> Vec<DedicatedExecutor<EmbeddingsHandler>> {
+        let models = self.models.read().unwrap_or_else(PoisonError::into_inner);
+        models
+            .values()
+            .filter_map(|entry| entry.loaded.as_ref())
+            .flat_map(|(_, executors)| executors.iter().cloned())
+            .collect()
+    }
+
+    /// The repositories of the models that aren't loaded by name, along with whether they are
+    /// being loaded.
+    pub fn unloaded_models(&self) -> Vec<(String, String, bool)> {
+        let models = self.models.read().unwrap_or_else(PoisonError::into_inner);
+        let mut unloaded: Vec<(String, String, bool)> = models
+            .iter()
+            .filter(|(_, entry)| entry.loaded.is_none())
+            .map(|(name, entry)| {
+                let loading = entry.loading.try_lock().is_err();
+                (name.clone(), entry.model_repo.clone(), loading)
+            })
+            .collect();
+        unloaded.sort();
+        unloaded
+    }
+}
+
+/// The least recently used of the `loaded` models, given when they were last used, to unload so
+/// that `capacity` models remain.
+fn least_recently_used(mut loaded: Vec<(&str, u64)>, capacity: usize) -> Vec<&str> {
+    let excess = loaded.len().saturating_sub(capacity);
+    loaded.sort_by_key(|&(_, last_used)| last_used);
+    loaded
+        .into_iter()
+        .take(excess)
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// Loads models with the settings of the server, at startup and on request.
+#[derive(Clone)]
+pub struct ModelLoader {
+    pub device: DeviceConfig,
+    pub cache: Option<Arc<EmbeddingCache>>,
+    pub preprocess_config: PreprocessConfig,
+    pub queue_config: QueueConfig,
+    pub images: ImageConfig,
+    /// Worker processes to run the models in, if enabled
+    pub workers: Option<Workers>,
+    /// Whether to warm up models when they are loaded
+    pub warmup: bool,
+}
+
+impl ModelLoader {
+    /// Load a model repository, `<repo>` or `<repo>:<revision>`, and start its executors.
+    pub fn load(&self, model_repo: &str) -> Result<LoadedModel> {
+        let (repo, _) = parse_repo_string(model_repo)?;
+        let limits = self.queue_config.limits(repo);
+
+        match &self.workers {
+            // Workers set up preprocessing and warm up themselves
+            Some(workers) => workers
+                .spawn(model_repo, &self.device, limits)
+                .map(|client| (client, Arc::new(Vec::new()))),
+            None => {
+                let preprocessor = self.preprocess_config.preprocessor(repo)?;
+                let handler = EmbeddingsHandler::from_repo_string(model_repo, &self.device)?
+                    .with_cache(self.cache.clone())
+                    .with_preprocessor(preprocessor)
+                    .with_images(self.images);
+                let classifier = handler.classifier();
+                let warmup = match self.warmup {
+                    true => Some(handler.warm_up(model_repo)?),
+                    false => None,
+                };
+                let (client, executors) = EmbeddingsClient::spawn(handler, limits)?;
+                let client = client.with_classifier(classifier)?.with_warmup(warmup);
+                Ok((client, Arc::new(executors)))
+            }
+        }
+    }
+}
+
+/// Names the models are served under, in the order of their repositories: the repository, or
+/// `<repository>:<revision>` for repositories that are served in several revisions and given
+/// with an explicit revision.
+pub fn model_names(model_repos: &[String]) -> Result<Vec<String>> {
+    let repos = model_repos
+        .iter()
+        .map(|model_repo| {
+            let explicit = model_repo.contains(':');
+            parse_repo_string(model_repo).map(|(repo, revision)| (repo, revision, explicit))
+        })
+        .collect::<glowrs::Result<Vec<_>>>()?;
+
+    let mut names: Vec<String> = Vec::with_capacity(repos.len());
+    for (repo, revision, explicit) in &repos {
+        let revisions = repos.iter().filter(|(other, ..)| other == repo).count();
+        let name = match revisions > 1 && *explicit {
+            true => format!("{repo}:{revision}"),
+            false => repo.to_string(),
+        };
+        if names.contains(&name) {
+            anyhow::bail!("Model `{name}` is given more than once");
+        }
+        names.push(name);
+    }
+
+    Ok(names)
+}
 
 /// Represents the state of the server.
 #[derive(Clone)]
 pub struct ServerState {
-    pub model_map: EmbeddingModelMap,
+    pub model_map: ModelMap,
+    /// Model for routes where the model is optional: the configured default model, or else the
+    /// first of the served models
+    pub default_model: Option<String>,
+    /// Aliases of the served models
+    pub aliases: Aliases,
+    /// Errors of the models that failed to load, by name
+    failed: Arc<RwLock<HashMap<String, String>>>,
+    /// Loads the models that are added while the server runs
+    pub loader: ModelLoader,
+    /// Device the models are loaded on, unless they fell back to the CPU
+    pub device: Device,
+    /// Responses by idempotency key, if enabled
+    pub idempotency: Option<IdempotencyStore>,
+    /// Shadow model requests are mirrored to, if configured
+    pub shadow: Option<Arc<Shadow>>,
+    /// Canary revision of one of the models, if configured
+    pub canary: Option<Arc<Canary>>,
+    /// Recorder of a sample of the requests, if configured
+    pub recorder: Option<Arc<Recorder>>,
+    /// Hooks that run on the embeddings routes
+    pub hooks: Hooks,
+    /// Token quotas by API key, if configured
+    pub quotas: Option<Arc<Quotas>>,
+    /// Store of the batch jobs, if enabled
+    pub jobs: Option<Arc<Jobs>>,
+    /// Limits on the inputs of requests
+    pub request_limits: RequestLimits,
 }
 
 impl ServerState {
-    pub fn new(model_repos: Vec<String>, device: &Device) -> Result<Self> {
+    pub fn new(
+        model_repos: Vec<String>,
+        loader: ModelLoader,
+        max_loaded_models: Option<NonZeroUsize>,
+        lazy: bool,
+    ) -> Result<Self> {
         if model_repos.is_empty() {
             return Err(anyhow::anyhow!("No models provided"));
         }
 
-        let map = model_repos
-            .into_iter()
-            .filter_map(|model_repo| {
-                let (name, _) = parse_repo_string(&model_repo).ok()?;
-                let handler = EmbeddingsHandler::from_repo_string(&model_repo, device).ok()?;
-                let executor = DedicatedExecutor::new(handler).ok()?;
-                let client = EmbeddingsClient::new(&executor);
+        let names = model_names(&model_repos)?;
 
-                Some((name.to_string(), (client, Arc::new(executor))))
-            })
-            .collect::<EmbeddingModelMap>();
+        // Validate all preprocessing pipelines up front, so configuration errors aren't
+        // silently skipped like models that fail to load
+        for model_repo in &model_repos {
+            let (repo, _) = parse_repo_string(model_repo)?;
+            loader.preprocess_config.preprocessor(repo)?;
+        }
+
+        let map = ModelMap::new(max_loaded_models);
+        let mut failed = HashMap::new();
+        let mut default_model = None;
+        for (model_repo, name) in model_repos.into_iter().zip(names) {
+            // Models beyond the capacity, or all models if loading lazily, are loaded on their
+            // first request
+            let model = match !lazy && map.has_room() {
+                true => loader.load(&model_repo).map(Some),
+                false => Ok(None),
+            };
+            match model {
+                Ok(model) => {
+                    if default_model.is_none() {
+                        default_model = Some(name.clone());
+                    }
+                    map.insert(name, model_repo, model);
+                }
+                Err(e) => {
+                    tracing::error!("Failed to load model {model_repo}: {e}");
+                    failed.insert(name, e.to_string());
+                }
+            }
+        }
+
+        Ok(Self {
+            model_map: map,
+            default_model,
+            aliases: Aliases::default(),
+            failed: Arc::new(RwLock::new(failed)),
+            device: loader.device.device.clone(),
+            loader,
+            idempotency: None,
+            shadow: None,
+            canary: None,
+            recorder: None,
+            hooks: Hooks::default(),
+            quotas: None,
+            jobs: None,
+            request_limits: RequestLimits::default(),
+        })
+    }
+
+    /// Replay responses to retried requests with the same idempotency key from `idempotency`.
+    pub fn with_idempotency(mut self, idempotency: Option<IdempotencyStore>) -> Self {
+        self.idempotency = idempotency;
+        self
+    }
+
+    /// Mirror requests to a shadow model.
+    pub fn with_shadow(mut self, shadow: Option<Shadow>) -> Self {
+        self.shadow = shadow.map(Arc::new);
+        self
+    }
+
+    /// Route a share of the requests for a model to a canary revision.
+    pub fn with_canary(mut self, canary: Option<Canary>) -> Self {
+        self.canary = canary.map(Arc::new);
+        self
+    }
+
+    /// Record a sample of the requests to disk.
+    pub fn with_recorder(mut self, recorder: Option<Recorder>) -> Self {
+        self.recorder = recorder.map(Arc::new);
+        self
+    }
+
+    /// Run `hooks` on the requests to and responses of the embeddings routes.
+    pub fn with_hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Limit the tokens embedded per API key.
+    pub fn with_quotas(mut self, quotas: Option<Quotas>) -> Self {
+        self.quotas = quotas.map(Arc::new);
+        self
+    }
+
+    /// Process batch jobs stored in `jobs`.
+    pub fn with_jobs(mut self, jobs: Option<Jobs>) -> Self {
+        self.jobs = jobs.map(Arc::new);
+        self
+    }
+
+    /// Serve requests for aliases and unknown models with the served models of `aliases`.
+    pub fn with_aliases(mut self, aliases: Aliases) -> Result<Self> {
+        let mut names = self.model_map.names();
+        names.extend(self.failed().into_keys());
+        aliases.validate(&names)?;
+
+        if let Some(model) = aliases.default_model() {
+            self.default_model = Some(model.to_string());
+        }
+        self.aliases = aliases;
+        Ok(self)
+    }
+
+    /// Reject requests whose inputs exceed `request_limits`.
+    pub fn with_request_limits(mut self, request_limits: RequestLimits) -> Self {
+        self.request_limits = request_limits;
+        self
+    }
+
+    /// Check the token quota of a request, returning the quota to charge its tokens to, if any.
+    pub fn quota(&self, headers: &HeaderMap) -> Result<Option<Charge>, ServerError> {
+        match &self.quotas {
+            Some(quotas) => quotas.acquire(headers).map_err(ServerError::QuotaExceeded),
+            None => Ok(None),
+        }
+    }
+
+    /// The served model to serve a request for `model` with, see [`Aliases::resolve`].
+    pub fn resolve<'a>(&'a self, model: &'a str) -> &'a str {
+        self.aliases.resolve(model, self.model_map.contains(model))
+    }
+
+    /// The model of a request, or the default model if the request doesn't name one.
+    pub fn model_or_default(&self, model: Option<String>) -> Result<String, ServerError> {
+        model
+            .or_else(|| self.default_model.clone())
+            .ok_or(ServerError::ModelNotFound)
+    }
+
+    /// Embed a request with its model, through the hooks and the token quota of the API key in
+    /// `headers`. Used by the routes that don't support idempotency keys.
+    pub async fn embed(
+        &self,
+        route: &'static str,
+        headers: &HeaderMap,
+        mut request: EmbeddingsRequest,
+    ) -> Result<EmbeddingsResponse, ServerError> {
+        let model = request.model.clone();
+        let (client, revision) = self.client(&model).await?;
+        let quota = self.quota(headers)?;
+        self.request_limits.check(&client, &request.input).await?;
+
+        let context = RequestContext {
+            route,
+            headers,
+            model: &model,
+            revision: revision.unwrap_or(client.revision()),
+        };
+        self.hooks.pre_tokenize(&context, &mut request)?;
+        let mut response = client.generate_embedding(request).await?;
+        response.revision = revision.map(str::to_string);
+        self.hooks.post_embedding(&context, &mut response)?;
+
+        if let Some(quota) = &quota {
+            quota.charge(response.total_tokens());
+        }
+
+        Ok(response)
+    }
+
+    /// Check a batch job before it is queued: its model must be served and its inputs within the
+    /// token limit. As the job runs after the request, its tokens are charged to the quota of the
+    /// API key in `headers` when it is submitted.
+    pub async fn admit_job(
+        &self,
+        headers: &HeaderMap,
+        request: &CreateJobRequest,
+    ) -> Result<(), ServerError> {
+        if !self.model_map.contains(self.resolve(&request.model)) {
+            return Err(ServerError::ModelNotFound);
+        }
+        let quota = self.quota(headers)?;
+        if quota.is_none() && self.request_limits.max_input_tokens.is_none() {
+            return Ok(());
+        }
+
+        let (client, _) = self.client(&request.model).await?;
+        let tokens = client.count_tokens(request.input.clone()).await?;
+        self.request_limits.check_tokens(&tokens)?;
+        if let Some(quota) = &quota {
+            quota.charge(tokens.iter().sum::<usize>() as u64);
+        }
+
+        Ok(())
+    }
+
+    /// Errors of the models that failed to load, by name.
+    pub fn failed(&self) -> HashMap<String, String> {
+        self.failed
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Load a model repository while the server runs, and serve it under `name`.
+    pub async fn load_model(&self, model_repo: String, name: String) -> Result<(), ServerError> {
+        if self.model_map.contains(&name) {
+            return Err(ServerError::ModelConflict(name));
+        }
+
+        // Loading reads (and possibly downloads) the weights, so it doesn't block the runtime
+        let loader = self.loader.clone();
+        let repo = model_repo.clone();
+        let model = tokio::task::spawn_blocking(move || loader.load(&repo))
+            .await
+            .map_err(anyhow::Error::from)??;
+
+        // Another request may have loaded a model under the same name in the meantime
+        if !self.model_map.insert(name.clone(), model_repo, Some(model)) {
+            return Err(ServerError::ModelConflict(name));
+        }
+        self.evict();
+        self.failed
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&name);
+        tracing::info!("Loaded model {name}");
+
+        Ok(())
+    }
+
+    /// Stop serving a model, or forget that it failed to load.
+    pub fn unload_model(&self, name: &str) -> Result<(), ServerError> {
+        let failed = self
+            .failed
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(name)
+            .is_some();
+        if !self.model_map.remove(name) && !failed {
+            return Err(ServerError::ModelNotFound);
+        }
+        tracing::info!("Unloaded model {name}");
+
+        Ok(())
+    }
+
+    /// Unload the least recently used models beyond the capacity of the model map.
+    fn evict(&self) {
+        for name in self.model_map.evict() {
+            tracing::info!("Unloaded model {name} to make room for other models");
+        }
+    }
+
+    /// Get the client of a served model, loading it if it isn't loaded yet or was unloaded to
+    /// make room for other models.
+    pub async fn resident(&self, model: &str) -> Result<EmbeddingsClient, ServerError> {
+        let model = self.resolve(model);
+        if let Some(client) = self.model_map.get(model) {
+            return Ok(client);
+        }
+        let (model_repo, loading) = self
+            .model_map
+            .unloaded(model)
+            .ok_or(ServerError::ModelNotFound)?;
+
+        // Requests that arrive while the model loads wait for it, instead of loading it again
+        let _loading = loading.lock().await;
+        if let Some(client) = self.model_map.get(model) {
+            return Ok(client);
+        }
+
+        tracing::info!("Loading model {model}");
+        let loader = self.loader.clone();
+        let loaded = tokio::task::spawn_blocking(move || loader.load(&model_repo))
+            .await
+            .map_err(anyhow::Error::from)??;
+        let client = loaded.0.clone();
+        if !self.model_map.set_loaded(model, loaded) {
+            // Unloaded while it was being loaded
+            return Err(ServerError::ModelNotFound);
+        }
+        self.evict();
+
+        Ok(client)
+    }
+
+    /// Like [`Self::client`], but unless `wait` is set, a model that isn't loaded is loaded in the
+    /// background and [`ServerError::ModelLoading`] is returned instead of waiting for it.
+    pub async fn client_or_load(
+        &self,
+        model: &str,
+        wait: bool,
+    ) -> Result<(EmbeddingsClient, Option<&str>), ServerError> {
+        let model = self.resolve(model);
+        if !wait && self.model_map.unloaded(model).is_some() {
+            if !self.model_map.is_loading(model) {
+                let state = self.clone();
+                let model = model.to_string();
+                tokio::spawn(async move {
+                    if let Err(err) = state.resident(&model).await {
+                        tracing::error!("Failed to load model {model}: {err}");
+                    }
+                });
+            }
+            return Err(ServerError::ModelLoading(model.to_string()));
+        }
+
+        self.client(model).await
+    }
+
+    /// Get the client to serve a request for `model` with, along with the revision it serves
+    /// if the model has a canary revision.
+    pub async fn client(
+        &self,
+        model: &str,
+    ) -> Result<(EmbeddingsClient, Option<&str>), ServerError> {
+        let model = self.resolve(model);
+        let stable = self.resident(model).await?;
+
+        match self.canary.as_ref().and_then(|canary| canary.route(model)) {
+            Some(Route::Canary(client, revision)) => Ok((client.clone(), Some(revision))),
+            Some(Route::Stable(revision)) => Ok((stable, Some(revision))),
+            None => Ok((stable, None)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_model_names() -> Result<()> {
+        let repos = |repos: &[&str]| repos.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            model_names(&repos(&["org/a", "org/b:v2"]))?,
+            vec!["org/a", "org/b"]
+        );
+        // Explicit revisions of the same repository get their own names
+        assert_eq!(
+            model_names(&repos(&["org/a", "org/a:v2", "org/b"]))?,
+            vec!["org/a", "org/a:v2", "org/b"]
+        );
+        assert_eq!(
+            model_names(&repos(&["org/a:v1", "org/a:v2"]))?,
+            vec!["org/a:v1", "org/a:v2"]
+        );
+        assert!(model_names(&repos(&["org/a:v2", "org/a:v2"])).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_least_recently_used() {
+        let loaded = vec![("a", 4), ("b", 1), ("c", 7), ("d", 2)];
 
-        Ok(Self { model_map: map })
+        assert_eq!(least_recently_used(loaded.clone(), 2), vec!["b", "d"]);
+        assert_eq!(least_recently_used(loaded.clone(), 3), vec!["b"]);
+        assert!(least_recently_used(loaded, 4).is_empty());
     }
 }
@@ -0,0 +1,64 @@
+//! Helpers to serve small models with random weights in tests.
+
+use candle_core::{DType, Device};
+use candle_nn::{VarBuilder, VarMap};
+use candle_transformers::models::bert::{BertModel, Config as BertConfig};
+use glowrs::SentenceTransformer;
+use std::fs;
+use std::path::Path;
+
+use crate::server::infer::embed::{EmbeddingsClient, EmbeddingsHandler};
+use crate::server::infer::limits::QueueLimits;
+use crate::server::infer::DedicatedExecutor;
+use crate::server::preprocess::Preprocessor;
+
+/// Fixture of the `glowrs` crate the tiny models take their tokenizer and configuration from
+const BERT_FIXTURE_PATH: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../glowrs/tests/fixtures/all-MiniLM-L6-v2"
+);
+const TINY_HIDDEN_SIZE: usize = 8;
+
+/// Create a tiny BERT model repository in `dir`, with randomly initialized weights and the
+/// tokenizer and pooling configuration of the `all-MiniLM-L6-v2` fixture.
+pub fn create_tiny_bert_repo(dir: &Path) -> anyhow::Result<()> {
+    let fixture = Path::new(BERT_FIXTURE_PATH);
+
+    let mut config: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(fixture.join("config.json"))?)?;
+    config["hidden_size"] = TINY_HIDDEN_SIZE.into();
+    config["intermediate_size"] = (2 * TINY_HIDDEN_SIZE).into();
+    config["num_attention_heads"] = 2.into();
+    config["num_hidden_layers"] = 1.into();
+    fs::write(dir.join("config.json"), serde_json::to_string(&config)?)?;
+
+    fs::copy(fixture.join("tokenizer.json"), dir.join("tokenizer.json"))?;
+    fs::create_dir_all(dir.join("1_Pooling"))?;
+    fs::copy(
+        fixture.join("1_Pooling/config.json"),
+        dir.join("1_Pooling/config.json"),
+    )?;
+
+    let bert_config: BertConfig = serde_json::from_value(config)?;
+    let var_map = VarMap::new();
+    let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+    let _ = BertModel::load(vb, &bert_config)?;
+    var_map.save(dir.join("model.safetensors"))?;
+
+    Ok(())
+}
+
+/// Serve the tiny BERT model of [`create_tiny_bert_repo`] in `dir`. The executors have to be
+/// kept alive for as long as the client is used.
+pub fn tiny_embeddings_client(
+    dir: &Path,
+    preprocessor: Option<Preprocessor>,
+) -> anyhow::Result<(EmbeddingsClient, Vec<DedicatedExecutor<EmbeddingsHandler>>)> {
+    create_tiny_bert_repo(dir)?;
+    let sentence_transformer = SentenceTransformer::builder()
+        .with_model_folder(dir)
+        .build()?;
+    let handler = EmbeddingsHandler::new(sentence_transformer).with_preprocessor(preprocessor);
+
+    EmbeddingsClient::spawn(handler, QueueLimits::default())
+}
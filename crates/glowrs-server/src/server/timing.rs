@@ -0,0 +1,110 @@
+//! Server-Timing breakdown of requests
+//!
+//! The time a request spends waiting for the model (`queue`), tokenizing its inputs
+//! (`tokenize`), running the model (`forward`) and serializing the response (`serialize`) is
+//! returned in a `Server-Timing` header, so clients can tell network, queue and compute latency
+//! apart. The stages are also traced as spans.
+
+use axum::http::HeaderValue;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+use crate::server::format::ResponseFormat;
+use crate::server::ServerError;
+
+pub const SERVER_TIMING_HEADER: &str = "server-timing";
+
+/// Durations of the stages of a request. Stages that didn't run, or aren't measured separately,
+/// are `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Timings {
+    pub queue: Option<Duration>,
+    pub tokenize: Option<Duration>,
+    pub forward: Option<Duration>,
+    pub serialize: Option<Duration>,
+}
+
+impl Timings {
+    /// The longest duration of each stage, for requests that run on several models concurrently.
+    pub fn max(self, other: Timings) -> Timings {
+        let max = |a: Option<Duration>, b: Option<Duration>| a.max(b);
+        Timings {
+            queue: max(self.queue, other.queue),
+            tokenize: max(self.tokenize, other.tokenize),
+            forward: max(self.forward, other.forward),
+            serialize: max(self.serialize, other.serialize),
+        }
+    }
+
+    /// The timings as a `Server-Timing` header value, in milliseconds.
+    pub fn header_value(&self) -> Option<HeaderValue> {
+        let metrics: Vec<String> = [
+            ("queue", self.queue),
+            ("tokenize", self.tokenize),
+            ("forward", self.forward),
+            ("serialize", self.serialize),
+        ]
+        .into_iter()
+        .filter_map(|(name, duration)| {
+            duration.map(|duration| format!("{name};dur={:.3}", duration.as_secs_f64() * 1e3))
+        })
+        .collect();
+
+        if metrics.is_empty() {
+            return None;
+        }
+        HeaderValue::from_str(&metrics.join(", ")).ok()
+    }
+}
+
+/// Run a stage of a request in a span, storing its duration in `duration`.
+pub fn timed<T>(stage: &'static str, duration: &mut Option<Duration>, f: impl FnOnce() -> T) -> T {
+    let _span = tracing::debug_span!("stage", stage).entered();
+    let start = Instant::now();
+    let output = f();
+    *duration = Some(start.elapsed());
+    output
+}
+
+/// Serialize a response in a format, timing it in `timings`.
+pub fn serialize<T: Serialize>(
+    response: &T,
+    format: ResponseFormat,
+    timings: &mut Timings,
+) -> Result<Bytes, ServerError> {
+    timed("serialize", &mut timings.serialize, || {
+        format.serialize(response)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_header_value() {
+        assert_eq!(Timings::default().header_value(), None);
+
+        let timings = Timings {
+            queue: Some(Duration::from_micros(1500)),
+            tokenize: None,
+            forward: Some(Duration::from_millis(12)),
+            serialize: None,
+        };
+        assert_eq!(
+            timings.header_value().unwrap(),
+            "queue;dur=1.500, forward;dur=12.000"
+        );
+
+        let other = Timings {
+            queue: Some(Duration::from_millis(1)),
+            tokenize: Some(Duration::from_millis(2)),
+            ..Default::default()
+        };
+        let max = timings.max(other);
+        assert_eq!(max.queue, Some(Duration::from_micros(1500)));
+        assert_eq!(max.tokenize, Some(Duration::from_millis(2)));
+        assert_eq!(max.forward, Some(Duration::from_millis(12)));
+    }
+}
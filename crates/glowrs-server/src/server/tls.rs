@@ -0,0 +1,223 @@
+//! TLS termination
+//!
+//! With a certificate and key, the server serves HTTPS itself, without a reverse proxy in front.
+//! Adding a CA bundle requires clients to authenticate with a certificate signed by one of its
+//! CAs (mutual TLS), for zero-trust deployments where API keys are not sufficient. The gRPC
+//! interface is served with the same certificate and client CAs.
+
+use anyhow::{Context, Result};
+use clap::Args;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+
+#[derive(Debug, Args)]
+pub struct TlsArgs {
+    /// PEM file with the certificate chain of the server. Serves HTTPS, with `--tls-key`
+    #[clap(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the server certificate
+    #[clap(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM bundle of the CAs that sign client certificates. Clients without a valid certificate
+    /// signed by one of them are rejected during the TLS handshake (mutual TLS)
+    #[clap(long, requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+}
+
+/// The TLS configuration of the server, or `None` to serve plain HTTP.
+pub fn server_config(args: &TlsArgs) -> Result<Option<ServerConfig>> {
+    let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) else {
+        return Ok(None);
+    };
+
+    let certs = read_certs(cert)?;
+    let key = read_key(key)?;
+
+    let builder = match &args.tls_client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for ca in read_certs(client_ca)? {
+                roots.add(ca).with_context(|| {
+                    format!("Invalid CA certificate in {}", client_ca.display())
+                })?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .context("Failed to set up client certificate verification")?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .context("Invalid server certificate or key")?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(Some(config))
+}
+
+/// The TLS configuration of the gRPC interface, or `None` to serve it in plaintext.
+pub fn grpc_config(args: &TlsArgs) -> Result<Option<ServerTlsConfig>> {
+    let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) else {
+        return Ok(None);
+    };
+    // Fail on the same files as the HTTP configuration, with the same errors
+    server_config(args)?;
+
+    let read = |path: &PathBuf| {
+        fs::read(path).with_context(|| format!("Failed to open {}", path.display()))
+    };
+    let config = ServerTlsConfig::new().identity(Identity::from_pem(read(cert)?, read(key)?));
+    Ok(Some(match &args.tls_client_ca {
+        Some(client_ca) => config.client_ca_root(Certificate::from_pem(read(client_ca)?)),
+        None => config,
+    }))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to read certificates from {}", path.display()))?;
+
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to read the private key from {}", path.display()))?
+        .with_context(|| format!("No private key found in {}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rcgen::{
+        BasicConstraints, CertificateParams, CertifiedKey, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use rustls::{ClientConfig, ClientConnection, ServerConnection};
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn ca() -> CertifiedKey {
+        let key_pair = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let cert = params.self_signed(&key_pair).unwrap();
+        CertifiedKey { cert, key_pair }
+    }
+
+    fn signed_by(ca: &CertifiedKey, name: &str, usage: ExtendedKeyUsagePurpose) -> CertifiedKey {
+        let key_pair = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+        params.extended_key_usages = vec![usage];
+        let cert = params.signed_by(&key_pair, &ca.cert, &ca.key_pair).unwrap();
+        CertifiedKey { cert, key_pair }
+    }
+
+    /// Run a TLS handshake in memory.
+    fn handshake(
+        mut client: ClientConnection,
+        mut server: ServerConnection,
+    ) -> Result<(), rustls::Error> {
+        let mut buffer = Vec::new();
+        for _ in 0..10 {
+            if !client.is_handshaking() && !server.is_handshaking() {
+                return Ok(());
+            }
+            buffer.clear();
+            client.write_tls(&mut buffer).unwrap();
+            server.read_tls(&mut buffer.as_slice()).unwrap();
+            server.process_new_packets()?;
+
+            buffer.clear();
+            server.write_tls(&mut buffer).unwrap();
+            client.read_tls(&mut buffer.as_slice()).unwrap();
+            client.process_new_packets()?;
+        }
+        panic!("Handshake did not complete");
+    }
+
+    #[test]
+    fn test_client_certificates() -> Result<()> {
+        let dir = tempdir()?;
+        let server_ca = ca();
+        let client_ca = ca();
+        let server = signed_by(&server_ca, "localhost", ExtendedKeyUsagePurpose::ServerAuth);
+
+        fs::write(dir.path().join("server.pem"), server.cert.pem())?;
+        fs::write(
+            dir.path().join("server.key"),
+            server.key_pair.serialize_pem(),
+        )?;
+        fs::write(dir.path().join("client-ca.pem"), client_ca.cert.pem())?;
+
+        let mut args = TlsArgs {
+            tls_cert: Some(dir.path().join("server.pem")),
+            tls_key: Some(dir.path().join("server.key")),
+            tls_client_ca: None,
+        };
+
+        let connect = |server_config: &ServerConfig, client_cert: Option<&CertifiedKey>| {
+            let mut roots = RootCertStore::empty();
+            roots.add(server_ca.cert.der().clone()).unwrap();
+            let builder = ClientConfig::builder().with_root_certificates(roots);
+            let client_config = match client_cert {
+                Some(cert) => builder
+                    .with_client_auth_cert(
+                        vec![cert.cert.der().clone()],
+                        PrivateKeyDer::try_from(cert.key_pair.serialize_der()).unwrap(),
+                    )
+                    .unwrap(),
+                None => builder.with_no_client_auth(),
+            };
+
+            let client =
+                ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap())
+                    .unwrap();
+            let server = ServerConnection::new(Arc::new(server_config.clone())).unwrap();
+            handshake(client, server)
+        };
+
+        // Server TLS only
+        let config = server_config(&args)?.expect("TLS should be configured");
+        assert!(connect(&config, None).is_ok());
+
+        // Mutual TLS
+        args.tls_client_ca = Some(dir.path().join("client-ca.pem"));
+        let config = server_config(&args)?.expect("TLS should be configured");
+
+        let client = signed_by(&client_ca, "client", ExtendedKeyUsagePurpose::ClientAuth);
+        assert!(connect(&config, Some(&client)).is_ok());
+        assert!(connect(&config, None).is_err());
+
+        let untrusted = signed_by(&ca(), "client", ExtendedKeyUsagePurpose::ClientAuth);
+        assert!(connect(&config, Some(&untrusted)).is_err());
+        assert!(grpc_config(&args)?.is_some());
+
+        // Plain HTTP without a certificate
+        let plain = TlsArgs {
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+        };
+        assert!(server_config(&plain)?.is_none());
+        assert!(grpc_config(&plain)?.is_none());
+
+        Ok(())
+    }
+}
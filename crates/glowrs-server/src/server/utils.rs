@@ -51,3 +51,45 @@ pub fn port_in_range(s: &str) -> result::Result<u16, String> {
         ))
     }
 }
+
+/// Deterministic sampling at `rate`: the `n`th event is sampled if it pushes the expected number
+/// of sampled events over an integer.
+pub fn sampled(n: u64, rate: f64) -> bool {
+    let n = n as f64;
+    ((n + 1.) * rate).floor() > (n * rate).floor()
+}
+
+/// Resident memory of the process in bytes, where the platform reports it (Linux).
+pub fn resident_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kilobytes = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+
+    Some(kilobytes * 1024)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sampled() {
+        let count = |rate| (0..100).filter(|&n| sampled(n, rate)).count();
+
+        assert_eq!(count(1.), 100);
+        assert_eq!(count(0.25), 25);
+        assert_eq!(count(0.), 0);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_resident_memory_bytes() {
+        assert!(resident_memory_bytes().is_some_and(|bytes| bytes > 0));
+    }
+}
@@ -0,0 +1,350 @@
+//! Directory watch mode
+//!
+//! Polls a directory for new or changed text files, embeds them through the executor queue of
+//! one of the loaded models and keeps an embeddings index file up to date. Files are embedded in
+//! batches of at most `--max-inputs` inputs, and files that can't be read as UTF-8 text are
+//! skipped until they change.
+
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+
+use crate::server::data_models::{EmbeddingsRequest, Sentences};
+use crate::server::state::model_names;
+use crate::server::state::ServerState;
+
+const WATCHED_EXTENSIONS: [&str; 2] = ["txt", "md"];
+
+/// Number of files embedded per request, unless `--max-inputs` is lower
+const BATCH_SIZE: usize = 64;
+
+#[derive(Debug, Args)]
+pub struct WatchArgs {
+    /// Directory to watch for new or changed text files (`.txt`, `.md`) to embed
+    #[clap(long)]
+    pub watch_dir: Option<PathBuf>,
+
+    /// Index file the embeddings of the watched files are written to
+    #[clap(long, default_value = "embeddings.json")]
+    pub watch_output: PathBuf,
+
+    /// Model used to embed the watched files. Defaults to the first given model
+    #[clap(long)]
+    pub watch_model: Option<String>,
+
+    /// Interval in seconds between directory scans
+    #[clap(long, default_value = "5")]
+    pub watch_interval: u64,
+}
+
+/// Entry in the embeddings index.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct IndexEntry {
+    /// Last modification time of the file, in milliseconds since the Unix epoch
+    pub modified: u128,
+    pub embedding: Vec<f32>,
+}
+
+/// Embeddings index, keyed by file path relative to the watched directory.
+type Index = BTreeMap<String, IndexEntry>;
+
+/// Modification times of the files that couldn't be read, by file path relative to the watched
+/// directory.
+type Skipped = HashMap<String, u128>;
+
+/// Start the directory watcher in the background, if a directory to watch is configured.
+pub fn spawn_watcher(
+    args: &WatchArgs,
+    model_repos: &[String],
+    state: Arc<ServerState>,
+) -> Result<Option<JoinHandle<()>>> {
+    let Some(watch_dir) = args.watch_dir.clone() else {
+        return Ok(None);
+    };
+
+    let model = match &args.watch_model {
+        Some(model) => model.clone(),
+        None => model_names(model_repos)?
+            .into_iter()
+            .next()
+            .context("No models provided")?,
+    };
+
+    if !state.model_map.contains(state.resolve(&model)) {
+        anyhow::bail!("Model `{model}` to embed watched files with is not loaded");
+    }
+
+    let watcher = Watcher {
+        dir: watch_dir,
+        output: args.watch_output.clone(),
+        model,
+        state,
+    };
+    let interval = Duration::from_secs(args.watch_interval.max(1));
+
+    tracing::info!(
+        "Watching {} for text files, writing embeddings to {}",
+        watcher.dir.display(),
+        watcher.output.display()
+    );
+
+    Ok(Some(tokio::spawn(watcher.run(interval))))
+}
+
+struct Watcher {
+    dir: PathBuf,
+    output: PathBuf,
+    model: String,
+    /// Looked up on every update rather than held, so the model can be unloaded in between
+    state: Arc<ServerState>,
+}
+
+impl Watcher {
+    async fn run(self, interval: Duration) {
+        let mut index = load_index(&self.output).unwrap_or_else(|err| {
+            tracing::warn!("Starting with an empty index: {err}");
+            Index::new()
+        });
+
+        let mut skipped = Skipped::new();
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+
+            if let Err(err) = self.update(&mut index, &mut skipped).await {
+                tracing::error!("Failed to update embeddings index: {err}");
+            }
+        }
+    }
+
+    /// Embed all new or changed files and write the index if anything changed. Files that
+    /// can't be read are logged and added to `skipped`, so they are only retried once they
+    /// change.
+    async fn update(&self, index: &mut Index, skipped: &mut Skipped) -> Result<()> {
+        let files = scan_dir(&self.dir)?;
+        let removed = prune_index(index, &files);
+        skipped.retain(|name, modified| files.get(name) == Some(modified));
+        let changed: Vec<String> = changed_files(index, &files)
+            .into_iter()
+            .filter(|name| !skipped.contains_key(name))
+            .collect();
+
+        if changed.is_empty() && removed == 0 {
+            return Ok(());
+        }
+
+        if !changed.is_empty() {
+            tracing::debug!("Embedding {} new or changed files", changed.len());
+
+            let (names, texts, unreadable) = read_files(&self.dir, changed).await;
+            skipped.extend(unreadable.into_iter().map(|name| {
+                let modified = files[&name];
+                (name, modified)
+            }));
+
+            let batch_size = batch_size(self.state.request_limits.max_inputs);
+            for (names, texts) in names.chunks(batch_size).zip(texts.chunks(batch_size)) {
+                let request =
+                    EmbeddingsRequest::new(Sentences::from(texts.to_vec()), self.model.clone());
+                let client = self.state.resident(&self.model).await?;
+                let response = client.generate_embedding(request).await?;
+
+                for (name, data) in names.iter().zip(response.data) {
+                    index.insert(
+                        name.clone(),
+                        IndexEntry {
+                            modified: files[name],
+                            embedding: data.embedding.to_vec(),
+                        },
+                    );
+                }
+            }
+        }
+
+        write_index(&self.output, index)
+    }
+}
+
+/// Read the files of `names` in `dir` as text. Returns the names and texts of the files that
+/// were read, and the names of those that couldn't be, e.g. as they aren't UTF-8.
+async fn read_files(dir: &Path, names: Vec<String>) -> (Vec<String>, Vec<String>, Vec<String>) {
+    let (mut read, mut texts, mut unreadable) = (Vec::new(), Vec::new(), Vec::new());
+    for name in names {
+        match tokio::fs::read_to_string(dir.join(&name)).await {
+            Ok(text) => {
+                read.push(name);
+                texts.push(text);
+            }
+            Err(err) => {
+                tracing::warn!("Skipping watched file {name} until it changes: {err}");
+                unreadable.push(name);
+            }
+        }
+    }
+    (read, texts, unreadable)
+}
+
+/// Number of files to embed per request, within the maximum number of inputs of a request.
+fn batch_size(max_inputs: Option<usize>) -> usize {
+    max_inputs
+        .map_or(BATCH_SIZE, |max_inputs| max_inputs.min(BATCH_SIZE))
+        .max(1)
+}
+
+/// List the watched files in `dir` with their modification times.
+fn scan_dir(dir: &Path) -> Result<HashMap<String, u128>> {
+    let mut files = HashMap::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        let is_watched = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| WATCHED_EXTENSIONS.contains(&ext));
+
+        if !path.is_file() || !is_watched {
+            continue;
+        }
+
+        let modified = path
+            .metadata()?
+            .modified()?
+            .duration_since(UNIX_EPOCH)?
+            .as_millis();
+
+        if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+            files.insert(name.to_string(), modified);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Files that are not in the index yet, or have been modified since they were embedded.
+fn changed_files(index: &Index, files: &HashMap<String, u128>) -> Vec<String> {
+    let mut changed: Vec<String> = files
+        .iter()
+        .filter(|(name, modified)| index.get(*name).map(|entry| entry.modified) != Some(**modified))
+        .map(|(name, _)| name.clone())
+        .collect();
+    changed.sort();
+    changed
+}
+
+/// Remove entries of files that no longer exist. Returns the number of removed entries.
+fn prune_index(index: &mut Index, files: &HashMap<String, u128>) -> usize {
+    let before = index.len();
+    index.retain(|name, _| files.contains_key(name));
+    before - index.len()
+}
+
+fn load_index(path: &Path) -> Result<Index> {
+    if !path.exists() {
+        return Ok(Index::new());
+    }
+    Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+}
+
+/// Write the index to a temporary file first, so readers never observe a partial index.
+fn write_index(path: &Path, index: &Index) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, serde_json::to_vec(index)?)?;
+    std::fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_scan_and_diff() -> Result<()> {
+        let dir = tempdir()?;
+        fs::write(dir.path().join("a.txt"), "a")?;
+        fs::write(dir.path().join("b.md"), "b")?;
+        fs::write(dir.path().join("c.bin"), "c")?;
+
+        let files = scan_dir(dir.path())?;
+        assert_eq!(files.len(), 2);
+
+        let mut index = Index::new();
+        assert_eq!(changed_files(&index, &files), vec!["a.txt", "b.md"]);
+
+        index.insert(
+            "a.txt".to_string(),
+            IndexEntry {
+                modified: files["a.txt"],
+                embedding: vec![1.0],
+            },
+        );
+        index.insert(
+            "removed.txt".to_string(),
+            IndexEntry {
+                modified: 0,
+                embedding: vec![1.0],
+            },
+        );
+
+        assert_eq!(prune_index(&mut index, &files), 1);
+        assert_eq!(changed_files(&index, &files), vec!["b.md"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_files() -> Result<()> {
+        let dir = tempdir()?;
+        fs::write(dir.path().join("a.txt"), "a")?;
+        fs::write(dir.path().join("b.txt"), [0xff, 0xfe])?;
+
+        let names = vec![
+            "a.txt".to_string(),
+            "b.txt".to_string(),
+            "c.txt".to_string(),
+        ];
+        let (read, texts, unreadable) = read_files(dir.path(), names).await;
+        assert_eq!(read, vec!["a.txt"]);
+        assert_eq!(texts, vec!["a"]);
+        assert_eq!(unreadable, vec!["b.txt", "c.txt"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_size() {
+        assert_eq!(batch_size(None), BATCH_SIZE);
+        assert_eq!(batch_size(Some(8)), 8);
+        assert_eq!(batch_size(Some(1000)), BATCH_SIZE);
+        assert_eq!(batch_size(Some(0)), 1);
+    }
+
+    #[test]
+    fn test_index_roundtrip() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("embeddings.json");
+
+        assert!(load_index(&path)?.is_empty());
+
+        let mut index = Index::new();
+        index.insert(
+            "a.txt".to_string(),
+            IndexEntry {
+                modified: 1,
+                embedding: vec![0.5, 0.25],
+            },
+        );
+        write_index(&path, &index)?;
+
+        assert_eq!(load_index(&path)?, index);
+
+        Ok(())
+    }
+}
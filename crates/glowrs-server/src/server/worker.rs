@@ -0,0 +1,640 @@
+//! Model worker processes
+//!
+//! With `--worker-processes`, every model runs in a child process of its own: the server binary
+//! started again with `--worker-model` and the settings needed to serve the model, but not e.g.
+//! the API keys of `--api-key-quota`. The router and the workers
+//! exchange length-prefixed JSON frames over the standard input and output of the workers. A
+//! crash of a worker, e.g. a CUDA fault, only fails the requests it was processing, with
+//! `503 Service Unavailable`; the router then restarts the worker and the model becomes available
+//! again once it is loaded. Unloading the model stops its worker.
+
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+use tokenizers::Tokenizer;
+use tokio::sync::oneshot;
+
+use crate::server::data_models::{EmbeddingsMetadata, EmbeddingsRequest, EmbeddingsResponse};
+use crate::server::device::{DeviceArgs, DeviceConfig};
+use crate::server::image::{ImageArgs, ImageConfig};
+use crate::server::infer::embed::{EmbeddingsClient, EmbeddingsHandler};
+use crate::server::infer::limits::{QueueArgs, QueueConfig, QueueError, QueueLimits};
+use crate::server::preprocess::{PreprocessArgs, PreprocessConfig};
+use crate::server::timing::Timings;
+use crate::server::{RouterArgs, ServerError};
+
+/// Frames larger than this are rejected as corrupt
+const MAX_FRAME_SIZE: usize = 1 << 30;
+/// Time to wait before restarting a worker that exited
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+/// Interval at which the supervisor checks whether the worker exited while there are no requests
+const EXIT_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Args)]
+pub struct WorkerArgs {
+    /// Run every model in a worker process of its own, so a crash of one model doesn't take down
+    /// the whole server
+    #[clap(long)]
+    pub worker_processes: bool,
+
+    /// Serve a single model over the standard input and output, as a worker process
+    #[clap(long, hide = true)]
+    pub worker_model: Option<String>,
+}
+
+/// Why a request to a worker failed.
+#[derive(Debug, Error, Serialize, Deserialize)]
+pub enum WorkerError {
+    #[error("{0}")]
+    InvalidRequest(String),
+
+    #[error("Too many requests queued for the model")]
+    QueueFull,
+
+    #[error("Request timed out")]
+    Timeout,
+
+    #[error("{0}")]
+    Internal(String),
+
+    #[error("Worker process of the model exited")]
+    Exited,
+}
+
+impl From<anyhow::Error> for WorkerError {
+    fn from(err: anyhow::Error) -> Self {
+        match ServerError::from(err) {
+            ServerError::InvalidRequest(msg) => Self::InvalidRequest(msg),
+            ServerError::TooManyRequestsError => Self::QueueFull,
+            ServerError::ServiceUnavailable { .. } => Self::Timeout,
+            err => Self::Internal(err.to_string()),
+        }
+    }
+}
+
+impl WorkerError {
+    /// Convert back to the error the worker failed with, so it gets the same response.
+    fn into_anyhow(self) -> anyhow::Error {
+        match self {
+            Self::InvalidRequest(msg) => ServerError::InvalidRequest(msg).into(),
+            Self::QueueFull => QueueError::Full.into(),
+            Self::Timeout => QueueError::Timeout.into(),
+            err => err.into(),
+        }
+    }
+}
+
+/// First frame a worker sends, once its model is loaded.
+#[derive(Debug, Serialize, Deserialize)]
+struct Ready {
+    metadata: EmbeddingsMetadata,
+    multimodal: bool,
+    /// Serialized tokenizer of the model, so the router can tokenize without the worker
+    tokenizer: String,
+    /// How long the model took to warm up, if it was warmed up
+    warmup: Option<Duration>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WorkerRequest {
+    id: u64,
+    request: EmbeddingsRequest,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WorkerResponse {
+    id: u64,
+    /// The response, with its timings, which aren't part of its serialized form
+    result: Result<(EmbeddingsResponse, Timings), WorkerError>,
+}
+
+fn write_frame<W: Write, T: Serialize>(writer: &mut W, value: &T) -> Result<()> {
+    let frame = serde_json::to_vec(value)?;
+    writer.write_all(&(frame.len() as u32).to_be_bytes())?;
+    writer.write_all(&frame)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read a frame, or `None` if the stream ended.
+fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut length = [0u8; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME_SIZE {
+        anyhow::bail!("Frame of {length} bytes exceeds the maximum size");
+    }
+    let mut frame = vec![0u8; length];
+    reader.read_exact(&mut frame)?;
+    Ok(Some(serde_json::from_slice(&frame)?))
+}
+
+type ResponseSender = oneshot::Sender<Result<EmbeddingsResponse>>;
+type Pending = Arc<Mutex<HashMap<u64, ResponseSender>>>;
+
+/// A running worker process. Dropping it kills the process.
+struct Connection {
+    child: Child,
+    stdin: BufWriter<ChildStdin>,
+    pending: Pending,
+    /// Set once the worker stopped responding
+    closed: Arc<AtomicBool>,
+}
+
+impl Connection {
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        // Fail the requests the worker didn't respond to
+        for (_, tx) in self
+            .pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .drain()
+        {
+            let _ = tx.send(Err(WorkerError::Exited.into()));
+        }
+    }
+}
+
+/// Starts worker processes for the models.
+#[derive(Debug, Clone)]
+pub struct Workers {
+    program: PathBuf,
+    /// Arguments the workers are started with, see [`worker_args`]
+    args: Vec<OsString>,
+}
+
+impl Workers {
+    pub fn from_args(args: &RouterArgs) -> Result<Option<Self>> {
+        if !args.worker_args.worker_processes {
+            return Ok(None);
+        }
+
+        let program =
+            std::env::current_exe().context("Failed to find the server binary for workers")?;
+        Ok(Some(Self {
+            program,
+            args: worker_args(args),
+        }))
+    }
+
+    /// Start a worker process for a model and wait for it to load the model.
+    pub fn spawn(
+        &self,
+        model_repo: &str,
+        device: &DeviceConfig,
+        limits: QueueLimits,
+    ) -> Result<EmbeddingsClient> {
+        let (connection, ready) = self.start(model_repo)?;
+
+        let (tx, rx) = mpsc::channel();
+        let worker = WorkerProcess {
+            model_repo: model_repo.to_string(),
+            requests: tx,
+        };
+
+        let workers = self.clone();
+        let model = model_repo.to_string();
+        thread::Builder::new()
+            .name(format!("worker-{model}"))
+            .spawn(move || workers.supervise(&model, connection, rx))?;
+
+        Ok(EmbeddingsClient::from_worker(
+            Arc::new(worker),
+            ready.metadata,
+            ready.multimodal,
+            device.device.clone(),
+            Tokenizer::from_str(&ready.tokenizer).map_err(anyhow::Error::msg)?,
+            limits,
+        )
+        .with_warmup(ready.warmup))
+    }
+
+    /// Start a worker process, and pass its responses on once it loaded its model.
+    fn start(&self, model_repo: &str) -> Result<(Connection, Ready)> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .arg(flag("model-repo", model_repo))
+            .arg(flag("worker-model", model_repo))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to start worker process for {model_repo}"))?;
+
+        let stdin = BufWriter::new(child.stdin.take().context("Worker has no stdin")?);
+        let mut stdout = BufReader::new(child.stdout.take().context("Worker has no stdout")?);
+        let connection = Connection {
+            child,
+            stdin,
+            pending: Pending::default(),
+            closed: Arc::default(),
+        };
+
+        let ready: Ready = read_frame(&mut stdout)?
+            .with_context(|| format!("Worker process for {model_repo} exited while loading"))?;
+        tracing::info!("Worker process for {model_repo} is ready");
+        listen(stdout, &connection);
+
+        Ok((connection, ready))
+    }
+
+    /// Forward requests to the worker, restarting it when it exits. Returns once the
+    /// [`WorkerProcess`] is dropped, killing the worker.
+    fn supervise(
+        &self,
+        model_repo: &str,
+        connection: Connection,
+        requests: mpsc::Receiver<(EmbeddingsRequest, ResponseSender)>,
+    ) {
+        let next_id = AtomicU64::new(0);
+        let mut connection = Some(connection);
+
+        loop {
+            match requests.recv_timeout(EXIT_CHECK_INTERVAL) {
+                Ok((request, response_tx)) => match connection.as_mut() {
+                    Some(conn) if !conn.is_closed() => {
+                        let id = next_id.fetch_add(1, Ordering::Relaxed);
+                        conn.pending
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .insert(id, response_tx);
+                        if let Err(e) = write_frame(&mut conn.stdin, &WorkerRequest { id, request })
+                        {
+                            tracing::error!(
+                                "Failed to send request to worker of {model_repo}: {e}"
+                            );
+                            conn.closed.store(true, Ordering::Relaxed);
+                        }
+                    }
+                    _ => {
+                        let _ = response_tx.send(Err(WorkerError::Exited.into()));
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) => {}
+                // The model was unloaded
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            if connection.as_ref().is_none_or(Connection::is_closed) {
+                // Dropping the connection kills the worker and fails its pending requests
+                if connection.take().is_some() {
+                    tracing::error!("Worker process for {model_repo} exited, restarting");
+                }
+
+                // Requests that arrive while the worker restarts wait for it
+                thread::sleep(RESTART_DELAY);
+                match self.start(model_repo) {
+                    Ok((conn, _)) => connection = Some(conn),
+                    Err(e) => tracing::error!("Failed to restart worker of {model_repo}: {e}"),
+                }
+            }
+        }
+        tracing::info!("Stopping worker process for {model_repo}");
+    }
+}
+
+/// Arguments the workers are started with: only the settings they use to load and serve their
+/// model, so that secrets such as the API keys of `--api-key-quota` aren't passed on to them.
+/// The arguments are destructured, so new settings have to be considered here.
+fn worker_args(args: &RouterArgs) -> Vec<OsString> {
+    let DeviceArgs {
+        device,
+        no_device_fallback,
+    } = &args.device_args;
+    let QueueArgs {
+        max_concurrent_requests,
+        max_queue_size,
+        request_timeout_ms,
+        replicas,
+        model_limits,
+    } = &args.queue_args;
+    let PreprocessArgs {
+        preprocess_config,
+        query_prefix,
+        passage_prefix,
+    } = &args.preprocess_args;
+    let ImageArgs {
+        allow_image_urls,
+        max_image_pixels,
+    } = &args.image_args;
+
+    let mut worker_args = Vec::new();
+    if let Some(device) = device {
+        worker_args.push(flag("device", device.to_string()));
+    }
+    worker_args.push(flag(
+        "max-concurrent-requests",
+        max_concurrent_requests.to_string(),
+    ));
+    worker_args.push(flag("max-queue-size", max_queue_size.to_string()));
+    if let Some(request_timeout_ms) = request_timeout_ms {
+        worker_args.push(flag("request-timeout-ms", request_timeout_ms.to_string()));
+    }
+    worker_args.push(flag("replicas", replicas.to_string()));
+    worker_args.extend(
+        model_limits
+            .iter()
+            .map(|limits| flag("model-limits", limits)),
+    );
+    if let Some(preprocess_config) = preprocess_config {
+        worker_args.push(flag("preprocess-config", preprocess_config));
+    }
+    worker_args.extend(
+        query_prefix
+            .iter()
+            .map(|prefix| flag("query-prefix", prefix)),
+    );
+    worker_args.extend(
+        passage_prefix
+            .iter()
+            .map(|prefix| flag("passage-prefix", prefix)),
+    );
+    worker_args.push(flag("max-image-pixels", max_image_pixels.to_string()));
+
+    for (name, set) in [
+        ("no-device-fallback", *no_device_fallback),
+        ("allow-image-urls", *allow_image_urls),
+        ("warmup", args.warmup),
+    ] {
+        if set {
+            worker_args.push(OsString::from(format!("--{name}")));
+        }
+    }
+
+    worker_args
+}
+
+/// A `--<name>=<value>` argument, which works for values starting with `-` as well.
+fn flag(name: &str, value: impl AsRef<OsStr>) -> OsString {
+    let mut arg = OsString::from(format!("--{name}="));
+    arg.push(value);
+    arg
+}
+
+/// Pass the responses of a worker on to the requests, until the worker exits.
+fn listen(mut stdout: BufReader<ChildStdout>, connection: &Connection) {
+    let pending = connection.pending.clone();
+    let closed = connection.closed.clone();
+    thread::spawn(move || {
+        loop {
+            match read_frame::<_, WorkerResponse>(&mut stdout) {
+                Ok(Some(WorkerResponse { id, result })) => {
+                    let response_tx = pending
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .remove(&id);
+                    if let Some(response_tx) = response_tx {
+                        let result = result
+                            .map(|(mut response, timings)| {
+                                response.timings = timings;
+                                response
+                            })
+                            .map_err(WorkerError::into_anyhow);
+                        let _ = response_tx.send(result);
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    tracing::error!("Invalid response from worker: {e}");
+                    break;
+                }
+            }
+        }
+        closed.store(true, Ordering::Relaxed);
+    });
+}
+
+/// Handle to the worker process of a model. The worker is stopped when the handle is dropped,
+/// e.g. when the model is unloaded.
+pub struct WorkerProcess {
+    model_repo: String,
+    /// The only sender of the supervisor, so that it stops once the handle is dropped
+    requests: mpsc::Sender<(EmbeddingsRequest, ResponseSender)>,
+}
+
+impl WorkerProcess {
+    pub async fn embed(&self, request: EmbeddingsRequest) -> Result<EmbeddingsResponse> {
+        let (tx, rx) = oneshot::channel();
+        self.requests
+            .send((request, tx))
+            .map_err(|_| WorkerError::Exited)?;
+
+        rx.await.map_err(|_| {
+            anyhow::anyhow!(
+                "Failed to receive response from worker of {}",
+                self.model_repo
+            )
+        })?
+    }
+}
+
+/// Serve a single model over the standard input and output, as a worker process of the router.
+pub async fn serve(args: &RouterArgs, model_repo: &str) -> Result<()> {
+    let device = DeviceConfig::from_args(&args.device_args)?;
+    let preprocess_config = PreprocessConfig::from_args(&args.preprocess_args)?;
+    let queue_config = QueueConfig::from_args(&args.queue_args)?;
+
+    let (repo, _) = glowrs::core::utils::parse_repo_string(model_repo)?;
+    let handler = EmbeddingsHandler::from_repo_string(model_repo, &device)?
+        .with_preprocessor(preprocess_config.preprocessor(repo)?)
+        .with_images(ImageConfig::from_args(&args.image_args));
+    let multimodal = handler.is_multimodal();
+    let warmup = match args.warmup {
+        true => Some(handler.warm_up(model_repo)?),
+        false => None,
+    };
+    let tokenizer = handler
+        .tokenizer()
+        .to_string(false)
+        .map_err(anyhow::Error::msg)?;
+    let (client, _executors) = EmbeddingsClient::spawn(handler, queue_config.limits(repo))?;
+
+    let stdout = Arc::new(Mutex::new(BufWriter::new(std::io::stdout())));
+    write_frame(
+        &mut *stdout.lock().unwrap_or_else(|e| e.into_inner()),
+        &Ready {
+            metadata: client.metadata(None),
+            multimodal,
+            tokenizer,
+            warmup,
+        },
+    )?;
+
+    let runtime = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        let mut stdin = BufReader::new(std::io::stdin());
+        // The router closes the input when it shuts down
+        while let Some(WorkerRequest { id, request }) = read_frame(&mut stdin)? {
+            let client = client.clone();
+            let stdout = stdout.clone();
+            runtime.spawn(async move {
+                let result = client
+                    .generate_embedding(request)
+                    .await
+                    .map(|response| {
+                        let timings = response.timings;
+                        (response, timings)
+                    })
+                    .map_err(WorkerError::from);
+
+                let mut stdout = stdout.lock().unwrap_or_else(|e| e.into_inner());
+                if let Err(e) = write_frame(&mut *stdout, &WorkerResponse { id, result }) {
+                    tracing::error!("Failed to send response to the router: {e}");
+                }
+            });
+        }
+        anyhow::Ok(())
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::data_models::Sentences;
+    use clap::Parser;
+    use glowrs::core::device::DeviceSpec;
+    use std::path::Path;
+
+    #[test]
+    fn test_frames() -> Result<()> {
+        let mut buffer = Vec::new();
+        let request = EmbeddingsRequest::new(
+            Sentences::from(vec!["hello".to_string(), "world".to_string()]),
+            "model".to_string(),
+        );
+        write_frame(&mut buffer, &WorkerRequest { id: 7, request })?;
+        write_frame(
+            &mut buffer,
+            &WorkerResponse {
+                id: 7,
+                result: Err(WorkerError::InvalidRequest("Too long".to_string())),
+            },
+        )?;
+
+        let mut reader = buffer.as_slice();
+        let request: WorkerRequest = read_frame(&mut reader)?.unwrap();
+        assert_eq!(request.id, 7);
+        assert_eq!(request.request.input.into_texts()?, vec!["hello", "world"]);
+
+        let response: WorkerResponse = read_frame(&mut reader)?.unwrap();
+        let err = response.result.unwrap_err().into_anyhow();
+        assert!(matches!(
+            ServerError::from(err),
+            ServerError::InvalidRequest(msg) if msg == "Too long"
+        ));
+
+        // The stream ended
+        assert!(read_frame::<_, WorkerRequest>(&mut reader)?.is_none());
+
+        // Truncated frames are an error
+        let mut reader = &buffer[..6];
+        assert!(read_frame::<_, WorkerRequest>(&mut reader).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_worker_args() -> Result<()> {
+        #[derive(Parser)]
+        struct Cli {
+            #[clap(flatten)]
+            args: RouterArgs,
+        }
+
+        let cli = Cli::try_parse_from([
+            "glowrs-server",
+            "--model-repo",
+            "model",
+            "--worker-processes",
+            "--api-key-quota",
+            "secret-key=minute:1000",
+            "--device",
+            "cpu",
+            "--query-prefix",
+            "model=-query: ",
+            "--replicas",
+            "2",
+            "--warmup",
+        ])?;
+        let worker_args = worker_args(&cli.args);
+        assert!(worker_args
+            .iter()
+            .all(|arg| !arg.to_string_lossy().contains("secret")));
+
+        // The workers get the settings they need
+        let worker = Cli::try_parse_from(
+            [OsString::from("glowrs-server")]
+                .into_iter()
+                .chain(worker_args)
+                .chain([flag("model-repo", "model"), flag("worker-model", "model")]),
+        )?;
+        let args = worker.args;
+        assert_eq!(args.worker_args.worker_model.as_deref(), Some("model"));
+        assert!(!args.worker_args.worker_processes);
+        assert!(args.quota_args.api_key_quota.is_empty());
+        assert_eq!(args.device_args.device, Some(DeviceSpec::Cpu));
+        assert_eq!(args.preprocess_args.query_prefix, vec!["model=-query: "]);
+        assert_eq!(args.queue_args.replicas, 2);
+        assert!(args.warmup);
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_stop_worker() -> Result<()> {
+        let mut child = Command::new("sleep")
+            .arg("60")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let pid = child.id();
+        let connection = Connection {
+            stdin: BufWriter::new(child.stdin.take().unwrap()),
+            child,
+            pending: Pending::default(),
+            closed: Arc::default(),
+        };
+        let workers = Workers {
+            program: PathBuf::from("sleep"),
+            args: Vec::new(),
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let supervisor = thread::spawn(move || workers.supervise("model", connection, rx));
+        assert!(Path::new(&format!("/proc/{pid}")).exists());
+
+        // Dropping the handle stops the supervisor, which kills and reaps the worker
+        drop(tx);
+        supervisor.join().unwrap();
+        assert!(!Path::new(&format!("/proc/{pid}")).exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,60 @@
+use clap::Args;
+use std::fs;
+use std::path::PathBuf;
+
+use glowrs::quantize::{calibrate, CalibrationMethod};
+
+use crate::model::ModelArgs;
+
+#[derive(Debug, Args)]
+pub struct CalibrateArgs {
+    #[clap(flatten)]
+    pub model_args: ModelArgs,
+
+    /// Text file with one sentence per line, representative of the data that will be embedded
+    #[clap(long)]
+    pub corpus: PathBuf,
+
+    /// Output file for the calibration ranges
+    #[clap(short, long, default_value = "calibration.json")]
+    pub output: PathBuf,
+
+    /// Clip this percentage of values at both ends of each dimension, instead of using the
+    /// minimum and maximum
+    #[clap(long)]
+    pub percentile: Option<f64>,
+
+    /// Calibrate on normalized embeddings
+    #[clap(long)]
+    pub normalize: bool,
+
+    #[clap(short, long, default_value = "32")]
+    pub batch_size: usize,
+}
+
+pub fn run(args: CalibrateArgs) -> glowrs::Result<()> {
+    let corpus = fs::read_to_string(&args.corpus)?;
+    let corpus: Vec<&str> = corpus.lines().filter(|l| !l.trim().is_empty()).collect();
+
+    let model = args.model_args.load()?;
+
+    let method = match args.percentile {
+        Some(p) => CalibrationMethod::Percentile {
+            lower: p,
+            upper: 100. - p,
+        },
+        None => CalibrationMethod::MinMax,
+    };
+
+    let calibration = calibrate(&model, &corpus, method, args.normalize, args.batch_size)?;
+    calibration.save(&args.output)?;
+
+    println!(
+        "Wrote {}-dimensional calibration over {} sentences to {}",
+        calibration.dim(),
+        corpus.len(),
+        args.output.display()
+    );
+
+    Ok(())
+}
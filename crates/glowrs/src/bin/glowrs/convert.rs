@@ -0,0 +1,40 @@
+use candle_core::DType;
+use clap::{Args, ValueEnum};
+use std::path::PathBuf;
+
+use glowrs::core::convert::convert_pth_to_safetensors;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum WeightsDType {
+    F32,
+    F16,
+    Bf16,
+}
+
+impl From<WeightsDType> for DType {
+    fn from(dtype: WeightsDType) -> Self {
+        match dtype {
+            WeightsDType::F32 => DType::F32,
+            WeightsDType::F16 => DType::F16,
+            WeightsDType::Bf16 => DType::BF16,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ConvertArgs {
+    /// Model folder containing `pytorch_model.bin`, or a path to a `.bin`/`.pth` file
+    pub path: PathBuf,
+
+    /// Cast floating point weights to this data type
+    #[clap(long)]
+    pub dtype: Option<WeightsDType>,
+}
+
+pub fn run(args: ConvertArgs) -> glowrs::Result<()> {
+    let dst = convert_pth_to_safetensors(&args.path, args.dtype.map(DType::from))?;
+
+    println!("{}", dst.display());
+
+    Ok(())
+}
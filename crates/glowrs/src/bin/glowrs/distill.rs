@@ -0,0 +1,61 @@
+use clap::Args;
+use std::fs;
+use std::path::PathBuf;
+
+use glowrs::distill::{distill, DistillationConfig, DistillationOutput};
+
+use crate::model::load_model;
+
+#[derive(Debug, Args)]
+pub struct DistillArgs {
+    /// Teacher model repository or folder, whose embeddings should be mimicked
+    #[clap(long)]
+    pub teacher: String,
+
+    /// Student model repository or folder, used at inference time
+    #[clap(long)]
+    pub student: String,
+
+    /// Text file with one sentence per line to fit the projection on
+    #[clap(long)]
+    pub corpus: PathBuf,
+
+    /// Output folder for the projection, in the `sentence-transformers` `Dense` module layout
+    #[clap(short, long, default_value = "2_Dense")]
+    pub output: PathBuf,
+
+    #[clap(long, default_value = "100")]
+    pub epochs: usize,
+
+    #[clap(long, default_value = "0.01")]
+    pub learning_rate: f64,
+
+    #[clap(short, long, default_value = "32")]
+    pub batch_size: usize,
+}
+
+pub fn run(args: DistillArgs) -> glowrs::Result<()> {
+    let corpus = fs::read_to_string(&args.corpus)?;
+    let corpus: Vec<&str> = corpus.lines().filter(|l| !l.trim().is_empty()).collect();
+
+    let teacher = load_model(&args.teacher, None)?;
+    let student = load_model(&args.student, None)?;
+
+    let config = DistillationConfig {
+        epochs: args.epochs,
+        learning_rate: args.learning_rate,
+        batch_size: args.batch_size,
+    };
+
+    let DistillationOutput { projection, loss } = distill(&teacher, &student, &corpus, &config)?;
+    projection.save(&args.output)?;
+
+    println!(
+        "Wrote {}x{} projection to {} (MSE: {loss:.6})",
+        projection.in_features(),
+        projection.out_features(),
+        args.output.display()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use clap::Args;
+use serde::Serialize;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc;
+use std::thread;
+
+use glowrs::SentenceTransformer;
+
+use crate::model::ModelArgs;
+
+#[derive(Debug, Args)]
+pub struct EmbedArgs {
+    #[clap(flatten)]
+    pub model: ModelArgs,
+
+    /// Sentences to embed
+    #[clap(required_unless_present = "stdin", conflicts_with = "stdin")]
+    pub sentences: Vec<String>,
+
+    /// Read one sentence per line from stdin and write one JSON embedding per line to stdout
+    #[clap(long)]
+    pub stdin: bool,
+
+    /// Maximum number of lines to embed in a single forward pass when reading from stdin
+    #[clap(short, long, default_value = "32")]
+    pub batch_size: usize,
+
+    /// L2-normalize the embeddings
+    #[clap(long)]
+    pub normalize: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct EmbeddingLine {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+pub fn run(args: EmbedArgs) -> glowrs::Result<()> {
+    let encoder = args.model.load()?;
+    let mut stdout = io::stdout().lock();
+
+    if !args.stdin {
+        return write_embeddings(&encoder, args.sentences, 0, args.normalize, &mut stdout);
+    }
+
+    // Read lines on a separate thread, so that batches can be formed from whatever input is
+    // available without blocking on a full batch.
+    let (tx, rx) = mpsc::channel::<String>();
+    let reader = thread::spawn(move || -> io::Result<()> {
+        for line in io::stdin().lock().lines() {
+            if tx.send(line?).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    });
+
+    let batch_size = args.batch_size.max(1);
+    let mut index = 0;
+
+    // Block until at least one line is available, then drain up to `batch_size` lines.
+    while let Ok(first) = rx.recv() {
+        let mut batch = vec![first];
+        while batch.len() < batch_size {
+            match rx.try_recv() {
+                Ok(line) => batch.push(line),
+                Err(_) => break,
+            }
+        }
+
+        let n = batch.len();
+        write_embeddings(&encoder, batch, index, args.normalize, &mut stdout)?;
+        index += n;
+    }
+
+    reader
+        .join()
+        .map_err(|_| glowrs::Error::InvalidArgument("Failed to read from stdin"))??;
+
+    Ok(())
+}
+
+fn write_embeddings<W: Write>(
+    encoder: &SentenceTransformer,
+    sentences: Vec<String>,
+    offset: usize,
+    normalize: bool,
+    writer: &mut W,
+) -> glowrs::Result<()> {
+    let embeddings: Vec<Vec<f32>> = encoder.encode_batch(sentences, normalize)?.to_vec2()?;
+
+    for (i, embedding) in embeddings.into_iter().enumerate() {
+        let line = EmbeddingLine {
+            index: offset + i,
+            embedding,
+        };
+        serde_json::to_writer(&mut *writer, &line)?;
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
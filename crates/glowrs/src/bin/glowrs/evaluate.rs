@@ -0,0 +1,85 @@
+use clap::{Args, Subcommand};
+use std::path::PathBuf;
+
+use glowrs::evaluate::{
+    evaluate_retrieval, evaluate_sts, load_retrieval_dataset, load_sts_dataset,
+};
+
+use crate::model::ModelArgs;
+
+#[derive(Debug, Args)]
+pub struct EvaluateArgs {
+    #[clap(subcommand)]
+    pub command: EvaluateCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum EvaluateCommand {
+    /// Correlate cosine similarities with gold scores of an STS dataset
+    Sts(StsArgs),
+    /// Rank a corpus for each query and score the rankings against relevance judgements
+    Retrieval(RetrievalArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct StsArgs {
+    #[clap(flatten)]
+    pub model: ModelArgs,
+
+    /// STS dataset (`.jsonl` or tab-separated with `sentence1`, `sentence2` and `score` columns)
+    #[clap(short, long)]
+    pub dataset: PathBuf,
+
+    /// Number of sentence pairs to encode at once
+    #[clap(short, long, default_value = "32")]
+    pub batch_size: usize,
+}
+
+#[derive(Debug, Args)]
+pub struct RetrievalArgs {
+    #[clap(flatten)]
+    pub model: ModelArgs,
+
+    /// Queries as JSON lines with `_id` and `text` keys (BEIR format)
+    #[clap(long)]
+    pub queries: PathBuf,
+
+    /// Corpus as JSON lines with `_id`, `text` and optional `title` keys (BEIR format)
+    #[clap(long)]
+    pub corpus: PathBuf,
+
+    /// Tab-separated relevance judgements with `query-id`, `corpus-id` and `score` columns
+    #[clap(long)]
+    pub qrels: PathBuf,
+
+    /// Number of top ranked documents to score
+    #[clap(short, default_value = "10")]
+    pub k: usize,
+
+    /// Number of texts to encode at once
+    #[clap(short, long, default_value = "32")]
+    pub batch_size: usize,
+}
+
+pub fn run(args: EvaluateArgs) -> glowrs::Result<()> {
+    match args.command {
+        EvaluateCommand::Sts(args) => {
+            let examples = load_sts_dataset(&args.dataset)?;
+            let encoder = args.model.load()?;
+
+            let evaluation = evaluate_sts(&encoder, &examples, args.batch_size)?;
+
+            println!("{}", serde_json::to_string_pretty(&evaluation)?);
+        }
+        EvaluateCommand::Retrieval(args) => {
+            let dataset = load_retrieval_dataset(&args.queries, &args.corpus, &args.qrels)?;
+            let encoder = args.model.load()?;
+
+            let evaluation = evaluate_retrieval(&encoder, &dataset, args.k, args.batch_size)?;
+
+            println!("{}", serde_json::to_string_pretty(&evaluation)?);
+        }
+    }
+
+    Ok(())
+}
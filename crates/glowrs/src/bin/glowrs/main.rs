@@ -0,0 +1,64 @@
+use clap::{Parser, Subcommand};
+use std::process::ExitCode;
+use tracing_subscriber::prelude::*;
+
+mod calibrate;
+mod convert;
+mod distill;
+mod embed;
+mod evaluate;
+mod model;
+mod project;
+mod replay;
+
+#[derive(Debug, Parser)]
+#[clap(name = "glowrs", about = "SentenceTransformers for candle-rs")]
+pub struct App {
+    #[clap(subcommand)]
+    pub command: Command,
+
+    #[clap(short, long, default_value = "info")]
+    pub log_level: String,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Compute per-dimension ranges over a corpus for embedding quantization
+    Calibrate(calibrate::CalibrateArgs),
+    /// Convert PyTorch weights (`pytorch_model.bin`) to `model.safetensors`
+    Convert(convert::ConvertArgs),
+    /// Fit a projection that maps student embeddings onto teacher embeddings
+    Distill(distill::DistillArgs),
+    /// Embed sentences given as arguments or read line by line from stdin
+    Embed(embed::EmbedArgs),
+    /// Evaluate a model on a benchmark dataset
+    Evaluate(evaluate::EvaluateArgs),
+    /// Reduce the embeddings of a corpus to 2-D points for visualization
+    Project(project::ProjectArgs),
+    /// Replay requests recorded by the server, against a server or through the library
+    Replay(replay::ReplayArgs),
+}
+
+fn main() -> glowrs::Result<ExitCode> {
+    let app = App::parse();
+
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| format!("glowrs={}", app.log_level).into()),
+        )
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .init();
+
+    match app.command {
+        Command::Calibrate(args) => calibrate::run(args)?,
+        Command::Convert(args) => convert::run(args)?,
+        Command::Distill(args) => distill::run(args)?,
+        Command::Embed(args) => embed::run(args)?,
+        Command::Evaluate(args) => evaluate::run(args)?,
+        Command::Project(args) => project::run(args)?,
+        Command::Replay(args) => replay::run(args)?,
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,45 @@
+use clap::Args;
+use std::path::Path;
+
+use glowrs::core::device::DEVICE;
+use glowrs::{PoolingStrategy, SentenceTransformer};
+
+#[derive(Debug, Args)]
+pub struct ModelArgs {
+    /// Hugging Face model repository (`<repo>[:<revision>]`) or path to a local model folder
+    #[clap(short, long)]
+    pub model_repo: String,
+
+    /// Pooling strategy, inferred from the model repository if not given
+    #[clap(long)]
+    pub pooling: Option<PoolingStrategy>,
+}
+
+impl ModelArgs {
+    /// Load the [`SentenceTransformer`] described by the arguments.
+    pub fn load(&self) -> glowrs::Result<SentenceTransformer> {
+        load_model(&self.model_repo, self.pooling)
+    }
+}
+
+/// Load a [`SentenceTransformer`] from a Hugging Face repository or a local model folder.
+pub fn load_model(
+    model_repo: &str,
+    pooling: Option<PoolingStrategy>,
+) -> glowrs::Result<SentenceTransformer> {
+    let builder = SentenceTransformer::builder().with_device(DEVICE.clone());
+
+    let builder = match pooling {
+        Some(pooling) => builder.with_pooling_strategy(pooling),
+        None => builder,
+    };
+
+    let path = Path::new(model_repo);
+    let builder = if path.is_dir() {
+        builder.with_model_folder(path)
+    } else {
+        builder.with_model_repo(model_repo)?
+    };
+
+    builder.build()
+}
@@ -0,0 +1,95 @@
+use clap::{Args, ValueEnum};
+use std::fs;
+use std::path::PathBuf;
+
+use glowrs::visualize::{project_corpus, save_points, ProjectionMethod};
+
+use crate::model::ModelArgs;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Method {
+    Pca,
+    Neighbors,
+}
+
+impl From<Method> for ProjectionMethod {
+    fn from(method: Method) -> Self {
+        match method {
+            Method::Pca => ProjectionMethod::Pca,
+            Method::Neighbors => ProjectionMethod::Neighbors,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ProjectArgs {
+    #[clap(flatten)]
+    pub model_args: ModelArgs,
+
+    /// Text file with one sentence per line. Blank lines are skipped
+    #[clap(long)]
+    pub corpus: PathBuf,
+
+    /// Text file with a label for each line of the corpus, on the same line. The points are
+    /// labeled with the sentences if not given
+    #[clap(long)]
+    pub labels: Option<PathBuf>,
+
+    /// How to reduce the embeddings to two dimensions
+    #[clap(long, value_enum, default_value = "pca")]
+    pub method: Method,
+
+    /// Output file for the points, as CSV if it ends in `.csv` or JSON otherwise
+    #[clap(short, long, default_value = "points.json")]
+    pub output: PathBuf,
+
+    #[clap(short, long, default_value = "32")]
+    pub batch_size: usize,
+}
+
+pub fn run(args: ProjectArgs) -> glowrs::Result<()> {
+    let corpus = fs::read_to_string(&args.corpus)?;
+    let labels = args.labels.as_ref().map(fs::read_to_string).transpose()?;
+    let (corpus, labels) = non_blank_lines(&corpus, labels.as_deref())?;
+
+    let model = args.model_args.load()?;
+
+    let points = project_corpus(
+        &model,
+        &corpus,
+        labels.as_deref(),
+        args.method.into(),
+        args.batch_size,
+    )?;
+    save_points(&points, &args.output)?;
+
+    println!("Wrote {} points to {}", points.len(), args.output.display());
+
+    Ok(())
+}
+
+/// The non-blank lines of the corpus, and the labels on the same lines. Fails if there isn't a
+/// label for every line of the corpus.
+fn non_blank_lines<'a>(
+    corpus: &'a str,
+    labels: Option<&'a str>,
+) -> glowrs::Result<(Vec<&'a str>, Option<Vec<&'a str>>)> {
+    let lines: Vec<&str> = corpus.lines().collect();
+    let labels: Option<Vec<&str>> = labels.map(|labels| labels.lines().collect());
+    if labels
+        .as_ref()
+        .is_some_and(|labels| labels.len() != lines.len())
+    {
+        return Err(glowrs::Error::InvalidArgument(
+            "Number of lines in the labels does not match the corpus",
+        ));
+    }
+
+    let non_blank = |i: &usize| !lines[*i].trim().is_empty();
+    let indices: Vec<usize> = (0..lines.len()).filter(non_blank).collect();
+
+    Ok((
+        indices.iter().map(|&i| lines[i]).collect(),
+        labels.map(|labels| indices.iter().map(|&i| labels[i]).collect()),
+    ))
+}
@@ -0,0 +1,187 @@
+use clap::Args;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use glowrs::{PoolingStrategy, SentenceTransformer};
+
+use crate::model::load_model;
+
+#[derive(Debug, Args)]
+pub struct ReplayArgs {
+    /// JSON lines file with requests recorded by `glowrs-server --record-file`
+    pub file: PathBuf,
+
+    /// Base URL of a server to send the requests to, e.g. `http://localhost:3000`. The requests
+    /// are run directly through the library if not given
+    #[clap(long)]
+    pub url: Option<String>,
+
+    /// Model to run the requests with instead of the recorded models, when not replaying against
+    /// a server (`<repo>[:<revision>]` or a local model folder)
+    #[clap(short, long, conflicts_with = "url")]
+    pub model_repo: Option<String>,
+
+    /// Wait between requests as long as between the recorded requests, instead of sending them
+    /// back to back
+    #[clap(long)]
+    pub realtime: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct RecordedRequest {
+    timestamp_ms: u64,
+    endpoint: String,
+    latency_ms: f64,
+    request: Value,
+}
+
+/// Fields of the embedding requests that are needed to run them through the library.
+#[derive(Debug, Deserialize)]
+struct EmbeddingsRequest {
+    input: Input,
+    model: Option<String>,
+    #[serde(default)]
+    models: Vec<String>,
+    pooling: Option<PoolingStrategy>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Input {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl From<Input> for Vec<String> {
+    fn from(input: Input) -> Self {
+        match input {
+            Input::Single(s) => vec![s],
+            Input::Multiple(v) => v,
+        }
+    }
+}
+
+pub fn run(args: ReplayArgs) -> glowrs::Result<()> {
+    let records: Vec<RecordedRequest> = fs::read_to_string(&args.file)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+
+    let mut models: HashMap<String, SentenceTransformer> = HashMap::new();
+    let mut latencies = Vec::with_capacity(records.len());
+    let mut errors = 0;
+
+    let start = Instant::now();
+    let first_timestamp = records.first().map_or(0, |r| r.timestamp_ms);
+
+    for (i, record) in records.iter().enumerate() {
+        if args.realtime {
+            let offset = Duration::from_millis(record.timestamp_ms.saturating_sub(first_timestamp));
+            if let Some(wait) = offset.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+
+        let result = match &args.url {
+            Some(url) => send(url, record),
+            None => run_local(record, args.model_repo.as_deref(), &mut models),
+        };
+
+        match result {
+            Ok(latency) => latencies.push(latency),
+            Err(e) => {
+                tracing::warn!("Request {i} to {} failed: {e}", record.endpoint);
+                errors += 1;
+            }
+        }
+    }
+
+    let recorded: Vec<f64> = records.iter().map(|r| r.latency_ms).collect();
+    let mut replayed: Vec<f64> = latencies.iter().map(|l| l.as_secs_f64() * 1000.).collect();
+    replayed.sort_by(f64::total_cmp);
+
+    println!("Requests:              {}", records.len());
+    println!("Errors:                {errors}");
+    println!("Recorded mean latency: {:.2} ms", mean(&recorded));
+    println!("Replayed mean latency: {:.2} ms", mean(&replayed));
+    println!(
+        "Replayed p50 latency:  {:.2} ms",
+        percentile(&replayed, 0.5)
+    );
+    println!(
+        "Replayed p95 latency:  {:.2} ms",
+        percentile(&replayed, 0.95)
+    );
+
+    Ok(())
+}
+
+/// Send a request to a server, returning its latency.
+fn send(url: &str, record: &RecordedRequest) -> glowrs::Result<Duration> {
+    let url = format!("{}/v1/{}", url.trim_end_matches('/'), record.endpoint);
+
+    let start = Instant::now();
+    ureq::post(&url)
+        .set("Content-Type", "application/json")
+        .send_string(&record.request.to_string())
+        .map_err(|e| anyhow::anyhow!("{url}: {e}"))?;
+
+    Ok(start.elapsed())
+}
+
+/// Run a request through the library, returning its latency. Models are loaded on first use,
+/// which is not included in the latency.
+fn run_local(
+    record: &RecordedRequest,
+    model_override: Option<&str>,
+    models: &mut HashMap<String, SentenceTransformer>,
+) -> glowrs::Result<Duration> {
+    let request: EmbeddingsRequest = serde_json::from_value(record.request.clone())?;
+
+    let names: Vec<String> = match model_override {
+        Some(model) => vec![model.to_string()],
+        None => request.model.into_iter().chain(request.models).collect(),
+    };
+    let sentences: Vec<String> = request.input.into();
+
+    for name in &names {
+        if !models.contains_key(name) {
+            models.insert(name.clone(), load_model(name, None)?);
+        }
+    }
+
+    let start = Instant::now();
+    for name in &names {
+        let model = &models[name];
+
+        let sentences = sentences.clone();
+        match request.pooling {
+            Some(pooling) => model.encode_batch_with_pooling(sentences, false, pooling)?,
+            None => model.encode_batch_with_usage(sentences, false)?,
+        };
+    }
+
+    Ok(start.elapsed())
+}
+
+fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return 0.;
+    }
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// Nearest-rank percentile of sorted values.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.;
+    }
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
@@ -0,0 +1,280 @@
+//! In-memory LRU embedding cache
+//!
+//! Caches embedding vectors keyed by model, text and encoding options, so that repeated inputs
+//! don't have to go through the model again. The cache is thread-safe and can be shared between
+//! multiple models (e.g. in a server) through an `Arc`.
+
+use candle_core::DType;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokenizers::EncodeInput;
+
+use crate::core::embedder::EmbedOutput;
+use crate::{InputUsage, PoolingStrategy, Result, SentenceTransformer, Usage};
+
+/// Cache key of a single embedding.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub model: String,
+    pub text: String,
+    pub normalize: bool,
+    /// Pooling strategy, if it differs from the one the model was loaded with
+    pub pooling: Option<PoolingStrategy>,
+}
+
+impl CacheKey {
+    /// Create a key for `text`, as is: whitespace can change the tokenized input.
+    pub fn new(model: &str, text: &str, normalize: bool) -> Self {
+        Self {
+            model: model.to_string(),
+            text: text.to_string(),
+            normalize,
+            pooling: None,
+        }
+    }
+
+    pub fn with_pooling(self, pooling: Option<PoolingStrategy>) -> Self {
+        Self { pooling, ..self }
+    }
+}
+
+/// Cache hit and miss counters.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub len: usize,
+}
+
+/// Output of a cached encoding call.
+#[derive(Debug)]
+pub struct CachedEmbedOutput {
+    pub embeddings: Vec<Vec<f32>>,
+    /// Usage of the sentences that were not in the cache and had to be encoded
+    pub usage: Usage,
+    /// Usage of each input, including the inputs that were in the cache
+    pub inputs: Vec<InputUsage>,
+}
+
+/// Thread-safe LRU cache of embedding vectors.
+pub struct EmbeddingCache {
+    entries: Mutex<LruCache<CacheKey, Vec<f32>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<EmbeddingCache>();
+};
+
+impl EmbeddingCache {
+    /// Create a cache holding at most `capacity` embeddings.
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Vec<f32>> {
+        let embedding = self.lock().get(key).cloned();
+
+        match embedding {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+
+        embedding
+    }
+
+    pub fn insert(&self, key: CacheKey, embedding: Vec<f32>) {
+        self.lock().put(key, embedding);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            len: self.lock().len(),
+        }
+    }
+
+    /// Encode a batch of sentences, only running the model for sentences that are not cached.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The model to encode cache misses with.
+    /// * `model_id` - Identifier of the model, used as part of the cache key.
+    /// * `sentences` - The sentences to encode.
+    /// * `normalize` - Whether to normalize the embeddings.
+    /// * `pooling` - Pooling strategy to use instead of the one the model was loaded with.
+    pub fn encode_batch_with_usage<S: AsRef<str>>(
+        &self,
+        model: &SentenceTransformer,
+        model_id: &str,
+        sentences: &[S],
+        normalize: bool,
+        pooling: Option<PoolingStrategy>,
+    ) -> Result<CachedEmbedOutput> {
+        let keys: Vec<CacheKey> = sentences
+            .iter()
+            .map(|s| CacheKey::new(model_id, s.as_ref(), normalize).with_pooling(pooling))
+            .collect();
+
+        let mut embeddings: Vec<Option<Vec<f32>>> = keys.iter().map(|k| self.get(k)).collect();
+
+        let (missing, hits): (Vec<usize>, Vec<usize>) =
+            (0..embeddings.len()).partition(|&i| embeddings[i].is_none());
+
+        // Cached inputs are only tokenized, to report their token counts
+        let mut inputs = vec![InputUsage::default(); sentences.len()];
+        if !hits.is_empty() {
+            let encodings =
+                model.tokenize(hits.iter().map(|&i| sentences[i].as_ref()).collect())?;
+            for (&i, encoding) in hits.iter().zip(&encodings) {
+                inputs[i] = encoding.into();
+            }
+        }
+
+        let usage = if missing.is_empty() {
+            Usage::default()
+        } else {
+            tracing::trace!(
+                "embedding cache: {} hits, {} misses",
+                sentences.len() - missing.len(),
+                missing.len()
+            );
+
+            let batch: Vec<EncodeInput> = missing
+                .iter()
+                .map(|&i| sentences[i].as_ref().into())
+                .collect();
+
+            let EmbedOutput {
+                embeddings: computed,
+                usage,
+                inputs: computed_inputs,
+            } = match pooling {
+                Some(pooling) => model.encode_batch_with_pooling(batch, normalize, pooling)?,
+                None => model.encode_batch_with_usage(batch, normalize)?,
+            };
+
+            for ((&i, embedding), input) in missing
+                .iter()
+                // The cache holds `f32` values regardless of the output dtype of the model
+                .zip(computed.to_dtype(DType::F32)?.to_vec2::<f32>()?)
+                .zip(computed_inputs)
+            {
+                self.insert(keys[i].clone(), embedding.clone());
+                embeddings[i] = Some(embedding);
+                inputs[i] = input;
+            }
+
+            usage
+        };
+
+        Ok(CachedEmbedOutput {
+            embeddings: embeddings.into_iter().flatten().collect(),
+            usage,
+            inputs,
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LruCache<CacheKey, Vec<f32>>> {
+        // A poisoned lock only means another thread panicked while holding it; the cache
+        // contents are still valid.
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::test_utils::create_tiny_bert_repo;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_cache_eviction() {
+        let cache = EmbeddingCache::new(NonZeroUsize::new(2).unwrap());
+
+        cache.insert(CacheKey::new("m", "a", false), vec![1.0]);
+        cache.insert(CacheKey::new("m", "b", false), vec![2.0]);
+
+        // Touch `a`, so `b` is the least recently used entry
+        assert_eq!(cache.get(&CacheKey::new("m", "a", false)), Some(vec![1.0]));
+        cache.insert(CacheKey::new("m", "c", false), vec![3.0]);
+
+        assert_eq!(cache.get(&CacheKey::new("m", "b", false)), None);
+        assert_eq!(cache.get(&CacheKey::new("other", "a", false)), None);
+        assert_eq!(cache.get(&CacheKey::new("m", "a", true)), None);
+        assert_eq!(cache.get(&CacheKey::new("m", " a ", false)), None);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.len), (1, 4, 2));
+    }
+
+    #[test]
+    fn test_encode_batch_with_cache() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+
+        let cache = EmbeddingCache::new(NonZeroUsize::new(16).unwrap());
+
+        let first =
+            cache.encode_batch_with_usage(&model, "tiny", &["a cat", "a dog"], true, None)?;
+        assert_eq!(cache.stats().misses, 2);
+
+        let second = cache.encode_batch_with_usage(
+            &model,
+            "tiny",
+            &["a dog", "a bird", "a cat"],
+            true,
+            None,
+        )?;
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 3));
+
+        assert_eq!(second.embeddings.len(), 3);
+        assert_eq!(second.embeddings[0], first.embeddings[1]);
+        assert_eq!(second.embeddings[2], first.embeddings[0]);
+        assert_eq!(second.inputs[0], first.inputs[1]);
+        assert!(second.inputs.iter().all(|input| input.tokens > 0));
+
+        let expected = model.encode_batch(vec!["a bird"], true)?.to_vec2::<f32>()?;
+        for (a, b) in second.embeddings[1].iter().zip(&expected[0]) {
+            assert!((a - b).abs() < 1e-5);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_batch_with_cache_half_precision() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .with_output_dtype(DType::F16)
+            .build()?;
+
+        let cache = EmbeddingCache::new(NonZeroUsize::new(16).unwrap());
+        let output = cache.encode_batch_with_usage(&model, "tiny", &["a cat"], true, None)?;
+
+        let expected = model
+            .encode_batch(vec!["a cat"], true)?
+            .to_dtype(DType::F32)?
+            .to_vec2::<f32>()?;
+        assert_eq!(output.embeddings, expected);
+
+        Ok(())
+    }
+}
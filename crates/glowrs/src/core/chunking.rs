@@ -0,0 +1,94 @@
+//! Chunking of long inputs
+//!
+//! Inputs longer than the context of a model are truncated, so the end of a long document
+//! doesn't contribute to its embedding. [`SentenceTransformer::encode_long`] instead splits
+//! them into overlapping chunks that each fit the context, embeds the chunks, and combines their
+//! embeddings into one embedding per input.
+//!
+//! [`SentenceTransformer::encode_long`]: crate::SentenceTransformer::encode_long
+
+use candle_core::{DType, Tensor};
+use serde::{Deserialize, Serialize};
+use tokenizers::Encoding;
+
+use crate::{InputUsage, Result};
+
+/// How the embeddings of the chunks of an input are combined.
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkAggregation {
+    /// Average the embeddings of the chunks
+    #[default]
+    Mean,
+    /// Average the embeddings of the chunks, weighted by their number of tokens, so that a short
+    /// last chunk counts for less
+    Weighted,
+}
+
+/// How long inputs are split into chunks and combined again.
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChunkConfig {
+    /// Number of tokens consecutive chunks share, so that text around the boundaries is seen
+    /// in context
+    pub overlap: usize,
+    /// How the embeddings of the chunks are combined
+    pub aggregation: ChunkAggregation,
+}
+
+/// Flatten encodings truncated with a stride into their chunks: each encoding followed by its
+/// overflowing encodings. Returns the chunks, along with the number of chunks of each encoding.
+pub(crate) fn split_chunks(encodings: Vec<Encoding>) -> (Vec<Encoding>, Vec<usize>) {
+    let mut chunks = Vec::with_capacity(encodings.len());
+    let mut counts = Vec::with_capacity(encodings.len());
+    for mut encoding in encodings {
+        let overflowing = encoding.take_overflowing();
+        counts.push(1 + overflowing.len());
+        chunks.push(encoding);
+        chunks.extend(overflowing);
+    }
+
+    (chunks, counts)
+}
+
+/// Combine the embeddings of chunks, of shape `(n_chunks, dim)`, into one embedding per input,
+/// given the usage of each chunk and the number of chunks of each input.
+pub(crate) fn aggregate(
+    embeddings: &Tensor,
+    chunks: &[InputUsage],
+    counts: &[usize],
+    aggregation: ChunkAggregation,
+) -> Result<(Tensor, Vec<InputUsage>)> {
+    let n_chunks = chunks.len();
+    let mut weights = vec![0f32; counts.len() * n_chunks];
+    let mut inputs = Vec::with_capacity(counts.len());
+
+    let mut start = 0;
+    for (i, &count) in counts.iter().enumerate() {
+        let input_chunks = &chunks[start..start + count];
+        let chunk_weights: Vec<f32> = input_chunks
+            .iter()
+            .map(|chunk| match aggregation {
+                ChunkAggregation::Mean => 1.,
+                ChunkAggregation::Weighted => chunk.tokens.max(1) as f32,
+            })
+            .collect();
+        let total: f32 = chunk_weights.iter().sum();
+        for (j, weight) in chunk_weights.into_iter().enumerate() {
+            weights[i * n_chunks + start + j] = weight / total;
+        }
+
+        inputs.push(InputUsage {
+            tokens: input_chunks.iter().map(|chunk| chunk.tokens).sum(),
+            truncated: false,
+        });
+        start += count;
+    }
+
+    let weights = Tensor::from_vec(weights, (counts.len(), n_chunks), embeddings.device())?;
+    let embeddings = weights.matmul(&embeddings.to_dtype(DType::F32)?)?;
+
+    Ok((embeddings, inputs))
+}
@@ -0,0 +1,362 @@
+//! Sequence classification
+//!
+//! `*ForSequenceClassification` checkpoints put a classification head on top of the first
+//! token embedding of a transformer, which maps it to a logit per label. A [`TextClassifier`]
+//! turns these logits into scores for the labels in the `id2label` of the model configuration,
+//! e.g. for sentiment analysis or topic classification, and a
+//! [`CrossEncoder`](crate::CrossEncoder) uses them to score pairs of texts.
+
+use candle_core::{DType, Module, Tensor};
+use candle_nn::ops::{sigmoid, softmax_last_dim};
+use candle_nn::{linear, Activation, Linear, VarBuilder};
+use serde::Serialize;
+use tokenizers::EncodeInput;
+
+use crate::core::config::model::{BaseModelConfig, ModelType};
+use crate::core::embedder::load_var_builder;
+use crate::core::repo::ModelRepo;
+use crate::{Device, Error, Result, SentenceTransformer};
+
+pub use crate::core::config::model::ProblemType;
+
+/// Activation of the dense layer of a classification head.
+#[derive(Clone, Copy)]
+enum HeadActivation {
+    Tanh,
+    Hidden(Activation),
+}
+
+/// Classification head on top of the first token embedding of a transformer: a dense layer
+/// with an activation, followed by a projection to the labels.
+#[derive(Clone)]
+struct ClassificationHead {
+    dense: Linear,
+    activation: HeadActivation,
+    classifier: Linear,
+}
+
+impl ClassificationHead {
+    /// Load the head of a `*ForSequenceClassification` checkpoint of the given model type.
+    fn load(vb: VarBuilder, model_config: &serde_json::Value, num_labels: usize) -> Result<Self> {
+        let model_type = model_config["model_type"].as_str().unwrap_or_default();
+        let config_usize = |key: &str| model_config[key].as_u64().map(|value| value as usize);
+        let hidden_size = config_usize("hidden_size")
+            .or_else(|| config_usize("dim"))
+            .ok_or(Error::InvalidModelConfig(
+                "Model configuration has no hidden size.",
+            ))?;
+
+        let (dense, activation, classifier) = match model_type {
+            // The pooler of the base model, which is saved with its prefix
+            "bert" => {
+                let pooler = match vb.contains_tensor("bert.pooler.dense.weight") {
+                    true => vb.pp("bert.pooler.dense"),
+                    false => vb.pp("pooler.dense"),
+                };
+                (
+                    linear(hidden_size, hidden_size, pooler)?,
+                    HeadActivation::Tanh,
+                    linear(hidden_size, num_labels, vb.pp("classifier"))?,
+                )
+            }
+            "roberta" | "xlm-roberta" | "camembert" => (
+                linear(hidden_size, hidden_size, vb.pp("classifier.dense"))?,
+                HeadActivation::Tanh,
+                linear(hidden_size, num_labels, vb.pp("classifier.out_proj"))?,
+            ),
+            "distilbert" => (
+                linear(hidden_size, hidden_size, vb.pp("pre_classifier"))?,
+                HeadActivation::Hidden(Activation::Relu),
+                linear(hidden_size, num_labels, vb.pp("classifier"))?,
+            ),
+            "deberta-v2" => {
+                let pooler_size = config_usize("pooler_hidden_size").unwrap_or(hidden_size);
+                let activation = serde_json::from_value(model_config["pooler_hidden_act"].clone())
+                    .unwrap_or(Activation::Gelu);
+                (
+                    linear(hidden_size, pooler_size, vb.pp("pooler.dense"))?,
+                    HeadActivation::Hidden(activation),
+                    linear(pooler_size, num_labels, vb.pp("classifier"))?,
+                )
+            }
+            _ => {
+                return Err(Error::ModelLoad(
+                    "Sequence classification is not supported for this model type.",
+                ))
+            }
+        };
+
+        Ok(Self {
+            dense,
+            activation,
+            classifier,
+        })
+    }
+}
+
+impl Module for ClassificationHead {
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        let xs = self.dense.forward(xs)?;
+        let xs = match self.activation {
+            HeadActivation::Tanh => xs.tanh()?,
+            HeadActivation::Hidden(activation) => activation.forward(&xs)?,
+        };
+        self.classifier.forward(&xs)
+    }
+}
+
+/// A transformer with the classification head of a `*ForSequenceClassification` checkpoint.
+#[derive(Clone)]
+pub(crate) struct ClassificationModel {
+    model: SentenceTransformer,
+    head: ClassificationHead,
+    labels: Vec<String>,
+    problem_type: Option<ProblemType>,
+}
+
+impl ClassificationModel {
+    pub(crate) fn from_model_repo(
+        model_repo: &ModelRepo,
+        device: &Device,
+        dtype: DType,
+        quantized: bool,
+    ) -> Result<Self> {
+        let model =
+            SentenceTransformer::from_model_repo(model_repo, device, dtype, None, None, quantized)?;
+
+        Self::from_model(model)
+    }
+
+    /// Put the classification head of the checkpoint on a loaded model, sharing its weights.
+    pub(crate) fn from_model(model: SentenceTransformer) -> Result<Self> {
+        if model.model_type() != &ModelType::Classifier {
+            return Err(Error::ModelLoad(
+                "Model is not a sequence classification model.",
+            ));
+        }
+
+        let config: BaseModelConfig = serde_json::from_value(model.model_config().clone())?;
+        if config.is_token_classifier() {
+            return Err(Error::ModelLoad(
+                "Token classification models can only be loaded as a `TokenClassifier`.",
+            ));
+        }
+        let labels = config.labels();
+
+        // The head is small, so it is kept in full precision like the embeddings
+        let vb = load_var_builder(model.model_weights(), model.device(), DType::F32)?;
+        let head = ClassificationHead::load(vb, model.model_config(), labels.len())?;
+
+        Ok(Self {
+            model,
+            head,
+            labels,
+            problem_type: config.problem_type,
+        })
+    }
+
+    pub(crate) fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    pub(crate) fn problem_type(&self) -> Option<ProblemType> {
+        self.problem_type
+    }
+
+    pub(crate) fn model(&self) -> &SentenceTransformer {
+        &self.model
+    }
+
+    /// The logits of each input, of shape `(inputs, labels)`.
+    pub(crate) fn logits<'s, E>(&self, inputs: Vec<E>) -> Result<Tensor>
+    where
+        E: Into<EncodeInput<'s>> + Send,
+    {
+        let batch = self.model.tokenize_batch(inputs)?;
+        // Classifiers are pooled with the first token
+        let embeddings = self.model.encode_tokenized(&batch, false)?.into_tensor();
+
+        Ok(self.head.forward(&embeddings)?)
+    }
+}
+
+/// Score of a label of a [`TextClassifier`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LabelScore {
+    pub label: String,
+    pub score: f32,
+}
+
+/// Classifies texts with a sequence classification model.
+#[derive(Clone)]
+pub struct TextClassifier {
+    model: ClassificationModel,
+}
+
+impl TextClassifier {
+    pub(crate) fn from_model_repo(
+        model_repo: &ModelRepo,
+        device: &Device,
+        dtype: DType,
+        quantized: bool,
+    ) -> Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "classifier-from-folder");
+        let _enter = span.enter();
+
+        Ok(Self {
+            model: ClassificationModel::from_model_repo(model_repo, device, dtype, quantized)?,
+        })
+    }
+
+    /// Classify texts with a `*ForSequenceClassification` model loaded as a
+    /// [`SentenceTransformer`], e.g. to serve it for both embeddings and classification. The
+    /// transformer weights are shared with `model`, see [`SentenceTransformer::is_text_classifier`].
+    pub fn from_model(model: SentenceTransformer) -> Result<Self> {
+        Ok(Self {
+            model: ClassificationModel::from_model(model)?,
+        })
+    }
+
+    /// Names of the labels, in the order of the logits.
+    pub fn labels(&self) -> &[String] {
+        self.model.labels()
+    }
+
+    /// How the logits are turned into scores. Without a `problem_type` in the model
+    /// configuration, models with one label are assumed to be multi-label classifiers (a
+    /// sigmoid) and others single-label classifiers (a softmax), like in `transformers`
+    /// pipelines.
+    pub fn problem_type(&self) -> ProblemType {
+        self.model
+            .problem_type()
+            .unwrap_or(match self.labels().len() {
+                1 => ProblemType::MultiLabelClassification,
+                _ => ProblemType::SingleLabelClassification,
+            })
+    }
+
+    /// The logits of each text, of shape `(texts, labels)`.
+    pub fn predict_logits<'s, E>(&self, texts: Vec<E>) -> Result<Tensor>
+    where
+        E: Into<EncodeInput<'s>> + Send,
+    {
+        let span = tracing::span!(tracing::Level::TRACE, "classifier-predict");
+        let _enter = span.enter();
+
+        self.model.logits(texts)
+    }
+
+    /// The scores of each label for each text, of shape `(texts, labels)`, see
+    /// [`Self::problem_type`].
+    pub fn predict_scores<'s, E>(&self, texts: Vec<E>) -> Result<Tensor>
+    where
+        E: Into<EncodeInput<'s>> + Send,
+    {
+        let logits = self.predict_logits(texts)?;
+
+        Ok(match self.problem_type() {
+            ProblemType::SingleLabelClassification => softmax_last_dim(&logits)?,
+            ProblemType::MultiLabelClassification => sigmoid(&logits)?,
+            ProblemType::Regression => logits,
+        })
+    }
+
+    /// Classify each text, with the scores of all labels from highest to lowest.
+    pub fn predict<'s, E>(&self, texts: Vec<E>) -> Result<Vec<Vec<LabelScore>>>
+    where
+        E: Into<EncodeInput<'s>> + Send,
+    {
+        let scores = self.predict_scores(texts)?.to_vec2::<f32>()?;
+
+        Ok(scores
+            .into_iter()
+            .map(|scores| {
+                let mut label_scores: Vec<LabelScore> = self
+                    .labels()
+                    .iter()
+                    .zip(scores)
+                    .map(|(label, score)| LabelScore {
+                        label: label.clone(),
+                        score,
+                    })
+                    .collect();
+                label_scores.sort_by(|a, b| b.score.total_cmp(&a.score));
+                label_scores
+            })
+            .collect())
+    }
+
+    /// The transformer the texts are encoded with.
+    pub fn model(&self) -> &SentenceTransformer {
+        self.model.model()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::test_utils::create_tiny_classifier_repo;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_text_classifier() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_classifier_repo(dir.path(), &["negative", "neutral", "positive"], None)?;
+
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build_classifier()?;
+        assert_eq!(model.labels(), ["negative", "neutral", "positive"]);
+        assert_eq!(model.problem_type(), ProblemType::SingleLabelClassification);
+
+        let texts = vec!["The cat sits outside", "I love pasta"];
+        let predictions = model.predict(texts.clone())?;
+        assert_eq!(predictions.len(), 2);
+        for prediction in &predictions {
+            assert_eq!(prediction.len(), 3);
+            assert!(prediction[0].score >= prediction[2].score);
+            let total: f32 = prediction.iter().map(|label| label.score).sum();
+            assert!((total - 1.).abs() < 1e-5);
+        }
+
+        // A classifier on a model loaded for embeddings shares its weights
+        let embedder = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+        assert!(embedder.is_text_classifier());
+        let shared = TextClassifier::from_model(embedder)?;
+        assert_eq!(shared.predict(texts.clone())?, predictions);
+
+        // The scores follow the logits
+        let logits = model.predict_logits(texts)?.to_vec2::<f32>()?;
+        let best = (0..3)
+            .max_by(|&a, &b| logits[0][a].total_cmp(&logits[0][b]))
+            .unwrap();
+        assert_eq!(predictions[0][0].label, model.labels()[best]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_label_classifier() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_classifier_repo(
+            dir.path(),
+            &["cats", "food"],
+            Some("multi_label_classification"),
+        )?;
+
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build_classifier()?;
+        assert_eq!(model.problem_type(), ProblemType::MultiLabelClassification);
+
+        let texts = vec!["The cat sits outside"];
+        let scores = model.predict_scores(texts.clone())?;
+        let expected = sigmoid(&model.predict_logits(texts)?)?;
+        let difference = (scores - expected)?.abs()?.sum_all()?.to_scalar::<f32>()?;
+        assert!(difference < 1e-6);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+//! ColBERT late-interaction embeddings
+//!
+//! ColBERT models embed each token of a text instead of pooling them into one embedding. A
+//! query and a document are scored with MaxSim: the similarity of each query token to its
+//! most similar document token, summed over the query tokens. This "late interaction" is more
+//! accurate than comparing single embeddings, while documents can still be embedded ahead of
+//! time. Checkpoints like `colbert-ir/colbertv2.0` project the token embeddings of a BERT model
+//! to a lower dimension with a `linear` layer.
+
+use candle_core::{DType, IndexOp, Module, Tensor, D};
+use candle_nn::Linear;
+use tokenizers::{AddedToken, PaddingParams, PaddingStrategy, TruncationParams};
+
+use crate::core::embedder::load_var_builder;
+use crate::core::repo::ModelRepo;
+use crate::core::utils::normalize_l2;
+use crate::{Device, Error, PoolingStrategy, Result, SentenceTransformer};
+
+/// Marker token put in front of queries.
+const QUERY_MARKER: &str = "[unused0]";
+/// Marker token put in front of documents.
+const DOCUMENT_MARKER: &str = "[unused1]";
+/// Token queries are padded with, which the model uses for query augmentation.
+const MASK_TOKEN: &str = "[MASK]";
+/// Number of tokens queries are padded or truncated to.
+const QUERY_LENGTH: usize = 32;
+/// Dimension of the projected embeddings, unless configured otherwise.
+const DEFAULT_DIM: usize = 128;
+
+/// Embeds the tokens of queries and documents with a ColBERT model.
+#[derive(Clone)]
+pub struct ColBERT {
+    /// Shares its weights with `document_model`, with a tokenizer that pads queries
+    query_model: SentenceTransformer,
+    document_model: SentenceTransformer,
+    projection: Linear,
+    /// Whether the vocabulary has the query and document marker tokens
+    markers: bool,
+}
+
+impl ColBERT {
+    pub(crate) fn from_model_repo(
+        model_repo: &ModelRepo,
+        device: &Device,
+        dtype: DType,
+        quantized: bool,
+    ) -> Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "colbert-from-folder");
+        let _enter = span.enter();
+
+        // The pooling strategy is only needed to load the model, the tokens aren't pooled
+        let mut document_model = SentenceTransformer::from_model_repo(
+            model_repo,
+            device,
+            dtype,
+            Some(PoolingStrategy::Cls),
+            None,
+            quantized,
+        )?;
+
+        // The projection is small, so it is kept in full precision like the embeddings
+        let vb = load_var_builder(document_model.model_weights(), device, DType::F32)?;
+        if !vb.contains_tensor("linear.weight") {
+            return Err(Error::ModelLoad("Model has no ColBERT projection layer."));
+        }
+        let model_config = document_model.model_config();
+        let hidden_size = model_config["hidden_size"]
+            .as_u64()
+            .ok_or(Error::InvalidModelConfig(
+                "Model configuration has no hidden size.",
+            ))? as usize;
+        let dim = model_config["dim"]
+            .as_u64()
+            .map_or(DEFAULT_DIM, |dim| dim as usize);
+        let projection = Linear::new(vb.get((dim, hidden_size), "linear.weight")?, None);
+
+        // The markers are unused tokens of the vocabulary, which the tokenizer has to match
+        // as a whole
+        let tokenizer = document_model.get_tokenizer_mut();
+        let markers = [QUERY_MARKER, DOCUMENT_MARKER]
+            .iter()
+            .all(|marker| tokenizer.token_to_id(marker).is_some());
+        if markers {
+            tokenizer.add_special_tokens(&[
+                AddedToken::from(QUERY_MARKER, true),
+                AddedToken::from(DOCUMENT_MARKER, true),
+            ]);
+        }
+
+        // Queries are padded with mask tokens to a fixed length
+        let mut query_model = document_model.clone();
+        let tokenizer = query_model.get_tokenizer_mut();
+        if let Some(mask_id) = tokenizer.token_to_id(MASK_TOKEN) {
+            let padding = tokenizer.get_padding().cloned().unwrap_or_default();
+            tokenizer.with_padding(Some(PaddingParams {
+                strategy: PaddingStrategy::Fixed(QUERY_LENGTH),
+                pad_id: mask_id,
+                pad_token: MASK_TOKEN.to_string(),
+                ..padding
+            }));
+            tokenizer.with_truncation(Some(TruncationParams {
+                max_length: QUERY_LENGTH,
+                ..Default::default()
+            }))?;
+        }
+
+        Ok(Self {
+            query_model,
+            document_model,
+            projection,
+            markers,
+        })
+    }
+
+    /// Dimension of the token embeddings.
+    pub fn embedding_dim(&self) -> Result<usize> {
+        Ok(self.projection.weight().dim(0)?)
+    }
+
+    /// Embed the tokens of each query, as a tensor of shape `(query_length, dim)` per query.
+    ///
+    /// Queries are padded with mask tokens, whose embeddings are kept: they let the model
+    /// expand the query with related terms.
+    pub fn encode_queries(&self, queries: &[&str]) -> Result<Vec<Tensor>> {
+        let span = tracing::span!(tracing::Level::TRACE, "colbert-encode-queries");
+        let _enter = span.enter();
+
+        self.encode(&self.query_model, queries, QUERY_MARKER, false)
+    }
+
+    /// Embed the tokens of each document, as a tensor of shape `(n_tokens, dim)` per document.
+    pub fn encode_documents(&self, documents: &[&str]) -> Result<Vec<Tensor>> {
+        let span = tracing::span!(tracing::Level::TRACE, "colbert-encode-documents");
+        let _enter = span.enter();
+
+        self.encode(&self.document_model, documents, DOCUMENT_MARKER, true)
+    }
+
+    /// Score each document for a query with MaxSim, see [`max_sim`].
+    pub fn score(&self, query: &str, documents: &[&str]) -> Result<Vec<f32>> {
+        let query = self.encode_queries(&[query])?.remove(0);
+
+        self.encode_documents(documents)?
+            .iter()
+            .map(|document| max_sim(&query, document))
+            .collect()
+    }
+
+    fn encode(
+        &self,
+        model: &SentenceTransformer,
+        texts: &[&str],
+        marker: &str,
+        skip_padding: bool,
+    ) -> Result<Vec<Tensor>> {
+        let texts: Vec<String> = match self.markers {
+            true => texts
+                .iter()
+                .map(|text| format!("{marker} {text}"))
+                .collect(),
+            false => texts.iter().map(|text| text.to_string()).collect(),
+        };
+
+        let batch = model.tokenize_batch(texts)?;
+        let embeddings = model.encode_tokens(&batch)?.to_dtype(DType::F32)?;
+        let embeddings = self.projection.forward(&embeddings)?;
+
+        batch
+            .encodings()
+            .iter()
+            .enumerate()
+            .map(|(i, encoding)| {
+                let mask = encoding.get_attention_mask();
+                let (start, n_tokens) = match skip_padding {
+                    true => (
+                        mask.iter().position(|&mask| mask == 1).unwrap_or(0),
+                        mask.iter().filter(|&&mask| mask == 1).count(),
+                    ),
+                    false => (0, encoding.len()),
+                };
+                Ok(normalize_l2(&embeddings.i((i, start..start + n_tokens))?)?)
+            })
+            .collect()
+    }
+}
+
+/// MaxSim score of a query and a document, given the embeddings of their tokens of shape
+/// `(n_tokens, dim)`: the sum over the query tokens of their highest similarity to a document
+/// token.
+pub fn max_sim(query: &Tensor, document: &Tensor) -> Result<f32> {
+    let similarities = query.matmul(&document.t()?)?;
+
+    Ok(similarities
+        .max(D::Minus1)?
+        .sum_all()?
+        .to_dtype(DType::F32)?
+        .to_scalar()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::test_utils::create_tiny_colbert_repo;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_colbert() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_colbert_repo(dir.path(), 4)?;
+
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build_colbert()?;
+        assert_eq!(model.embedding_dim()?, 4);
+
+        // Queries are padded to a fixed length with mask tokens
+        let queries = model.encode_queries(&["What do cats do?", "Pasta"])?;
+        assert!(queries.iter().all(|q| q.dims() == [QUERY_LENGTH, 4]));
+
+        // [CLS] [unused1] the cat [SEP], without padding
+        let documents = model.encode_documents(&["The cat", "A dog sits on the mat"])?;
+        assert_eq!(documents[0].dims(), &[5, 4]);
+        assert_eq!(documents[1].dims(), &[9, 4]);
+        let batch = model
+            .document_model
+            .tokenize_batch(vec!["[unused1] The cat"])?;
+        assert_eq!(batch.encodings()[0].get_ids()[1], 2);
+
+        // Token embeddings are normalized
+        let norms = documents[1].sqr()?.sum(1)?.to_vec1::<f32>()?;
+        assert!(norms.iter().all(|norm| (norm - 1.).abs() < 1e-5));
+
+        let scores = model.score("What do cats do?", &["The cat", "A dog sits on the mat"])?;
+        let expected = max_sim(&queries[0], &documents[0])?;
+        assert!((scores[0] - expected).abs() < 1e-5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_sim() -> Result<()> {
+        let query = Tensor::new(&[[1f32, 0.], [0., 1.]], &Device::Cpu)?;
+        let document = Tensor::new(&[[0.6f32, 0.8], [1., 0.], [0., -1.]], &Device::Cpu)?;
+
+        // 1 for the first query token, 0.8 for the second
+        assert!((max_sim(&query, &document)? - 1.8).abs() < 1e-6);
+
+        Ok(())
+    }
+}
@@ -6,11 +6,20 @@
 
 use crate::core::config::parse::parse_config;
 use crate::core::repo::ModelRepo;
+use crate::models::jina_v3::Config as JinaV3Config;
+use crate::models::mpnet::Config as MPNetConfig;
+use crate::models::nomic_bert::Config as NomicBertConfig;
+use crate::models::static_embedding::Config as StaticEmbeddingConfig;
 use crate::pooling::PoolingStrategy;
 use crate::Result;
 use candle_transformers::models::bert::Config as _BertConfig;
+use candle_transformers::models::debertav2::Config as DebertaV2Config;
 use candle_transformers::models::distilbert::Config as DistilBertConfig;
 use candle_transformers::models::jina_bert::Config as _JinaBertConfig;
+use candle_transformers::models::modernbert::Config as ModernBertConfig;
+use candle_transformers::models::qwen2::Config as Qwen2Config;
+use candle_transformers::models::t5::Config as T5Config;
+use candle_transformers::models::xlm_roberta::Config as _XlmRobertaConfig;
 use serde::Deserialize;
 use std::collections::HashMap;
 
@@ -22,12 +31,54 @@ use std::collections::HashMap;
 pub(crate) struct BaseModelConfig {
     pub architectures: Vec<String>,
     pub model_type: String,
-    #[serde(alias = "n_positions")]
+    // Static embedding models have no positions
+    #[serde(alias = "n_positions", default)]
     pub max_position_embeddings: usize,
     #[serde(default)]
     pub pad_token_id: usize,
     pub id2label: Option<HashMap<usize, String>>,
     pub label2id: Option<HashMap<String, usize>>,
+    #[serde(default)]
+    pub problem_type: Option<ProblemType>,
+}
+
+impl BaseModelConfig {
+    /// Whether the model classifies each token, rather than the whole sequence.
+    pub(crate) fn is_token_classifier(&self) -> bool {
+        self.architectures
+            .iter()
+            .any(|arch| arch.ends_with("ForTokenClassification"))
+    }
+
+    /// Names of the labels of a classifier, by index. HF models have two labels unless
+    /// configured otherwise.
+    pub(crate) fn labels(&self) -> Vec<String> {
+        let Some(id2label) = &self.id2label else {
+            return vec!["LABEL_0".to_string(), "LABEL_1".to_string()];
+        };
+        let num_labels = id2label.keys().max().map_or(0, |max| max + 1);
+        (0..num_labels)
+            .map(|i| {
+                id2label
+                    .get(&i)
+                    .cloned()
+                    .unwrap_or_else(|| format!("LABEL_{i}"))
+            })
+            .collect()
+    }
+}
+
+/// The task a classifier was trained for, which determines how its logits are turned into
+/// scores.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemType {
+    /// One label per input: the scores are the softmax of the logits
+    SingleLabelClassification,
+    /// Any number of labels per input: the scores are the sigmoid of each logit
+    MultiLabelClassification,
+    /// The logits are the scores
+    Regression,
 }
 
 #[derive(Debug, Deserialize)]
@@ -37,6 +88,14 @@ pub(crate) enum BertConfig {
     JinaBert(_JinaBertConfig),
 }
 
+/// XLM-RoBERTa models, or Jina v3 models with LoRA adapters, which share their model type.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum XlmRobertaConfig {
+    JinaV3(Box<JinaV3Config>),
+    XlmRoberta(_XlmRobertaConfig),
+}
+
 /// The given core type.
 ///
 /// Based on the `model_type` key in the `config.json`, the given variant enables the parser
@@ -46,11 +105,22 @@ pub(crate) enum BertConfig {
 #[serde(tag = "model_type", rename_all = "kebab-case")]
 pub(crate) enum EmbedderConfig {
     Bert(BertConfig),
-    // XlmRoberta(BertConfig),
-    // Camembert(BertConfig),
-    // Roberta(BertConfig),
+    XlmRoberta(XlmRobertaConfig),
+    Camembert(_XlmRobertaConfig),
+    Roberta(_XlmRobertaConfig),
+    DebertaV2(Box<DebertaV2Config>),
+    #[serde(rename(deserialize = "mpnet"))]
+    MPNet(MPNetConfig),
+    #[serde(rename(deserialize = "modernbert"))]
+    ModernBert(ModernBertConfig),
+    Qwen2(Qwen2Config),
+    T5(T5Config),
+    #[serde(rename(deserialize = "nomic_bert"))]
+    NomicBert(NomicBertConfig),
     #[serde(rename(deserialize = "distilbert"))]
     DistilBert(DistilBertConfig),
+    #[serde(rename(deserialize = "model2vec"))]
+    Model2Vec(StaticEmbeddingConfig),
 }
 
 /// The embedding strategy used by a given core.
@@ -62,6 +132,7 @@ pub enum ModelType {
 
 /// The core definition
 pub struct SentenceTransformerConfig {
+    pub(crate) model_config: serde_json::Value,
     pub(crate) embedder_config: EmbedderConfig,
     pub(crate) model_type: ModelType,
     pub(crate) tokenizer_config: serde_json::Value,
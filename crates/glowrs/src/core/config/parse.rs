@@ -1,10 +1,10 @@
 use std::fs;
-use std::path::PathBuf;
 
 use crate::core::config::model::{
     BaseModelConfig, EmbedderConfig, ModelType, SentenceTransformerConfig,
 };
-use crate::core::repo::{ModelRepo, ModelRepoFiles};
+use crate::core::repo::{ModelRepo, ModelRepoFiles, TokenizerPath};
+use crate::core::slow_tokenizer::load_slow_tokenizer;
 use crate::pooling::{PoolConfig, PoolingStrategy};
 use crate::{Error, Result};
 
@@ -14,26 +14,74 @@ pub(crate) fn parse_config(
     model_repo: &ModelRepo,
     // If not given, it'll be inferred from the core configuration
     pooling_strategy: Option<PoolingStrategy>,
+) -> Result<SentenceTransformerConfig> {
+    if let ModelRepo::Embedded(bytes) = model_repo {
+        let as_str = |bytes: &'static [u8]| {
+            std::str::from_utf8(bytes)
+                .map_err(|_| Error::InvalidModelConfig("Embedded configuration is not UTF-8"))
+        };
+
+        return parse_config_str(
+            as_str(bytes.config)?,
+            as_str(bytes.tokenizer)?,
+            bytes.pooling_config.map(as_str).transpose()?,
+            pooling_strategy,
+        );
+    }
+
+    parse_config_files(&model_repo.file_paths()?, pooling_strategy)
+}
+
+/// Parse the core configuration from the resolved files of a repository.
+pub(crate) fn parse_config_files(
+    files: &ModelRepoFiles,
+    pooling_strategy: Option<PoolingStrategy>,
 ) -> Result<SentenceTransformerConfig> {
     let ModelRepoFiles {
         config,
         tokenizer_config,
         pooling_config,
         ..
-    } = model_repo.file_paths()?;
+    } = files;
+
+    let pooling_config = pooling_config
+        .as_ref()
+        .map(fs::read_to_string)
+        .transpose()?;
+
+    let tokenizer_config = match tokenizer_config {
+        TokenizerPath::Fast(path) => fs::read_to_string(path)?,
+        TokenizerPath::Slow(dir) => load_slow_tokenizer(dir)?.to_string(false)?,
+    };
 
+    parse_config_str(
+        &fs::read_to_string(config)?,
+        &tokenizer_config,
+        pooling_config.as_deref(),
+        pooling_strategy,
+    )
+}
+
+/// Parse the core configuration from the contents of `config.json`, `tokenizer.json` and
+/// optionally `1_Pooling/config.json`.
+fn parse_config_str(
+    config_str: &str,
+    tokenizer_config_str: &str,
+    pooling_config_str: Option<&str>,
+    pooling_strategy: Option<PoolingStrategy>,
+) -> Result<SentenceTransformerConfig> {
     // Parse config.json
-    let config_str = &fs::read_to_string(config)?;
+    let model_config: serde_json::Value = serde_json::from_str(config_str)?;
     let hf_config: BaseModelConfig = serde_json::from_str(config_str)?;
     let embedder_config: EmbedderConfig = serde_json::from_str(config_str)?;
 
     // Parse tokenizer.json
-    let tokenizer_config: serde_json::Value =
-        serde_json::from_str(&fs::read_to_string(tokenizer_config)?)?;
+    let tokenizer_config: serde_json::Value = serde_json::from_str(tokenizer_config_str)?;
 
-    let model_type = get_backend_model_type(&hf_config, pooling_config, pooling_strategy)?;
+    let model_type = get_backend_model_type(&hf_config, pooling_config_str, pooling_strategy)?;
 
     Ok(SentenceTransformerConfig {
+        model_config,
         embedder_config,
         model_type,
         tokenizer_config,
@@ -45,19 +93,19 @@ pub(crate) fn parse_config(
 /// Source: `text-embeddings-inference`: [`backends/candle/src/lib.rs`](https://github.com/huggingface/text-embeddings-inference/blob/7e55c61c2a39612ade5db9b929ffc883913ae0f3/backends/candle/src/lib.rs)
 pub(crate) fn get_backend_model_type(
     config: &BaseModelConfig,
-    pooling_config_path: Option<PathBuf>,
+    pooling_config: Option<&str>,
     pooling: Option<PoolingStrategy>,
 ) -> Result<ModelType> {
-    if let Some(p) = pooling {
-        for arch in &config.architectures {
-            if matches!(p, PoolingStrategy::Splade) && arch.ends_with("MaskedLM") {
-                return Ok(ModelType::Embedding(PoolingStrategy::Splade));
-            } else if arch.ends_with("Classification") {
+    for arch in &config.architectures {
+        if Some(PoolingStrategy::Splade) == pooling && arch.ends_with("MaskedLM") {
+            return Ok(ModelType::Embedding(PoolingStrategy::Splade));
+        } else if arch.ends_with("Classification") {
+            if pooling.is_some() {
                 tracing::warn!(
                     "`--pooling` arg is set but core is a classifier. Ignoring `--pooling` arg."
                 );
-                return Ok(ModelType::Classifier);
             }
+            return Ok(ModelType::Classifier);
         }
     }
 
@@ -68,27 +116,26 @@ pub(crate) fn get_backend_model_type(
     }
 
     // Set pooling
-    let pool: Result<_> = match (pooling, pooling_config_path) {
+    let pool: Result<_> = match (pooling, pooling_config) {
         (Some(ps), _) => Ok(ps),
-        (None, Some(pooling_config_path)) => {
-            // Load pooling config
-
-            let config = fs::read_to_string(pooling_config_path)?;
-            // .map_err(|_| Err(Error::InvalidArgument("The `--pooling` arg is not set and we could not find a pooling configuration (`1_Pooling/config.json`) for this core.")))?;
-
-            let config: PoolConfig = serde_json::from_str(&config)?;
+        (None, Some(config)) => {
+            let config: PoolConfig = serde_json::from_str(config)?;
             // .map_err(|_| Err(Error::InvalidArgument("Failed to parse `1_Pooling/config.json`")).into())?;
 
             if config.pooling_mode_cls_token {
                 Ok(PoolingStrategy::Cls)
             } else if config.pooling_mode_mean_tokens {
                 Ok(PoolingStrategy::Mean)
+            } else if config.pooling_mode_lasttoken {
+                Ok(PoolingStrategy::LastToken)
             } else {
                 return Err(Error::ModelLoad(
                     "Pooling config {config:?} is not supported",
                 ));
             }
         }
+        // Static embeddings are mean pooled
+        (None, None) if config.model_type == "model2vec" => Ok(PoolingStrategy::Mean),
         (_, _) => Err(Error::NoPoolingConfiguration(
             "No pooling configuration provided or found in model repository.",
         )),
@@ -137,9 +184,26 @@ mod test {
             pad_token_id: 0,
             id2label: None,
             label2id: None,
+            problem_type: None,
         };
         let model_type =
             get_backend_model_type(&config, None, Some(PoolingStrategy::Mean)).unwrap();
         assert_eq!(model_type, ModelType::Embedding(PoolingStrategy::Mean));
     }
+
+    #[test]
+    fn test_get_backend_model_type_classifier() {
+        let config = BaseModelConfig {
+            architectures: vec!["BertForSequenceClassification".to_string()],
+            model_type: "bert".to_string(),
+            max_position_embeddings: 512,
+            pad_token_id: 0,
+            id2label: None,
+            label2id: None,
+            problem_type: None,
+        };
+        // Classifiers need no pooling configuration
+        let model_type = get_backend_model_type(&config, None, None).unwrap();
+        assert_eq!(model_type, ModelType::Classifier);
+    }
 }
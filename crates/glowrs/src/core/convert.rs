@@ -0,0 +1,185 @@
+//! Weight conversion utilities
+//!
+//! Converts PyTorch pickle weights (`pytorch_model.bin` / `*.pth`) into the SafeTensors format,
+//! so they can be memory-mapped on subsequent loads instead of being unpickled every time.
+//! Quantized GGUF weights are dequantized, to load them with the regular models.
+
+use candle_core::quantized::gguf_file;
+use candle_core::{DType, Device, Tensor};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::core::repo::{PTH_FILE, SAFETENSORS_FILE};
+use crate::{Error, Result};
+
+/// Resolve the source weights file and the target SafeTensors file for a given path.
+///
+/// If `path` is a directory, it is assumed to be a model repository folder containing a
+/// `pytorch_model.bin` file. The output is written to `model.safetensors` next to the source
+/// weights.
+pub(crate) fn resolve_conversion_paths(path: &Path) -> Result<(PathBuf, PathBuf)> {
+    let src = if path.is_dir() {
+        path.join(PTH_FILE)
+    } else {
+        path.to_owned()
+    };
+
+    if !src.exists() {
+        return Err(Error::ModelLoad("No PyTorch weights found to convert."));
+    }
+
+    let dst = src
+        .parent()
+        .map(|p| p.join(SAFETENSORS_FILE))
+        .unwrap_or_else(|| PathBuf::from(SAFETENSORS_FILE));
+
+    Ok((src, dst))
+}
+
+/// Convert PyTorch pickle weights into a `model.safetensors` file.
+///
+/// # Arguments
+///
+/// * `path` - Path to a `.bin`/`.pth` weights file, or a model folder containing `pytorch_model.bin`.
+/// * `dtype` - Optional data type to cast floating point tensors to (e.g. `DType::F16`).
+///
+/// # Returns
+///
+/// The path of the written SafeTensors file.
+pub fn convert_pth_to_safetensors<P: AsRef<Path>>(
+    path: P,
+    dtype: Option<DType>,
+) -> Result<PathBuf> {
+    let span = tracing::span!(tracing::Level::TRACE, "convert-pth");
+    let _enter = span.enter();
+
+    let (src, dst) = resolve_conversion_paths(path.as_ref())?;
+
+    write_pth_as_safetensors(&src, &dst, dtype)?;
+
+    Ok(dst)
+}
+
+/// Read the PyTorch weights in `src` and write them to the SafeTensors file `dst`.
+pub(crate) fn write_pth_as_safetensors(src: &Path, dst: &Path, dtype: Option<DType>) -> Result<()> {
+    tracing::info!("Reading PyTorch weights from {}", src.display());
+    let tensors = candle_core::pickle::read_all(src)?;
+
+    let tensors = cast_tensors(tensors, dtype)?;
+
+    tracing::info!("Writing {} tensors to {}", tensors.len(), dst.display());
+    candle_core::safetensors::save(&tensors, dst)?;
+
+    Ok(())
+}
+
+/// Read and dequantize all tensors of a GGUF file. The tensors should be named like in the
+/// SafeTensors weights of the model.
+pub(crate) fn read_gguf_dequantized(
+    path: &Path,
+    device: &Device,
+) -> Result<HashMap<String, Tensor>> {
+    let mut file = std::fs::File::open(path)?;
+    let content = gguf_file::Content::read(&mut file)?;
+
+    let names: Vec<String> = content.tensor_infos.keys().cloned().collect();
+    names
+        .into_iter()
+        .map(|name| {
+            let tensor = content
+                .tensor(&mut file, &name, device)?
+                .dequantize(device)?;
+            Ok((name, tensor))
+        })
+        .collect()
+}
+
+/// Cast all floating point tensors to the given data type. Integer tensors are left untouched.
+fn cast_tensors(
+    tensors: Vec<(String, Tensor)>,
+    dtype: Option<DType>,
+) -> Result<HashMap<String, Tensor>> {
+    tensors
+        .into_iter()
+        .map(|(name, tensor)| {
+            let tensor = match dtype {
+                Some(dtype) if tensor.dtype().is_float() => tensor.to_dtype(dtype)?,
+                _ => tensor,
+            };
+            Ok((name, tensor))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use candle_core::quantized::{GgmlDType, QTensor};
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_resolve_conversion_paths() -> Result<()> {
+        let dir = tempdir()?;
+        let pth_path = dir.path().join(PTH_FILE);
+        fs::write(&pth_path, r"\b")?;
+
+        let (src, dst) = resolve_conversion_paths(dir.path())?;
+        assert_eq!(src, pth_path);
+        assert_eq!(dst, dir.path().join(SAFETENSORS_FILE));
+
+        let (src, _) = resolve_conversion_paths(&pth_path)?;
+        assert_eq!(src, pth_path);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_conversion_paths_missing_weights() -> Result<()> {
+        let dir = tempdir()?;
+        assert!(resolve_conversion_paths(dir.path()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cast_tensors() -> Result<()> {
+        let tensors = vec![
+            (
+                "weight".to_string(),
+                Tensor::zeros((2, 2), DType::F32, &Device::Cpu)?,
+            ),
+            (
+                "position_ids".to_string(),
+                Tensor::zeros(2, DType::I64, &Device::Cpu)?,
+            ),
+        ];
+
+        let tensors = cast_tensors(tensors, Some(DType::F16))?;
+        assert_eq!(tensors["weight"].dtype(), DType::F16);
+        assert_eq!(tensors["position_ids"].dtype(), DType::I64);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_gguf_dequantized() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("model.Q8_0.gguf");
+        let tensor = Tensor::arange(0f32, 64., &Device::Cpu)?.reshape((2, 32))?;
+        let qtensor = QTensor::quantize(&tensor, GgmlDType::Q8_0)?;
+        gguf_file::write(
+            &mut fs::File::create(&path)?,
+            &[],
+            &[("embeddings.weight", &qtensor)],
+        )?;
+
+        let tensors = read_gguf_dequantized(&path, &Device::Cpu)?;
+        let weight = &tensors["embeddings.weight"];
+        assert_eq!(weight.dims(), &[2, 32]);
+        let diff = (weight - &tensor)?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert!(diff < 0.5);
+
+        Ok(())
+    }
+}
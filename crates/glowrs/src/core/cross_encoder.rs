@@ -0,0 +1,116 @@
+//! Cross-encoders
+//!
+//! A cross-encoder scores a pair of texts, such as a query and a document, by running both
+//! through the transformer as one input, followed by a classification head. This is slower
+//! than comparing embeddings, but more accurate, so cross-encoders are typically used to rerank
+//! the top results of an embedding search. They are `*ForSequenceClassification` checkpoints,
+//! e.g. `cross-encoder/ms-marco-MiniLM-L-6-v2`.
+
+use candle_core::{DType, Tensor};
+use tokenizers::EncodeInput;
+
+use crate::core::classifier::ClassificationModel;
+use crate::core::repo::ModelRepo;
+use crate::{Device, Error, Result, SentenceTransformer};
+
+/// Scores pairs of texts with a sequence classification model.
+#[derive(Clone)]
+pub struct CrossEncoder {
+    model: ClassificationModel,
+}
+
+impl CrossEncoder {
+    pub(crate) fn from_model_repo(
+        model_repo: &ModelRepo,
+        device: &Device,
+        dtype: DType,
+        quantized: bool,
+    ) -> Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "cross-encoder-from-folder");
+        let _enter = span.enter();
+
+        Ok(Self {
+            model: ClassificationModel::from_model_repo(model_repo, device, dtype, quantized)?,
+        })
+    }
+
+    /// Number of labels, i.e. scores per pair, of the classification head.
+    pub fn num_labels(&self) -> usize {
+        self.model.labels().len()
+    }
+
+    /// The logits of each pair, of shape `(pairs, labels)`.
+    ///
+    /// Each pair is tokenized as one input, like with [`SentenceTransformer::encode_pairs`].
+    pub fn predict_logits<'s, A, B>(&self, pairs: Vec<(A, B)>) -> Result<Tensor>
+    where
+        (A, B): Into<EncodeInput<'s>> + Send,
+    {
+        let span = tracing::span!(tracing::Level::TRACE, "cross-encoder-predict");
+        let _enter = span.enter();
+
+        self.model.logits(pairs)
+    }
+
+    /// Score each pair, e.g. the relevance of a document to a query for a reranker. The scores
+    /// are the raw logits of the model; apply a sigmoid to get scores between 0 and 1.
+    ///
+    /// Only models with a single label have one score per pair, use [`Self::predict_logits`]
+    /// for the others.
+    pub fn predict<'s, A, B>(&self, pairs: Vec<(A, B)>) -> Result<Vec<f32>>
+    where
+        (A, B): Into<EncodeInput<'s>> + Send,
+    {
+        if self.num_labels() != 1 {
+            return Err(Error::InvalidArgument(
+                "Only models with a single label have one score per pair.",
+            ));
+        }
+
+        Ok(self.predict_logits(pairs)?.squeeze(1)?.to_vec1()?)
+    }
+
+    /// The transformer the pairs are encoded with.
+    pub fn model(&self) -> &SentenceTransformer {
+        self.model.model()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::test_utils::{create_tiny_bert_repo, create_tiny_classifier_repo};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_cross_encoder() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_classifier_repo(dir.path(), &["LABEL_0"], None)?;
+
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build_cross_encoder()?;
+        assert_eq!(model.num_labels(), 1);
+
+        let pairs = vec![
+            ("What do cats do?", "The cat sits outside"),
+            ("What do cats do?", "I love pasta, it is my favourite food"),
+        ];
+        let scores = model.predict(pairs.clone())?;
+        assert_eq!(scores.len(), 2);
+        assert_ne!(scores[0], scores[1]);
+
+        let logits = model.predict_logits(pairs)?;
+        assert_eq!(logits.dims(), &[2, 1]);
+
+        // Embedding models have no classification head
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        assert!(SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build_cross_encoder()
+            .is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+//! Dense projection modules
+//!
+//! Some `sentence-transformers` repositories, such as `sentence-transformers/LaBSE` and
+//! `sentence-transformers/sentence-t5-base`, project the pooled embeddings with one or more
+//! `Dense` modules (e.g. `2_Dense/`): a linear layer followed by an activation function. Each
+//! module folder holds a `config.json` and the weights of the layer as `linear.weight` and
+//! `linear.bias`.
+
+use candle_core::{DType, Device, Module, Tensor};
+use candle_nn::{Linear, VarBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::core::embedder::load_var_builder;
+use crate::core::repo::{ModelWeightsPath, CONFIG_FILE, PTH_FILE, SAFETENSORS_FILE};
+use crate::{Error, Result};
+
+/// Activation function of a `Dense` module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Activation {
+    Identity,
+    Tanh,
+    Relu,
+    Sigmoid,
+    Gelu,
+}
+
+impl Activation {
+    /// Parse the name of the PyTorch module, e.g. `torch.nn.modules.activation.Tanh`.
+    fn from_name(name: &str) -> Result<Self> {
+        match name.rsplit('.').next() {
+            Some("Identity") => Ok(Self::Identity),
+            Some("Tanh") => Ok(Self::Tanh),
+            Some("ReLU") => Ok(Self::Relu),
+            Some("Sigmoid") => Ok(Self::Sigmoid),
+            Some("GELU") => Ok(Self::Gelu),
+            _ => Err(Error::ModelLoad(
+                "Unsupported activation function in Dense module.",
+            )),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Identity => "torch.nn.modules.linear.Identity",
+            Self::Tanh => "torch.nn.modules.activation.Tanh",
+            Self::Relu => "torch.nn.modules.activation.ReLU",
+            Self::Sigmoid => "torch.nn.modules.activation.Sigmoid",
+            Self::Gelu => "torch.nn.modules.activation.GELU",
+        }
+    }
+}
+
+fn default_activation() -> String {
+    Activation::Tanh.name().to_string()
+}
+
+fn default_bias() -> bool {
+    true
+}
+
+/// The `config.json` of a `Dense` module. Like in `sentence-transformers`, the activation
+/// defaults to `Tanh` and the bias to `true`.
+#[derive(Debug, Serialize, Deserialize)]
+struct DenseConfig {
+    in_features: usize,
+    out_features: usize,
+    #[serde(default = "default_bias")]
+    bias: bool,
+    #[serde(default = "default_activation")]
+    activation_function: String,
+}
+
+/// A linear projection of pooled embeddings, followed by an activation function.
+#[derive(Debug, Clone)]
+pub struct Dense {
+    linear: Linear,
+    activation: Activation,
+}
+
+impl Dense {
+    /// Load a `Dense` module from its folder. The weights are kept in full precision, like the
+    /// pooled embeddings they project.
+    pub(crate) fn load(dir: &Path, device: &Device) -> Result<Self> {
+        let config: DenseConfig =
+            serde_json::from_str(&fs::read_to_string(dir.join(CONFIG_FILE))?)?;
+        let activation = Activation::from_name(&config.activation_function)?;
+
+        let weights = match dir.join(SAFETENSORS_FILE) {
+            path if path.exists() => ModelWeightsPath::Safetensors(path),
+            _ => ModelWeightsPath::Pth(dir.join(PTH_FILE)),
+        };
+        let vb = load_var_builder(&weights, device, DType::F32)?;
+        let weight = vb.get((config.out_features, config.in_features), "linear.weight")?;
+        let bias = match config.bias {
+            true => Some(vb.get(config.out_features, "linear.bias")?),
+            false => None,
+        };
+
+        Ok(Self {
+            linear: Linear::new(weight, bias),
+            activation,
+        })
+    }
+
+    /// Load the trained pooler of a checkpoint (`pooler.dense`, possibly with the prefix of the
+    /// model type such as `bert.`), which is a `Dense` module with a `Tanh` activation.
+    pub(crate) fn load_pooler(vb: VarBuilder, model_config: &serde_json::Value) -> Result<Self> {
+        let hidden_size = model_config["hidden_size"]
+            .as_u64()
+            .ok_or(Error::InvalidModelConfig(
+                "Model configuration has no hidden size.",
+            ))? as usize;
+        let prefix = model_config["model_type"].as_str().unwrap_or_default();
+        let vb = match vb.contains_tensor(&format!("{prefix}.pooler.dense.weight")) {
+            true => vb.pp(format!("{prefix}.pooler.dense")),
+            false if vb.contains_tensor("pooler.dense.weight") => vb.pp("pooler.dense"),
+            false => return Err(Error::ModelLoad("Model has no pooler layer.")),
+        };
+
+        Ok(Self {
+            linear: candle_nn::linear(hidden_size, hidden_size, vb)?,
+            activation: Activation::Tanh,
+        })
+    }
+
+    /// Dimension of the projected embeddings.
+    pub fn out_features(&self) -> usize {
+        self.linear.weight().dims()[0]
+    }
+
+    /// Project a batch of embeddings of shape `(n, in_features)`.
+    pub fn forward(&self, embeddings: &Tensor) -> Result<Tensor> {
+        let embeddings = self.linear.forward(embeddings)?;
+
+        Ok(match self.activation {
+            Activation::Identity => embeddings,
+            Activation::Tanh => embeddings.tanh()?,
+            Activation::Relu => embeddings.relu()?,
+            Activation::Sigmoid => candle_nn::ops::sigmoid(&embeddings)?,
+            Activation::Gelu => embeddings.gelu_erf()?,
+        })
+    }
+
+    /// Save the module to a folder, in the layout it is loaded from.
+    pub(crate) fn save(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+
+        let (out_features, in_features) = self.linear.weight().dims2()?;
+        let config = DenseConfig {
+            in_features,
+            out_features,
+            bias: self.linear.bias().is_some(),
+            activation_function: self.activation.name().to_string(),
+        };
+        fs::write(
+            dir.join(CONFIG_FILE),
+            serde_json::to_string_pretty(&config)?,
+        )?;
+
+        let mut tensors = HashMap::new();
+        tensors.insert("linear.weight".to_string(), self.linear.weight().clone());
+        if let Some(bias) = self.linear.bias() {
+            tensors.insert("linear.bias".to_string(), bias.clone());
+        }
+        candle_core::safetensors::save(&tensors, dir.join(SAFETENSORS_FILE))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_dense() -> Result<()> {
+        let dir = tempdir()?;
+        fs::write(
+            dir.path().join(CONFIG_FILE),
+            r#"{"in_features": 2, "out_features": 3, "bias": false}"#,
+        )?;
+        let weight = Tensor::new(&[[1f32, 0.], [0., 2.], [1., 1.]], &Device::Cpu)?;
+        candle_core::safetensors::save(
+            &HashMap::from([("linear.weight".to_string(), weight)]),
+            dir.path().join(SAFETENSORS_FILE),
+        )?;
+
+        // Tanh is the default activation
+        let dense = Dense::load(dir.path(), &Device::Cpu)?;
+        assert_eq!(dense.activation, Activation::Tanh);
+        assert_eq!(dense.out_features(), 3);
+
+        let embeddings = Tensor::new(&[[0.5f32, -0.25]], &Device::Cpu)?;
+        let projected = dense.forward(&embeddings)?.to_vec2::<f32>()?;
+        let expected = [0.5f32.tanh(), (-0.5f32).tanh(), 0.25f32.tanh()];
+        for (value, expected) in projected[0].iter().zip(expected) {
+            assert!((value - expected).abs() < 1e-6);
+        }
+
+        let saved = tempdir()?;
+        dense.save(saved.path())?;
+        let reloaded = Dense::load(saved.path(), &Device::Cpu)?;
+        assert_eq!(reloaded.forward(&embeddings)?.to_vec2::<f32>()?, projected);
+
+        assert!(Activation::from_name("torch.nn.modules.activation.Softmax").is_err());
+
+        Ok(())
+    }
+}
@@ -1,18 +1,177 @@
 use candle_core::Device;
 use once_cell::sync::Lazy;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{Error, Result};
 
 #[cfg(all(feature = "metal", feature = "cuda"))]
 compile_error!("feature \"metal\" and feature \"cuda\" cannot be enabled at the same time");
 
-#[cfg(feature = "metal")]
+/// The accelerator the crate is compiled for, or the CPU if it fails to initialize.
 pub static DEVICE: Lazy<Device> =
-    Lazy::new(|| Device::new_metal(0).expect("No Metal device found."));
+    Lazy::new(|| select_device(true).expect("Device selection with fallback can't fail."));
+
+/// A device to run models on, parsed from names like `cpu`, `cuda:1` or `metal:0`. The index
+/// defaults to 0 if it is left out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceSpec {
+    Cpu,
+    Cuda(usize),
+    Metal(usize),
+}
+
+impl FromStr for DeviceSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, index) = match s.split_once(':') {
+            Some((kind, index)) => {
+                let index = index.parse().map_err(|_| {
+                    Error::InvalidArgument("Device index should be a non-negative integer")
+                })?;
+                (kind, Some(index))
+            }
+            None => (s, None),
+        };
+
+        match (kind.to_ascii_lowercase().as_str(), index) {
+            ("cpu", None) => Ok(Self::Cpu),
+            ("cuda", index) => Ok(Self::Cuda(index.unwrap_or(0))),
+            ("metal", index) => Ok(Self::Metal(index.unwrap_or(0))),
+            ("cpu", Some(_)) => Err(Error::InvalidArgument("The CPU device has no index")),
+            _ => Err(Error::InvalidArgument(
+                "Unknown device, expected `cpu`, `cuda[:<index>]` or `metal[:<index>]`",
+            )),
+        }
+    }
+}
+
+/// Formats the device like it is parsed, e.g. `cuda:1`.
+impl fmt::Display for DeviceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cpu => write!(f, "cpu"),
+            Self::Cuda(index) => write!(f, "cuda:{index}"),
+            Self::Metal(index) => write!(f, "metal:{index}"),
+        }
+    }
+}
+
+impl DeviceSpec {
+    /// The accelerator the crate is compiled for (CUDA or Metal), or the CPU without one.
+    pub fn compiled() -> Self {
+        #[cfg(feature = "metal")]
+        return Self::Metal(0);
 
-#[cfg(feature = "cuda")]
-pub static DEVICE: Lazy<Device> = Lazy::new(|| Device::new_cuda(0).expect("No CUDA device found."));
+        #[cfg(feature = "cuda")]
+        return Self::Cuda(0);
 
-#[cfg(not(any(feature = "metal", feature = "cuda")))]
-pub static DEVICE: Lazy<Device> = Lazy::new(|| Device::Cpu);
+        #[cfg(not(any(feature = "metal", feature = "cuda")))]
+        Self::Cpu
+    }
+
+    /// Initialize the device. Fails for accelerators the crate isn't compiled for.
+    pub fn init(self) -> Result<Device> {
+        Ok(match self {
+            Self::Cpu => Device::Cpu,
+            Self::Cuda(index) => Device::new_cuda(index)?,
+            Self::Metal(index) => Device::new_metal(index)?,
+        })
+    }
+}
+
+/// Initialize the accelerator the crate is compiled for (CUDA or Metal), or the CPU without one.
+///
+/// If the accelerator fails to initialize, fall back to the CPU with a warning, unless
+/// `fallback` is false.
+pub fn select_device(fallback: bool) -> Result<Device> {
+    select_device_spec(DeviceSpec::compiled(), fallback)
+}
+
+/// Initialize a device. If it fails to initialize, fall back to the CPU with a warning, unless
+/// `fallback` is false.
+pub fn select_device_spec(spec: DeviceSpec, fallback: bool) -> Result<Device> {
+    match spec.init() {
+        Ok(device) => Ok(device),
+        Err(e) if fallback => {
+            tracing::warn!("FALLING BACK TO CPU: failed to initialize {spec:?}: {e}");
+            Ok(Device::Cpu)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Errors that loading on the CPU instead of an accelerator may avoid, such as running out of
+/// device memory or an operation the device doesn't support.
+pub trait DeviceError {
+    fn is_device_error(&self) -> bool;
+}
+
+impl DeviceError for candle_core::Error {
+    fn is_device_error(&self) -> bool {
+        use candle_core::Error as E;
+        match self {
+            E::Context { inner, .. }
+            | E::WithPath { inner, .. }
+            | E::WithBacktrace { inner, .. } => inner.is_device_error(),
+            E::Cuda(_)
+            | E::Metal(_)
+            | E::NotCompiledWithCudaSupport
+            | E::NotCompiledWithMetalSupport
+            | E::UnsupportedDTypeForOp(..)
+            | E::DeviceMismatchBinaryOp { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl DeviceError for Error {
+    fn is_device_error(&self) -> bool {
+        matches!(self, Error::Candle(e) if e.is_device_error())
+    }
+}
+
+impl DeviceError for anyhow::Error {
+    fn is_device_error(&self) -> bool {
+        self.downcast_ref::<Error>()
+            .is_some_and(DeviceError::is_device_error)
+            || self
+                .downcast_ref::<candle_core::Error>()
+                .is_some_and(DeviceError::is_device_error)
+    }
+}
+
+/// Load something on `device` with `load`. If that fails on an accelerator with a
+/// [`DeviceError`], retry on the CPU with a warning, unless `fallback` is false. Other errors,
+/// such as missing files, are returned as they would fail on the CPU as well.
+pub fn load_with_fallback<T, E>(
+    device: &Device,
+    fallback: bool,
+    load: impl Fn(&Device) -> std::result::Result<T, E>,
+) -> std::result::Result<T, E>
+where
+    E: fmt::Display + DeviceError,
+{
+    load_with_retry(device, fallback && !device.is_cpu(), load)
+}
+
+fn load_with_retry<T, E>(
+    device: &Device,
+    retry: bool,
+    load: impl Fn(&Device) -> std::result::Result<T, E>,
+) -> std::result::Result<T, E>
+where
+    E: fmt::Display + DeviceError,
+{
+    match load(device) {
+        Err(e) if retry && e.is_device_error() => {
+            tracing::warn!("FALLING BACK TO CPU: failed to load on {device:?}: {e}");
+            load(&Device::Cpu)
+        }
+        result => result,
+    }
+}
 
 pub fn print_device_info() {
     #[cfg(not(any(feature = "metal", feature = "cuda")))]
@@ -24,3 +183,71 @@ pub fn print_device_info() {
     #[cfg(feature = "metal")]
     tracing::info!("Using Metal");
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn test_parse_device_spec() {
+        assert_eq!("cpu".parse::<DeviceSpec>().unwrap(), DeviceSpec::Cpu);
+        assert_eq!("cuda".parse::<DeviceSpec>().unwrap(), DeviceSpec::Cuda(0));
+        assert_eq!("cuda:1".parse::<DeviceSpec>().unwrap(), DeviceSpec::Cuda(1));
+        assert_eq!(
+            "Metal:0".parse::<DeviceSpec>().unwrap(),
+            DeviceSpec::Metal(0)
+        );
+        assert!("cuda:-1".parse::<DeviceSpec>().is_err());
+        assert!("cpu:0".parse::<DeviceSpec>().is_err());
+        assert!("tpu".parse::<DeviceSpec>().is_err());
+
+        for spec in [DeviceSpec::Cpu, DeviceSpec::Cuda(1), DeviceSpec::Metal(0)] {
+            assert_eq!(spec.to_string().parse::<DeviceSpec>().unwrap(), spec);
+        }
+    }
+
+    #[test]
+    fn test_load_with_fallback() {
+        // Nothing to fall back from on the CPU
+        let result: Result<()> =
+            load_with_fallback(&Device::Cpu, true, |_| Err(Error::ModelLoad("test")));
+        assert!(result.is_err());
+
+        let devices = RefCell::new(Vec::new());
+        let result: Result<usize> = load_with_fallback(&Device::Cpu, true, |device| {
+            devices.borrow_mut().push(device.is_cpu());
+            Ok(devices.borrow().len())
+        });
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(devices.into_inner(), vec![true]);
+    }
+
+    #[test]
+    fn test_fallback_only_on_device_errors() {
+        // Number of attempts to load, retrying as if the first attempt was on an accelerator
+        let attempts = |error: fn() -> Error| {
+            let attempts = RefCell::new(0);
+            let result: Result<()> = load_with_retry(&Device::Cpu, true, |_| {
+                *attempts.borrow_mut() += 1;
+                Err(error())
+            });
+            assert!(result.is_err());
+            attempts.into_inner()
+        };
+        let out_of_memory = || Error::Candle(candle_core::Error::Cuda("out of memory".into()));
+
+        assert_eq!(attempts(out_of_memory), 2);
+        assert_eq!(attempts(|| Error::ModelLoad("test")), 1);
+        assert_eq!(
+            attempts(|| Error::Candle(candle_core::Error::CannotFindTensor {
+                path: "weight".to_string()
+            })),
+            1
+        );
+
+        let context = candle_core::Error::Cuda("out of memory".into()).context("loading");
+        assert!(anyhow::Error::from(Error::Candle(context)).is_device_error());
+        assert!(!anyhow::anyhow!("missing file").is_device_error());
+    }
+}
@@ -0,0 +1,275 @@
+//! Asymmetric (query/document) models
+//!
+//! Retrieval models can embed queries and documents differently. `sentence-transformers` `Asym`
+//! models have a separate transformer for each, listed as branches of the `Asym` module, and
+//! other models expect a prompt (prefix) for each, configured in
+//! `config_sentence_transformers.json`. A [`DualEncoder`] loads both sides of a model and routes
+//! queries and documents to the right one.
+
+use candle_core::{DType, Tensor};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::core::embedder::EmbedOutput;
+use crate::core::repo::ModelRepo;
+use crate::{Device, PoolingStrategy, Result, SentenceTransformer};
+
+pub(crate) const ST_CONFIG_FILE: &str = "config_sentence_transformers.json";
+
+/// Branch of an asymmetric model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsymBranch {
+    Query,
+    Document,
+}
+
+/// Embeds queries and documents, each with their own model branch or prompt.
+///
+/// For symmetric models, both sides share the same model, so it is only loaded once.
+#[derive(Clone)]
+pub struct DualEncoder {
+    query: SentenceTransformer,
+    document: SentenceTransformer,
+    query_prompt: Option<String>,
+    document_prompt: Option<String>,
+    asymmetric: bool,
+}
+
+impl DualEncoder {
+    pub(crate) fn from_model_repo(
+        model_repo: &ModelRepo,
+        device: &Device,
+        dtype: DType,
+        pooling_strategy: Option<PoolingStrategy>,
+        quantized: bool,
+    ) -> Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "dual-from-folder");
+        let _enter = span.enter();
+
+        let load = |branch| {
+            SentenceTransformer::from_model_repo(
+                model_repo,
+                device,
+                dtype,
+                pooling_strategy,
+                Some(branch),
+                quantized,
+            )
+        };
+
+        let asymmetric = model_repo.is_asymmetric()?;
+        let query = load(AsymBranch::Query)?;
+        let document = match asymmetric {
+            true => load(AsymBranch::Document)?,
+            false => query.clone(),
+        };
+
+        let (query_prompt, document_prompt) = match model_repo.read_file(ST_CONFIG_FILE)? {
+            Some(st_config) => parse_prompts(&st_config)?,
+            None => (None, None),
+        };
+
+        Ok(Self {
+            query,
+            document,
+            query_prompt,
+            document_prompt,
+            asymmetric,
+        })
+    }
+
+    /// Whether queries and documents are embedded by different model branches.
+    pub fn is_asymmetric(&self) -> bool {
+        self.asymmetric
+    }
+
+    pub fn query_model(&self) -> &SentenceTransformer {
+        &self.query
+    }
+
+    pub fn document_model(&self) -> &SentenceTransformer {
+        &self.document
+    }
+
+    /// Prompt prepended to queries, if the model has one.
+    pub fn query_prompt(&self) -> Option<&str> {
+        self.query_prompt.as_deref()
+    }
+
+    /// Prompt prepended to documents, if the model has one.
+    pub fn document_prompt(&self) -> Option<&str> {
+        self.document_prompt.as_deref()
+    }
+
+    pub fn encode_queries<S: AsRef<str>>(&self, queries: &[S], normalize: bool) -> Result<Tensor> {
+        Ok(self
+            .encode_queries_with_usage(queries, normalize)?
+            .into_tensor())
+    }
+
+    pub fn encode_queries_with_usage<S: AsRef<str>>(
+        &self,
+        queries: &[S],
+        normalize: bool,
+    ) -> Result<EmbedOutput> {
+        let span = tracing::span!(tracing::Level::TRACE, "dual-encode-queries");
+        let _enter = span.enter();
+
+        self.query.encode_batch_with_usage(
+            with_prompt(self.query_prompt.as_deref(), queries),
+            normalize,
+        )
+    }
+
+    pub fn encode_documents<S: AsRef<str>>(
+        &self,
+        documents: &[S],
+        normalize: bool,
+    ) -> Result<Tensor> {
+        Ok(self
+            .encode_documents_with_usage(documents, normalize)?
+            .into_tensor())
+    }
+
+    pub fn encode_documents_with_usage<S: AsRef<str>>(
+        &self,
+        documents: &[S],
+        normalize: bool,
+    ) -> Result<EmbedOutput> {
+        let span = tracing::span!(tracing::Level::TRACE, "dual-encode-documents");
+        let _enter = span.enter();
+
+        self.document.encode_batch_with_usage(
+            with_prompt(self.document_prompt.as_deref(), documents),
+            normalize,
+        )
+    }
+}
+
+fn with_prompt<S: AsRef<str>>(prompt: Option<&str>, sentences: &[S]) -> Vec<String> {
+    let prompt = prompt.unwrap_or_default();
+    sentences
+        .iter()
+        .map(|sentence| format!("{prompt}{}", sentence.as_ref()))
+        .collect()
+}
+
+/// Get the query and document prompts from `config_sentence_transformers.json`. The document
+/// prompt is named `document`, `passage`, `doc` or `corpus`, depending on the model.
+fn parse_prompts(st_config: &str) -> Result<(Option<String>, Option<String>)> {
+    #[derive(Deserialize)]
+    struct StConfig {
+        #[serde(default)]
+        prompts: HashMap<String, String>,
+    }
+
+    let StConfig { mut prompts } = serde_json::from_str(st_config)?;
+    let mut take = |names: &[&str]| {
+        names
+            .iter()
+            .find_map(|name| prompts.remove(*name))
+            .filter(|prompt| !prompt.is_empty())
+    };
+
+    let query = take(&["query"]);
+    let document = take(&["document", "passage", "doc", "corpus"]);
+
+    Ok((query, document))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::test_utils::create_tiny_bert_repo;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn assert_close(a: &Tensor, b: &Tensor) -> Result<()> {
+        let diff = (a - b)?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert!(diff < 1e-6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_asymmetric_model() -> Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path();
+        let query_dir = root.join("0_Asym/query_0_Transformer");
+        let doc_dir = root.join("0_Asym/doc_1_Transformer");
+        for branch_dir in [&query_dir, &doc_dir] {
+            fs::create_dir_all(branch_dir)?;
+            create_tiny_bert_repo(branch_dir)?;
+        }
+        fs::write(
+            root.join("modules.json"),
+            r#"[
+                {"idx": 0, "name": "0", "path": "0_Asym", "type": "sentence_transformers.models.Asym"},
+                {"idx": 1, "name": "1", "path": "1_Pooling", "type": "sentence_transformers.models.Pooling"}
+            ]"#,
+        )?;
+        fs::write(
+            root.join("0_Asym/config.json"),
+            r#"{
+                "types": {
+                    "query_0_Transformer": "sentence_transformers.models.Transformer",
+                    "doc_1_Transformer": "sentence_transformers.models.Transformer"
+                },
+                "structure": {"query": ["query_0_Transformer"], "doc": ["doc_1_Transformer"]},
+                "parameters": {"allow_empty_key": true}
+            }"#,
+        )?;
+        fs::create_dir_all(root.join("1_Pooling"))?;
+        fs::copy(
+            query_dir.join("1_Pooling/config.json"),
+            root.join("1_Pooling/config.json"),
+        )?;
+
+        // Plain sentence transformers can't choose a branch
+        let builder = || SentenceTransformer::builder().with_model_folder(root);
+        assert!(builder().build().is_err());
+
+        let dual = builder().build_dual()?;
+        assert!(dual.is_asymmetric());
+
+        let sentences = ["The cat sits outside", "I love pasta"];
+        let load = |dir| {
+            SentenceTransformer::builder()
+                .with_model_folder(dir)
+                .build()
+        };
+        let expected_queries = load(&query_dir)?.encode_batch(sentences.to_vec(), true)?;
+        let expected_documents = load(&doc_dir)?.encode_batch(sentences.to_vec(), true)?;
+
+        let queries = dual.encode_queries(&sentences, true)?;
+        let documents = dual.encode_documents(&sentences, true)?;
+        assert_close(&queries, &expected_queries)?;
+        assert_close(&documents, &expected_documents)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prompts() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        fs::write(
+            dir.path().join(ST_CONFIG_FILE),
+            r#"{"prompts": {"query": "query: ", "passage": "passage: "}, "default_prompt_name": null}"#,
+        )?;
+
+        let dual = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build_dual()?;
+        assert!(!dual.is_asymmetric());
+        assert_eq!(dual.query_prompt(), Some("query: "));
+        assert_eq!(dual.document_prompt(), Some("passage: "));
+
+        let model = dual.query_model();
+        let expected = model.encode_batch(vec!["query: Birds fly"], true)?;
+        assert_close(&dual.encode_queries(&["Birds fly"], true)?, &expected)?;
+        let expected = model.encode_batch(vec!["passage: Birds fly"], true)?;
+        assert_close(&dual.encode_documents(&["Birds fly"], true)?, &expected)?;
+
+        Ok(())
+    }
+}
@@ -1,18 +1,42 @@
 use candle_core::{DType, Device, IndexOp, Module, Tensor, D};
 use candle_nn::VarBuilder;
+use candle_transformers::models::debertav2::{
+    Config as DebertaV2Config, DebertaV2Model as _DebertaV2Model,
+};
+use candle_transformers::models::modernbert::{Config as ModernBertConfig, ModernBert};
+use candle_transformers::models::qwen2::{Config as Qwen2Config, Model as _Qwen2Model};
+use candle_transformers::models::xlm_roberta::Config as _XlmRobertaConfig;
+use candle_transformers::quantized_var_builder::VarBuilder as QuantizedVarBuilder;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Arc;
 
-use tokenizers::{EncodeInput, Tokenizer};
+use tokenizers::{
+    pad_encodings, EncodeInput, Encoding, PaddingDirection, Tokenizer, TruncationDirection,
+};
 
 // Re-exports
 pub use candle_transformers::models::{
     bert::BertModel, distilbert::DistilBertModel, jina_bert::BertModel as JinaBertModel,
+    xlm_roberta::XLMRobertaModel,
 };
 
-use crate::core::config::model::{BertConfig, EmbedderConfig, ModelType};
+use crate::core::config::model::{BertConfig, EmbedderConfig, ModelType, XlmRobertaConfig};
+use crate::core::convert::read_gguf_dequantized;
 use crate::core::repo::ModelWeightsPath;
+use crate::core::session::IdsBuffer;
+use crate::core::splade::{splade_pool, to_sparse, SparseValue};
 use crate::core::utils::normalize_l2;
+pub use crate::models::jina_v3::JinaV3Model;
+pub use crate::models::mpnet::MPNetModel;
+pub use crate::models::nomic_bert::NomicBertModel;
+#[cfg(feature = "onnx")]
+pub use crate::models::onnx::OnnxModel;
+pub use crate::models::quantized_bert::QuantizedBertModel;
+pub use crate::models::static_embedding::StaticEmbeddingModel;
+pub use crate::models::t5::T5EncoderModel;
 use crate::pooling::PoolingStrategy;
-use crate::{Result, Usage};
+use crate::{Error, InputUsage, Result, Usage};
 
 pub(crate) fn load_model(
     vb: VarBuilder,
@@ -22,32 +46,100 @@ where
 {
     match model_config {
         EmbedderConfig::Bert(cfg) => Ok(match cfg {
-            BertConfig::Bert(cfg_inner) => Box::new(BertModel::load(vb, &cfg_inner)?),
+            BertConfig::Bert(cfg_inner) => Box::new(BertModel::load(
+                base_model_var_builder(vb, "bert"),
+                &cfg_inner,
+            )?),
             BertConfig::JinaBert(cfg_inner) => Box::new(JinaBertModel::new(vb, &cfg_inner)?),
         }),
-        EmbedderConfig::DistilBert(cfg) => Ok(Box::new(DistilBertModel::load(vb, &cfg)?)),
+        EmbedderConfig::XlmRoberta(XlmRobertaConfig::JinaV3(cfg)) => {
+            // Checkpoints save the weights with the `roberta.` prefix of the model with adapters
+            let vb = match vb.contains_tensor("roberta.emb_ln.weight") {
+                true => vb.pp("roberta"),
+                false => vb,
+            };
+            Ok(Box::new(JinaV3Model::load(vb, &cfg)?))
+        }
+        EmbedderConfig::XlmRoberta(XlmRobertaConfig::XlmRoberta(cfg))
+        | EmbedderConfig::Camembert(cfg)
+        | EmbedderConfig::Roberta(cfg) => Ok(Box::new(RobertaModel::load(vb, &cfg)?)),
+        EmbedderConfig::DebertaV2(cfg) => Ok(Box::new(DebertaV2Model::load(vb, &cfg)?)),
+        EmbedderConfig::ModernBert(cfg) => Ok(Box::new(ModernBertModel::load(vb, &cfg)?)),
+        EmbedderConfig::T5(cfg) => Ok(Box::new(T5EncoderModel::load(vb, &cfg)?)),
+        EmbedderConfig::Qwen2(cfg) => Ok(Box::new(Qwen2Model::load(vb, &cfg)?)),
+        EmbedderConfig::MPNet(cfg) => Ok(Box::new(MPNetModel::load(vb, &cfg)?)),
+        EmbedderConfig::NomicBert(cfg) => Ok(Box::new(NomicBertModel::load(vb, &cfg)?)),
+        EmbedderConfig::DistilBert(cfg) => Ok(Box::new(DistilBertModel::load(
+            base_model_var_builder(vb, "distilbert"),
+            &cfg,
+        )?)),
+        EmbedderConfig::Model2Vec(cfg) => Ok(Box::new(StaticEmbeddingModel::load(vb, &cfg)?)),
     }
 }
 
-pub(crate) fn load_pretrained_model(
-    model_weights_path: ModelWeightsPath,
+/// Whether models with this configuration can run on quantized weights, see
+/// [`load_quantized_model`]. Others are loaded from the dequantized weights.
+pub(crate) fn supports_quantized(model_config: &EmbedderConfig) -> bool {
+    matches!(model_config, EmbedderConfig::Bert(BertConfig::Bert(_)))
+}
+
+/// Load a model that runs on quantized (GGUF) weights with the quantized kernels of candle.
+pub(crate) fn load_quantized_model(
+    vb: QuantizedVarBuilder,
     model_config: EmbedderConfig,
-    device: &Device,
 ) -> Result<Box<dyn EmbedderModel>> {
-    let vb = match model_weights_path {
-        ModelWeightsPath::Pth(path) => VarBuilder::from_pth(&path, DType::F32, device)?,
+    match model_config {
+        EmbedderConfig::Bert(BertConfig::Bert(cfg)) => {
+            let vb = match vb.contains_key("embeddings.word_embeddings.weight") {
+                true => vb,
+                false => vb.pp("bert"),
+            };
+            Ok(Box::new(QuantizedBertModel::load(vb, &cfg)?))
+        }
+        _ => Err(Error::ModelLoad(
+            "Quantized inference is not supported for this model type.",
+        )),
+    }
+}
+
+pub(crate) fn load_var_builder(
+    model_weights_path: &ModelWeightsPath,
+    device: &Device,
+    dtype: DType,
+) -> Result<VarBuilder<'static>> {
+    Ok(match model_weights_path {
+        ModelWeightsPath::Pth(path) => VarBuilder::from_pth(path, dtype, device)?,
         ModelWeightsPath::Safetensors(path) => unsafe {
-            VarBuilder::from_mmaped_safetensors(&[path], DType::F32, device)?
+            VarBuilder::from_mmaped_safetensors(&[path], dtype, device)?
         },
-    };
-
-    load_model(vb, model_config)
+        ModelWeightsPath::ShardedSafetensors(paths) => unsafe {
+            VarBuilder::from_mmaped_safetensors(paths, dtype, device)?
+        },
+        ModelWeightsPath::Gguf(path) => {
+            VarBuilder::from_tensors(read_gguf_dequantized(path, device)?, dtype, device)
+        }
+        ModelWeightsPath::Embedded(bytes) => {
+            VarBuilder::from_slice_safetensors(bytes, dtype, device)?
+        }
+        #[cfg(feature = "onnx")]
+        ModelWeightsPath::Onnx(_) => {
+            return Err(Error::ModelLoad(
+                "ONNX weights can only be run by ONNX Runtime, not loaded as tensors.",
+            ))
+        }
+    })
 }
 
 /// Trait for embedder models
 pub trait EmbedderModel: Send + Sync {
     fn encode(&self, token_ids: &Tensor) -> Result<Tensor>;
 
+    /// Encode token ids with the segment (token type) id of each token, for sentence pairs.
+    /// Models without segment embeddings ignore them.
+    fn encode_with_type_ids(&self, token_ids: &Tensor, _token_type_ids: &Tensor) -> Result<Tensor> {
+        self.encode(token_ids)
+    }
+
     #[inline]
     fn encode_with_pooling(
         &self,
@@ -60,13 +152,86 @@ pub trait EmbedderModel: Send + Sync {
     }
 
     fn get_device(&self) -> &Device;
+
+    /// Names of the tasks the model has an adapter for, see [`Self::encode_with_task`].
+    fn tasks(&self) -> &[String] {
+        &[]
+    }
+
+    /// Encode token ids with the adapter of a task, by index in [`Self::tasks`]. Models without
+    /// adapters ignore the task.
+    fn encode_with_task(&self, token_ids: &Tensor, _task: usize) -> Result<Tensor> {
+        self.encode(token_ids)
+    }
+}
+
+/// Runs a model with the adapter of one of its tasks, see [`EmbedderModel::tasks`].
+pub(crate) struct TaskModel {
+    model: Arc<dyn EmbedderModel>,
+    task: usize,
+}
+
+impl TaskModel {
+    pub(crate) fn new(model: Arc<dyn EmbedderModel>, task: usize) -> Self {
+        Self { model, task }
+    }
+}
+
+impl EmbedderModel for TaskModel {
+    fn encode(&self, token_ids: &Tensor) -> Result<Tensor> {
+        self.model.encode_with_task(token_ids, self.task)
+    }
+
+    fn get_device(&self) -> &Device {
+        self.model.get_device()
+    }
+
+    fn tasks(&self) -> &[String] {
+        self.model.tasks()
+    }
+
+    fn encode_with_task(&self, token_ids: &Tensor, task: usize) -> Result<Tensor> {
+        self.model.encode_with_task(token_ids, task)
+    }
 }
 
 impl EmbedderModel for BertModel {
     #[inline]
     fn encode(&self, token_ids: &Tensor) -> Result<Tensor> {
         let token_type_ids = token_ids.zeros_like()?;
-        Ok(self.forward(token_ids, &token_type_ids)?)
+        Ok(self.forward(token_ids, &token_type_ids, None)?)
+    }
+
+    #[inline]
+    fn encode_with_type_ids(&self, token_ids: &Tensor, token_type_ids: &Tensor) -> Result<Tensor> {
+        Ok(self.forward(token_ids, token_type_ids, None)?)
+    }
+
+    fn get_device(&self) -> &Device {
+        &self.device
+    }
+}
+
+impl EmbedderModel for StaticEmbeddingModel {
+    #[inline]
+    fn encode(&self, token_ids: &Tensor) -> Result<Tensor> {
+        Ok(self.forward(token_ids)?)
+    }
+
+    fn get_device(&self) -> &Device {
+        &self.device
+    }
+}
+
+impl EmbedderModel for QuantizedBertModel {
+    #[inline]
+    fn encode(&self, token_ids: &Tensor) -> Result<Tensor> {
+        self.encode_with_type_ids(token_ids, &token_ids.zeros_like()?)
+    }
+
+    #[inline]
+    fn encode_with_type_ids(&self, input_ids: &Tensor, token_type_ids: &Tensor) -> Result<Tensor> {
+        Ok(self.forward(input_ids, token_type_ids)?)
     }
 
     fn get_device(&self) -> &Device {
@@ -104,10 +269,280 @@ impl EmbedderModel for DistilBertModel {
     }
 }
 
+/// A RoBERTa or XLM-RoBERTa model.
+///
+/// Unlike BERT, the position ids of RoBERTa models are derived from the padding, and padding
+/// tokens have to be masked out of the attention.
+pub struct RobertaModel {
+    model: XLMRobertaModel,
+    pad_token_id: u32,
+    device: Device,
+}
+
+impl RobertaModel {
+    pub fn load(vb: VarBuilder, config: &_XlmRobertaConfig) -> Result<Self> {
+        let vb = base_model_var_builder(vb, "roberta");
+        let device = vb.device().clone();
+
+        Ok(Self {
+            model: XLMRobertaModel::new(config, vb)?,
+            pad_token_id: config.pad_token_id,
+            device,
+        })
+    }
+}
+
+impl EmbedderModel for RobertaModel {
+    #[inline]
+    fn encode(&self, token_ids: &Tensor) -> Result<Tensor> {
+        let attention_mask = token_ids.ne(self.pad_token_id)?;
+        let token_type_ids = token_ids.zeros_like()?;
+
+        Ok(self.model.forward(
+            token_ids,
+            &attention_mask,
+            &token_type_ids,
+            None,
+            None,
+            None,
+        )?)
+    }
+
+    fn get_device(&self) -> &Device {
+        &self.device
+    }
+}
+
+/// A ModernBERT model, with rotary position embeddings and alternating global and local
+/// (sliding window) attention layers.
+pub struct ModernBertModel {
+    model: ModernBert,
+    pad_token_id: u32,
+    device: Device,
+}
+
+impl ModernBertModel {
+    pub fn load(vb: VarBuilder, config: &ModernBertConfig) -> Result<Self> {
+        // Checkpoints of the base model are saved without the `model.` prefix of the masked
+        // language model
+        let vb = if vb.contains_tensor("embeddings.tok_embeddings.weight") {
+            vb.rename_f(|name| name.strip_prefix("model.").unwrap_or(name).to_string())
+        } else {
+            vb
+        };
+        let device = vb.device().clone();
+
+        Ok(Self {
+            model: ModernBert::load(vb, config)?,
+            pad_token_id: config.pad_token_id,
+            device,
+        })
+    }
+}
+
+impl EmbedderModel for ModernBertModel {
+    #[inline]
+    fn encode(&self, token_ids: &Tensor) -> Result<Tensor> {
+        let attention_mask = token_ids.ne(self.pad_token_id)?;
+        Ok(self.model.forward(token_ids, &attention_mask)?)
+    }
+
+    fn get_device(&self) -> &Device {
+        &self.device
+    }
+}
+
+impl EmbedderModel for T5EncoderModel {
+    #[inline]
+    fn encode(&self, token_ids: &Tensor) -> Result<Tensor> {
+        let attention_mask = token_ids.ne(self.pad_token_id)?;
+        Ok(self.forward(token_ids, &attention_mask)?)
+    }
+
+    fn get_device(&self) -> &Device {
+        &self.device
+    }
+}
+
+/// A Qwen2 decoder, e.g. of `Alibaba-NLP/gte-Qwen2-1.5B-instruct`.
+///
+/// The attention is causal, so padding at the end of a sentence doesn't change the states of
+/// its tokens, and the last token is the only one that has seen the whole sentence. These models
+/// are used with [`PoolingStrategy::LastToken`].
+pub struct Qwen2Model {
+    // The forward pass takes `&mut self` to cache keys and values for generation. Every batch
+    // runs on its own clone, which shares the weights, so concurrent batches don't wait on
+    // each other and the cache is dropped with the clone
+    model: _Qwen2Model,
+    device: Device,
+}
+
+impl Qwen2Model {
+    pub fn load(vb: VarBuilder, config: &Qwen2Config) -> Result<Self> {
+        // Checkpoints of the base model are saved without the `model.` prefix of the causal
+        // language model
+        let vb = if vb.contains_tensor("embed_tokens.weight") {
+            vb.rename_f(|name| name.strip_prefix("model.").unwrap_or(name).to_string())
+        } else {
+            vb
+        };
+        let device = vb.device().clone();
+
+        Ok(Self {
+            model: _Qwen2Model::new(config, vb)?,
+            device,
+        })
+    }
+}
+
+impl EmbedderModel for Qwen2Model {
+    #[inline]
+    fn encode(&self, token_ids: &Tensor) -> Result<Tensor> {
+        let mut model = self.model.clone();
+        Ok(model.forward(token_ids, 0, None)?)
+    }
+
+    fn get_device(&self) -> &Device {
+        &self.device
+    }
+}
+
+impl EmbedderModel for MPNetModel {
+    #[inline]
+    fn encode(&self, token_ids: &Tensor) -> Result<Tensor> {
+        let attention_mask = token_ids.ne(self.pad_token_id)?;
+        Ok(self.forward(token_ids, &attention_mask)?)
+    }
+
+    fn get_device(&self) -> &Device {
+        &self.device
+    }
+}
+
+/// A DeBERTa-v2 or DeBERTa-v3 model, with disentangled attention over the content and the
+/// relative positions of the tokens.
+pub struct DebertaV2Model {
+    model: _DebertaV2Model,
+    pad_token_id: u32,
+}
+
+impl DebertaV2Model {
+    pub fn load(vb: VarBuilder, config: &DebertaV2Config) -> Result<Self> {
+        let vb = base_model_var_builder(vb, "deberta");
+
+        Ok(Self {
+            model: _DebertaV2Model::load(vb, config)?,
+            pad_token_id: config.pad_token_id.unwrap_or(0) as u32,
+        })
+    }
+}
+
+impl EmbedderModel for DebertaV2Model {
+    #[inline]
+    fn encode(&self, token_ids: &Tensor) -> Result<Tensor> {
+        let attention_mask = token_ids.ne(self.pad_token_id)?.to_dtype(DType::I64)?;
+        Ok(self.model.forward(token_ids, None, Some(attention_mask))?)
+    }
+
+    fn get_device(&self) -> &Device {
+        &self.model.device
+    }
+}
+
+/// Checkpoints of a base model are saved without a prefix, while checkpoints that include a
+/// task head prefix the base model weights with the name of the model, e.g. `roberta.`.
+fn base_model_var_builder<'a>(vb: VarBuilder<'a>, prefix: &str) -> VarBuilder<'a> {
+    if vb.contains_tensor("embeddings.word_embeddings.weight") {
+        vb
+    } else {
+        vb.pp(prefix)
+    }
+}
+
+impl EmbedderModel for JinaV3Model {
+    #[inline]
+    fn encode(&self, token_ids: &Tensor) -> Result<Tensor> {
+        self.encode_with_type_ids(token_ids, &token_ids.zeros_like()?)
+    }
+
+    #[inline]
+    fn encode_with_type_ids(&self, token_ids: &Tensor, token_type_ids: &Tensor) -> Result<Tensor> {
+        let attention_mask = token_ids.ne(self.pad_token_id)?;
+        Ok(self.forward(token_ids, token_type_ids, &attention_mask, None)?)
+    }
+
+    fn get_device(&self) -> &Device {
+        &self.device
+    }
+
+    fn tasks(&self) -> &[String] {
+        &self.tasks
+    }
+
+    fn encode_with_task(&self, token_ids: &Tensor, task: usize) -> Result<Tensor> {
+        let attention_mask = token_ids.ne(self.pad_token_id)?;
+        let token_type_ids = token_ids.zeros_like()?;
+        Ok(self.forward(token_ids, &token_type_ids, &attention_mask, Some(task))?)
+    }
+}
+
+impl EmbedderModel for NomicBertModel {
+    #[inline]
+    fn encode(&self, token_ids: &Tensor) -> Result<Tensor> {
+        self.encode_with_type_ids(token_ids, &token_ids.zeros_like()?)
+    }
+
+    #[inline]
+    fn encode_with_type_ids(&self, token_ids: &Tensor, token_type_ids: &Tensor) -> Result<Tensor> {
+        let attention_mask = token_ids.ne(self.pad_token_id)?;
+        Ok(self.forward(token_ids, token_type_ids, &attention_mask)?)
+    }
+
+    fn get_device(&self) -> &Device {
+        &self.device
+    }
+}
+
+/// Embeddings of a batch of inputs, along with their usage.
+///
+/// The embeddings stay on the device of the model, so they can be used in further candle
+/// computations (e.g. similarity search) without copying them to the host.
 #[derive(Debug)]
 pub struct EmbedOutput {
     pub embeddings: Tensor,
     pub usage: Usage,
+    /// Usage of each input, in the same order as the inputs
+    pub inputs: Vec<InputUsage>,
+}
+
+impl EmbedOutput {
+    /// Take the embeddings, of shape `(n_inputs, dim)`, without copying them.
+    pub fn into_tensor(self) -> Tensor {
+        self.embeddings
+    }
+
+    /// Device the embeddings are stored on.
+    pub fn device(&self) -> &Device {
+        self.embeddings.device()
+    }
+
+    /// The non-zero values of each embedding as `(index, value)` pairs, by increasing index.
+    /// Meant for sparse embeddings, such as those of SPLADE models.
+    pub fn sparse(&self) -> Result<Vec<Vec<SparseValue>>> {
+        to_sparse(&self.embeddings)
+    }
+
+    /// Move the embeddings to `device`. Doesn't copy if they are already on it.
+    pub fn to_device(self, device: &Device) -> Result<Self> {
+        if self.device().same_device(device) {
+            return Ok(self);
+        }
+
+        Ok(Self {
+            embeddings: self.embeddings.to_device(device)?,
+            ..self
+        })
+    }
 }
 
 /// Encodes a batch of sentences by tokenizing them and running encoding them with the core,
@@ -138,50 +573,290 @@ pub(crate) fn encode_batch_with_usage<'s, E>(
 where
     E: Into<EncodeInput<'s>> + Send,
 {
-    let tokens = tokenizer.encode_batch_fast(sentences, true)?;
+    encode_batch_with_buffer(model, tokenizer, sentences, model_type, normalize, None)
+}
+
+/// Like [`encode_batch_with_usage`], collecting the token ids in `ids_buffer`, if given, before
+/// copying them to the device, so the buffer can be reused across calls.
+pub(crate) fn encode_batch_with_buffer<'s, E>(
+    model: &dyn EmbedderModel,
+    tokenizer: &Tokenizer,
+    sentences: Vec<E>,
+    model_type: &ModelType,
+    normalize: bool,
+    ids_buffer: Option<&mut IdsBuffer>,
+) -> Result<EmbedOutput>
+where
+    E: Into<EncodeInput<'s>> + Send,
+{
+    let batch = tokenize_batch(model, tokenizer, sentences, ids_buffer)?;
+
+    encode_tokenized(model, &batch, model_type, normalize)
+}
+
+/// A batch of tokenized sentences, with the token ids copied to the device of the model.
+///
+/// Tokenizing and copying the token ids is independent of the model weights, so a batch can be
+/// prepared on another thread while the model is busy with the previous batch.
+#[derive(Debug, Clone)]
+pub struct TokenizedBatch {
+    encodings: Vec<Encoding>,
+    token_ids: Tensor,
+    /// Segment ids, only if some token isn't in the first segment (e.g. for sentence pairs)
+    type_ids: Option<Tensor>,
+    pad_id: u32,
+}
+
+impl TokenizedBatch {
+    pub fn encodings(&self) -> &[Encoding] {
+        &self.encodings
+    }
+
+    /// Token ids of the batch, of shape `(batch_size, sequence_length)`.
+    pub fn token_ids(&self) -> &Tensor {
+        &self.token_ids
+    }
+
+    /// Segment ids of the batch, of the same shape as the token ids. `None` if all tokens are
+    /// in the first segment.
+    pub fn type_ids(&self) -> Option<&Tensor> {
+        self.type_ids.as_ref()
+    }
+
+    /// Attention mask of the batch, of the same shape as the token ids: 1 for tokens and 0 for
+    /// padding.
+    pub fn attention_mask(&self) -> Result<Tensor> {
+        let mask: Vec<u32> = self
+            .encodings
+            .iter()
+            .flat_map(|encoding| encoding.get_attention_mask().iter().copied())
+            .collect();
+
+        Ok(Tensor::from_vec(
+            mask,
+            self.token_ids.shape(),
+            self.token_ids.device(),
+        )?)
+    }
+
+    pub fn len(&self) -> usize {
+        self.encodings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.encodings.is_empty()
+    }
+}
+
+/// Tokenize a batch of sentences and copy the token ids to the device of the model, collecting
+/// them in `ids_buffer` first if given.
+pub(crate) fn tokenize_batch<'s, E>(
+    model: &dyn EmbedderModel,
+    tokenizer: &Tokenizer,
+    sentences: Vec<E>,
+    ids_buffer: Option<&mut IdsBuffer>,
+) -> Result<TokenizedBatch>
+where
+    E: Into<EncodeInput<'s>> + Send,
+{
+    let encodings = tokenizer.encode_batch_fast(sentences, true)?;
 
-    let prompt_tokens = tokens.len() as u32;
+    batch_from_encodings(model, tokenizer, encodings, ids_buffer)
+}
 
-    let usage = Usage {
-        prompt_tokens,
-        total_tokens: prompt_tokens,
+/// Build encodings from sequences of token ids, which are used as they are, special tokens
+/// included, and copy them to the device of the model. Sequences longer than `max_length` are
+/// truncated, like sentences are by the tokenizer.
+pub(crate) fn batch_from_ids(
+    model: &dyn EmbedderModel,
+    tokenizer: &Tokenizer,
+    ids: Vec<Vec<u32>>,
+    max_length: Option<usize>,
+) -> Result<TokenizedBatch> {
+    let vocab_size = tokenizer.get_vocab_size(true) as u32;
+    let mut encodings = ids
+        .into_iter()
+        .map(|ids| {
+            if ids.is_empty() {
+                return Err(Error::InvalidArgument("Token ids should not be empty"));
+            }
+            if ids.iter().any(|&id| id >= vocab_size) {
+                return Err(Error::InvalidArgument(
+                    "Token id is out of the vocabulary of the model",
+                ));
+            }
+            let len = ids.len();
+            let tokens = ids
+                .iter()
+                .map(|&id| tokenizer.id_to_token(id).unwrap_or_default())
+                .collect();
+            let mut encoding = Encoding::new(
+                ids,
+                vec![0; len],
+                tokens,
+                vec![None; len],
+                vec![(0, 0); len],
+                vec![0; len],
+                vec![1; len],
+                Vec::new(),
+                HashMap::new(),
+            );
+            if let Some(max_length) = max_length {
+                encoding.truncate(max_length, 0, TruncationDirection::Right);
+            }
+            Ok(encoding)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(padding) = tokenizer.get_padding() {
+        pad_encodings(&mut encodings, padding)?;
+    }
+
+    batch_from_encodings(model, tokenizer, encodings, None)
+}
+
+/// Split a batch into sub-batches of at most `max_tokens` tokens, padding included, so that the
+/// activations of a large batch don't have to fit in memory at once. Sentences are grouped by
+/// length to keep the padding down, and each sub-batch comes with the positions of its
+/// sentences in the batch. A sentence longer than `max_tokens` makes up a sub-batch on its own.
+pub(crate) fn split_batch(
+    model: &dyn EmbedderModel,
+    tokenizer: &Tokenizer,
+    batch: &TokenizedBatch,
+    max_tokens: usize,
+) -> Result<Vec<(Vec<usize>, TokenizedBatch)>> {
+    let padding = tokenizer.get_padding();
+    let direction = match padding.map(|padding| padding.direction) {
+        Some(PaddingDirection::Left) => TruncationDirection::Left,
+        _ => TruncationDirection::Right,
     };
 
-    let token_ids = tokens
+    // Strip the padding, so that each sub-batch is only padded to its longest sentence
+    let mut sentences: Vec<(usize, Encoding)> = batch
+        .encodings
         .iter()
-        .map(|tokens| {
-            let tokens = tokens.get_ids().to_vec();
-
-            Tensor::new(tokens.as_slice(), model.get_device())
+        .cloned()
+        .map(|mut encoding| {
+            let len = encoding.get_attention_mask().iter().sum::<u32>() as usize;
+            let overflowing = encoding.take_overflowing();
+            encoding.truncate(len, 0, direction);
+            encoding.set_overflowing(overflowing);
+            encoding
         })
-        .collect::<candle_core::Result<Vec<_>>>()?;
+        .enumerate()
+        .collect();
+    sentences.sort_by_key(|(_, encoding)| Reverse(encoding.len()));
+
+    let mut sub_batches = Vec::new();
+    while !sentences.is_empty() {
+        // Sentences are sorted by length, so the first is the longest of the sub-batch
+        let size = (max_tokens / sentences[0].1.len().max(1)).clamp(1, sentences.len());
+        let rest = sentences.split_off(size);
+        let (positions, mut encodings): (Vec<usize>, Vec<Encoding>) =
+            std::mem::replace(&mut sentences, rest).into_iter().unzip();
+        if let Some(padding) = padding {
+            pad_encodings(&mut encodings, padding)?;
+        }
+        sub_batches.push((
+            positions,
+            batch_from_encodings(model, tokenizer, encodings, None)?,
+        ));
+    }
 
-    let token_ids = Tensor::stack(&token_ids, 0)?;
+    Ok(sub_batches)
+}
+
+/// Copy the token ids of encodings padded by `tokenizer` to the device of the model,
+/// collecting them in `ids_buffer` first if given.
+pub(crate) fn batch_from_encodings(
+    model: &dyn EmbedderModel,
+    tokenizer: &Tokenizer,
+    encodings: Vec<Encoding>,
+    ids_buffer: Option<&mut IdsBuffer>,
+) -> Result<TokenizedBatch> {
+    // All encodings are padded to the same length
+    let seq_len = encodings.first().map_or(0, |encoding| encoding.len());
+    let sequences = encodings.iter().map(Encoding::get_ids);
+    let shape = (encodings.len(), seq_len);
+    let token_ids = match ids_buffer {
+        Some(ids_buffer) => ids_buffer.copy_to_device(sequences, shape, model.get_device())?,
+        None => Tensor::from_vec(
+            sequences.flatten().copied().collect::<Vec<u32>>(),
+            shape,
+            model.get_device(),
+        )?,
+    };
+
+    // Sentence pairs are tokenized as e.g. `[CLS] a [SEP] b [SEP]`, with the second sentence
+    // in its own segment
+    let has_segments = encodings
+        .iter()
+        .any(|encoding| encoding.get_type_ids().iter().any(|&type_id| type_id != 0));
+    let type_ids = if has_segments {
+        let type_ids: Vec<u32> = encodings
+            .iter()
+            .flat_map(|encoding| encoding.get_type_ids().iter().copied())
+            .collect();
+        Some(Tensor::from_vec(
+            type_ids,
+            (encodings.len(), seq_len),
+            model.get_device(),
+        )?)
+    } else {
+        None
+    };
+
+    Ok(TokenizedBatch {
+        encodings,
+        token_ids,
+        type_ids,
+        pad_id: tokenizer.get_padding().map_or(0, |pp| pp.pad_id),
+    })
+}
+
+/// Run the model on a tokenized batch, returning the embedding of each token of shape
+/// `(batch, seq_len, hidden_size)`.
+pub(crate) fn encode_tokens(model: &dyn EmbedderModel, batch: &TokenizedBatch) -> Result<Tensor> {
+    let token_ids = &batch.token_ids;
 
     tracing::trace!("running inference on batch {:?}", token_ids.shape());
 
-    // let embeddings = core.encode(&token_ids)?;
-    let embeddings = model.encode(&token_ids)?;
+    match &batch.type_ids {
+        Some(type_ids) => model.encode_with_type_ids(token_ids, type_ids),
+        None => model.encode(token_ids),
+    }
+}
 
+/// Run the model on a tokenized batch and pool the token embeddings.
+pub(crate) fn encode_tokenized(
+    model: &dyn EmbedderModel,
+    batch: &TokenizedBatch,
+    model_type: &ModelType,
+    normalize: bool,
+) -> Result<EmbedOutput> {
     let pooling_strategy = match model_type {
         ModelType::Classifier => &PoolingStrategy::Cls, // TODO: Is this correct?
         ModelType::Embedding(ps) => ps,
     };
 
-    let embeddings = match pooling_strategy {
-        PoolingStrategy::Cls => embeddings.i((.., 0))?,
-        PoolingStrategy::Mean => {
-            let pad_id = tokenizer.get_padding().map_or(0, |pp| pp.pad_id);
+    encode_tokenized_with(model, batch, normalize, |embeddings| {
+        pool(embeddings, batch, pooling_strategy)
+    })
+}
 
-            let attention_mask = token_ids
-                .ne(pad_id)?
-                .unsqueeze(D::Minus1)?
-                .to_dtype(embeddings.dtype())?;
+/// Run the model on a tokenized batch and pool the token embeddings with `pool`.
+pub(crate) fn encode_tokenized_with(
+    model: &dyn EmbedderModel,
+    batch: &TokenizedBatch,
+    normalize: bool,
+    pool: impl FnOnce(&Tensor) -> Result<Tensor>,
+) -> Result<EmbedOutput> {
+    let inputs: Vec<InputUsage> = batch.encodings.iter().map(InputUsage::from).collect();
+    let usage = Usage::from(inputs.as_slice());
 
-            embeddings.broadcast_mul(&attention_mask)?.sum(1)?
-        }
-        PoolingStrategy::Splade => panic!("SPLADE is not yet implemented."),
-    };
+    let embeddings = pool(&encode_tokens(model, batch)?)?;
+
+    // Embeddings are always returned in full precision, regardless of the model data type
+    let embeddings = embeddings.to_dtype(DType::F32)?;
 
     // Normalize embeddings (if required)
     let embeddings = {
@@ -193,7 +868,58 @@ where
     };
 
     tracing::trace!("generated embeddings {:?}", embeddings.shape());
-    Ok(EmbedOutput { embeddings, usage })
+    Ok(EmbedOutput {
+        embeddings,
+        usage,
+        inputs,
+    })
+}
+
+/// Pool the token embeddings of a batch, of shape `(batch, seq_len, hidden_size)`.
+fn pool(
+    embeddings: &Tensor,
+    batch: &TokenizedBatch,
+    pooling_strategy: &PoolingStrategy,
+) -> Result<Tensor> {
+    let token_ids = &batch.token_ids;
+
+    Ok(match pooling_strategy {
+        // The pooler is applied to the CLS token by the sentence transformer
+        PoolingStrategy::Cls | PoolingStrategy::Pooler => embeddings.i((.., 0))?,
+        PoolingStrategy::Mean => {
+            let attention_mask = token_ids
+                .ne(batch.pad_id)?
+                .unsqueeze(D::Minus1)?
+                .to_dtype(embeddings.dtype())?;
+
+            // Averaged over the tokens that aren't padding before the output modules, whose
+            // activations make the result depend on the scale
+            embeddings
+                .broadcast_mul(&attention_mask)?
+                .sum(1)?
+                .broadcast_div(&attention_mask.sum(1)?.maximum(1.)?)?
+        }
+        PoolingStrategy::LastToken => {
+            // The last token that isn't padding, which is the last token of the sequence if
+            // the tokenizer pads on the left
+            let last_tokens = batch
+                .encodings
+                .iter()
+                .enumerate()
+                .map(|(i, encoding)| {
+                    let last = encoding
+                        .get_attention_mask()
+                        .iter()
+                        .rposition(|&mask| mask == 1)
+                        .unwrap_or(0);
+                    embeddings.i((i, last))
+                })
+                .collect::<candle_core::Result<Vec<_>>>()?;
+
+            Tensor::stack(&last_tokens, 0)?
+        }
+        PoolingStrategy::Splade => splade_pool(embeddings, &token_ids.ne(batch.pad_id)?)?,
+    })
 }
 
 /// Encodes a batch of sentences using the given `core` and `tokenizer`.
@@ -225,11 +951,20 @@ where
 mod test {
     use super::*;
     use crate::core::repo::ModelRepo;
+    use crate::core::test_utils::{
+        create_tiny_bert_repo, create_tiny_deberta_v3_repo, create_tiny_modernbert_repo,
+        create_tiny_mpnet_repo, create_tiny_nomic_bert_repo, create_tiny_qwen2_repo,
+        create_tiny_t5_repo, create_tiny_xlm_roberta_repo, TINY_HIDDEN_SIZE,
+    };
+    use crate::core::utils::cosine_similarity;
+    use crate::SentenceTransformer;
     use std::path::Path;
+    use tempfile::tempdir;
 
     const BERT_PATH: &str = "tests/fixtures/all-MiniLM-L6-v2/";
     const JINABERT_PATH: &str = "tests/fixtures/jina-embeddings-v2-base-en/";
     const DISTILBERT_PATH: &str = "tests/fixtures/multi-qa-distilbert-dot-v1/";
+    const MPNET_PATH: &str = "tests/fixtures/all-mpnet-base-v2/";
 
     #[test]
     fn test_parse_config_bert() -> Result<()> {
@@ -247,6 +982,190 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_embed_output_on_device() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+
+        let output = model.encode_batch_with_usage(vec!["The cat", "A dog"], true)?;
+        assert!(output.device().same_device(&Device::Cpu));
+
+        let embeddings = output.to_device(&Device::Cpu)?.into_tensor();
+        assert_eq!(embeddings.dims(), &[2, TINY_HIDDEN_SIZE]);
+
+        let similarity = cosine_similarity(&embeddings, &embeddings)?.to_vec2::<f32>()?;
+        assert!((similarity[0][0] - 1.).abs() < 1e-5);
+        assert!((similarity[0][1] - similarity[1][0]).abs() < 1e-5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_pairs() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+
+        let pairs = vec![("The cat sits", "A dog"), ("I love pasta", "Pasta")];
+        let batch = model.tokenize_batch(pairs.clone())?;
+        // [CLS] the cat sits [SEP] a dog [SEP]
+        let ids = batch.encodings()[0].get_ids();
+        assert_eq!((ids.len(), ids[0], ids[4], ids[7]), (8, 101, 102, 102));
+        let type_ids = batch.type_ids().expect("Pairs should have segment ids");
+        assert_eq!(type_ids.to_vec2::<u32>()?[0], vec![0, 0, 0, 0, 0, 1, 1, 1]);
+
+        // The second segment is embedded differently than the same tokens in the first
+        let single_segment = TokenizedBatch {
+            type_ids: None,
+            ..batch.clone()
+        };
+        let pair_embeddings = model.encode_tokenized(&batch, false)?.into_tensor();
+        let single_embeddings = model
+            .encode_tokenized(&single_segment, false)?
+            .into_tensor();
+        let difference = (pair_embeddings - single_embeddings)?
+            .abs()?
+            .sum_all()?
+            .to_scalar::<f32>()?;
+        assert!(difference > 0.);
+
+        let embeddings = model.encode_pairs(pairs, true)?;
+        assert_eq!(embeddings.dims(), &[2, TINY_HIDDEN_SIZE]);
+
+        // Single sentences stay in the first segment
+        assert!(model
+            .tokenize_batch(vec!["The cat sits"])?
+            .type_ids()
+            .is_none());
+
+        Ok(())
+    }
+
+    /// Tiny model repositories of the architectures that are embedded with an attention mask
+    /// or, for causal models, a padding side that leaves sentences unchanged.
+    struct ArchitectureCase {
+        architecture: &'static str,
+        create_repo: fn(&Path) -> Result<()>,
+        /// Id that pads the shorter sentence of a batch
+        pad_token_id: u32,
+        /// Largest difference of its padded and unpadded embedding
+        tolerance: f32,
+    }
+
+    /// Create a tiny MPNet repository without a padding configuration in its tokenizer, which
+    /// then pads with the `<pad>` token of the model.
+    fn create_tiny_mpnet_repo_without_padding(dir: &Path) -> Result<()> {
+        create_tiny_mpnet_repo(dir)?;
+
+        let tokenizer_path = dir.join("tokenizer.json");
+        let mut tokenizer: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&tokenizer_path)?)?;
+        tokenizer["padding"] = serde_json::Value::Null;
+        std::fs::write(&tokenizer_path, serde_json::to_string(&tokenizer)?)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_padding_invariance() -> Result<()> {
+        let cases = [
+            ArchitectureCase {
+                architecture: "XLM-RoBERTa",
+                create_repo: create_tiny_xlm_roberta_repo,
+                pad_token_id: 0,
+                tolerance: 1e-5,
+            },
+            ArchitectureCase {
+                architecture: "DeBERTa-v3",
+                create_repo: create_tiny_deberta_v3_repo,
+                pad_token_id: 0,
+                tolerance: 1e-4,
+            },
+            ArchitectureCase {
+                // Rotary embeddings only depend on the relative positions of the tokens
+                architecture: "Nomic BERT",
+                create_repo: create_tiny_nomic_bert_repo,
+                pad_token_id: 0,
+                tolerance: 1e-5,
+            },
+            ArchitectureCase {
+                architecture: "ModernBERT",
+                create_repo: create_tiny_modernbert_repo,
+                pad_token_id: 0,
+                tolerance: 1e-5,
+            },
+            ArchitectureCase {
+                architecture: "T5",
+                create_repo: create_tiny_t5_repo,
+                pad_token_id: 0,
+                tolerance: 1e-5,
+            },
+            ArchitectureCase {
+                // The causal attention embeds the shorter sentence exactly like on its own
+                architecture: "Qwen2",
+                create_repo: create_tiny_qwen2_repo,
+                pad_token_id: 0,
+                tolerance: 1e-5,
+            },
+            ArchitectureCase {
+                architecture: "MPNet",
+                create_repo: create_tiny_mpnet_repo_without_padding,
+                pad_token_id: 1,
+                tolerance: 1e-5,
+            },
+        ];
+
+        for case in cases {
+            let architecture = case.architecture;
+            let dir = tempdir()?;
+            (case.create_repo)(dir.path())?;
+            let model = SentenceTransformer::builder()
+                .with_model_folder(dir.path())
+                .build()?;
+
+            let sentences = vec!["The cat", "A dog sits on the mat"];
+            let batch = model.tokenize_batch(sentences.clone())?;
+            let ids = batch.encodings()[0].get_ids();
+            assert_eq!(ids.last(), Some(&case.pad_token_id), "{architecture}");
+
+            // Padding doesn't change the embedding of the shorter sentence
+            let padded = model.encode_batch(sentences, false)?;
+            let single = model.encode_batch(vec!["The cat"], false)?;
+            assert_eq!(padded.dims(), &[2, TINY_HIDDEN_SIZE], "{architecture}");
+            let difference = (padded.i(0)? - single.i(0)?)?
+                .abs()?
+                .max(0)?
+                .to_scalar::<f32>()?;
+            assert!(
+                difference < case.tolerance,
+                "{architecture}: difference {difference}"
+            );
+
+            // Sentence pairs are embedded with the segment embeddings, or like single sentences
+            let pairs = model.encode_pairs(vec![("The cat", "A dog")], false)?;
+            assert_eq!(pairs.dims(), &[1, TINY_HIDDEN_SIZE], "{architecture}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_config_mpnet() -> Result<()> {
+        let config = ModelRepo::from_path(Path::new(MPNET_PATH)).get_config()?;
+        assert_eq!(
+            config.model_type,
+            ModelType::Embedding(PoolingStrategy::Mean)
+        );
+        assert!(matches!(config.embedder_config, EmbedderConfig::MPNet(_)));
+
+        Ok(())
+    }
+
     #[test]
     fn test_parse_config_jinabert() -> Result<()> {
         let path = Path::new(JINABERT_PATH);
@@ -0,0 +1,64 @@
+//! Model load timings
+//!
+//! Loading a [`crate::SentenceTransformer`] is timed per stage, so slow startups can be traced
+//! back to the network (fetching the repository files), the disk (mapping the weights) or the
+//! model itself (initializing the layers from the weights). Every stage also runs in its own
+//! tracing span.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Time spent in each stage of loading a model.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoadReport {
+    /// Resolving the repository files, including downloading them if they aren't cached
+    pub fetch: Duration,
+    /// Parsing the model, tokenizer and pooling configuration
+    pub config: Duration,
+    /// Building the tokenizer
+    pub tokenizer: Duration,
+    /// Memory-mapping (or reading, for PyTorch weights) the weights
+    pub weights: Duration,
+    /// Initializing the model layers from the weights, including copying them to the device
+    pub model_init: Duration,
+    /// Loading the PCA projection, if the repository has one
+    pub pca: Duration,
+    /// Size of the weights file in bytes
+    pub weights_bytes: u64,
+}
+
+impl LoadReport {
+    pub fn total(&self) -> Duration {
+        self.fetch + self.config + self.tokenizer + self.weights + self.model_init + self.pca
+    }
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.2?} total (fetch {:.2?}, config {:.2?}, tokenizer {:.2?}, weights {:.2?} for {:.1} MB, \
+             model init {:.2?}, pca {:.2?})",
+            self.total(),
+            self.fetch,
+            self.config,
+            self.tokenizer,
+            self.weights,
+            self.weights_bytes as f64 / 1e6,
+            self.model_init,
+            self.pca,
+        )
+    }
+}
+
+/// Run `f` in a tracing span named `name`, adding its duration to `elapsed`.
+pub(crate) fn timed<T>(name: &'static str, elapsed: &mut Duration, f: impl FnOnce() -> T) -> T {
+    let span = tracing::span!(tracing::Level::DEBUG, "st-load", stage = name);
+    let _enter = span.enter();
+
+    let start = Instant::now();
+    let result = f();
+    *elapsed += start.elapsed();
+
+    result
+}
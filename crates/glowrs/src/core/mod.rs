@@ -1,6 +1,22 @@
+pub mod cache;
+pub mod chunking;
+pub mod classifier;
+pub mod colbert;
 pub mod config;
+pub mod convert;
+pub mod cross_encoder;
+pub mod dense;
 pub mod device;
+pub mod dual_encoder;
 pub mod embedder;
+pub mod load_report;
+pub mod pipeline;
 pub mod repo;
 pub mod sentence_transformer;
+pub mod session;
+pub mod slow_tokenizer;
+pub mod splade;
+#[cfg(test)]
+pub(crate) mod test_utils;
+pub mod token_classifier;
 pub mod utils;
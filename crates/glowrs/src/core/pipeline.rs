@@ -0,0 +1,63 @@
+//! Modules of a `sentence-transformers` pipeline after pooling
+//!
+//! `modules.json` lists the pipeline of a model: a transformer, pooling, and then modules that
+//! transform the pooled embeddings, such as `Dense` projections and `Normalize`. They are applied
+//! in the listed order, so that the embeddings match those of `sentence-transformers`, which
+//! applies a `Normalize` module regardless of whether the caller asks for normalization.
+
+use candle_core::{Device, Tensor};
+use serde_json::json;
+use std::fs;
+use std::path::Path;
+
+use crate::core::dense::Dense;
+use crate::core::repo::OutputModulePath;
+use crate::core::utils::normalize_l2;
+use crate::Result;
+
+/// A module applied to the pooled embeddings.
+#[derive(Debug, Clone)]
+pub enum OutputModule {
+    /// A linear projection, see [`crate::core::dense`]
+    Dense(Dense),
+    /// L2 normalization
+    Normalize,
+}
+
+impl OutputModule {
+    pub(crate) fn load(module: &OutputModulePath, device: &Device) -> Result<Self> {
+        Ok(match module {
+            OutputModulePath::Dense(dir) => Self::Dense(Dense::load(dir, device)?),
+            OutputModulePath::Normalize => Self::Normalize,
+        })
+    }
+
+    /// Apply the module to a batch of embeddings of shape `(n, dim)`.
+    pub fn forward(&self, embeddings: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Dense(dense) => dense.forward(embeddings),
+            Self::Normalize => Ok(normalize_l2(embeddings)?),
+        }
+    }
+
+    /// Save the module to the folder of a model as the `idx`-th module of the pipeline, and
+    /// return its entry in `modules.json`.
+    pub(crate) fn save(&self, path: &Path, idx: usize) -> Result<serde_json::Value> {
+        let (dir, kind) = match self {
+            Self::Dense(_) => (format!("{idx}_Dense"), "Dense"),
+            Self::Normalize => (format!("{idx}_Normalize"), "Normalize"),
+        };
+        match self {
+            Self::Dense(dense) => dense.save(&path.join(&dir))?,
+            // Like in `sentence-transformers`, the folder of a `Normalize` module is empty
+            Self::Normalize => fs::create_dir_all(path.join(&dir))?,
+        }
+
+        Ok(json!({
+            "idx": idx,
+            "name": idx.to_string(),
+            "path": dir,
+            "type": format!("sentence_transformers.models.{kind}")
+        }))
+    }
+}
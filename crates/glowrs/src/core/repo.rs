@@ -1,19 +1,86 @@
+#[cfg(feature = "hub")]
 use hf_hub::api::sync::ApiRepo;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
 
 use crate::core::config::model::SentenceTransformerConfig;
 use crate::core::config::parse::parse_config;
+use crate::core::dual_encoder::AsymBranch;
+use crate::core::slow_tokenizer::has_slow_tokenizer;
+#[cfg(feature = "hub")]
+use crate::core::slow_tokenizer::SLOW_TOKENIZER_FILES;
 use crate::{Error, Result};
 
 /// Represents a folder with core weights structured as a repository on HF Hub.
 pub enum ModelRepo {
     Folder(PathBuf),
+    #[cfg(feature = "hub")]
     ApiRepo(Box<ApiRepo>),
+    /// Model files embedded in the binary, see [`crate::include_model`]
+    Embedded(ModelBytes),
 }
 
-const SAFETENSORS_FILE: &str = "model.safetensors";
-const PTH_FILE: &str = "pytorch_model.bin";
-const POOLING_CONFIG_FILE: &str = "1_Pooling/config.json";
+/// The files of a model, embedded in the binary with [`crate::include_model`] so it can be
+/// loaded without filesystem or network access.
+#[derive(Debug, Clone, Copy)]
+pub struct ModelBytes {
+    /// Contents of `config.json`
+    pub config: &'static [u8],
+    /// Contents of `tokenizer.json`
+    pub tokenizer: &'static [u8],
+    /// Contents of `model.safetensors`
+    pub weights: &'static [u8],
+    /// Contents of `1_Pooling/config.json`, if any
+    pub pooling_config: Option<&'static [u8]>,
+}
+
+/// Embed the files of a model folder in the binary at compile time, as [`ModelBytes`].
+///
+/// The folder should contain `config.json`, `tokenizer.json` and `model.safetensors`; its path
+/// is relative to the file the macro is used in, like with [`include_bytes`]. The pooling
+/// configuration is only included with the `pooling` argument, as the macro can't check whether
+/// it exists. Without it, set the pooling strategy on the builder.
+///
+/// ```rust,ignore
+/// let model = SentenceTransformer::builder()
+///     .with_model_bytes(glowrs::include_model!("../models/all-MiniLM-L6-v2", pooling))
+///     .build()?;
+/// ```
+#[macro_export]
+macro_rules! include_model {
+    ($dir:literal) => {
+        $crate::core::repo::ModelBytes {
+            config: include_bytes!(concat!($dir, "/config.json")),
+            tokenizer: include_bytes!(concat!($dir, "/tokenizer.json")),
+            weights: include_bytes!(concat!($dir, "/model.safetensors")),
+            pooling_config: None,
+        }
+    };
+    ($dir:literal, pooling) => {
+        $crate::core::repo::ModelBytes {
+            pooling_config: Some(include_bytes!(concat!($dir, "/1_Pooling/config.json"))),
+            ..$crate::include_model!($dir)
+        }
+    };
+}
+
+pub(crate) const SAFETENSORS_FILE: &str = "model.safetensors";
+/// Index of the shards of weights split over several files (`model-00001-of-00002.safetensors`)
+pub(crate) const SAFETENSORS_INDEX_FILE: &str = "model.safetensors.index.json";
+pub(crate) const PTH_FILE: &str = "pytorch_model.bin";
+pub(crate) const CONFIG_FILE: &str = "config.json";
+pub(crate) const TOKENIZER_FILE: &str = "tokenizer.json";
+pub(crate) const POOLING_CONFIG_FILE: &str = "1_Pooling/config.json";
+pub(crate) const MODULES_FILE: &str = "modules.json";
+pub(crate) const PCA_FILE: &str = "pca.safetensors";
+pub(crate) const GGUF_EXTENSION: &str = "gguf";
+/// ONNX exports in the repository root or the `onnx` directory, which are only used if the
+/// repository has no other weights. They are run with ONNX Runtime (the `onnx` feature).
+const ONNX_FILES: [&str; 2] = ["model.onnx", "onnx/model.onnx"];
+#[cfg(not(feature = "onnx"))]
+const ONNX_ONLY_MESSAGE: &str =
+    "Repository only has ONNX weights, which need the `onnx` feature. Use safetensors or pth weights.";
 
 impl ModelRepo {
     pub fn from_path<P>(root: P) -> Self
@@ -23,89 +90,544 @@ impl ModelRepo {
         Self::Folder(root.as_ref().to_owned())
     }
 
+    #[cfg(feature = "hub")]
     pub fn from_api_repo(api_repo: ApiRepo) -> Self {
         Self::ApiRepo(Box::new(api_repo))
     }
 
+    pub fn from_bytes(bytes: ModelBytes) -> Self {
+        Self::Embedded(bytes)
+    }
+
     /// Get the relevant repository files.
     ///
+    /// The transformer weights, configuration and tokenizer are looked up in the directory of
+    /// the transformer module in `modules.json` (e.g. `0_Transformer/`), and the pooling
+    /// configuration in that of the pooling module. Without `modules.json`, they are expected
+    /// in the repository root and `1_Pooling/`. The tokenizer falls back to the root if the
+    /// module directory doesn't have one.
+    ///
     /// **Warning**: Will download model weights if not present in the expected
     /// folder in the Huggingface cache.
     pub(crate) fn file_paths(&self) -> Result<ModelRepoFiles> {
+        self.branch_file_paths(None, false)
+    }
+
+    /// Same as [`ModelRepo::file_paths`], for a branch of an asymmetric (`Asym`) model. The
+    /// branch is ignored for other models, and required for asymmetric ones.
+    ///
+    /// With `quantized`, pre-quantized GGUF weights in the transformer directory are used
+    /// instead of the full precision weights if there are any, see [`select_quantized`].
+    pub(crate) fn branch_file_paths(
+        &self,
+        branch: Option<AsymBranch>,
+        quantized: bool,
+    ) -> Result<ModelRepoFiles> {
+        let mut module_dirs = match self.read_file(MODULES_FILE)? {
+            Some(modules) => ModuleDirs::parse(&modules)?,
+            None => ModuleDirs::default(),
+        };
+        if let Some(asym) = &module_dirs.asym {
+            let branch = branch.ok_or(Error::ModelLoad(
+                "Asymmetric models can only be loaded as a `DualEncoder`.",
+            ))?;
+            let asym_config = self
+                .read_file(&format!("{asym}/{CONFIG_FILE}"))?
+                .ok_or(Error::ModelLoad("Asym module has no configuration."))?;
+            module_dirs.select_branch(&asym_config, branch)?;
+        }
+
         let root = match self {
+            ModelRepo::Embedded(_) => {
+                return Err(Error::ModelLoad(
+                    "Embedded models have no repository files.",
+                ))
+            }
             ModelRepo::Folder(pathbuf) => pathbuf.to_owned(),
+            #[cfg(feature = "hub")]
             ModelRepo::ApiRepo(api_repo) => {
-                let model_path = api_repo
-                    .get(SAFETENSORS_FILE)
-                    .or_else(|_e| api_repo.get(PTH_FILE))?;
-
-                let _ = api_repo.get("config.json")?;
-
-                let _ = api_repo.get("tokenizer.json")?;
+                let transformer_file = |file: &str| module_dirs.transformer_file(file);
+
+                // Only download the full precision weights if there are no quantized ones
+                let quantized_file = match quantized {
+                    true => match api_repo.info() {
+                        Ok(info) => select_quantized(info.siblings.iter().filter_map(|sibling| {
+                            let dir = Path::new(&sibling.rfilename).parent()?;
+                            (dir == Path::new(&module_dirs.transformer))
+                                .then_some(sibling.rfilename.as_str())
+                        }))
+                        .map(str::to_owned),
+                        Err(e) => {
+                            tracing::warn!("Failed to list repository files: {e}");
+                            None
+                        }
+                    },
+                    false => None,
+                };
+                let model_path = match quantized_file {
+                    Some(file) => api_repo.get(&file)?,
+                    None => match api_repo.get(&transformer_file(SAFETENSORS_FILE)) {
+                        Ok(path) => path,
+                        // Sharded weights, of which the index lists the files
+                        Err(_) => match api_repo.get(&transformer_file(SAFETENSORS_INDEX_FILE)) {
+                            Ok(index) => {
+                                for shard in shard_files(&std::fs::read_to_string(&index)?)? {
+                                    let _ = api_repo.get(&transformer_file(&shard))?;
+                                }
+                                index
+                            }
+                            Err(_) => match api_repo.get(&transformer_file(PTH_FILE)) {
+                                Ok(path) => path,
+                                Err(e) => {
+                                    let onnx_file = api_repo.info().ok().and_then(|info| {
+                                        ONNX_FILES.into_iter().find(|file| {
+                                            info.siblings
+                                                .iter()
+                                                .any(|sibling| sibling.rfilename == *file)
+                                        })
+                                    });
+                                    match onnx_file {
+                                        #[cfg(feature = "onnx")]
+                                        Some(file) => {
+                                            let _ = api_repo.get(file)?;
+                                            // The configuration is next to the transformer
+                                            // weights, so the root is found from it instead
+                                            api_repo.get(&transformer_file(CONFIG_FILE))?
+                                        }
+                                        #[cfg(not(feature = "onnx"))]
+                                        Some(_) => return Err(Error::ModelLoad(ONNX_ONLY_MESSAGE)),
+                                        None => return Err(e.into()),
+                                    }
+                                }
+                            },
+                        },
+                    },
+                };
+
+                let _ = api_repo.get(&transformer_file(CONFIG_FILE))?;
+
+                if api_repo.get(&transformer_file(TOKENIZER_FILE)).is_err()
+                    && api_repo.get(TOKENIZER_FILE).is_err()
+                {
+                    // Older repositories only have the files of a slow tokenizer
+                    for file in SLOW_TOKENIZER_FILES {
+                        let _ = api_repo
+                            .get(&transformer_file(file))
+                            .or_else(|_| api_repo.get(file));
+                    }
+                }
 
-                let pooling_dir_opt = api_repo.get("1_Pooling/config.json").ok();
+                let pooling_dir_opt = api_repo.get(&module_dirs.pooling_config_file()).ok();
                 if pooling_dir_opt.is_none() {
                     tracing::info!(
                         "No pooling configuration found. Using default or given strategy."
                     );
                 }
 
+                for module in &module_dirs.output {
+                    if let OutputModulePath::Dense(dir) = module {
+                        let dir = dir.display();
+                        let _ = api_repo.get(&format!("{dir}/{CONFIG_FILE}"))?;
+                        if api_repo.get(&format!("{dir}/{SAFETENSORS_FILE}")).is_err() {
+                            let _ = api_repo.get(&format!("{dir}/{PTH_FILE}"))?;
+                        }
+                    }
+                }
+
+                // Optional dimensionality reduction, see [`crate::reduce`]
+                let _ = api_repo.get(PCA_FILE).ok();
+
+                // The weights are in the transformer directory, below the repository root
+                let depth = Path::new(&module_dirs.transformer).components().count();
                 let root = model_path
-                    .parent()
+                    .ancestors()
+                    .nth(depth + 1)
                     .expect("Model path has no parent directory");
 
                 root.to_owned()
             }
         };
-        let config = root.join("config.json");
-        let tokenizer_config = root.join("tokenizer.json");
+        let transformer_file = |file: &str| root.join(module_dirs.transformer_file(file));
+        let config = transformer_file(CONFIG_FILE);
+        let transformer_dir = root.join(&module_dirs.transformer);
+        let tokenizer_config = [&transformer_dir, &root]
+            .into_iter()
+            .map(|dir| dir.join(TOKENIZER_FILE))
+            .find(|p| p.exists())
+            .map(TokenizerPath::Fast)
+            .or_else(|| {
+                [&transformer_dir, &root]
+                    .into_iter()
+                    .find(|dir| has_slow_tokenizer(dir))
+                    .map(|dir| TokenizerPath::Slow(dir.to_owned()))
+            });
+
+        let tokenizer_config = match tokenizer_config {
+            Some(tokenizer_config) if config.exists() => tokenizer_config,
+            _ => return Err(Error::ModelLoad("Repository misses configuration files.")),
+        };
 
-        for p in [&config, &tokenizer_config] {
-            if !p.exists() {
-                return Err(Error::ModelLoad("Repository misses configuration files."));
-            }
-        }
+        let quantized_weights = match quantized {
+            true => find_quantized(&transformer_dir)?,
+            false => None,
+        };
 
-        // Safetensors get precedence over pth.
-        let model_weights = if root.join(SAFETENSORS_FILE).exists() {
-            ModelWeightsPath::Safetensors(root.join(SAFETENSORS_FILE))
-        } else if root.join(PTH_FILE).exists() {
-            ModelWeightsPath::Pth(root.join(PTH_FILE))
+        // Quantized weights (if asked for) get precedence over safetensors, safetensors over
+        // sharded safetensors, and those over pth.
+        let model_weights = if let Some(path) = quantized_weights {
+            tracing::info!("Using quantized weights from {}", path.display());
+            ModelWeightsPath::Gguf(path)
+        } else if transformer_file(SAFETENSORS_FILE).exists() {
+            ModelWeightsPath::Safetensors(transformer_file(SAFETENSORS_FILE))
+        } else if transformer_file(SAFETENSORS_INDEX_FILE).exists() {
+            let index = std::fs::read_to_string(transformer_file(SAFETENSORS_INDEX_FILE))?;
+            let shards: Vec<PathBuf> = shard_files(&index)?
+                .iter()
+                .map(|shard| transformer_file(shard))
+                .collect();
+            if !shards.iter().all(|shard| shard.exists()) {
+                return Err(Error::ModelLoad(
+                    "Repository misses shards of the model weights.",
+                ));
+            }
+            ModelWeightsPath::ShardedSafetensors(shards)
+        } else if transformer_file(PTH_FILE).exists() {
+            ModelWeightsPath::Pth(transformer_file(PTH_FILE))
+        } else if let Some(path) = ONNX_FILES
+            .into_iter()
+            .map(|file| root.join(file))
+            .find(|path| path.exists())
+        {
+            onnx_weights(path)?
         } else {
             return Err(Error::ModelLoad(
                 "Repository doesn't contain model weights.",
             ));
         };
 
-        let pooling_config = if root.join(POOLING_CONFIG_FILE).exists() {
-            Some(root.join(POOLING_CONFIG_FILE))
-        } else {
-            None
-        };
+        let pooling_config =
+            Some(root.join(module_dirs.pooling_config_file())).filter(|p| p.exists());
+
+        let output_modules = module_dirs
+            .output
+            .iter()
+            .map(|module| match module {
+                OutputModulePath::Dense(dir) => match root.join(dir) {
+                    dir if dir.join(CONFIG_FILE).exists() => Ok(OutputModulePath::Dense(dir)),
+                    _ => Err(Error::ModelLoad(
+                        "Repository misses the configuration of a Dense module.",
+                    )),
+                },
+                OutputModulePath::Normalize => Ok(OutputModulePath::Normalize),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let pca = Some(root.join(PCA_FILE)).filter(|p| p.exists());
 
         Ok(ModelRepoFiles {
             config,
             tokenizer_config,
             model_weights,
             pooling_config,
+            output_modules,
+            pca,
         })
     }
 
+    /// Read a file of the repository, given relative to its root, if it exists.
+    ///
+    /// **Warning**: Will download the file if not present in the Huggingface cache.
+    pub(crate) fn read_file(&self, file: &str) -> Result<Option<String>> {
+        let path = match self {
+            ModelRepo::Embedded(_) => None,
+            ModelRepo::Folder(root) => Some(root.join(file)).filter(|p| p.exists()),
+            #[cfg(feature = "hub")]
+            ModelRepo::ApiRepo(api_repo) => api_repo.get(file).ok(),
+        };
+
+        Ok(path.map(std::fs::read_to_string).transpose()?)
+    }
+
+    /// Whether the repository holds an asymmetric (`Asym`) model, with separate transformers
+    /// for queries and documents.
+    pub(crate) fn is_asymmetric(&self) -> Result<bool> {
+        match self.read_file(MODULES_FILE)? {
+            Some(modules) => Ok(ModuleDirs::parse(&modules)?.asym.is_some()),
+            None => Ok(false),
+        }
+    }
+
     pub fn get_config(&self) -> Result<SentenceTransformerConfig> {
         parse_config(self, None)
     }
 }
 
+/// Commit hash of a file in the Hugging Face cache, from the name of the snapshot directory it
+/// is in (`.../snapshots/<commit>/...`).
+pub(crate) fn snapshot_commit(path: &Path) -> Option<String> {
+    path.ancestors()
+        .find(|dir| dir.parent().and_then(Path::file_name) == Some("snapshots".as_ref()))
+        .and_then(Path::file_name)
+        .map(|commit| commit.to_string_lossy().into_owned())
+}
+
+/// The files of sharded weights, in name order, from the `weight_map` of their index.
+fn shard_files(index: &str) -> Result<Vec<String>> {
+    #[derive(Deserialize)]
+    struct ShardIndex {
+        weight_map: HashMap<String, String>,
+    }
+
+    let ShardIndex { weight_map } = serde_json::from_str(index)?;
+    let mut files: Vec<String> = weight_map.into_values().collect();
+    files.sort();
+    files.dedup();
+
+    match files.is_empty() {
+        true => Err(Error::ModelLoad("Sharded weights index lists no files.")),
+        false => Ok(files),
+    }
+}
+
+/// Find the preferred quantized weights file in a directory, if any.
+fn find_quantized(dir: &Path) -> Result<Option<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(None);
+    }
+
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        names.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+
+    Ok(select_quantized(names.iter().map(String::as_str)).map(|name| dir.join(name)))
+}
+
+/// Choose between quantized (GGUF) weights files. Files with more bits per weight are
+/// preferred, as given by the quantization type in their name (e.g. `model.Q8_0.gguf` over
+/// `model.Q4_K_M.gguf`), then files without a quantization type in their name.
+pub(crate) fn select_quantized<'a>(files: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    // Number of bits of the quantization type in a file name, if any
+    let bits = |file: &str| -> Option<u32> {
+        let stem = file.rsplit('/').next()?.strip_suffix(".gguf")?;
+        stem.split(['.', '-', '_'])
+            .filter_map(|part| part.strip_prefix(['q', 'Q']))
+            .find_map(|bits| bits.parse().ok())
+    };
+
+    files
+        .filter(|file| {
+            Path::new(file)
+                .extension()
+                .is_some_and(|extension| extension == GGUF_EXTENSION)
+        })
+        // Prefer the first file in name order among equals
+        .min_by_key(|file| (std::cmp::Reverse(bits(file)), *file))
+}
+
+/// A module of the pipeline that is applied after pooling, as listed in `modules.json`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum OutputModulePath {
+    /// A `Dense` projection, with the directory of its configuration and weights
+    Dense(PathBuf),
+    /// L2 normalization, which has no files
+    Normalize,
+}
+
+/// Directories of the modules of a repository, relative to its root, from `modules.json`.
+#[derive(Debug, Default, PartialEq)]
+struct ModuleDirs {
+    /// Directory of the transformer module, empty for the root
+    transformer: String,
+    /// Directory of the pooling module, if listed
+    pooling: Option<String>,
+    /// Directory of the `Asym` module, if listed. Its branches each have their own transformer
+    asym: Option<String>,
+    /// Modules after pooling, in the order they are applied
+    output: Vec<OutputModulePath>,
+}
+
+impl ModuleDirs {
+    /// Parse the pipeline of `modules.json`: a transformer (possibly in an `Asym` module),
+    /// pooling, and then any number of `Dense` and `Normalize` modules. Other modules aren't
+    /// supported, so that a model is never run with only part of its pipeline.
+    fn parse(modules: &str) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Module {
+            #[serde(default)]
+            path: String,
+            #[serde(rename = "type")]
+            kind: String,
+        }
+
+        let modules: Vec<Module> = serde_json::from_str(modules)?;
+        let mut module_dirs = Self::default();
+        for module in modules {
+            let path = match module.path.trim_matches('/') {
+                "." => String::new(),
+                path => path.to_string(),
+            };
+            match module.kind.rsplit('.').next().unwrap_or_default() {
+                // Static embeddings (model2vec) take the place of the transformer
+                "Transformer" | "StaticEmbedding" => module_dirs.transformer = path,
+                "Asym" => module_dirs.asym = Some(path),
+                "Pooling" if module_dirs.output.is_empty() => module_dirs.pooling = Some(path),
+                "Pooling" => {
+                    return Err(Error::ModelLoad(
+                        "Modules before pooling other than the transformer are not supported.",
+                    ))
+                }
+                "Dense" => module_dirs
+                    .output
+                    .push(OutputModulePath::Dense(PathBuf::from(path))),
+                "Normalize" => module_dirs.output.push(OutputModulePath::Normalize),
+                _ => {
+                    return Err(Error::ModelLoad(
+                        "Repository has a module in `modules.json` that is not supported.",
+                    ))
+                }
+            }
+        }
+
+        Ok(module_dirs)
+    }
+
+    /// Use the transformer of a branch of the `Asym` module, given the module configuration.
+    ///
+    /// The query branch is the one named `query`; the document branch is the first other one
+    /// (usually `doc`, `document` or `passage`).
+    fn select_branch(&mut self, asym_config: &str, branch: AsymBranch) -> Result<()> {
+        #[derive(Deserialize)]
+        struct AsymConfig {
+            types: HashMap<String, String>,
+            structure: BTreeMap<String, Vec<String>>,
+        }
+
+        let AsymConfig { types, structure } = serde_json::from_str(asym_config)?;
+        let modules = structure
+            .iter()
+            .find(|(key, _)| (key.as_str() == "query") == (branch == AsymBranch::Query))
+            .map(|(_, modules)| modules)
+            .ok_or(Error::ModelLoad(
+                "Asym module misses a query or document branch.",
+            ))?;
+        let transformer = modules
+            .iter()
+            .find(|module| {
+                types
+                    .get(*module)
+                    .is_some_and(|kind| kind.rsplit('.').next() == Some("Transformer"))
+            })
+            .ok_or(Error::ModelLoad("Asym module branch has no transformer."))?;
+
+        self.transformer = match &self.asym {
+            Some(asym) if !asym.is_empty() => format!("{asym}/{transformer}"),
+            _ => transformer.to_string(),
+        };
+
+        Ok(())
+    }
+
+    /// Path of a file of the transformer module, relative to the repository root.
+    fn transformer_file(&self, file: &str) -> String {
+        match self.transformer.as_str() {
+            "" => file.to_string(),
+            dir => format!("{dir}/{file}"),
+        }
+    }
+
+    /// Path of the pooling configuration, relative to the repository root.
+    fn pooling_config_file(&self) -> String {
+        match &self.pooling {
+            Some(dir) => format!("{dir}/{CONFIG_FILE}"),
+            None => POOLING_CONFIG_FILE.to_string(),
+        }
+    }
+}
+
+/// Where the tokenizer of a model is loaded from.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum TokenizerPath {
+    /// `tokenizer.json`
+    Fast(PathBuf),
+    /// Directory with the files of a slow tokenizer, see [`crate::core::slow_tokenizer`]
+    Slow(PathBuf),
+}
+
 pub(crate) struct ModelRepoFiles {
     pub(crate) config: PathBuf,
-    pub(crate) tokenizer_config: PathBuf,
+    pub(crate) tokenizer_config: TokenizerPath,
     pub(crate) model_weights: ModelWeightsPath,
     pub(crate) pooling_config: Option<PathBuf>,
+    /// Modules applied after pooling, see [`crate::core::pipeline`]
+    pub(crate) output_modules: Vec<OutputModulePath>,
+    pub(crate) pca: Option<PathBuf>,
 }
 
+#[derive(Debug, Clone)]
 pub(crate) enum ModelWeightsPath {
     Pth(PathBuf),
     Safetensors(PathBuf),
+    /// SafeTensors weights split over several files, see [`SAFETENSORS_INDEX_FILE`]
+    ShardedSafetensors(Vec<PathBuf>),
+    /// Quantized GGUF weights, dequantized when loading
+    Gguf(PathBuf),
+    /// SafeTensors weights embedded in the binary
+    Embedded(&'static [u8]),
+    /// ONNX export, run with ONNX Runtime instead of candle
+    #[cfg(feature = "onnx")]
+    Onnx(PathBuf),
+}
+
+/// Weights of a repository that only has an ONNX export.
+#[cfg(feature = "onnx")]
+fn onnx_weights(path: PathBuf) -> Result<ModelWeightsPath> {
+    Ok(ModelWeightsPath::Onnx(path))
+}
+
+#[cfg(not(feature = "onnx"))]
+fn onnx_weights(_path: PathBuf) -> Result<ModelWeightsPath> {
+    Err(Error::ModelLoad(ONNX_ONLY_MESSAGE))
+}
+
+impl ModelWeightsPath {
+    /// Path of the weights file, or of the first shard, unless the weights are embedded.
+    pub(crate) fn path(&self) -> Option<&Path> {
+        match self {
+            ModelWeightsPath::Pth(path)
+            | ModelWeightsPath::Safetensors(path)
+            | ModelWeightsPath::Gguf(path) => Some(path),
+            #[cfg(feature = "onnx")]
+            ModelWeightsPath::Onnx(path) => Some(path),
+            ModelWeightsPath::ShardedSafetensors(paths) => paths.first().map(PathBuf::as_path),
+            ModelWeightsPath::Embedded(_) => None,
+        }
+    }
+
+    /// Whether the weights are an ONNX export, which is run with ONNX Runtime.
+    pub(crate) fn is_onnx(&self) -> bool {
+        match self {
+            #[cfg(feature = "onnx")]
+            ModelWeightsPath::Onnx(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Size of the weights in bytes.
+    pub(crate) fn size(&self) -> Result<u64> {
+        match self {
+            ModelWeightsPath::Pth(path)
+            | ModelWeightsPath::Safetensors(path)
+            | ModelWeightsPath::Gguf(path) => Ok(std::fs::metadata(path)?.len()),
+            #[cfg(feature = "onnx")]
+            ModelWeightsPath::Onnx(path) => Ok(std::fs::metadata(path)?.len()),
+            ModelWeightsPath::ShardedSafetensors(paths) => paths
+                .iter()
+                .map(|path| Ok(std::fs::metadata(path)?.len()))
+                .sum(),
+            ModelWeightsPath::Embedded(bytes) => Ok(bytes.len() as u64),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -169,6 +691,146 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_model_repo_with_module_dirs() -> Result<()> {
+        let dir = tempdir()?;
+        let transformer_dir = dir.path().join("0_Transformer");
+        let pooling_dir = dir.path().join("2_Pooling");
+        let dense_dir = dir.path().join("3_Dense");
+
+        fs::create_dir_all(&transformer_dir)?;
+        fs::create_dir_all(&pooling_dir)?;
+        fs::create_dir_all(&dense_dir)?;
+        fs::write(transformer_dir.join("config.json"), "{}")?;
+        fs::write(transformer_dir.join("model.safetensors"), "{}")?;
+        fs::write(dir.path().join("tokenizer.json"), "{}")?;
+        fs::write(pooling_dir.join("config.json"), "{}")?;
+        fs::write(dense_dir.join("config.json"), "{}")?;
+        fs::write(
+            dir.path().join("modules.json"),
+            r#"[
+                {"idx": 0, "name": "0", "path": "0_Transformer", "type": "sentence_transformers.models.Transformer"},
+                {"idx": 1, "name": "1", "path": "2_Pooling", "type": "sentence_transformers.models.Pooling"},
+                {"idx": 2, "name": "2", "path": "3_Dense", "type": "sentence_transformers.models.Dense"}
+            ]"#,
+        )?;
+
+        let repo = ModelRepo::from_path(dir.path());
+        let ModelRepoFiles {
+            config,
+            tokenizer_config,
+            model_weights,
+            pooling_config,
+            output_modules,
+            ..
+        } = repo.file_paths()?;
+
+        assert_eq!(config, transformer_dir.join("config.json"));
+        // Falls back to the tokenizer in the root
+        assert_eq!(
+            tokenizer_config,
+            TokenizerPath::Fast(dir.path().join("tokenizer.json"))
+        );
+        assert!(
+            matches!(model_weights, ModelWeightsPath::Safetensors(path) if path == transformer_dir.join("model.safetensors"))
+        );
+        assert_eq!(pooling_config, Some(pooling_dir.join("config.json")));
+        assert_eq!(output_modules, vec![OutputModulePath::Dense(dense_dir)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_module_dirs() -> Result<()> {
+        let module_dirs = ModuleDirs::parse(
+            r#"[{"idx": 0, "name": "0", "path": "", "type": "sentence_transformers.models.Transformer"}]"#,
+        )?;
+        assert_eq!(module_dirs, ModuleDirs::default());
+        assert_eq!(module_dirs.transformer_file(CONFIG_FILE), CONFIG_FILE);
+        assert_eq!(module_dirs.pooling_config_file(), POOLING_CONFIG_FILE);
+
+        // model2vec
+        let module_dirs = ModuleDirs::parse(
+            r#"[
+                {"idx": 0, "name": "0", "path": ".", "type": "sentence_transformers.models.StaticEmbedding"},
+                {"idx": 1, "name": "1", "path": "1_Normalize", "type": "sentence_transformers.models.Normalize"}
+            ]"#,
+        )?;
+        assert_eq!(module_dirs.transformer, "");
+        assert_eq!(module_dirs.output, vec![OutputModulePath::Normalize]);
+
+        // Modules that can't be reproduced are rejected, instead of being skipped
+        assert!(ModuleDirs::parse(
+            r#"[
+                {"idx": 0, "name": "0", "path": "", "type": "sentence_transformers.models.Transformer"},
+                {"idx": 1, "name": "1", "path": "1_LSTM", "type": "sentence_transformers.models.LSTM"}
+            ]"#,
+        )
+        .is_err());
+        assert!(ModuleDirs::parse(
+            r#"[
+                {"idx": 0, "name": "0", "path": "", "type": "sentence_transformers.models.Transformer"},
+                {"idx": 1, "name": "1", "path": "1_Dense", "type": "sentence_transformers.models.Dense"},
+                {"idx": 2, "name": "2", "path": "2_Pooling", "type": "sentence_transformers.models.Pooling"}
+            ]"#,
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_commit() {
+        let path = Path::new("/hub/models--org--model/snapshots/abc123/0_Transformer/config.json");
+        assert_eq!(snapshot_commit(path), Some("abc123".to_string()));
+        assert_eq!(
+            snapshot_commit(Path::new("/models/model/config.json")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_select_quantized() {
+        let files = [
+            "model.safetensors",
+            "model.gguf",
+            "model.Q4_K_M.gguf",
+            "model-q8_0.gguf",
+        ];
+        assert_eq!(select_quantized(files.into_iter()), Some("model-q8_0.gguf"));
+        assert_eq!(
+            select_quantized(files[..3].iter().copied()),
+            Some("model.Q4_K_M.gguf")
+        );
+        assert_eq!(
+            select_quantized(files[..2].iter().copied()),
+            Some("model.gguf")
+        );
+        assert_eq!(select_quantized(files[..1].iter().copied()), None);
+    }
+
+    #[test]
+    fn test_model_repo_with_quantized_weights() -> Result<()> {
+        let dir = tempdir()?;
+
+        fs::write(dir.path().join("config.json"), "{}")?;
+        fs::write(dir.path().join("tokenizer.json"), "{}")?;
+        fs::write(dir.path().join("model.safetensors"), "{}")?;
+        fs::write(dir.path().join("model.Q8_0.gguf"), "")?;
+
+        let repo = ModelRepo::from_path(dir.path());
+        let ModelRepoFiles { model_weights, .. } = repo.branch_file_paths(None, true)?;
+        assert!(
+            matches!(model_weights, ModelWeightsPath::Gguf(path) if path == dir.path().join("model.Q8_0.gguf"))
+        );
+
+        // Only when asked for
+        let ModelRepoFiles { model_weights, .. } = repo.file_paths()?;
+        assert!(matches!(model_weights, ModelWeightsPath::Safetensors(_)));
+
+        Ok(())
+    }
+
     #[test]
     fn test_model_repo_with_pt_weights() -> Result<()> {
         let dir = tempdir()?;
@@ -186,4 +848,69 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_model_repo_with_sharded_weights() -> Result<()> {
+        let dir = tempdir()?;
+        fs::write(dir.path().join("config.json"), "{}")?;
+        fs::write(dir.path().join("tokenizer.json"), "{}")?;
+        fs::write(
+            dir.path().join(SAFETENSORS_INDEX_FILE),
+            r#"{"weight_map": {
+                "embeddings.word_embeddings.weight": "model-00001-of-00002.safetensors",
+                "pooler.dense.weight": "model-00002-of-00002.safetensors",
+                "pooler.dense.bias": "model-00002-of-00002.safetensors"
+            }}"#,
+        )?;
+        fs::write(dir.path().join("model-00001-of-00002.safetensors"), "{}")?;
+
+        // All shards are needed
+        let repo = ModelRepo::from_path(dir.path());
+        assert!(repo.file_paths().is_err());
+
+        fs::write(dir.path().join("model-00002-of-00002.safetensors"), "{}")?;
+        let ModelRepoFiles { model_weights, .. } = repo.file_paths()?;
+        match model_weights {
+            ModelWeightsPath::ShardedSafetensors(shards) => assert_eq!(
+                shards,
+                [
+                    dir.path().join("model-00001-of-00002.safetensors"),
+                    dir.path().join("model-00002-of-00002.safetensors")
+                ]
+            ),
+            _ => panic!("Expected sharded weights"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_model_repo_with_only_onnx_weights() -> Result<()> {
+        let dir = tempdir()?;
+        fs::create_dir_all(dir.path().join("onnx"))?;
+        fs::write(dir.path().join("config.json"), "{}")?;
+        fs::write(dir.path().join("tokenizer.json"), "{}")?;
+        fs::write(dir.path().join("onnx/model.onnx"), "")?;
+
+        let repo = ModelRepo::from_path(dir.path());
+        #[cfg(not(feature = "onnx"))]
+        assert!(matches!(
+            repo.file_paths(),
+            Err(Error::ModelLoad(message)) if message == ONNX_ONLY_MESSAGE
+        ));
+        #[cfg(feature = "onnx")]
+        assert!(matches!(
+            repo.file_paths()?.model_weights,
+            ModelWeightsPath::Onnx(path) if path == dir.path().join("onnx/model.onnx")
+        ));
+
+        // Other weights get precedence over the ONNX export
+        fs::write(dir.path().join("model.safetensors"), "{}")?;
+        assert!(matches!(
+            repo.file_paths()?.model_weights,
+            ModelWeightsPath::Safetensors(_)
+        ));
+
+        Ok(())
+    }
 }
@@ -1,38 +1,117 @@
-use crate::core::config::model::{ModelType, SentenceTransformerConfig};
+use crate::core::chunking::{aggregate, split_chunks, ChunkConfig};
+use crate::core::classifier::TextClassifier;
+use crate::core::colbert::ColBERT;
+use crate::core::config::model::{
+    BaseModelConfig, EmbedderConfig, ModelType, SentenceTransformerConfig,
+};
+use crate::core::config::parse::parse_config_files;
+use crate::core::cross_encoder::CrossEncoder;
+use crate::core::dense::Dense;
+use crate::core::device::load_with_fallback;
+use crate::core::dual_encoder::{AsymBranch, DualEncoder};
+#[cfg(feature = "onnx")]
+use crate::core::embedder::OnnxModel;
 use crate::core::embedder::{
-    encode_batch, encode_batch_with_usage, load_pretrained_model, EmbedOutput, EmbedderModel,
+    batch_from_encodings, batch_from_ids, encode_batch, encode_tokenized, encode_tokenized_with,
+    encode_tokens, load_model, load_quantized_model, load_var_builder, split_batch,
+    supports_quantized, tokenize_batch, EmbedOutput, EmbedderModel, TaskModel, TokenizedBatch,
+};
+use crate::core::load_report::{timed, LoadReport};
+use crate::core::pipeline::OutputModule;
+use crate::core::repo::{
+    snapshot_commit, ModelBytes, ModelRepo, ModelRepoFiles, ModelWeightsPath, CONFIG_FILE,
+    MODULES_FILE, POOLING_CONFIG_FILE, SAFETENSORS_FILE, TOKENIZER_FILE,
 };
-use crate::core::repo::{ModelRepo, ModelRepoFiles};
-use crate::{Device, Error, PoolingStrategy, Result};
+use crate::core::session::{EncodeSession, IdsBuffer};
+use crate::core::splade::SpladeModel;
+use crate::core::token_classifier::TokenClassifier;
+use crate::pooling::{CustomPooling, PoolConfig};
+use crate::reduce::Pca;
+use crate::{Device, Error, InputUsage, PoolingStrategy, Result, Usage};
 
+use crate::core::convert::{read_gguf_dequantized, write_pth_as_safetensors};
 use crate::core::utils;
-use candle_core::Tensor;
+use candle_core::{DType, Tensor};
+use candle_transformers::quantized_var_builder::VarBuilder as QuantizedVarBuilder;
+#[cfg(feature = "hub")]
 use hf_hub::api::sync::Api;
+#[cfg(feature = "hub")]
 use hf_hub::{Repo, RepoType};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
 use std::marker::PhantomData;
 use std::path::Path;
 use std::str::FromStr;
+use std::sync::Arc;
 use tokenizers::tokenizer::Tokenizer;
-use tokenizers::{EncodeInput, Encoding};
+use tokenizers::{
+    pad_encodings, EncodeInput, Encoding, PostProcessor, PostProcessorWrapper, TruncationParams,
+};
 
 /// The SentenceTransformer struct is the main abstraction for using pre-trained models for
 /// generating text embeddings.
+///
+/// Encoding only needs a shared reference and the model holds no mutable state, so a single
+/// instance can be shared across threads with [`std::sync::Arc`] to encode concurrently.
+///
+/// Cloning is cheap: clones share the model weights and tokenizer, so several replicas of a
+/// model don't take more memory than one.
+#[derive(Clone)]
 pub struct SentenceTransformer {
-    model: Box<dyn EmbedderModel>,
-    tokenizer: Tokenizer,
+    model: Arc<dyn EmbedderModel>,
+    tokenizer: Arc<Tokenizer>,
     model_type: ModelType,
+    model_config: serde_json::Value,
+    model_weights: ModelWeightsPath,
+    dtype: DType,
+    /// Commit hash of the repository revision, if loaded from the Hugging Face cache
+    commit: Option<String>,
+    /// Trained pooler, applied to the CLS token for [`PoolingStrategy::Pooler`]
+    pooler: Option<Dense>,
+    /// Modules applied to the pooled embeddings, in order
+    output_modules: Vec<OutputModule>,
+    /// Pooling function that replaces the pooling strategy of the model, if given
+    custom_pooling: Option<CustomPooling>,
+    pca: Option<Pca>,
+    /// Number of leading dimensions the embeddings are truncated to, for Matryoshka models
+    truncate_dim: Option<usize>,
+    /// Data type of the returned embeddings
+    output_dtype: DType,
+    /// Maximum number of tokens encoded in one forward pass, padding included
+    max_batch_tokens: Option<usize>,
+    load_report: LoadReport,
 }
 
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<SentenceTransformer>();
+};
+
 impl SentenceTransformer {
     pub(crate) fn new(
         model: Box<dyn EmbedderModel>,
         tokenizer: Tokenizer,
         model_type: ModelType,
+        model_config: serde_json::Value,
+        model_weights: ModelWeightsPath,
     ) -> Self {
         Self {
-            model,
-            tokenizer,
+            model: model.into(),
+            tokenizer: Arc::new(tokenizer),
             model_type,
+            model_config,
+            model_weights,
+            dtype: DType::F32,
+            commit: None,
+            pooler: None,
+            output_modules: Vec::new(),
+            custom_pooling: None,
+            pca: None,
+            truncate_dim: None,
+            output_dtype: DType::F32,
+            max_batch_tokens: None,
+            load_report: LoadReport::default(),
         }
     }
 
@@ -48,37 +127,295 @@ impl SentenceTransformer {
     pub(crate) fn from_model_repo(
         model_repo_folder: &ModelRepo,
         device: &Device,
+        dtype: DType,
         pooling_strategy: Option<PoolingStrategy>,
+        branch: Option<AsymBranch>,
+        quantized: bool,
     ) -> Result<Self> {
         let span = tracing::span!(tracing::Level::TRACE, "st-from-folder");
         let _enter = span.enter();
 
-        let ModelRepoFiles {
-            model_weights: model_weights_path,
-            ..
-        } = model_repo_folder.file_paths()?;
-
-        let st_config =
-            SentenceTransformerConfig::try_from_model_repo(model_repo_folder, pooling_strategy)?;
+        let mut report = LoadReport::default();
 
-        let tokenizer_config_str = serde_json::to_string(&st_config.tokenizer_config)?;
+        let (model_weights_path, output_modules, pca_path, mut st_config) = match model_repo_folder
+        {
+            ModelRepo::Embedded(bytes) => {
+                let st_config = timed("config", &mut report.config, || {
+                    SentenceTransformerConfig::try_from_model_repo(
+                        model_repo_folder,
+                        pooling_strategy,
+                    )
+                })?;
+                (
+                    ModelWeightsPath::Embedded(bytes.weights),
+                    Vec::new(),
+                    None,
+                    st_config,
+                )
+            }
+            _ => {
+                let files = timed("fetch", &mut report.fetch, || {
+                    model_repo_folder.branch_file_paths(branch, quantized)
+                })?;
+                let st_config = timed("config", &mut report.config, || {
+                    parse_config_files(&files, pooling_strategy)
+                })?;
+                let ModelRepoFiles {
+                    model_weights,
+                    output_modules,
+                    pca,
+                    ..
+                } = files;
+                (model_weights, output_modules, pca, st_config)
+            }
+        };
 
-        let mut tokenizer = Tokenizer::from_str(&tokenizer_config_str)?;
+        let mut tokenizer = timed("tokenizer", &mut report.tokenizer, || {
+            let tokenizer_config_str = serde_json::to_string(&st_config.tokenizer_config)?;
+            Tokenizer::from_str(&tokenizer_config_str).map_err(Error::Tokenization)
+        })?;
 
         if let Some(pp) = tokenizer.get_padding_mut() {
             pp.strategy = tokenizers::PaddingStrategy::BatchLongest
         } else {
+            // Pad with the padding token of the model, e.g. `<pad>` (1) for RoBERTa and MPNet
+            let pad_id = st_config.model_config["pad_token_id"].as_u64().unwrap_or(0) as u32;
             let pp = tokenizers::PaddingParams {
                 strategy: tokenizers::PaddingStrategy::BatchLongest,
+                pad_id,
+                pad_token: tokenizer
+                    .id_to_token(pad_id)
+                    .unwrap_or_else(|| "[PAD]".to_string()),
                 ..Default::default()
             };
             tokenizer.with_padding(Some(pp));
         }
 
-        let embedder_model =
-            load_pretrained_model(model_weights_path, st_config.embedder_config, device)?;
+        // Static embeddings are looked up without special tokens, for the whole vocabulary
+        if let EmbedderConfig::Model2Vec(config) = &mut st_config.embedder_config {
+            tokenizer.with_post_processor(None::<PostProcessorWrapper>);
+            config.vocab_size = tokenizer.get_vocab_size(true);
+        }
+
+        // Quantized weights are used as is where the architecture supports it, and otherwise
+        // dequantized to `dtype`. SPLADE heads are only loaded from dequantized weights.
+        let quantized_path = match &model_weights_path {
+            ModelWeightsPath::Gguf(path)
+                if supports_quantized(&st_config.embedder_config)
+                    && st_config.model_type != ModelType::Embedding(PoolingStrategy::Splade) =>
+            {
+                Some(path)
+            }
+            _ => None,
+        };
+        report.weights_bytes = model_weights_path.size()?;
+        // Quantized and ONNX models compute in full precision
+        let model_dtype = match quantized_path.is_some() || model_weights_path.is_onnx() {
+            true => DType::F32,
+            false => dtype,
+        };
+
+        let embedder_model = match (&model_weights_path, quantized_path) {
+            #[cfg(feature = "onnx")]
+            (ModelWeightsPath::Onnx(path), _) => {
+                // The export only outputs the token embeddings, not the logits of the MLM head
+                if st_config.model_type == ModelType::Embedding(PoolingStrategy::Splade) {
+                    return Err(Error::ModelLoad(
+                        "SPLADE models can't be run with ONNX Runtime.",
+                    ));
+                }
+                timed("model-init", &mut report.model_init, || -> Result<_> {
+                    let model = OnnxModel::load(path, &st_config.model_config, device)?;
+                    Ok(Box::new(model) as Box<dyn EmbedderModel>)
+                })?
+            }
+            (_, Some(path)) => {
+                let vb = timed("weights", &mut report.weights, || -> Result<_> {
+                    Ok(QuantizedVarBuilder::from_gguf(path, device)?)
+                })?;
+                timed("model-init", &mut report.model_init, || {
+                    load_quantized_model(vb, st_config.embedder_config)
+                })?
+            }
+            (_, None) => {
+                let vb = timed("weights", &mut report.weights, || {
+                    load_var_builder(&model_weights_path, device, dtype)
+                })?;
+
+                timed("model-init", &mut report.model_init, || -> Result<_> {
+                    let model = load_model(vb.clone(), st_config.embedder_config)?;
+                    // SPLADE pools the logits of the MLM head instead of the token embeddings
+                    Ok(match st_config.model_type {
+                        ModelType::Embedding(PoolingStrategy::Splade) => {
+                            Box::new(SpladeModel::load(model, vb, &st_config.model_config)?)
+                                as Box<dyn EmbedderModel>
+                        }
+                        _ => model,
+                    })
+                })?
+            }
+        };
+
+        // The pooler is small, so it is kept in full precision like the pooled embeddings
+        let pooler = match st_config.model_type {
+            ModelType::Embedding(PoolingStrategy::Pooler) => {
+                Some(timed("model-init", &mut report.model_init, || {
+                    let vb = load_var_builder(&model_weights_path, device, DType::F32)?;
+                    Dense::load_pooler(vb, &st_config.model_config)
+                })?)
+            }
+            _ => None,
+        };
+
+        let commit = model_weights_path.path().and_then(snapshot_commit);
+        let mut model = Self::new(
+            embedder_model,
+            tokenizer,
+            st_config.model_type,
+            st_config.model_config,
+            model_weights_path,
+        );
+        model.dtype = model_dtype;
+        model.commit = commit;
+        model.pooler = pooler;
+        model.output_modules = timed("model-init", &mut report.model_init, || {
+            output_modules
+                .iter()
+                .map(|module| OutputModule::load(module, device))
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        if let Some(pca_path) = pca_path {
+            tracing::info!("Applying PCA projection from {}", pca_path.display());
+            model.pca = Some(timed("pca", &mut report.pca, || {
+                Pca::load(pca_path, device)
+            })?);
+        }
+
+        tracing::info!("Loaded model in {report}");
+        model.load_report = report;
+
+        Ok(model)
+    }
+
+    /// Save the model to a folder in the standard `sentence-transformers` repository layout, so
+    /// it can be loaded again with [`SentenceTransformerBuilder::with_model_folder`] or pushed
+    /// to the Hugging Face Hub.
+    ///
+    /// Writes `config.json`, `tokenizer.json`, `modules.json`, the pooling configuration
+    /// and the weights as `model.safetensors`. PyTorch weights are converted to SafeTensors, and
+    /// ONNX exports are copied to `model.onnx`.
+    /// Modules after pooling, such as `Dense` projections, are saved as `2_Dense/` and onwards.
+    /// A PCA projection, if set, is saved as `pca.safetensors`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let span = tracing::span!(tracing::Level::TRACE, "st-save");
+        let _enter = span.enter();
+
+        if self.custom_pooling.is_some() {
+            return Err(Error::InvalidArgument(
+                "Models with custom pooling can't be saved",
+            ));
+        }
+
+        let path = path.as_ref();
+        fs::create_dir_all(path)?;
+
+        fs::write(
+            path.join(CONFIG_FILE),
+            serde_json::to_string_pretty(&self.model_config)?,
+        )?;
+
+        self.tokenizer
+            .save(path.join(TOKENIZER_FILE), true)
+            .map_err(Error::Tokenization)?;
+
+        let mut modules = vec![json!({
+            "idx": 0,
+            "name": "0",
+            "path": "",
+            "type": "sentence_transformers.models.Transformer"
+        })];
 
-        Ok(Self::new(embedder_model, tokenizer, st_config.model_type))
+        if let ModelType::Embedding(pooling_strategy) = &self.model_type {
+            let word_embedding_dimension = self
+                .model_config
+                .get("hidden_size")
+                .or_else(|| self.model_config.get("dim"))
+                .and_then(|v| v.as_u64())
+                .ok_or(Error::InvalidModelConfig(
+                    "Model configuration has no hidden size",
+                ))? as usize;
+
+            let pool_config = PoolConfig::new(*pooling_strategy, word_embedding_dimension)?;
+            let pool_config_path = path.join(POOLING_CONFIG_FILE);
+            if let Some(parent) = pool_config_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(
+                pool_config_path,
+                serde_json::to_string_pretty(&pool_config)?,
+            )?;
+
+            modules.push(json!({
+                "idx": 1,
+                "name": "1",
+                "path": "1_Pooling",
+                "type": "sentence_transformers.models.Pooling"
+            }));
+        }
+
+        // The pooler is saved as a `Dense` module after CLS pooling, which is equivalent
+        if let (ModelType::Embedding(PoolingStrategy::Pooler), Some(pooler)) =
+            (&self.model_type, &self.pooler)
+        {
+            modules.push(OutputModule::Dense(pooler.clone()).save(path, modules.len())?);
+        }
+        for module in &self.output_modules {
+            modules.push(module.save(path, modules.len())?);
+        }
+
+        fs::write(
+            path.join(MODULES_FILE),
+            serde_json::to_string_pretty(&modules)?,
+        )?;
+
+        let weights_path = path.join(SAFETENSORS_FILE);
+        match &self.model_weights {
+            ModelWeightsPath::Safetensors(src) => {
+                // Don't copy a file onto itself when saving to the folder the model came from
+                if fs::canonicalize(src)? != fs::canonicalize(&weights_path).unwrap_or_default() {
+                    fs::copy(src, &weights_path)?;
+                }
+            }
+            // The shards are merged into one file
+            ModelWeightsPath::ShardedSafetensors(srcs) => {
+                let mut tensors = HashMap::new();
+                for src in srcs {
+                    tensors.extend(candle_core::safetensors::load(src, &Device::Cpu)?);
+                }
+                candle_core::safetensors::save(&tensors, &weights_path)?
+            }
+            ModelWeightsPath::Pth(src) => write_pth_as_safetensors(src, &weights_path, None)?,
+            ModelWeightsPath::Gguf(src) => candle_core::safetensors::save(
+                &read_gguf_dequantized(src, &Device::Cpu)?,
+                &weights_path,
+            )?,
+            ModelWeightsPath::Embedded(bytes) => fs::write(&weights_path, bytes)?,
+            // The export is copied as is, and loaded with the `onnx` feature again
+            #[cfg(feature = "onnx")]
+            ModelWeightsPath::Onnx(src) => {
+                let onnx_path = path.join("model.onnx");
+                if fs::canonicalize(src)? != fs::canonicalize(&onnx_path).unwrap_or_default() {
+                    fs::copy(src, onnx_path)?;
+                }
+            }
+        }
+
+        if let Some(pca) = &self.pca {
+            pca.save(path)?;
+        }
+
+        Ok(())
     }
 
     pub fn tokenize<'s, E>(&self, sentences: Vec<E>) -> Result<Vec<Encoding>>
@@ -99,33 +436,557 @@ impl SentenceTransformer {
         let span = tracing::span!(tracing::Level::TRACE, "st-encode-batch");
         let _enter = span.enter();
 
-        encode_batch_with_usage(
+        self.encode_with_model_type(sentences, normalize, &self.model_type, None)
+    }
+
+    /// Encode a batch of sentences with a different pooling strategy than the one the model
+    /// was loaded with. Only embedding models support overriding the pooling strategy.
+    pub fn encode_batch_with_pooling<'s, E>(
+        &self,
+        sentences: Vec<E>,
+        normalize: bool,
+        pooling_strategy: PoolingStrategy,
+    ) -> Result<EmbedOutput>
+    where
+        E: Into<EncodeInput<'s>> + Send,
+    {
+        let span = tracing::span!(tracing::Level::TRACE, "st-encode-batch");
+        let _enter = span.enter();
+
+        let model_type = self.pooling_model_type(pooling_strategy)?;
+        self.encode_with_model_type(sentences, normalize, &model_type, None)
+    }
+
+    /// The model type to encode with when overriding the pooling strategy.
+    pub(crate) fn pooling_model_type(
+        &self,
+        pooling_strategy: PoolingStrategy,
+    ) -> Result<ModelType> {
+        if self.model_type == ModelType::Classifier {
+            return Err(Error::InvalidArgument(
+                "The pooling strategy of a classifier model can't be overridden",
+            ));
+        }
+        if pooling_strategy == PoolingStrategy::Splade {
+            return Err(Error::InvalidArgument(
+                "SPLADE pooling is only available for models it was configured for",
+            ));
+        }
+        if pooling_strategy == PoolingStrategy::Pooler && self.pooler.is_none() {
+            return Err(Error::InvalidArgument(
+                "Pooler pooling is only available for models loaded with it",
+            ));
+        }
+
+        Ok(ModelType::Embedding(pooling_strategy))
+    }
+
+    /// Encode a batch of sentences, collecting the token ids in `ids_buffer` if given.
+    pub(crate) fn encode_with_model_type<'s, E>(
+        &self,
+        sentences: Vec<E>,
+        normalize: bool,
+        model_type: &ModelType,
+        ids_buffer: Option<&mut IdsBuffer>,
+    ) -> Result<EmbedOutput>
+    where
+        E: Into<EncodeInput<'s>> + Send,
+    {
+        let batch = tokenize_batch(self.model.as_ref(), &self.tokenizer, sentences, ids_buffer)?;
+
+        self.encode_tokenized_with_model_type(&batch, normalize, model_type)
+    }
+
+    /// Tokenize a batch of sentences and copy the token ids to the device of the model, to be
+    /// encoded later with [`Self::encode_tokenized`].
+    ///
+    /// This doesn't touch the model weights, so a server can prepare the next batch on another
+    /// thread while the model encodes the current one.
+    pub fn tokenize_batch<'s, E>(&self, sentences: Vec<E>) -> Result<TokenizedBatch>
+    where
+        E: Into<EncodeInput<'s>> + Send,
+    {
+        let span = tracing::span!(tracing::Level::TRACE, "st-tokenize-batch");
+        let _enter = span.enter();
+
+        tokenize_batch(self.model.as_ref(), &self.tokenizer, sentences, None)
+    }
+
+    /// Prepare sequences of token ids to be encoded with [`Self::encode_tokenized`], without
+    /// tokenizing, e.g. the ids of [`Self::tokenize`]. The ids are used as they are, so they
+    /// should include the special tokens of the model. Sequences longer than
+    /// [`Self::max_input_length`] are truncated.
+    pub fn batch_from_ids(&self, ids: Vec<Vec<u32>>) -> Result<TokenizedBatch> {
+        batch_from_ids(
             self.model.as_ref(),
             &self.tokenizer,
-            sentences,
-            &self.model_type,
-            normalize,
+            ids,
+            self.max_input_length(),
         )
     }
 
-    pub fn encode_batch<'s, E>(&self, sentences: Vec<E>, normalize: bool) -> Result<Tensor>
+    /// Encode sequences of token ids, see [`Self::batch_from_ids`].
+    pub fn encode_ids(&self, ids: Vec<Vec<u32>>, normalize: bool) -> Result<EmbedOutput> {
+        self.encode_tokenized(&self.batch_from_ids(ids)?, normalize)
+    }
+
+    /// Like [`Self::tokenize_batch`], with the tokens and their byte offsets in the encodings,
+    /// which the fast path leaves out.
+    pub(crate) fn tokenize_batch_with_offsets<'s, E>(
+        &self,
+        sentences: Vec<E>,
+    ) -> Result<TokenizedBatch>
     where
         E: Into<EncodeInput<'s>> + Send,
     {
+        let encodings = self.tokenizer.encode_batch(sentences, true)?;
+
+        batch_from_encodings(self.model.as_ref(), &self.tokenizer, encodings, None)
+    }
+
+    /// Encode a batch tokenized with [`Self::tokenize_batch`].
+    pub fn encode_tokenized(&self, batch: &TokenizedBatch, normalize: bool) -> Result<EmbedOutput> {
         let span = tracing::span!(tracing::Level::TRACE, "st-encode-batch");
         let _enter = span.enter();
 
-        encode_batch(
+        self.encode_tokenized_with_model_type(batch, normalize, &self.model_type)
+    }
+
+    /// Encode a batch tokenized with [`Self::tokenize_batch`] with a different pooling strategy
+    /// than the one the model was loaded with.
+    pub fn encode_tokenized_with_pooling(
+        &self,
+        batch: &TokenizedBatch,
+        normalize: bool,
+        pooling_strategy: PoolingStrategy,
+    ) -> Result<EmbedOutput> {
+        let span = tracing::span!(tracing::Level::TRACE, "st-encode-batch");
+        let _enter = span.enter();
+
+        let model_type = self.pooling_model_type(pooling_strategy)?;
+        self.encode_tokenized_with_model_type(batch, normalize, &model_type)
+    }
+
+    fn encode_tokenized_with_model_type(
+        &self,
+        batch: &TokenizedBatch,
+        normalize: bool,
+        model_type: &ModelType,
+    ) -> Result<EmbedOutput> {
+        if let Some(max_batch_tokens) = self.max_batch_tokens {
+            if batch.len() > 1 && batch.token_ids().elem_count() > max_batch_tokens {
+                return self.encode_split(batch, normalize, model_type, max_batch_tokens);
+            }
+        }
+
+        // Models whose pipeline ends in normalization always return normalized embeddings
+        let normalize = normalize || self.normalizes();
+        // Custom pooling replaces the pooling strategy of the model, unless it is overridden
+        let encode = |normalize| match &self.custom_pooling {
+            Some(pooling) if model_type == &self.model_type => {
+                encode_tokenized_with(self.model.as_ref(), batch, normalize, |embeddings| {
+                    let attention_mask = batch.attention_mask()?.to_dtype(embeddings.dtype())?;
+                    let pooled = pooling(embeddings, &attention_mask)?;
+                    match pooled.dims() {
+                        [n, _] if *n == batch.len() => Ok(pooled),
+                        _ => Err(Error::InvalidArgument(
+                            "Custom pooling should return an embedding per input",
+                        )),
+                    }
+                })
+            }
+            _ => encode_tokenized(self.model.as_ref(), batch, model_type, normalize),
+        };
+        if *model_type != ModelType::Embedding(PoolingStrategy::Pooler)
+            && self.output_modules.is_empty()
+            && self.pca.is_none()
+            && self.truncate_dim.is_none()
+        {
+            let output = encode(normalize)?;
+            return Ok(EmbedOutput {
+                embeddings: output.embeddings.to_dtype(self.output_dtype)?,
+                ..output
+            });
+        }
+
+        // Normalization has to happen after the projections
+        let EmbedOutput {
+            mut embeddings,
+            usage,
+            inputs,
+        } = encode(false)?;
+
+        embeddings = self.apply_output_modules(embeddings, model_type)?;
+        if let Some(pca) = &self.pca {
+            embeddings = pca.transform(&embeddings)?;
+        }
+        if let Some(dim) = self.truncate_dim {
+            embeddings = utils::truncate_dim(&embeddings, dim)?;
+        }
+        // Truncated embeddings are normalized again
+        let embeddings = if normalize {
+            utils::normalize_l2(&embeddings)?
+        } else {
+            embeddings
+        };
+
+        Ok(EmbedOutput {
+            embeddings: embeddings.to_dtype(self.output_dtype)?,
+            usage,
+            inputs,
+        })
+    }
+
+    /// Encode a batch in sub-batches of at most `max_tokens` tokens, and put the embeddings back
+    /// together in the order of the batch.
+    fn encode_split(
+        &self,
+        batch: &TokenizedBatch,
+        normalize: bool,
+        model_type: &ModelType,
+        max_tokens: usize,
+    ) -> Result<EmbedOutput> {
+        let mut positions = Vec::with_capacity(batch.len());
+        let mut embeddings = Vec::new();
+        let mut usage = Usage::default();
+        let mut inputs = vec![InputUsage::default(); batch.len()];
+        let sub_batches = split_batch(self.model.as_ref(), &self.tokenizer, batch, max_tokens)?;
+        for (sub_positions, sub_batch) in sub_batches {
+            let output =
+                self.encode_tokenized_with_model_type(&sub_batch, normalize, model_type)?;
+            usage.prompt_tokens += output.usage.prompt_tokens;
+            usage.total_tokens += output.usage.total_tokens;
+            for (&position, input) in sub_positions.iter().zip(output.inputs) {
+                inputs[position] = input;
+            }
+            positions.extend(sub_positions);
+            embeddings.push(output.embeddings);
+        }
+
+        // Row of the concatenated embeddings for each sentence of the batch
+        let mut rows = vec![0u32; positions.len()];
+        for (row, position) in positions.into_iter().enumerate() {
+            rows[position] = row as u32;
+        }
+        let embeddings = Tensor::cat(&embeddings, 0)?;
+        let rows = Tensor::from_vec(rows, batch.len(), embeddings.device())?;
+
+        Ok(EmbedOutput {
+            embeddings: embeddings.index_select(&rows, 0)?,
+            usage,
+            inputs,
+        })
+    }
+
+    /// Apply the modules after pooling to pooled embeddings: the pooler for
+    /// [`PoolingStrategy::Pooler`], and then the output modules of the pipeline.
+    fn apply_output_modules(&self, embeddings: Tensor, model_type: &ModelType) -> Result<Tensor> {
+        let mut embeddings = match (model_type, &self.pooler) {
+            (ModelType::Embedding(PoolingStrategy::Pooler), Some(pooler)) => {
+                pooler.forward(&embeddings)?
+            }
+            _ => embeddings,
+        };
+        for module in &self.output_modules {
+            embeddings = module.forward(&embeddings)?;
+        }
+
+        Ok(embeddings)
+    }
+
+    pub fn encode_batch<'s, E>(&self, sentences: Vec<E>, normalize: bool) -> Result<Tensor>
+    where
+        E: Into<EncodeInput<'s>> + Send,
+    {
+        Ok(self
+            .encode_batch_with_usage(sentences, normalize)?
+            .embeddings)
+    }
+
+    /// Encode pairs of sentences, e.g. a premise and a hypothesis, into one embedding each.
+    ///
+    /// Each pair is tokenized as one input the way the model expects, such as
+    /// `[CLS] a [SEP] b [SEP]` for BERT models, with the second sentence in its own segment.
+    pub fn encode_pairs<'s, A, B>(&self, pairs: Vec<(A, B)>, normalize: bool) -> Result<Tensor>
+    where
+        (A, B): Into<EncodeInput<'s>> + Send,
+    {
+        Ok(self.encode_pairs_with_usage(pairs, normalize)?.embeddings)
+    }
+
+    /// Like [`Self::encode_pairs`], along with the usage of each pair.
+    pub fn encode_pairs_with_usage<'s, A, B>(
+        &self,
+        pairs: Vec<(A, B)>,
+        normalize: bool,
+    ) -> Result<EmbedOutput>
+    where
+        (A, B): Into<EncodeInput<'s>> + Send,
+    {
+        self.encode_batch_with_usage(pairs, normalize)
+    }
+
+    /// Names of the tasks the model has a LoRA adapter for, such as `retrieval.query` and
+    /// `retrieval.passage` for `jinaai/jina-embeddings-v3`. Empty for most models.
+    pub fn tasks(&self) -> &[String] {
+        self.model.tasks()
+    }
+
+    /// A replica of the model that encodes with the adapter of a task, see [`Self::tasks`].
+    /// Like clones, replicas share the model weights.
+    pub fn with_task(&self, task: &str) -> Result<Self> {
+        let task =
+            self.tasks()
+                .iter()
+                .position(|name| name == task)
+                .ok_or(Error::InvalidArgument(
+                    "Model has no adapter for this task.",
+                ))?;
+
+        let mut replica = self.clone();
+        replica.model = Arc::new(TaskModel::new(self.model.clone(), task));
+        Ok(replica)
+    }
+
+    /// Instruction the model expects in front of the inputs of a task, if any.
+    pub fn task_instruction(&self, task: &str) -> Option<&str> {
+        self.model_config["task_instructions"][task]
+            .as_str()
+            .filter(|instruction| !instruction.is_empty())
+    }
+
+    /// Encode sentences for a task, with its adapter and instruction, see [`Self::tasks`].
+    pub fn encode_batch_with_task<S: AsRef<str>>(
+        &self,
+        sentences: &[S],
+        normalize: bool,
+        task: &str,
+    ) -> Result<EmbedOutput> {
+        let instruction = self.task_instruction(task).unwrap_or_default();
+        let sentences: Vec<String> = sentences
+            .iter()
+            .map(|sentence| format!("{instruction}{}", sentence.as_ref()))
+            .collect();
+
+        self.with_task(task)?
+            .encode_batch_with_usage(sentences, normalize)
+    }
+
+    /// Encode sentences that may be longer than the context of the model, see
+    /// [`crate::core::chunking`]. Sentences that don't fit are split into chunks of
+    /// [`Self::max_input_length`] tokens that overlap by `config.overlap` tokens, and the
+    /// embeddings of their chunks are combined into one embedding per sentence.
+    ///
+    /// The usage of each sentence counts the tokens of all its chunks.
+    pub fn encode_long<S: AsRef<str>>(
+        &self,
+        sentences: &[S],
+        normalize: bool,
+        config: &ChunkConfig,
+    ) -> Result<EmbedOutput> {
+        let span = tracing::span!(tracing::Level::TRACE, "st-encode-long");
+        let _enter = span.enter();
+
+        // Chunks are the overflowing encodings of truncation with a stride, padded as a whole
+        let mut tokenizer = Tokenizer::clone(&self.tokenizer);
+        tokenizer.with_padding(None);
+        let truncation = match self.max_input_length() {
+            Some(max_length) => {
+                let special_tokens = tokenizer
+                    .get_post_processor()
+                    .map_or(0, |processor| processor.added_tokens(false));
+                if config.overlap + special_tokens >= max_length {
+                    return Err(Error::InvalidArgument(
+                        "The overlap of chunks should be shorter than the context of the model",
+                    ));
+                }
+                Some(TruncationParams {
+                    max_length,
+                    stride: config.overlap,
+                    ..Default::default()
+                })
+            }
+            None => None,
+        };
+        tokenizer.with_truncation(truncation)?;
+
+        let sentences: Vec<&str> = sentences.iter().map(AsRef::as_ref).collect();
+        let (mut chunks, counts) = split_chunks(tokenizer.encode_batch(sentences, true)?);
+        if let Some(padding) = self.tokenizer.get_padding() {
+            pad_encodings(&mut chunks, padding)?;
+        }
+        let batch = batch_from_encodings(self.model.as_ref(), &self.tokenizer, chunks, None)?;
+
+        let EmbedOutput {
+            embeddings,
+            usage,
+            inputs,
+        } = self.encode_tokenized_with_model_type(&batch, false, &self.model_type)?;
+        let (embeddings, inputs) = aggregate(&embeddings, &inputs, &counts, config.aggregation)?;
+        let embeddings = if normalize || self.normalizes() {
+            utils::normalize_l2(&embeddings)?
+        } else {
+            embeddings
+        };
+
+        Ok(EmbedOutput {
+            embeddings: embeddings.to_dtype(self.output_dtype)?,
+            usage,
+            inputs,
+        })
+    }
+
+    /// Maximum number of tokens of an input, including special tokens: the truncation length of
+    /// the tokenizer, or else the number of positions of the model. `None` for models without a
+    /// limit, such as static embeddings.
+    pub fn max_input_length(&self) -> Option<usize> {
+        if let Some(truncation) = self.tokenizer.get_truncation() {
+            return Some(truncation.max_length);
+        }
+        let max_positions = self.model_config["max_position_embeddings"].as_u64()? as usize;
+
+        // The positions of RoBERTa models start after the padding token
+        Some(match self.model_config["model_type"].as_str() {
+            Some("roberta" | "xlm-roberta" | "camembert") => {
+                let pad_token_id = self.model_config["pad_token_id"].as_u64().unwrap_or(1);
+                max_positions.saturating_sub(pad_token_id as usize + 1)
+            }
+            _ => max_positions,
+        })
+    }
+
+    /// Start an [`EncodeSession`], which reuses its host buffers across encode calls.
+    pub fn session(&self) -> EncodeSession<'_> {
+        EncodeSession::new(self)
+    }
+
+    pub(crate) fn model_type(&self) -> &ModelType {
+        &self.model_type
+    }
+
+    pub(crate) fn model_config(&self) -> &serde_json::Value {
+        &self.model_config
+    }
+
+    pub(crate) fn model_weights(&self) -> &ModelWeightsPath {
+        &self.model_weights
+    }
+
+    /// The embedding of each token of a batch tokenized with [`Self::tokenize_batch`], of shape
+    /// `(batch, seq_len, hidden_size)`, in the data type of the model.
+    pub(crate) fn encode_tokens(&self, batch: &TokenizedBatch) -> Result<Tensor> {
+        encode_tokens(self.model.as_ref(), batch)
+    }
+
+    /// Pooling strategy of the model, unless it is a classifier or uses custom pooling.
+    pub fn pooling_strategy(&self) -> Option<PoolingStrategy> {
+        if self.custom_pooling.is_some() {
+            return None;
+        }
+
+        match self.model_type {
+            ModelType::Embedding(pooling_strategy) => Some(pooling_strategy),
+            ModelType::Classifier => None,
+        }
+    }
+
+    /// Whether the model is a `*ForSequenceClassification` checkpoint, which can classify texts
+    /// with [`TextClassifier::from_model`].
+    pub fn is_text_classifier(&self) -> bool {
+        self.model_type == ModelType::Classifier
+            && serde_json::from_value::<BaseModelConfig>(self.model_config.clone())
+                .is_ok_and(|config| !config.is_token_classifier())
+    }
+
+    /// Device the model runs on.
+    pub fn device(&self) -> &Device {
+        self.model.get_device()
+    }
+
+    /// Data type the model weights are loaded in.
+    pub fn dtype(&self) -> DType {
+        self.dtype
+    }
+
+    /// Commit hash of the revision of the model repository, if the model was loaded from the
+    /// Hugging Face cache.
+    pub fn commit_hash(&self) -> Option<&str> {
+        self.commit.as_deref()
+    }
+
+    /// Encode a batch of sentences without applying the PCA projection, to fit a new projection on.
+    pub(crate) fn encode_batch_unprojected<'s, E>(&self, sentences: Vec<E>) -> Result<Tensor>
+    where
+        E: Into<EncodeInput<'s>> + Send,
+    {
+        let embeddings = encode_batch(
             self.model.as_ref(),
             &self.tokenizer,
             sentences,
             &self.model_type,
-            normalize,
-        )
+            false,
+        )?;
+
+        self.apply_output_modules(embeddings, &self.model_type)
+    }
+
+    /// Time spent in each stage of loading the model. All zero if the model wasn't loaded from a
+    /// repository or folder.
+    pub fn load_report(&self) -> &LoadReport {
+        &self.load_report
+    }
+
+    /// The modules applied to the pooled embeddings, in order. See [`crate::core::pipeline`].
+    pub fn output_modules(&self) -> &[OutputModule] {
+        &self.output_modules
+    }
+
+    /// Whether the pipeline of the model ends in a `Normalize` module, so that its embeddings
+    /// are normalized even if not asked for.
+    pub fn normalizes(&self) -> bool {
+        matches!(self.output_modules.last(), Some(OutputModule::Normalize))
+    }
+
+    /// The PCA projection applied to the embeddings, if any. See [`crate::reduce`].
+    pub fn pca(&self) -> Option<&Pca> {
+        self.pca.as_ref()
     }
 
+    /// Number of leading dimensions the embeddings are truncated to, if set with
+    /// [`SentenceTransformerBuilder::with_truncate_dim`].
+    pub fn truncate_dim(&self) -> Option<usize> {
+        self.truncate_dim
+    }
+
+    /// Data type of the returned embeddings, set with
+    /// [`SentenceTransformerBuilder::with_output_dtype`].
+    pub fn output_dtype(&self) -> DType {
+        self.output_dtype
+    }
+
+    /// Maximum number of tokens encoded in one forward pass, if set with
+    /// [`SentenceTransformerBuilder::with_max_batch_tokens`].
+    pub fn max_batch_tokens(&self) -> Option<usize> {
+        self.max_batch_tokens
+    }
+
+    /// Set or remove the PCA projection applied to the embeddings.
+    pub fn set_pca(&mut self, pca: Option<Pca>) -> Result<()> {
+        self.pca = pca
+            .map(|pca| pca.to_device(self.model.get_device()))
+            .transpose()?;
+        Ok(())
+    }
+
+    /// The tokenizer the model encodes its inputs with.
+    pub fn get_tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    /// Mutable access to the tokenizer. If the tokenizer is shared with clones of the model, it
+    /// is copied first, so the clones are not affected.
     pub fn get_tokenizer_mut(&mut self) -> &mut Tokenizer {
-        &mut self.tokenizer
+        Arc::make_mut(&mut self.tokenizer)
     }
 }
 
@@ -143,7 +1004,14 @@ where
 {
     model_repo: Option<ModelRepo>,
     pooling_strategy: Option<PoolingStrategy>,
+    custom_pooling: Option<CustomPooling>,
+    truncate_dim: Option<usize>,
+    output_dtype: DType,
+    max_batch_tokens: Option<usize>,
     device: Device,
+    dtype: DType,
+    quantized: bool,
+    device_fallback: bool,
     _marker: PhantomData<S>,
 }
 
@@ -158,7 +1026,14 @@ impl SentenceTransformerBuilder<Uninitialised> {
         Self {
             model_repo: None,
             pooling_strategy: None,
+            custom_pooling: None,
+            truncate_dim: None,
+            output_dtype: DType::F32,
+            max_batch_tokens: None,
             device: Device::Cpu,
+            dtype: DType::F32,
+            quantized: false,
+            device_fallback: true,
             _marker: PhantomData,
         }
     }
@@ -168,6 +1043,9 @@ impl<S> SentenceTransformerBuilder<S>
 where
     S: BuilderState,
 {
+    /// Load the model from a Hugging Face Hub repository, given as `<repo>[:<revision>]`. The
+    /// files are downloaded to the Hugging Face cache if they aren't there yet.
+    #[cfg(feature = "hub")]
     pub fn with_model_repo<MR: AsRef<str>>(
         self,
         model_repo: MR,
@@ -180,7 +1058,14 @@ where
         Ok(SentenceTransformerBuilder::<Initialised> {
             model_repo: Some(model_repo),
             pooling_strategy: self.pooling_strategy,
+            custom_pooling: self.custom_pooling,
+            truncate_dim: self.truncate_dim,
+            output_dtype: self.output_dtype,
+            max_batch_tokens: self.max_batch_tokens,
             device: self.device,
+            dtype: self.dtype,
+            quantized: self.quantized,
+            device_fallback: self.device_fallback,
             _marker: PhantomData,
         })
     }
@@ -193,7 +1078,34 @@ where
         SentenceTransformerBuilder::<Initialised> {
             model_repo: Some(model_repo_folder),
             pooling_strategy: self.pooling_strategy,
+            custom_pooling: self.custom_pooling,
+            truncate_dim: self.truncate_dim,
+            output_dtype: self.output_dtype,
+            max_batch_tokens: self.max_batch_tokens,
             device: self.device,
+            dtype: self.dtype,
+            quantized: self.quantized,
+            device_fallback: self.device_fallback,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Load the model from files embedded in the binary with [`crate::include_model`].
+    pub fn with_model_bytes(
+        self,
+        model_bytes: ModelBytes,
+    ) -> SentenceTransformerBuilder<Initialised> {
+        SentenceTransformerBuilder::<Initialised> {
+            model_repo: Some(ModelRepo::from_bytes(model_bytes)),
+            pooling_strategy: self.pooling_strategy,
+            custom_pooling: self.custom_pooling,
+            truncate_dim: self.truncate_dim,
+            output_dtype: self.output_dtype,
+            max_batch_tokens: self.max_batch_tokens,
+            device: self.device,
+            dtype: self.dtype,
+            quantized: self.quantized,
+            device_fallback: self.device_fallback,
             _marker: PhantomData,
         }
     }
@@ -205,10 +1117,87 @@ where
         }
     }
 
+    /// Pool the token embeddings with a function of the token embeddings and the attention
+    /// mask, such as attention pooling, instead of the pooling strategy of the model. See
+    /// [`CustomPooling`]. Pooling overrides such as
+    /// [`SentenceTransformer::encode_batch_with_pooling`] still use the built-in strategies.
+    ///
+    /// Only applies to [`Self::build`]; models with custom pooling can't be saved.
+    pub fn with_custom_pooling<F>(self, pooling: F) -> Self
+    where
+        F: Fn(&Tensor, &Tensor) -> Result<Tensor> + Send + Sync + 'static,
+    {
+        Self {
+            custom_pooling: Some(Arc::new(pooling)),
+            ..self
+        }
+    }
+
+    /// Truncate the embeddings to their first `truncate_dim` dimensions, for models trained
+    /// with Matryoshka Representation Learning (MRL) such as `nomic-ai/nomic-embed-text-v1.5`,
+    /// whose leading dimensions carry most of the information. Normalized embeddings are
+    /// normalized after truncation.
+    ///
+    /// Only applies to [`Self::build`].
+    pub fn with_truncate_dim(self, truncate_dim: usize) -> Self {
+        Self {
+            truncate_dim: Some(truncate_dim),
+            ..self
+        }
+    }
+
+    /// Limit the number of tokens encoded in one forward pass, padding included. Larger batches
+    /// are split into sub-batches of sentences of similar length, which are encoded one after
+    /// the other, so that encoding thousands of sentences at once doesn't run out of memory. The
+    /// embeddings are returned in the order of the sentences, as without a limit.
+    ///
+    /// Only applies to [`Self::build`].
+    pub fn with_max_batch_tokens(self, max_batch_tokens: usize) -> Self {
+        Self {
+            max_batch_tokens: Some(max_batch_tokens),
+            ..self
+        }
+    }
+
     pub fn with_device(self, device: Device) -> Self {
         Self { device, ..self }
     }
 
+    /// Set the data type the model weights are loaded in (`F32` by default). Embeddings are
+    /// returned as `F32` regardless, unless set otherwise with [`Self::with_output_dtype`].
+    pub fn with_dtype(self, dtype: DType) -> Self {
+        Self { dtype, ..self }
+    }
+
+    /// Set the data type the embeddings are returned in: `F32` (default), `F16` or `BF16`. Half
+    /// precision embeddings take half the memory, which adds up for large corpora. The
+    /// embeddings are computed in full precision and converted at the end.
+    ///
+    /// Only applies to [`Self::build`].
+    pub fn with_output_dtype(self, output_dtype: DType) -> Self {
+        Self {
+            output_dtype,
+            ..self
+        }
+    }
+
+    /// Whether to fall back to the CPU, with a warning, if the model fails to load on the
+    /// device (enabled by default). Disable it to get an error instead.
+    pub fn with_device_fallback(self, device_fallback: bool) -> Self {
+        Self {
+            device_fallback,
+            ..self
+        }
+    }
+
+    /// Prefer pre-quantized GGUF weights (e.g. `model.Q8_0.gguf`) over the full precision
+    /// weights, if the repository has them, which saves on downloads and disk space at some cost
+    /// in accuracy. BERT models run on the quantized weights directly, which also saves memory;
+    /// others are dequantized to the data type of the model when loading.
+    pub fn with_quantized_weights(self, quantized: bool) -> Self {
+        Self { quantized, ..self }
+    }
+
     #[cfg(feature = "metal")]
     pub fn with_metal_device(self) -> Result<Self> {
         let device = Device::new_metal(0)?;
@@ -226,11 +1215,909 @@ where
 
 impl SentenceTransformerBuilder<Initialised> {
     pub fn build(self) -> Result<SentenceTransformer> {
-        match self.model_repo {
+        if !matches!(self.output_dtype, DType::F32 | DType::F16 | DType::BF16) {
+            return Err(Error::InvalidArgument(
+                "Embeddings can only be returned as F32, F16 or BF16",
+            ));
+        }
+        match &self.model_repo {
             None => Err(Error::ModelLoad("No model directory or repository given.")),
-            Some(mr) => {
-                SentenceTransformer::from_model_repo(&mr, &self.device, self.pooling_strategy)
-            }
+            Some(mr) => load_with_fallback(&self.device, self.device_fallback, |device| {
+                // With custom pooling, the pooling strategy is only needed to load the model
+                let pooling_strategy = match &self.custom_pooling {
+                    Some(_) => self.pooling_strategy.or(Some(PoolingStrategy::Cls)),
+                    None => self.pooling_strategy,
+                };
+                let mut model = SentenceTransformer::from_model_repo(
+                    mr,
+                    device,
+                    self.dtype,
+                    pooling_strategy,
+                    None,
+                    self.quantized,
+                )?;
+                model.custom_pooling = self.custom_pooling.clone();
+                model.truncate_dim = self.truncate_dim;
+                model.output_dtype = self.output_dtype;
+                model.max_batch_tokens = self.max_batch_tokens;
+
+                Ok(model)
+            }),
+        }
+    }
+
+    /// Build a [`DualEncoder`], which embeds queries and documents with the branches of an
+    /// asymmetric (`Asym`) model, or with the prompts of the model for each.
+    pub fn build_dual(self) -> Result<DualEncoder> {
+        match &self.model_repo {
+            None => Err(Error::ModelLoad("No model directory or repository given.")),
+            Some(mr) => load_with_fallback(&self.device, self.device_fallback, |device| {
+                DualEncoder::from_model_repo(
+                    mr,
+                    device,
+                    self.dtype,
+                    self.pooling_strategy,
+                    self.quantized,
+                )
+            }),
+        }
+    }
+
+    /// Build a [`CrossEncoder`], which scores pairs of texts with a `*ForSequenceClassification`
+    /// model, e.g. to rerank search results. The pooling strategy is ignored.
+    pub fn build_cross_encoder(self) -> Result<CrossEncoder> {
+        match &self.model_repo {
+            None => Err(Error::ModelLoad("No model directory or repository given.")),
+            Some(mr) => load_with_fallback(&self.device, self.device_fallback, |device| {
+                CrossEncoder::from_model_repo(mr, device, self.dtype, self.quantized)
+            }),
+        }
+    }
+
+    /// Build a [`TextClassifier`], which classifies texts with a `*ForSequenceClassification`
+    /// model into the labels of its configuration. The pooling strategy is ignored.
+    pub fn build_classifier(self) -> Result<TextClassifier> {
+        match &self.model_repo {
+            None => Err(Error::ModelLoad("No model directory or repository given.")),
+            Some(mr) => load_with_fallback(&self.device, self.device_fallback, |device| {
+                TextClassifier::from_model_repo(mr, device, self.dtype, self.quantized)
+            }),
+        }
+    }
+
+    /// Build a [`TokenClassifier`], which labels each token of texts with a
+    /// `*ForTokenClassification` model, e.g. for named entity recognition.
+    pub fn build_token_classifier(self) -> Result<TokenClassifier> {
+        match &self.model_repo {
+            None => Err(Error::ModelLoad("No model directory or repository given.")),
+            Some(mr) => load_with_fallback(&self.device, self.device_fallback, |device| {
+                TokenClassifier::from_model_repo(mr, device, self.dtype, self.quantized)
+            }),
+        }
+    }
+
+    /// Build a [`ColBERT`] model, which embeds each token of queries and documents for
+    /// late-interaction retrieval. The pooling strategy is ignored.
+    pub fn build_colbert(self) -> Result<ColBERT> {
+        match &self.model_repo {
+            None => Err(Error::ModelLoad("No model directory or repository given.")),
+            Some(mr) => load_with_fallback(&self.device, self.device_fallback, |device| {
+                ColBERT::from_model_repo(mr, device, self.dtype, self.quantized)
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::repo::SAFETENSORS_INDEX_FILE;
+    use crate::core::test_utils::{create_tiny_bert_repo, TINY_HIDDEN_SIZE};
+    use candle_core::IndexOp;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_concurrent_encoding() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+
+        let model = Arc::new(
+            SentenceTransformer::builder()
+                .with_model_folder(dir.path())
+                .build()?,
+        );
+        let sentences = [
+            "The cat sits outside",
+            "I love pasta",
+            "The new movie is awesome",
+        ];
+        let expected = model
+            .encode_batch(sentences.to_vec(), true)?
+            .to_vec2::<f32>()?;
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let model = model.clone();
+                std::thread::spawn(move || -> Result<Vec<Vec<f32>>> {
+                    Ok(model.encode_batch(sentences.to_vec(), true)?.to_vec2()?)
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            assert_eq!(handle.join().expect("Encoding thread panicked")?, expected);
         }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clone_shares_weights() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+        let mut replica = model.clone();
+        assert!(Arc::ptr_eq(&model.model, &replica.model));
+        assert!(Arc::ptr_eq(&model.tokenizer, &replica.tokenizer));
+
+        // Changing the tokenizer of a clone doesn't affect the original
+        replica
+            .get_tokenizer_mut()
+            .with_truncation(Some(tokenizers::TruncationParams {
+                max_length: 2,
+                ..Default::default()
+            }))?;
+        assert!(!Arc::ptr_eq(&model.tokenizer, &replica.tokenizer));
+        assert_eq!(replica.tokenize(vec!["The cat sits outside"])?[0].len(), 2);
+        assert!(model.tokenize(vec!["The cat sits outside"])?[0].len() > 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_and_reload() -> Result<()> {
+        let src = tempdir()?;
+        create_tiny_bert_repo(src.path())?;
+
+        let model = SentenceTransformer::builder()
+            .with_model_folder(src.path())
+            .build()?;
+
+        let dst = tempdir()?;
+        model.save(dst.path())?;
+
+        for file in [
+            CONFIG_FILE,
+            TOKENIZER_FILE,
+            MODULES_FILE,
+            POOLING_CONFIG_FILE,
+            SAFETENSORS_FILE,
+        ] {
+            assert!(dst.path().join(file).exists(), "{file} is missing");
+        }
+
+        let reloaded = SentenceTransformer::builder()
+            .with_model_folder(dst.path())
+            .build()?;
+
+        let sentences = vec!["The cat sits outside", "I love pasta"];
+        let expected = model.encode_batch(sentences.clone(), true)?;
+        let actual = reloaded.encode_batch(sentences, true)?;
+
+        assert_eq!(actual.dims(), &[2, TINY_HIDDEN_SIZE]);
+        let diff = (expected - actual)?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert!(diff < 1e-6);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dense_modules() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        // The mean of the token embeddings, computed by hand from the attention mask
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .with_custom_pooling(|embeddings, attention_mask| {
+                let sums = embeddings
+                    .broadcast_mul(&attention_mask.unsqueeze(2)?)?
+                    .sum(1)?;
+                Ok(sums.broadcast_div(&attention_mask.sum_keepdim(1)?)?)
+            })
+            .build()?;
+
+        // Project the pooled embeddings to 4 dimensions, like LaBSE with `Tanh`
+        let dense_dir = dir.path().join("2_Dense");
+        fs::create_dir_all(&dense_dir)?;
+        fs::write(
+            dense_dir.join(CONFIG_FILE),
+            json!({"in_features": TINY_HIDDEN_SIZE, "out_features": 4, "bias": true}).to_string(),
+        )?;
+        let weight = Tensor::randn(0f32, 1., (4, TINY_HIDDEN_SIZE), &Device::Cpu)?;
+        let bias = Tensor::randn(0f32, 1., 4, &Device::Cpu)?;
+        candle_core::safetensors::save(
+            &HashMap::from([
+                ("linear.weight".to_string(), weight.clone()),
+                ("linear.bias".to_string(), bias.clone()),
+            ]),
+            dense_dir.join(SAFETENSORS_FILE),
+        )?;
+        fs::write(
+            dir.path().join(MODULES_FILE),
+            json!([
+                {"idx": 0, "name": "0", "path": "", "type": "sentence_transformers.models.Transformer"},
+                {"idx": 1, "name": "1", "path": "1_Pooling", "type": "sentence_transformers.models.Pooling"},
+                {"idx": 2, "name": "2", "path": "2_Dense", "type": "sentence_transformers.models.Dense"}
+            ])
+            .to_string(),
+        )?;
+
+        let projected = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+        assert_eq!(projected.output_modules().len(), 1);
+
+        let sentences = vec!["The cat sits outside", "I love pasta"];
+        let embeddings = projected.encode_batch(sentences.clone(), true)?;
+        assert_eq!(embeddings.dims(), &[2, 4]);
+        // The mean, not the sum, goes through the nonlinear projection like in sentence-transformers
+        let pooled = model.encode_batch(sentences.clone(), false)?;
+        let expected =
+            utils::normalize_l2(&pooled.matmul(&weight.t()?)?.broadcast_add(&bias)?.tanh()?)?;
+        let diff = (&embeddings - expected)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-5);
+
+        // The modules are saved along with the model
+        let dst = tempdir()?;
+        projected.save(dst.path())?;
+        let reloaded = SentenceTransformer::builder()
+            .with_model_folder(dst.path())
+            .build()?;
+        let diff = (embeddings - reloaded.encode_batch(sentences, true)?)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-6);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pooler() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+
+        // Add the pooler of a `BertModel` checkpoint
+        let weights_path = dir.path().join(SAFETENSORS_FILE);
+        let mut tensors = candle_core::safetensors::load(&weights_path, &Device::Cpu)?;
+        let weight = Tensor::randn(0f32, 1., (TINY_HIDDEN_SIZE, TINY_HIDDEN_SIZE), &Device::Cpu)?;
+        let bias = Tensor::randn(0f32, 1., TINY_HIDDEN_SIZE, &Device::Cpu)?;
+        tensors.insert("pooler.dense.weight".to_string(), weight.clone());
+        tensors.insert("pooler.dense.bias".to_string(), bias.clone());
+        candle_core::safetensors::save(&tensors, &weights_path)?;
+
+        let load = |pooling_strategy| {
+            SentenceTransformer::builder()
+                .with_model_folder(dir.path())
+                .with_pooling_strategy(pooling_strategy)
+                .build()
+        };
+        let cls = load(PoolingStrategy::Cls)?;
+        let pooler = load(PoolingStrategy::Pooler)?;
+
+        let sentences = vec!["The cat sits outside", "I love pasta"];
+        let embeddings = pooler.encode_batch(sentences.clone(), false)?;
+        let expected = cls
+            .encode_batch(sentences.clone(), false)?
+            .matmul(&weight.t()?)?
+            .broadcast_add(&bias)?
+            .tanh()?;
+        let diff = (&embeddings - expected)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-5);
+
+        // Only models loaded with their pooler can use it
+        assert!(cls
+            .encode_batch_with_pooling(sentences.clone(), false, PoolingStrategy::Pooler)
+            .is_err());
+
+        // Saved as CLS pooling followed by a `Dense` module
+        let dst = tempdir()?;
+        pooler.save(dst.path())?;
+        let reloaded = SentenceTransformer::builder()
+            .with_model_folder(dst.path())
+            .build()?;
+        assert_eq!(reloaded.pooling_strategy(), Some(PoolingStrategy::Cls));
+        let diff = (embeddings - reloaded.encode_batch(sentences, false)?)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-6);
+
+        // Checkpoints without a pooler can't be loaded with it
+        tensors.remove("pooler.dense.weight");
+        candle_core::safetensors::save(&tensors, &weights_path)?;
+        assert!(load(PoolingStrategy::Pooler).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_pooling() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        let mean = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .with_pooling_strategy(PoolingStrategy::Mean)
+            .build()?;
+
+        // The built-in mean pooling, as a closure
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .with_custom_pooling(|embeddings, attention_mask| {
+                Ok(embeddings
+                    .broadcast_mul(&attention_mask.unsqueeze(2)?)?
+                    .sum(1)?
+                    .broadcast_div(&attention_mask.sum_keepdim(1)?)?)
+            })
+            .build()?;
+        assert_eq!(model.pooling_strategy(), None);
+
+        let sentences = vec!["The cat", "A dog sits on the mat"];
+        let expected = mean.encode_batch(sentences.clone(), true)?;
+        let embeddings = model.encode_batch(sentences.clone(), true)?;
+        let diff = (expected - embeddings)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-6);
+        assert!(model.save(tempdir()?.path()).is_err());
+
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .with_custom_pooling(|embeddings, _| Ok(embeddings.clone()))
+            .build()?;
+        assert!(model.encode_batch(sentences, true).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncate_dim() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+        let truncated = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .with_truncate_dim(4)
+            .build()?;
+        assert_eq!(truncated.truncate_dim(), Some(4));
+
+        let sentences = vec!["The cat sits outside", "I love pasta"];
+        let full = model.encode_batch(sentences.clone(), false)?;
+        let embeddings = truncated.encode_batch(sentences.clone(), false)?;
+        assert_eq!(embeddings.dims(), &[2, 4]);
+        let diff = (full.narrow(1, 0, 4)? - embeddings)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-6);
+
+        // Normalized after truncation
+        let norms = truncated
+            .encode_batch(sentences.clone(), true)?
+            .sqr()?
+            .sum(1)?
+            .to_vec1::<f32>()?;
+        assert!(norms.iter().all(|norm| (norm - 1.).abs() < 1e-5));
+
+        let too_large = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .with_truncate_dim(TINY_HIDDEN_SIZE + 1)
+            .build()?;
+        assert!(too_large.encode_batch(sentences, true).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_long() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        let mut model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+        assert_eq!(model.max_input_length(), Some(128));
+
+        // Chunks of 4 tokens between `[CLS]` and `[SEP]`
+        model
+            .get_tokenizer_mut()
+            .with_truncation(Some(tokenizers::TruncationParams {
+                max_length: 6,
+                ..Default::default()
+            }))?;
+        let config = ChunkConfig {
+            overlap: 1,
+            ..Default::default()
+        };
+        let sentences = vec!["the cat sits outside on the mat", "i love pasta"];
+        let output = model.encode_long(&sentences, false, &config)?;
+        assert_eq!(output.embeddings.dims(), &[2, TINY_HIDDEN_SIZE]);
+        assert_eq!(output.inputs[0].tokens, 12);
+        assert!(!output.inputs[0].truncated);
+
+        let chunks = model.encode_batch(
+            vec!["the cat sits outside", "outside on the mat", "i love pasta"],
+            false,
+        )?;
+        let expected = Tensor::cat(&[chunks.i(0..2)?.mean_keepdim(0)?, chunks.i(2..3)?], 0)?;
+        let diff = (output.embeddings - expected)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-5);
+
+        let config = ChunkConfig {
+            overlap: 4,
+            ..Default::default()
+        };
+        assert!(model.encode_long(&sentences, false, &config).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_batch_tokens() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+        // Every sentence in a sub-batch of its own
+        let split = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .with_max_batch_tokens(1)
+            .build()?;
+        assert_eq!(split.max_batch_tokens(), Some(1));
+
+        let sentences = vec![
+            "The cat sits outside",
+            "I love pasta",
+            "The new movie is awesome and everybody should watch it",
+            "A man is playing guitar",
+            "Hi",
+        ];
+        let batch = split.tokenize_batch(sentences.clone())?;
+        let sub_batches = split_batch(split.model.as_ref(), &split.tokenizer, &batch, 16)?;
+        assert!(sub_batches.len() > 1);
+        for (_, sub_batch) in &sub_batches {
+            assert!(sub_batch.len() == 1 || sub_batch.token_ids().elem_count() <= 16);
+        }
+
+        // Without padding, the embeddings are the same as those of each sentence on its own
+        let expected = model.encode_batch_with_usage(sentences.clone(), true)?;
+        let output = split.encode_batch_with_usage(sentences.clone(), true)?;
+        assert_eq!(output.inputs, expected.inputs);
+        let single = sentences
+            .iter()
+            .map(|&sentence| model.encode_batch(vec![sentence], true))
+            .collect::<Result<Vec<_>>>()?;
+        let diff = (output.embeddings - Tensor::cat(&single, 0)?)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_usage() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+
+        let sentences = vec!["The cat sits outside", "Hi"];
+        let encodings = model.tokenize(sentences.clone())?;
+        let output = model.encode_batch_with_usage(sentences, true)?;
+
+        // Padding doesn't count
+        let tokens: Vec<u32> = encodings
+            .iter()
+            .map(|encoding| encoding.get_attention_mask().iter().sum())
+            .collect();
+        assert!(tokens[1] < tokens[0]);
+        assert_eq!(
+            output
+                .inputs
+                .iter()
+                .map(|input| input.tokens)
+                .collect::<Vec<_>>(),
+            tokens
+        );
+        assert_eq!(output.usage.prompt_tokens, tokens.iter().sum::<u32>());
+        assert_eq!(output.usage.total_tokens, output.usage.prompt_tokens);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_ids() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+
+        let sentences = vec!["The cat sits outside", "I love pasta"];
+        let ids = model
+            .tokenize(sentences.clone())?
+            .iter()
+            .map(|encoding| {
+                // Without the padding, which the batch is padded with again
+                let len = encoding.get_attention_mask().iter().sum::<u32>() as usize;
+                encoding.get_ids()[..len].to_vec()
+            })
+            .collect();
+        let expected = model.encode_batch_with_usage(sentences, true)?;
+        let output = model.encode_ids(ids, true)?;
+        assert_eq!(output.inputs, expected.inputs);
+        let diff = (output.embeddings - expected.embeddings)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-5);
+
+        // Sequences longer than the context are truncated
+        let output = model.encode_ids(vec![vec![5; 200]], true)?;
+        assert_eq!(output.inputs[0].tokens, 128);
+        assert!(output.inputs[0].truncated);
+
+        assert!(model.encode_ids(vec![vec![]], true).is_err());
+        assert!(model.encode_ids(vec![vec![u32::MAX]], true).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_dtype() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+        let sentences = vec!["The cat sits outside", "I love pasta"];
+        let expected = model.encode_batch(sentences.clone(), true)?;
+
+        for dtype in [DType::F16, DType::BF16] {
+            let half = SentenceTransformer::builder()
+                .with_model_folder(dir.path())
+                .with_output_dtype(dtype)
+                .build()?;
+            assert_eq!(half.output_dtype(), dtype);
+
+            let embeddings = half.encode_batch(sentences.clone(), true)?;
+            assert_eq!(embeddings.dtype(), dtype);
+            let diff = (embeddings.to_dtype(DType::F32)? - &expected)?
+                .abs()?
+                .max_all()?
+                .to_scalar::<f32>()?;
+            assert!(diff < 1e-2);
+        }
+
+        let result = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .with_output_dtype(DType::U32)
+            .build();
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_module() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+        assert!(!model.normalizes());
+
+        fs::write(
+            dir.path().join(MODULES_FILE),
+            json!([
+                {"idx": 0, "name": "0", "path": "", "type": "sentence_transformers.models.Transformer"},
+                {"idx": 1, "name": "1", "path": "1_Pooling", "type": "sentence_transformers.models.Pooling"},
+                {"idx": 2, "name": "2", "path": "2_Normalize", "type": "sentence_transformers.models.Normalize"}
+            ])
+            .to_string(),
+        )?;
+        let normalizing = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+        assert!(normalizing.normalizes());
+
+        // Like in `sentence-transformers`, the module normalizes without being asked to
+        let sentences = vec!["The cat sits outside", "I love pasta"];
+        let expected = model.encode_batch(sentences.clone(), true)?;
+        let embeddings = normalizing.encode_batch(sentences.clone(), false)?;
+        let diff = (expected - &embeddings)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-6);
+
+        let dst = tempdir()?;
+        normalizing.save(dst.path())?;
+        let modules: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dst.path().join(MODULES_FILE))?)?;
+        assert_eq!(modules[2]["type"], "sentence_transformers.models.Normalize");
+        let reloaded = SentenceTransformer::builder()
+            .with_model_folder(dst.path())
+            .build()?;
+        assert!(reloaded.normalizes());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sharded_weights() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+
+        // Split the weights over two shards
+        let weights_path = dir.path().join(SAFETENSORS_FILE);
+        let tensors = candle_core::safetensors::load(&weights_path, &Device::Cpu)?;
+        let (mut shards, mut weight_map) = ([HashMap::new(), HashMap::new()], json!({}));
+        for (i, (name, tensor)) in tensors.into_iter().enumerate() {
+            let shard = format!("model-0000{}-of-00002.safetensors", i % 2 + 1);
+            weight_map[&name] = shard.into();
+            shards[i % 2].insert(name, tensor);
+        }
+        for (i, shard) in shards.iter().enumerate() {
+            let file = format!("model-0000{}-of-00002.safetensors", i + 1);
+            candle_core::safetensors::save(shard, dir.path().join(file))?;
+        }
+        fs::write(
+            dir.path().join(SAFETENSORS_INDEX_FILE),
+            json!({"metadata": {}, "weight_map": weight_map}).to_string(),
+        )?;
+        fs::remove_file(&weights_path)?;
+
+        let sharded = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+        assert!(matches!(
+            &sharded.model_weights,
+            ModelWeightsPath::ShardedSafetensors(shards) if shards.len() == 2
+        ));
+
+        let sentences = vec!["The cat sits outside", "I love pasta"];
+        let expected = model.encode_batch(sentences.clone(), true)?;
+        let actual = sharded.encode_batch(sentences, true)?;
+        let diff = (expected - actual)?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert!(diff < 1e-6);
+
+        // Saving merges the shards
+        let dst = tempdir()?;
+        sharded.save(dst.path())?;
+        assert!(dst.path().join(SAFETENSORS_FILE).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_from_bytes() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        let read = |file: &str| -> Result<&'static [u8]> {
+            Ok(Box::leak(
+                fs::read(dir.path().join(file))?.into_boxed_slice(),
+            ))
+        };
+
+        let model_bytes = ModelBytes {
+            config: read(CONFIG_FILE)?,
+            tokenizer: read(TOKENIZER_FILE)?,
+            weights: read(SAFETENSORS_FILE)?,
+            pooling_config: Some(read(POOLING_CONFIG_FILE)?),
+        };
+        let embedded = SentenceTransformer::builder()
+            .with_model_bytes(model_bytes)
+            .build()?;
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+
+        let sentences = vec!["The cat sits outside", "I love pasta"];
+        assert_eq!(
+            embedded
+                .encode_batch(sentences.clone(), true)?
+                .to_vec2::<f32>()?,
+            model.encode_batch(sentences, true)?.to_vec2::<f32>()?
+        );
+        assert_eq!(
+            embedded.load_report().weights_bytes,
+            model_bytes.weights.len() as u64
+        );
+
+        // Embedded models can be saved like any other model
+        let dst = tempdir()?;
+        embedded.save(dst.path())?;
+        assert_eq!(
+            fs::read(dst.path().join(SAFETENSORS_FILE))?,
+            model_bytes.weights
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_include_model() -> Result<()> {
+        let model_bytes = crate::include_model!("../../tests/fixtures/all-MiniLM-L6-v2", pooling);
+        assert!(model_bytes.pooling_config.is_some());
+
+        let config = ModelRepo::from_bytes(model_bytes).get_config()?;
+        assert_eq!(
+            config.model_type,
+            ModelType::Embedding(PoolingStrategy::Mean)
+        );
+
+        let config = ModelRepo::from_bytes(crate::include_model!(
+            "../../tests/fixtures/all-MiniLM-L6-v2"
+        ))
+        .get_config();
+        assert!(matches!(config, Err(Error::NoPoolingConfiguration(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_report() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+        let report = model.load_report();
+
+        assert_eq!(
+            report.weights_bytes,
+            fs::metadata(dir.path().join(SAFETENSORS_FILE))?.len()
+        );
+        assert!(report.model_init > Duration::ZERO);
+        assert_eq!(report.pca, Duration::ZERO);
+        assert_eq!(
+            report.total(),
+            report.fetch + report.config + report.tokenizer + report.weights + report.model_init
+        );
+        assert!(report.to_string().contains("model init"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_batch_with_pooling() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .with_pooling_strategy(PoolingStrategy::Mean)
+            .build()?;
+        let cls_model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .with_pooling_strategy(PoolingStrategy::Cls)
+            .build()?;
+
+        let sentences = vec!["The cat sits outside", "I love pasta"];
+        let expected = cls_model.encode_batch(sentences.clone(), false)?;
+        let actual = model
+            .encode_batch_with_pooling(sentences.clone(), false, PoolingStrategy::Cls)?
+            .embeddings;
+
+        let diff = (expected - actual)?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert!(diff < 1e-6);
+
+        assert!(model
+            .encode_batch_with_pooling(sentences, false, PoolingStrategy::Splade)
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_tokenized() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+
+        let sentences = vec!["The cat sits outside", "I love pasta"];
+        let batch = model.tokenize_batch(sentences.clone())?;
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch.token_ids().dims()[1], batch.encodings()[0].len());
+
+        let expected = model.encode_batch_with_usage(sentences.clone(), true)?;
+        let actual = model.encode_tokenized(&batch, true)?;
+        assert_eq!(
+            actual.embeddings.to_vec2::<f32>()?,
+            expected.embeddings.to_vec2::<f32>()?
+        );
+        assert_eq!(actual.inputs, expected.inputs);
+
+        let expected = model.encode_batch_with_pooling(sentences, false, PoolingStrategy::Cls)?;
+        let actual = model.encode_tokenized_with_pooling(&batch, false, PoolingStrategy::Cls)?;
+        assert_eq!(
+            actual.embeddings.to_vec2::<f32>()?,
+            expected.embeddings.to_vec2::<f32>()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantized_weights() -> Result<()> {
+        use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+
+        let tensors =
+            candle_core::safetensors::load(dir.path().join(SAFETENSORS_FILE), &Device::Cpu)?;
+        let qtensors = tensors
+            .iter()
+            .map(|(name, tensor)| Ok((name.as_str(), QTensor::quantize(tensor, GgmlDType::F16)?)))
+            .collect::<Result<Vec<_>>>()?;
+        gguf_file::write(
+            &mut fs::File::create(dir.path().join("model.F16.gguf"))?,
+            &[],
+            &qtensors
+                .iter()
+                .map(|(name, qtensor)| (*name, qtensor))
+                .collect::<Vec<_>>(),
+        )?;
+
+        let sentences = vec!["The cat sits outside", "I love pasta"];
+        let build = |quantized| {
+            SentenceTransformer::builder()
+                .with_model_folder(dir.path())
+                .with_quantized_weights(quantized)
+                .build()
+        };
+        let model = build(false)?;
+        let quantized_model = build(true)?;
+        assert!(matches!(
+            quantized_model.model_weights,
+            ModelWeightsPath::Gguf(_)
+        ));
+
+        let expected = model.encode_batch(sentences.clone(), true)?;
+        let embeddings = quantized_model.encode_batch(sentences, true)?;
+        let diff = (embeddings - expected)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-2);
+
+        Ok(())
     }
 }
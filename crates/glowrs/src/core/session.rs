@@ -0,0 +1,231 @@
+//! Encoding sessions
+//!
+//! An [`EncodeSession`] keeps two host buffers between calls: the flattened token ids of a batch,
+//! and the embeddings copied back by [`EncodeSession::encode_batch_to_host`]. Only these are
+//! reused. The tokenizer output, the tensors and the activations of the model are allocated on
+//! every call as without a session, and on the CPU the token ids tensor is still created as a
+//! copy of the buffer, so the savings there are small.
+//!
+//! On CUDA, the token ids are staged in page-locked (pinned) host memory, which the GPU copies
+//! from directly, instead of the driver first copying them from pageable memory to its own
+//! staging buffer. The embeddings are copied back to pageable memory: the pinned memory of
+//! `cudarc` is write-combined, which is fast to write from the host but slow to read.
+
+use candle_core::{CpuStorage, Device, Storage, Tensor};
+use tokenizers::EncodeInput;
+#[cfg(feature = "cuda")]
+use {
+    candle_core::cuda_backend::cudarc::driver::PinnedHostSlice,
+    candle_core::cuda_backend::{CudaDevice, WrapErr},
+};
+
+use crate::core::embedder::EmbedOutput;
+use crate::{Error, PoolingStrategy, Result, SentenceTransformer};
+
+/// Encodes batches with a [`SentenceTransformer`], reusing the host buffers of the token ids and
+/// embeddings across calls.
+///
+/// A session needs exclusive access to its buffers, so it can't be shared across threads; use
+/// one session per thread, all borrowing the same model.
+pub struct EncodeSession<'m> {
+    model: &'m SentenceTransformer,
+    ids_buffer: IdsBuffer,
+    embeddings_buffer: Vec<f32>,
+}
+
+impl<'m> EncodeSession<'m> {
+    pub(crate) fn new(model: &'m SentenceTransformer) -> Self {
+        Self {
+            model,
+            ids_buffer: IdsBuffer::default(),
+            embeddings_buffer: Vec::new(),
+        }
+    }
+
+    pub fn model(&self) -> &'m SentenceTransformer {
+        self.model
+    }
+
+    /// Same as [`SentenceTransformer::encode_batch_with_usage`].
+    pub fn encode_batch_with_usage<'s, E>(
+        &mut self,
+        sentences: Vec<E>,
+        normalize: bool,
+    ) -> Result<EmbedOutput>
+    where
+        E: Into<EncodeInput<'s>> + Send,
+    {
+        let span = tracing::span!(tracing::Level::TRACE, "session-encode-batch");
+        let _enter = span.enter();
+
+        self.model.encode_with_model_type(
+            sentences,
+            normalize,
+            self.model.model_type(),
+            Some(&mut self.ids_buffer),
+        )
+    }
+
+    /// Same as [`SentenceTransformer::encode_batch_with_pooling`].
+    pub fn encode_batch_with_pooling<'s, E>(
+        &mut self,
+        sentences: Vec<E>,
+        normalize: bool,
+        pooling_strategy: PoolingStrategy,
+    ) -> Result<EmbedOutput>
+    where
+        E: Into<EncodeInput<'s>> + Send,
+    {
+        let span = tracing::span!(tracing::Level::TRACE, "session-encode-batch");
+        let _enter = span.enter();
+
+        let model_type = self.model.pooling_model_type(pooling_strategy)?;
+        self.model.encode_with_model_type(
+            sentences,
+            normalize,
+            &model_type,
+            Some(&mut self.ids_buffer),
+        )
+    }
+
+    /// Encode a batch of sentences and copy the embeddings to the session's host buffer.
+    ///
+    /// Returns the embeddings as a row-major slice of `sentences.len() * dim` values, which is
+    /// valid until the next call on the session.
+    pub fn encode_batch_to_host<'s, E>(
+        &mut self,
+        sentences: Vec<E>,
+        normalize: bool,
+    ) -> Result<&[f32]>
+    where
+        E: Into<EncodeInput<'s>> + Send,
+    {
+        let EmbedOutput { embeddings, .. } = self.encode_batch_with_usage(sentences, normalize)?;
+        copy_to_host(&embeddings, &mut self.embeddings_buffer)?;
+
+        Ok(&self.embeddings_buffer)
+    }
+}
+
+/// Host buffer of the token ids of a batch, which are copied from it to the device of the model.
+///
+/// The buffers grow with the largest batch, and are reused for smaller ones.
+#[derive(Default)]
+pub(crate) struct IdsBuffer {
+    ids: Vec<u32>,
+    /// Pinned staging buffer of the token ids, for copies to a CUDA device
+    #[cfg(feature = "cuda")]
+    pinned: Option<PinnedHostSlice<u32>>,
+}
+
+impl IdsBuffer {
+    /// Copy the concatenated token ids of the sequences of a batch to `device`, as a tensor of
+    /// shape `(batch_size, sequence_length)`.
+    pub(crate) fn copy_to_device<'a>(
+        &mut self,
+        sequences: impl IntoIterator<Item = &'a [u32]>,
+        shape: (usize, usize),
+        device: &Device,
+    ) -> Result<Tensor> {
+        self.ids.clear();
+        for ids in sequences {
+            self.ids.extend_from_slice(ids);
+        }
+
+        match device {
+            #[cfg(feature = "cuda")]
+            Device::Cuda(cuda) => self.copy_to_cuda(shape, cuda, device),
+            _ => Ok(Tensor::from_slice(self.ids.as_slice(), shape, device)?),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn copy_to_cuda(
+        &mut self,
+        shape: (usize, usize),
+        cuda: &CudaDevice,
+        device: &Device,
+    ) -> Result<Tensor> {
+        let stream = cuda.cuda_stream();
+        let len = self.ids.len();
+
+        let reusable = self
+            .pinned
+            .as_ref()
+            .is_some_and(|pinned| pinned.len() >= len && pinned.context() == stream.context());
+        if !reusable {
+            // Safety: the ids are written to the buffer before they are copied from it
+            let pinned = unsafe { stream.context().alloc_pinned::<u32>(len.max(1)) }.w()?;
+            self.pinned = Some(pinned);
+        }
+        let pinned = self.pinned.as_mut().expect("Pinned buffer is allocated");
+
+        let staged = &mut pinned.as_mut_slice().w()?[..len];
+        staged.copy_from_slice(&self.ids);
+        // The copy from the pinned memory is a direct DMA transfer. It completes before the
+        // tensor is returned, so the buffer can be written again on the next call
+        let token_ids = Tensor::from_slice(staged, shape, device)?;
+
+        Ok(token_ids)
+    }
+}
+
+/// Copy the values of an `f32` tensor into `buffer`, replacing its content.
+fn copy_to_host(tensor: &Tensor, buffer: &mut Vec<f32>) -> Result<()> {
+    let tensor = tensor.to_device(&Device::Cpu)?.contiguous()?;
+    let (storage, layout) = tensor.storage_and_layout();
+
+    let Storage::Cpu(CpuStorage::F32(values)) = &*storage else {
+        return Err(Error::InferenceError("Embeddings should be of type f32"));
+    };
+    let (start, end) = layout
+        .contiguous_offsets()
+        .ok_or(Error::InferenceError("Embeddings should be contiguous"))?;
+
+    buffer.clear();
+    buffer.extend_from_slice(&values[start..end]);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::test_utils::{create_tiny_bert_repo, TINY_HIDDEN_SIZE};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_session_reuses_buffers() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+        let mut session = model.session();
+
+        let sentences = vec!["The cat sits on the mat", "A dog", "Birds fly"];
+        let expected = model
+            .encode_batch(sentences.clone(), true)?
+            .to_vec2::<f32>()?;
+
+        let output = session.encode_batch_with_usage(sentences.clone(), true)?;
+        assert_eq!(output.embeddings.to_vec2::<f32>()?, expected);
+
+        let embeddings = session.encode_batch_to_host(sentences, true)?.to_vec();
+        assert_eq!(embeddings.len(), 3 * TINY_HIDDEN_SIZE);
+        assert_eq!(embeddings, expected.concat());
+
+        // A smaller batch reuses the allocations of the larger one
+        let (ids_ptr, embeddings_ptr) = (
+            session.ids_buffer.ids.as_ptr(),
+            session.embeddings_buffer.as_ptr(),
+        );
+        let expected = model.encode_batch(vec!["A dog"], true)?.to_vec2::<f32>()?;
+        let embeddings = session.encode_batch_to_host(vec!["A dog"], true)?.to_vec();
+        assert_eq!(embeddings, expected[0]);
+        assert_eq!(session.ids_buffer.ids.as_ptr(), ids_ptr);
+        assert_eq!(session.embeddings_buffer.as_ptr(), embeddings_ptr);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+//! Tokenizers of repositories without `tokenizer.json`
+//!
+//! Older repositories only have the files of a "slow" `transformers` tokenizer: `vocab.txt` for
+//! WordPiece tokenizers such as that of BERT, or `vocab.json` and `merges.txt` for byte-level
+//! BPE tokenizers such as that of RoBERTa. Their settings and special tokens are in
+//! `tokenizer_config.json` and `special_tokens_map.json`. The equivalent fast tokenizer is built
+//! from these files, like `transformers` converts slow tokenizers.
+
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use tokenizers::decoders::byte_level::ByteLevel as ByteLevelDecoder;
+use tokenizers::decoders::wordpiece::WordPiece as WordPieceDecoder;
+use tokenizers::models::bpe::BPE;
+use tokenizers::models::wordpiece::WordPiece;
+use tokenizers::normalizers::BertNormalizer;
+use tokenizers::pre_tokenizers::bert::BertPreTokenizer;
+use tokenizers::pre_tokenizers::byte_level::ByteLevel;
+use tokenizers::processors::bert::BertProcessing;
+use tokenizers::processors::roberta::RobertaProcessing;
+use tokenizers::{AddedToken, Tokenizer, TruncationParams};
+
+use crate::{Error, Result};
+
+/// Vocabulary of a WordPiece tokenizer, one token per line
+pub(crate) const WORDPIECE_VOCAB_FILE: &str = "vocab.txt";
+/// Vocabulary of a BPE tokenizer, as a map of tokens to ids
+pub(crate) const BPE_VOCAB_FILE: &str = "vocab.json";
+/// Merges of a BPE tokenizer
+pub(crate) const BPE_MERGES_FILE: &str = "merges.txt";
+pub(crate) const TOKENIZER_CONFIG_FILE: &str = "tokenizer_config.json";
+pub(crate) const SPECIAL_TOKENS_MAP_FILE: &str = "special_tokens_map.json";
+
+/// Files a slow tokenizer may be made of.
+#[cfg(feature = "hub")]
+pub(crate) const SLOW_TOKENIZER_FILES: [&str; 5] = [
+    WORDPIECE_VOCAB_FILE,
+    BPE_VOCAB_FILE,
+    BPE_MERGES_FILE,
+    TOKENIZER_CONFIG_FILE,
+    SPECIAL_TOKENS_MAP_FILE,
+];
+
+/// Whether `dir` has the vocabulary of a slow tokenizer.
+pub(crate) fn has_slow_tokenizer(dir: &Path) -> bool {
+    dir.join(WORDPIECE_VOCAB_FILE).exists()
+        || (dir.join(BPE_VOCAB_FILE).exists() && dir.join(BPE_MERGES_FILE).exists())
+}
+
+/// The settings of a slow tokenizer, from `tokenizer_config.json` and `special_tokens_map.json`.
+struct SlowTokenizerConfig {
+    config: Value,
+    special_tokens: Value,
+}
+
+impl SlowTokenizerConfig {
+    fn read(dir: &Path) -> Result<Self> {
+        let read = |file: &str| -> Result<Value> {
+            match dir.join(file) {
+                path if path.exists() => Ok(serde_json::from_str(&fs::read_to_string(path)?)?),
+                _ => Ok(Value::Null),
+            }
+        };
+
+        Ok(Self {
+            config: read(TOKENIZER_CONFIG_FILE)?,
+            special_tokens: read(SPECIAL_TOKENS_MAP_FILE)?,
+        })
+    }
+
+    fn bool(&self, key: &str) -> Option<bool> {
+        self.config[key].as_bool()
+    }
+
+    /// A special token such as `unk_token`, which is either a string or an added token object.
+    fn token(&self, key: &str, default: &str) -> String {
+        [&self.special_tokens[key], &self.config[key]]
+            .into_iter()
+            .find_map(|token| token.as_str().or_else(|| token["content"].as_str()))
+            .unwrap_or(default)
+            .to_string()
+    }
+
+    /// Truncation to the maximum length of the model, unless it is left unset, in which case
+    /// `transformers` writes a huge number.
+    fn truncation(&self) -> Option<TruncationParams> {
+        let max_length = self.config["model_max_length"]
+            .as_u64()
+            .filter(|&max_length| max_length < 1 << 20)?;
+
+        Some(TruncationParams {
+            max_length: max_length as usize,
+            ..Default::default()
+        })
+    }
+}
+
+/// Keys of the special tokens in the configuration of a slow tokenizer.
+const SPECIAL_TOKEN_KEYS: [&str; 5] = [
+    "unk_token",
+    "sep_token",
+    "cls_token",
+    "pad_token",
+    "mask_token",
+];
+
+/// Build the tokenizer of the slow tokenizer files in `dir`, see the [module](self)
+/// documentation.
+pub(crate) fn load_slow_tokenizer(dir: &Path) -> Result<Tokenizer> {
+    if !has_slow_tokenizer(dir) {
+        return Err(Error::ModelLoad(
+            "Repository has no tokenizer vocabulary (`vocab.txt`, or `vocab.json` and `merges.txt`).",
+        ));
+    }
+    let config = SlowTokenizerConfig::read(dir)?;
+    let path = |file: &str| dir.join(file).to_string_lossy().into_owned();
+    let wordpiece = dir.join(WORDPIECE_VOCAB_FILE).exists();
+    let add_prefix_space = config.bool("add_prefix_space").unwrap_or(false);
+
+    // The special tokens of BERT and RoBERTa, unless the configuration names others
+    let defaults = match wordpiece {
+        true => ["[UNK]", "[SEP]", "[CLS]", "[PAD]", "[MASK]"],
+        false => ["<unk>", "</s>", "<s>", "<pad>", "<mask>"],
+    };
+    let special_tokens: [String; 5] =
+        std::array::from_fn(|i| config.token(SPECIAL_TOKEN_KEYS[i], defaults[i]));
+    let [unk, sep, cls, ..] = &special_tokens;
+
+    let mut tokenizer = if wordpiece {
+        let model = WordPiece::from_file(&path(WORDPIECE_VOCAB_FILE))
+            .unk_token(unk.clone())
+            .build()?;
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_normalizer(Some(BertNormalizer::new(
+            true,
+            config.bool("tokenize_chinese_chars").unwrap_or(true),
+            config.bool("strip_accents"),
+            config.bool("do_lower_case").unwrap_or(true),
+        )));
+        tokenizer.with_pre_tokenizer(Some(BertPreTokenizer));
+        tokenizer.with_decoder(Some(WordPieceDecoder::default()));
+        tokenizer
+    } else {
+        let model = BPE::from_file(&path(BPE_VOCAB_FILE), &path(BPE_MERGES_FILE)).build()?;
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(
+            ByteLevel::default().add_prefix_space(add_prefix_space),
+        ));
+        tokenizer.with_decoder(Some(ByteLevelDecoder::default()));
+        tokenizer
+    };
+
+    let with_id = |token: &String| {
+        tokenizer
+            .token_to_id(token)
+            .map(|id| (token.clone(), id))
+            .ok_or(Error::InvalidModelConfig(
+                "Special token of the tokenizer is not in its vocabulary.",
+            ))
+    };
+    let (sep, cls) = (with_id(sep)?, with_id(cls)?);
+    if wordpiece {
+        tokenizer.with_post_processor(Some(BertProcessing::new(sep, cls)));
+    } else {
+        tokenizer.with_post_processor(Some(
+            RobertaProcessing::new(sep, cls).add_prefix_space(add_prefix_space),
+        ));
+    }
+
+    let special_tokens: Vec<AddedToken> = special_tokens
+        .into_iter()
+        .map(|token| AddedToken::from(token, true))
+        .collect();
+    tokenizer.add_special_tokens(&special_tokens);
+    tokenizer.with_truncation(config.truncation())?;
+
+    Ok(tokenizer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::repo::TOKENIZER_FILE;
+    use crate::core::test_utils::create_tiny_bert_repo;
+    use crate::SentenceTransformer;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_wordpiece_tokenizer() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+        let fast = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+
+        // Replace `tokenizer.json` with the files of the slow tokenizer
+        let tokenizer = Tokenizer::from_file(dir.path().join(TOKENIZER_FILE))?;
+        let mut vocab: Vec<(String, u32)> = tokenizer.get_vocab(false).into_iter().collect();
+        vocab.sort_by_key(|&(_, id)| id);
+        let vocab: String = vocab.into_iter().map(|(token, _)| token + "\n").collect();
+        fs::write(dir.path().join(WORDPIECE_VOCAB_FILE), vocab)?;
+        fs::write(
+            dir.path().join(TOKENIZER_CONFIG_FILE),
+            r#"{"do_lower_case": true, "model_max_length": 128}"#,
+        )?;
+        fs::remove_file(dir.path().join(TOKENIZER_FILE))?;
+        let slow = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+
+        let sentences = vec!["The cat sits outside", "Héllo, wörld! Unbelievable"];
+        let ids = |model: &SentenceTransformer| -> Result<Vec<Vec<u32>>> {
+            Ok(model
+                .tokenize(sentences.clone())?
+                .iter()
+                .map(|encoding| encoding.get_ids().to_vec())
+                .collect())
+        };
+        assert_eq!(ids(&slow)?, ids(&fast)?);
+        assert_eq!(slow.max_input_length(), Some(128));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bpe_tokenizer() -> Result<()> {
+        let dir = tempdir()?;
+        fs::write(
+            dir.path().join(BPE_VOCAB_FILE),
+            r#"{"<s>": 0, "<pad>": 1, "</s>": 2, "<unk>": 3, "<mask>": 4, "a": 5, "b": 6, "ab": 7, "Ġ": 8, "Ġab": 9}"#,
+        )?;
+        fs::write(
+            dir.path().join(BPE_MERGES_FILE),
+            "#version: 0.2\na b\nĠ ab\n",
+        )?;
+        // `transformers` writes a huge maximum length when the model has none
+        fs::write(
+            dir.path().join(TOKENIZER_CONFIG_FILE),
+            r#"{"model_max_length": 1000000000000000019884624838656}"#,
+        )?;
+        fs::write(
+            dir.path().join(SPECIAL_TOKENS_MAP_FILE),
+            r#"{"cls_token": {"content": "<s>", "lstrip": false}}"#,
+        )?;
+        assert!(has_slow_tokenizer(dir.path()));
+
+        let tokenizer = load_slow_tokenizer(dir.path())?;
+        let encoding = tokenizer.encode("ab ab", true)?;
+        assert_eq!(encoding.get_ids(), [0, 7, 9, 2]);
+        assert!(tokenizer.get_truncation().is_none());
+        assert_eq!(tokenizer.decode(encoding.get_ids(), true)?, "ab ab");
+
+        fs::remove_file(dir.path().join(BPE_MERGES_FILE))?;
+        assert!(load_slow_tokenizer(dir.path()).is_err());
+
+        Ok(())
+    }
+}
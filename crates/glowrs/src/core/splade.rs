@@ -0,0 +1,235 @@
+//! SPLADE sparse embeddings
+//!
+//! SPLADE models are `*ForMaskedLM` checkpoints whose masked language modeling (MLM) head
+//! predicts a logit for each word in the vocabulary at each token. The embedding of an input
+//! is the maximum of `log(1 + ReLU(logit))` over its tokens, which is zero for most of the
+//! vocabulary, so the embeddings are best stored as sparse `(index, value)` pairs, see
+//! [`EmbedOutput::sparse`](crate::core::embedder::EmbedOutput::sparse).
+
+use candle_core::{DType, Module, Tensor, D};
+use candle_nn::{layer_norm, linear, Activation, LayerNorm, Linear, VarBuilder};
+use serde::Serialize;
+
+use crate::core::embedder::EmbedderModel;
+use crate::{Device, Error, Result};
+
+/// Masked language modeling head, which maps token embeddings to logits over the vocabulary.
+struct MlmHead {
+    dense: Linear,
+    activation: Activation,
+    layer_norm: LayerNorm,
+    decoder: Linear,
+}
+
+impl MlmHead {
+    /// Load the MLM head of a `*ForMaskedLM` checkpoint. The decoder weights are usually tied
+    /// to the word embeddings, in which case checkpoints only store the latter.
+    fn load(vb: VarBuilder, model_config: &serde_json::Value) -> Result<Self> {
+        let config_usize = |key: &str| model_config[key].as_u64().map(|value| value as usize);
+        let config_activation = |key: &str| {
+            serde_json::from_value(model_config[key].clone()).unwrap_or(Activation::Gelu)
+        };
+        let word_embeddings = |prefix: &str| {
+            let name = format!("{prefix}.embeddings.word_embeddings.weight");
+            match vb.contains_tensor(&name) {
+                true => name,
+                false => "embeddings.word_embeddings.weight".to_string(),
+            }
+        };
+        let vocab_size = config_usize("vocab_size").ok_or(Error::InvalidModelConfig(
+            "Model configuration has no vocabulary size.",
+        ))?;
+
+        let model_type = model_config["model_type"].as_str().unwrap_or_default();
+        let (hidden_size, eps, activation, head, decoder_weight, decoder_bias) = match model_type {
+            "bert" => {
+                let head = vb.pp("cls.predictions");
+                let decoder_weight = match head.contains_tensor("decoder.weight") {
+                    true => "cls.predictions.decoder.weight".to_string(),
+                    false => word_embeddings("bert"),
+                };
+                (
+                    config_usize("hidden_size").unwrap_or_default(),
+                    model_config["layer_norm_eps"].as_f64().unwrap_or(1e-12),
+                    config_activation("hidden_act"),
+                    (head.pp("transform.dense"), head.pp("transform.LayerNorm")),
+                    decoder_weight,
+                    "cls.predictions.bias",
+                )
+            }
+            "distilbert" => {
+                let decoder_weight = match vb.contains_tensor("vocab_projector.weight") {
+                    true => "vocab_projector.weight".to_string(),
+                    false => word_embeddings("distilbert"),
+                };
+                (
+                    config_usize("dim").unwrap_or_default(),
+                    1e-12,
+                    config_activation("activation"),
+                    (vb.pp("vocab_transform"), vb.pp("vocab_layer_norm")),
+                    decoder_weight,
+                    "vocab_projector.bias",
+                )
+            }
+            _ => {
+                return Err(Error::ModelLoad(
+                    "SPLADE is not supported for this model type.",
+                ))
+            }
+        };
+
+        let (dense, norm) = head;
+        let decoder = Linear::new(
+            vb.get((vocab_size, hidden_size), &decoder_weight)?,
+            Some(vb.get(vocab_size, decoder_bias)?),
+        );
+
+        Ok(Self {
+            dense: linear(hidden_size, hidden_size, dense)?,
+            activation,
+            layer_norm: layer_norm(hidden_size, eps, norm)?,
+            decoder,
+        })
+    }
+}
+
+impl Module for MlmHead {
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        let xs = self.activation.forward(&self.dense.forward(xs)?)?;
+        self.decoder.forward(&self.layer_norm.forward(&xs)?)
+    }
+}
+
+/// A transformer with an MLM head, whose token embeddings are the logits over the vocabulary.
+pub(crate) struct SpladeModel {
+    model: Box<dyn EmbedderModel>,
+    head: MlmHead,
+}
+
+impl SpladeModel {
+    pub(crate) fn load(
+        model: Box<dyn EmbedderModel>,
+        vb: VarBuilder,
+        model_config: &serde_json::Value,
+    ) -> Result<Self> {
+        Ok(Self {
+            model,
+            head: MlmHead::load(vb, model_config)?,
+        })
+    }
+}
+
+impl EmbedderModel for SpladeModel {
+    fn encode(&self, token_ids: &Tensor) -> Result<Tensor> {
+        Ok(self.head.forward(&self.model.encode(token_ids)?)?)
+    }
+
+    fn encode_with_type_ids(&self, token_ids: &Tensor, token_type_ids: &Tensor) -> Result<Tensor> {
+        let embeddings = self.model.encode_with_type_ids(token_ids, token_type_ids)?;
+        Ok(self.head.forward(&embeddings)?)
+    }
+
+    fn get_device(&self) -> &Device {
+        self.model.get_device()
+    }
+}
+
+/// Pool the vocabulary logits of each token, of shape `(batch, seq_len, vocab_size)`, into
+/// SPLADE embeddings, leaving out the tokens where `attention_mask` is 0.
+pub(crate) fn splade_pool(logits: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+    let attention_mask = attention_mask
+        .unsqueeze(D::Minus1)?
+        .to_dtype(logits.dtype())?;
+    let weights = (logits.relu()? + 1.)?
+        .log()?
+        .broadcast_mul(&attention_mask)?;
+
+    Ok(weights.max(1)?)
+}
+
+/// A non-zero value of a sparse embedding.
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct SparseValue {
+    /// Index in the embedding, i.e. the token id for SPLADE embeddings
+    pub index: u32,
+    pub value: f32,
+}
+
+/// The non-zero values of each embedding of shape `(n_inputs, dim)`, by increasing index.
+pub(crate) fn to_sparse(embeddings: &Tensor) -> Result<Vec<Vec<SparseValue>>> {
+    let embeddings: Vec<Vec<f32>> = embeddings.to_dtype(DType::F32)?.to_vec2()?;
+
+    Ok(embeddings
+        .into_iter()
+        .map(|embedding| {
+            embedding
+                .into_iter()
+                .enumerate()
+                .filter(|(_, value)| *value != 0.)
+                .map(|(index, value)| SparseValue {
+                    index: index as u32,
+                    value,
+                })
+                .collect()
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::test_utils::create_tiny_splade_repo;
+    use crate::{PoolingStrategy, SentenceTransformer};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_splade() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_splade_repo(dir.path())?;
+
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .with_pooling_strategy(PoolingStrategy::Splade)
+            .build()?;
+
+        let output =
+            model.encode_batch_with_usage(vec!["The cat", "A dog sits on the mat"], false)?;
+        // One weight per word in the vocabulary
+        assert_eq!(output.embeddings.dims(), &[2, 30522]);
+        assert!(output.embeddings.min_all()?.to_scalar::<f32>()? >= 0.);
+
+        let sparse = output.sparse()?;
+        let dense: Vec<Vec<f32>> = output.embeddings.to_vec2()?;
+        for (sparse, dense) in sparse.iter().zip(&dense) {
+            assert!(!sparse.is_empty());
+            assert!(sparse.windows(2).all(|w| w[0].index < w[1].index));
+            for value in sparse {
+                assert_eq!(value.value, dense[value.index as usize]);
+            }
+            let non_zero = dense.iter().filter(|&&value| value != 0.).count();
+            assert_eq!(sparse.len(), non_zero);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_splade_pool() -> Result<()> {
+        let logits = Tensor::new(
+            &[[[1f32, -1.], [3., 0.5]], [[2., -2.], [9., 9.]]],
+            &Device::Cpu,
+        )?;
+        let attention_mask = Tensor::new(&[[1u32, 1], [1, 0]], &Device::Cpu)?;
+
+        let pooled = splade_pool(&logits, &attention_mask)?.to_vec2::<f32>()?;
+        let expected = [[4f32.ln(), 1.5f32.ln()], [3f32.ln(), 0.]];
+        for (row, expected) in pooled.iter().zip(expected) {
+            for (value, expected) in row.iter().zip(expected) {
+                assert!((value - expected).abs() < 1e-6);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,493 @@
+//! Helpers to create small model repositories with random weights for tests.
+
+use candle_core::{DType, Device, Shape, Tensor};
+use candle_nn::var_builder::SimpleBackend;
+use candle_nn::{layer_norm, linear, linear_no_bias, Init, VarBuilder, VarMap};
+use candle_transformers::models::bert::{BertModel, Config as BertConfig};
+use candle_transformers::models::debertav2::{Config as DebertaV2Config, DebertaV2Model};
+use candle_transformers::models::modernbert::{Config as ModernBertConfig, ModernBert};
+use candle_transformers::models::qwen2::{Config as Qwen2Config, Model as Qwen2Model};
+use candle_transformers::models::xlm_roberta::{Config as XlmRobertaConfig, XLMRobertaModel};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::models::jina_v3::{Config as JinaV3Config, JinaV3Model};
+use crate::models::mpnet::{Config as MPNetConfig, MPNetModel};
+use crate::models::nomic_bert::{Config as NomicBertConfig, NomicBertModel};
+use crate::models::t5::{Config as T5Config, T5EncoderModel};
+use crate::pooling::{PoolConfig, PoolingStrategy};
+use crate::Result;
+
+pub(crate) const BERT_FIXTURE_PATH: &str = "tests/fixtures/all-MiniLM-L6-v2";
+pub(crate) const MPNET_FIXTURE_PATH: &str = "tests/fixtures/all-mpnet-base-v2";
+pub(crate) const TINY_HIDDEN_SIZE: usize = 8;
+
+/// Create a tiny BERT model repository in `dir`, with randomly initialized weights and the
+/// tokenizer and pooling configuration of the `all-MiniLM-L6-v2` fixture.
+pub(crate) fn create_tiny_bert_repo(dir: &Path) -> Result<()> {
+    let config = write_tiny_config(dir, BERT_FIXTURE_PATH, |_| {})?;
+
+    let bert_config: BertConfig = serde_json::from_value(config)?;
+    let var_map = VarMap::new();
+    let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+    let _ = BertModel::load(vb, &bert_config)?;
+    var_map.save(dir.join("model.safetensors"))?;
+
+    Ok(())
+}
+
+/// Create a tiny BERT classifier repository in `dir`, like [`create_tiny_bert_repo`], with the
+/// pooler and classifier of a `BertForSequenceClassification` checkpoint with the given labels.
+pub(crate) fn create_tiny_classifier_repo(
+    dir: &Path,
+    labels: &[&str],
+    problem_type: Option<&str>,
+) -> Result<()> {
+    let config = write_tiny_config(dir, BERT_FIXTURE_PATH, |config| {
+        config["architectures"] = serde_json::json!(["BertForSequenceClassification"]);
+        set_labels(config, labels);
+        if let Some(problem_type) = problem_type {
+            config["problem_type"] = problem_type.into();
+        }
+    })?;
+    // Classifiers have no pooling configuration
+    fs::remove_dir_all(dir.join("1_Pooling"))?;
+
+    let bert_config: BertConfig = serde_json::from_value(config)?;
+    let var_map = VarMap::new();
+    let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+    let _ = BertModel::load(vb.pp("bert"), &bert_config)?;
+    let _ = linear(
+        TINY_HIDDEN_SIZE,
+        TINY_HIDDEN_SIZE,
+        vb.pp("bert.pooler.dense"),
+    )?;
+    let _ = linear(TINY_HIDDEN_SIZE, labels.len(), vb.pp("classifier"))?;
+    var_map.save(dir.join("model.safetensors"))?;
+
+    Ok(())
+}
+
+/// Create a tiny BERT token classifier repository in `dir`, like [`create_tiny_bert_repo`], with
+/// the classifier of a `BertForTokenClassification` checkpoint with the given labels.
+pub(crate) fn create_tiny_token_classifier_repo(dir: &Path, labels: &[&str]) -> Result<()> {
+    let config = write_tiny_config(dir, BERT_FIXTURE_PATH, |config| {
+        config["architectures"] = serde_json::json!(["BertForTokenClassification"]);
+        set_labels(config, labels);
+    })?;
+    fs::remove_dir_all(dir.join("1_Pooling"))?;
+
+    let bert_config: BertConfig = serde_json::from_value(config)?;
+    let var_map = VarMap::new();
+    let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+    let _ = BertModel::load(vb.pp("bert"), &bert_config)?;
+    let _ = linear(TINY_HIDDEN_SIZE, labels.len(), vb.pp("classifier"))?;
+    var_map.save(dir.join("model.safetensors"))?;
+
+    Ok(())
+}
+
+/// Create a tiny model2vec repository in `dir`, with random static embeddings for the
+/// vocabulary of the BERT tokenizer and no pooling configuration.
+pub(crate) fn create_tiny_model2vec_repo(dir: &Path) -> Result<()> {
+    let fixture = Path::new(BERT_FIXTURE_PATH);
+    let config = serde_json::json!({
+        "model_type": "model2vec",
+        "architectures": ["StaticModel"],
+        "hidden_dim": TINY_HIDDEN_SIZE,
+        "normalize": true
+    });
+    fs::write(dir.join("config.json"), serde_json::to_string(&config)?)?;
+    fs::copy(fixture.join("tokenizer.json"), dir.join("tokenizer.json"))?;
+
+    let embeddings = candle_core::Tensor::randn(0f32, 1., (30522, TINY_HIDDEN_SIZE), &Device::Cpu)?;
+    candle_core::safetensors::save(
+        &HashMap::from([("embeddings".to_string(), embeddings)]),
+        dir.join("model.safetensors"),
+    )?;
+
+    Ok(())
+}
+
+/// Create a tiny BERT SPLADE repository in `dir`, like [`create_tiny_bert_repo`], with the MLM
+/// head of a `BertForMaskedLM` checkpoint. Its decoder is tied to the word embeddings, so only
+/// the decoder bias is saved.
+pub(crate) fn create_tiny_splade_repo(dir: &Path) -> Result<()> {
+    let config = write_tiny_config(dir, BERT_FIXTURE_PATH, |config| {
+        config["architectures"] = serde_json::json!(["BertForMaskedLM"]);
+    })?;
+    fs::remove_dir_all(dir.join("1_Pooling"))?;
+
+    let bert_config: BertConfig = serde_json::from_value(config)?;
+    let var_map = VarMap::new();
+    let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+    let _ = BertModel::load(vb.pp("bert"), &bert_config)?;
+    let head = vb.pp("cls.predictions");
+    let _ = linear(
+        TINY_HIDDEN_SIZE,
+        TINY_HIDDEN_SIZE,
+        head.pp("transform.dense"),
+    )?;
+    let _ = layer_norm(TINY_HIDDEN_SIZE, 1e-12, head.pp("transform.LayerNorm"))?;
+    let _ = head.get_with_hints(
+        bert_config.vocab_size,
+        "bias",
+        candle_nn::Init::Randn {
+            mean: 0.,
+            stdev: 1.,
+        },
+    )?;
+    var_map.save(dir.join("model.safetensors"))?;
+
+    Ok(())
+}
+
+/// Create a tiny ColBERT repository in `dir`, like [`create_tiny_bert_repo`], with a projection
+/// of the token embeddings to `dim` dimensions and without a pooling configuration.
+pub(crate) fn create_tiny_colbert_repo(dir: &Path, dim: usize) -> Result<()> {
+    let config = write_tiny_config(dir, BERT_FIXTURE_PATH, |config| {
+        config["architectures"] = serde_json::json!(["HF_ColBERT"]);
+        config["dim"] = dim.into();
+    })?;
+    fs::remove_dir_all(dir.join("1_Pooling"))?;
+
+    let bert_config: BertConfig = serde_json::from_value(config)?;
+    let var_map = VarMap::new();
+    let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+    let _ = BertModel::load(vb.pp("bert"), &bert_config)?;
+    let _ = linear_no_bias(TINY_HIDDEN_SIZE, dim, vb.pp("linear"))?;
+    var_map.save(dir.join("model.safetensors"))?;
+
+    Ok(())
+}
+
+/// Set the `id2label` and `label2id` of a classifier configuration.
+fn set_labels(config: &mut serde_json::Value, labels: &[&str]) {
+    config["id2label"] = labels
+        .iter()
+        .enumerate()
+        .map(|(i, label)| (i.to_string(), serde_json::json!(label)))
+        .collect();
+    config["label2id"] = labels
+        .iter()
+        .enumerate()
+        .map(|(i, label)| (label.to_string(), serde_json::json!(i)))
+        .collect();
+}
+
+/// Create a tiny XLM-RoBERTa model repository in `dir`, like [`create_tiny_bert_repo`]. The
+/// weights are saved with the `roberta.` prefix of checkpoints that include a task head.
+pub(crate) fn create_tiny_xlm_roberta_repo(dir: &Path) -> Result<()> {
+    let config = write_tiny_config(dir, BERT_FIXTURE_PATH, |config| {
+        config["model_type"] = "xlm-roberta".into();
+        config["architectures"] = serde_json::json!(["XLMRobertaModel"]);
+        config["type_vocab_size"] = 1.into();
+    })?;
+
+    let roberta_config: XlmRobertaConfig = serde_json::from_value(config)?;
+    let var_map = VarMap::new();
+    let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+    let _ = XLMRobertaModel::new(&roberta_config, vb.pp("roberta"))?;
+    var_map.save(dir.join("model.safetensors"))?;
+
+    Ok(())
+}
+
+/// Create a tiny Jina v3 model repository in `dir`, like [`create_tiny_xlm_roberta_repo`], with
+/// random LoRA adapters for the tasks of `jinaai/jina-embeddings-v3`, saved as parametrizations
+/// of the weights.
+pub(crate) fn create_tiny_jina_v3_repo(dir: &Path) -> Result<()> {
+    let config = write_tiny_config(dir, BERT_FIXTURE_PATH, |config| {
+        config["model_type"] = "xlm-roberta".into();
+        config["architectures"] = serde_json::json!(["XLMRobertaLoRA"]);
+        config["type_vocab_size"] = 1.into();
+        config["position_embedding_type"] = "rotary".into();
+        config["rotary_emb_base"] = 20000.0.into();
+        config["lora_adaptations"] = serde_json::json!([
+            "retrieval.query",
+            "retrieval.passage",
+            "separation",
+            "classification",
+            "text-matching"
+        ]);
+        config["lora_rank"] = 2.into();
+        config["lora_alpha"] = 1.into();
+        config["task_instructions"] = serde_json::json!({
+            "retrieval.query": "Represent the query for retrieving evidence documents: ",
+            "retrieval.passage": "Represent the document for retrieval: ",
+            "separation": "",
+            "classification": "",
+            "text-matching": ""
+        });
+    })?;
+
+    let jina_config: JinaV3Config = serde_json::from_value(config)?;
+    let var_map = VarMap::new();
+    let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+    let _ = JinaV3Model::load(vb, &jina_config)?;
+
+    let tasks = jina_config.lora_adaptations.len();
+    let randn = |shape: &[usize]| candle_core::Tensor::randn(0f32, 0.5, shape, &Device::Cpu);
+    let mut tensors = HashMap::new();
+    for (name, var) in var_map.data().lock().unwrap().iter() {
+        // Layer norms keep their initialization, other weights are zero without random ones
+        let tensor = match name.contains("norm") || name.contains("emb_ln") {
+            true => var.as_tensor().clone(),
+            false => randn(var.dims())?,
+        };
+        let Some(layer) = name
+            .strip_suffix(".weight")
+            .filter(|layer| !layer.contains("norm") && !layer.contains("emb_ln"))
+        else {
+            tensors.insert(format!("roberta.{name}"), tensor);
+            continue;
+        };
+
+        let (rows, columns) = tensor.dims2()?;
+        let (a_shape, b_shape) = match layer.ends_with("embeddings") {
+            true => ([tasks, rows, 2], [tasks, 2, columns]),
+            false => ([tasks, 2, columns], [tasks, rows, 2]),
+        };
+        let parametrization = format!("roberta.{layer}.parametrizations.weight");
+        tensors.insert(format!("{parametrization}.original"), tensor);
+        tensors.insert(format!("{parametrization}.0.lora_A"), randn(&a_shape)?);
+        tensors.insert(format!("{parametrization}.0.lora_B"), randn(&b_shape)?);
+    }
+    candle_core::safetensors::save(&tensors, dir.join("model.safetensors"))?;
+
+    Ok(())
+}
+
+/// Create a tiny DeBERTa-v3 model repository in `dir`, like [`create_tiny_bert_repo`], with
+/// relative position buckets and without segment embeddings.
+pub(crate) fn create_tiny_deberta_v3_repo(dir: &Path) -> Result<()> {
+    let config = write_tiny_config(dir, BERT_FIXTURE_PATH, |config| {
+        config["model_type"] = "deberta-v2".into();
+        config["architectures"] = serde_json::json!(["DebertaV2Model"]);
+        config["type_vocab_size"] = 0.into();
+        config["relative_attention"] = true.into();
+        config["max_relative_positions"] = (-1).into();
+        config["position_buckets"] = 16.into();
+        config["position_biased_input"] = false.into();
+        config["pos_att_type"] = serde_json::json!(["p2c", "c2p"]);
+        config["share_att_key"] = true.into();
+        config["norm_rel_ebd"] = "layer_norm".into();
+        config["layer_norm_eps"] = 1e-7.into();
+    })?;
+
+    let deberta_config: DebertaV2Config = serde_json::from_value(config)?;
+    let var_map = VarMap::new();
+    let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+    let _ = DebertaV2Model::load(vb, &deberta_config)?;
+    var_map.save(dir.join("model.safetensors"))?;
+
+    Ok(())
+}
+
+/// Create a tiny Nomic BERT model repository in `dir`, like [`create_tiny_bert_repo`].
+pub(crate) fn create_tiny_nomic_bert_repo(dir: &Path) -> Result<()> {
+    let config = write_tiny_config(dir, BERT_FIXTURE_PATH, |config| {
+        config["model_type"] = "nomic_bert".into();
+        config["architectures"] = serde_json::json!(["NomicBertModel"]);
+        config["n_embd"] = TINY_HIDDEN_SIZE.into();
+        config["n_inner"] = (2 * TINY_HIDDEN_SIZE).into();
+        config["n_head"] = 2.into();
+        config["n_layer"] = 1.into();
+        // Nomic BERT names the maximum sequence length `n_positions`
+        if let Some(config) = config.as_object_mut() {
+            let max_positions = config.remove("max_position_embeddings");
+            config.insert("n_positions".to_string(), max_positions.into());
+        }
+        config["layer_norm_epsilon"] = 1e-12.into();
+        config["activation_function"] = "swiglu".into();
+        config["rotary_emb_base"] = 1000.into();
+        config["rotary_emb_fraction"] = 1.0.into();
+    })?;
+
+    let nomic_config: NomicBertConfig = serde_json::from_value(config)?;
+    let var_map = VarMap::new();
+    let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+    let _ = NomicBertModel::load(vb, &nomic_config)?;
+    var_map.save(dir.join("model.safetensors"))?;
+
+    Ok(())
+}
+
+/// Create a tiny ModernBERT model repository in `dir`, like [`create_tiny_bert_repo`], with a
+/// local attention layer whose window is shorter than the test sentences. The weights are saved
+/// without the `model.` prefix, like those of the base model.
+pub(crate) fn create_tiny_modernbert_repo(dir: &Path) -> Result<()> {
+    let config = write_tiny_config(dir, BERT_FIXTURE_PATH, |config| {
+        config["model_type"] = "modernbert".into();
+        config["architectures"] = serde_json::json!(["ModernBertModel"]);
+        config["num_hidden_layers"] = 2.into();
+        config["global_attn_every_n_layers"] = 2.into();
+        config["global_rope_theta"] = 160000.0.into();
+        config["local_attention"] = 4.into();
+        config["local_rope_theta"] = 10000.0.into();
+        config["id2label"] = serde_json::json!({"0": "LABEL_0"});
+        config["label2id"] = serde_json::json!({"LABEL_0": 0});
+    })?;
+
+    let modernbert_config: ModernBertConfig = serde_json::from_value(config)?;
+    let var_map = VarMap::new();
+    let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+    let _ = ModernBert::load(vb, &modernbert_config)?;
+    let tensors = var_map
+        .data()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, var)| {
+            (
+                name.trim_start_matches("model.").to_string(),
+                var.as_tensor().clone(),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    candle_core::safetensors::save(&tensors, dir.join("model.safetensors"))?;
+
+    Ok(())
+}
+
+/// Create a tiny T5 encoder model repository in `dir`, like [`create_tiny_bert_repo`].
+pub(crate) fn create_tiny_t5_repo(dir: &Path) -> Result<()> {
+    let config = write_tiny_config(dir, BERT_FIXTURE_PATH, |config| {
+        config["model_type"] = "t5".into();
+        config["architectures"] = serde_json::json!(["T5EncoderModel"]);
+        config["d_model"] = TINY_HIDDEN_SIZE.into();
+        config["d_kv"] = 4.into();
+        config["d_ff"] = (2 * TINY_HIDDEN_SIZE).into();
+        config["num_layers"] = 1.into();
+        config["num_heads"] = 2.into();
+        config["relative_attention_num_buckets"] = 32.into();
+        config["dropout_rate"] = 0.1.into();
+        config["layer_norm_epsilon"] = 1e-6.into();
+        config["initializer_factor"] = 1.0.into();
+        config["feed_forward_proj"] = "relu".into();
+        config["is_encoder_decoder"] = true.into();
+        config["eos_token_id"] = 1.into();
+        // T5 names the maximum sequence length `n_positions`
+        if let Some(config) = config.as_object_mut() {
+            let max_positions = config.remove("max_position_embeddings");
+            config.insert("n_positions".to_string(), max_positions.into());
+        }
+    })?;
+
+    let t5_config: T5Config = serde_json::from_value(config)?;
+    let var_map = VarMap::new();
+    let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+    let _ = T5EncoderModel::load(vb, &t5_config)?;
+    var_map.save(dir.join("model.safetensors"))?;
+
+    Ok(())
+}
+
+/// Create a tiny Qwen2 model repository in `dir`, like [`create_tiny_bert_repo`], configured
+/// for last token pooling.
+pub(crate) fn create_tiny_qwen2_repo(dir: &Path) -> Result<()> {
+    let config = write_tiny_config(dir, BERT_FIXTURE_PATH, |config| {
+        config["model_type"] = "qwen2".into();
+        config["architectures"] = serde_json::json!(["Qwen2ForCausalLM"]);
+        config["num_key_value_heads"] = 1.into();
+        config["sliding_window"] = 512.into();
+        config["max_window_layers"] = 1.into();
+        config["tie_word_embeddings"] = false.into();
+        config["rope_theta"] = 10000.0.into();
+        config["rms_norm_eps"] = 1e-6.into();
+        config["use_sliding_window"] = false.into();
+        config["hidden_act"] = "silu".into();
+    })?;
+    let pool_config = PoolConfig::new(PoolingStrategy::LastToken, TINY_HIDDEN_SIZE)?;
+    fs::write(
+        dir.join("1_Pooling/config.json"),
+        serde_json::to_string(&pool_config)?,
+    )?;
+
+    let qwen2_config: Qwen2Config = serde_json::from_value(config)?;
+    let var_map = VarMap::new();
+    let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+    let _ = Qwen2Model::new(&qwen2_config, vb)?;
+    var_map.save(dir.join("model.safetensors"))?;
+
+    Ok(())
+}
+
+/// Create a tiny MPNet model repository in `dir`, with randomly initialized weights and the
+/// tokenizer and pooling configuration of the `all-mpnet-base-v2` fixture.
+pub(crate) fn create_tiny_mpnet_repo(dir: &Path) -> Result<()> {
+    let config = write_tiny_config(dir, MPNET_FIXTURE_PATH, |_| {})?;
+
+    let mpnet_config: MPNetConfig = serde_json::from_value(config)?;
+    let var_map = VarMap::new();
+    let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+    let _ = MPNetModel::load(vb, &mpnet_config)?;
+    var_map.save(dir.join("model.safetensors"))?;
+
+    Ok(())
+}
+
+/// Weights that only depend on their name, so that reference outputs can be computed for the
+/// same checkpoint outside of glowrs. Element `i` of a tensor is `sin(0.7 * (s + i))`, with `s`
+/// the sum of the bytes of its name.
+struct NamedWeights;
+
+impl SimpleBackend for NamedWeights {
+    fn get(
+        &self,
+        shape: Shape,
+        name: &str,
+        _: Init,
+        dtype: DType,
+        device: &Device,
+    ) -> candle_core::Result<Tensor> {
+        let seed: usize = name.bytes().map(usize::from).sum();
+        let values: Vec<f32> = (0..shape.elem_count())
+            .map(|i| (0.7 * (seed + i) as f64).sin() as f32)
+            .collect();
+        Tensor::from_vec(values, shape, device)?.to_dtype(dtype)
+    }
+
+    fn get_unchecked(&self, name: &str, _: DType, _: &Device) -> candle_core::Result<Tensor> {
+        // The values are only defined for a shape, which all models pass when loading
+        candle_core::bail!("Weight `{name}` can only be loaded with its shape")
+    }
+
+    fn contains_tensor(&self, _: &str) -> bool {
+        true
+    }
+}
+
+/// A [`VarBuilder`] of a checkpoint with [`NamedWeights`], for reference output tests.
+pub(crate) fn named_weights_var_builder() -> VarBuilder<'static> {
+    VarBuilder::from_backend(Box::new(NamedWeights), DType::F32, Device::Cpu)
+}
+
+/// Write a shrunk version of the configuration of a fixture to `dir`, along with its tokenizer
+/// and pooling configuration.
+fn write_tiny_config(
+    dir: &Path,
+    fixture: &str,
+    update: impl FnOnce(&mut serde_json::Value),
+) -> Result<serde_json::Value> {
+    let fixture = Path::new(fixture);
+
+    let mut config: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(fixture.join("config.json"))?)?;
+    config["hidden_size"] = TINY_HIDDEN_SIZE.into();
+    config["intermediate_size"] = (2 * TINY_HIDDEN_SIZE).into();
+    config["num_attention_heads"] = 2.into();
+    config["num_hidden_layers"] = 1.into();
+    update(&mut config);
+    fs::write(dir.join("config.json"), serde_json::to_string(&config)?)?;
+
+    fs::copy(fixture.join("tokenizer.json"), dir.join("tokenizer.json"))?;
+    fs::create_dir_all(dir.join("1_Pooling"))?;
+    fs::copy(
+        fixture.join("1_Pooling/config.json"),
+        dir.join("1_Pooling/config.json"),
+    )?;
+
+    Ok(config)
+}
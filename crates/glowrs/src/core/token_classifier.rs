@@ -0,0 +1,174 @@
+//! Token classification
+//!
+//! `*ForTokenClassification` checkpoints label each token of a text, e.g. with the entity it
+//! is part of for named entity recognition (NER). A [`TokenClassifier`] returns the most
+//! likely label of each token, along with its position in the text.
+
+use candle_core::{DType, Module};
+use candle_nn::ops::softmax_last_dim;
+use candle_nn::{linear, Linear};
+use serde::Serialize;
+use tokenizers::EncodeInput;
+
+use crate::core::config::model::{BaseModelConfig, ModelType};
+use crate::core::embedder::load_var_builder;
+use crate::core::repo::ModelRepo;
+use crate::{Device, Error, Result, SentenceTransformer};
+
+/// Label of a token of a text.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TokenLabel {
+    /// The token, as in the vocabulary of the tokenizer
+    pub token: String,
+    pub label: String,
+    pub score: f32,
+    /// Byte offset of the start of the token in the text
+    pub start: usize,
+    /// Byte offset of the end of the token in the text
+    pub end: usize,
+}
+
+/// Labels the tokens of texts with a token classification model.
+#[derive(Clone)]
+pub struct TokenClassifier {
+    model: SentenceTransformer,
+    classifier: Linear,
+    labels: Vec<String>,
+}
+
+impl TokenClassifier {
+    pub(crate) fn from_model_repo(
+        model_repo: &ModelRepo,
+        device: &Device,
+        dtype: DType,
+        quantized: bool,
+    ) -> Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "token-classifier-from-folder");
+        let _enter = span.enter();
+
+        let model =
+            SentenceTransformer::from_model_repo(model_repo, device, dtype, None, None, quantized)?;
+        let config: BaseModelConfig = serde_json::from_value(model.model_config().clone())?;
+        if model.model_type() != &ModelType::Classifier || !config.is_token_classifier() {
+            return Err(Error::ModelLoad(
+                "Model is not a token classification model.",
+            ));
+        }
+        let labels = config.labels();
+
+        let model_config = model.model_config();
+        let hidden_size = model_config["hidden_size"]
+            .as_u64()
+            .or_else(|| model_config["dim"].as_u64())
+            .ok_or(Error::InvalidModelConfig(
+                "Model configuration has no hidden size.",
+            ))? as usize;
+
+        // The classifier is small, so it is kept in full precision like the embeddings
+        let vb = load_var_builder(model.model_weights(), device, DType::F32)?;
+        let classifier = linear(hidden_size, labels.len(), vb.pp("classifier"))?;
+
+        Ok(Self {
+            model,
+            classifier,
+            labels,
+        })
+    }
+
+    /// Names of the labels, in the order of the logits.
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    /// Label each token of each text, except special tokens such as `[CLS]` and `[SEP]`.
+    pub fn predict<'s, E>(&self, texts: Vec<E>) -> Result<Vec<Vec<TokenLabel>>>
+    where
+        E: Into<EncodeInput<'s>> + Send,
+    {
+        let span = tracing::span!(tracing::Level::TRACE, "token-classifier-predict");
+        let _enter = span.enter();
+
+        let batch = self.model.tokenize_batch_with_offsets(texts)?;
+        let embeddings = self.model.encode_tokens(&batch)?.to_dtype(DType::F32)?;
+        let scores = softmax_last_dim(&self.classifier.forward(&embeddings)?)?;
+        let scores: Vec<Vec<Vec<f32>>> = scores.to_vec3()?;
+
+        Ok(batch
+            .encodings()
+            .iter()
+            .zip(scores)
+            .map(|(encoding, scores)| {
+                encoding
+                    .get_tokens()
+                    .iter()
+                    .zip(encoding.get_offsets())
+                    .zip(encoding.get_special_tokens_mask())
+                    .zip(encoding.get_attention_mask())
+                    .zip(scores)
+                    .filter(|((((_, _), &special), &attention), _)| special == 0 && attention == 1)
+                    .map(|((((token, &(start, end)), _), _), scores)| {
+                        let (best, score) = scores
+                            .into_iter()
+                            .enumerate()
+                            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                            .unwrap_or_default();
+                        TokenLabel {
+                            token: token.clone(),
+                            label: self.labels[best].clone(),
+                            score,
+                            start,
+                            end,
+                        }
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// The transformer the texts are encoded with.
+    pub fn model(&self) -> &SentenceTransformer {
+        &self.model
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::test_utils::create_tiny_token_classifier_repo;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_token_classifier() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_token_classifier_repo(dir.path(), &["O", "B-PER", "I-PER"])?;
+
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build_token_classifier()?;
+
+        let texts = vec!["The cat", "A dog sits on the mat"];
+        let predictions = model.predict(texts.clone())?;
+        assert_eq!(predictions.len(), 2);
+
+        // Special tokens and padding are left out
+        let tokens: Vec<&str> = predictions[0].iter().map(|t| t.token.as_str()).collect();
+        assert_eq!(tokens, ["the", "cat"]);
+        assert_eq!(predictions[1].len(), 6);
+
+        for (text, prediction) in texts.iter().zip(&predictions) {
+            for token in prediction {
+                assert_eq!(text[token.start..token.end].to_lowercase(), token.token);
+                assert!(model.labels().contains(&token.label));
+                assert!(token.score > 1. / 3. && token.score <= 1.);
+            }
+        }
+
+        // Token classifiers can't be loaded as sequence classifiers
+        assert!(SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build_classifier()
+            .is_err());
+
+        Ok(())
+    }
+}
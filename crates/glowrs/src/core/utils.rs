@@ -9,6 +9,46 @@ pub fn normalize_l2(v: &Tensor) -> candle_core::Result<Tensor> {
     v.broadcast_div(&v.sqr()?.sum_keepdim(1)?.sqrt()?)
 }
 
+/// Cosine similarity between every row of `a` (`(n, dim)`) and every row of `b` (`(m, dim)`), as
+/// an `(n, m)` matrix. Computed on the device of the inputs.
+pub fn cosine_similarity(a: &Tensor, b: &Tensor) -> candle_core::Result<Tensor> {
+    normalize_l2(a)?.matmul(&normalize_l2(b)?.t()?)
+}
+
+/// Encode a corpus `batch_size` sentences at a time with `encode`, into embeddings of shape
+/// `(n, dim)`, or `None` if the corpus is empty.
+pub(crate) fn encode_in_batches<S, F>(
+    corpus: &[S],
+    batch_size: usize,
+    mut encode: F,
+) -> Result<Option<Tensor>>
+where
+    S: AsRef<str>,
+    F: FnMut(Vec<&str>) -> Result<Tensor>,
+{
+    let embeddings = corpus
+        .chunks(batch_size.max(1))
+        .map(|batch| encode(batch.iter().map(|s| s.as_ref()).collect()))
+        .collect::<Result<Vec<_>>>()?;
+
+    match embeddings.is_empty() {
+        true => Ok(None),
+        false => Ok(Some(Tensor::cat(&embeddings, 0)?)),
+    }
+}
+
+/// Keep the first `dim` dimensions of embeddings of shape `(n, dim')`, as for Matryoshka
+/// embeddings. Fails if `dim` is zero or more than the dimensions of the embeddings.
+pub fn truncate_dim(embeddings: &Tensor, dim: usize) -> Result<Tensor> {
+    if dim == 0 || dim > embeddings.dim(1)? {
+        return Err(crate::Error::InvalidArgument(
+            "Truncation dimension should be between 1 and the embedding dimension",
+        ));
+    }
+
+    Ok(embeddings.narrow(1, 0, dim)?)
+}
+
 pub fn parse_repo_string(repo_string: &str) -> Result<(&str, &str)> {
     use crate::Error::InvalidModelName;
 
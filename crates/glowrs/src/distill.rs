@@ -0,0 +1,257 @@
+//! Knowledge distillation of embeddings
+//!
+//! Fits a linear projection on top of a (small) student model so that its embeddings mimic those
+//! of a (large) teacher model over a corpus. The projection is stored in the layout of a
+//! `sentence-transformers` `Dense` module, so it can be saved alongside the student model.
+
+use candle_core::{DType, Device, Module, Tensor};
+use candle_nn::{AdamW, Linear, Optimizer, VarBuilder, VarMap};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::{Error, Result, SentenceTransformer};
+
+const DENSE_WEIGHTS_FILE: &str = "model.safetensors";
+const DENSE_CONFIG_FILE: &str = "config.json";
+
+/// Training hyperparameters for distillation.
+#[derive(Debug, Clone)]
+pub struct DistillationConfig {
+    /// Number of passes over the corpus
+    pub epochs: usize,
+    /// AdamW learning rate
+    pub learning_rate: f64,
+    /// Number of sentences to encode and fit at once
+    pub batch_size: usize,
+}
+
+impl Default for DistillationConfig {
+    fn default() -> Self {
+        Self {
+            epochs: 100,
+            learning_rate: 1e-2,
+            batch_size: 32,
+        }
+    }
+}
+
+/// A linear projection from the student embedding space into the teacher embedding space.
+pub struct LinearProjection {
+    linear: Linear,
+    in_features: usize,
+    out_features: usize,
+}
+
+#[derive(Serialize)]
+struct DenseConfig {
+    in_features: usize,
+    out_features: usize,
+    bias: bool,
+    activation_function: &'static str,
+}
+
+impl LinearProjection {
+    pub fn in_features(&self) -> usize {
+        self.in_features
+    }
+
+    pub fn out_features(&self) -> usize {
+        self.out_features
+    }
+
+    /// Project a batch of student embeddings of shape `(n, in_features)`.
+    pub fn forward(&self, embeddings: &Tensor) -> Result<Tensor> {
+        Ok(self.linear.forward(embeddings)?)
+    }
+
+    /// Save the projection as a `sentence-transformers` `Dense` module folder (e.g. `2_Dense`).
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        let config = DenseConfig {
+            in_features: self.in_features,
+            out_features: self.out_features,
+            bias: true,
+            activation_function: "torch.nn.modules.linear.Identity",
+        };
+        fs::write(
+            dir.join(DENSE_CONFIG_FILE),
+            serde_json::to_string_pretty(&config)?,
+        )?;
+
+        let mut tensors = HashMap::new();
+        tensors.insert("linear.weight".to_string(), self.linear.weight().clone());
+        if let Some(bias) = self.linear.bias() {
+            tensors.insert("linear.bias".to_string(), bias.clone());
+        }
+        candle_core::safetensors::save(&tensors, dir.join(DENSE_WEIGHTS_FILE))?;
+
+        Ok(())
+    }
+}
+
+/// Output of a distillation run.
+pub struct DistillationOutput {
+    pub projection: LinearProjection,
+    /// Mean squared error over the whole corpus after the last epoch
+    pub loss: f32,
+}
+
+/// Distill a teacher model into a student model plus a linear projection.
+///
+/// # Arguments
+///
+/// * `teacher` - The model whose embeddings should be mimicked.
+/// * `student` - The (cheaper) model that is used at inference time.
+/// * `corpus` - The sentences to fit the projection on.
+/// * `config` - Training hyperparameters.
+pub fn distill<S: AsRef<str>>(
+    teacher: &SentenceTransformer,
+    student: &SentenceTransformer,
+    corpus: &[S],
+    config: &DistillationConfig,
+) -> Result<DistillationOutput> {
+    let span = tracing::span!(tracing::Level::TRACE, "distill");
+    let _enter = span.enter();
+
+    if corpus.is_empty() {
+        return Err(Error::InvalidArgument("Distillation corpus is empty"));
+    }
+
+    let mut student_embeddings = Vec::new();
+    let mut teacher_embeddings = Vec::new();
+
+    for batch in corpus.chunks(config.batch_size.max(1)) {
+        let sentences: Vec<&str> = batch.iter().map(|s| s.as_ref()).collect();
+
+        teacher_embeddings.push(
+            teacher
+                .encode_batch(sentences.clone(), false)?
+                .to_device(&Device::Cpu)?,
+        );
+        student_embeddings.push(
+            student
+                .encode_batch(sentences, false)?
+                .to_device(&Device::Cpu)?,
+        );
+    }
+
+    let student_embeddings = Tensor::cat(&student_embeddings, 0)?;
+    let teacher_embeddings = Tensor::cat(&teacher_embeddings, 0)?;
+
+    fit_projection(&student_embeddings, &teacher_embeddings, config)
+}
+
+/// Fit a linear projection mapping `inputs` onto `targets` by minimizing the mean squared error.
+///
+/// Both tensors should be of shape `(n, dim)` with the same number of rows.
+pub fn fit_projection(
+    inputs: &Tensor,
+    targets: &Tensor,
+    config: &DistillationConfig,
+) -> Result<DistillationOutput> {
+    let (n, in_features) = inputs.dims2()?;
+    let (n_targets, out_features) = targets.dims2()?;
+
+    if n != n_targets {
+        return Err(Error::InvalidArgument(
+            "Inputs and targets have a different number of rows",
+        ));
+    }
+
+    let var_map = VarMap::new();
+    let vb = VarBuilder::from_varmap(&var_map, DType::F32, inputs.device());
+    let linear = candle_nn::linear(in_features, out_features, vb.pp("linear"))?;
+
+    let mut optimizer = AdamW::new_lr(var_map.all_vars(), config.learning_rate)?;
+    let batch_size = config.batch_size.max(1);
+
+    for epoch in 0..config.epochs {
+        for start in (0..n).step_by(batch_size) {
+            let len = batch_size.min(n - start);
+            let x = inputs.narrow(0, start, len)?;
+            let y = targets.narrow(0, start, len)?;
+
+            let loss = candle_nn::loss::mse(&linear.forward(&x)?, &y)?;
+            optimizer.backward_step(&loss)?;
+        }
+
+        tracing::trace!("finished distillation epoch {}", epoch);
+    }
+
+    let loss = candle_nn::loss::mse(&linear.forward(inputs)?, targets)?.to_scalar::<f32>()?;
+    tracing::debug!("distillation loss: {}", loss);
+
+    // Detach the trained weights from the variables and optimizer
+    let linear = Linear::new(linear.weight().detach(), linear.bias().map(|b| b.detach()));
+
+    Ok(DistillationOutput {
+        projection: LinearProjection {
+            linear,
+            in_features,
+            out_features,
+        },
+        loss,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_fit_projection() -> Result<()> {
+        let device = Device::Cpu;
+        let inputs = Tensor::randn(0f32, 1., (64, 4), &device)?;
+        let true_weights = Tensor::randn(0f32, 1., (4, 6), &device)?;
+        let targets = inputs.matmul(&true_weights)?;
+
+        let config = DistillationConfig {
+            epochs: 300,
+            learning_rate: 5e-2,
+            batch_size: 16,
+        };
+        let DistillationOutput { projection, loss } = fit_projection(&inputs, &targets, &config)?;
+
+        assert_eq!(projection.in_features(), 4);
+        assert_eq!(projection.out_features(), 6);
+        assert!(loss < 1e-2, "loss {loss} too high");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fit_projection_shape_mismatch() -> Result<()> {
+        let device = Device::Cpu;
+        let inputs = Tensor::zeros((4, 2), DType::F32, &device)?;
+        let targets = Tensor::zeros((3, 2), DType::F32, &device)?;
+
+        assert!(fit_projection(&inputs, &targets, &DistillationConfig::default()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_projection() -> Result<()> {
+        let device = Device::Cpu;
+        let inputs = Tensor::randn(0f32, 1., (8, 2), &device)?;
+        let config = DistillationConfig {
+            epochs: 1,
+            ..Default::default()
+        };
+        let DistillationOutput { projection, .. } = fit_projection(&inputs, &inputs, &config)?;
+
+        let dir = tempdir()?;
+        projection.save(dir.path())?;
+
+        let tensors = candle_core::safetensors::load(dir.path().join(DENSE_WEIGHTS_FILE), &device)?;
+        assert_eq!(tensors["linear.weight"].dims(), &[2, 2]);
+        assert!(dir.path().join(DENSE_CONFIG_FILE).exists());
+
+        Ok(())
+    }
+}
@@ -35,9 +35,14 @@ pub enum Error {
     #[error("IO error: {0}")]
     IO(#[from] std::io::Error),
 
+    #[cfg(feature = "hub")]
     #[error("HF Hub error: {0}")]
     HFHub(#[from] hf_hub::api::sync::ApiError),
 
+    #[cfg(feature = "onnx")]
+    #[error("ONNX Runtime error: {0}")]
+    Onnx(#[from] ort::Error),
+
     #[error("Generic error: {0}")]
     Generic(#[from] anyhow::Error),
 }
@@ -72,7 +77,10 @@ mod test {
         let error = Error::IO(std::io::Error::new(std::io::ErrorKind::Other, "test"));
         assert_eq!(error.to_string(), "IO error: test");
 
-        let error = Error::HFHub(hf_hub::api::sync::ApiError::MissingHeader("test"));
-        assert_eq!(error.to_string(), "HF Hub error: Header test is missing");
+        #[cfg(feature = "hub")]
+        {
+            let error = Error::HFHub(hf_hub::api::sync::ApiError::MissingHeader("test"));
+            assert_eq!(error.to_string(), "HF Hub error: Header test is missing");
+        }
     }
 }
@@ -0,0 +1,13 @@
+//! Model quality evaluation
+//!
+//! Utilities to check that a loaded model reproduces the quality reported for it, by running it
+//! over standard benchmark style datasets.
+
+pub mod retrieval;
+pub mod sts;
+
+pub use retrieval::{
+    evaluate_retrieval, load_retrieval_dataset, mrr, ndcg, recall_at_k, RetrievalDataset,
+    RetrievalEvaluation,
+};
+pub use sts::{evaluate_sts, load_sts_dataset, StsEvaluation, StsExample};
@@ -0,0 +1,331 @@
+//! Information retrieval evaluation
+//!
+//! Ranks a corpus by cosine similarity for every query and scores the rankings against relevance
+//! judgements (qrels), as done by the `InformationRetrievalEvaluator` in `sentence-transformers`.
+//! Datasets can be loaded in the BEIR format.
+
+use candle_core::{DType, Tensor};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::core::utils::encode_in_batches;
+use crate::{Error, Result, SentenceTransformer};
+
+/// Number of queries scored against the corpus at once
+const QUERY_CHUNK_SIZE: usize = 256;
+
+/// Relevance of documents for a query, by document id. Documents with a relevance of zero or
+/// less are considered not relevant.
+pub type Relevance = HashMap<String, f64>;
+
+/// A query or corpus document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document {
+    pub id: String,
+    pub text: String,
+}
+
+/// Queries, a corpus to search and the relevance judgements for each query.
+#[derive(Debug, Clone, Default)]
+pub struct RetrievalDataset {
+    pub queries: Vec<Document>,
+    pub corpus: Vec<Document>,
+    /// Relevance judgements, by query id
+    pub qrels: HashMap<String, Relevance>,
+}
+
+/// Retrieval metrics at cut-off `k`, averaged over all queries with relevance judgements.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RetrievalEvaluation {
+    pub k: usize,
+    pub recall: f64,
+    pub mrr: f64,
+    pub ndcg: f64,
+    pub n_queries: usize,
+}
+
+#[derive(Deserialize)]
+struct BeirDocument {
+    #[serde(rename = "_id")]
+    id: String,
+    #[serde(default)]
+    title: String,
+    text: String,
+}
+
+impl From<BeirDocument> for Document {
+    fn from(doc: BeirDocument) -> Self {
+        let text = if doc.title.is_empty() {
+            doc.text
+        } else {
+            format!("{} {}", doc.title, doc.text)
+        };
+
+        Self { id: doc.id, text }
+    }
+}
+
+/// Load a retrieval dataset in the BEIR format.
+///
+/// # Arguments
+///
+/// * `queries` - JSON lines file with `_id` and `text` keys.
+/// * `corpus` - JSON lines file with `_id`, `text` and optionally `title` keys. Titles are
+///   prepended to the text.
+/// * `qrels` - Tab-separated file with `query-id`, `corpus-id` and `score` columns and a header row.
+pub fn load_retrieval_dataset<P: AsRef<Path>>(
+    queries: P,
+    corpus: P,
+    qrels: P,
+) -> Result<RetrievalDataset> {
+    Ok(RetrievalDataset {
+        queries: parse_documents(&fs::read_to_string(queries)?)?,
+        corpus: parse_documents(&fs::read_to_string(corpus)?)?,
+        qrels: parse_qrels(&fs::read_to_string(qrels)?)?,
+    })
+}
+
+fn parse_documents(content: &str) -> Result<Vec<Document>> {
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Ok(serde_json::from_str::<BeirDocument>(line)?.into()))
+        .collect()
+}
+
+fn parse_qrels(content: &str) -> Result<HashMap<String, Relevance>> {
+    let mut qrels: HashMap<String, Relevance> = HashMap::new();
+
+    // Skip the header row
+    for line in content.lines().skip(1).filter(|l| !l.trim().is_empty()) {
+        let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+        let [query_id, corpus_id, score] = fields[..] else {
+            return Err(Error::InvalidArgument(
+                "Qrels row should have `query-id`, `corpus-id` and `score` columns",
+            ));
+        };
+        let score = score
+            .parse()
+            .map_err(|_| Error::InvalidArgument("Invalid score in qrels"))?;
+
+        qrels
+            .entry(query_id.to_string())
+            .or_default()
+            .insert(corpus_id.to_string(), score);
+    }
+
+    Ok(qrels)
+}
+
+/// Fraction of the relevant documents that are ranked in the top `k`.
+pub fn recall_at_k<S: AsRef<str>>(ranking: &[S], relevant: &Relevance, k: usize) -> f64 {
+    let n_relevant = relevant.values().filter(|&&r| r > 0.).count();
+    if n_relevant == 0 {
+        return 0.;
+    }
+
+    let hits = ranking
+        .iter()
+        .take(k)
+        .filter(|id| gain(relevant, id.as_ref()) > 0.)
+        .count();
+
+    hits as f64 / n_relevant as f64
+}
+
+/// Reciprocal rank of the first relevant document in the top `k`, or zero if there is none.
+pub fn mrr<S: AsRef<str>>(ranking: &[S], relevant: &Relevance, k: usize) -> f64 {
+    ranking
+        .iter()
+        .take(k)
+        .position(|id| gain(relevant, id.as_ref()) > 0.)
+        .map_or(0., |rank| 1. / (rank + 1) as f64)
+}
+
+/// Normalized discounted cumulative gain of the top `k`, using graded relevance as gain.
+pub fn ndcg<S: AsRef<str>>(ranking: &[S], relevant: &Relevance, k: usize) -> f64 {
+    let actual = dcg(ranking.iter().map(|id| gain(relevant, id.as_ref())), k);
+
+    let mut ideal: Vec<f64> = relevant.values().copied().filter(|&r| r > 0.).collect();
+    ideal.sort_by(|a, b| b.total_cmp(a));
+    let idcg = dcg(ideal.into_iter(), k);
+
+    if idcg > 0. {
+        actual / idcg
+    } else {
+        0.
+    }
+}
+
+fn gain(relevant: &Relevance, id: &str) -> f64 {
+    relevant.get(id).copied().unwrap_or(0.).max(0.)
+}
+
+fn dcg(gains: impl Iterator<Item = f64>, k: usize) -> f64 {
+    gains
+        .take(k)
+        .enumerate()
+        .map(|(i, gain)| gain / (i as f64 + 2.).log2())
+        .sum()
+}
+
+/// Evaluate a model on a retrieval dataset.
+///
+/// # Arguments
+///
+/// * `model` - The [`SentenceTransformer`] to evaluate.
+/// * `dataset` - Queries, corpus and relevance judgements.
+/// * `k` - Number of top ranked documents to score.
+/// * `batch_size` - Number of texts to encode at once.
+pub fn evaluate_retrieval(
+    model: &SentenceTransformer,
+    dataset: &RetrievalDataset,
+    k: usize,
+    batch_size: usize,
+) -> Result<RetrievalEvaluation> {
+    let span = tracing::span!(tracing::Level::TRACE, "evaluate-retrieval");
+    let _enter = span.enter();
+
+    let queries: Vec<&Document> = dataset
+        .queries
+        .iter()
+        .filter(|q| dataset.qrels.contains_key(&q.id))
+        .collect();
+
+    if queries.is_empty() {
+        return Err(Error::InvalidArgument(
+            "No queries with relevance judgements to evaluate",
+        ));
+    }
+    if dataset.corpus.is_empty() {
+        return Err(Error::InvalidArgument("Retrieval corpus is empty"));
+    }
+
+    let encode = |texts: Vec<&str>| -> Result<Tensor> {
+        encode_in_batches(&texts, batch_size, |batch| model.encode_batch(batch, true))?
+            .ok_or(Error::InvalidArgument("Nothing to encode"))
+    };
+
+    let corpus_embeddings = encode(dataset.corpus.iter().map(|d| d.text.as_str()).collect())?;
+    let query_embeddings = encode(queries.iter().map(|q| q.text.as_str()).collect())?;
+    let corpus_embeddings = corpus_embeddings.t()?;
+
+    // Score a chunk of queries at a time, so the scores don't take memory for every query and
+    // document at once
+    let (mut recall_sum, mut mrr_sum, mut ndcg_sum) = (0., 0., 0.);
+    for (i, queries) in queries.chunks(QUERY_CHUNK_SIZE).enumerate() {
+        // Embeddings are normalized, so the dot product is the cosine similarity
+        let scores = query_embeddings
+            .narrow(0, i * QUERY_CHUNK_SIZE, queries.len())?
+            .matmul(&corpus_embeddings)?
+            .to_dtype(DType::F32)?
+            .to_vec2::<f32>()?;
+
+        for (query, scores) in queries.iter().zip(scores) {
+            let ranking: Vec<&str> = top_k(&scores, k)
+                .into_iter()
+                .map(|i| dataset.corpus[i].id.as_str())
+                .collect();
+            let relevant = &dataset.qrels[&query.id];
+
+            recall_sum += recall_at_k(&ranking, relevant, k);
+            mrr_sum += mrr(&ranking, relevant, k);
+            ndcg_sum += ndcg(&ranking, relevant, k);
+        }
+    }
+
+    let n = queries.len() as f64;
+    Ok(RetrievalEvaluation {
+        k,
+        recall: recall_sum / n,
+        mrr: mrr_sum / n,
+        ndcg: ndcg_sum / n,
+        n_queries: queries.len(),
+    })
+}
+
+/// Indices of the `k` highest scores, from highest to lowest. Ties are ranked by index.
+fn top_k(scores: &[f32], k: usize) -> Vec<usize> {
+    let by_score = |a: &usize, b: &usize| scores[*b].total_cmp(&scores[*a]).then(a.cmp(b));
+
+    let mut order: Vec<usize> = (0..scores.len()).collect();
+    if k < order.len() {
+        order.select_nth_unstable_by(k, by_score);
+        order.truncate(k);
+    }
+    order.sort_unstable_by(by_score);
+
+    order
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    fn relevance(judgements: &[(&str, f64)]) -> Relevance {
+        judgements
+            .iter()
+            .map(|(id, r)| (id.to_string(), *r))
+            .collect()
+    }
+
+    #[test]
+    fn test_recall_at_k() {
+        let relevant = relevance(&[("a", 1.), ("c", 1.), ("x", 0.)]);
+        let ranking = ["a", "b", "c", "d"];
+
+        assert_relative_eq!(recall_at_k(&ranking, &relevant, 1), 0.5);
+        assert_relative_eq!(recall_at_k(&ranking, &relevant, 3), 1.0);
+        assert_relative_eq!(recall_at_k(&ranking, &relevance(&[]), 3), 0.0);
+    }
+
+    #[test]
+    fn test_mrr() {
+        let relevant = relevance(&[("c", 1.)]);
+        let ranking = ["a", "b", "c"];
+
+        assert_relative_eq!(mrr(&ranking, &relevant, 3), 1. / 3.);
+        assert_relative_eq!(mrr(&ranking, &relevant, 2), 0.0);
+    }
+
+    #[test]
+    fn test_ndcg() {
+        let relevant = relevance(&[("a", 2.), ("b", 1.)]);
+
+        assert_relative_eq!(ndcg(&["a", "b", "c"], &relevant, 3), 1.0);
+
+        // Swapping the two relevant documents
+        let expected = (1. + 2. / 3f64.log2()) / (2. + 1. / 3f64.log2());
+        assert_relative_eq!(ndcg(&["b", "a", "c"], &relevant, 3), expected);
+
+        assert_relative_eq!(ndcg(&["c", "d"], &relevant, 2), 0.0);
+    }
+
+    #[test]
+    fn test_top_k() {
+        let scores = [0.1, 0.9, 0.5, 0.9, -0.2];
+        assert_eq!(top_k(&scores, 3), vec![1, 3, 2]);
+        assert_eq!(top_k(&scores, 10), vec![1, 3, 2, 0, 4]);
+        assert!(top_k(&scores, 0).is_empty());
+    }
+
+    #[test]
+    fn test_parse_beir() -> Result<()> {
+        let corpus = parse_documents(
+            "{\"_id\": \"d1\", \"title\": \"Cats\", \"text\": \"Cats sit outside.\"}\n\
+             {\"_id\": \"d2\", \"text\": \"I love pasta.\"}\n",
+        )?;
+        assert_eq!(corpus[0].text, "Cats Cats sit outside.");
+        assert_eq!(corpus[1].text, "I love pasta.");
+
+        let qrels = parse_qrels("query-id\tcorpus-id\tscore\nq1\td1\t1\nq1\td2\t0\n")?;
+        assert_eq!(qrels["q1"], relevance(&[("d1", 1.), ("d2", 0.)]));
+
+        assert!(parse_qrels("query-id\tcorpus-id\tscore\nq1\td1\n").is_err());
+
+        Ok(())
+    }
+}
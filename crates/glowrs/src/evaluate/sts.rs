@@ -0,0 +1,221 @@
+//! Semantic Textual Similarity (STS) evaluation
+//!
+//! Computes the cosine similarity between the embeddings of sentence pairs and correlates it with
+//! gold similarity scores, as done by the `EmbeddingSimilarityEvaluator` in `sentence-transformers`.
+
+use candle_core::DType;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::{Error, Result, SentenceTransformer};
+
+/// A sentence pair with a gold similarity score.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct StsExample {
+    pub sentence1: String,
+    pub sentence2: String,
+    pub score: f32,
+}
+
+/// Correlation between the predicted cosine similarities and the gold scores.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StsEvaluation {
+    pub pearson: f64,
+    pub spearman: f64,
+    pub n_examples: usize,
+}
+
+/// Load an STS dataset from a file.
+///
+/// Supported formats are JSON lines (`.jsonl`) with `sentence1`, `sentence2` and `score` keys, and
+/// tab-separated files (e.g. the STS benchmark `.tsv`/`.csv` files) with a header row that contains
+/// `sentence1`, `sentence2` and `score` columns.
+pub fn load_sts_dataset<P: AsRef<Path>>(path: P) -> Result<Vec<StsExample>> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path)?;
+
+    if path.extension().is_some_and(|ext| ext == "jsonl") {
+        content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| Ok(serde_json::from_str(line)?))
+            .collect()
+    } else {
+        parse_tsv(&content)
+    }
+}
+
+fn parse_tsv(content: &str) -> Result<Vec<StsExample>> {
+    let mut lines = content.lines();
+    let header: Vec<&str> = lines
+        .next()
+        .ok_or(Error::InvalidArgument("STS dataset is empty"))?
+        .split('\t')
+        .collect();
+
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|c| c.trim() == name)
+            .ok_or(Error::InvalidArgument(
+                "STS dataset misses a `sentence1`, `sentence2` or `score` column",
+            ))
+    };
+    let (s1_idx, s2_idx, score_idx) =
+        (column("sentence1")?, column("sentence2")?, column("score")?);
+
+    lines
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let field = |idx: usize| {
+                fields.get(idx).copied().ok_or(Error::InvalidArgument(
+                    "STS dataset row has too few columns",
+                ))
+            };
+
+            Ok(StsExample {
+                sentence1: field(s1_idx)?.to_string(),
+                sentence2: field(s2_idx)?.to_string(),
+                score: field(score_idx)?
+                    .trim()
+                    .parse()
+                    .map_err(|_| Error::InvalidArgument("Invalid score in STS dataset"))?,
+            })
+        })
+        .collect()
+}
+
+/// Evaluate a model on an STS dataset.
+///
+/// # Arguments
+///
+/// * `model` - The [`SentenceTransformer`] to evaluate.
+/// * `examples` - Sentence pairs with gold similarity scores.
+/// * `batch_size` - Number of sentence pairs to encode at once.
+pub fn evaluate_sts(
+    model: &SentenceTransformer,
+    examples: &[StsExample],
+    batch_size: usize,
+) -> Result<StsEvaluation> {
+    let span = tracing::span!(tracing::Level::TRACE, "evaluate-sts");
+    let _enter = span.enter();
+
+    if examples.len() < 2 {
+        return Err(Error::InvalidArgument(
+            "At least two examples are required to compute correlations",
+        ));
+    }
+
+    let mut similarities = Vec::with_capacity(examples.len());
+    for batch in examples.chunks(batch_size.max(1)) {
+        let sentences1: Vec<&str> = batch.iter().map(|e| e.sentence1.as_str()).collect();
+        let sentences2: Vec<&str> = batch.iter().map(|e| e.sentence2.as_str()).collect();
+
+        let embeddings1 = model.encode_batch(sentences1, true)?;
+        let embeddings2 = model.encode_batch(sentences2, true)?;
+
+        // Embeddings are normalized, so the dot product is the cosine similarity
+        let cosine = (embeddings1 * embeddings2)?
+            .sum(1)?
+            .to_dtype(DType::F32)?
+            .to_vec1::<f32>()?;
+        similarities.extend(cosine.into_iter().map(f64::from));
+    }
+
+    let scores: Vec<f64> = examples.iter().map(|e| f64::from(e.score)).collect();
+
+    Ok(StsEvaluation {
+        pearson: pearson(&similarities, &scores),
+        spearman: spearman(&similarities, &scores),
+        n_examples: examples.len(),
+    })
+}
+
+/// Pearson correlation coefficient.
+pub fn pearson(x: &[f64], y: &[f64]) -> f64 {
+    let n = x.len().min(y.len()) as f64;
+    let mean_x = x.iter().sum::<f64>() / n;
+    let mean_y = y.iter().sum::<f64>() / n;
+
+    let (mut cov, mut var_x, mut var_y) = (0.0, 0.0, 0.0);
+    for (xi, yi) in x.iter().zip(y) {
+        let (dx, dy) = (xi - mean_x, yi - mean_y);
+        cov += dx * dy;
+        var_x += dx * dx;
+        var_y += dy * dy;
+    }
+
+    cov / (var_x * var_y).sqrt()
+}
+
+/// Spearman rank correlation coefficient.
+pub fn spearman(x: &[f64], y: &[f64]) -> f64 {
+    pearson(&ranks(x), &ranks(y))
+}
+
+/// Fractional ranks, where ties get the average of the ranks they span.
+fn ranks(values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|&a, &b| values[a].total_cmp(&values[b]));
+
+    let mut ranks = vec![0.0; values.len()];
+    let mut i = 0;
+    while i < order.len() {
+        let mut j = i;
+        while j + 1 < order.len() && values[order[j + 1]] == values[order[i]] {
+            j += 1;
+        }
+        let rank = (i + j) as f64 / 2.0 + 1.0;
+        for &idx in &order[i..=j] {
+            ranks[idx] = rank;
+        }
+        i = j + 1;
+    }
+
+    ranks
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_pearson() {
+        let x = [1.0, 2.0, 3.0, 4.0];
+        assert_relative_eq!(pearson(&x, &[2.0, 4.0, 6.0, 8.0]), 1.0);
+        assert_relative_eq!(pearson(&x, &[8.0, 6.0, 4.0, 2.0]), -1.0);
+    }
+
+    #[test]
+    fn test_spearman() {
+        // Monotonic but non-linear relation
+        let x = [1.0, 2.0, 3.0, 4.0];
+        let y = [1.0, 10.0, 100.0, 1000.0];
+        assert_relative_eq!(spearman(&x, &y), 1.0);
+        assert!(pearson(&x, &y) < 1.0);
+    }
+
+    #[test]
+    fn test_ranks_with_ties() {
+        assert_eq!(ranks(&[3.0, 1.0, 3.0, 2.0]), vec![3.5, 1.0, 3.5, 2.0]);
+    }
+
+    #[test]
+    fn test_parse_tsv() -> Result<()> {
+        let content = "split\tscore\tsentence1\tsentence2\n\
+            test\t5.0\tA man is playing guitar.\tA man plays the guitar.\n\
+            test\t0.5\tA cat sits.\tI love pasta.\n";
+
+        let examples = parse_tsv(content)?;
+        assert_eq!(examples.len(), 2);
+        assert_eq!(examples[0].sentence2, "A man plays the guitar.");
+        assert_eq!(examples[1].score, 0.5);
+
+        assert!(parse_tsv("sentence1\tsentence2\n").is_err());
+
+        Ok(())
+    }
+}
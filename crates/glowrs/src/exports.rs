@@ -1 +1 @@
-pub use candle_core::Device;
+pub use candle_core::{DType, Device};
@@ -1,8 +1,15 @@
 #![doc = include_str!("../README.md")]
 
 pub mod core;
+pub mod distill;
 mod error;
+pub mod evaluate;
 mod exports;
+pub mod models;
+pub mod quantize;
+pub mod reduce;
+pub mod vision;
+pub mod visualize;
 
 pub(crate) mod pooling;
 
@@ -10,13 +17,54 @@ pub use exports::*;
 
 pub use crate::error::{Error, Result};
 
+pub use core::chunking::{ChunkAggregation, ChunkConfig};
+pub use core::classifier::TextClassifier;
+pub use core::colbert::ColBERT;
+pub use core::cross_encoder::CrossEncoder;
+pub use core::dual_encoder::DualEncoder;
 pub use core::sentence_transformer::SentenceTransformer;
-pub use pooling::PoolingStrategy;
+pub use core::token_classifier::TokenClassifier;
+pub use pooling::{CustomPooling, PoolingStrategy};
+pub use vision::ImageEncoder;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, PartialEq, Default)]
+/// Version of the `glowrs` library.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Default)]
 pub struct Usage {
+    /// Number of tokens of the inputs, excluding padding
     pub prompt_tokens: u32,
+    /// Number of tokens processed, which are only the prompt tokens for embeddings
     pub total_tokens: u32,
 }
+
+impl From<&[InputUsage]> for Usage {
+    fn from(inputs: &[InputUsage]) -> Self {
+        let prompt_tokens = inputs.iter().map(|input| input.tokens).sum();
+        Self {
+            prompt_tokens,
+            total_tokens: prompt_tokens,
+        }
+    }
+}
+
+/// Token usage of a single input.
+#[derive(Debug, Serialize, PartialEq, Clone, Copy, Default)]
+pub struct InputUsage {
+    /// Number of tokens the input was encoded with, excluding padding
+    pub tokens: u32,
+    /// Whether the input was truncated to fit the model
+    pub truncated: bool,
+}
+
+impl From<&tokenizers::Encoding> for InputUsage {
+    fn from(encoding: &tokenizers::Encoding) -> Self {
+        Self {
+            tokens: encoding.get_attention_mask().iter().sum(),
+            truncated: !encoding.get_overflowing().is_empty(),
+        }
+    }
+}
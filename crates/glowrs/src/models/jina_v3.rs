@@ -0,0 +1,451 @@
+//! Jina embeddings v3
+//!
+//! Port of the `XLMRobertaLoRA` model of `jinaai/xlm-roberta-flash-implementation`, the
+//! architecture of `jinaai/jina-embeddings-v3`. It is an XLM-RoBERTa encoder with rotary
+//! position embeddings instead of learned ones, and a low-rank (LoRA) adapter per task on each
+//! embedding and linear layer. The adapter is picked when encoding, so one set of weights
+//! serves all tasks; without a task, the layers run without adapter.
+
+use candle_core::{bail, DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle_nn::{
+    layer_norm, ops::softmax_last_dim, rotary_emb::rope, Activation, LayerNorm, VarBuilder,
+};
+use serde::Deserialize;
+
+fn default_lora_alpha() -> f64 {
+    1.
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub intermediate_size: usize,
+    pub hidden_act: Activation,
+    pub type_vocab_size: usize,
+    pub layer_norm_eps: f64,
+    pub position_embedding_type: String,
+    pub rotary_emb_base: f64,
+    #[serde(default)]
+    pub pad_token_id: u32,
+    /// Names of the tasks with an adapter, in the order of the adapter weights
+    pub lora_adaptations: Vec<String>,
+    pub lora_rank: usize,
+    #[serde(default = "default_lora_alpha")]
+    pub lora_alpha: f64,
+}
+
+impl Config {
+    fn head_size(&self) -> usize {
+        self.hidden_size / self.num_attention_heads
+    }
+}
+
+/// The weights of a layer with the LoRA adapter of each task.
+///
+/// Checkpoints save them as a `torch.nn.utils.parametrize` parametrization of the weight: the
+/// original weight, and the `lora_A` and `lora_B` matrices of each task. The adapter adds
+/// `lora_alpha / lora_rank * lora_B @ lora_A` to the weight of linear layers, and
+/// `lora_alpha / lora_rank * lora_A @ lora_B` to that of embeddings.
+struct LoraWeight {
+    weight: Tensor,
+    /// `lora_A` and `lora_B` of all tasks, if the layer has adapters
+    lora: Option<(Tensor, Tensor)>,
+    scaling: f64,
+}
+
+impl LoraWeight {
+    /// Load a weight of shape `(rows, columns)`. For linear layers, the `lora_A` of each task
+    /// is of shape `(rank, columns)` and `lora_B` of shape `(rows, rank)`; for embeddings, the
+    /// other way around.
+    fn load(
+        vb: &VarBuilder,
+        shape: (usize, usize),
+        embedding: bool,
+        config: &Config,
+    ) -> Result<Self> {
+        let scaling = config.lora_alpha / config.lora_rank as f64;
+        if !vb.contains_tensor("parametrizations.weight.original") {
+            return Ok(Self {
+                weight: vb.get(shape, "weight")?,
+                lora: None,
+                scaling,
+            });
+        }
+
+        let (rows, columns) = shape;
+        let (tasks, rank) = (config.lora_adaptations.len(), config.lora_rank);
+        let (a_shape, b_shape) = match embedding {
+            true => ((tasks, rows, rank), (tasks, rank, columns)),
+            false => ((tasks, rank, columns), (tasks, rows, rank)),
+        };
+        let lora = vb.pp("parametrizations.weight.0");
+
+        Ok(Self {
+            weight: vb.get(shape, "parametrizations.weight.original")?,
+            lora: Some((lora.get(a_shape, "lora_A")?, lora.get(b_shape, "lora_B")?)),
+            scaling,
+        })
+    }
+
+    /// The `lora_A` and `lora_B` of a task, if the layer has adapters.
+    fn adapter(&self, task: Option<usize>) -> Result<Option<(Tensor, Tensor)>> {
+        match (&self.lora, task) {
+            (Some((a, b)), Some(task)) => Ok(Some((a.i(task)?, b.i(task)?))),
+            _ => Ok(None),
+        }
+    }
+}
+
+struct LoraEmbedding {
+    weight: LoraWeight,
+}
+
+impl LoraEmbedding {
+    fn load(
+        vb: VarBuilder,
+        vocab_size: usize,
+        hidden_size: usize,
+        config: &Config,
+    ) -> Result<Self> {
+        Ok(Self {
+            weight: LoraWeight::load(&vb, (vocab_size, hidden_size), true, config)?,
+        })
+    }
+
+    fn forward(&self, ids: &Tensor, task: Option<usize>) -> Result<Tensor> {
+        let (batch_size, seq_len) = ids.dims2()?;
+        let ids = ids.flatten_all()?;
+        let mut embeddings = self.weight.weight.index_select(&ids, 0)?;
+        if let Some((a, b)) = self.weight.adapter(task)? {
+            let delta = a.index_select(&ids, 0)?.matmul(&b)?;
+            embeddings = (embeddings + (delta * self.weight.scaling)?)?;
+        }
+        embeddings.reshape((batch_size, seq_len, ()))
+    }
+}
+
+struct LoraLinear {
+    weight: LoraWeight,
+    bias: Tensor,
+}
+
+impl LoraLinear {
+    fn load(vb: VarBuilder, in_dim: usize, out_dim: usize, config: &Config) -> Result<Self> {
+        Ok(Self {
+            weight: LoraWeight::load(&vb, (out_dim, in_dim), false, config)?,
+            bias: vb.get(out_dim, "bias")?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, task: Option<usize>) -> Result<Tensor> {
+        let mut ys = xs.broadcast_matmul(&self.weight.weight.t()?)?;
+        if let Some((a, b)) = self.weight.adapter(task)? {
+            let delta = xs.broadcast_matmul(&a.t()?)?.broadcast_matmul(&b.t()?)?;
+            ys = (ys + (delta * self.weight.scaling)?)?;
+        }
+        ys.broadcast_add(&self.bias)
+    }
+}
+
+/// Rotary position embeddings, rotating the two halves of each head.
+struct RotaryEmbedding {
+    inv_freq: Vec<f32>,
+}
+
+impl RotaryEmbedding {
+    fn new(config: &Config) -> Self {
+        let dim = config.head_size();
+        let inv_freq = (0..dim)
+            .step_by(2)
+            .map(|i| 1. / config.rotary_emb_base.powf(i as f64 / dim as f64) as f32)
+            .collect();
+        Self { inv_freq }
+    }
+
+    /// Cosine and sine of the rotation of each position, of shape `(seq_len, head_size / 2)`.
+    fn cos_sin(&self, seq_len: usize, device: &Device) -> Result<(Tensor, Tensor)> {
+        let inv_freq = Tensor::new(self.inv_freq.as_slice(), device)?;
+        let positions = Tensor::arange(0u32, seq_len as u32, device)?.to_dtype(DType::F32)?;
+        let freqs = positions
+            .unsqueeze(1)?
+            .broadcast_mul(&inv_freq.unsqueeze(0)?)?;
+        Ok((freqs.cos()?, freqs.sin()?))
+    }
+}
+
+struct JinaV3Attention {
+    wqkv: LoraLinear,
+    out_proj: LoraLinear,
+    num_heads: usize,
+    head_size: usize,
+}
+
+impl JinaV3Attention {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let hidden_size = config.hidden_size;
+        Ok(Self {
+            wqkv: LoraLinear::load(vb.pp("Wqkv"), hidden_size, 3 * hidden_size, config)?,
+            out_proj: LoraLinear::load(vb.pp("out_proj"), hidden_size, hidden_size, config)?,
+            num_heads: config.num_attention_heads,
+            head_size: config.head_size(),
+        })
+    }
+
+    fn forward(
+        &self,
+        hidden_states: &Tensor,
+        attention_mask: &Tensor,
+        (cos, sin): (&Tensor, &Tensor),
+        task: Option<usize>,
+    ) -> Result<Tensor> {
+        let (batch_size, seq_len, hidden_size) = hidden_states.dims3()?;
+
+        // Queries, keys and values of shape `(batch, heads, seq_len, head_size)`
+        let qkv = self
+            .wqkv
+            .forward(hidden_states, task)?
+            .reshape((batch_size, seq_len, 3, self.num_heads, self.head_size))?
+            .permute((2, 0, 3, 1, 4))?;
+        let (cos, sin) = (cos.to_dtype(qkv.dtype())?, sin.to_dtype(qkv.dtype())?);
+        let q = rope(&qkv.get(0)?.contiguous()?, &cos, &sin)?;
+        let k = rope(&qkv.get(1)?.contiguous()?, &cos, &sin)?;
+        let v = qkv.get(2)?.contiguous()?;
+
+        let scores =
+            (q.matmul(&k.t()?)? / (self.head_size as f64).sqrt())?.broadcast_add(attention_mask)?;
+        let probs = softmax_last_dim(&scores)?;
+
+        let context =
+            probs
+                .matmul(&v)?
+                .transpose(1, 2)?
+                .reshape((batch_size, seq_len, hidden_size))?;
+        self.out_proj.forward(&context, task)
+    }
+}
+
+struct JinaV3Block {
+    mixer: JinaV3Attention,
+    norm1: LayerNorm,
+    fc1: LoraLinear,
+    activation: Activation,
+    fc2: LoraLinear,
+    norm2: LayerNorm,
+}
+
+impl JinaV3Block {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let (hidden_size, eps) = (config.hidden_size, config.layer_norm_eps);
+        Ok(Self {
+            mixer: JinaV3Attention::load(vb.pp("mixer"), config)?,
+            norm1: layer_norm(hidden_size, eps, vb.pp("norm1"))?,
+            fc1: LoraLinear::load(
+                vb.pp("mlp.fc1"),
+                hidden_size,
+                config.intermediate_size,
+                config,
+            )?,
+            activation: config.hidden_act,
+            fc2: LoraLinear::load(
+                vb.pp("mlp.fc2"),
+                config.intermediate_size,
+                hidden_size,
+                config,
+            )?,
+            norm2: layer_norm(hidden_size, eps, vb.pp("norm2"))?,
+        })
+    }
+
+    fn forward(
+        &self,
+        hidden_states: &Tensor,
+        attention_mask: &Tensor,
+        cos_sin: (&Tensor, &Tensor),
+        task: Option<usize>,
+    ) -> Result<Tensor> {
+        let attention = self
+            .mixer
+            .forward(hidden_states, attention_mask, cos_sin, task)?;
+        let hidden_states = self.norm1.forward(&(attention + hidden_states)?)?;
+
+        let mlp = self
+            .activation
+            .forward(&self.fc1.forward(&hidden_states, task)?)?;
+        let mlp = self.fc2.forward(&mlp, task)?;
+        self.norm2.forward(&(mlp + hidden_states)?)
+    }
+}
+
+pub struct JinaV3Model {
+    word_embeddings: LoraEmbedding,
+    token_type_embeddings: Option<LoraEmbedding>,
+    emb_ln: LayerNorm,
+    layers: Vec<JinaV3Block>,
+    rotary: RotaryEmbedding,
+    /// Names of the tasks with an adapter
+    pub tasks: Vec<String>,
+    pub pad_token_id: u32,
+    pub device: Device,
+}
+
+impl JinaV3Model {
+    pub fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        if config.position_embedding_type != "rotary" {
+            bail!(
+                "Position embeddings of type `{}` are not supported",
+                config.position_embedding_type
+            );
+        }
+
+        let token_type_embeddings = if config.type_vocab_size > 0 {
+            Some(LoraEmbedding::load(
+                vb.pp("embeddings.token_type_embeddings"),
+                config.type_vocab_size,
+                config.hidden_size,
+                config,
+            )?)
+        } else {
+            None
+        };
+        let layers = (0..config.num_hidden_layers)
+            .map(|i| JinaV3Block::load(vb.pp(format!("encoder.layers.{i}")), config))
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            word_embeddings: LoraEmbedding::load(
+                vb.pp("embeddings.word_embeddings"),
+                config.vocab_size,
+                config.hidden_size,
+                config,
+            )?,
+            token_type_embeddings,
+            emb_ln: layer_norm(config.hidden_size, config.layer_norm_eps, vb.pp("emb_ln"))?,
+            layers,
+            rotary: RotaryEmbedding::new(config),
+            tasks: config.lora_adaptations.clone(),
+            pad_token_id: config.pad_token_id,
+            device: vb.device().clone(),
+        })
+    }
+
+    /// Embed each token of `input_ids` with the adapter of a task, by index in
+    /// [`Self::tasks`], with padding tokens masked out of the attention by `attention_mask`
+    /// (1 for tokens to attend to, 0 for padding).
+    pub fn forward(
+        &self,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        attention_mask: &Tensor,
+        task: Option<usize>,
+    ) -> Result<Tensor> {
+        let mut hidden_states = self.word_embeddings.forward(input_ids, task)?;
+        if let Some(token_type_embeddings) = &self.token_type_embeddings {
+            hidden_states =
+                (hidden_states + token_type_embeddings.forward(token_type_ids, task)?)?;
+        }
+        let mut hidden_states = self.emb_ln.forward(&hidden_states)?;
+        let dtype = hidden_states.dtype();
+
+        // Masked keys get the lowest possible score, of shape `(batch, 1, 1, seq_len)`
+        let attention_mask = ((1. - attention_mask.to_dtype(DType::F32)?)? * f32::MIN as f64)?
+            .to_dtype(dtype)?
+            .unsqueeze(1)?
+            .unsqueeze(1)?;
+        let (cos, sin) = self
+            .rotary
+            .cos_sin(input_ids.dim(D::Minus1)?, &self.device)?;
+
+        for layer in &self.layers {
+            hidden_states = layer.forward(&hidden_states, &attention_mask, (&cos, &sin), task)?;
+        }
+        Ok(hidden_states)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::test_utils::create_tiny_jina_v3_repo;
+    use crate::{Result, SentenceTransformer};
+    use candle_core::{Device, IndexOp, Tensor};
+    use std::collections::HashMap;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn max_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+        Ok((a - b)?.abs()?.max_all()?.to_scalar::<f32>()?)
+    }
+
+    #[test]
+    fn test_jina_v3() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_jina_v3_repo(dir.path())?;
+
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+        assert_eq!(model.tasks().len(), 5);
+        assert!(model.with_task("summarization").is_err());
+
+        let sentences = vec!["The cat sits outside", "I love pasta"];
+        let base = model.encode_batch(sentences.clone(), true)?;
+        let query = model.with_task("retrieval.query")?;
+        let embeddings = query.encode_batch(sentences.clone(), true)?;
+        assert!(max_diff(&base, &embeddings)? > 1e-3);
+
+        // Running with an adapter is the same as merging it into the weights: `lora_alpha /
+        // lora_rank * lora_B @ lora_A` for linear layers and `lora_A @ lora_B` for embeddings
+        let weights_path = dir.path().join("model.safetensors");
+        let tensors = candle_core::safetensors::load(&weights_path, &Device::Cpu)?;
+        let mut merged = HashMap::new();
+        for (name, tensor) in &tensors {
+            let Some(layer) = name.strip_suffix(".parametrizations.weight.original") else {
+                if !name.contains(".parametrizations.") {
+                    merged.insert(name.clone(), tensor.clone());
+                }
+                continue;
+            };
+            let lora = |matrix: &str| -> Result<Tensor> {
+                Ok(tensors[&format!("{layer}.parametrizations.weight.0.{matrix}")].i(0)?)
+            };
+            let delta = match layer.ends_with("embeddings") {
+                true => lora("lora_A")?.matmul(&lora("lora_B")?)?,
+                false => lora("lora_B")?.matmul(&lora("lora_A")?)?,
+            };
+            merged.insert(format!("{layer}.weight"), (tensor + (delta * 0.5)?)?);
+        }
+        let merged_dir = tempdir()?;
+        for file in ["config.json", "tokenizer.json"] {
+            fs::copy(dir.path().join(file), merged_dir.path().join(file))?;
+        }
+        fs::create_dir_all(merged_dir.path().join("1_Pooling"))?;
+        fs::copy(
+            dir.path().join("1_Pooling/config.json"),
+            merged_dir.path().join("1_Pooling/config.json"),
+        )?;
+        candle_core::safetensors::save(&merged, merged_dir.path().join("model.safetensors"))?;
+
+        let merged_model = SentenceTransformer::builder()
+            .with_model_folder(merged_dir.path())
+            .build()?;
+        let expected = merged_model.encode_batch(sentences.clone(), true)?;
+        assert!(max_diff(&embeddings, &expected)? < 1e-4);
+
+        // Tasks with an instruction prepend it to the inputs
+        let output = model.encode_batch_with_task(&sentences, true, "retrieval.query")?;
+        let instruction = model.task_instruction("retrieval.query").unwrap();
+        let expected = query.encode_batch(
+            sentences
+                .iter()
+                .map(|sentence| format!("{instruction}{sentence}"))
+                .collect(),
+            true,
+        )?;
+        assert!(max_diff(&output.embeddings, &expected)? < 1e-6);
+        assert_eq!(model.task_instruction("text-matching"), None);
+
+        Ok(())
+    }
+}
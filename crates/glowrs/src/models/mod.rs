@@ -0,0 +1,10 @@
+//! Model architectures that are not available in `candle-transformers`
+
+pub mod jina_v3;
+pub mod mpnet;
+pub mod nomic_bert;
+#[cfg(feature = "onnx")]
+pub mod onnx;
+pub mod quantized_bert;
+pub mod static_embedding;
+pub mod t5;
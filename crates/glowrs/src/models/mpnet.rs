@@ -0,0 +1,365 @@
+//! MPNet
+//!
+//! Port of `MPNetModel` from `transformers`, the architecture of e.g.
+//! `sentence-transformers/all-mpnet-base-v2`. MPNet is a BERT-like encoder without segment
+//! embeddings, with RoBERTa-style position ids and a bucketed relative position bias that is
+//! shared by all layers.
+
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::{
+    embedding, layer_norm, linear, ops::softmax_last_dim, Activation, Embedding, LayerNorm, Linear,
+    VarBuilder,
+};
+use serde::Deserialize;
+
+/// Maximum relative distance with its own position bias buckets.
+const MAX_DISTANCE: usize = 128;
+
+fn default_relative_attention_num_buckets() -> usize {
+    32
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub intermediate_size: usize,
+    pub hidden_act: Activation,
+    pub max_position_embeddings: usize,
+    pub layer_norm_eps: f64,
+    #[serde(default = "default_relative_attention_num_buckets")]
+    pub relative_attention_num_buckets: usize,
+    pub pad_token_id: u32,
+}
+
+struct MPNetEmbeddings {
+    word_embeddings: Embedding,
+    position_embeddings: Embedding,
+    layer_norm: LayerNorm,
+    padding_idx: u32,
+}
+
+impl MPNetEmbeddings {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        Ok(Self {
+            word_embeddings: embedding(
+                config.vocab_size,
+                config.hidden_size,
+                vb.pp("word_embeddings"),
+            )?,
+            position_embeddings: embedding(
+                config.max_position_embeddings,
+                config.hidden_size,
+                vb.pp("position_embeddings"),
+            )?,
+            layer_norm: layer_norm(
+                config.hidden_size,
+                config.layer_norm_eps,
+                vb.pp("LayerNorm"),
+            )?,
+            padding_idx: config.pad_token_id,
+        })
+    }
+
+    fn forward(&self, input_ids: &Tensor) -> Result<Tensor> {
+        // Positions count from after the padding index, and padding tokens keep the padding
+        // index as their position
+        let mask = input_ids.ne(self.padding_idx)?.to_dtype(DType::F32)?;
+        let position_ids =
+            ((mask.cumsum(1)? * &mask)? + self.padding_idx as f64)?.to_dtype(DType::U32)?;
+
+        let embeddings = (self.word_embeddings.forward(input_ids)?
+            + self.position_embeddings.forward(&position_ids)?)?;
+        self.layer_norm.forward(&embeddings)
+    }
+}
+
+struct MPNetSelfAttention {
+    q: Linear,
+    k: Linear,
+    v: Linear,
+    o: Linear,
+    num_attention_heads: usize,
+    head_size: usize,
+}
+
+impl MPNetSelfAttention {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let hidden_size = config.hidden_size;
+        Ok(Self {
+            q: linear(hidden_size, hidden_size, vb.pp("q"))?,
+            k: linear(hidden_size, hidden_size, vb.pp("k"))?,
+            v: linear(hidden_size, hidden_size, vb.pp("v"))?,
+            o: linear(hidden_size, hidden_size, vb.pp("o"))?,
+            num_attention_heads: config.num_attention_heads,
+            head_size: hidden_size / config.num_attention_heads,
+        })
+    }
+
+    /// Split the hidden states into heads, of shape `(batch, heads, seq_len, head_size)`.
+    fn split_heads(&self, xs: &Tensor) -> Result<Tensor> {
+        let (batch_size, seq_len, _) = xs.dims3()?;
+        xs.reshape((
+            batch_size,
+            seq_len,
+            self.num_attention_heads,
+            self.head_size,
+        ))?
+        .transpose(1, 2)?
+        .contiguous()
+    }
+
+    fn forward(
+        &self,
+        hidden_states: &Tensor,
+        attention_mask: &Tensor,
+        position_bias: &Tensor,
+    ) -> Result<Tensor> {
+        let (batch_size, seq_len, hidden_size) = hidden_states.dims3()?;
+        let q = self.split_heads(&self.q.forward(hidden_states)?)?;
+        let k = self.split_heads(&self.k.forward(hidden_states)?)?;
+        let v = self.split_heads(&self.v.forward(hidden_states)?)?;
+
+        let scores = (q.matmul(&k.t()?)? / (self.head_size as f64).sqrt())?
+            .broadcast_add(position_bias)?
+            .broadcast_add(attention_mask)?;
+        let probs = softmax_last_dim(&scores)?;
+
+        let context =
+            probs
+                .matmul(&v)?
+                .transpose(1, 2)?
+                .reshape((batch_size, seq_len, hidden_size))?;
+        self.o.forward(&context)
+    }
+}
+
+struct MPNetLayer {
+    attention: MPNetSelfAttention,
+    attention_layer_norm: LayerNorm,
+    intermediate: Linear,
+    activation: Activation,
+    output: Linear,
+    output_layer_norm: LayerNorm,
+}
+
+impl MPNetLayer {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        Ok(Self {
+            attention: MPNetSelfAttention::load(vb.pp("attention.attn"), config)?,
+            attention_layer_norm: layer_norm(
+                config.hidden_size,
+                config.layer_norm_eps,
+                vb.pp("attention.LayerNorm"),
+            )?,
+            intermediate: linear(
+                config.hidden_size,
+                config.intermediate_size,
+                vb.pp("intermediate.dense"),
+            )?,
+            activation: config.hidden_act,
+            output: linear(
+                config.intermediate_size,
+                config.hidden_size,
+                vb.pp("output.dense"),
+            )?,
+            output_layer_norm: layer_norm(
+                config.hidden_size,
+                config.layer_norm_eps,
+                vb.pp("output.LayerNorm"),
+            )?,
+        })
+    }
+
+    fn forward(
+        &self,
+        hidden_states: &Tensor,
+        attention_mask: &Tensor,
+        position_bias: &Tensor,
+    ) -> Result<Tensor> {
+        let attention = self
+            .attention
+            .forward(hidden_states, attention_mask, position_bias)?;
+        let attention = self
+            .attention_layer_norm
+            .forward(&(attention + hidden_states)?)?;
+
+        let intermediate = self
+            .activation
+            .forward(&self.intermediate.forward(&attention)?)?;
+        self.output_layer_norm
+            .forward(&(self.output.forward(&intermediate)? + attention)?)
+    }
+}
+
+/// The bucket of each relative position `key - query`, like in T5: small distances get a
+/// bucket of their own, larger distances share logarithmically sized buckets up to
+/// `max_distance`. Half of the buckets are for keys after the query.
+pub(crate) fn relative_position_bucket(
+    relative_position: i64,
+    num_buckets: usize,
+    max_distance: usize,
+) -> u32 {
+    let num_buckets = num_buckets / 2;
+    let max_exact = num_buckets / 2;
+
+    let n = -relative_position;
+    let offset = if n < 0 { num_buckets } else { 0 };
+    let n = n.unsigned_abs() as usize;
+
+    let bucket = if n < max_exact {
+        n
+    } else {
+        let scale = (n as f32 / max_exact as f32).ln()
+            / (max_distance as f32 / max_exact as f32).ln()
+            * (num_buckets - max_exact) as f32;
+        (max_exact + scale as usize).min(num_buckets - 1)
+    };
+    (offset + bucket) as u32
+}
+
+pub struct MPNetModel {
+    embeddings: MPNetEmbeddings,
+    layers: Vec<MPNetLayer>,
+    relative_attention_bias: Embedding,
+    relative_attention_num_buckets: usize,
+    pub pad_token_id: u32,
+    pub device: Device,
+}
+
+impl MPNetModel {
+    pub fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        // Checkpoints of the base model are saved with or without the `mpnet.` prefix
+        let vb = if vb.contains_tensor("embeddings.word_embeddings.weight") {
+            vb
+        } else {
+            vb.pp("mpnet")
+        };
+
+        let layers = (0..config.num_hidden_layers)
+            .map(|i| MPNetLayer::load(vb.pp(format!("encoder.layer.{i}")), config))
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            embeddings: MPNetEmbeddings::load(vb.pp("embeddings"), config)?,
+            layers,
+            relative_attention_bias: embedding(
+                config.relative_attention_num_buckets,
+                config.num_attention_heads,
+                vb.pp("encoder.relative_attention_bias"),
+            )?,
+            relative_attention_num_buckets: config.relative_attention_num_buckets,
+            pad_token_id: config.pad_token_id,
+            device: vb.device().clone(),
+        })
+    }
+
+    /// Position bias of every query and key, of shape `(1, heads, seq_len, seq_len)`.
+    fn position_bias(&self, seq_len: usize) -> Result<Tensor> {
+        let buckets: Vec<u32> = (0..seq_len as i64)
+            .flat_map(|query| {
+                (0..seq_len as i64).map(move |key| {
+                    relative_position_bucket(
+                        key - query,
+                        self.relative_attention_num_buckets,
+                        MAX_DISTANCE,
+                    )
+                })
+            })
+            .collect();
+        let buckets = Tensor::from_vec(buckets, (seq_len, seq_len), &self.device)?;
+
+        self.relative_attention_bias
+            .forward(&buckets)?
+            .permute((2, 0, 1))?
+            .unsqueeze(0)
+    }
+
+    /// Embed each token of `input_ids`, with padding tokens masked out of the attention by
+    /// `attention_mask` (1 for tokens to attend to, 0 for padding).
+    pub fn forward(&self, input_ids: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+        let mut hidden_states = self.embeddings.forward(input_ids)?;
+        let dtype = hidden_states.dtype();
+
+        // Masked keys get the lowest possible score, of shape `(batch, 1, 1, seq_len)`
+        let attention_mask = ((1. - attention_mask.to_dtype(DType::F32)?)? * f32::MIN as f64)?
+            .to_dtype(dtype)?
+            .unsqueeze(1)?
+            .unsqueeze(1)?;
+        let position_bias = self.position_bias(input_ids.dim(D::Minus1)?)?;
+
+        for layer in &self.layers {
+            hidden_states = layer.forward(&hidden_states, &attention_mask, &position_bias)?;
+        }
+        Ok(hidden_states)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::test_utils::named_weights_var_builder;
+
+    #[test]
+    fn test_relative_position_bucket() {
+        // Same as `MPNetEncoder.relative_position_bucket` in `transformers`
+        let buckets: Vec<u32> = [-200, -128, -20, -8, -1, 0, 1, 7, 8, 20, 127, 200]
+            .into_iter()
+            .map(|position| relative_position_bucket(position, 32, MAX_DISTANCE))
+            .collect();
+        assert_eq!(buckets, [15, 15, 10, 8, 1, 0, 17, 23, 24, 26, 31, 31]);
+    }
+
+    #[test]
+    fn test_reference_output() -> Result<()> {
+        let config = Config {
+            vocab_size: 10,
+            hidden_size: 4,
+            num_hidden_layers: 2,
+            num_attention_heads: 2,
+            intermediate_size: 8,
+            hidden_act: Activation::Gelu,
+            max_position_embeddings: 16,
+            layer_norm_eps: 1e-5,
+            relative_attention_num_buckets: 32,
+            pad_token_id: 1,
+        };
+        let model = MPNetModel::load(named_weights_var_builder(), &config)?;
+
+        let input_ids = Tensor::new(&[[0u32, 5, 6, 7, 2], [0, 8, 2, 1, 1]], &Device::Cpu)?;
+        let attention_mask = input_ids.ne(config.pad_token_id)?;
+        let hidden_states = model.forward(&input_ids, &attention_mask)?;
+
+        // Hidden states for the same weights of a double precision transcription of
+        // `MPNetModel` in `transformers`, including those of the padding tokens
+        let expected = Tensor::new(
+            &[
+                [
+                    [0.205425f32, 0.447011, 0.228371, 2.459984],
+                    [0.201891, 0.446228, 0.225205, 2.462866],
+                    [0.215561, 0.440926, 0.258859, 2.480295],
+                    [0.202500, 0.450328, 0.216035, 2.448138],
+                    [0.217140, 0.437561, 0.270236, 2.491188],
+                ],
+                [
+                    [0.205541, 0.451623, 0.217313, 2.443326],
+                    [0.210889, 0.440561, 0.252896, 2.481876],
+                    [0.216320, 0.446801, 0.244846, 2.460141],
+                    [0.211016, 0.450716, 0.227427, 2.446459],
+                    [0.212758, 0.441831, 0.252322, 2.477484],
+                ],
+            ],
+            &Device::Cpu,
+        )?;
+        let difference = (hidden_states - expected)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(difference < 1e-5, "difference: {difference}");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,366 @@
+//! Nomic BERT
+//!
+//! Port of the `NomicBertModel` of `nomic-ai/nomic-bert-2048`, the architecture of e.g.
+//! `nomic-ai/nomic-embed-text-v1.5`. It is a BERT encoder with rotary position embeddings
+//! instead of learned ones, a SwiGLU feed forward network and no biases in the attention and
+//! feed forward projections.
+
+use candle_core::{bail, DType, Device, Module, Result, Tensor, D};
+use candle_nn::{
+    embedding, layer_norm, linear_b, ops::softmax_last_dim, rotary_emb::rope, Embedding, LayerNorm,
+    Linear, VarBuilder,
+};
+use serde::Deserialize;
+
+fn default_rotary_emb_fraction() -> f64 {
+    1.
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub vocab_size: usize,
+    pub type_vocab_size: usize,
+    pub n_embd: usize,
+    pub n_head: usize,
+    pub n_inner: usize,
+    pub n_layer: usize,
+    pub layer_norm_epsilon: f64,
+    pub activation_function: String,
+    pub rotary_emb_base: f64,
+    #[serde(default = "default_rotary_emb_fraction")]
+    pub rotary_emb_fraction: f64,
+    #[serde(default)]
+    pub rotary_emb_interleaved: bool,
+    #[serde(default)]
+    pub qkv_proj_bias: bool,
+    #[serde(default)]
+    pub mlp_fc1_bias: bool,
+    #[serde(default)]
+    pub mlp_fc2_bias: bool,
+    #[serde(default)]
+    pub prenorm: bool,
+    #[serde(default)]
+    pub pad_token_id: u32,
+}
+
+impl Config {
+    fn head_size(&self) -> usize {
+        self.n_embd / self.n_head
+    }
+
+    /// Number of dimensions of each head that are rotated.
+    fn rotary_dim(&self) -> usize {
+        (self.head_size() as f64 * self.rotary_emb_fraction) as usize
+    }
+}
+
+/// Rotary position embeddings, rotating the two halves of the first dimensions of each head.
+struct RotaryEmbedding {
+    inv_freq: Vec<f32>,
+}
+
+impl RotaryEmbedding {
+    fn new(config: &Config) -> Self {
+        let dim = config.rotary_dim();
+        let inv_freq = (0..dim)
+            .step_by(2)
+            .map(|i| 1. / config.rotary_emb_base.powf(i as f64 / dim as f64) as f32)
+            .collect();
+        Self { inv_freq }
+    }
+
+    /// Cosine and sine of the rotation of each position, of shape `(seq_len, rotary_dim / 2)`.
+    fn cos_sin(&self, seq_len: usize, device: &Device) -> Result<(Tensor, Tensor)> {
+        let inv_freq = Tensor::new(self.inv_freq.as_slice(), device)?;
+        let positions = Tensor::arange(0u32, seq_len as u32, device)?.to_dtype(DType::F32)?;
+        let freqs = positions
+            .unsqueeze(1)?
+            .broadcast_mul(&inv_freq.unsqueeze(0)?)?;
+        Ok((freqs.cos()?, freqs.sin()?))
+    }
+
+    /// Rotate queries or keys, of shape `(batch, heads, seq_len, head_size)`.
+    fn apply(&self, xs: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
+        let rotary_dim = 2 * self.inv_freq.len();
+        let head_size = xs.dim(D::Minus1)?;
+        let (cos, sin) = (cos.to_dtype(xs.dtype())?, sin.to_dtype(xs.dtype())?);
+
+        if rotary_dim == head_size {
+            return rope(&xs.contiguous()?, &cos, &sin);
+        }
+        let rotated = rope(
+            &xs.narrow(D::Minus1, 0, rotary_dim)?.contiguous()?,
+            &cos,
+            &sin,
+        )?;
+        let rest = xs.narrow(D::Minus1, rotary_dim, head_size - rotary_dim)?;
+        Tensor::cat(&[rotated, rest], D::Minus1)
+    }
+}
+
+struct NomicBertAttention {
+    wqkv: Linear,
+    out_proj: Linear,
+    num_heads: usize,
+    head_size: usize,
+}
+
+impl NomicBertAttention {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        Ok(Self {
+            wqkv: linear_b(
+                config.n_embd,
+                3 * config.n_embd,
+                config.qkv_proj_bias,
+                vb.pp("Wqkv"),
+            )?,
+            out_proj: linear_b(
+                config.n_embd,
+                config.n_embd,
+                config.qkv_proj_bias,
+                vb.pp("out_proj"),
+            )?,
+            num_heads: config.n_head,
+            head_size: config.head_size(),
+        })
+    }
+
+    fn forward(
+        &self,
+        hidden_states: &Tensor,
+        attention_mask: &Tensor,
+        rotary: (&RotaryEmbedding, &Tensor, &Tensor),
+    ) -> Result<Tensor> {
+        let (batch_size, seq_len, hidden_size) = hidden_states.dims3()?;
+        let (rotary, cos, sin) = rotary;
+
+        // Queries, keys and values of shape `(batch, heads, seq_len, head_size)`
+        let qkv = self
+            .wqkv
+            .forward(hidden_states)?
+            .reshape((batch_size, seq_len, 3, self.num_heads, self.head_size))?
+            .permute((2, 0, 3, 1, 4))?;
+        let q = rotary.apply(&qkv.get(0)?, cos, sin)?;
+        let k = rotary.apply(&qkv.get(1)?, cos, sin)?;
+        let v = qkv.get(2)?.contiguous()?;
+
+        let scores =
+            (q.matmul(&k.t()?)? / (self.head_size as f64).sqrt())?.broadcast_add(attention_mask)?;
+        let probs = softmax_last_dim(&scores)?;
+
+        let context =
+            probs
+                .matmul(&v)?
+                .transpose(1, 2)?
+                .reshape((batch_size, seq_len, hidden_size))?;
+        self.out_proj.forward(&context)
+    }
+}
+
+/// Feed forward network with a SwiGLU activation.
+struct NomicBertGatedMlp {
+    fc11: Linear,
+    fc12: Linear,
+    fc2: Linear,
+}
+
+impl NomicBertGatedMlp {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        Ok(Self {
+            fc11: linear_b(
+                config.n_embd,
+                config.n_inner,
+                config.mlp_fc1_bias,
+                vb.pp("fc11"),
+            )?,
+            fc12: linear_b(
+                config.n_embd,
+                config.n_inner,
+                config.mlp_fc1_bias,
+                vb.pp("fc12"),
+            )?,
+            fc2: linear_b(
+                config.n_inner,
+                config.n_embd,
+                config.mlp_fc2_bias,
+                vb.pp("fc2"),
+            )?,
+        })
+    }
+}
+
+impl Module for NomicBertGatedMlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let gate = self.fc12.forward(xs)?.silu()?;
+        self.fc2.forward(&(self.fc11.forward(xs)? * gate)?)
+    }
+}
+
+struct NomicBertBlock {
+    attn: NomicBertAttention,
+    norm1: LayerNorm,
+    mlp: NomicBertGatedMlp,
+    norm2: LayerNorm,
+}
+
+impl NomicBertBlock {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        Ok(Self {
+            attn: NomicBertAttention::load(vb.pp("attn"), config)?,
+            norm1: layer_norm(config.n_embd, config.layer_norm_epsilon, vb.pp("norm1"))?,
+            mlp: NomicBertGatedMlp::load(vb.pp("mlp"), config)?,
+            norm2: layer_norm(config.n_embd, config.layer_norm_epsilon, vb.pp("norm2"))?,
+        })
+    }
+
+    fn forward(
+        &self,
+        hidden_states: &Tensor,
+        attention_mask: &Tensor,
+        rotary: (&RotaryEmbedding, &Tensor, &Tensor),
+    ) -> Result<Tensor> {
+        let attention = self.attn.forward(hidden_states, attention_mask, rotary)?;
+        let hidden_states = self.norm1.forward(&(attention + hidden_states)?)?;
+
+        let mlp = self.mlp.forward(&hidden_states)?;
+        self.norm2.forward(&(mlp + hidden_states)?)
+    }
+}
+
+pub struct NomicBertModel {
+    word_embeddings: Embedding,
+    token_type_embeddings: Option<Embedding>,
+    emb_ln: LayerNorm,
+    layers: Vec<NomicBertBlock>,
+    rotary: RotaryEmbedding,
+    pub pad_token_id: u32,
+    pub device: Device,
+}
+
+impl NomicBertModel {
+    pub fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        if config.activation_function != "swiglu" {
+            bail!(
+                "Activation function `{}` is not supported",
+                config.activation_function
+            );
+        }
+        if config.prenorm || config.rotary_emb_interleaved {
+            bail!("Pre-norm blocks and interleaved rotary embeddings are not supported");
+        }
+
+        let token_type_embeddings = if config.type_vocab_size > 0 {
+            Some(embedding(
+                config.type_vocab_size,
+                config.n_embd,
+                vb.pp("embeddings.token_type_embeddings"),
+            )?)
+        } else {
+            None
+        };
+        let layers = (0..config.n_layer)
+            .map(|i| NomicBertBlock::load(vb.pp(format!("encoder.layers.{i}")), config))
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            word_embeddings: embedding(
+                config.vocab_size,
+                config.n_embd,
+                vb.pp("embeddings.word_embeddings"),
+            )?,
+            token_type_embeddings,
+            emb_ln: layer_norm(config.n_embd, config.layer_norm_epsilon, vb.pp("emb_ln"))?,
+            layers,
+            rotary: RotaryEmbedding::new(config),
+            pad_token_id: config.pad_token_id,
+            device: vb.device().clone(),
+        })
+    }
+
+    /// Embed each token of `input_ids`, with padding tokens masked out of the attention by
+    /// `attention_mask` (1 for tokens to attend to, 0 for padding).
+    pub fn forward(
+        &self,
+        input_ids: &Tensor,
+        token_type_ids: &Tensor,
+        attention_mask: &Tensor,
+    ) -> Result<Tensor> {
+        let mut hidden_states = self.word_embeddings.forward(input_ids)?;
+        if let Some(token_type_embeddings) = &self.token_type_embeddings {
+            hidden_states = (hidden_states + token_type_embeddings.forward(token_type_ids)?)?;
+        }
+        let mut hidden_states = self.emb_ln.forward(&hidden_states)?;
+        let dtype = hidden_states.dtype();
+
+        // Masked keys get the lowest possible score, of shape `(batch, 1, 1, seq_len)`
+        let attention_mask = ((1. - attention_mask.to_dtype(DType::F32)?)? * f32::MIN as f64)?
+            .to_dtype(dtype)?
+            .unsqueeze(1)?
+            .unsqueeze(1)?;
+        let (cos, sin) = self
+            .rotary
+            .cos_sin(input_ids.dim(D::Minus1)?, &self.device)?;
+
+        for layer in &self.layers {
+            hidden_states =
+                layer.forward(&hidden_states, &attention_mask, (&self.rotary, &cos, &sin))?;
+        }
+        Ok(hidden_states)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::test_utils::named_weights_var_builder;
+
+    #[test]
+    fn test_reference_output() -> Result<()> {
+        let config = Config {
+            vocab_size: 10,
+            type_vocab_size: 2,
+            n_embd: 16,
+            n_head: 2,
+            n_inner: 8,
+            n_layer: 1,
+            layer_norm_epsilon: 1e-12,
+            activation_function: "swiglu".to_string(),
+            rotary_emb_base: 1000.,
+            rotary_emb_fraction: 1.,
+            rotary_emb_interleaved: false,
+            qkv_proj_bias: false,
+            mlp_fc1_bias: false,
+            mlp_fc2_bias: false,
+            prenorm: false,
+            pad_token_id: 0,
+        };
+        let model = NomicBertModel::load(named_weights_var_builder(), &config)?;
+
+        let input_ids = Tensor::new(&[[2u32, 5, 6, 3], [2, 7, 3, 0]], &Device::Cpu)?;
+        let token_type_ids = input_ids.zeros_like()?;
+        let attention_mask = input_ids.ne(config.pad_token_id)?;
+        let hidden_states = model.forward(&input_ids, &token_type_ids, &attention_mask)?;
+
+        // Hidden states for the same weights of a double precision transcription of
+        // `NomicBertModel` in `nomic-ai/nomic-bert-2048`, summed over all tokens of a sequence
+        let expected: [[f32; 16]; 2] = [
+            [
+                0.41301, -1.13017, -0.93934, -0.64683, -5.93415, -7.61609, -7.35694, -1.10697,
+                0.99407, 0.80316, -0.70411, -1.50231, -0.82044, -5.62469, -7.05216, -7.19141,
+            ],
+            [
+                0.89575, -1.19957, -1.30860, -0.64196, -5.81028, -7.24304, -7.75424, -1.23830,
+                1.02951, 1.38108, -0.58388, -1.89287, -0.82390, -5.45883, -6.50141, -7.46925,
+            ],
+        ];
+        let expected = Tensor::new(&expected, &Device::Cpu)?;
+        let difference = (hidden_states.sum(1)? - expected)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(difference < 1e-4, "difference: {difference}");
+
+        Ok(())
+    }
+}
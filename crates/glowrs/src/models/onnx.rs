@@ -0,0 +1,157 @@
+//! ONNX Runtime backend for repositories that only have ONNX weights (`model.onnx`)
+//!
+//! The exported graph is run by ONNX Runtime on the CPU, and the token embeddings it outputs are
+//! pooled by glowrs like those of the candle models. The ONNX Runtime library is loaded at runtime
+//! from `ORT_DYLIB_PATH`, or from the library search path.
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+
+use candle_core::{DType, Device, Tensor};
+use ort::session::Session;
+use ort::value::Tensor as OrtTensor;
+
+use crate::core::embedder::EmbedderModel;
+use crate::{Error, Result};
+
+/// Inputs of `transformers` exports. Only `input_ids` is required.
+const INPUT_IDS: &str = "input_ids";
+const ATTENTION_MASK: &str = "attention_mask";
+const TOKEN_TYPE_IDS: &str = "token_type_ids";
+
+/// Outputs with the token embeddings, of `transformers` and `sentence-transformers` exports
+const OUTPUTS: [&str; 2] = ["last_hidden_state", "token_embeddings"];
+
+pub struct OnnxModel {
+    /// Runs take `&mut self` in `ort`, so they are queued. ONNX Runtime spreads each run over its
+    /// intra-op threads.
+    session: Mutex<Session>,
+    inputs: Vec<String>,
+    output: String,
+    pad_token_id: u32,
+    pub device: Device,
+}
+
+impl OnnxModel {
+    pub fn load(path: &Path, model_config: &serde_json::Value, device: &Device) -> Result<Self> {
+        // `ort` panics if the ONNX Runtime library can't be loaded
+        let session = catch_unwind(AssertUnwindSafe(|| {
+            Session::builder()?.commit_from_file(path)
+        }))
+        .map_err(|_| {
+            Error::ModelLoad(
+                "ONNX Runtime library could not be loaded. Set `ORT_DYLIB_PATH` to its path.",
+            )
+        })??;
+
+        let inputs: Vec<String> = session
+            .inputs
+            .iter()
+            .map(|input| input.name.clone())
+            .collect();
+        if !inputs.iter().any(|name| name == INPUT_IDS) {
+            return Err(Error::ModelLoad("ONNX model has no `input_ids` input."));
+        }
+        if inputs
+            .iter()
+            .any(|name| ![INPUT_IDS, ATTENTION_MASK, TOKEN_TYPE_IDS].contains(&name.as_str()))
+        {
+            return Err(Error::ModelLoad(
+                "ONNX model has inputs other than `input_ids`, `attention_mask` and `token_type_ids`.",
+            ));
+        }
+        let output = OUTPUTS
+            .into_iter()
+            .find(|name| session.outputs.iter().any(|output| output.name == *name))
+            .ok_or(Error::ModelLoad(
+                "ONNX model has no `last_hidden_state` or `token_embeddings` output.",
+            ))?
+            .to_string();
+
+        Ok(Self {
+            session: Mutex::new(session),
+            inputs,
+            output,
+            pad_token_id: model_config["pad_token_id"].as_u64().unwrap_or(0) as u32,
+            device: device.clone(),
+        })
+    }
+
+    fn forward(&self, token_ids: &Tensor, token_type_ids: Option<&Tensor>) -> Result<Tensor> {
+        let (batch_size, seq_len) = token_ids.dims2()?;
+        let shape = [batch_size, seq_len];
+        let to_vec = |ids: &Tensor| ids.to_dtype(DType::I64)?.flatten_all()?.to_vec1::<i64>();
+
+        let input_ids = to_vec(token_ids)?;
+        // Padding tokens are masked out of the attention, like for the RoBERTa models
+        let attention_mask = input_ids
+            .iter()
+            .map(|&id| i64::from(id != i64::from(self.pad_token_id)))
+            .collect::<Vec<_>>();
+        let token_type_ids = match token_type_ids {
+            Some(token_type_ids) => to_vec(token_type_ids)?,
+            None => vec![0; input_ids.len()],
+        };
+
+        let inputs = self
+            .inputs
+            .iter()
+            .map(|name| {
+                let values = match name.as_str() {
+                    INPUT_IDS => input_ids.clone(),
+                    ATTENTION_MASK => attention_mask.clone(),
+                    _ => token_type_ids.clone(),
+                };
+                Ok((name.as_str(), OrtTensor::from_array((shape, values))?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut session = self.session.lock().unwrap_or_else(PoisonError::into_inner);
+        let outputs = session.run(inputs)?;
+        let (dims, embeddings) = outputs[self.output.as_str()].try_extract_tensor::<f32>()?;
+        let dims = dims.iter().map(|&dim| dim as usize).collect::<Vec<_>>();
+        if dims.len() != 3 {
+            return Err(Error::InferenceError(
+                "ONNX model output is not a batch of token embeddings.",
+            ));
+        }
+
+        Ok(Tensor::from_slice(embeddings, dims, &self.device)?)
+    }
+}
+
+impl EmbedderModel for OnnxModel {
+    #[inline]
+    fn encode(&self, token_ids: &Tensor) -> Result<Tensor> {
+        self.forward(token_ids, None)
+    }
+
+    #[inline]
+    fn encode_with_type_ids(&self, token_ids: &Tensor, token_type_ids: &Tensor) -> Result<Tensor> {
+        self.forward(token_ids, Some(token_type_ids))
+    }
+
+    fn get_device(&self) -> &Device {
+        &self.device
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_load_invalid() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("model.onnx");
+        std::fs::write(&path, b"not an ONNX model")?;
+
+        // Either the library isn't available or the model is rejected, but loading doesn't panic
+        let model = OnnxModel::load(&path, &serde_json::json!({}), &Device::Cpu);
+        assert!(matches!(model, Err(Error::ModelLoad(_) | Error::Onnx(_))));
+
+        Ok(())
+    }
+}
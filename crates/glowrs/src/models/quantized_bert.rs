@@ -0,0 +1,271 @@
+//! Quantized BERT
+//!
+//! BERT on quantized (GGUF) weights, whose linear layers run on the quantized matrix
+//! multiplication kernels of candle. The weights stay quantized in memory; only the embeddings
+//! and layer norms are dequantized, and activations are computed in `f32`. The tensors are
+//! named like in the SafeTensors weights of `BertModel`.
+
+use candle_core::{Device, Module, Result, Tensor, D};
+use candle_nn::{ops::softmax_last_dim, LayerNorm};
+use candle_transformers::models::bert::{Config, HiddenAct};
+use candle_transformers::quantized_nn::{layer_norm, linear, Embedding, Linear};
+use candle_transformers::quantized_var_builder::VarBuilder;
+
+struct BertEmbeddings {
+    word_embeddings: Embedding,
+    position_embeddings: Embedding,
+    token_type_embeddings: Embedding,
+    layer_norm: LayerNorm,
+}
+
+impl BertEmbeddings {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        Ok(Self {
+            word_embeddings: Embedding::new(
+                config.vocab_size,
+                config.hidden_size,
+                vb.pp("word_embeddings"),
+            )?,
+            position_embeddings: Embedding::new(
+                config.max_position_embeddings,
+                config.hidden_size,
+                vb.pp("position_embeddings"),
+            )?,
+            token_type_embeddings: Embedding::new(
+                config.type_vocab_size,
+                config.hidden_size,
+                vb.pp("token_type_embeddings"),
+            )?,
+            layer_norm: layer_norm(
+                config.hidden_size,
+                config.layer_norm_eps,
+                vb.pp("LayerNorm"),
+            )?,
+        })
+    }
+
+    fn forward(&self, input_ids: &Tensor, token_type_ids: &Tensor) -> Result<Tensor> {
+        let (_, seq_len) = input_ids.dims2()?;
+        let position_ids = Tensor::arange(0u32, seq_len as u32, input_ids.device())?;
+
+        let embeddings = (self.word_embeddings.forward(input_ids)?
+            + self.token_type_embeddings.forward(token_type_ids)?)?
+        .broadcast_add(&self.position_embeddings.forward(&position_ids)?)?;
+        self.layer_norm.forward(&embeddings)
+    }
+}
+
+struct BertSelfAttention {
+    query: Linear,
+    key: Linear,
+    value: Linear,
+    num_attention_heads: usize,
+    attention_head_size: usize,
+}
+
+impl BertSelfAttention {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let hidden_size = config.hidden_size;
+        Ok(Self {
+            query: linear(hidden_size, hidden_size, vb.pp("query"))?,
+            key: linear(hidden_size, hidden_size, vb.pp("key"))?,
+            value: linear(hidden_size, hidden_size, vb.pp("value"))?,
+            num_attention_heads: config.num_attention_heads,
+            attention_head_size: hidden_size / config.num_attention_heads,
+        })
+    }
+
+    /// Split the hidden states into heads, as `(batch, heads, seq_len, head_size)`.
+    fn transpose_for_scores(&self, xs: &Tensor) -> Result<Tensor> {
+        let (batch_size, seq_len, _) = xs.dims3()?;
+        xs.reshape((
+            batch_size,
+            seq_len,
+            self.num_attention_heads,
+            self.attention_head_size,
+        ))?
+        .transpose(1, 2)?
+        .contiguous()
+    }
+
+    fn forward(&self, hidden_states: &Tensor) -> Result<Tensor> {
+        let query = self.transpose_for_scores(&self.query.forward(hidden_states)?)?;
+        let key = self.transpose_for_scores(&self.key.forward(hidden_states)?)?;
+        let value = self.transpose_for_scores(&self.value.forward(hidden_states)?)?;
+
+        let scores = (query.matmul(&key.t()?)? / (self.attention_head_size as f64).sqrt())?;
+        let probs = softmax_last_dim(&scores)?;
+
+        probs
+            .matmul(&value)?
+            .transpose(1, 2)?
+            .contiguous()?
+            .flatten_from(D::Minus2)
+    }
+}
+
+/// A dense layer followed by a residual connection and a layer norm.
+struct BertOutput {
+    dense: Linear,
+    layer_norm: LayerNorm,
+}
+
+impl BertOutput {
+    fn load(vb: VarBuilder, in_dim: usize, config: &Config) -> Result<Self> {
+        Ok(Self {
+            dense: linear(in_dim, config.hidden_size, vb.pp("dense"))?,
+            layer_norm: layer_norm(
+                config.hidden_size,
+                config.layer_norm_eps,
+                vb.pp("LayerNorm"),
+            )?,
+        })
+    }
+
+    fn forward(&self, hidden_states: &Tensor, input_tensor: &Tensor) -> Result<Tensor> {
+        let hidden_states = self.dense.forward(hidden_states)?;
+        self.layer_norm.forward(&(hidden_states + input_tensor)?)
+    }
+}
+
+struct BertLayer {
+    attention: BertSelfAttention,
+    attention_output: BertOutput,
+    intermediate: Linear,
+    activation: HiddenAct,
+    output: BertOutput,
+}
+
+impl BertLayer {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        Ok(Self {
+            attention: BertSelfAttention::load(vb.pp("attention.self"), config)?,
+            attention_output: BertOutput::load(
+                vb.pp("attention.output"),
+                config.hidden_size,
+                config,
+            )?,
+            intermediate: linear(
+                config.hidden_size,
+                config.intermediate_size,
+                vb.pp("intermediate.dense"),
+            )?,
+            activation: config.hidden_act,
+            output: BertOutput::load(vb.pp("output"), config.intermediate_size, config)?,
+        })
+    }
+
+    fn forward(&self, hidden_states: &Tensor) -> Result<Tensor> {
+        let attention = self.attention.forward(hidden_states)?;
+        let attention = self.attention_output.forward(&attention, hidden_states)?;
+
+        let intermediate = self.intermediate.forward(&attention)?;
+        let intermediate = match self.activation {
+            HiddenAct::Gelu => intermediate.gelu_erf()?,
+            HiddenAct::GeluApproximate => intermediate.gelu()?,
+            HiddenAct::Relu => intermediate.relu()?,
+        };
+        self.output.forward(&intermediate, &attention)
+    }
+}
+
+pub struct QuantizedBertModel {
+    embeddings: BertEmbeddings,
+    layers: Vec<BertLayer>,
+    pub device: Device,
+}
+
+impl QuantizedBertModel {
+    pub fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let layers = (0..config.num_hidden_layers)
+            .map(|index| BertLayer::load(vb.pp(format!("encoder.layer.{index}")), config))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            embeddings: BertEmbeddings::load(vb.pp("embeddings"), config)?,
+            layers,
+            device: vb.device().clone(),
+        })
+    }
+
+    pub fn forward(&self, input_ids: &Tensor, token_type_ids: &Tensor) -> Result<Tensor> {
+        let mut hidden_states = self.embeddings.forward(input_ids, token_type_ids)?;
+        for layer in &self.layers {
+            hidden_states = layer.forward(&hidden_states)?;
+        }
+
+        Ok(hidden_states)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::embedder::EmbedderModel;
+    use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+    use candle_core::DType;
+    use candle_nn::VarMap;
+    use candle_transformers::models::bert::BertModel;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_quantized_bert() -> crate::Result<()> {
+        let device = Device::Cpu;
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "vocab_size": 64,
+            "hidden_size": 32,
+            "num_hidden_layers": 2,
+            "num_attention_heads": 2,
+            "intermediate_size": 64,
+            "hidden_act": "gelu",
+            "hidden_dropout_prob": 0.1,
+            "max_position_embeddings": 32,
+            "type_vocab_size": 2,
+            "initializer_range": 0.02,
+            "layer_norm_eps": 1e-12,
+            "pad_token_id": 0
+        }))?;
+
+        let var_map = VarMap::new();
+        let vb = candle_nn::VarBuilder::from_varmap(&var_map, DType::F32, &device);
+        let model = BertModel::load(vb, &config)?;
+
+        // Quantize the matrices to 8 bits, and keep the vectors in full precision
+        let tensors = var_map.data().lock().unwrap().clone();
+        let qtensors = tensors
+            .iter()
+            .map(|(name, var)| {
+                let dtype = match var.rank() {
+                    2 => GgmlDType::Q8_0,
+                    _ => GgmlDType::F32,
+                };
+                Ok((name.as_str(), QTensor::quantize(var.as_tensor(), dtype)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut gguf = Cursor::new(Vec::new());
+        gguf_file::write(
+            &mut gguf,
+            &[],
+            &qtensors
+                .iter()
+                .map(|(name, qtensor)| (*name, qtensor))
+                .collect::<Vec<_>>(),
+        )?;
+        let vb = VarBuilder::from_gguf_buffer(gguf.get_ref(), &device)?;
+        let quantized_model = QuantizedBertModel::load(vb, &config)?;
+
+        let token_ids = Tensor::new(&[[1u32, 5, 9, 2], [1, 7, 2, 0]], &device)?;
+        let expected = model.encode(&token_ids)?;
+        let embeddings = quantized_model.encode(&token_ids)?;
+        assert_eq!(embeddings.dims(), expected.dims());
+
+        let diff = (embeddings - &expected)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        let scale = expected.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert!(diff < 0.05 * scale);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+//! Static embeddings
+//!
+//! model2vec models, such as `minishlab/potion-base-8M`, distill a sentence transformer into a
+//! single embedding per token of its vocabulary. Embedding a text takes no forward pass: the
+//! embeddings of its tokens are looked up and mean pooled. Like in model2vec, texts are
+//! tokenized without special tokens such as `[CLS]` and `[SEP]`.
+
+use candle_core::{Device, Module, Result, Tensor};
+use candle_nn::{Embedding, VarBuilder};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub hidden_dim: usize,
+    /// Number of tokens in the vocabulary, which model2vec configurations leave out. It is set
+    /// from the tokenizer when loading the model.
+    #[serde(skip)]
+    pub vocab_size: usize,
+}
+
+pub struct StaticEmbeddingModel {
+    embeddings: Embedding,
+    pub device: Device,
+}
+
+impl StaticEmbeddingModel {
+    pub fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let embeddings = Embedding::new(
+            vb.get((config.vocab_size, config.hidden_dim), "embeddings")?,
+            config.hidden_dim,
+        );
+
+        Ok(Self {
+            embeddings,
+            device: vb.device().clone(),
+        })
+    }
+
+    pub fn forward(&self, input_ids: &Tensor) -> Result<Tensor> {
+        self.embeddings.forward(input_ids)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::test_utils::create_tiny_model2vec_repo;
+    use crate::core::utils::normalize_l2;
+    use crate::SentenceTransformer;
+    use candle_core::{DType, IndexOp};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_static_embedding() -> crate::Result<()> {
+        let dir = tempdir()?;
+        create_tiny_model2vec_repo(dir.path())?;
+
+        // Mean pooling is the default
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+
+        // Without special tokens: `the` (1996) and `cat` (4937)
+        let tokens = model.tokenize(vec!["The cat"])?;
+        assert_eq!(tokens[0].get_ids(), [1996, 4937]);
+
+        let embeddings = model.encode_batch(vec!["The cat", "A dog sits on the mat"], true)?;
+        let weights =
+            candle_core::safetensors::load(dir.path().join("model.safetensors"), &Device::Cpu)?;
+        let table = &weights["embeddings"];
+        let expected = normalize_l2(
+            &(table.i(1996)? + table.i(4937)?)?
+                .unsqueeze(0)?
+                .to_dtype(DType::F32)?,
+        )?;
+        let diff = (embeddings.i(0..1)? - expected)?
+            .abs()?
+            .max_all()?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-6);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+//! T5 encoder
+//!
+//! The encoder stack of T5, the architecture of e.g. `sentence-transformers/gtr-t5-base` and the
+//! `sentence-t5-*` models. Unlike `T5EncoderModel` of `candle-transformers`, which caches keys and
+//! values for the decoder, it keeps no state between forward passes and takes an attention mask,
+//! so a padded batch is encoded in a single pass.
+
+use candle_core::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::{
+    embedding, linear_no_bias, ops::softmax_last_dim, rms_norm, Activation, Embedding, Linear,
+    RmsNorm, VarBuilder,
+};
+
+pub use candle_transformers::models::t5::Config;
+
+use crate::models::mpnet::relative_position_bucket;
+
+struct T5Attention {
+    q: Linear,
+    k: Linear,
+    v: Linear,
+    o: Linear,
+    num_heads: usize,
+    d_kv: usize,
+}
+
+impl T5Attention {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let inner_dim = config.num_heads * config.d_kv;
+        Ok(Self {
+            q: linear_no_bias(config.d_model, inner_dim, vb.pp("q"))?,
+            k: linear_no_bias(config.d_model, inner_dim, vb.pp("k"))?,
+            v: linear_no_bias(config.d_model, inner_dim, vb.pp("v"))?,
+            o: linear_no_bias(inner_dim, config.d_model, vb.pp("o"))?,
+            num_heads: config.num_heads,
+            d_kv: config.d_kv,
+        })
+    }
+
+    /// Split the hidden states into heads, of shape `(batch, heads, seq_len, d_kv)`.
+    fn split_heads(&self, xs: &Tensor) -> Result<Tensor> {
+        let (batch_size, seq_len, _) = xs.dims3()?;
+        xs.reshape((batch_size, seq_len, self.num_heads, self.d_kv))?
+            .transpose(1, 2)?
+            .contiguous()
+    }
+
+    fn forward(&self, hidden_states: &Tensor, position_bias: &Tensor) -> Result<Tensor> {
+        let (batch_size, seq_len, _) = hidden_states.dims3()?;
+        let q = self.split_heads(&self.q.forward(hidden_states)?)?;
+        let k = self.split_heads(&self.k.forward(hidden_states)?)?;
+        let v = self.split_heads(&self.v.forward(hidden_states)?)?;
+
+        // T5 doesn't scale the scores, and the attention mask is part of the position bias
+        let scores = q.matmul(&k.t()?)?.broadcast_add(position_bias)?;
+        let probs = softmax_last_dim(&scores)?;
+
+        let context = probs.matmul(&v)?.transpose(1, 2)?.reshape((
+            batch_size,
+            seq_len,
+            self.num_heads * self.d_kv,
+        ))?;
+        self.o.forward(&context)
+    }
+}
+
+/// The feed forward layer, gated for the `gated-gelu` models of T5 v1.1.
+struct T5FeedForward {
+    wi: Linear,
+    wi_gate: Option<Linear>,
+    wo: Linear,
+    activation: Activation,
+}
+
+impl T5FeedForward {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let (wi, wi_gate) = match config.feed_forward_proj.gated {
+            true => (
+                linear_no_bias(config.d_model, config.d_ff, vb.pp("wi_0"))?,
+                Some(linear_no_bias(config.d_model, config.d_ff, vb.pp("wi_1"))?),
+            ),
+            false => (
+                linear_no_bias(config.d_model, config.d_ff, vb.pp("wi"))?,
+                None,
+            ),
+        };
+        Ok(Self {
+            wi,
+            wi_gate,
+            wo: linear_no_bias(config.d_ff, config.d_model, vb.pp("wo"))?,
+            activation: config.feed_forward_proj.activation,
+        })
+    }
+}
+
+impl Module for T5FeedForward {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let hidden_states = self.activation.forward(&self.wi.forward(xs)?)?;
+        let hidden_states = match &self.wi_gate {
+            Some(wi_gate) => (hidden_states * wi_gate.forward(xs)?)?,
+            None => hidden_states,
+        };
+        self.wo.forward(&hidden_states)
+    }
+}
+
+struct T5Layer {
+    attention: T5Attention,
+    attention_layer_norm: RmsNorm,
+    feed_forward: T5FeedForward,
+    feed_forward_layer_norm: RmsNorm,
+}
+
+impl T5Layer {
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        Ok(Self {
+            attention: T5Attention::load(vb.pp("0.SelfAttention"), config)?,
+            attention_layer_norm: rms_norm(
+                config.d_model,
+                config.layer_norm_epsilon,
+                vb.pp("0.layer_norm"),
+            )?,
+            feed_forward: T5FeedForward::load(vb.pp("1.DenseReluDense"), config)?,
+            feed_forward_layer_norm: rms_norm(
+                config.d_model,
+                config.layer_norm_epsilon,
+                vb.pp("1.layer_norm"),
+            )?,
+        })
+    }
+
+    fn forward(&self, hidden_states: &Tensor, position_bias: &Tensor) -> Result<Tensor> {
+        // T5 normalizes the inputs of each sublayer rather than its outputs
+        let attention = self.attention.forward(
+            &self.attention_layer_norm.forward(hidden_states)?,
+            position_bias,
+        )?;
+        let hidden_states = (hidden_states + attention)?;
+
+        let feed_forward = self
+            .feed_forward
+            .forward(&self.feed_forward_layer_norm.forward(&hidden_states)?)?;
+        hidden_states + feed_forward
+    }
+}
+
+pub struct T5EncoderModel {
+    shared: Embedding,
+    layers: Vec<T5Layer>,
+    relative_attention_bias: Embedding,
+    relative_attention_num_buckets: usize,
+    relative_attention_max_distance: usize,
+    final_layer_norm: RmsNorm,
+    pub pad_token_id: u32,
+    pub device: Device,
+}
+
+impl T5EncoderModel {
+    pub fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        // Encoder-only checkpoints may save the shared embeddings with the encoder
+        let shared = match vb.contains_tensor("shared.weight") {
+            true => vb.pp("shared"),
+            false => vb.pp("encoder.embed_tokens"),
+        };
+        let vb = vb.pp("encoder");
+
+        let layers = (0..config.num_layers)
+            .map(|i| T5Layer::load(vb.pp(format!("block.{i}.layer")), config))
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            shared: embedding(config.vocab_size, config.d_model, shared)?,
+            layers,
+            // Only the first layer has the relative attention bias, which all layers share
+            relative_attention_bias: embedding(
+                config.relative_attention_num_buckets,
+                config.num_heads,
+                vb.pp("block.0.layer.0.SelfAttention.relative_attention_bias"),
+            )?,
+            relative_attention_num_buckets: config.relative_attention_num_buckets,
+            relative_attention_max_distance: config.relative_attention_max_distance,
+            final_layer_norm: rms_norm(
+                config.d_model,
+                config.layer_norm_epsilon,
+                vb.pp("final_layer_norm"),
+            )?,
+            pad_token_id: config.pad_token_id as u32,
+            device: vb.device().clone(),
+        })
+    }
+
+    /// Position bias of every query and key, of shape `(1, heads, seq_len, seq_len)`.
+    fn position_bias(&self, seq_len: usize) -> Result<Tensor> {
+        let buckets: Vec<u32> = (0..seq_len as i64)
+            .flat_map(|query| {
+                (0..seq_len as i64).map(move |key| {
+                    relative_position_bucket(
+                        key - query,
+                        self.relative_attention_num_buckets,
+                        self.relative_attention_max_distance,
+                    )
+                })
+            })
+            .collect();
+        let buckets = Tensor::from_vec(buckets, (seq_len, seq_len), &self.device)?;
+
+        self.relative_attention_bias
+            .forward(&buckets)?
+            .permute((2, 0, 1))?
+            .unsqueeze(0)
+    }
+
+    /// Embed each token of `input_ids`, with padding tokens masked out of the attention by
+    /// `attention_mask` (1 for tokens to attend to, 0 for padding).
+    pub fn forward(&self, input_ids: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+        let mut hidden_states = self.shared.forward(input_ids)?;
+        let dtype = hidden_states.dtype();
+
+        // Masked keys get the lowest possible score, of shape `(batch, 1, 1, seq_len)`, which is
+        // added to the position bias of all layers, of shape `(batch, heads, seq_len, seq_len)`
+        let attention_mask = ((1. - attention_mask.to_dtype(DType::F32)?)? * f32::MIN as f64)?
+            .to_dtype(dtype)?
+            .unsqueeze(1)?
+            .unsqueeze(1)?;
+        let position_bias = self
+            .position_bias(input_ids.dim(D::Minus1)?)?
+            .broadcast_add(&attention_mask)?;
+
+        for layer in &self.layers {
+            hidden_states = layer.forward(&hidden_states, &position_bias)?;
+        }
+        self.final_layer_norm.forward(&hidden_states)
+    }
+}
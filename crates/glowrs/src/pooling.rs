@@ -1,4 +1,8 @@
-use serde::Deserialize;
+use candle_core::Tensor;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{Error, Result};
 
 #[cfg(feature = "clap")]
 use clap::ValueEnum;
@@ -7,22 +11,96 @@ use clap::ValueEnum;
 ///
 /// Source: `text-embeddings-inference`: [`backends/candle/src/lib.rs`](https://github.com/huggingface/text-embeddings-inference/blob/7e55c61c2a39612ade5db9b929ffc883913ae0f3/backends/candle/src/lib.rs)
 #[cfg_attr(feature = "clap", derive(ValueEnum))]
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum PoolingStrategy {
     /// Select the CLS token as embedding
     Cls,
+    /// Select the CLS token and pass it through the trained pooler of the model (a dense layer
+    /// with `tanh`), like `pooler_output` in `transformers`. Only available for checkpoints
+    /// with a pooler, such as most BERT models.
+    Pooler,
     /// Apply Mean pooling to the core embeddings
     Mean,
+    /// Select the last non-padding token as embedding, for decoder models with causal
+    /// attention where only the last token has seen the whole input
+    LastToken,
     /// Apply SPLADE (Sparse Lexical and Expansion) to the core embeddings.
     /// This option is only available if the loaded core is a `ForMaskedLM` Transformer
     /// core.
     Splade,
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize)]
+/// A pooling function given by the user, see
+/// [`SentenceTransformerBuilder::with_custom_pooling`](crate::core::sentence_transformer::SentenceTransformerBuilder::with_custom_pooling).
+///
+/// It is called with the token embeddings of a batch, of shape `(batch, seq_len, hidden_size)`,
+/// and its attention mask of shape `(batch, seq_len)` in the same data type, which is 1 for
+/// tokens and 0 for padding. It returns one embedding per input, of shape `(batch, dim)`.
+pub type CustomPooling = Arc<dyn Fn(&Tensor, &Tensor) -> Result<Tensor> + Send + Sync>;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct PoolConfig {
+    #[serde(default)]
+    pub(crate) word_embedding_dimension: usize,
     pub(crate) pooling_mode_cls_token: bool,
     pub(crate) pooling_mode_mean_tokens: bool,
     pooling_mode_max_tokens: bool,
     pooling_mode_mean_sqrt_len_tokens: bool,
+    #[serde(default)]
+    pub(crate) pooling_mode_lasttoken: bool,
+}
+
+impl PoolConfig {
+    /// Create the `1_Pooling/config.json` contents for a given pooling strategy. The pooler of
+    /// [`PoolingStrategy::Pooler`] is stored as a `Dense` module after CLS pooling.
+    pub(crate) fn new(
+        pooling_strategy: PoolingStrategy,
+        word_embedding_dimension: usize,
+    ) -> Result<Self> {
+        let (cls, mean, last_token) = match pooling_strategy {
+            PoolingStrategy::Cls | PoolingStrategy::Pooler => (true, false, false),
+            PoolingStrategy::Mean => (false, true, false),
+            PoolingStrategy::LastToken => (false, false, true),
+            PoolingStrategy::Splade => {
+                return Err(Error::InvalidArgument(
+                    "SPLADE pooling can't be stored in a pooling configuration",
+                ))
+            }
+        };
+
+        Ok(Self {
+            word_embedding_dimension,
+            pooling_mode_cls_token: cls,
+            pooling_mode_mean_tokens: mean,
+            pooling_mode_max_tokens: false,
+            pooling_mode_mean_sqrt_len_tokens: false,
+            pooling_mode_lasttoken: last_token,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pool_config_roundtrip() -> Result<()> {
+        let config = PoolConfig::new(PoolingStrategy::Mean, 384)?;
+        let parsed: PoolConfig = serde_json::from_str(&serde_json::to_string(&config)?)?;
+
+        assert_eq!(parsed, config);
+        assert!(parsed.pooling_mode_mean_tokens);
+        assert!(!parsed.pooling_mode_cls_token);
+
+        let config = PoolConfig::new(PoolingStrategy::LastToken, 384)?;
+        assert!(config.pooling_mode_lasttoken);
+        let parsed: PoolConfig = serde_json::from_str(&serde_json::to_string(&config)?)?;
+        assert_eq!(parsed, config);
+
+        assert!(PoolConfig::new(PoolingStrategy::Splade, 384).is_err());
+
+        Ok(())
+    }
 }
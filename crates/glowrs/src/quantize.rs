@@ -0,0 +1,296 @@
+//! Embedding quantization
+//!
+//! Quantizes `f32` embeddings into `int8` or packed binary vectors. Instead of using fixed ranges,
+//! the quantizers use per-dimension ranges that are calibrated over a sample corpus, which can be
+//! persisted next to the model and reused at inference time.
+
+use candle_core::{DType, Tensor};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::core::utils::encode_in_batches;
+use crate::{Error, Result, SentenceTransformer};
+
+/// How the per-dimension ranges are determined from the calibration embeddings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CalibrationMethod {
+    /// Use the minimum and maximum value of each dimension.
+    MinMax,
+    /// Use the `lower` and `upper` percentiles (between 0 and 100) of each dimension, which makes
+    /// the ranges robust against outliers.
+    Percentile { lower: f64, upper: f64 },
+}
+
+/// Per-dimension value ranges used by the quantizers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    pub min: Vec<f32>,
+    pub max: Vec<f32>,
+}
+
+impl Calibration {
+    /// Compute the calibration ranges from a set of embeddings of shape `(n, dim)`.
+    pub fn from_embeddings(embeddings: &Tensor, method: CalibrationMethod) -> Result<Self> {
+        let embeddings = embeddings.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+        let dim = match embeddings.first() {
+            Some(embedding) => embedding.len(),
+            None => return Err(Error::InvalidArgument("No embeddings to calibrate on")),
+        };
+
+        let (lower, upper) = match method {
+            CalibrationMethod::MinMax => (0., 100.),
+            CalibrationMethod::Percentile { lower, upper } => {
+                if !(0. ..=100.).contains(&lower) || !(0. ..=100.).contains(&upper) {
+                    return Err(Error::InvalidArgument(
+                        "Percentiles should be between 0 and 100",
+                    ));
+                }
+                if lower >= upper {
+                    return Err(Error::InvalidArgument(
+                        "Lower percentile should be smaller than the upper percentile",
+                    ));
+                }
+                (lower, upper)
+            }
+        };
+
+        let mut min = Vec::with_capacity(dim);
+        let mut max = Vec::with_capacity(dim);
+        let mut values = Vec::with_capacity(embeddings.len());
+
+        for d in 0..dim {
+            values.clear();
+            values.extend(embeddings.iter().map(|embedding| embedding[d]));
+            values.sort_by(|a, b| a.total_cmp(b));
+
+            min.push(percentile(&values, lower));
+            max.push(percentile(&values, upper));
+        }
+
+        Ok(Self { min, max })
+    }
+
+    /// Number of dimensions of the calibrated embeddings.
+    pub fn dim(&self) -> usize {
+        self.min.len()
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let calibration: Self = serde_json::from_str(&fs::read_to_string(path)?)?;
+
+        if calibration.min.len() != calibration.max.len() {
+            return Err(Error::InvalidArgument(
+                "Calibration ranges have a different number of dimensions",
+            ));
+        }
+
+        Ok(calibration)
+    }
+
+    fn check_dim(&self, embeddings: &[Vec<f32>]) -> Result<()> {
+        if embeddings.iter().any(|e| e.len() != self.dim()) {
+            return Err(Error::InvalidArgument(
+                "Embedding dimension does not match the calibration",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Linearly interpolated percentile `p` of a sorted, non-empty slice.
+fn percentile(sorted: &[f32], p: f64) -> f32 {
+    let rank = p / 100. * (sorted.len() - 1) as f64;
+    let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+    let weight = (rank - lo as f64) as f32;
+
+    sorted[lo] + (sorted[hi] - sorted[lo]) * weight
+}
+
+/// Calibrate the quantization ranges of a model by encoding a sample corpus.
+///
+/// # Arguments
+///
+/// * `model` - The model whose embeddings will be quantized.
+/// * `corpus` - Sentences representative of the data that will be embedded.
+/// * `method` - How to determine the ranges.
+/// * `normalize` - Whether the embeddings will be normalized before quantization.
+/// * `batch_size` - Number of sentences to encode at once.
+pub fn calibrate<S: AsRef<str>>(
+    model: &SentenceTransformer,
+    corpus: &[S],
+    method: CalibrationMethod,
+    normalize: bool,
+    batch_size: usize,
+) -> Result<Calibration> {
+    let span = tracing::span!(tracing::Level::TRACE, "calibrate");
+    let _enter = span.enter();
+
+    let embeddings = encode_in_batches(corpus, batch_size, |sentences| {
+        model.encode_batch(sentences, normalize)
+    })?
+    .ok_or(Error::InvalidArgument("Calibration corpus is empty"))?;
+
+    Calibration::from_embeddings(&embeddings, method)
+}
+
+/// Quantize embeddings of shape `(n, dim)` to `int8`, mapping each calibrated range onto
+/// `[-128, 127]`. Values outside of the range are clamped.
+pub fn quantize_int8(embeddings: &Tensor, calibration: &Calibration) -> Result<Vec<Vec<i8>>> {
+    let embeddings = embeddings.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+    calibration.check_dim(&embeddings)?;
+
+    let quantized = embeddings
+        .iter()
+        .map(|embedding| {
+            embedding
+                .iter()
+                .zip(calibration.min.iter().zip(&calibration.max))
+                .map(|(&x, (&min, &max))| {
+                    let step = ((max - min) / 255.).max(f32::EPSILON);
+                    ((x - min) / step - 128.).round().clamp(-128., 127.) as i8
+                })
+                .collect()
+        })
+        .collect();
+
+    Ok(quantized)
+}
+
+/// Quantize embeddings of shape `(n, dim)` to bits, packed into bytes (most significant bit
+/// first). A bit is set if the value is above the midpoint of the calibrated range of its
+/// dimension, or above zero if no calibration is given.
+pub fn quantize_binary(
+    embeddings: &Tensor,
+    calibration: Option<&Calibration>,
+) -> Result<Vec<Vec<u8>>> {
+    let embeddings = embeddings.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+
+    let thresholds = match calibration {
+        Some(calibration) => {
+            calibration.check_dim(&embeddings)?;
+            calibration
+                .min
+                .iter()
+                .zip(&calibration.max)
+                .map(|(min, max)| (min + max) / 2.)
+                .collect()
+        }
+        None => vec![0.; embeddings.first().map_or(0, |e| e.len())],
+    };
+
+    let quantized = embeddings
+        .iter()
+        .map(|embedding| {
+            embedding
+                .chunks(8)
+                .zip(thresholds.chunks(8))
+                .map(|(values, thresholds)| {
+                    values
+                        .iter()
+                        .zip(thresholds)
+                        .enumerate()
+                        .fold(0u8, |byte, (i, (x, t))| byte | (u8::from(x > t) << (7 - i)))
+                })
+                .collect()
+        })
+        .collect();
+
+    Ok(quantized)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use candle_core::Device;
+    use tempfile::tempdir;
+
+    fn embeddings() -> Result<Tensor> {
+        let values: Vec<f32> = (0..=100).flat_map(|i| [i as f32, -(i as f32)]).collect();
+        Ok(Tensor::from_vec(values, (101, 2), &Device::Cpu)?)
+    }
+
+    #[test]
+    fn test_calibration() -> Result<()> {
+        let embeddings = embeddings()?;
+
+        let calibration = Calibration::from_embeddings(&embeddings, CalibrationMethod::MinMax)?;
+        assert_eq!(calibration.min, vec![0., -100.]);
+        assert_eq!(calibration.max, vec![100., 0.]);
+
+        let method = CalibrationMethod::Percentile {
+            lower: 5.,
+            upper: 95.,
+        };
+        let calibration = Calibration::from_embeddings(&embeddings, method)?;
+        assert_eq!(calibration.min, vec![5., -95.]);
+        assert_eq!(calibration.max, vec![95., -5.]);
+
+        let method = CalibrationMethod::Percentile {
+            lower: 95.,
+            upper: 5.,
+        };
+        assert!(Calibration::from_embeddings(&embeddings, method).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_and_load_calibration() -> Result<()> {
+        let calibration = Calibration::from_embeddings(&embeddings()?, CalibrationMethod::MinMax)?;
+
+        let dir = tempdir()?;
+        let path = dir.path().join("calibration.json");
+        calibration.save(&path)?;
+
+        assert_eq!(Calibration::load(&path)?, calibration);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantize_int8() -> Result<()> {
+        let calibration = Calibration {
+            min: vec![0., -1.],
+            max: vec![255., 1.],
+        };
+        let embeddings = Tensor::new(&[[0f32, -1.], [255., 1.], [1000., -2.]], &Device::Cpu)?;
+
+        let quantized = quantize_int8(&embeddings, &calibration)?;
+        assert_eq!(
+            quantized,
+            vec![vec![-128, -128], vec![127, 127], vec![127, -128]]
+        );
+
+        let embeddings = Tensor::new(&[[0f32, 0., 0.]], &Device::Cpu)?;
+        assert!(quantize_int8(&embeddings, &calibration).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantize_binary() -> Result<()> {
+        let values: Vec<f32> = vec![1., -1., 1., -1., 1., -1., 1., -1., 1., 1.];
+        let embeddings = Tensor::from_vec(values, (1, 10), &Device::Cpu)?;
+
+        let quantized = quantize_binary(&embeddings, None)?;
+        assert_eq!(quantized, vec![vec![0b1010_1010, 0b1100_0000]]);
+
+        // Shifting the range of the first dimension above its value flips its bit
+        let mut calibration = Calibration {
+            min: vec![-1.; 10],
+            max: vec![1.; 10],
+        };
+        calibration.min[0] = 2.;
+        calibration.max[0] = 4.;
+        let quantized = quantize_binary(&embeddings, Some(&calibration))?;
+        assert_eq!(quantized, vec![vec![0b0010_1010, 0b1100_0000]]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,328 @@
+//! Dimensionality reduction of embeddings
+//!
+//! Fits a PCA projection on embeddings of a user corpus, to get smaller vectors (e.g. 64 or 128
+//! dimensions) from a large model. A fitted projection is saved as `pca.safetensors` in the model
+//! folder, and applied automatically at encode time whenever the model is loaded from that folder.
+
+use candle_core::{DType, Device, Tensor, D};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::core::repo::PCA_FILE;
+use crate::core::utils::encode_in_batches;
+use crate::{Error, Result, SentenceTransformer};
+
+const MAX_ITERATIONS: usize = 1000;
+const TOLERANCE: f64 = 1e-10;
+
+/// A PCA projection from the model embedding space onto its principal components.
+#[derive(Debug, Clone)]
+pub struct Pca {
+    /// Mean embedding, of shape `(dim)`
+    mean: Tensor,
+    /// Principal components, of shape `(n_components, dim)`
+    components: Tensor,
+    explained_variance: Vec<f32>,
+}
+
+impl Pca {
+    /// Fit the projection on embeddings of shape `(n, dim)`.
+    pub fn fit(embeddings: &Tensor, n_components: usize) -> Result<Self> {
+        let (n, dim) = embeddings.dims2()?;
+
+        if n_components == 0 || n_components > dim {
+            return Err(Error::InvalidArgument(
+                "Number of components should be between 1 and the embedding dimension",
+            ));
+        }
+        if n < 2 {
+            return Err(Error::InvalidArgument(
+                "At least two embeddings are needed to fit a PCA projection",
+            ));
+        }
+
+        let device = embeddings.device();
+        let embeddings = embeddings.to_dtype(DType::F64)?;
+        let mean = embeddings.mean(0)?;
+        let centered = embeddings.broadcast_sub(&mean)?;
+        let covariance = (centered.t()?.matmul(&centered)? / (n - 1) as f64)?;
+
+        let (components, explained_variance) =
+            top_eigenvectors(&covariance.to_vec2::<f64>()?, n_components);
+
+        let components: Vec<f32> = components.into_iter().flatten().map(|v| v as f32).collect();
+
+        Ok(Self {
+            mean: mean.to_dtype(DType::F32)?,
+            components: Tensor::from_vec(components, (n_components, dim), device)?,
+            explained_variance: explained_variance.into_iter().map(|v| v as f32).collect(),
+        })
+    }
+
+    /// Number of dimensions of the input embeddings.
+    pub fn input_dim(&self) -> usize {
+        self.mean.dim(0).unwrap_or_default()
+    }
+
+    /// Number of dimensions of the projected embeddings.
+    pub fn n_components(&self) -> usize {
+        self.explained_variance.len()
+    }
+
+    /// Variance of the fitted embeddings along each principal component, in descending order.
+    pub fn explained_variance(&self) -> &[f32] {
+        &self.explained_variance
+    }
+
+    /// Project embeddings of shape `(n, dim)` onto the principal components.
+    pub fn transform(&self, embeddings: &Tensor) -> Result<Tensor> {
+        if embeddings.dim(D::Minus1)? != self.input_dim() {
+            return Err(Error::InvalidArgument(
+                "Embedding dimension does not match the PCA projection",
+            ));
+        }
+
+        Ok(embeddings
+            .broadcast_sub(&self.mean)?
+            .matmul(&self.components.t()?)?)
+    }
+
+    /// Move the projection to `device`.
+    pub fn to_device(&self, device: &Device) -> Result<Self> {
+        Ok(Self {
+            mean: self.mean.to_device(device)?,
+            components: self.components.to_device(device)?,
+            explained_variance: self.explained_variance.clone(),
+        })
+    }
+
+    /// Save the projection as `pca.safetensors` in a model folder, so it is applied whenever
+    /// the model is loaded from that folder.
+    pub fn save<P: AsRef<Path>>(&self, model_folder: P) -> Result<()> {
+        let explained_variance = Tensor::new(self.explained_variance.as_slice(), &Device::Cpu)?;
+
+        let tensors = HashMap::from([
+            ("mean".to_string(), self.mean.clone()),
+            ("components".to_string(), self.components.clone()),
+            ("explained_variance".to_string(), explained_variance),
+        ]);
+        candle_core::safetensors::save(&tensors, model_folder.as_ref().join(PCA_FILE))?;
+
+        Ok(())
+    }
+
+    /// Load a projection saved with [`Pca::save`].
+    pub fn load<P: AsRef<Path>>(path: P, device: &Device) -> Result<Self> {
+        let mut tensors = candle_core::safetensors::load(path, device)?;
+        let mut take = |name: &str| {
+            tensors
+                .remove(name)
+                .ok_or(Error::ModelLoad("PCA projection is missing tensors"))
+        };
+
+        let mean = take("mean")?;
+        let components = take("components")?;
+        let explained_variance = take("explained_variance")?.to_vec1::<f32>()?;
+
+        if components.dims() != [explained_variance.len(), mean.dim(0)?] {
+            return Err(Error::ModelLoad("PCA projection has inconsistent shapes"));
+        }
+
+        Ok(Self {
+            mean,
+            components,
+            explained_variance,
+        })
+    }
+}
+
+/// Fit a PCA projection on the embeddings of a corpus.
+///
+/// The projection is fitted on unnormalized embeddings; normalization is applied after the
+/// projection at encode time.
+///
+/// # Arguments
+///
+/// * `model` - The model to reduce the embeddings of.
+/// * `corpus` - Sentences representative of the data that will be embedded.
+/// * `n_components` - Number of dimensions of the reduced embeddings.
+/// * `batch_size` - Number of sentences to encode at once.
+pub fn fit_pca<S: AsRef<str>>(
+    model: &SentenceTransformer,
+    corpus: &[S],
+    n_components: usize,
+    batch_size: usize,
+) -> Result<Pca> {
+    let span = tracing::span!(tracing::Level::TRACE, "fit-pca");
+    let _enter = span.enter();
+
+    let embeddings = encode_in_batches(corpus, batch_size, |sentences| {
+        model.encode_batch_unprojected(sentences)
+    })?
+    .ok_or(Error::InvalidArgument("PCA corpus is empty"))?;
+
+    Pca::fit(&embeddings, n_components)
+}
+
+/// Compute the `k` eigenvectors of a symmetric matrix with the largest eigenvalues, using
+/// subspace iteration. Returns the eigenvectors as rows, along with their eigenvalues, in
+/// descending order of eigenvalue.
+fn top_eigenvectors(matrix: &[Vec<f64>], k: usize) -> (Vec<Vec<f64>>, Vec<f64>) {
+    let dim = matrix.len();
+
+    // Deterministic pseudo-random start, so results are reproducible
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut basis: Vec<Vec<f64>> = (0..k)
+        .map(|_| {
+            (0..dim)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    (state >> 11) as f64 / (1u64 << 53) as f64 - 0.5
+                })
+                .collect()
+        })
+        .collect();
+    orthonormalize(&mut basis);
+
+    let mut eigenvalues = vec![0.; k];
+    for iteration in 0..MAX_ITERATIONS {
+        let mut next: Vec<Vec<f64>> = basis.iter().map(|v| mat_vec(matrix, v)).collect();
+        let norms = orthonormalize(&mut next);
+        basis = next;
+
+        let converged = norms
+            .iter()
+            .zip(&eigenvalues)
+            .all(|(new, old)| (new - old).abs() <= TOLERANCE * new.abs().max(1.));
+        eigenvalues = norms;
+
+        if converged {
+            tracing::trace!("PCA converged after {} iterations", iteration + 1);
+            break;
+        }
+    }
+
+    // Fix the sign of each component, so the largest absolute value is positive
+    for v in basis.iter_mut() {
+        let max = v
+            .iter()
+            .fold(0., |max: f64, &x| if x.abs() > max.abs() { x } else { max });
+        if max < 0. {
+            v.iter_mut().for_each(|x| *x = -*x);
+        }
+    }
+
+    (basis, eigenvalues)
+}
+
+fn mat_vec(matrix: &[Vec<f64>], v: &[f64]) -> Vec<f64> {
+    matrix.iter().map(|row| dot(row, v)).collect()
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Orthonormalize vectors in place with modified Gram-Schmidt, returning the norm of each
+/// vector after removing the previous directions.
+fn orthonormalize(vectors: &mut [Vec<f64>]) -> Vec<f64> {
+    let mut norms = Vec::with_capacity(vectors.len());
+
+    for i in 0..vectors.len() {
+        let (previous, rest) = vectors.split_at_mut(i);
+        let v = &mut rest[0];
+
+        for u in previous.iter() {
+            let projection = dot(u, v);
+            v.iter_mut().zip(u).for_each(|(x, y)| *x -= projection * y);
+        }
+
+        let norm = dot(v, v).sqrt();
+        if norm > 0. {
+            v.iter_mut().for_each(|x| *x /= norm);
+        }
+        norms.push(norm);
+    }
+
+    norms
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::test_utils::{create_tiny_bert_repo, TINY_HIDDEN_SIZE};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_fit_pca() -> Result<()> {
+        // Points spread mostly along (1, 1, 0), a bit along (1, -1, 0) and not at all along z
+        let values: Vec<f32> = (0..20)
+            .flat_map(|i| {
+                let a = (i as f32 - 10.) * 3.;
+                let b = if i % 4 == 0 || i % 4 == 3 { 1. } else { -1. };
+                [a + b + 1., a - b + 2., 3.]
+            })
+            .collect();
+        let embeddings = Tensor::from_vec(values, (20, 3), &Device::Cpu)?;
+
+        let pca = Pca::fit(&embeddings, 2)?;
+        assert_eq!((pca.input_dim(), pca.n_components()), (3, 2));
+
+        let variance = pca.explained_variance();
+        assert!(variance[0] > variance[1] && variance[1] > 0.);
+
+        let components = pca.components.to_vec2::<f32>()?;
+        let s = 0.5f32.sqrt();
+        for (actual, expected) in components[0].iter().zip([s, s, 0.]) {
+            assert!((actual - expected).abs() < 1e-4);
+        }
+        assert!(components[1][2].abs() < 1e-4);
+
+        let projected = pca.transform(&embeddings)?;
+        assert_eq!(projected.dims(), &[20, 2]);
+        let mean = projected.mean(0)?.abs()?.max(0)?.to_scalar::<f32>()?;
+        assert!(mean < 1e-4);
+
+        assert!(Pca::fit(&embeddings, 4).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pca_applied_on_load() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_bert_repo(dir.path())?;
+
+        let model = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+
+        let corpus = [
+            "The cat sits outside",
+            "A man is playing guitar",
+            "I love pasta",
+            "Do you like pizza?",
+            "The new movie is awesome",
+            "The dog plays in the garden",
+        ];
+        let pca = fit_pca(&model, &corpus, 4, 4)?;
+        pca.save(dir.path())?;
+
+        let reduced = SentenceTransformer::builder()
+            .with_model_folder(dir.path())
+            .build()?;
+
+        let full = model.encode_batch(vec!["I love pasta"], false)?;
+        assert_eq!(full.dims(), &[1, TINY_HIDDEN_SIZE]);
+
+        let embeddings = reduced.encode_batch(vec!["I love pasta"], true)?;
+        assert_eq!(embeddings.dims(), &[1, 4]);
+
+        let norm = embeddings.sqr()?.sum_all()?.sqrt()?.to_scalar::<f32>()?;
+        assert!((norm - 1.).abs() < 1e-5);
+
+        Ok(())
+    }
+}
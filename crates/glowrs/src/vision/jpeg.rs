@@ -0,0 +1,89 @@
+//! JPEG decoding
+//!
+//! Decodes baseline and progressive JPEG images with `jpeg-decoder`, converting grayscale and
+//! CMYK images to RGB like `PIL.Image.convert("RGB")` does. The size of the image is read from
+//! its header and checked against the pixel limit before it is decoded.
+
+use jpeg_decoder::{Decoder, PixelFormat};
+
+use super::{check_size, RgbImage};
+use crate::{Error, Result};
+
+pub(crate) const SIGNATURE: &[u8] = b"\xff\xd8\xff";
+
+const INVALID: Error = Error::InvalidArgument("Invalid JPEG image");
+
+pub(crate) fn decode(data: &[u8], max_pixels: usize) -> Result<RgbImage> {
+    let mut decoder = Decoder::new(data);
+    decoder.read_info().map_err(|_| INVALID)?;
+    let info = decoder.info().ok_or(INVALID)?;
+    let (width, height) = (info.width as usize, info.height as usize);
+    check_size(width, height, max_pixels)?;
+    // The decoder allocates at most the decoded image, with up to 4 bytes per pixel
+    decoder.set_max_decoding_buffer_size(width * height * 4);
+
+    let decoded = decoder.decode().map_err(|_| INVALID)?;
+    let pixels = match info.pixel_format {
+        PixelFormat::RGB24 => decoded,
+        PixelFormat::L8 => decoded.iter().flat_map(|&value| [value; 3]).collect(),
+        PixelFormat::CMYK32 => decoded
+            .chunks_exact(4)
+            .flat_map(|cmyk| {
+                let white = 255 - cmyk[3] as u16;
+                let channel = |value: u8| (white - value as u16 * white / 255) as u8;
+                [channel(cmyk[0]), channel(cmyk[1]), channel(cmyk[2])]
+            })
+            .collect(),
+        PixelFormat::L16 => {
+            return Err(Error::InvalidArgument(
+                "JPEG images with 16-bit channels are not supported",
+            ))
+        }
+    };
+
+    RgbImage::new(width, height, pixels)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vision::MAX_IMAGE_PIXELS as MAX;
+    use jpeg_encoder::{ColorType, Encoder};
+
+    fn encode(pixels: &[u8], width: u16, height: u16, color_type: ColorType) -> Vec<u8> {
+        let mut jpeg = Vec::new();
+        Encoder::new(&mut jpeg, 100)
+            .encode(pixels, width, height, color_type)
+            .unwrap();
+        jpeg
+    }
+
+    #[test]
+    fn test_decode() -> Result<()> {
+        let color = [200u8, 100, 50];
+        let jpeg = encode(&color.repeat(16 * 8), 16, 8, ColorType::Rgb);
+        assert!(jpeg.starts_with(SIGNATURE));
+
+        let image = decode(&jpeg, MAX)?;
+        assert_eq!((image.width(), image.height()), (16, 8));
+        // JPEG is lossy, even at the highest quality
+        for (value, expected) in image.pixels.iter().zip(color.iter().cycle()) {
+            assert!(value.abs_diff(*expected) <= 2);
+        }
+
+        // Grayscale images are converted to RGB
+        let image = decode(&encode(&[128; 8 * 8], 8, 8, ColorType::Luma), MAX)?;
+        assert_eq!(image.pixels.len(), 8 * 8 * 3);
+        assert!(image.pixels.iter().all(|value| value.abs_diff(128) <= 1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let jpeg = encode(&[0; 16 * 8 * 3], 16, 8, ColorType::Rgb);
+        assert!(decode(&jpeg, 16 * 8 - 1).is_err());
+        assert!(decode(&jpeg[..jpeg.len() / 2], MAX).is_err());
+        assert!(decode(SIGNATURE, MAX).is_err());
+    }
+}
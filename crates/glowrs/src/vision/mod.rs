@@ -0,0 +1,719 @@
+//! Image embeddings
+//!
+//! [`ImageEncoder`] embeds images with a CLIP model. CLIP is trained to map images and their
+//! captions to the same embedding space, so the image embeddings can be compared directly with
+//! the text embeddings of the same model, e.g. to search images by a text query.
+//!
+//! Images are passed as RGB pixels, decoded with [`RgbImage::decode`] (PNG, JPEG or PPM) or
+//! with e.g. the `image` crate for other formats. They are preprocessed like in `transformers`:
+//! resized so the shortest edge matches the model input size, center cropped, and normalized with the mean and standard deviation of the model's
+//! `preprocessor_config.json`. Resizing is bilinear, where `transformers` uses bicubic
+//! resampling, so embeddings can differ slightly from the reference implementation.
+
+use candle_core::{DType, Device, Tensor};
+use candle_transformers::models::clip::text_model::{Activation, ClipTextConfig};
+use candle_transformers::models::clip::vision_model::ClipVisionConfig;
+use candle_transformers::models::clip::{ClipConfig, ClipModel};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokenizers::{EncodeInput, Tokenizer};
+
+use crate::core::embedder::{load_var_builder, EmbedOutput};
+use crate::core::repo::{
+    snapshot_commit, ModelWeightsPath, CONFIG_FILE, SAFETENSORS_FILE, TOKENIZER_FILE,
+};
+use crate::core::utils::normalize_l2;
+use crate::{Error, InputUsage, Result, Usage};
+
+mod jpeg;
+mod png;
+
+/// Maximum number of pixels of images decoded with [`RgbImage::decode`], like
+/// `PIL.Image.MAX_IMAGE_PIXELS`
+pub const MAX_IMAGE_PIXELS: usize = 89_478_485;
+
+pub(crate) const PREPROCESSOR_CONFIG_FILE: &str = "preprocessor_config.json";
+
+/// An image as 8-bit RGB pixels, in row-major order.
+#[derive(Debug, Clone)]
+pub struct RgbImage {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl RgbImage {
+    /// Create an image from `width * height * 3` interleaved RGB values.
+    pub fn new(width: usize, height: usize, pixels: Vec<u8>) -> Result<Self> {
+        if width == 0 || height == 0 {
+            return Err(Error::InvalidArgument("Image should not be empty"));
+        }
+        if width.checked_mul(height).and_then(|n| n.checked_mul(3)) != Some(pixels.len()) {
+            return Err(Error::InvalidArgument(
+                "Number of pixel values doesn't match the image size",
+            ));
+        }
+
+        Ok(Self {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Decode a PNG, JPEG or binary PPM image, detecting the format from its content. Images with
+    /// more than [`MAX_IMAGE_PIXELS`] pixels are rejected.
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        Self::decode_with_limit(data, MAX_IMAGE_PIXELS)
+    }
+
+    /// Decode an image like [`Self::decode`], rejecting images with more than `max_pixels`
+    /// pixels before their pixels are allocated.
+    pub fn decode_with_limit(data: &[u8], max_pixels: usize) -> Result<Self> {
+        if data.starts_with(png::SIGNATURE) {
+            png::decode(data, max_pixels)
+        } else if data.starts_with(jpeg::SIGNATURE) {
+            jpeg::decode(data, max_pixels)
+        } else if data.starts_with(b"P6") {
+            // The pixels are copied from the data, so its size bounds the allocation
+            let image = Self::from_ppm(data)?;
+            check_size(image.width, image.height, max_pixels)?;
+            Ok(image)
+        } else {
+            Err(Error::InvalidArgument(
+                "Unsupported image format, expected a PNG, JPEG or PPM image",
+            ))
+        }
+    }
+
+    /// Parse a binary PPM (`P6`) image with 8-bit channels.
+    pub fn from_ppm(data: &[u8]) -> Result<Self> {
+        let invalid = || Error::InvalidArgument("Invalid PPM image");
+
+        // The header has four whitespace separated fields, followed by a single whitespace
+        let mut fields = Vec::with_capacity(4);
+        let mut pos = 0;
+        while fields.len() < 4 {
+            while data.get(pos).ok_or_else(invalid)?.is_ascii_whitespace() {
+                pos += 1;
+            }
+            let start = pos;
+            while !data.get(pos).ok_or_else(invalid)?.is_ascii_whitespace() {
+                pos += 1;
+            }
+            fields.push(std::str::from_utf8(&data[start..pos]).map_err(|_| invalid())?);
+        }
+        pos += 1;
+
+        let number = |field: &str| field.parse::<usize>().map_err(|_| invalid());
+        if fields[0] != "P6" || number(fields[3])? != 255 {
+            return Err(Error::InvalidArgument(
+                "Only binary PPM images with 8-bit channels are supported",
+            ));
+        }
+
+        Self::new(
+            number(fields[1])?,
+            number(fields[2])?,
+            data.get(pos..).ok_or_else(invalid)?.to_vec(),
+        )
+    }
+
+    /// Value of channel `c` at `(x, y)`, interpolated bilinearly between the pixel centers.
+    fn sample(&self, x: f32, y: f32, c: usize) -> f32 {
+        let x = x.clamp(0., (self.width - 1) as f32);
+        let y = y.clamp(0., (self.height - 1) as f32);
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (dx, dy) = (x - x0 as f32, y - y0 as f32);
+
+        let value = |x: usize, y: usize| self.pixels[(y * self.width + x) * 3 + c] as f32;
+        let top = value(x0, y0) * (1. - dx) + value(x1, y0) * dx;
+        let bottom = value(x0, y1) * (1. - dx) + value(x1, y1) * dx;
+
+        top * (1. - dy) + bottom * dy
+    }
+}
+
+/// Check that an image of `width` by `height` pixels has at most `max_pixels` pixels, so it can
+/// be decoded without overflowing or allocating an excessive amount of memory.
+fn check_size(width: usize, height: usize, max_pixels: usize) -> Result<()> {
+    match width.checked_mul(height) {
+        Some(pixels) if pixels <= max_pixels => Ok(()),
+        _ => Err(Error::InvalidArgument("Image has too many pixels")),
+    }
+}
+
+/// Image preprocessing settings, from `preprocessor_config.json`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct PreprocessorConfig {
+    #[serde(default = "default_image_mean")]
+    image_mean: [f32; 3],
+    #[serde(default = "default_image_std")]
+    image_std: [f32; 3],
+    /// Size of the shortest edge after resizing
+    size: Option<ImageSize>,
+    /// Size of the center crop
+    crop_size: Option<ImageSize>,
+}
+
+fn default_image_mean() -> [f32; 3] {
+    [0.481_454_66, 0.457_827_5, 0.408_210_73]
+}
+
+fn default_image_std() -> [f32; 3] {
+    [0.268_629_54, 0.261_302_6, 0.275_777_1]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(untagged)]
+enum ImageSize {
+    Square(usize),
+    ShortestEdge { shortest_edge: usize },
+    Size { height: usize, width: usize },
+}
+
+impl ImageSize {
+    fn edge(self) -> usize {
+        match self {
+            ImageSize::Square(size) => size,
+            ImageSize::ShortestEdge { shortest_edge } => shortest_edge,
+            ImageSize::Size { height, width } => height.min(width),
+        }
+    }
+}
+
+/// Fields of a CLIP `config.json`. Missing fields default to `openai/clip-vit-base-patch32`.
+#[derive(Debug, Deserialize)]
+struct ClipJsonConfig {
+    #[serde(default = "default_projection_dim")]
+    projection_dim: usize,
+    text_config: ClipTextJsonConfig,
+    vision_config: ClipVisionJsonConfig,
+}
+
+fn default_projection_dim() -> usize {
+    512
+}
+
+fn default_activation() -> String {
+    "quick_gelu".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+struct ClipTextJsonConfig {
+    #[serde(default = "ClipTextJsonConfig::default_vocab_size")]
+    vocab_size: usize,
+    #[serde(default = "ClipTextJsonConfig::default_hidden_size")]
+    hidden_size: usize,
+    #[serde(default = "ClipTextJsonConfig::default_intermediate_size")]
+    intermediate_size: usize,
+    #[serde(default = "ClipTextJsonConfig::default_max_position_embeddings")]
+    max_position_embeddings: usize,
+    #[serde(default = "ClipTextJsonConfig::default_num_hidden_layers")]
+    num_hidden_layers: usize,
+    #[serde(default = "ClipTextJsonConfig::default_num_attention_heads")]
+    num_attention_heads: usize,
+    #[serde(default = "default_activation")]
+    hidden_act: String,
+}
+
+impl ClipTextJsonConfig {
+    fn default_vocab_size() -> usize {
+        49408
+    }
+    fn default_hidden_size() -> usize {
+        512
+    }
+    fn default_intermediate_size() -> usize {
+        2048
+    }
+    fn default_max_position_embeddings() -> usize {
+        77
+    }
+    fn default_num_hidden_layers() -> usize {
+        12
+    }
+    fn default_num_attention_heads() -> usize {
+        8
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ClipVisionJsonConfig {
+    #[serde(default = "ClipVisionJsonConfig::default_hidden_size")]
+    hidden_size: usize,
+    #[serde(default = "ClipVisionJsonConfig::default_intermediate_size")]
+    intermediate_size: usize,
+    #[serde(default = "ClipVisionJsonConfig::default_num_hidden_layers")]
+    num_hidden_layers: usize,
+    #[serde(default = "ClipVisionJsonConfig::default_num_attention_heads")]
+    num_attention_heads: usize,
+    #[serde(default = "ClipVisionJsonConfig::default_num_channels")]
+    num_channels: usize,
+    #[serde(default = "ClipVisionJsonConfig::default_image_size")]
+    image_size: usize,
+    #[serde(default = "ClipVisionJsonConfig::default_patch_size")]
+    patch_size: usize,
+    #[serde(default = "default_activation")]
+    hidden_act: String,
+}
+
+impl ClipVisionJsonConfig {
+    fn default_hidden_size() -> usize {
+        768
+    }
+    fn default_intermediate_size() -> usize {
+        3072
+    }
+    fn default_num_hidden_layers() -> usize {
+        12
+    }
+    fn default_num_attention_heads() -> usize {
+        12
+    }
+    fn default_num_channels() -> usize {
+        3
+    }
+    fn default_image_size() -> usize {
+        224
+    }
+    fn default_patch_size() -> usize {
+        32
+    }
+}
+
+impl TryFrom<ClipJsonConfig> for ClipConfig {
+    type Error = Error;
+
+    fn try_from(config: ClipJsonConfig) -> Result<Self> {
+        let ClipJsonConfig {
+            projection_dim,
+            text_config: text,
+            vision_config: vision,
+        } = config;
+
+        // The activation is the only one candle implements for CLIP
+        if text.hidden_act != "quick_gelu" || vision.hidden_act != "quick_gelu" {
+            return Err(Error::InvalidModelConfig(
+                "Only CLIP models with the `quick_gelu` activation are supported",
+            ));
+        }
+        if vision.num_channels != 3 {
+            return Err(Error::InvalidModelConfig(
+                "Only CLIP models for RGB images are supported",
+            ));
+        }
+
+        Ok(ClipConfig {
+            text_config: ClipTextConfig {
+                vocab_size: text.vocab_size,
+                embed_dim: text.hidden_size,
+                activation: Activation::QuickGelu,
+                intermediate_size: text.intermediate_size,
+                max_position_embeddings: text.max_position_embeddings,
+                pad_with: None,
+                num_hidden_layers: text.num_hidden_layers,
+                num_attention_heads: text.num_attention_heads,
+                projection_dim,
+            },
+            vision_config: ClipVisionConfig {
+                embed_dim: vision.hidden_size,
+                activation: Activation::QuickGelu,
+                intermediate_size: vision.intermediate_size,
+                num_hidden_layers: vision.num_hidden_layers,
+                num_attention_heads: vision.num_attention_heads,
+                projection_dim,
+                num_channels: vision.num_channels,
+                image_size: vision.image_size,
+                patch_size: vision.patch_size,
+            },
+            logit_scale_init_value: 2.6592,
+            image_size: vision.image_size,
+        })
+    }
+}
+
+/// Embeds images, and texts in the same embedding space, with a CLIP model.
+pub struct ImageEncoder {
+    model: ClipModel,
+    tokenizer: Tokenizer,
+    preprocessor: PreprocessorConfig,
+    image_size: usize,
+    projection_dim: usize,
+    device: Device,
+    /// Commit hash of the repository revision, if loaded from the Hugging Face cache
+    commit: Option<String>,
+}
+
+impl ImageEncoder {
+    /// Load a CLIP model from a folder with `config.json`, `tokenizer.json`,
+    /// `model.safetensors` and optionally `preprocessor_config.json`.
+    pub fn from_model_folder<P: AsRef<Path>>(path: P, device: &Device) -> Result<Self> {
+        let path = path.as_ref();
+        let preprocessor_config = Some(path.join(PREPROCESSOR_CONFIG_FILE)).filter(|p| p.exists());
+
+        Self::from_files(
+            &path.join(CONFIG_FILE),
+            &path.join(TOKENIZER_FILE),
+            &path.join(SAFETENSORS_FILE),
+            preprocessor_config.as_deref(),
+            device,
+        )
+    }
+
+    /// Load a CLIP model from a Hugging Face Hub repository, given as `<repo>[:<revision>]`.
+    #[cfg(feature = "hub")]
+    pub fn from_model_repo(model_repo: &str, device: &Device) -> Result<Self> {
+        use hf_hub::api::sync::Api;
+        use hf_hub::{Repo, RepoType};
+
+        let (repo_id, revision) = crate::core::utils::parse_repo_string(model_repo)?;
+        let api_repo = Api::new()?.repo(Repo::with_revision(
+            repo_id.to_owned(),
+            RepoType::Model,
+            revision.to_owned(),
+        ));
+
+        Self::from_files(
+            &api_repo.get(CONFIG_FILE)?,
+            &api_repo.get(TOKENIZER_FILE)?,
+            &api_repo.get(SAFETENSORS_FILE)?,
+            api_repo.get(PREPROCESSOR_CONFIG_FILE).ok().as_deref(),
+            device,
+        )
+    }
+
+    fn from_files(
+        config: &Path,
+        tokenizer: &Path,
+        weights: &Path,
+        preprocessor_config: Option<&Path>,
+        device: &Device,
+    ) -> Result<Self> {
+        let span = tracing::span!(tracing::Level::TRACE, "image-encoder-load");
+        let _enter = span.enter();
+
+        let config: ClipJsonConfig = serde_json::from_str(&fs::read_to_string(config)?)?;
+        let config = ClipConfig::try_from(config)?;
+
+        let preprocessor = match preprocessor_config {
+            Some(path) => serde_json::from_str(&fs::read_to_string(path)?)?,
+            None => PreprocessorConfig {
+                image_mean: default_image_mean(),
+                image_std: default_image_std(),
+                size: None,
+                crop_size: None,
+            },
+        };
+
+        let mut tokenizer = Tokenizer::from_file(tokenizer)?;
+        let padding = tokenizer.get_padding().cloned().unwrap_or_default();
+        tokenizer.with_padding(Some(tokenizers::PaddingParams {
+            strategy: tokenizers::PaddingStrategy::BatchLongest,
+            ..padding
+        }));
+        tokenizer.with_truncation(Some(tokenizers::TruncationParams {
+            max_length: config.text_config.max_position_embeddings,
+            ..Default::default()
+        }))?;
+
+        let weights = ModelWeightsPath::Safetensors(PathBuf::from(weights));
+        let vb = load_var_builder(&weights, device, DType::F32)?;
+        let model = ClipModel::new(vb, &config)?;
+
+        Ok(Self {
+            model,
+            tokenizer,
+            preprocessor,
+            image_size: config.image_size,
+            projection_dim: config.vision_config.projection_dim,
+            device: device.clone(),
+            commit: weights.path().and_then(snapshot_commit),
+        })
+    }
+
+    /// Dimension of the image and text embeddings.
+    pub fn embedding_dim(&self) -> usize {
+        self.projection_dim
+    }
+
+    /// The tokenizer texts are encoded with.
+    pub fn get_tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// Commit hash of the revision of the model repository, if the model was loaded from the
+    /// Hugging Face cache.
+    pub fn commit_hash(&self) -> Option<&str> {
+        self.commit.as_deref()
+    }
+
+    /// Resize, crop and normalize images into pixel values of shape
+    /// `(n_images, 3, image_size, image_size)`, on the device of the model.
+    pub fn preprocess(&self, images: &[RgbImage]) -> Result<Tensor> {
+        let crop = self.image_size;
+        let shortest_edge = self
+            .preprocessor
+            .size
+            .map_or(crop, ImageSize::edge)
+            .max(crop);
+        let PreprocessorConfig {
+            image_mean: mean,
+            image_std: std,
+            ..
+        } = &self.preprocessor;
+
+        let mut values = Vec::with_capacity(images.len() * 3 * crop * crop);
+        for image in images {
+            // Resize the shortest edge, then take the center crop
+            let scale = shortest_edge as f32 / image.width.min(image.height) as f32;
+            let (width, height) = (image.width as f32 * scale, image.height as f32 * scale);
+            let left = ((width - crop as f32) / 2.).floor();
+            let top = ((height - crop as f32) / 2.).floor();
+
+            for c in 0..3 {
+                for y in 0..crop {
+                    for x in 0..crop {
+                        // Map the center of the output pixel back onto the source image
+                        let src_x = (left + x as f32 + 0.5) / scale - 0.5;
+                        let src_y = (top + y as f32 + 0.5) / scale - 0.5;
+                        let value = image.sample(src_x, src_y, c) / 255.;
+                        values.push((value - mean[c]) / std[c]);
+                    }
+                }
+            }
+        }
+
+        Ok(Tensor::from_vec(
+            values,
+            (images.len(), 3, crop, crop),
+            &self.device,
+        )?)
+    }
+
+    /// Embed images, returning embeddings of shape `(n_images, embedding_dim)`.
+    pub fn encode_images(&self, images: &[RgbImage], normalize: bool) -> Result<Tensor> {
+        let pixel_values = self.preprocess(images)?;
+
+        self.encode_pixels(&pixel_values, normalize)
+    }
+
+    /// Embed preprocessed pixel values of shape `(n_images, 3, image_size, image_size)`.
+    pub fn encode_pixels(&self, pixel_values: &Tensor, normalize: bool) -> Result<Tensor> {
+        let span = tracing::span!(tracing::Level::TRACE, "image-encoder-encode-images");
+        let _enter = span.enter();
+
+        let embeddings = self.model.get_image_features(pixel_values)?;
+
+        if normalize {
+            Ok(normalize_l2(&embeddings)?)
+        } else {
+            Ok(embeddings)
+        }
+    }
+
+    /// Embed texts with the text tower of the model, in the same space as the images.
+    pub fn encode_text<'s, E>(&self, sentences: Vec<E>, normalize: bool) -> Result<Tensor>
+    where
+        E: Into<EncodeInput<'s>> + Send,
+    {
+        Ok(self
+            .encode_text_with_usage(sentences, normalize)?
+            .into_tensor())
+    }
+
+    /// Same as [`ImageEncoder::encode_text`], along with the token usage of the texts.
+    pub fn encode_text_with_usage<'s, E>(
+        &self,
+        sentences: Vec<E>,
+        normalize: bool,
+    ) -> Result<EmbedOutput>
+    where
+        E: Into<EncodeInput<'s>> + Send,
+    {
+        let span = tracing::span!(tracing::Level::TRACE, "image-encoder-encode-text");
+        let _enter = span.enter();
+
+        let encodings = self.tokenizer.encode_batch(sentences, true)?;
+        let seq_len = encodings.first().map_or(0, |encoding| encoding.len());
+        let token_ids: Vec<u32> = encodings
+            .iter()
+            .flat_map(|encoding| encoding.get_ids().iter().copied())
+            .collect();
+        let token_ids = Tensor::from_vec(token_ids, (encodings.len(), seq_len), &self.device)?;
+
+        let embeddings = self.model.get_text_features(&token_ids)?;
+        let embeddings = if normalize {
+            normalize_l2(&embeddings)?
+        } else {
+            embeddings
+        };
+
+        let inputs: Vec<InputUsage> = encodings.iter().map(InputUsage::from).collect();
+        Ok(EmbedOutput {
+            embeddings,
+            usage: Usage::from(inputs.as_slice()),
+            inputs,
+        })
+    }
+}
+
+/// Whether a Hugging Face Hub repository, given as `<repo>[:<revision>]`, holds a CLIP model
+/// that can be loaded with [`ImageEncoder::from_model_repo`].
+#[cfg(feature = "hub")]
+pub fn is_clip_model_repo(model_repo: &str) -> Result<bool> {
+    use hf_hub::api::sync::Api;
+    use hf_hub::{Repo, RepoType};
+
+    #[derive(Deserialize)]
+    struct ModelType {
+        model_type: Option<String>,
+    }
+
+    let (repo_id, revision) = crate::core::utils::parse_repo_string(model_repo)?;
+    let api_repo = Api::new()?.repo(Repo::with_revision(
+        repo_id.to_owned(),
+        RepoType::Model,
+        revision.to_owned(),
+    ));
+    // Sentence Transformers repositories can keep the configuration in a module directory
+    let Ok(config) = api_repo.get(CONFIG_FILE) else {
+        return Ok(false);
+    };
+    let ModelType { model_type } = serde_json::from_str(&fs::read_to_string(config)?)?;
+
+    Ok(model_type.as_deref() == Some("clip"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core::test_utils::BERT_FIXTURE_PATH;
+    use candle_nn::{VarBuilder, VarMap};
+    use tempfile::tempdir;
+
+    const PROJECTION_DIM: usize = 4;
+
+    /// Create a tiny CLIP model folder with random weights, using the BERT tokenizer fixture.
+    fn create_tiny_clip_repo(dir: &Path) -> Result<()> {
+        let config = serde_json::json!({
+            "model_type": "clip",
+            "projection_dim": PROJECTION_DIM,
+            "text_config": {
+                "vocab_size": 30522,
+                "hidden_size": 8,
+                "intermediate_size": 16,
+                "max_position_embeddings": 16,
+                "num_hidden_layers": 1,
+                "num_attention_heads": 2
+            },
+            "vision_config": {
+                "hidden_size": 8,
+                "intermediate_size": 16,
+                "num_hidden_layers": 1,
+                "num_attention_heads": 2,
+                "image_size": 8,
+                "patch_size": 4
+            }
+        });
+        fs::write(dir.join(CONFIG_FILE), config.to_string())?;
+        fs::copy(
+            Path::new(BERT_FIXTURE_PATH).join(TOKENIZER_FILE),
+            dir.join(TOKENIZER_FILE),
+        )?;
+
+        let config = ClipConfig::try_from(serde_json::from_value::<ClipJsonConfig>(config)?)?;
+        let var_map = VarMap::new();
+        let vb = VarBuilder::from_varmap(&var_map, DType::F32, &Device::Cpu);
+        let _ = ClipModel::new(vb, &config)?;
+        var_map.save(dir.join(SAFETENSORS_FILE))?;
+
+        Ok(())
+    }
+
+    fn uniform_image(width: usize, height: usize, value: u8) -> RgbImage {
+        RgbImage::new(width, height, vec![value; width * height * 3]).unwrap()
+    }
+
+    #[test]
+    fn test_preprocess() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_clip_repo(dir.path())?;
+        let encoder = ImageEncoder::from_model_folder(dir.path(), &Device::Cpu)?;
+
+        let images = [uniform_image(32, 16, 255), uniform_image(5, 7, 0)];
+        let pixel_values = encoder.preprocess(&images)?;
+        assert_eq!(pixel_values.dims(), &[2, 3, 8, 8]);
+
+        // Uniform images stay uniform after resizing
+        let values = pixel_values.flatten_all()?.to_vec1::<f32>()?;
+        let (mean, std) = (default_image_mean(), default_image_std());
+        for (i, value) in values.iter().enumerate() {
+            let (image, c) = (i / (3 * 64), (i / 64) % 3);
+            let expected = ([1., 0.][image] - mean[c]) / std[c];
+            assert!((value - expected).abs() < 1e-5);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_images_and_text() -> Result<()> {
+        let dir = tempdir()?;
+        create_tiny_clip_repo(dir.path())?;
+        let encoder = ImageEncoder::from_model_folder(dir.path(), &Device::Cpu)?;
+        assert_eq!(encoder.embedding_dim(), PROJECTION_DIM);
+
+        let images = [uniform_image(16, 16, 200), uniform_image(10, 20, 30)];
+        let image_embeddings = encoder.encode_images(&images, true)?;
+        let text_output =
+            encoder.encode_text_with_usage(vec!["a white square", "a dark image"], true)?;
+        assert_eq!(text_output.inputs.len(), 2);
+        let text_embeddings = text_output.into_tensor();
+
+        assert_eq!(image_embeddings.dims(), &[2, PROJECTION_DIM]);
+        assert_eq!(text_embeddings.dims(), &[2, PROJECTION_DIM]);
+        for norm in image_embeddings.sqr()?.sum(1)?.to_vec1::<f32>()? {
+            assert!((norm - 1.).abs() < 1e-5);
+        }
+
+        // Both live in the same space, so they can be compared
+        let similarity = text_embeddings.matmul(&image_embeddings.t()?)?;
+        assert_eq!(similarity.dims(), &[2, 2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_ppm() -> Result<()> {
+        let mut data = b"P6\n2 1\n255\n".to_vec();
+        data.extend_from_slice(&[255, 0, 0, 0, 0, 255]);
+
+        let image = RgbImage::from_ppm(&data)?;
+        assert_eq!((image.width(), image.height()), (2, 1));
+        assert_eq!(image.pixels, vec![255, 0, 0, 0, 0, 255]);
+
+        assert!(RgbImage::from_ppm(b"P3\n2 1\n255\n").is_err());
+        assert!(RgbImage::from_ppm(b"P6\n2 1\n255\n\x00").is_err());
+
+        assert_eq!(RgbImage::decode(&data)?.pixels, image.pixels);
+        assert!(RgbImage::decode(b"GIF89a").is_err());
+        assert!(RgbImage::decode_with_limit(&data, 1).is_err());
+
+        Ok(())
+    }
+}
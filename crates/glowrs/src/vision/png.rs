@@ -0,0 +1,303 @@
+//! Minimal PNG decoder
+//!
+//! Decodes non-interlaced PNG images of any color type to 8-bit RGB, dropping the alpha channel
+//! like `PIL.Image.convert("RGB")` does. Chunk checksums aren't verified; the compressed image
+//! data is still checked by the zlib decoder. The size of the image is checked against the pixel
+//! limit before anything is allocated, and the image data is inflated up to the size the header
+//! implies, so crafted images can't exhaust the memory.
+
+use flate2::read::ZlibDecoder;
+use std::io::Read;
+
+use super::{check_size, RgbImage};
+use crate::{Error, Result};
+
+pub(crate) const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+const INVALID: Error = Error::InvalidArgument("Invalid PNG image");
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ColorType {
+    Gray,
+    Rgb,
+    Palette,
+    GrayAlpha,
+    Rgba,
+}
+
+impl ColorType {
+    fn from_byte(byte: u8) -> Result<Self> {
+        Ok(match byte {
+            0 => ColorType::Gray,
+            2 => ColorType::Rgb,
+            3 => ColorType::Palette,
+            4 => ColorType::GrayAlpha,
+            6 => ColorType::Rgba,
+            _ => return Err(INVALID),
+        })
+    }
+
+    fn channels(self) -> usize {
+        match self {
+            ColorType::Gray | ColorType::Palette => 1,
+            ColorType::GrayAlpha => 2,
+            ColorType::Rgb => 3,
+            ColorType::Rgba => 4,
+        }
+    }
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32> {
+    let bytes = data
+        .get(pos..pos.checked_add(4).ok_or(INVALID)?)
+        .ok_or(INVALID)?;
+    Ok(u32::from_be_bytes(bytes.try_into().map_err(|_| INVALID)?))
+}
+
+pub(crate) fn decode(data: &[u8], max_pixels: usize) -> Result<RgbImage> {
+    if !data.starts_with(SIGNATURE) {
+        return Err(INVALID);
+    }
+
+    let mut header = None;
+    let mut palette: &[u8] = &[];
+    let mut compressed = Vec::new();
+
+    let mut pos = SIGNATURE.len();
+    loop {
+        let length = read_u32(data, pos)? as usize;
+        let kind = data.get(pos + 4..pos + 8).ok_or(INVALID)?;
+        let end = (pos + 8).checked_add(length).ok_or(INVALID)?;
+        let chunk = data.get(pos + 8..end).ok_or(INVALID)?;
+        // Skip the chunk and its checksum
+        pos = end + 4;
+
+        match kind {
+            b"IHDR" => header = Some(chunk),
+            b"PLTE" => palette = chunk,
+            b"IDAT" => compressed.extend_from_slice(chunk),
+            b"IEND" => break,
+            _ => {}
+        }
+    }
+
+    let header = header.filter(|header| header.len() == 13).ok_or(INVALID)?;
+    let width = read_u32(header, 0)? as usize;
+    let height = read_u32(header, 4)? as usize;
+    let bit_depth = header[8] as usize;
+    let color_type = ColorType::from_byte(header[9])?;
+    if header[12] != 0 {
+        return Err(Error::InvalidArgument(
+            "Interlaced PNG images are not supported",
+        ));
+    }
+    let valid_depth = match color_type {
+        ColorType::Gray => matches!(bit_depth, 1 | 2 | 4 | 8 | 16),
+        ColorType::Palette => matches!(bit_depth, 1 | 2 | 4 | 8),
+        _ => matches!(bit_depth, 8 | 16),
+    };
+    if !valid_depth || width == 0 || height == 0 {
+        return Err(INVALID);
+    }
+    check_size(width, height, max_pixels)?;
+
+    let bits_per_pixel = color_type.channels() * bit_depth;
+    let stride = width
+        .checked_mul(bits_per_pixel)
+        .ok_or(INVALID)?
+        .div_ceil(8);
+    // Filters work on whole bytes, with at least one byte per pixel
+    let bpp = bits_per_pixel.div_ceil(8);
+    let expected_len = (stride + 1).checked_mul(height).ok_or(INVALID)?;
+
+    // Inflate one byte more than expected, to detect image data that is too long
+    let mut raw = Vec::with_capacity(expected_len);
+    ZlibDecoder::new(compressed.as_slice())
+        .take(expected_len as u64 + 1)
+        .read_to_end(&mut raw)
+        .map_err(|_| INVALID)?;
+    if raw.len() != expected_len {
+        return Err(INVALID);
+    }
+
+    let mut previous = vec![0u8; stride];
+    let mut row = vec![0u8; stride];
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for line in raw.chunks_exact(stride + 1).take(height) {
+        unfilter(line[0], &line[1..], &previous, &mut row, bpp)?;
+
+        for x in 0..width {
+            let sample = |channel: usize| -> u8 {
+                let index = x * color_type.channels() + channel;
+                match bit_depth {
+                    8 => row[index],
+                    // Keep the most significant byte
+                    16 => row[index * 2],
+                    _ => {
+                        let bit = index * bit_depth;
+                        let max = (1u16 << bit_depth) - 1;
+                        let value = (row[bit / 8] >> (8 - bit_depth - bit % 8)) as u16 & max;
+                        if color_type == ColorType::Palette {
+                            value as u8
+                        } else {
+                            (value * 255 / max) as u8
+                        }
+                    }
+                }
+            };
+
+            match color_type {
+                ColorType::Gray | ColorType::GrayAlpha => {
+                    let value = sample(0);
+                    pixels.extend_from_slice(&[value, value, value]);
+                }
+                ColorType::Rgb | ColorType::Rgba => {
+                    pixels.extend_from_slice(&[sample(0), sample(1), sample(2)]);
+                }
+                ColorType::Palette => {
+                    let index = sample(0) as usize * 3;
+                    pixels.extend_from_slice(palette.get(index..index + 3).ok_or(INVALID)?);
+                }
+            }
+        }
+
+        std::mem::swap(&mut previous, &mut row);
+    }
+
+    RgbImage::new(width, height, pixels)
+}
+
+/// Reverse the filter of a scanline, given the unfiltered previous scanline.
+fn unfilter(filter: u8, line: &[u8], previous: &[u8], row: &mut [u8], bpp: usize) -> Result<()> {
+    for i in 0..line.len() {
+        let left = if i >= bpp { row[i - bpp] } else { 0 };
+        let up = previous[i];
+        let up_left = if i >= bpp { previous[i - bpp] } else { 0 };
+
+        let predictor = match filter {
+            0 => 0,
+            1 => left,
+            2 => up,
+            3 => ((left as u16 + up as u16) / 2) as u8,
+            4 => paeth(left, up, up_left),
+            _ => return Err(INVALID),
+        };
+        row[i] = line[i].wrapping_add(predictor);
+    }
+
+    Ok(())
+}
+
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let p = left as i16 + up as i16 - up_left as i16;
+    let (pa, pb, pc) = (
+        (p - left as i16).abs(),
+        (p - up as i16).abs(),
+        (p - up_left as i16).abs(),
+    );
+
+    if pa <= pb && pa <= pc {
+        left
+    } else if pb <= pc {
+        up
+    } else {
+        up_left
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vision::MAX_IMAGE_PIXELS as MAX;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    /// Encode a PNG with the given header fields and filtered scanlines (filter byte included).
+    fn encode(width: u32, height: u32, bit_depth: u8, color_type: u8, scanlines: &[u8]) -> Vec<u8> {
+        let chunk = |png: &mut Vec<u8>, kind: &[u8], data: &[u8]| {
+            png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            png.extend_from_slice(kind);
+            png.extend_from_slice(data);
+            // Checksums aren't verified
+            png.extend_from_slice(&[0; 4]);
+        };
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&width.to_be_bytes());
+        header.extend_from_slice(&height.to_be_bytes());
+        header.extend_from_slice(&[bit_depth, color_type, 0, 0, 0]);
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(scanlines).unwrap();
+
+        let mut png = SIGNATURE.to_vec();
+        chunk(&mut png, b"IHDR", &header);
+        if color_type == 3 {
+            chunk(&mut png, b"PLTE", &[255, 0, 0, 0, 0, 255]);
+        }
+        chunk(&mut png, b"IDAT", &encoder.finish().unwrap());
+        chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    #[test]
+    fn test_decode_filters() -> Result<()> {
+        // 2x2 RGBA: the first row with the Sub filter, the second with the Paeth filter
+        #[rustfmt::skip]
+        let scanlines = [
+            1, 10, 20, 30, 255, 5, 5, 5, 0,
+            4, 1, 2, 3, 255, 10, 10, 10, 0,
+        ];
+        let image = decode(&encode(2, 2, 8, 6, &scanlines), MAX)?;
+
+        assert_eq!((image.width(), image.height()), (2, 2));
+        #[rustfmt::skip]
+        assert_eq!(
+            image.pixels,
+            vec![
+                10, 20, 30, 15, 25, 35,
+                11, 22, 33, 25, 35, 45,
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_low_bit_depths() -> Result<()> {
+        // 1-bit grayscale
+        let image = decode(&encode(3, 1, 1, 0, &[0, 0b1010_0000]), MAX)?;
+        assert_eq!(image.pixels, vec![255, 255, 255, 0, 0, 0, 255, 255, 255]);
+
+        // 2-bit palette
+        let image = decode(&encode(2, 1, 2, 3, &[0, 0b0001_0000]), MAX)?;
+        assert_eq!(image.pixels, vec![255, 0, 0, 0, 0, 255]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(decode(b"not a png", MAX).is_err());
+        // Truncated image data
+        assert!(decode(&encode(2, 2, 8, 2, &[0, 1, 2, 3]), MAX).is_err());
+        // Too long image data
+        assert!(decode(&encode(1, 1, 8, 0, &[0, 0, 0, 0]), MAX).is_err());
+        // Interlaced
+        let mut png = encode(1, 1, 8, 0, &[0, 0]);
+        png[SIGNATURE.len() + 8 + 12] = 1;
+        assert!(decode(&png, MAX).is_err());
+    }
+
+    #[test]
+    fn test_decode_too_large() {
+        // The size is checked before the image data is inflated
+        assert!(decode(&encode(2, 2, 8, 0, &[]), 3).is_err());
+        assert!(decode(&encode(u32::MAX, u32::MAX, 16, 6, &[]), MAX).is_err());
+
+        // Highly compressible image data is only inflated up to the expected size
+        let scanlines = vec![0; 16 * 1024 * 1024];
+        assert!(decode(&encode(1, 1, 8, 0, &scanlines), MAX).is_err());
+    }
+}
@@ -0,0 +1,322 @@
+//! 2-D projection of embeddings for visualization
+//!
+//! Reduces a set of embeddings to two dimensions, either linearly with PCA or with a simple
+//! UMAP-like neighbor embedding that keeps similar inputs close together, and exports the points
+//! with their labels to JSON or CSV, for a quick visual inspection of the structure of a corpus.
+
+use candle_core::{DType, Tensor};
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+use crate::core::utils::{encode_in_batches, normalize_l2};
+use crate::reduce::Pca;
+use crate::{Error, Result, SentenceTransformer};
+
+const N_NEIGHBORS: usize = 15;
+const N_EPOCHS: usize = 200;
+const N_NEGATIVE_SAMPLES: usize = 5;
+/// Range of the initial layout, which the neighbor embedding then refines
+const INITIAL_SCALE: f32 = 10.;
+const MAX_GRADIENT: f32 = 4.;
+/// Number of embeddings whose similarities to all others are computed at once
+const SIMILARITY_CHUNK_SIZE: usize = 1024;
+
+/// How embeddings are reduced to two dimensions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProjectionMethod {
+    /// Project onto the two principal components. Fast and deterministic, but clusters that
+    /// differ along other directions may overlap.
+    #[default]
+    Pca,
+    /// Lay out the `k`-nearest-neighbor graph of the embeddings (by cosine similarity), like
+    /// UMAP. Keeps local neighborhoods together, but distances between clusters are not
+    /// meaningful.
+    Neighbors,
+}
+
+/// A projected embedding.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Point {
+    pub label: String,
+    pub x: f32,
+    pub y: f32,
+}
+
+/// Reduce embeddings of shape `(n, dim)` to 2-D coordinates, one per embedding.
+pub fn project_2d(embeddings: &Tensor, method: ProjectionMethod) -> Result<Vec<[f32; 2]>> {
+    let embeddings = embeddings.to_dtype(DType::F32)?;
+    let pca_coordinates = Pca::fit(&embeddings, 2)?
+        .transform(&embeddings)?
+        .to_vec2::<f32>()?
+        .into_iter()
+        .map(|point| [point[0], point[1]])
+        .collect();
+
+    match method {
+        ProjectionMethod::Pca => Ok(pca_coordinates),
+        ProjectionMethod::Neighbors => {
+            let neighbors = nearest_neighbors(&embeddings, N_NEIGHBORS)?;
+            Ok(neighbor_embedding(
+                scale(pca_coordinates, INITIAL_SCALE),
+                &neighbors,
+            ))
+        }
+    }
+}
+
+/// Embed a corpus and project the embeddings to 2-D points, labeled with `labels` or, if not
+/// given, with the sentences.
+///
+/// # Arguments
+///
+/// * `model` - The model to embed the corpus with.
+/// * `corpus` - Sentences to project.
+/// * `labels` - A label for each sentence, e.g. the class or source of the sentences.
+/// * `method` - How to reduce the embeddings to two dimensions.
+/// * `batch_size` - Number of sentences to encode at once.
+pub fn project_corpus<S: AsRef<str>>(
+    model: &SentenceTransformer,
+    corpus: &[S],
+    labels: Option<&[S]>,
+    method: ProjectionMethod,
+    batch_size: usize,
+) -> Result<Vec<Point>> {
+    let labels = labels.unwrap_or(corpus);
+    if labels.len() != corpus.len() {
+        return Err(Error::InvalidArgument(
+            "Number of labels does not match the corpus",
+        ));
+    }
+
+    let embeddings = encode_in_batches(corpus, batch_size, |sentences| {
+        model.encode_batch(sentences, false)
+    })?
+    .ok_or(Error::InvalidArgument("Corpus to project is empty"))?;
+
+    let coordinates = project_2d(&embeddings, method)?;
+
+    Ok(labels
+        .iter()
+        .zip(coordinates)
+        .map(|(label, [x, y])| Point {
+            label: label.as_ref().to_string(),
+            x,
+            y,
+        })
+        .collect())
+}
+
+/// Save points as CSV with a `label,x,y` header if the path ends in `.csv`, or as a JSON array
+/// otherwise.
+pub fn save_points<P: AsRef<Path>>(points: &[Point], path: P) -> Result<()> {
+    let path = path.as_ref();
+
+    let contents = match path.extension().and_then(|e| e.to_str()) {
+        Some("csv") => to_csv(points),
+        _ => serde_json::to_string_pretty(points)?,
+    };
+    fs::write(path, contents)?;
+
+    Ok(())
+}
+
+fn to_csv(points: &[Point]) -> String {
+    let mut csv = String::from("label,x,y\n");
+    for point in points {
+        csv.push_str(&format!(
+            "{},{},{}\n",
+            csv_field(&point.label),
+            point.x,
+            point.y
+        ));
+    }
+    csv
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Scale coordinates to fit in `[-range, range]`.
+fn scale(coordinates: Vec<[f32; 2]>, range: f32) -> Vec<[f32; 2]> {
+    let max = coordinates
+        .iter()
+        .flatten()
+        .fold(0f32, |max, &v| max.max(v.abs()));
+    if max == 0. {
+        return coordinates;
+    }
+
+    coordinates
+        .into_iter()
+        .map(|[x, y]| [x / max * range, y / max * range])
+        .collect()
+}
+
+/// The `k` most cosine-similar other embeddings of each embedding. Similarities are computed
+/// for a chunk of embeddings at a time, so memory doesn't grow quadratically with the corpus.
+fn nearest_neighbors(embeddings: &Tensor, k: usize) -> Result<Vec<Vec<usize>>> {
+    let embeddings = normalize_l2(embeddings)?;
+    let n = embeddings.dim(0)?;
+    let transposed = embeddings.t()?;
+
+    let mut neighbors = Vec::with_capacity(n);
+    for start in (0..n).step_by(SIMILARITY_CHUNK_SIZE) {
+        let len = SIMILARITY_CHUNK_SIZE.min(n - start);
+        let similarities = embeddings
+            .narrow(0, start, len)?
+            .matmul(&transposed)?
+            .to_vec2::<f32>()?;
+
+        neighbors.extend(similarities.iter().enumerate().map(|(offset, row)| {
+            let i = start + offset;
+            let mut others: Vec<usize> = (0..n).filter(|&j| j != i).collect();
+            others.sort_by(|&a, &b| row[b].total_cmp(&row[a]));
+            others.truncate(k);
+            others
+        }));
+    }
+
+    Ok(neighbors)
+}
+
+/// Refine a layout by stochastic gradient descent, pulling neighbors together and pushing
+/// randomly sampled points apart, with the low-dimensional similarity `1 / (1 + d²)` of UMAP.
+fn neighbor_embedding(mut layout: Vec<[f32; 2]>, neighbors: &[Vec<usize>]) -> Vec<[f32; 2]> {
+    let n = layout.len();
+
+    // Deterministic pseudo-random sampling, so results are reproducible
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut sample = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state % n as u64) as usize
+    };
+
+    let clip = |v: f32| v.clamp(-MAX_GRADIENT, MAX_GRADIENT);
+
+    for epoch in 0..N_EPOCHS {
+        let learning_rate = 1. - epoch as f32 / N_EPOCHS as f32;
+
+        for (i, neighbors) in neighbors.iter().enumerate() {
+            for &j in neighbors {
+                let [dx, dy] = [layout[i][0] - layout[j][0], layout[i][1] - layout[j][1]];
+                let coefficient = -2. / (1. + dx * dx + dy * dy);
+                let step = [
+                    learning_rate * clip(coefficient * dx),
+                    learning_rate * clip(coefficient * dy),
+                ];
+                layout[i] = [layout[i][0] + step[0], layout[i][1] + step[1]];
+                layout[j] = [layout[j][0] - step[0], layout[j][1] - step[1]];
+
+                for _ in 0..N_NEGATIVE_SAMPLES {
+                    let k = sample();
+                    if k == i {
+                        continue;
+                    }
+                    let [dx, dy] = [layout[i][0] - layout[k][0], layout[i][1] - layout[k][1]];
+                    let d2 = dx * dx + dy * dy;
+                    let coefficient = 2. / ((0.001 + d2) * (1. + d2));
+                    layout[i] = [
+                        layout[i][0] + learning_rate * clip(coefficient * dx),
+                        layout[i][1] + learning_rate * clip(coefficient * dy),
+                    ];
+                }
+            }
+        }
+    }
+
+    layout
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use candle_core::Device;
+    use tempfile::tempdir;
+
+    /// Two well separated clusters in 8 dimensions, of `n` points each.
+    fn clusters(n: usize) -> Result<Tensor> {
+        let values: Vec<f32> = (0..2 * n)
+            .flat_map(|i| {
+                let cluster = (i / n) as f32;
+                (0..8).map(move |d| {
+                    let noise = ((i * 7 + d * 13) % 11) as f32 / 110.;
+                    match d % 2 == (i / n) {
+                        true => 1. + noise,
+                        false => noise - cluster * 0.5,
+                    }
+                })
+            })
+            .collect();
+        Ok(Tensor::from_vec(values, (2 * n, 8), &Device::Cpu)?)
+    }
+
+    fn distance(a: [f32; 2], b: [f32; 2]) -> f32 {
+        ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2)).sqrt()
+    }
+
+    #[test]
+    fn test_project_2d() -> Result<()> {
+        let n = 20;
+        let embeddings = clusters(n)?;
+
+        for method in [ProjectionMethod::Pca, ProjectionMethod::Neighbors] {
+            let points = project_2d(&embeddings, method)?;
+            assert_eq!(points.len(), 2 * n);
+            assert!(points.iter().flatten().all(|v| v.is_finite()));
+
+            // Points are closer to their own cluster than to the other one
+            let mean_distance = |a: std::ops::Range<usize>, b: std::ops::Range<usize>| {
+                let pairs = a.len() * b.len();
+                a.flat_map(|i| b.clone().map(move |j| (i, j)))
+                    .map(|(i, j)| distance(points[i], points[j]))
+                    .sum::<f32>()
+                    / pairs as f32
+            };
+            let within = mean_distance(0..n, 0..n);
+            let between = mean_distance(0..n, n..2 * n);
+            assert!(within < between, "{method:?}: {within} >= {between}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_points() -> Result<()> {
+        let dir = tempdir()?;
+        let points = vec![
+            Point {
+                label: "cat".to_string(),
+                x: 1.5,
+                y: -2.,
+            },
+            Point {
+                label: "Hello, \"world\"".to_string(),
+                x: 0.,
+                y: 3.25,
+            },
+        ];
+
+        let csv = dir.path().join("points.csv");
+        save_points(&points, &csv)?;
+        assert_eq!(
+            fs::read_to_string(&csv)?,
+            "label,x,y\ncat,1.5,-2\n\"Hello, \"\"world\"\"\",0,3.25\n"
+        );
+
+        let json = dir.path().join("points.json");
+        save_points(&points, &json)?;
+        let saved: serde_json::Value = serde_json::from_str(&fs::read_to_string(&json)?)?;
+        assert_eq!(saved[1]["label"], "Hello, \"world\"");
+        assert_eq!(saved[0]["x"], 1.5);
+
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+#![cfg(feature = "hub")]
+
 use candle_core::Tensor;
 use serde::Deserialize;
 use std::process::ExitCode;